@@ -0,0 +1,116 @@
+//! Measures initial syncs of an account in many rooms, once with the rooms loaded one after
+//! another and once with the default `sync_room_concurrency`:
+//! `cargo bench --bench initial_sync`
+
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use conduit::{
+    api::client_server::{create_room_route, sync_events_route},
+    services, Config, KeyValueDatabase, Ruma, Services, SERVICES,
+};
+use ruma::{
+    api::client::{room::create_room, sync::sync_events},
+    OwnedDeviceId, OwnedUserId, UserId,
+};
+
+const ROOMS: usize = 200;
+const SYNCS: usize = 5;
+
+fn request<T>(body: T, user_id: &UserId, device_id: &str) -> Ruma<T> {
+    Ruma {
+        body,
+        sender_user: Some(user_id.to_owned()),
+        sender_device: Some(device_id.into()),
+        sender_servername: None,
+        json_body: None,
+        from_appservice: false,
+        client_ip: None,
+    }
+}
+
+/// Sets up services with a new SQLite database in `database_path` and makes them the current
+/// ones.
+fn start_server(database_path: &Path, sync_room_concurrency: usize) -> OwnedUserId {
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "server_name": "conduit.bench",
+        "database_backend": "sqlite",
+        "database_path": database_path,
+        "sync_room_concurrency": sync_room_concurrency,
+    }))
+    .expect("bench config is valid");
+
+    let db = Box::leak(Box::new(
+        KeyValueDatabase::open(&config).expect("database opens"),
+    ));
+    let services = Box::leak(Box::new(
+        Services::build(db, config).expect("services build on an empty database"),
+    ));
+    *SERVICES.write().unwrap() = Some(services);
+
+    let user_id = UserId::parse("@bench:conduit.bench").expect("user id is valid");
+    services()
+        .users
+        .create(&user_id, Some("password"))
+        .expect("user can be created");
+
+    user_id
+}
+
+/// Returns the median time of an initial sync of a user in `ROOMS` rooms.
+async fn initial_sync(sync_room_concurrency: usize) -> Duration {
+    let database_path = std::env::temp_dir().join(format!(
+        "conduit-bench-{}-{}",
+        std::process::id(),
+        sync_room_concurrency
+    ));
+    let user_id = start_server(&database_path, sync_room_concurrency);
+
+    for _ in 0..ROOMS {
+        create_room_route(request(create_room::v3::Request::new(), &user_id, "SETUP"))
+            .await
+            .expect("room can be created");
+    }
+
+    let mut times = Vec::new();
+    for i in 0..SYNCS {
+        // Every sync comes from a new device, so no sync result is shared
+        let device_id: OwnedDeviceId = format!("SYNC{i}").into();
+        services()
+            .users
+            .create_device(&user_id, &device_id, &format!("token{i}"), None)
+            .expect("device can be created");
+
+        let start = Instant::now();
+        let response = sync_events_route(request(
+            sync_events::v3::Request::new(),
+            &user_id,
+            device_id.as_str(),
+        ))
+        .await
+        .unwrap_or_else(|_| panic!("initial sync succeeds"));
+        times.push(start.elapsed());
+
+        assert_eq!(response.rooms.join.len(), ROOMS);
+    }
+
+    // The services stay alive, but nothing writes to the database anymore
+    std::fs::remove_dir_all(&database_path).expect("bench database can be removed");
+
+    times.sort();
+    times[SYNCS / 2]
+}
+
+#[tokio::main]
+async fn main() {
+    for sync_room_concurrency in [1, 8] {
+        println!(
+            "initial sync of {} rooms, sync_room_concurrency = {}: {:?}",
+            ROOMS,
+            sync_room_concurrency,
+            initial_sync(sync_room_concurrency).await
+        );
+    }
+}
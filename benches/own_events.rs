@@ -0,0 +1,170 @@
+//! Measures checking the events this server created when they come back over federation, once
+//! fully verified and once with `trust_own_signatures`:
+//! `cargo bench --bench own_events`
+
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::RwLock,
+    time::{Duration, Instant, SystemTime},
+};
+
+use conduit::{
+    api::client_server::{create_room_route, send_message_event_route},
+    services, Config, KeyValueDatabase, Ruma, Services, SERVICES,
+};
+use ruma::{
+    api::{
+        client::{message::send_message_event, room::create_room},
+        federation::discovery::{ServerSigningKeys, VerifyKey},
+    },
+    events::room::message::RoomMessageEventContent,
+    serde::Base64,
+    signatures::Verified,
+    MilliSecondsSinceUnixEpoch, OwnedUserId, TransactionId, UserId,
+};
+
+const EVENTS: usize = 500;
+const RUNS: usize = 5;
+
+fn request<T>(body: T, user_id: &UserId) -> Ruma<T> {
+    Ruma {
+        body,
+        sender_user: Some(user_id.to_owned()),
+        sender_device: Some("BENCH".into()),
+        sender_servername: None,
+        json_body: None,
+        from_appservice: false,
+        client_ip: None,
+    }
+}
+
+/// Sets up services with a new SQLite database in `database_path` and makes them the current
+/// ones. Our own signing key is stored like a key of another server, so full verification doesn't
+/// have to fetch it.
+fn start_server(database_path: &Path, trust_own_signatures: bool) -> OwnedUserId {
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "server_name": "conduit.bench",
+        "database_backend": "sqlite",
+        "database_path": database_path,
+        "trust_own_signatures": trust_own_signatures,
+    }))
+    .expect("bench config is valid");
+
+    let db = Box::leak(Box::new(
+        KeyValueDatabase::open(&config).expect("database opens"),
+    ));
+    let services = Box::leak(Box::new(
+        Services::build(db, config).expect("services build on an empty database"),
+    ));
+    *SERVICES.write().unwrap() = Some(services);
+
+    let globals = &services().globals;
+    let keypair = globals.keypair();
+    let mut keys = ServerSigningKeys::new(
+        globals.server_name().to_owned(),
+        MilliSecondsSinceUnixEpoch::from_system_time(
+            SystemTime::now() + Duration::from_secs(60 * 60),
+        )
+        .expect("time is valid"),
+    );
+    keys.verify_keys.insert(
+        format!("ed25519:{}", keypair.version())
+            .try_into()
+            .expect("key id is valid"),
+        VerifyKey::new(Base64::new(keypair.public_key().to_vec())),
+    );
+    globals
+        .add_signing_key(globals.server_name(), keys)
+        .expect("signing key can be stored");
+
+    let user_id = UserId::parse("@bench:conduit.bench").expect("user id is valid");
+    services()
+        .users
+        .create(&user_id, Some("password"))
+        .expect("user can be created");
+
+    user_id
+}
+
+/// Returns the median time of checking `EVENTS` messages this server sent.
+async fn verify_own_events(trust_own_signatures: bool) -> Duration {
+    let database_path = std::env::temp_dir().join(format!(
+        "conduit-bench-{}-{}",
+        std::process::id(),
+        trust_own_signatures
+    ));
+    let user_id = start_server(&database_path, trust_own_signatures);
+
+    let room_id = create_room_route(request(create_room::v3::Request::new(), &user_id))
+        .await
+        .expect("room can be created")
+        .room_id;
+    let room_version_id = services()
+        .rooms
+        .state
+        .get_room_version(&room_id)
+        .expect("room has a version");
+
+    let mut events = Vec::new();
+    for i in 0..EVENTS {
+        let event_id = send_message_event_route(request(
+            send_message_event::v3::Request::new(
+                room_id.clone(),
+                TransactionId::new(),
+                &RoomMessageEventContent::text_plain(format!("Message {i}")),
+            )
+            .expect("message serializes"),
+            &user_id,
+        ))
+        .await
+        .expect("message can be sent")
+        .event_id;
+
+        // The event as it comes back over federation
+        let mut value = services()
+            .rooms
+            .timeline
+            .get_pdu_json(&event_id)
+            .expect("database works")
+            .expect("event was stored");
+        value.remove("event_id");
+        value.remove("unsigned");
+        events.push((event_id, value));
+    }
+
+    let mut times = Vec::new();
+    for _ in 0..RUNS {
+        let pub_key_map = RwLock::new(BTreeMap::new());
+
+        let start = Instant::now();
+        for (event_id, value) in &events {
+            let verified = services()
+                .rooms
+                .event_handler
+                .verify_event(event_id, value, &room_version_id, &pub_key_map)
+                .await
+                .expect("event is valid");
+            assert!(matches!(verified, Verified::All));
+        }
+        times.push(start.elapsed());
+    }
+
+    // The services stay alive, but nothing writes to the database anymore
+    std::fs::remove_dir_all(&database_path).expect("bench database can be removed");
+
+    times.sort();
+    times[RUNS / 2]
+}
+
+#[tokio::main]
+async fn main() {
+    for trust_own_signatures in [false, true] {
+        println!(
+            "checking {} of our own events, trust_own_signatures = {}: {:?}",
+            EVENTS,
+            trust_own_signatures,
+            verify_own_events(trust_own_signatures).await
+        );
+    }
+}
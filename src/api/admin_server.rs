@@ -0,0 +1,489 @@
+//! Conduit specific endpoints below `/_conduit/admin/` that can only be used by server admins.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    time::SystemTime,
+};
+
+use axum::{
+    async_trait,
+    extract::{FromRequest, Path as UrlPath, Query, RequestParts, TypedHeader},
+    headers::{authorization::Bearer, Authorization},
+    Json,
+};
+use ruma::{
+    api::client::error::ErrorKind, MilliSecondsSinceUnixEpoch, OwnedServerName, OwnedUserId,
+    ServerName,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{error, info};
+
+use crate::{
+    service::{reports::Report, rooms::timeline::PduCacheStats, sending::DestinationStatus},
+    services, utils, Error, Result,
+};
+
+/// Extractor for the user id of a server admin, authenticated by their access token
+pub struct AdminUser(pub OwnedUserId);
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for AdminUser {
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let auth_header = Option::<TypedHeader<Authorization<Bearer>>>::from_request(req).await?;
+
+        let token = match &auth_header {
+            Some(TypedHeader(Authorization(bearer))) => bearer.token(),
+            None => {
+                return Err(Error::BadRequest(
+                    ErrorKind::MissingToken,
+                    "Missing access token.",
+                ))
+            }
+        };
+
+        let user_id = match services().users.find_from_token(token)? {
+            Some((user_id, device_id))
+                if services()
+                    .users
+                    .token_expired(&user_id, device_id.as_str().into())? =>
+            {
+                return Err(Error::BadRequest(
+                    ErrorKind::UnknownToken { soft_logout: true },
+                    "Access token has expired.",
+                ))
+            }
+            Some((user_id, _device_id)) => user_id,
+            None => {
+                return Err(Error::BadRequest(
+                    ErrorKind::UnknownToken { soft_logout: false },
+                    "Unknown access token.",
+                ))
+            }
+        };
+
+        if !services().users.is_admin(&user_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Only server admins can use this endpoint.",
+            ));
+        }
+
+        Ok(AdminUser(user_id))
+    }
+}
+
+/// # `POST /_conduit/admin/backup`
+///
+/// Writes a consistent snapshot of the database into a new directory below `backup_path`.
+///
+/// - Returns the path and size in bytes of the snapshot
+/// - The server keeps serving requests while the snapshot is taken
+/// - `GET /_conduit/admin/backup` reports the progress meanwhile
+pub async fn backup_route(AdminUser(user_id): AdminUser) -> Result<Json<Value>> {
+    info!("Backup requested by {}", user_id);
+
+    let (path, size) = tokio::task::spawn_blocking(|| services().globals.backup())
+        .await
+        .map_err(|e| {
+            error!("Backup task failed: {}", e);
+            Error::BadRequest(ErrorKind::Unknown, "Backup failed.")
+        })??;
+
+    Ok(Json(json!({
+        "path": path,
+        "size": size,
+    })))
+}
+
+/// # `GET /_conduit/admin/backup`
+///
+/// Returns the status of the running or last backup, with its progress while it runs.
+pub async fn get_backup_status_route(_: AdminUser) -> Result<Json<Value>> {
+    Ok(Json(json!(services().globals.backup_status())))
+}
+
+/// # `GET /_conduit/admin/maintenance`
+///
+/// Returns whether the server is in read-only maintenance mode.
+pub async fn get_maintenance_mode_route(_: AdminUser) -> Result<Json<Value>> {
+    Ok(Json(json!({
+        "enabled": services().globals.maintenance_mode(),
+    })))
+}
+
+/// # `POST /_conduit/admin/maintenance/enable`
+///
+/// Puts the server into read-only maintenance mode: requests that would write to the database
+/// are rejected until it is disabled again.
+///
+/// - Endpoints below `/_conduit/admin/` keep working during maintenance
+pub async fn enable_maintenance_mode_route(AdminUser(user_id): AdminUser) -> Result<Json<Value>> {
+    info!("{} enabled maintenance mode", user_id);
+    services().globals.set_maintenance_mode(true);
+
+    Ok(Json(json!({})))
+}
+
+/// # `POST /_conduit/admin/maintenance/disable`
+///
+/// Leaves maintenance mode.
+pub async fn disable_maintenance_mode_route(AdminUser(user_id): AdminUser) -> Result<Json<Value>> {
+    info!("{} disabled maintenance mode", user_id);
+    services().globals.set_maintenance_mode(false);
+
+    Ok(Json(json!({})))
+}
+
+/// # `POST /_conduit/admin/signing_key/rotate`
+///
+/// Generates a new signing key and uses it for everything signed from now on.
+///
+/// - The old key stays published as an old verify key, so events signed with it can still be
+/// verified
+/// - Returns the id of the new key
+pub async fn rotate_signing_key_route(AdminUser(user_id): AdminUser) -> Result<Json<Value>> {
+    let key_id = services().globals.rotate_keypair()?;
+    info!("{} rotated the signing key to {}", user_id, key_id);
+
+    Ok(Json(json!({ "key_id": key_id })))
+}
+
+/// # `POST /_conduit/admin/media/{serverName}/{mediaId}/quarantine`
+///
+/// Quarantines media, so it and its thumbnails can't be downloaded from this server anymore.
+///
+/// - Works for local and remote media, even if it wasn't downloaded yet
+pub async fn quarantine_media_route(
+    AdminUser(user_id): AdminUser,
+    UrlPath((server_name, media_id)): UrlPath<(String, String)>,
+) -> Result<Json<Value>> {
+    let mxc = format!("mxc://{}/{}", server_name, media_id);
+
+    info!("{} quarantined {}", user_id, mxc);
+    services().media.quarantine(&mxc)?;
+
+    Ok(Json(json!({})))
+}
+
+/// # `GET /_conduit/admin/status`
+///
+/// Returns a snapshot of the current load of the server.
+///
+/// - `connections` is null if the HTTP server handle isn't available
+/// - `sync_waiters` counts the /sync requests that are open right now
+/// - `federation_queues` maps destination servers to the number of events waiting to be sent
+/// - `event_cache` counts the lookups of events that were and weren't cached since the start
+pub async fn status_route(_: AdminUser) -> Result<Json<Value>> {
+    let sync_waiters = services().globals.sync_limiter.open_syncs();
+    // Both walk through a lot of data on larger servers
+    let (queue_depths, database_size) = tokio::task::spawn_blocking(|| {
+        Ok::<_, Error>((
+            services().sending.queue_depths()?,
+            utils::dir_size(Path::new(&services().globals.config.database_path))?,
+        ))
+    })
+    .await
+    .map_err(|e| {
+        error!("Status task failed: {}", e);
+        Error::BadRequest(ErrorKind::Unknown, "Failed to collect the status.")
+    })??;
+
+    Ok(Json(status_json(
+        services().globals.connection_count(),
+        sync_waiters,
+        queue_depths,
+        database_size,
+        services().rooms.timeline.pdu_cache.stats(),
+    )))
+}
+
+/// # `GET /_conduit/admin/reports`
+///
+/// Lists the event and room reports users sent, oldest first.
+///
+/// - `event_id` is null for reports of a whole room
+pub async fn reports_route(_: AdminUser) -> Result<Json<Value>> {
+    let reports = services().reports.reports().collect::<Result<Vec<_>>>()?;
+
+    Ok(Json(reports_json(reports)))
+}
+
+#[derive(Deserialize)]
+pub struct DestinationsParams {
+    destination: Option<String>,
+}
+
+/// # `GET /_conduit/admin/federation/destinations`
+///
+/// Lists the federation destinations we sent to or have events queued for.
+///
+/// - `?destination=` only lists the given server
+/// - The status of each destination is only kept in memory, it starts empty after a restart
+/// - `retry_after_ts` is null if sending to the destination isn't backed off
+pub async fn federation_destinations_route(
+    _: AdminUser,
+    Query(params): Query<DestinationsParams>,
+) -> Result<Json<Value>> {
+    let destination = params
+        .destination
+        .as_deref()
+        .map(parse_destination)
+        .transpose()?;
+
+    Ok(Json(destinations_json(
+        services().sending.destination_statuses(),
+        services().sending.queue_depths()?,
+        destination.as_deref(),
+    )))
+}
+
+/// # `POST /_conduit/admin/federation/destinations/{serverName}/retry`
+///
+/// Resets the backoff of a destination and retries its failed transaction right away.
+///
+/// - Does nothing if no transaction to the destination failed
+pub async fn retry_federation_destination_route(
+    AdminUser(user_id): AdminUser,
+    UrlPath(server_name): UrlPath<String>,
+) -> Result<Json<Value>> {
+    let server_name = parse_destination(&server_name)?;
+
+    info!(
+        "{} reset the federation backoff for {}",
+        user_id, server_name
+    );
+    services().sending.reset_backoff(server_name);
+
+    Ok(Json(json!({})))
+}
+
+/// # `POST /_conduit/admin/federation/destinations/{serverName}/purge`
+///
+/// Drops all events waiting to be sent to a destination. They are not sent to it anymore.
+pub async fn purge_federation_destination_route(
+    AdminUser(user_id): AdminUser,
+    UrlPath(server_name): UrlPath<String>,
+) -> Result<Json<Value>> {
+    let server_name = parse_destination(&server_name)?;
+
+    info!(
+        "{} purged the federation queue for {}",
+        user_id, server_name
+    );
+    services().sending.purge_queue(server_name);
+
+    Ok(Json(json!({})))
+}
+
+fn parse_destination(server_name: &str) -> Result<OwnedServerName> {
+    ServerName::parse(server_name)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid server name."))
+}
+
+fn destinations_json(
+    mut statuses: HashMap<OwnedServerName, DestinationStatus>,
+    queue_depths: BTreeMap<OwnedServerName, u64>,
+    filter: Option<&ServerName>,
+) -> Value {
+    for server in queue_depths.keys() {
+        statuses.entry(server.clone()).or_default();
+    }
+
+    let ts = |time: SystemTime| MilliSecondsSinceUnixEpoch::from_system_time(time);
+    let destinations = statuses
+        .into_iter()
+        .filter(|(server, _)| filter.map_or(true, |filter| &**server == filter))
+        .map(|(server, status)| {
+            let queued = queue_depths.get(&server).copied().unwrap_or(0);
+            (
+                server,
+                json!({
+                    "last_success_ts": status.last_success.and_then(ts),
+                    "last_failure_ts": status.last_failure.and_then(ts),
+                    "failures": status.failures,
+                    "retry_after_ts": status.retry_at().and_then(ts),
+                    "queued_events": queued,
+                    "last_error": status.last_error,
+                    "unstable_features": status.unstable_features,
+                }),
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    json!({ "destinations": destinations })
+}
+
+fn reports_json(reports: Vec<(u64, Report)>) -> Value {
+    json!({
+        "reports": reports
+            .into_iter()
+            .map(|(id, report)| {
+                json!({
+                    "id": id,
+                    "reporter": report.reporter,
+                    "room_id": report.room_id,
+                    "event_id": report.event_id,
+                    "score": report.score,
+                    "reason": report.reason,
+                    "received_ts": report.received_ts,
+                })
+            })
+            .collect::<Vec<_>>(),
+    })
+}
+
+fn status_json(
+    connections: Option<usize>,
+    sync_waiters: usize,
+    federation_queues: BTreeMap<OwnedServerName, u64>,
+    database_size: u64,
+    event_cache: PduCacheStats,
+) -> Value {
+    json!({
+        "connections": connections,
+        "sync_waiters": sync_waiters,
+        "federation_queues": federation_queues,
+        "database_size": database_size,
+        "event_cache": event_cache,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use ruma::{room_id, server_name, user_id, UInt};
+
+    use super::*;
+
+    #[test]
+    fn status_has_expected_shape() {
+        let queues = BTreeMap::from([(server_name!("example.org").to_owned(), 3)]);
+
+        assert_eq!(
+            status_json(
+                Some(5),
+                2,
+                queues,
+                1024,
+                PduCacheStats {
+                    hits: 10,
+                    misses: 4,
+                    entries: 4,
+                }
+            ),
+            json!({
+                "connections": 5,
+                "sync_waiters": 2,
+                "federation_queues": { "example.org": 3 },
+                "database_size": 1024,
+                "event_cache": { "hits": 10, "misses": 4, "entries": 4 },
+            })
+        );
+    }
+
+    #[test]
+    fn failed_destinations_show_their_error() {
+        let failed_at = UNIX_EPOCH + Duration::from_secs(1);
+        let statuses = HashMap::from([(
+            server_name!("down.example").to_owned(),
+            DestinationStatus {
+                last_success: None,
+                last_failure: Some(failed_at),
+                failures: 1,
+                last_error: Some("Connection refused".to_owned()),
+                ..Default::default()
+            },
+        )]);
+        let queues = BTreeMap::from([
+            (server_name!("down.example").to_owned(), 4),
+            (server_name!("up.example").to_owned(), 1),
+        ]);
+
+        assert_eq!(
+            destinations_json(
+                statuses.clone(),
+                queues.clone(),
+                Some(server_name!("down.example"))
+            ),
+            json!({
+                "destinations": {
+                    "down.example": {
+                        "last_success_ts": null,
+                        "last_failure_ts": 1000,
+                        "failures": 1,
+                        "retry_after_ts": 31000,
+                        "queued_events": 4,
+                        "last_error": "Connection refused",
+                        "unstable_features": null,
+                    },
+                },
+            })
+        );
+        assert_eq!(
+            destinations_json(statuses, queues, None)["destinations"]["up.example"],
+            json!({
+                "last_success_ts": null,
+                "last_failure_ts": null,
+                "failures": 0,
+                "retry_after_ts": null,
+                "queued_events": 1,
+                "last_error": null,
+                "unstable_features": null,
+            })
+        );
+    }
+
+    #[test]
+    fn room_reports_are_listed() {
+        let report = Report {
+            reporter: user_id!("@alice:example.org").to_owned(),
+            room_id: room_id!("!spam:example.com").to_owned(),
+            event_id: None,
+            score: None,
+            reason: Some("Spam".to_owned()),
+            received_ts: MilliSecondsSinceUnixEpoch(UInt::from(1000_u32)),
+        };
+
+        assert_eq!(
+            reports_json(vec![(7, report)]),
+            json!({
+                "reports": [{
+                    "id": 7,
+                    "reporter": "@alice:example.org",
+                    "room_id": "!spam:example.com",
+                    "event_id": null,
+                    "score": null,
+                    "reason": "Spam",
+                    "received_ts": 1000,
+                }],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn destinations_are_filtered_by_the_query() {
+        let admin = crate::utils::testing::admin("destinations_admin").await;
+        let destinations = |destination: &str| {
+            federation_destinations_route(
+                AdminUser(admin.clone()),
+                Query(DestinationsParams {
+                    destination: Some(destination.to_owned()),
+                }),
+            )
+        };
+
+        assert_eq!(
+            destinations("unknown.remote.test").await.unwrap().0,
+            json!({ "destinations": {} })
+        );
+        assert!(matches!(
+            destinations("not a server name").await,
+            Err(Error::BadRequest(ErrorKind::InvalidParam, _))
+        ));
+    }
+}
@@ -1,17 +1,34 @@
 use super::{DEVICE_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH};
-use crate::{api::client_server, services, utils, Error, Result, Ruma};
+use crate::{
+    api::{
+        client_server,
+        identity_server::{self, ValidatedThreepid},
+    },
+    service::users::{canonical_threepid_address, Threepid},
+    services, utils, Error, Result, Ruma,
+};
+use axum::{extract::Query, response::IntoResponse, Json};
+use regex::RegexSet;
 use ruma::{
     api::client::{
         account::{
-            change_password, deactivate, get_3pids, get_username_availability, register,
-            request_3pid_management_token_via_email, request_3pid_management_token_via_msisdn,
-            whoami, ThirdPartyIdRemovalStatus,
+            add_3pid, bind_3pid, change_password, deactivate, delete_3pid, get_3pids,
+            get_username_availability, register, request_3pid_management_token_via_email,
+            request_3pid_management_token_via_msisdn, request_registration_token_via_email,
+            request_registration_token_via_msisdn, unbind_3pid, whoami, ThirdPartyIdRemovalStatus,
         },
         error::ErrorKind,
         uiaa::{AuthFlow, AuthType, UiaaInfo},
     },
     events::{room::message::RoomMessageEventContent, GlobalAccountDataEventType},
-    push, UserId,
+    push,
+    thirdparty::Medium,
+    MilliSecondsSinceUnixEpoch, OwnedClientSecret, OwnedSessionId, OwnedUserId, UserId,
+};
+use serde::Deserialize;
+use serde_json::{
+    json,
+    value::{to_raw_value, RawValue as RawJsonValue},
 };
 use tracing::{info, warn};
 
@@ -24,40 +41,82 @@ const RANDOM_USER_ID_LENGTH: usize = 10;
 /// Checks if a username is valid and available on this server.
 ///
 /// Conditions for returning true:
-/// - The user id is not historical
+/// - The localpart is not empty and not historical
 /// - The server name of the user id matches this server
-/// - No user or appservice on this server already claimed this username
+/// - No user on this server already has this username
+/// - The username doesn't match `forbidden_usernames`
+/// - No appservice claimed this username with an exclusive namespace
 ///
 /// Note: This will not reserve the username, so the username might become invalid when trying to register
 pub async fn get_register_available_route(
     body: Ruma<get_username_availability::v3::Request>,
 ) -> Result<get_username_availability::v3::Response> {
-    // Validate user id
-    let user_id = UserId::parse_with_server_name(
-        body.username.to_lowercase(),
-        services().globals.server_name(),
+    let user_id = parse_username(&body.username)?;
+    check_username(&user_id, body.from_appservice)?;
+
+    // If no if check is true we have an username that's available to be used.
+    Ok(get_username_availability::v3::Response { available: true })
+}
+
+/// Parses the username clients ask for into a user id on this server.
+fn parse_username(username: &str) -> Result<OwnedUserId> {
+    UserId::parse_with_server_name(username.to_lowercase(), services().globals.server_name())
+        .ok()
+        .filter(|user_id| {
+            !user_id.localpart().is_empty()
+                && !user_id.is_historical()
+                && services().globals.server_is_ours(user_id.server_name())
+        })
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidUsername,
+            "Username is invalid.",
+        ))
+}
+
+/// Checks that the user id is free. Appservices may take forbidden usernames and usernames in
+/// exclusive namespaces.
+fn check_username(user_id: &UserId, from_appservice: bool) -> Result<()> {
+    let taken = services().users.exists(user_id)?;
+    if from_appservice {
+        return username_available(user_id, taken, &RegexSet::empty(), &RegexSet::empty());
+    }
+
+    username_available(
+        user_id,
+        taken,
+        services().globals.forbidden_usernames(),
+        &services().appservice.exclusive_users()?,
     )
-    .ok()
-    .filter(|user_id| {
-        !user_id.is_historical() && user_id.server_name() == services().globals.server_name()
-    })
-    .ok_or(Error::BadRequest(
-        ErrorKind::InvalidUsername,
-        "Username is invalid.",
-    ))?;
+}
+
+fn username_available(
+    user_id: &UserId,
+    taken: bool,
+    forbidden_usernames: &RegexSet,
+    appservice_exclusive_users: &RegexSet,
+) -> Result<()> {
+    if forbidden_usernames.is_match(user_id.localpart()) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidUsername,
+            "Username is reserved.",
+        ));
+    }
 
-    // Check if username is creative enough
-    if services().users.exists(&user_id)? {
+    if taken {
         return Err(Error::BadRequest(
             ErrorKind::UserInUse,
             "Desired user ID is already taken.",
         ));
     }
 
-    // TODO add check for appservice namespaces
+    if appservice_exclusive_users.is_match(user_id.as_str()) {
+        return Err(Error::BadRequest(
+            ErrorKind::Exclusive,
+            "Username is reserved by an appservice.",
+        ));
+    }
 
-    // If no if check is true we have an username that's available to be used.
-    Ok(get_username_availability::v3::Response { available: true })
+    Ok(())
 }
 
 /// # `POST /_matrix/client/r0/register`
@@ -69,7 +128,12 @@ pub async fn get_register_available_route(
 ///
 /// - Only works if registration is enabled
 /// - If type is guest: ignores all parameters except initial_device_display_name
-/// - If sender is not appservice: Requires UIAA (but we only use a dummy stage)
+/// - If sender is not appservice: Requires UIAA (a dummy stage, or an email or phone number stage
+///   if these can be validated)
+/// - If `registration_requires_email` or `registration_requires_msisdn` is set: Requires a
+///   validated email address or phone number, which is added to the account
+/// - If a captcha is configured: Every flow starts with a `m.login.recaptcha` stage, checked with
+///   the captcha provider
 /// - If type is not guest and no username is given: Always fails after UIAA check
 /// - Creates a new account and populates it with default account data
 /// - If `inhibit_login` is false: Creates a device and returns device id and access_token
@@ -85,25 +149,8 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
 
     let user_id = match (&body.username, is_guest) {
         (Some(username), false) => {
-            let proposed_user_id = UserId::parse_with_server_name(
-                username.to_lowercase(),
-                services().globals.server_name(),
-            )
-            .ok()
-            .filter(|user_id| {
-                !user_id.is_historical()
-                    && user_id.server_name() == services().globals.server_name()
-            })
-            .ok_or(Error::BadRequest(
-                ErrorKind::InvalidUsername,
-                "Username is invalid.",
-            ))?;
-            if services().users.exists(&proposed_user_id)? {
-                return Err(Error::BadRequest(
-                    ErrorKind::UserInUse,
-                    "Desired user ID is already taken.",
-                ));
-            }
+            let proposed_user_id = parse_username(username)?;
+            check_username(&proposed_user_id, body.from_appservice)?;
             proposed_user_id
         }
         _ => loop {
@@ -120,28 +167,35 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
 
     // UIAA
     let mut uiaainfo = UiaaInfo {
-        flows: vec![AuthFlow {
-            stages: vec![AuthType::Dummy],
-        }],
+        flows: registration_flows(),
         completed: Vec::new(),
-        params: Default::default(),
+        params: registration_params(),
         session: None,
         auth_error: None,
     };
 
+    let mut email = None;
+    let mut msisdn = None;
     if !body.from_appservice {
         if let Some(auth) = &body.auth {
-            let (worked, uiaainfo) = services().uiaa.try_auth(
-                &UserId::parse_with_server_name("", services().globals.server_name())
-                    .expect("we know this is valid"),
-                "".into(),
-                auth,
-                &uiaainfo,
-            )?;
+            let (worked, uiaainfo) = services()
+                .uiaa
+                .try_auth(
+                    &UserId::parse_with_server_name("", services().globals.server_name())
+                        .expect("we know this is valid"),
+                    "".into(),
+                    auth,
+                    &uiaainfo,
+                )
+                .await?;
             if !worked {
                 return Err(Error::Uiaa(uiaainfo));
             }
-        // Success!
+            // Success!
+            if let Some(session) = uiaainfo.session.as_deref() {
+                email = services().email.take_uiaa_email(session);
+                msisdn = services().msisdn.take_uiaa_msisdn(session);
+            }
         } else if let Some(json) = body.json_body {
             uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
             services().uiaa.create(
@@ -157,6 +211,49 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
         }
     }
 
+    if email.is_none() && services().globals.registration_requires_email() && !body.from_appservice
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Registration requires a validated email address.",
+        ));
+    }
+
+    if msisdn.is_none()
+        && services().globals.registration_requires_msisdn()
+        && !body.from_appservice
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Registration requires a validated phone number.",
+        ));
+    }
+
+    if let Some((address, _)) = &email {
+        if services()
+            .users
+            .find_from_threepid(&Medium::Email, address)?
+            .is_some()
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidInUse,
+                "The email address is already in use.",
+            ));
+        }
+    }
+    if let Some((address, _)) = &msisdn {
+        if services()
+            .users
+            .find_from_threepid(&Medium::Msisdn, address)?
+            .is_some()
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidInUse,
+                "The phone number is already in use.",
+            ));
+        }
+    }
+
     let password = if is_guest {
         None
     } else {
@@ -166,8 +263,28 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
     // Create user
     services().users.create(&user_id, password)?;
 
+    for (medium, (address, validated_at)) in email
+        .map(|email| (Medium::Email, email))
+        .into_iter()
+        .chain(msisdn.map(|msisdn| (Medium::Msisdn, msisdn)))
+    {
+        services().users.add_threepid(
+            &user_id,
+            &Threepid {
+                medium,
+                address,
+                validated_at,
+                added_at: MilliSecondsSinceUnixEpoch::now(),
+                bound_id_server: None,
+            },
+        )?;
+    }
+
     // Default to pretty displayname
-    let mut displayname = user_id.localpart().to_owned();
+    let mut displayname = default_displayname(
+        services().globals.default_displayname_template(),
+        user_id.localpart(),
+    );
 
     // If enabled append lightning bolt to display name (default true)
     if services().globals.enable_lightning_bolt() {
@@ -177,6 +294,11 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
     services()
         .users
         .set_displayname(&user_id, Some(displayname.clone()))?;
+    if let Some(avatar_url) = services().globals.default_avatar_url() {
+        services()
+            .users
+            .set_avatar_url(&user_id, Some(avatar_url.clone()))?;
+    }
 
     // Initial account data
     services().account_data.update(
@@ -229,7 +351,7 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
         )));
 
     // If this is the first real user, grant them admin privileges
-    // Note: the server user, @conduit:servername, is generated first
+    // Note: the server user, @conduit:servername by default, is generated first
     if services().users.count()? == 2 {
         services()
             .admin
@@ -248,6 +370,52 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
     })
 }
 
+/// The display name of a new user, from the `default_displayname_template` config option.
+fn default_displayname(template: &str, localpart: &str) -> String {
+    template.replace("{localpart}", localpart)
+}
+
+/// The UIAA flows of registration: the required third party ids, or a dummy stage and any third
+/// party id we can validate. All of them start with the captcha, if there is one.
+fn registration_flows() -> Vec<AuthFlow> {
+    let captcha = services().captcha.enabled();
+    let flow = |mut stages: Vec<AuthType>| {
+        if captcha {
+            stages.insert(0, AuthType::ReCaptcha);
+        }
+        AuthFlow { stages }
+    };
+
+    let mut required = Vec::new();
+    if services().globals.registration_requires_email() {
+        required.push(AuthType::EmailIdentity);
+    }
+    if services().globals.registration_requires_msisdn() {
+        required.push(AuthType::Msisdn);
+    }
+    if !required.is_empty() {
+        return vec![flow(required)];
+    }
+
+    let mut flows = vec![flow(vec![AuthType::Dummy])];
+    if services().email.enabled() {
+        flows.push(flow(vec![AuthType::EmailIdentity]));
+    }
+    if services().msisdn.enabled() {
+        flows.push(flow(vec![AuthType::Msisdn]));
+    }
+    flows
+}
+
+/// The UIAA params of registration, which tell clients the public key of the captcha.
+fn registration_params() -> Box<RawJsonValue> {
+    let params = match services().captcha.site_key() {
+        Some(site_key) => json!({ "m.login.recaptcha": { "public_key": site_key } }),
+        None => json!({}),
+    };
+    to_raw_value(&params).expect("JSON values can be serialized")
+}
+
 /// # `POST /_matrix/client/r0/account/password`
 ///
 /// Changes the password of this account.
@@ -265,6 +433,13 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
 pub async fn change_password_route(
     body: Ruma<change_password::v3::Request>,
 ) -> Result<change_password::v3::Response> {
+    if !services().globals.allow_password_change() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Changing passwords is disabled on this server.",
+        ));
+    }
+
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
     let sender_device = body.sender_device.as_ref().expect("user is authenticated");
 
@@ -279,10 +454,10 @@ pub async fn change_password_route(
     };
 
     if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) =
-            services()
-                .uiaa
-                .try_auth(sender_user, sender_device, auth, &uiaainfo)?;
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
@@ -348,6 +523,8 @@ pub async fn whoami_route(body: Ruma<whoami::v3::Request>) -> Result<whoami::v3:
 /// - Deletes all device metadata (device id, device display name, last seen ip, last seen ts)
 /// - Forgets all to-device events
 /// - Triggers device list updates
+/// - Removes third party identifiers and unbinds them at `id_server`, or the identity server they
+///   were bound at
 /// - Removes ability to log in again
 pub async fn deactivate_route(
     body: Ruma<deactivate::v3::Request>,
@@ -366,10 +543,10 @@ pub async fn deactivate_route(
     };
 
     if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) =
-            services()
-                .uiaa
-                .try_auth(sender_user, sender_device, auth, &uiaainfo)?;
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
@@ -387,8 +564,11 @@ pub async fn deactivate_route(
     // Make the user leave all rooms before deactivation
     client_server::leave_all_rooms(sender_user).await?;
 
-    // Remove devices and mark account as deactivated
-    services().users.deactivate_account(sender_user)?;
+    // Remove devices and third party ids and mark account as deactivated
+    let id_server_unbind_result = services()
+        .users
+        .deactivate_account(sender_user, body.id_server.as_deref())
+        .await?;
 
     info!("User {} deactivated their account.", sender_user);
     services()
@@ -398,47 +578,580 @@ pub async fn deactivate_route(
         )));
 
     Ok(deactivate::v3::Response {
-        id_server_unbind_result: ThirdPartyIdRemovalStatus::NoSupport,
+        id_server_unbind_result,
     })
 }
 
 /// # `GET _matrix/client/v3/account/3pid`
 ///
 /// Get a list of third party identifiers associated with this account.
-///
-/// - Currently always returns empty list
 pub async fn third_party_route(
     body: Ruma<get_3pids::v3::Request>,
 ) -> Result<get_3pids::v3::Response> {
-    let _sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let threepids = services()
+        .users
+        .threepids(sender_user)
+        .filter_map(|r| r.ok())
+        .map(Into::into)
+        .collect();
 
-    Ok(get_3pids::v3::Response::new(Vec::new()))
+    Ok(get_3pids::v3::Response::new(threepids))
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/add`
+///
+/// Adds a third party identifier to the account.
+///
+/// - Requires UIAA to verify user password
+/// - The session has to come from our own `requestToken` endpoints. Sessions of identity servers
+///   can only be validated with an identity server access token, see `/account/3pid/bind`
+pub async fn add_3pid_route(body: Ruma<add_3pid::v3::Request>) -> Result<add_3pid::v3::Response> {
+    check_3pid_changes_allowed()?;
+
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_device = body.sender_device.as_ref().expect("user is authenticated");
+
+    let mut uiaainfo = UiaaInfo {
+        flows: vec![AuthFlow {
+            stages: vec![AuthType::Password],
+        }],
+        completed: Vec::new(),
+        params: Default::default(),
+        session: None,
+        auth_error: None,
+    };
+
+    if let Some(auth) = &body.auth {
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
+        if !worked {
+            return Err(Error::Uiaa(uiaainfo));
+        }
+    // Success!
+    } else if let Some(json) = body.json_body {
+        uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
+        services()
+            .uiaa
+            .create(sender_user, sender_device, &uiaainfo, &json)?;
+        return Err(Error::Uiaa(uiaainfo));
+    } else {
+        return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
+    }
+
+    // Sessions we sent the validation email for ourselves, or that were submitted through us
+    let validated = services()
+        .email
+        .validated(&body.sid, &body.client_secret)
+        .map(|(address, validated_at)| ValidatedThreepid {
+            medium: Medium::Email,
+            address,
+            validated_at,
+        })
+        .or_else(|| {
+            services()
+                .msisdn
+                .validated(&body.sid, &body.client_secret)
+                .map(|(address, validated_at)| ValidatedThreepid {
+                    medium: Medium::Msisdn,
+                    address,
+                    validated_at,
+                })
+        });
+    let validated = validated.ok_or(Error::BadRequest(
+        ErrorKind::ThreepidAuthFailed,
+        "The third party identifier has not been validated.",
+    ))?;
+
+    save_threepid(sender_user, validated, None)?;
+
+    Ok(add_3pid::v3::Response {})
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/bind`
+///
+/// Binds a third party identifier to the account at an identity server, which publishes it for
+/// contact discovery.
+///
+/// - The identifier is also added to the account
+pub async fn bind_3pid_route(
+    body: Ruma<bind_3pid::v3::Request>,
+) -> Result<bind_3pid::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    check_id_server_trusted(&body.id_server)?;
+
+    let validated = identity_server::validated_threepid(
+        &body.id_server,
+        &body.id_access_token,
+        &body.sid,
+        &body.client_secret,
+    )
+    .await
+    .map_err(|_| {
+        Error::BadRequest(
+            ErrorKind::ThreepidAuthFailed,
+            "The third party identifier has not been validated.",
+        )
+    })?;
+
+    let address = canonical_threepid_address(&validated.medium, &validated.address);
+    if services()
+        .users
+        .find_from_threepid(&validated.medium, &address)?
+        .map_or(false, |user_id| &user_id != sender_user)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidInUse,
+            "The third party identifier is already in use.",
+        ));
+    }
+
+    identity_server::bind(
+        &body.id_server,
+        &body.id_access_token,
+        &body.sid,
+        &body.client_secret,
+        sender_user,
+    )
+    .await?;
+
+    save_threepid(sender_user, validated, Some(body.id_server.clone()))?;
+
+    Ok(bind_3pid::v3::Response {})
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/delete`
+///
+/// Removes a third party identifier from the account.
+///
+/// - Also unbinds it at the given identity server, or the one it was bound at
+pub async fn delete_3pid_route(
+    body: Ruma<delete_3pid::v3::Request>,
+) -> Result<delete_3pid::v3::Response> {
+    check_3pid_changes_allowed()?;
+
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    if let Some(id_server) = &body.id_server {
+        check_id_server_trusted(id_server)?;
+    }
+
+    let address = canonical_threepid_address(&body.medium, &body.address);
+    let threepid = services()
+        .users
+        .threepid(sender_user, &body.medium, &address)?;
+
+    let id_server = body
+        .id_server
+        .clone()
+        .or_else(|| threepid.and_then(|threepid| threepid.bound_id_server));
+    let id_server_unbind_result = services()
+        .users
+        .unbind_threepid(sender_user, id_server.as_deref(), &body.medium, &address)
+        .await;
+
+    services()
+        .users
+        .remove_threepid(sender_user, &body.medium, &address)?;
+
+    Ok(delete_3pid::v3::Response {
+        id_server_unbind_result,
+    })
+}
+
+/// Users can't add or remove third party identifiers if `allow_3pid_changes` is off. Binding them
+/// at identity servers is still possible.
+fn check_3pid_changes_allowed() -> Result<()> {
+    if services().globals.allow_3pid_changes() {
+        Ok(())
+    } else {
+        Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Changing third party identifiers is disabled on this server.",
+        ))
+    }
+}
+
+/// Requests to identity servers are only sent to the trusted ones, which are also the only ones
+/// that may validate third party identifiers for accounts.
+fn check_id_server_trusted(id_server: &str) -> Result<()> {
+    if services().globals.identity_server_trusted(id_server) {
+        Ok(())
+    } else {
+        Err(Error::BadRequest(
+            ErrorKind::ServerNotTrusted,
+            "This server doesn't trust the identity server.",
+        ))
+    }
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/unbind`
+///
+/// Unbinds a third party identifier at an identity server. It stays on the account.
+pub async fn unbind_3pid_route(
+    body: Ruma<unbind_3pid::v3::Request>,
+) -> Result<unbind_3pid::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    if let Some(id_server) = &body.id_server {
+        check_id_server_trusted(id_server)?;
+    }
+
+    let address = canonical_threepid_address(&body.medium, &body.address);
+    let threepid = services()
+        .users
+        .threepid(sender_user, &body.medium, &address)?;
+
+    let id_server = body.id_server.clone().or_else(|| {
+        threepid
+            .as_ref()
+            .and_then(|threepid| threepid.bound_id_server.clone())
+    });
+    let id_server_unbind_result = services()
+        .users
+        .unbind_threepid(sender_user, id_server.as_deref(), &body.medium, &address)
+        .await;
+
+    if let Some(mut threepid) = threepid {
+        if matches!(id_server_unbind_result, ThirdPartyIdRemovalStatus::Success) {
+            threepid.bound_id_server = None;
+            services().users.add_threepid(sender_user, &threepid)?;
+        }
+    }
+
+    Ok(unbind_3pid::v3::Response {
+        id_server_unbind_result,
+    })
+}
+
+/// Puts a validated third party identifier on the account, unless another user has it.
+fn save_threepid(
+    user_id: &UserId,
+    validated: ValidatedThreepid,
+    bound_id_server: Option<String>,
+) -> Result<()> {
+    let address = canonical_threepid_address(&validated.medium, &validated.address);
+
+    if services()
+        .users
+        .find_from_threepid(&validated.medium, &address)?
+        .map_or(false, |owner| owner != user_id)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidInUse,
+            "The third party identifier is already in use.",
+        ));
+    }
+
+    let existing = services()
+        .users
+        .threepid(user_id, &validated.medium, &address)?;
+
+    services().users.add_threepid(
+        user_id,
+        &Threepid {
+            added_at: existing
+                .as_ref()
+                .map_or_else(MilliSecondsSinceUnixEpoch::now, |existing| {
+                    existing.added_at
+                }),
+            bound_id_server: bound_id_server
+                .or_else(|| existing.and_then(|existing| existing.bound_id_server)),
+            medium: validated.medium,
+            address,
+            validated_at: validated.validated_at,
+        },
+    )
+}
+
+/// # `POST /_matrix/client/v3/register/email/requestToken`
+///
+/// Sends an email with a validation link, for the `m.login.email.identity` stage of registration.
+///
+/// - 403 signals that the server can't send emails
+pub async fn request_registration_token_via_email_route(
+    body: Ruma<request_registration_token_via_email::v3::Request>,
+) -> Result<request_registration_token_via_email::v3::Response> {
+    if !services().email.enabled() {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidDenied,
+            "Third party identifier is not allowed",
+        ));
+    }
+
+    let email = canonical_threepid_address(&Medium::Email, &body.email);
+    if services()
+        .users
+        .find_from_threepid(&Medium::Email, &email)?
+        .is_some()
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidInUse,
+            "The email address is already in use.",
+        ));
+    }
+
+    let sid = services()
+        .email
+        .request_validation(
+            &email,
+            &body.client_secret,
+            body.send_attempt.into(),
+            body.client_ip,
+        )
+        .await?;
+
+    Ok(request_registration_token_via_email::v3::Response {
+        sid: sid.into(),
+        submit_url: None,
+    })
 }
 
 /// # `POST /_matrix/client/v3/account/3pid/email/requestToken`
 ///
 /// "This API should be used to request validation tokens when adding an email address to an account"
 ///
+/// - Sends an email with a validation link, the session can then be used with `/account/3pid/add`
 /// - 403 signals that The homeserver does not allow the third party identifier as a contact option.
 pub async fn request_3pid_management_token_via_email_route(
-    _body: Ruma<request_3pid_management_token_via_email::v3::Request>,
+    body: Ruma<request_3pid_management_token_via_email::v3::Request>,
 ) -> Result<request_3pid_management_token_via_email::v3::Response> {
-    Err(Error::BadRequest(
-        ErrorKind::ThreepidDenied,
-        "Third party identifier is not allowed",
-    ))
+    check_3pid_changes_allowed()?;
+
+    if !services().email.enabled() {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidDenied,
+            "Third party identifier is not allowed",
+        ));
+    }
+
+    let email = canonical_threepid_address(&Medium::Email, &body.email);
+    if services()
+        .users
+        .find_from_threepid(&Medium::Email, &email)?
+        .is_some()
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidInUse,
+            "The email address is already in use.",
+        ));
+    }
+
+    let sid = services()
+        .email
+        .request_validation(
+            &email,
+            &body.client_secret,
+            body.send_attempt.into(),
+            body.client_ip,
+        )
+        .await?;
+
+    Ok(request_3pid_management_token_via_email::v3::Response {
+        sid: sid.into(),
+        submit_url: None,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct SubmitTokenParams {
+    sid: String,
+    client_secret: String,
+    token: String,
+}
+
+/// # `GET /_matrix/client/unstable/add_threepid/email/submit_token`
+///
+/// The link in validation emails. Marks the email address as validated.
+pub async fn submit_email_token_route(
+    Query(params): Query<SubmitTokenParams>,
+) -> Result<impl IntoResponse> {
+    services()
+        .email
+        .submit_token(&params.sid, &params.client_secret, &params.token)?;
+
+    Ok("Your email address has been validated. You can return to your Matrix client now.")
 }
 
 /// # `POST /_matrix/client/v3/account/3pid/msisdn/requestToken`
 ///
 /// "This API should be used to request validation tokens when adding an phone number to an account"
 ///
+/// - Limited per phone number and per client IP, like emails
 /// - 403 signals that The homeserver does not allow the third party identifier as a contact option.
 pub async fn request_3pid_management_token_via_msisdn_route(
-    _body: Ruma<request_3pid_management_token_via_msisdn::v3::Request>,
+    body: Ruma<request_3pid_management_token_via_msisdn::v3::Request>,
 ) -> Result<request_3pid_management_token_via_msisdn::v3::Response> {
-    Err(Error::BadRequest(
-        ErrorKind::ThreepidDenied,
-        "Third party identifier is not allowed",
-    ))
+    check_3pid_changes_allowed()?;
+
+    let sid = services()
+        .msisdn
+        .request_token(
+            &body.client_secret,
+            &body.country,
+            &body.phone_number,
+            body.send_attempt.into(),
+            body.client_ip,
+        )
+        .await?;
+
+    Ok(request_3pid_management_token_via_msisdn::v3::Response {
+        sid,
+        submit_url: Some(msisdn_submit_url()),
+    })
+}
+
+/// # `POST /_matrix/client/v3/register/msisdn/requestToken`
+///
+/// Makes the identity server text a validation token, for the `m.login.msisdn` stage of
+/// registration.
+///
+/// - Limited per phone number and per client IP, like emails
+/// - 403 signals that the server can't validate phone numbers
+pub async fn request_registration_token_via_msisdn_route(
+    body: Ruma<request_registration_token_via_msisdn::v3::Request>,
+) -> Result<request_registration_token_via_msisdn::v3::Response> {
+    let sid = services()
+        .msisdn
+        .request_token(
+            &body.client_secret,
+            &body.country,
+            &body.phone_number,
+            body.send_attempt.into(),
+            body.client_ip,
+        )
+        .await?;
+
+    Ok(request_registration_token_via_msisdn::v3::Response {
+        sid,
+        submit_url: Some(msisdn_submit_url()),
+    })
+}
+
+fn msisdn_submit_url() -> String {
+    format!(
+        "{}/_matrix/client/unstable/add_threepid/msisdn/submit_token",
+        services().globals.client_base_url()
+    )
+}
+
+#[derive(Deserialize)]
+pub struct SubmitMsisdnTokenRequest {
+    sid: OwnedSessionId,
+    client_secret: OwnedClientSecret,
+    token: String,
+}
+
+/// # `POST /_matrix/client/unstable/add_threepid/msisdn/submit_token`
+///
+/// The `submit_url` of phone number validations. Passes the token on to the identity server
+/// that texted it.
+pub async fn submit_msisdn_token_route(
+    Json(body): Json<SubmitMsisdnTokenRequest>,
+) -> Result<impl IntoResponse> {
+    let success = services()
+        .msisdn
+        .submit_token(&body.sid, &body.client_secret, &body.token)
+        .await?;
+
+    Ok(Json(json!({ "success": success })))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{api::client_server::get_profile_route, service::appservice, utils::testing};
+    use ruma::api::client::{
+        profile::get_profile,
+        uiaa::{AuthData, Dummy},
+    };
+
+    fn registrations() -> Vec<(String, serde_yaml::Value)> {
+        vec![(
+            "irc".to_owned(),
+            serde_yaml::from_str(
+                r#"
+namespaces:
+  users:
+    - exclusive: true
+      regex: "@irc_.*:example.org"
+    - exclusive: false
+      regex: "@bridged_.*:example.org"
+"#,
+            )
+            .unwrap(),
+        )]
+    }
+
+    fn available(localpart: &str, taken: bool) -> Result<()> {
+        let forbidden = RegexSet::new(["^admin", "^system$"]).unwrap();
+        username_available(
+            &UserId::parse_with_server_name(localpart, "example.org".try_into().unwrap()).unwrap(),
+            taken,
+            &forbidden,
+            &appservice::exclusive_user_regexes(&registrations()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn default_displayname_fills_in_the_localpart() {
+        assert_eq!(default_displayname("{localpart}", "alice"), "alice");
+        assert_eq!(
+            default_displayname("{localpart} (Example Corp)", "alice"),
+            "alice (Example Corp)"
+        );
+        assert_eq!(default_displayname("New user", "alice"), "New user");
+    }
+
+    #[tokio::test]
+    async fn registered_users_get_the_default_profile() {
+        testing::services_for_tests().await;
+
+        let mut request = register::v3::Request::new();
+        request.username = Some("defaultprofile".to_owned());
+        request.password = Some("password".to_owned());
+        request.auth = Some(AuthData::Dummy(Dummy::new()));
+        let user_id = register_route(testing::unauthenticated_request(request))
+            .await
+            .unwrap()
+            .user_id;
+
+        // The test config sets the defaults and turns the lightning bolt off
+        let profile = get_profile_route(testing::unauthenticated_request(
+            get_profile::v3::Request::new(user_id),
+        ))
+        .await
+        .unwrap()
+        .0;
+        assert_eq!(profile["displayname"], "defaultprofile (Test)");
+        assert_eq!(profile["avatar_url"], "mxc://conduit.test/default");
+    }
+
+    #[test]
+    fn unavailable_usernames_are_rejected() {
+        assert!(available("alice", false).is_ok());
+        assert!(available("bridged_alice", false).is_ok());
+        assert!(available("systems", false).is_ok());
+
+        assert!(matches!(
+            available("alice", true),
+            Err(Error::BadRequest(ErrorKind::UserInUse, _))
+        ));
+        assert!(matches!(
+            available("administrator", false),
+            Err(Error::BadRequest(ErrorKind::InvalidUsername, _))
+        ));
+        assert!(matches!(
+            available("system", false),
+            Err(Error::BadRequest(ErrorKind::InvalidUsername, _))
+        ));
+        assert!(matches!(
+            available("irc_alice", false),
+            Err(Error::BadRequest(ErrorKind::Exclusive, _))
+        ));
+    }
 }
@@ -18,7 +18,10 @@ use ruma::{
 pub async fn create_alias_route(
     body: Ruma<create_alias::v3::Request>,
 ) -> Result<create_alias::v3::Response> {
-    if body.room_alias.server_name() != services().globals.server_name() {
+    if !services()
+        .globals
+        .server_is_ours(body.room_alias.server_name())
+    {
         return Err(Error::BadRequest(
             ErrorKind::InvalidParam,
             "Alias is from another server.",
@@ -51,7 +54,10 @@ pub async fn create_alias_route(
 pub async fn delete_alias_route(
     body: Ruma<delete_alias::v3::Request>,
 ) -> Result<delete_alias::v3::Response> {
-    if body.room_alias.server_name() != services().globals.server_name() {
+    if !services()
+        .globals
+        .server_is_ours(body.room_alias.server_name())
+    {
         return Err(Error::BadRequest(
             ErrorKind::InvalidParam,
             "Alias is from another server.",
@@ -79,7 +85,7 @@ pub async fn get_alias_route(
 pub(crate) async fn get_alias_helper(
     room_alias: OwnedRoomAliasId,
 ) -> Result<get_alias::v3::Response> {
-    if room_alias.server_name() != services().globals.server_name() {
+    if !services().globals.server_is_ours(room_alias.server_name()) {
         let response = services()
             .sending
             .send_federation_request(
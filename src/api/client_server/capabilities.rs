@@ -1,12 +1,15 @@
-use crate::{services, Result, Ruma};
+use crate::{services, Config, Result, Ruma};
 use ruma::api::client::discovery::get_capabilities::{
     self, Capabilities, RoomVersionStability, RoomVersionsCapability,
 };
+use serde_json::json;
 use std::collections::BTreeMap;
 
 /// # `GET /_matrix/client/r0/capabilities`
 ///
 /// Get information on the supported feature set and other relevent capabilities of this server.
+///
+/// - Account changes that are disabled in the config are reported as disabled
 pub async fn get_capabilities_route(
     _body: Ruma<get_capabilities::v3::Request>,
 ) -> Result<get_capabilities::v3::Response> {
@@ -24,5 +27,61 @@ pub async fn get_capabilities_route(
         available,
     };
 
+    set_account_changes(&mut capabilities, &services().globals.config);
+    set_enabled(&mut capabilities, "m.get_login_token", true);
+
     Ok(get_capabilities::v3::Response { capabilities })
 }
+
+/// Reports the account changes the config allows.
+fn set_account_changes(capabilities: &mut Capabilities, config: &Config) {
+    set_enabled(
+        capabilities,
+        "m.change_password",
+        config.allow_password_change,
+    );
+    set_enabled(
+        capabilities,
+        "m.set_displayname",
+        config.allow_displayname_change,
+    );
+    set_enabled(capabilities, "m.set_avatar_url", config.allow_avatar_change);
+    set_enabled(capabilities, "m.3pid_changes", config.allow_3pid_changes);
+}
+
+fn set_enabled(capabilities: &mut Capabilities, capability: &str, enabled: bool) {
+    capabilities
+        .set(capability, json!({ "enabled": enabled }))
+        .expect("capabilities with an enabled flag are valid");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn disabled_account_changes_are_reported() {
+        let config: Config = serde_json::from_value(json!({
+            "server_name": "example.org",
+            "database_path": "/var/lib/matrix-conduit/",
+            "allow_password_change": false,
+        }))
+        .unwrap();
+
+        let mut capabilities = Capabilities::new();
+        set_account_changes(&mut capabilities, &config);
+
+        let capabilities = serde_json::to_value(&capabilities).unwrap();
+        assert_eq!(
+            capabilities["m.change_password"],
+            json!({ "enabled": false })
+        );
+        // The other changes are allowed by default
+        assert_eq!(
+            capabilities["m.set_displayname"],
+            json!({ "enabled": true })
+        );
+        assert_eq!(capabilities["m.set_avatar_url"], json!({ "enabled": true }));
+        assert_eq!(capabilities["m.3pid_changes"], json!({ "enabled": true }));
+    }
+}
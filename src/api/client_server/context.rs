@@ -1,7 +1,12 @@
-use crate::{services, Error, Result, Ruma};
+use crate::{services, Error, PduEvent, Result, Ruma};
 use ruma::{
-    api::client::{context::get_context, error::ErrorKind, filter::LazyLoadOptions},
+    api::client::{
+        context::get_context,
+        error::ErrorKind,
+        filter::{LazyLoadOptions, RoomEventFilter},
+    },
     events::StateEventType,
+    UserId,
 };
 use std::{collections::HashSet, convert::TryFrom};
 use tracing::error;
@@ -10,8 +15,9 @@ use tracing::error;
 ///
 /// Allows loading room history around an event.
 ///
-/// - Only works if the user is joined (TODO: always allow, but only show events if the user was
-/// joined, depending on history_visibility)
+/// - Only works if the user is allowed to see the event according to the history visibility
+/// - Only returns surrounding events the user is allowed to see and that match the filter
+/// - The state is the state of the room right before the requested event
 pub async fn get_context_route(
     body: Ruma<get_context::v3::Request>,
 ) -> Result<get_context::v3::Response> {
@@ -51,15 +57,24 @@ pub async fn get_context_route(
 
     if !services()
         .rooms
-        .state_cache
-        .is_joined(sender_user, &room_id)?
+        .state_accessor
+        .user_can_see_event(sender_user, &room_id, &body.event_id)?
     {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
-            "You don't have permission to view this room.",
+            "You don't have permission to view this event.",
         ));
     }
 
+    let can_see = |pdu: &PduEvent| {
+        matches_filter(&body.filter, &pdu.sender, &pdu.kind.to_string())
+            && services()
+                .rooms
+                .state_accessor
+                .user_can_see_event(sender_user, &room_id, &pdu.event_id)
+                .unwrap_or(false)
+    };
+
     if !services().rooms.lazy_loading.lazy_load_was_sent_before(
         sender_user,
         sender_device,
@@ -85,6 +100,16 @@ pub async fn get_context_route(
         .filter_map(|r| r.ok()) // Remove buggy events
         .collect();
 
+    let start_token = events_before
+        .last()
+        .and_then(|(pdu_id, _)| services().rooms.timeline.pdu_count(pdu_id).ok())
+        .map(|count| count.to_string());
+
+    let events_before: Vec<_> = events_before
+        .into_iter()
+        .filter(|(_, pdu)| can_see(pdu))
+        .collect();
+
     for (_, event) in &events_before {
         if !services().rooms.lazy_loading.lazy_load_was_sent_before(
             sender_user,
@@ -97,11 +122,6 @@ pub async fn get_context_route(
         }
     }
 
-    let start_token = events_before
-        .last()
-        .and_then(|(pdu_id, _)| services().rooms.timeline.pdu_count(pdu_id).ok())
-        .map(|count| count.to_string());
-
     let events_before: Vec<_> = events_before
         .into_iter()
         .map(|(_, pdu)| pdu.to_room_event())
@@ -120,6 +140,16 @@ pub async fn get_context_route(
         .filter_map(|r| r.ok()) // Remove buggy events
         .collect();
 
+    let end_token = events_after
+        .last()
+        .and_then(|(pdu_id, _)| services().rooms.timeline.pdu_count(pdu_id).ok())
+        .map(|count| count.to_string());
+
+    let events_after: Vec<_> = events_after
+        .into_iter()
+        .filter(|(_, pdu)| can_see(pdu))
+        .collect();
+
     for (_, event) in &events_after {
         if !services().rooms.lazy_loading.lazy_load_was_sent_before(
             sender_user,
@@ -132,17 +162,21 @@ pub async fn get_context_route(
         }
     }
 
-    let shortstatehash = match services().rooms.state_accessor.pdu_shortstatehash(
-        events_after
-            .last()
-            .map_or(&*body.event_id, |(_, e)| &*e.event_id),
-    )? {
+    // Members that joined after the event must not show up in its context
+    let shortstatehash = match services()
+        .rooms
+        .state_accessor
+        .pdu_shortstatehash(&body.event_id)?
+    {
         Some(s) => s,
-        None => services()
-            .rooms
-            .state
-            .get_room_shortstatehash(&room_id)?
-            .expect("All rooms have state"),
+        None => {
+            error!("Event {} has no state", body.event_id);
+            services()
+                .rooms
+                .state
+                .get_room_shortstatehash(&room_id)?
+                .expect("All rooms have state")
+        }
     };
 
     let state_ids = services()
@@ -151,11 +185,6 @@ pub async fn get_context_route(
         .state_full_ids(shortstatehash)
         .await?;
 
-    let end_token = events_after
-        .last()
-        .and_then(|(pdu_id, _)| services().rooms.timeline.pdu_count(pdu_id).ok())
-        .map(|count| count.to_string());
-
     let events_after: Vec<_> = events_after
         .into_iter()
         .map(|(_, pdu)| pdu.to_room_event())
@@ -201,3 +230,89 @@ pub async fn get_context_route(
 
     Ok(resp)
 }
+
+/// Checks the `types`, `not_types`, `senders` and `not_senders` fields of a filter. Types can end
+/// with a `*` wildcard.
+fn matches_filter(filter: &RoomEventFilter, sender: &UserId, event_type: &str) -> bool {
+    let type_matches = |pattern: &String| match pattern.strip_suffix('*') {
+        Some(prefix) => event_type.starts_with(prefix),
+        None => event_type == pattern,
+    };
+
+    filter
+        .types
+        .as_ref()
+        .map_or(true, |types| types.iter().any(type_matches))
+        && !filter.not_types.iter().any(type_matches)
+        && filter
+            .senders
+            .as_ref()
+            .map_or(true, |senders| senders.iter().any(|s| s == sender))
+        && !filter.not_senders.iter().any(|s| s == sender)
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::{api::client::room::create_room, room::RoomPreset, user_id};
+
+    use super::*;
+    use crate::utils::testing;
+
+    #[test]
+    fn filter_by_type_and_sender() {
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+
+        let mut filter = RoomEventFilter::default();
+        assert!(matches_filter(&filter, alice, "m.room.message"));
+
+        filter.types = Some(vec!["m.room.*".to_owned()]);
+        filter.not_types = vec!["m.room.member".to_owned()];
+        filter.not_senders = vec![bob.to_owned()];
+        assert!(matches_filter(&filter, alice, "m.room.message"));
+        assert!(!matches_filter(&filter, alice, "m.room.member"));
+        assert!(!matches_filter(&filter, alice, "m.reaction"));
+        assert!(!matches_filter(&filter, bob, "m.room.message"));
+    }
+
+    #[tokio::test]
+    async fn members_that_joined_later_are_not_in_the_context_state() {
+        let alice = testing::user("context_alice").await;
+        let bob = testing::user("context_bob").await;
+        let room_id = testing::room_with(
+            &alice,
+            create_room::v3::Request {
+                preset: Some(RoomPreset::PublicChat),
+                ..create_room::v3::Request::new()
+            },
+        )
+        .await;
+        let message = testing::send_message(&alice, &room_id, "before bob").await;
+        testing::join(&bob, &room_id).await;
+
+        let context = get_context_route(testing::request(
+            get_context::v3::Request::new(room_id.clone(), message.clone()),
+            &alice,
+        ))
+        .await
+        .unwrap();
+        let members: Vec<_> = context
+            .state
+            .iter()
+            .filter(|event| {
+                event.get_field::<String>("type").unwrap().as_deref() == Some("m.room.member")
+            })
+            .filter_map(|event| event.get_field::<String>("state_key").unwrap())
+            .collect();
+
+        assert_eq!(
+            context
+                .event
+                .unwrap()
+                .get_field::<String>("event_id")
+                .unwrap(),
+            Some(message.to_string())
+        );
+        assert_eq!(members, [alice.to_string()]);
+    }
+}
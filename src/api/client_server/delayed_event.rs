@@ -0,0 +1,159 @@
+use axum::{
+    extract::{Path, Query},
+    Json,
+};
+use ruma::{api::client::error::ErrorKind, RoomId, UserId};
+use serde::Deserialize;
+use serde_json::{json, value::to_raw_value, Map, Value};
+
+use crate::{
+    api::ruma_wrapper::SenderUser,
+    service::delayed_events::{Delay, DelayedEventAction},
+    services, Error, Result,
+};
+
+#[derive(Deserialize)]
+pub struct DelayParams {
+    /// In milliseconds.
+    delay: Option<u64>,
+    parent_delay_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateDelayedEventBody {
+    action: DelayedEventAction,
+}
+
+/// # `PUT /_matrix/client/unstable/org.matrix.msc4140/rooms/{roomId}/send/{eventType}/{txnId}`
+///
+/// Schedules a message event to be sent after a delay, or together with its parent (MSC4140).
+///
+/// - Returns the delay id, which the sender uses to restart, cancel or send the event
+/// - Retries with the same transaction id return the same delay id until the event was sent
+pub async fn send_delayed_message_event_route(
+    SenderUser(sender_user): SenderUser,
+    Path((room_id, event_type, txn_id)): Path<(String, String, String)>,
+    Query(params): Query<DelayParams>,
+    Json(content): Json<Map<String, Value>>,
+) -> Result<Json<Value>> {
+    schedule(
+        &sender_user,
+        &room_id,
+        &event_type,
+        None,
+        content,
+        &params,
+        Some(&txn_id),
+    )
+}
+
+/// # `PUT /_matrix/client/unstable/org.matrix.msc4140/rooms/{roomId}/state/{eventType}/{stateKey}`
+///
+/// Schedules a state event to be sent after a delay, or together with its parent (MSC4140).
+///
+/// - The content is validated now and again when the event is sent
+pub async fn send_delayed_state_event_route(
+    SenderUser(sender_user): SenderUser,
+    Path((room_id, event_type, state_key)): Path<(String, String, String)>,
+    Query(params): Query<DelayParams>,
+    Json(content): Json<Map<String, Value>>,
+) -> Result<Json<Value>> {
+    schedule(
+        &sender_user,
+        &room_id,
+        &event_type,
+        Some(&state_key),
+        content,
+        &params,
+        None,
+    )
+}
+
+/// # `PUT /_matrix/client/unstable/org.matrix.msc4140/rooms/{roomId}/state/{eventType}`
+///
+/// Schedules a state event with an empty state key, see `send_delayed_state_event_route`.
+pub async fn send_delayed_state_event_for_empty_key_route(
+    SenderUser(sender_user): SenderUser,
+    Path((room_id, event_type)): Path<(String, String)>,
+    Query(params): Query<DelayParams>,
+    Json(content): Json<Map<String, Value>>,
+) -> Result<Json<Value>> {
+    schedule(
+        &sender_user,
+        &room_id,
+        &event_type,
+        Some(""),
+        content,
+        &params,
+        None,
+    )
+}
+
+/// # `GET /_matrix/client/unstable/org.matrix.msc4140/delayed_events`
+///
+/// Lists the delayed events of the sender user that were not sent yet.
+pub async fn get_delayed_events_route(SenderUser(sender_user): SenderUser) -> Result<Json<Value>> {
+    let delayed_events = services().delayed_events.delayed_events(&sender_user)?;
+
+    Ok(Json(json!({ "delayed_events": delayed_events })))
+}
+
+/// # `POST /_matrix/client/unstable/org.matrix.msc4140/delayed_events/{delayId}`
+///
+/// Cancels, restarts or immediately sends a delayed event of the sender user.
+///
+/// - Cancelling or sending an event with children does the same for its children
+/// - Only events with a delay of their own can be restarted
+pub async fn update_delayed_event_route(
+    SenderUser(sender_user): SenderUser,
+    Path(delay_id): Path<String>,
+    Json(body): Json<UpdateDelayedEventBody>,
+) -> Result<Json<Value>> {
+    services()
+        .delayed_events
+        .act(&sender_user, &delay_id, body.action)
+        .await?;
+
+    Ok(Json(json!({})))
+}
+
+fn schedule(
+    sender_user: &UserId,
+    room_id: &str,
+    event_type: &str,
+    state_key: Option<&str>,
+    content: Map<String, Value>,
+    params: &DelayParams,
+    txn_id: Option<&str>,
+) -> Result<Json<Value>> {
+    let room_id = <&RoomId>::try_from(room_id)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid room id."))?;
+
+    let delay_id = services().delayed_events.schedule(
+        sender_user,
+        room_id,
+        event_type,
+        state_key,
+        to_raw_value(&content).expect("JSON objects can be serialized"),
+        delay(params)?,
+        txn_id,
+    )?;
+
+    Ok(Json(json!({ "delay_id": delay_id })))
+}
+
+/// A delayed event either has a delay or a parent, never both.
+fn delay(params: &DelayParams) -> Result<Delay<'_>> {
+    match (params.delay, params.parent_delay_id.as_deref()) {
+        (Some(delay), None) => Ok(Delay::Timeout(delay)),
+        (None, Some(parent_delay_id)) => Ok(Delay::Parent(parent_delay_id)),
+        (Some(_), Some(_)) => Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Only one of delay and parent_delay_id can be given.",
+        )),
+        (None, None) => Err(Error::BadRequest(
+            ErrorKind::MissingParam,
+            "Either delay or parent_delay_id has to be given.",
+        )),
+    }
+}
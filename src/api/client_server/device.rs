@@ -89,10 +89,10 @@ pub async fn delete_device_route(
     };
 
     if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) =
-            services()
-                .uiaa
-                .try_auth(sender_user, sender_device, auth, &uiaainfo)?;
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
@@ -109,19 +109,19 @@ pub async fn delete_device_route(
 
     services()
         .users
-        .remove_device(sender_user, &body.device_id)?;
+        .remove_devices(sender_user, &[body.device_id.clone()])?;
 
     Ok(delete_device::v3::Response {})
 }
 
-/// # `PUT /_matrix/client/r0/devices/{deviceId}`
+/// # `POST /_matrix/client/r0/delete_devices`
 ///
-/// Deletes the given device.
+/// Deletes the given devices.
 ///
-/// - Requires UIAA to verify user password
+/// - Requires UIAA to verify user password, once for all devices
+/// - Invalidates the access tokens of all devices before anything else
 ///
 /// For each device:
-/// - Invalidates access token
 /// - Deletes device metadata (device id, device display name, last seen ip, last seen ts)
 /// - Forgets to-device events
 /// - Triggers device list updates
@@ -143,10 +143,10 @@ pub async fn delete_devices_route(
     };
 
     if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) =
-            services()
-                .uiaa
-                .try_auth(sender_user, sender_device, auth, &uiaainfo)?;
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
@@ -161,9 +161,115 @@ pub async fn delete_devices_route(
         return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
     }
 
-    for device_id in &body.devices {
-        services().users.remove_device(sender_user, device_id)?
-    }
+    services()
+        .users
+        .remove_devices(sender_user, &body.devices)?;
 
     Ok(delete_devices::v3::Response {})
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use ruma::MilliSecondsSinceUnixEpoch;
+
+    use super::*;
+    use crate::utils::testing;
+
+    #[tokio::test]
+    async fn requests_update_last_seen_and_the_display_name_is_shown() {
+        let alice = testing::user("last_seen_alice").await;
+        let device_id = testing::device_id();
+        let before = MilliSecondsSinceUnixEpoch::now();
+        let ip: IpAddr = "192.0.2.7".parse().unwrap();
+
+        let request = testing::extract::<get_device::v3::Request>(
+            "/_matrix/client/v3/devices/:device_id",
+            testing::client_request(
+                http::Method::GET,
+                &format!("/_matrix/client/v3/devices/{device_id}"),
+                &testing::access_token("last_seen_alice"),
+                serde_json::json!({}),
+            ),
+            Some(ip),
+        )
+        .await
+        .unwrap();
+        let device = get_device_route(request).await.unwrap().device;
+        assert_eq!(device.last_seen_ip.as_deref(), Some("192.0.2.7"));
+        assert!(device.last_seen_ts.unwrap() >= before);
+
+        let mut update = update_device::v3::Request::new(device_id.clone());
+        update.display_name = Some("Laptop".to_owned());
+        update_device_route(testing::request(update, &alice))
+            .await
+            .unwrap();
+
+        let devices = get_devices_route(testing::request(get_devices::v3::Request::new(), &alice))
+            .await
+            .unwrap()
+            .devices;
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].device_id, device_id);
+        assert_eq!(devices[0].display_name.as_deref(), Some("Laptop"));
+        assert_eq!(devices[0].last_seen_ip.as_deref(), Some("192.0.2.7"));
+    }
+
+    #[tokio::test]
+    async fn deleting_devices_revokes_all_their_tokens() {
+        let alice = testing::user("delete_devices_alice").await;
+        for (device_id, token) in [("PHONE", "phone_token"), ("TABLET", "tablet_token")] {
+            services()
+                .users
+                .create_device(&alice, device_id.into(), token, None)
+                .unwrap();
+        }
+        let whoami = |token: &str| {
+            testing::extract::<ruma::api::client::account::whoami::v3::Request>(
+                "/_matrix/client/v3/account/whoami",
+                testing::client_request(
+                    http::Method::GET,
+                    "/_matrix/client/v3/account/whoami",
+                    token,
+                    serde_json::json!({}),
+                ),
+                None,
+            )
+        };
+        assert!(whoami("phone_token").await.is_ok());
+
+        // One password check for both devices
+        let request = testing::extract::<delete_devices::v3::Request>(
+            "/_matrix/client/v3/delete_devices",
+            testing::client_request(
+                http::Method::POST,
+                "/_matrix/client/v3/delete_devices",
+                &testing::access_token("delete_devices_alice"),
+                serde_json::json!({
+                    "devices": ["PHONE", "TABLET"],
+                    "auth": {
+                        "type": "m.login.password",
+                        "identifier": { "type": "m.id.user", "user": alice },
+                        "password": "password",
+                    },
+                }),
+            ),
+            None,
+        )
+        .await
+        .unwrap();
+        delete_devices_route(request).await.unwrap();
+
+        for token in ["phone_token", "tablet_token"] {
+            assert_eq!(
+                whoami(token).await.unwrap_err()["errcode"],
+                "M_UNKNOWN_TOKEN"
+            );
+        }
+        assert!(whoami(&testing::access_token("delete_devices_alice"))
+            .await
+            .is_ok());
+        assert_eq!(services().users.all_device_ids(&alice).count(), 1);
+    }
+}
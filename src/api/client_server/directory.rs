@@ -25,7 +25,7 @@ use ruma::{
         },
         StateEventType,
     },
-    ServerName, UInt,
+    ServerName, UInt, UserId,
 };
 use tracing::{error, info, warn};
 
@@ -55,6 +55,18 @@ pub async fn get_public_rooms_filtered_route(
 pub async fn get_public_rooms_route(
     body: Ruma<get_public_rooms::v3::Request>,
 ) -> Result<get_public_rooms::v3::Response> {
+    if !services()
+        .globals
+        .allow_public_room_directory_without_auth()
+        && body.sender_user.is_none()
+        && !body.from_appservice
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::MissingToken,
+            "The room directory is only available to logged in users.",
+        ));
+    }
+
     let response = get_public_rooms_filtered_helper(
         body.server.as_deref(),
         body.limit,
@@ -76,6 +88,7 @@ pub async fn get_public_rooms_route(
 ///
 /// Sets the visibility of a given room in the room directory.
 ///
+/// - Only admins can publish rooms if `room_list_publication_requires_admin` is set
 /// - TODO: Access control checks
 pub async fn set_room_visibility_route(
     body: Ruma<set_room_visibility::v3::Request>,
@@ -89,6 +102,13 @@ pub async fn set_room_visibility_route(
 
     match &body.visibility {
         room::Visibility::Public => {
+            if !can_publish_rooms(sender_user)? {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "Only server admins can publish rooms to the room directory.",
+                ));
+            }
+
             services().rooms.directory.set_public(&body.room_id)?;
             info!("{} made {} public", sender_user, body.room_id);
         }
@@ -104,6 +124,12 @@ pub async fn set_room_visibility_route(
     Ok(set_room_visibility::v3::Response {})
 }
 
+/// Whether the user may publish rooms to the room directory of this server.
+pub(crate) fn can_publish_rooms(user_id: &UserId) -> Result<bool> {
+    Ok(!services().globals.room_list_publication_requires_admin()
+        || services().users.is_admin(user_id)?)
+}
+
 /// # `GET /_matrix/client/r0/directory/list/room/{roomId}`
 ///
 /// Gets the visibility of a given room in the room directory.
@@ -131,9 +157,7 @@ pub(crate) async fn get_public_rooms_filtered_helper(
     filter: &Filter,
     _network: &RoomNetwork,
 ) -> Result<get_public_rooms_filtered::v3::Response> {
-    if let Some(other_server) =
-        server.filter(|server| *server != services().globals.server_name().as_str())
-    {
+    if let Some(other_server) = server.filter(|server| !services().globals.server_is_ours(server)) {
         let response = services()
             .sending
             .send_federation_request(
@@ -377,3 +401,74 @@ pub(crate) async fn get_public_rooms_filtered_helper(
         total_room_count_estimate: Some(total_room_count_estimate),
     })
 }
+
+#[cfg(test)]
+mod test {
+    use ruma::api::client::room::create_room;
+
+    use super::*;
+    use crate::{api::client_server::create_room_route, utils::testing};
+
+    #[tokio::test]
+    async fn only_admins_publish_rooms_if_configured() {
+        // The test config sets room_list_publication_requires_admin
+        let alice = testing::user("directory_alice").await;
+        let admin = testing::admin("directory_admin").await;
+        let room_id = testing::room(&alice).await;
+
+        let set_visibility = |user_id: &UserId, visibility: room::Visibility| {
+            set_room_visibility_route(testing::request(
+                set_room_visibility::v3::Request::new(room_id.clone(), visibility),
+                user_id,
+            ))
+        };
+        let visibility = || async {
+            get_room_visibility_route(testing::unauthenticated_request(
+                get_room_visibility::v3::Request::new(room_id.clone()),
+            ))
+            .await
+            .unwrap()
+            .visibility
+        };
+
+        assert!(matches!(
+            set_visibility(&alice, room::Visibility::Public).await,
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+        assert_eq!(visibility().await, room::Visibility::Private);
+
+        set_visibility(&admin, room::Visibility::Public)
+            .await
+            .unwrap();
+        assert_eq!(visibility().await, room::Visibility::Public);
+
+        // Anyone who may change the room's visibility can still unpublish it
+        set_visibility(&alice, room::Visibility::Private)
+            .await
+            .unwrap();
+        assert_eq!(visibility().await, room::Visibility::Private);
+
+        let create_public = |user_id: &UserId| {
+            create_room_route(testing::request(
+                create_room::v3::Request {
+                    visibility: room::Visibility::Public,
+                    ..create_room::v3::Request::new()
+                },
+                user_id,
+            ))
+        };
+        assert!(matches!(
+            create_public(&alice).await,
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+
+        let room_id = create_public(&admin).await.unwrap().room_id;
+        let response = get_public_rooms_route(testing::request(
+            get_public_rooms::v3::Request::new(),
+            &alice,
+        ))
+        .await
+        .unwrap();
+        assert!(response.chunk.iter().any(|room| room.room_id == room_id));
+    }
+}
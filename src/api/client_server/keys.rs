@@ -13,11 +13,17 @@ use ruma::{
         },
         federation,
     },
+    encryption::CrossSigningKey,
     serde::Raw,
-    DeviceKeyAlgorithm, OwnedDeviceId, OwnedUserId, UserId,
+    CanonicalJsonValue, DeviceKeyAlgorithm, OwnedDeviceId, OwnedUserId, UserId,
 };
 use serde_json::json;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    future::Future,
+    time::Duration,
+};
+use tracing::warn;
 
 /// # `POST /_matrix/client/r0/keys/upload`
 ///
@@ -90,12 +96,18 @@ pub async fn claim_keys_route(
 /// Uploads end-to-end key information for the sender user.
 ///
 /// - Requires UIAA to verify password
+/// - Re-uploading the keys that are already stored is accepted without UIAA, because it doesn't
+/// change anything
 pub async fn upload_signing_keys_route(
     body: Ruma<upload_signing_keys::v3::Request>,
 ) -> Result<upload_signing_keys::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
     let sender_device = body.sender_device.as_ref().expect("user is authenticated");
 
+    if is_signing_keys_reupload(sender_user, &body)? {
+        return Ok(upload_signing_keys::v3::Response {});
+    }
+
     // UIAA
     let mut uiaainfo = UiaaInfo {
         flows: vec![AuthFlow {
@@ -108,10 +120,10 @@ pub async fn upload_signing_keys_route(
     };
 
     if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) =
-            services()
-                .uiaa
-                .try_auth(sender_user, sender_device, auth, &uiaainfo)?;
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
@@ -138,6 +150,56 @@ pub async fn upload_signing_keys_route(
     Ok(upload_signing_keys::v3::Response {})
 }
 
+/// Checks if the request only contains cross-signing keys the user already has.
+fn is_signing_keys_reupload(
+    sender_user: &UserId,
+    body: &upload_signing_keys::v3::Request,
+) -> Result<bool> {
+    let master_key = match &body.master_key {
+        Some(master_key) => master_key,
+        None => return Ok(false),
+    };
+
+    let stored_master_key = services().users.get_master_key(sender_user, &|_| false)?;
+    if !stored_master_key.map_or(false, |stored| same_cross_signing_key(master_key, &stored)) {
+        return Ok(false);
+    }
+
+    if let Some(self_signing_key) = &body.self_signing_key {
+        let stored = services()
+            .users
+            .get_self_signing_key(sender_user, &|_| false)?;
+        if !stored.map_or(false, |stored| {
+            same_cross_signing_key(self_signing_key, &stored)
+        }) {
+            return Ok(false);
+        }
+    }
+
+    if let Some(user_signing_key) = &body.user_signing_key {
+        let stored = services().users.get_user_signing_key(sender_user)?;
+        if !stored.map_or(false, |stored| {
+            same_cross_signing_key(user_signing_key, &stored)
+        }) {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Compares two cross-signing keys as canonical JSON, including their signatures. Only an exact
+/// re-upload of a stored key can skip UIA, anything else could be a key swapped in by someone who
+/// only has the access token.
+fn same_cross_signing_key(a: &Raw<CrossSigningKey>, b: &Raw<CrossSigningKey>) -> bool {
+    let canonical = |key: &Raw<CrossSigningKey>| key.deserialize_as::<CanonicalJsonValue>().ok();
+
+    match (canonical(a), canonical(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
 /// # `POST /_matrix/client/r0/keys/signatures/upload`
 ///
 /// Uploads end-to-end key signatures from the sender user.
@@ -277,7 +339,7 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool>(
     for (user_id, device_ids) in device_keys_input {
         let user_id: &UserId = user_id;
 
-        if user_id.server_name() != services().globals.server_name() {
+        if !services().globals.server_is_ours(user_id.server_name()) {
             get_over_federation
                 .entry(user_id.server_name())
                 .or_insert_with(Vec::new)
@@ -342,41 +404,31 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool>(
         }
     }
 
-    let mut failures = BTreeMap::new();
-
-    let mut futures: FuturesUnordered<_> = get_over_federation
-        .into_iter()
-        .map(|(server, vec)| async move {
+    let (responses, failures) = query_servers(
+        KeysOperation::Query,
+        get_over_federation.into_iter().map(|(server, vec)| {
             let mut device_keys_input_fed = BTreeMap::new();
             for (user_id, keys) in vec {
                 device_keys_input_fed.insert(user_id.to_owned(), keys.clone());
             }
             (
                 server,
-                services()
-                    .sending
-                    .send_federation_request(
-                        server,
-                        federation::keys::get_keys::v1::Request {
-                            device_keys: device_keys_input_fed,
-                        },
-                    )
-                    .await,
+                services().sending.send_federation_request(
+                    server,
+                    federation::keys::get_keys::v1::Request {
+                        device_keys: device_keys_input_fed,
+                    },
+                ),
             )
-        })
-        .collect();
-
-    while let Some((server, response)) = futures.next().await {
-        match response {
-            Ok(response) => {
-                master_keys.extend(response.master_keys);
-                self_signing_keys.extend(response.self_signing_keys);
-                device_keys.extend(response.device_keys);
-            }
-            Err(_e) => {
-                failures.insert(server.to_string(), json!({}));
-            }
-        }
+        }),
+        federation_keys_timeout(),
+    )
+    .await;
+
+    for response in responses {
+        master_keys.extend(response.master_keys);
+        self_signing_keys.extend(response.self_signing_keys);
+        device_keys.extend(response.device_keys);
     }
 
     Ok(get_keys::v3::Response {
@@ -388,6 +440,72 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool>(
     })
 }
 
+fn federation_keys_timeout() -> Duration {
+    Duration::from_secs(services().globals.config.federation_keys_timeout_secs)
+}
+
+/// What `query_servers` asks the servers for, used in its logs.
+#[derive(Clone, Copy)]
+enum KeysOperation {
+    Query,
+    Claim,
+}
+
+impl std::fmt::Display for KeysOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            KeysOperation::Query => "query keys",
+            KeysOperation::Claim => "claim one-time keys",
+        })
+    }
+}
+
+/// Sends one request per server, all concurrently, and waits at most `timeout` for each. A slow
+/// server only affects its own users: returns the responses of the servers that answered in time
+/// and the `failures` map entries of all others.
+async fn query_servers<S, T, F>(
+    operation: KeysOperation,
+    requests: impl IntoIterator<Item = (S, F)>,
+    timeout: Duration,
+) -> (Vec<T>, BTreeMap<String, serde_json::Value>)
+where
+    S: std::fmt::Display,
+    F: Future<Output = Result<T>>,
+{
+    let mut futures: FuturesUnordered<_> =
+        requests
+            .into_iter()
+            .map(|(server, request)| async move {
+                (server, tokio::time::timeout(timeout, request).await)
+            })
+            .collect();
+
+    let mut responses = Vec::new();
+    let mut failures = BTreeMap::new();
+
+    while let Some((server, response)) = futures.next().await {
+        match response {
+            Ok(Ok(response)) => responses.push(response),
+            Ok(Err(e)) => {
+                warn!("Failed to {} from {}: {}", operation, server, e);
+                failures.insert(server.to_string(), json!({}));
+            }
+            Err(_) => {
+                warn!("Timed out trying to {} from {}", operation, server);
+                failures.insert(
+                    server.to_string(),
+                    json!({
+                        "status": 504,
+                        "message": "Timed out waiting for remote server"
+                    }),
+                );
+            }
+        }
+    }
+
+    (responses, failures)
+}
+
 fn add_unsigned_device_display_name(
     keys: &mut Raw<ruma::encryption::DeviceKeys>,
     metadata: ruma::api::client::device::Device,
@@ -414,7 +532,7 @@ pub(crate) async fn claim_keys_helper(
     let mut get_over_federation = BTreeMap::new();
 
     for (user_id, map) in one_time_keys_input {
-        if user_id.server_name() != services().globals.server_name() {
+        if !services().globals.server_is_ours(user_id.server_name()) {
             get_over_federation
                 .entry(user_id.server_name())
                 .or_insert_with(Vec::new)
@@ -436,39 +554,29 @@ pub(crate) async fn claim_keys_helper(
         one_time_keys.insert(user_id.clone(), container);
     }
 
-    let mut failures = BTreeMap::new();
-
-    let mut futures: FuturesUnordered<_> = get_over_federation
-        .into_iter()
-        .map(|(server, vec)| async move {
+    let (responses, failures) = query_servers(
+        KeysOperation::Claim,
+        get_over_federation.into_iter().map(|(server, vec)| {
             let mut one_time_keys_input_fed = BTreeMap::new();
             for (user_id, keys) in vec {
                 one_time_keys_input_fed.insert(user_id.clone(), keys.clone());
             }
             (
                 server,
-                services()
-                    .sending
-                    .send_federation_request(
-                        server,
-                        federation::keys::claim_keys::v1::Request {
-                            one_time_keys: one_time_keys_input_fed,
-                        },
-                    )
-                    .await,
+                services().sending.send_federation_request(
+                    server,
+                    federation::keys::claim_keys::v1::Request {
+                        one_time_keys: one_time_keys_input_fed,
+                    },
+                ),
             )
-        })
-        .collect();
+        }),
+        federation_keys_timeout(),
+    )
+    .await;
 
-    while let Some((server, response)) = futures.next().await {
-        match response {
-            Ok(keys) => {
-                one_time_keys.extend(keys.one_time_keys);
-            }
-            Err(_e) => {
-                failures.insert(server.to_string(), json!({}));
-            }
-        }
+    for keys in responses {
+        one_time_keys.extend(keys.one_time_keys);
     }
 
     Ok(claim_keys::v3::Response {
@@ -476,3 +584,144 @@ pub(crate) async fn claim_keys_helper(
         one_time_keys,
     })
 }
+
+#[cfg(test)]
+mod test {
+    use ruma::{
+        api::client::uiaa::{AuthData, Password, UserIdentifier},
+        user_id, CanonicalJsonObject,
+    };
+
+    use super::*;
+    use crate::utils::testing;
+
+    fn key(public_key: &str, signature: &str) -> Raw<CrossSigningKey> {
+        user_key(user_id!("@alice:example.org"), public_key, signature)
+    }
+
+    fn user_key(user_id: &UserId, public_key: &str, signature: &str) -> Raw<CrossSigningKey> {
+        Raw::from_json(
+            serde_json::value::to_raw_value(&json!({
+                "user_id": user_id,
+                "usage": ["master"],
+                "keys": { format!("ed25519:{public_key}"): public_key },
+                "signatures": {
+                    user_id.as_str(): { "ed25519:DEVICE": signature }
+                }
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn upload(
+        user_id: &UserId,
+        master_key: Raw<CrossSigningKey>,
+        auth: Option<AuthData>,
+    ) -> Ruma<upload_signing_keys::v3::Request> {
+        let mut request = testing::request(
+            upload_signing_keys::v3::Request {
+                auth,
+                master_key: Some(master_key),
+                ..upload_signing_keys::v3::Request::new()
+            },
+            user_id,
+        );
+        request.json_body = Some(CanonicalJsonValue::Object(CanonicalJsonObject::new()));
+        request
+    }
+
+    fn password(user_id: &UserId, session: Option<String>) -> Option<AuthData> {
+        Some(AuthData::Password(Password {
+            session,
+            ..Password::new(
+                UserIdentifier::UserIdOrLocalpart(user_id.localpart().to_owned()),
+                "password".to_owned(),
+            )
+        }))
+    }
+
+    fn uiaa_session(result: Result<upload_signing_keys::v3::Response>) -> String {
+        match result {
+            Err(Error::Uiaa(info)) => info.session.expect("UIAA response has a session"),
+            _ => panic!("expected a UIAA response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn first_signing_key_upload_needs_uiaa() {
+        let alice = testing::user("keys_alice").await;
+        let master_key = user_key(&alice, "abc", "sig1");
+
+        let session =
+            uiaa_session(upload_signing_keys_route(upload(&alice, master_key.clone(), None)).await);
+        assert!(services()
+            .users
+            .get_master_key(&alice, &|_| false)
+            .unwrap()
+            .is_none());
+
+        upload_signing_keys_route(upload(&alice, master_key, password(&alice, Some(session))))
+            .await
+            .unwrap();
+        assert!(services()
+            .users
+            .get_master_key(&alice, &|_| false)
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn only_reuploads_of_the_stored_signing_keys_skip_uiaa() {
+        let bob = testing::user("keys_bob").await;
+        let master_key = user_key(&bob, "abc", "sig1");
+        upload_signing_keys_route(upload(&bob, master_key.clone(), password(&bob, None)))
+            .await
+            .unwrap();
+
+        upload_signing_keys_route(upload(&bob, master_key, None))
+            .await
+            .unwrap();
+        uiaa_session(
+            upload_signing_keys_route(upload(&bob, user_key(&bob, "def", "sig1"), None)).await,
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_servers_dont_hold_up_key_queries() {
+        let answer_after = |delay, answer| async move {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            Ok::<_, Error>(answer)
+        };
+
+        let (responses, failures) = query_servers(
+            KeysOperation::Query,
+            [
+                ("fast.example.org", answer_after(10, 1)),
+                ("slow.example.org", answer_after(60_000, 2)),
+            ],
+            Duration::from_millis(200),
+        )
+        .await;
+
+        assert_eq!(responses, [1]);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures["slow.example.org"]["status"], 504);
+    }
+
+    #[test]
+    fn only_identical_cross_signing_keys_skip_uia() {
+        assert!(same_cross_signing_key(
+            &key("abc", "sig1"),
+            &key("abc", "sig1")
+        ));
+        // A changed signature or key is a new upload and needs UIA
+        assert!(!same_cross_signing_key(
+            &key("abc", "sig1"),
+            &key("abc", "sig2")
+        ));
+        assert!(!same_cross_signing_key(
+            &key("abc", "sig1"),
+            &key("def", "sig1")
+        ));
+    }
+}
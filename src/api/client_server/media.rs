@@ -3,7 +3,7 @@ use ruma::api::client::{
     error::ErrorKind,
     media::{
         create_content, get_content, get_content_as_filename, get_content_thumbnail,
-        get_media_config,
+        get_media_config, get_media_preview,
     },
 };
 
@@ -16,7 +16,7 @@ pub async fn get_media_config_route(
     _body: Ruma<get_media_config::v3::Request>,
 ) -> Result<get_media_config::v3::Response> {
     Ok(get_media_config::v3::Response {
-        upload_size: services().globals.max_request_size().into(),
+        upload_size: services().globals.max_media_upload_size().into(),
     })
 }
 
@@ -29,13 +29,19 @@ pub async fn get_media_config_route(
 pub async fn create_content_route(
     body: Ruma<create_content::v3::Request>,
 ) -> Result<create_content::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    services()
+        .media
+        .reserve_quota(sender_user, body.file.len() as u64)?;
+
     let mxc = format!(
         "mxc://{}/{}",
         services().globals.server_name(),
         utils::random_string(MXC_LENGTH)
     );
 
-    services()
+    if let Err(e) = services()
         .media
         .create(
             mxc.clone(),
@@ -46,7 +52,13 @@ pub async fn create_content_route(
             body.content_type.as_deref(),
             &body.file,
         )
-        .await?;
+        .await
+    {
+        services()
+            .media
+            .release_quota(sender_user, body.file.len() as u64)?;
+        return Err(e);
+    }
 
     Ok(create_content::v3::Response {
         content_uri: mxc.try_into().expect("Invalid mxc:// URI"),
@@ -54,6 +66,33 @@ pub async fn create_content_route(
     })
 }
 
+/// # `GET /_matrix/media/v3/preview_url`
+///
+/// Returns OpenGraph data of a web page, fetched by the server.
+///
+/// - Only works if `url_preview_enabled` is set in the config
+/// - The image of the page is stored as media
+pub async fn get_media_preview_route(
+    body: Ruma<get_media_preview::v3::Request>,
+) -> Result<get_media_preview::v3::Response> {
+    if !services().globals.url_preview_enabled() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "URL previews are disabled on this server.",
+        ));
+    }
+
+    let url = body
+        .url
+        .parse()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid URL."))?;
+
+    let preview = services().media.get_url_preview(&url).await?;
+
+    Ok(get_media_preview::v3::Response::from_serialize(&preview)
+        .expect("URL preview can be serialized"))
+}
+
 pub async fn get_remote_content(
     mxc: &str,
     server_name: &ruma::ServerName,
@@ -94,6 +133,10 @@ pub async fn get_content_route(
 ) -> Result<get_content::v3::Response> {
     let mxc = format!("mxc://{}/{}", body.server_name, body.media_id);
 
+    if services().media.is_quarantined(&mxc)? {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."));
+    }
+
     if let Some(FileMeta {
         content_disposition,
         content_type,
@@ -106,7 +149,7 @@ pub async fn get_content_route(
             content_disposition,
             cross_origin_resource_policy: Some("cross-origin".to_owned()),
         })
-    } else if &*body.server_name != services().globals.server_name() && body.allow_remote {
+    } else if !services().globals.server_is_ours(&body.server_name) && body.allow_remote {
         let remote_content_response =
             get_remote_content(&mxc, &body.server_name, body.media_id.clone()).await?;
         Ok(remote_content_response)
@@ -125,6 +168,10 @@ pub async fn get_content_as_filename_route(
 ) -> Result<get_content_as_filename::v3::Response> {
     let mxc = format!("mxc://{}/{}", body.server_name, body.media_id);
 
+    if services().media.is_quarantined(&mxc)? {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."));
+    }
+
     if let Some(FileMeta {
         content_disposition: _,
         content_type,
@@ -137,7 +184,7 @@ pub async fn get_content_as_filename_route(
             content_disposition: Some(format!("inline; filename={}", body.filename)),
             cross_origin_resource_policy: Some("cross-origin".to_owned()),
         })
-    } else if &*body.server_name != services().globals.server_name() && body.allow_remote {
+    } else if !services().globals.server_is_ours(&body.server_name) && body.allow_remote {
         let remote_content_response =
             get_remote_content(&mxc, &body.server_name, body.media_id.clone()).await?;
 
@@ -162,6 +209,10 @@ pub async fn get_content_thumbnail_route(
 ) -> Result<get_content_thumbnail::v3::Response> {
     let mxc = format!("mxc://{}/{}", body.server_name, body.media_id);
 
+    if services().media.is_quarantined(&mxc)? {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."));
+    }
+
     if let Some(FileMeta {
         content_type, file, ..
     }) = services()
@@ -182,7 +233,7 @@ pub async fn get_content_thumbnail_route(
             content_type,
             cross_origin_resource_policy: Some("cross-origin".to_owned()),
         })
-    } else if &*body.server_name != services().globals.server_name() && body.allow_remote {
+    } else if !services().globals.server_is_ours(&body.server_name) && body.allow_remote {
         let get_thumbnail_response = services()
             .sending
             .send_federation_request(
@@ -215,3 +266,85 @@ pub async fn get_content_thumbnail_route(
         Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use axum::extract::Path;
+
+    use super::*;
+    use crate::{
+        api::admin_server::{quarantine_media_route, AdminUser},
+        utils::testing,
+    };
+
+    async fn upload(user_id: &ruma::UserId, size: usize) -> Result<create_content::v3::Response> {
+        create_content_route(testing::request(
+            create_content::v3::Request::new(vec![0; size]),
+            user_id,
+        ))
+        .await
+    }
+
+    async fn download(content_uri: &ruma::MxcUri) -> Result<get_content::v3::Response> {
+        let (server_name, media_id) = content_uri.parts().unwrap();
+        get_content_route(testing::unauthenticated_request(get_content::v3::Request {
+            allow_remote: false,
+            server_name: server_name.to_owned(),
+            media_id: media_id.to_owned(),
+        }))
+        .await
+    }
+
+    #[tokio::test]
+    async fn uploads_are_refused_once_the_quota_is_used_up() {
+        let alice = testing::user("media-quota-alice").await;
+
+        let first = upload(&alice, 600).await.unwrap();
+        assert_eq!(download(&first.content_uri).await.unwrap().file.len(), 600);
+
+        // The test config allows 1024 bytes per user
+        assert!(matches!(
+            upload(&alice, 600).await,
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+        upload(&alice, 424).await.unwrap();
+
+        // Admins have no quota
+        let admin = testing::admin("media-quota-admin").await;
+        upload(&admin, 2048).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn quarantined_media_is_not_served() {
+        let bob = testing::user("media-quarantine-bob").await;
+        let admin = testing::admin("media-quarantine-admin").await;
+
+        let content_uri = upload(&bob, 10).await.unwrap().content_uri;
+        download(&content_uri).await.unwrap();
+
+        let (server_name, media_id) = content_uri.parts().unwrap();
+        quarantine_media_route(
+            AdminUser(admin),
+            Path((server_name.to_string(), media_id.to_owned())),
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            download(&content_uri).await,
+            Err(Error::BadRequest(ErrorKind::NotFound, _))
+        ));
+        assert!(matches!(
+            get_content_thumbnail_route(testing::unauthenticated_request(
+                get_content_thumbnail::v3::Request::new(
+                    media_id.to_owned(),
+                    server_name.to_owned(),
+                    32_u32.into(),
+                    32_u32.into(),
+                )
+            ))
+            .await,
+            Err(Error::BadRequest(ErrorKind::NotFound, _))
+        ));
+    }
+}
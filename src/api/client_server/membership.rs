@@ -1,38 +1,46 @@
 use ruma::{
     api::{
         client::{
-            error::ErrorKind,
+            error::{Error as RumaError, ErrorBody, ErrorKind},
             membership::{
-                ban_user, forget_room, get_member_events, invite_user, join_room_by_id,
-                join_room_by_id_or_alias, joined_members, joined_rooms, kick_user, leave_room,
-                unban_user, ThirdPartySigned,
+                ban_user, forget_room,
+                get_member_events::{self, v3::MembershipEventFilter},
+                invite_user, join_room_by_id, join_room_by_id_or_alias, joined_members,
+                joined_rooms, kick_user, leave_room, unban_user, Invite3pid, ThirdPartySigned,
             },
         },
         federation::{self, membership::create_invite},
+        IncomingResponse,
     },
     canonical_json::to_canonical_value,
     events::{
         room::{
             join_rules::{AllowRule, JoinRule, RoomJoinRulesEventContent},
-            member::{MembershipState, RoomMemberEventContent},
+            member::{MembershipState, RoomMemberEventContent, ThirdPartyInvite},
             power_levels::RoomPowerLevelsEventContent,
         },
         RoomEventType, StateEventType,
     },
     serde::Base64,
     state_res, CanonicalJsonObject, CanonicalJsonValue, EventId, OwnedEventId, OwnedRoomId,
-    OwnedServerName, OwnedUserId, RoomId, RoomVersionId, UserId,
+    OwnedServerName, OwnedUserId, RoomId, RoomVersionId, ServerName, UserId,
 };
+use serde::Deserialize;
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
 use std::{
     collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+    iter,
     sync::{Arc, RwLock},
     time::{Duration, Instant},
 };
 use tracing::{debug, error, warn};
 
 use crate::{
-    service::pdu::{gen_event_id_canonical_json, PduBuilder},
+    api::identity_server::{self, StoreInviteRequest, StoreInviteResponse},
+    service::{
+        pdu::{gen_event_id_canonical_json, PduBuilder},
+        rooms::state_cache::below_limit,
+    },
     services, utils, Error, PduEvent, Result, Ruma,
 };
 
@@ -44,6 +52,7 @@ use super::get_alias_helper;
 ///
 /// - If the server knowns about this room: creates the join event and does auth rules locally
 /// - If the server does not know about the room: asks other servers over federation
+/// - Fails once the user is in `max_rooms_per_user_join` rooms
 pub async fn join_room_by_id_route(
     body: Ruma<join_room_by_id::v3::Request>,
 ) -> Result<join_room_by_id::v3::Response> {
@@ -66,6 +75,14 @@ pub async fn join_room_by_id_route(
 
     servers.push(body.room_id.server_name().to_owned());
 
+    if !services()
+        .rooms
+        .state_cache
+        .is_joined(sender_user, &body.room_id)?
+    {
+        check_room_limits(sender_user, body.from_appservice, false)?;
+    }
+
     join_room_by_id_helper(
         body.sender_user.as_deref(),
         &body.room_id,
@@ -82,10 +99,12 @@ pub async fn join_room_by_id_route(
 ///
 /// - If the server knowns about this room: creates the join event and does auth rules locally
 /// - If the server does not know about the room: asks other servers over federation
+/// - Fails once the user is in `max_rooms_per_user_join` rooms
 pub async fn join_room_by_id_or_alias_route(
     body: Ruma<join_room_by_id_or_alias::v3::Request>,
 ) -> Result<join_room_by_id_or_alias::v3::Response> {
     let sender_user = body.sender_user.as_deref().expect("user is authenticated");
+    let from_appservice = body.from_appservice;
     let body = body.body;
 
     let (servers, room_id) = match OwnedRoomId::try_from(body.room_id_or_alias) {
@@ -115,6 +134,14 @@ pub async fn join_room_by_id_or_alias_route(
         }
     };
 
+    if !services()
+        .rooms
+        .state_cache
+        .is_joined(sender_user, &room_id)?
+    {
+        check_room_limits(sender_user, from_appservice, false)?;
+    }
+
     let join_room_response = join_room_by_id_helper(
         Some(sender_user),
         &room_id,
@@ -129,6 +156,112 @@ pub async fn join_room_by_id_or_alias_route(
     })
 }
 
+/// Refuses another room once the user is in `max_rooms_per_user_join` rooms, or when creating a
+/// room, once they created `max_rooms_per_user_create` rooms. Admins and appservices have no
+/// limits.
+pub(crate) fn check_room_limits(
+    sender_user: &UserId,
+    from_appservice: bool,
+    creating: bool,
+) -> Result<()> {
+    let max_joined = services().globals.max_rooms_per_user_join();
+    let max_created = services()
+        .globals
+        .max_rooms_per_user_create()
+        .filter(|_| creating);
+
+    if (max_joined.is_none() && max_created.is_none())
+        || from_appservice
+        || services().users.is_admin(sender_user)?
+    {
+        return Ok(());
+    }
+
+    if max_joined.is_some()
+        && !below_limit(
+            services().rooms.state_cache.joined_count(sender_user)?,
+            max_joined,
+        )
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::LimitExceeded {
+                retry_after_ms: None,
+            },
+            "You are in too many rooms.",
+        ));
+    }
+
+    if max_created.is_some()
+        && !below_limit(services().users.rooms_created(sender_user)?, max_created)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::LimitExceeded {
+                retry_after_ms: None,
+            },
+            "You have created too many rooms.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Refuses an invite once the invited user has `max_outstanding_invites_per_user` pending invites,
+/// or `max_invites_per_room` users are invited to the room. Invites by admins have no limits.
+pub(crate) fn check_invite_limits(
+    sender_user: &UserId,
+    user_id: &UserId,
+    room_id: &RoomId,
+) -> Result<()> {
+    let max_user = services().globals.max_outstanding_invites_per_user();
+    let max_room = services().globals.max_invites_per_room();
+
+    if (max_user.is_none() && max_room.is_none()) || services().users.is_admin(sender_user)? {
+        return Ok(());
+    }
+
+    // Only count as far as the limit, a spammed user can have a lot of invites
+    let user_invites = max_user.map_or(0, |max| {
+        services()
+            .rooms
+            .state_cache
+            .rooms_invited(user_id)
+            .take(usize::try_from(max).unwrap_or(usize::MAX))
+            .count() as u64
+    });
+    let room_invites = services()
+        .rooms
+        .state_cache
+        .room_invited_count(room_id)?
+        .unwrap_or(0);
+
+    invite_limits_allow(user_invites, room_invites, max_user, max_room)
+}
+
+fn invite_limits_allow(
+    user_invites: u64,
+    room_invites: u64,
+    max_user: Option<u64>,
+    max_room: Option<u64>,
+) -> Result<()> {
+    if !below_limit(user_invites, max_user) {
+        return Err(Error::BadRequest(
+            ErrorKind::LimitExceeded {
+                retry_after_ms: None,
+            },
+            "This user has too many pending invites.",
+        ));
+    }
+
+    if !below_limit(room_invites, max_room) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Too many users are invited to this room.",
+        ));
+    }
+
+    Ok(())
+}
+
 /// # `POST /_matrix/client/r0/rooms/{roomId}/leave`
 ///
 /// Tries to leave the sender user from a room.
@@ -147,24 +280,31 @@ pub async fn leave_room_route(
 /// # `POST /_matrix/client/r0/rooms/{roomId}/invite`
 ///
 /// Tries to send an invite event into the room.
+///
+/// - Invites by email address or phone number go through an identity server
 pub async fn invite_user_route(
     body: Ruma<invite_user::v3::Request>,
 ) -> Result<invite_user::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    if let invite_user::v3::InvitationRecipient::UserId { user_id } = &body.recipient {
-        invite_helper(
-            sender_user,
-            user_id,
-            &body.room_id,
-            body.reason.clone(),
-            false,
-        )
-        .await?;
-        Ok(invite_user::v3::Response {})
-    } else {
-        Err(Error::BadRequest(ErrorKind::NotFound, "User not found."))
+    match &body.recipient {
+        invite_user::v3::InvitationRecipient::UserId { user_id } => {
+            invite_helper(
+                sender_user,
+                user_id,
+                &body.room_id,
+                body.reason.clone(),
+                false,
+                None,
+            )
+            .await?;
+        }
+        invite_user::v3::InvitationRecipient::ThirdPartyId(invite) => {
+            invite_3pid_helper(sender_user, &body.room_id, invite).await?;
+        }
     }
+
+    Ok(invite_user::v3::Response {})
 }
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/kick`
@@ -351,7 +491,9 @@ pub async fn unban_user_route(
 ///
 /// Forgets about a room.
 ///
-/// - If the sender user currently left the room: Stops sender user from receiving information about the room
+/// - Fails unless the sender user left the room or was never in it
+/// - Stops sender user from receiving information about the room, and deletes their account data,
+///   read markers and notification counts in it
 ///
 /// Note: Other devices of the user have no way of knowing the room was forgotten, so this has to
 /// be called from every device
@@ -360,6 +502,21 @@ pub async fn forget_room_route(
 ) -> Result<forget_room::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    if services()
+        .rooms
+        .state_cache
+        .is_joined(sender_user, &body.room_id)?
+        || services()
+            .rooms
+            .state_cache
+            .is_invited(sender_user, &body.room_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Unknown,
+            "You have to leave the room before forgetting it.",
+        ));
+    }
+
     services()
         .rooms
         .state_cache
@@ -388,37 +545,121 @@ pub async fn joined_rooms_route(
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/members`
 ///
-/// Lists all joined users in a room (TODO: at a specific point in time, with a specific membership).
+/// Lists the member events of a room.
 ///
-/// - Only works if the user is currently joined
+/// - With `at`, the members at that sync token are returned, otherwise the current ones
+/// - `membership` and `not_membership` filter the members by their membership
+/// - Members see the current member list, former members the lists of the times they were
+/// allowed to see according to the history visibility
 pub async fn get_member_events_route(
     body: Ruma<get_member_events::v3::Request>,
 ) -> Result<get_member_events::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    // TODO: check history visibility?
-    if !services()
-        .rooms
-        .state_cache
-        .is_joined(sender_user, &body.room_id)?
-    {
+    let at = body
+        .at
+        .as_deref()
+        .map(str::parse)
+        .transpose()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `at` value."))?;
+
+    let shortstatehash = match at {
+        Some(at) => shortstatehash_at(sender_user, &body.room_id, at)?,
+        None => services()
+            .rooms
+            .state
+            .get_room_shortstatehash(&body.room_id)?,
+    }
+    .ok_or(Error::BadRequest(ErrorKind::NotFound, "Room not found."))?;
+
+    let allowed = match at {
+        Some(at) => services().rooms.state_accessor.user_can_see_state_at(
+            sender_user,
+            &body.room_id,
+            shortstatehash,
+            at,
+        )?,
+        None => services()
+            .rooms
+            .state_accessor
+            .user_can_see_state(Some(sender_user), &body.room_id)?,
+    };
+
+    if !allowed {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
             "You don't have permission to view this room.",
         ));
     }
 
-    Ok(get_member_events::v3::Response {
-        chunk: services()
+    let mut chunk = Vec::new();
+    for ((event_type, _), pdu) in services()
+        .rooms
+        .state_accessor
+        .state_full(shortstatehash)
+        .await?
+    {
+        if event_type != StateEventType::RoomMember {
+            continue;
+        }
+
+        let membership = serde_json::from_str::<RoomMemberEventContent>(pdu.content.get())
+            .map_err(|_| Error::bad_database("Invalid member event in database."))?
+            .membership;
+
+        if membership_matches(
+            &membership,
+            body.membership.as_ref(),
+            body.not_membership.as_ref(),
+        ) {
+            chunk.push(pdu.to_member_event());
+        }
+    }
+
+    Ok(get_member_events::v3::Response { chunk })
+}
+
+/// The state of a room at a sync token: the state the sync returned, if it was a sync token of
+/// this room, otherwise the state before the first event after the token.
+fn shortstatehash_at(sender_user: &UserId, room_id: &RoomId, at: u64) -> Result<Option<u64>> {
+    if services().rooms.short.get_shortroomid(room_id)?.is_none() {
+        return Ok(None);
+    }
+
+    if let Some(shortstatehash) = services()
+        .rooms
+        .user
+        .get_token_shortstatehash(room_id, at)?
+    {
+        return Ok(Some(shortstatehash));
+    }
+
+    match services()
+        .rooms
+        .timeline
+        .pdus_after(sender_user, room_id, at)?
+        .next()
+        .transpose()?
+    {
+        Some((_, pdu)) => services()
             .rooms
             .state_accessor
-            .room_state_full(&body.room_id)
-            .await?
-            .iter()
-            .filter(|(key, _)| key.0 == StateEventType::RoomMember)
-            .map(|(_, pdu)| pdu.to_member_event())
-            .collect(),
-    })
+            .pdu_shortstatehash(&pdu.event_id),
+        // Nothing happened since the token
+        None => services().rooms.state.get_room_shortstatehash(room_id),
+    }
+}
+
+/// Whether a member passes the `membership` and `not_membership` filters of the members endpoint.
+fn membership_matches(
+    membership: &MembershipState,
+    filter: Option<&MembershipEventFilter>,
+    not_filter: Option<&MembershipEventFilter>,
+) -> bool {
+    filter.map_or(true, |filter| filter.as_str() == membership.as_str())
+        && not_filter.map_or(true, |not_filter| {
+            not_filter.as_str() != membership.as_str()
+        })
 }
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/joined_members`
@@ -443,26 +684,11 @@ pub async fn joined_members_route(
         ));
     }
 
-    let mut joined = BTreeMap::new();
-    for user_id in services()
-        .rooms
-        .state_cache
-        .room_members(&body.room_id)
-        .filter_map(|r| r.ok())
-    {
-        let display_name = services().users.displayname(&user_id)?;
-        let avatar_url = services().users.avatar_url(&user_id)?;
+    let joined = services().rooms.state_cache.joined_members(&body.room_id)?;
 
-        joined.insert(
-            user_id,
-            joined_members::v3::RoomMember {
-                display_name,
-                avatar_url,
-            },
-        );
-    }
-
-    Ok(joined_members::v3::Response { joined })
+    Ok(joined_members::v3::Response {
+        joined: (*joined).clone(),
+    })
 }
 
 async fn join_room_by_id_helper(
@@ -494,17 +720,10 @@ async fn join_room_by_id_helper(
         let (make_join_response, remote_server) =
             make_join_request(sender_user, room_id, servers).await?;
 
-        let room_version_id = match make_join_response.room_version {
-            Some(room_version)
-                if services()
-                    .globals
-                    .supported_room_versions()
-                    .contains(&room_version) =>
-            {
-                room_version
-            }
-            _ => return Err(Error::BadServerResponse("Room version is not supported")),
-        };
+        let room_version_id = joinable_room_version(
+            make_join_response.room_version,
+            &services().globals.supported_room_versions(),
+        )?;
 
         let mut join_event_stub: CanonicalJsonObject =
             serde_json::from_str(make_join_response.event.get()).map_err(|_| {
@@ -554,7 +773,7 @@ async fn join_room_by_id_helper(
         // In order to create a compatible ref hash (EventID) the `hashes` field needs to be present
         ruma::signatures::hash_and_sign_event(
             services().globals.server_name().as_str(),
-            services().globals.keypair(),
+            &*services().globals.keypair(),
             &mut join_event_stub,
             &room_version_id,
         )
@@ -578,17 +797,16 @@ async fn join_room_by_id_helper(
         // It has enough fields to be called a proper event now
         let mut join_event = join_event_stub;
 
-        let send_join_response = services()
-            .sending
-            .send_federation_request(
-                &remote_server,
-                federation::membership::create_join_event::v2::Request {
-                    room_id: room_id.to_owned(),
-                    event_id: event_id.to_owned(),
-                    pdu: PduEvent::convert_to_outgoing_federation_event(join_event.clone()),
-                },
-            )
-            .await?;
+        let (send_join_response, servers_in_room) = send_join_request(
+            sender_user,
+            &remote_server,
+            federation::membership::create_join_event::v2::Request {
+                room_id: room_id.to_owned(),
+                event_id: event_id.to_owned(),
+                pdu: PduEvent::convert_to_outgoing_federation_event(join_event.clone()),
+            },
+        )
+        .await?;
 
         if let Some(signed_raw) = &send_join_response.room_state.event {
             let (signed_event_id, signed_value) =
@@ -760,6 +978,35 @@ async fn join_room_by_id_helper(
             .rooms
             .state
             .set_room_state(room_id, statehash_after_join, &state_lock)?;
+
+        if let Some(servers_in_room) = servers_in_room {
+            // The server we joined through knows the full state for sure, so it's asked first
+            let servers = iter::once(&*remote_server)
+                .chain(
+                    servers_in_room
+                        .iter()
+                        .map(|server| &**server)
+                        .filter(|server| *server != &*remote_server),
+                )
+                .collect::<Vec<_>>();
+            services().rooms.metadata.mark_partial_state(
+                room_id,
+                &parsed_join_pdu.event_id,
+                &servers,
+            )?;
+
+            let room_id = room_id.to_owned();
+            tokio::spawn(async move {
+                if let Err(e) = services()
+                    .rooms
+                    .event_handler
+                    .resync_partial_state(&room_id)
+                    .await
+                {
+                    warn!("Could not resync the members of {}: {}", room_id, e);
+                }
+            });
+        }
     } else {
         let join_rules_event = services().rooms.state_accessor.room_state_get(
             room_id,
@@ -825,7 +1072,7 @@ async fn join_room_by_id_helper(
                         c.users
                             .iter()
                             .filter(|(uid, i)| {
-                                uid.server_name() == services().globals.server_name()
+                                services().globals.server_is_ours(uid.server_name())
                                     && **i > ruma::int!(0)
                                     && services()
                                         .rooms
@@ -844,7 +1091,7 @@ async fn join_room_by_id_helper(
                             .state_cache
                             .room_members(restriction_room_id)
                             .filter_map(|r| r.ok())
-                            .find(|uid| uid.server_name() == services().globals.server_name())
+                            .find(|uid| services().globals.server_is_ours(uid.server_name()))
                     });
                 Some(authorized_user)
             })
@@ -884,17 +1131,10 @@ async fn join_room_by_id_helper(
             let (make_join_response, remote_server) =
                 make_join_request(sender_user, room_id, servers).await?;
 
-            let room_version_id = match make_join_response.room_version {
-                Some(room_version_id)
-                    if services()
-                        .globals
-                        .supported_room_versions()
-                        .contains(&room_version_id) =>
-                {
-                    room_version_id
-                }
-                _ => return Err(Error::BadServerResponse("Room version is not supported")),
-            };
+            let room_version_id = joinable_room_version(
+                make_join_response.room_version,
+                &services().globals.supported_room_versions(),
+            )?;
             let mut join_event_stub: CanonicalJsonObject =
                 serde_json::from_str(make_join_response.event.get()).map_err(|_| {
                     Error::BadServerResponse("Invalid make_join event json received from server.")
@@ -941,7 +1181,7 @@ async fn join_room_by_id_helper(
             // In order to create a compatible ref hash (EventID) the `hashes` field needs to be present
             ruma::signatures::hash_and_sign_event(
                 services().globals.server_name().as_str(),
-                services().globals.keypair(),
+                &*services().globals.keypair(),
                 &mut join_event_stub,
                 &room_version_id,
             )
@@ -967,7 +1207,8 @@ async fn join_room_by_id_helper(
 
             let send_join_response = services()
                 .sending
-                .send_federation_request(
+                .send_federation_request_as(
+                    sender_user.server_name(),
                     &remote_server,
                     federation::membership::create_join_event::v2::Request {
                         room_id: room_id.to_owned(),
@@ -1035,12 +1276,13 @@ async fn make_join_request(
     ));
 
     for remote_server in servers {
-        if remote_server == services().globals.server_name() {
+        if services().globals.server_is_ours(remote_server) {
             continue;
         }
         let make_join_response = services()
             .sending
-            .send_federation_request(
+            .send_federation_request_as(
+                sender_user.server_name(),
                 remote_server,
                 federation::membership::prepare_join_event::v1::Request {
                     room_id: room_id.to_owned(),
@@ -1050,6 +1292,18 @@ async fn make_join_request(
             )
             .await;
 
+        // All servers in the room agree on its version, asking the others won't help
+        if let Some(room_version) = make_join_response
+            .as_ref()
+            .err()
+            .and_then(incompatible_room_version)
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::IncompatibleRoomVersion { room_version },
+                "Room version is not supported.",
+            ));
+        }
+
         make_join_response_and_server = make_join_response.map(|r| (r, remote_server.clone()));
 
         if make_join_response_and_server.is_ok() {
@@ -1060,6 +1314,97 @@ async fn make_join_request(
     make_join_response_and_server
 }
 
+/// The version of the room a remote server offered to let us join, if we support it. Otherwise
+/// clients are told the version of the room, so they can explain why it can't be joined.
+fn joinable_room_version(
+    room_version: Option<RoomVersionId>,
+    supported: &[RoomVersionId],
+) -> Result<RoomVersionId> {
+    // Servers leave out the version for version 1 rooms
+    let room_version = room_version.unwrap_or(RoomVersionId::V1);
+
+    if supported.contains(&room_version) {
+        Ok(room_version)
+    } else {
+        Err(Error::BadRequest(
+            ErrorKind::IncompatibleRoomVersion { room_version },
+            "Room version is not supported.",
+        ))
+    }
+}
+
+/// The room version of a make_join error, if the remote server rejected us because we don't
+/// support the version of the room.
+fn incompatible_room_version(error: &Error) -> Option<RoomVersionId> {
+    match error {
+        Error::FederationError(
+            _,
+            RumaError {
+                body:
+                    ErrorBody::Standard {
+                        kind: ErrorKind::IncompatibleRoomVersion { room_version },
+                        ..
+                    },
+                ..
+            },
+        ) => Some(room_version.clone()),
+        _ => None,
+    }
+}
+
+/// The fields a send_join response adds when it leaves out the members (MSC3706).
+#[derive(Deserialize)]
+struct PartialStateFields {
+    #[serde(default, alias = "org.matrix.msc3706.partial_state")]
+    members_omitted: bool,
+    #[serde(default, alias = "org.matrix.msc3706.servers_in_room")]
+    servers_in_room: Vec<OwnedServerName>,
+}
+
+/// Sends our join event to `remote_server`. With `partial_state_joins` it asks for the state
+/// without the members, and returns the servers in the room if the response left them out.
+async fn send_join_request(
+    sender_user: &UserId,
+    remote_server: &ServerName,
+    request: federation::membership::create_join_event::v2::Request,
+) -> Result<(
+    federation::membership::create_join_event::v2::Response,
+    Option<Vec<OwnedServerName>>,
+)> {
+    if !services().globals.partial_state_joins() {
+        let response = services()
+            .sending
+            .send_federation_request_as(sender_user.server_name(), remote_server, request)
+            .await?;
+
+        return Ok((response, None));
+    }
+
+    let body = services()
+        .sending
+        .send_federation_request_with_query_as(
+            sender_user.server_name(),
+            remote_server,
+            request,
+            "omit_members=true",
+        )
+        .await?;
+
+    let response = federation::membership::create_join_event::v2::Response::try_from_http_response(
+        http::Response::new(body.clone()),
+    )
+    .map_err(|_| Error::BadServerResponse("Invalid send_join response."))?;
+    let partial_state = serde_json::from_slice::<PartialStateFields>(&body)
+        .map_err(|_| Error::BadServerResponse("Invalid send_join response."))?;
+
+    Ok((
+        response,
+        partial_state
+            .members_omitted
+            .then_some(partial_state.servers_in_room),
+    ))
+}
+
 fn validate_and_add_event_id(
     pdu: &RawJsonValue,
     room_version: &RoomVersionId,
@@ -1134,8 +1479,18 @@ pub(crate) async fn invite_helper<'a>(
     room_id: &RoomId,
     reason: Option<String>,
     is_direct: bool,
+    third_party_invite: Option<ThirdPartyInvite>,
 ) -> Result<()> {
-    if user_id.server_name() != services().globals.server_name() {
+    check_invite_limits(sender_user, user_id, room_id)?;
+
+    if !services().globals.server_is_ours(user_id.server_name()) {
+        if services().rooms.metadata.is_local_only(room_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "This room does not federate, only local users can be invited.",
+            ));
+        }
+
         let (pdu, pdu_json, invite_room_state) = {
             let mutex_state = Arc::clone(
                 services()
@@ -1153,7 +1508,7 @@ pub(crate) async fn invite_helper<'a>(
                 displayname: None,
                 is_direct: Some(is_direct),
                 membership: MembershipState::Invite,
-                third_party_invite: None,
+                third_party_invite: third_party_invite.clone(),
                 blurhash: None,
                 reason,
                 join_authorized_via_users_server: None,
@@ -1184,7 +1539,8 @@ pub(crate) async fn invite_helper<'a>(
 
         let response = services()
             .sending
-            .send_federation_request(
+            .send_federation_request_as(
+                sender_user.server_name(),
                 user_id.server_name(),
                 create_invite::v2::Request {
                     room_id: room_id.to_owned(),
@@ -1240,7 +1596,7 @@ pub(crate) async fn invite_helper<'a>(
             .state_cache
             .room_servers(room_id)
             .filter_map(|r| r.ok())
-            .filter(|server| &**server != services().globals.server_name());
+            .filter(|server| !services().globals.server_is_ours(server));
 
         services().sending.send_pdu(servers, &pdu_id)?;
 
@@ -1277,7 +1633,7 @@ pub(crate) async fn invite_helper<'a>(
                 displayname: services().users.displayname(user_id)?,
                 avatar_url: services().users.avatar_url(user_id)?,
                 is_direct: Some(is_direct),
-                third_party_invite: None,
+                third_party_invite,
                 blurhash: services().users.blurhash(user_id)?,
                 reason,
                 join_authorized_via_users_server: None,
@@ -1297,6 +1653,164 @@ pub(crate) async fn invite_helper<'a>(
     Ok(())
 }
 
+/// Invites someone by email address or phone number. If the address is bound to a Matrix user,
+/// that user is invited directly. Otherwise the identity server sends the invitation and an
+/// m.room.third_party_invite event is sent, which is exchanged for an invite once the address is
+/// bound.
+pub(crate) async fn invite_3pid_helper(
+    sender_user: &UserId,
+    room_id: &RoomId,
+    invite: &Invite3pid,
+) -> Result<()> {
+    if !services()
+        .globals
+        .identity_server_trusted(&invite.id_server)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::ServerNotTrusted,
+            "This server doesn't trust the identity server.",
+        ));
+    }
+
+    if !services()
+        .rooms
+        .state_cache
+        .is_joined(sender_user, room_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to view this room.",
+        ));
+    }
+
+    let power_levels: Option<RoomPowerLevelsEventContent> = services()
+        .rooms
+        .state_accessor
+        .room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
+        .map(|event| {
+            serde_json::from_str(event.content.get())
+                .map_err(|_| Error::bad_database("Invalid power levels event in database."))
+        })
+        .transpose()?;
+
+    // Checked before the identity server sends the invite, the event would be rejected later
+    if !can_invite(power_levels, sender_user) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to invite users to this room.",
+        ));
+    }
+
+    if let Some(user_id) = identity_server::lookup(
+        &invite.id_server,
+        &invite.id_access_token,
+        &invite.medium,
+        &invite.address,
+    )
+    .await?
+    {
+        return invite_helper(sender_user, &user_id, room_id, None, false, None).await;
+    }
+
+    let room_name = services()
+        .rooms
+        .state_accessor
+        .room_state_get(room_id, &StateEventType::RoomName, "")?
+        .and_then(|pdu| serde_json::from_str::<serde_json::Value>(pdu.content.get()).ok())
+        .and_then(|content| content.get("name")?.as_str().map(ToOwned::to_owned));
+
+    let response = identity_server::store_invite(
+        &invite.id_server,
+        &invite.id_access_token,
+        &StoreInviteRequest {
+            medium: invite.medium.as_str(),
+            address: &invite.address,
+            room_id: room_id.as_str(),
+            sender: sender_user.as_str(),
+            sender_display_name: services().users.displayname(sender_user)?,
+            room_name,
+        },
+    )
+    .await?;
+
+    let mutex_state = Arc::clone(
+        services()
+            .globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    services().rooms.timeline.build_and_append_pdu(
+        PduBuilder {
+            event_type: RoomEventType::RoomThirdPartyInvite,
+            content: to_raw_value(&third_party_invite_content(&response)?)
+                .expect("event is valid, we just created it"),
+            unsigned: None,
+            state_key: Some(response.token),
+            redacts: None,
+        },
+        sender_user,
+        room_id,
+        &state_lock,
+    )?;
+
+    drop(state_lock);
+
+    Ok(())
+}
+
+/// Whether the power levels allow the user to send third party invites: the user needs the invite
+/// level and the level to send m.room.third_party_invite state events. Without power levels
+/// everyone can.
+fn can_invite(power_levels: Option<RoomPowerLevelsEventContent>, user_id: &UserId) -> bool {
+    let power_levels = match power_levels {
+        Some(power_levels) => power_levels,
+        None => return true,
+    };
+
+    let user_level = power_levels
+        .users
+        .get(user_id)
+        .copied()
+        .unwrap_or(power_levels.users_default);
+    let event_level = power_levels
+        .events
+        .get(&RoomEventType::RoomThirdPartyInvite)
+        .copied()
+        .unwrap_or(power_levels.state_default);
+
+    user_level >= power_levels.invite && user_level >= event_level
+}
+
+/// The content of the m.room.third_party_invite event for an invite stored by an identity server.
+/// The first key is also given in the deprecated single key fields.
+fn third_party_invite_content(response: &StoreInviteResponse) -> Result<serde_json::Value> {
+    let first_key = response
+        .public_keys
+        .first()
+        .ok_or(Error::BadServerResponse(
+            "Identity server returned no public keys.",
+        ))?;
+
+    Ok(serde_json::json!({
+        "display_name": response.display_name,
+        "key_validity_url": first_key.key_validity_url,
+        "public_key": first_key.public_key,
+        "public_keys": response
+            .public_keys
+            .iter()
+            .map(|key| serde_json::json!({
+                "public_key": key.public_key,
+                "key_validity_url": key.key_validity_url,
+            }))
+            .collect::<Vec<_>>(),
+    }))
+}
+
 // Make a user leave all their joined rooms
 pub async fn leave_all_rooms(user_id: &UserId) -> Result<()> {
     let all_rooms = services()
@@ -1327,7 +1841,7 @@ pub async fn leave_all_rooms(user_id: &UserId) -> Result<()> {
 pub async fn leave_room(user_id: &UserId, room_id: &RoomId, reason: Option<String>) -> Result<()> {
     // Ask a remote server if we don't have this room
     if !services().rooms.metadata.exists(room_id)?
-        && room_id.server_name() != services().globals.server_name()
+        && !services().globals.server_is_ours(room_id.server_name())
     {
         if let Err(e) = remote_leave_room(user_id, room_id).await {
             warn!("Failed to leave room {} remotely: {}", user_id, e);
@@ -1437,7 +1951,8 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
     for remote_server in servers {
         let make_leave_response = services()
             .sending
-            .send_federation_request(
+            .send_federation_request_as(
+                user_id.server_name(),
                 &remote_server,
                 federation::membership::prepare_leave_event::v1::Request {
                     room_id: room_id.to_owned(),
@@ -1491,7 +2006,7 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
     // In order to create a compatible ref hash (EventID) the `hashes` field needs to be present
     ruma::signatures::hash_and_sign_event(
         services().globals.server_name().as_str(),
-        services().globals.keypair(),
+        &*services().globals.keypair(),
         &mut leave_event_stub,
         &room_version_id,
     )
@@ -1516,7 +2031,8 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
 
     services()
         .sending
-        .send_federation_request(
+        .send_federation_request_as(
+            user_id.server_name(),
             &remote_server,
             federation::membership::create_leave_event::v2::Request {
                 room_id: room_id.to_owned(),
@@ -1528,3 +2044,383 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use ruma::{
+        api::client::profile::set_display_name,
+        events::room::third_party_invite::RoomThirdPartyInviteEventContent,
+    };
+
+    use super::*;
+    use crate::{api::client_server::set_displayname_route, utils::testing};
+
+    #[test]
+    fn invites_are_limited_per_user_and_room() {
+        assert!(invite_limits_allow(99, 99, Some(100), Some(100)).is_ok());
+        assert!(invite_limits_allow(1000, 1000, None, None).is_ok());
+
+        assert!(matches!(
+            invite_limits_allow(100, 0, Some(100), Some(100)),
+            Err(Error::BadRequest(ErrorKind::LimitExceeded { .. }, _))
+        ));
+        assert!(matches!(
+            invite_limits_allow(0, 100, Some(100), Some(100)),
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+    }
+
+    async fn invite(sender: &UserId, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+        invite_user_route(testing::request(
+            invite_user::v3::Request::new(
+                room_id.to_owned(),
+                invite_user::v3::InvitationRecipient::UserId {
+                    user_id: user_id.to_owned(),
+                },
+            ),
+            sender,
+        ))
+        .await
+        .map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn local_invites_stop_at_the_limits() {
+        let alice = testing::user("invite_limits_alice").await;
+        let carol = testing::user("invite_limits_carol").await;
+        let rooms = [
+            testing::room(&alice).await,
+            testing::room(&alice).await,
+            testing::room(&alice).await,
+        ];
+
+        // The test config allows two pending invites per user
+        invite(&alice, &carol, &rooms[0]).await.unwrap();
+        invite(&alice, &carol, &rooms[1]).await.unwrap();
+        assert!(matches!(
+            invite(&alice, &carol, &rooms[2]).await,
+            Err(Error::BadRequest(ErrorKind::LimitExceeded { .. }, _))
+        ));
+
+        // ... and three invited users per room
+        for localpart in ["invite_limits_dave", "invite_limits_erin"] {
+            let user_id = testing::user(localpart).await;
+            invite(&alice, &user_id, &rooms[0]).await.unwrap();
+        }
+        let frank = testing::user("invite_limits_frank").await;
+        assert!(matches!(
+            invite(&alice, &frank, &rooms[0]).await,
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+
+        // Joining frees the place of an invite
+        testing::join(&carol, &rooms[0]).await;
+        invite(&alice, &carol, &rooms[2]).await.unwrap();
+        invite(&alice, &frank, &rooms[0]).await.unwrap();
+
+        // Admins have no limits
+        let admin = testing::admin("invite_limits_admin").await;
+        let admin_room = testing::room(&admin).await;
+        invite(&admin, &carol, &admin_room).await.unwrap();
+    }
+
+    #[test]
+    fn inviting_needs_the_invite_power_level() {
+        let alice = ruma::user_id!("@alice:example.org");
+        let bob = ruma::user_id!("@bob:example.org");
+
+        // Without power levels everyone can invite
+        assert!(can_invite(None, bob));
+
+        let mut power_levels = RoomPowerLevelsEventContent::default();
+        power_levels.invite = ruma::int!(50);
+        power_levels.users.insert(alice.to_owned(), ruma::int!(50));
+        assert!(can_invite(Some(power_levels.clone()), alice));
+        assert!(!can_invite(Some(power_levels.clone()), bob));
+
+        power_levels.users_default = ruma::int!(50);
+        assert!(can_invite(Some(power_levels.clone()), bob));
+
+        // The m.room.third_party_invite event is checked as well, state_default is the fallback
+        power_levels.state_default = ruma::int!(75);
+        assert!(!can_invite(Some(power_levels.clone()), bob));
+        power_levels.state_default = ruma::int!(50);
+        power_levels
+            .events
+            .insert(RoomEventType::RoomThirdPartyInvite, ruma::int!(100));
+        assert!(!can_invite(Some(power_levels.clone()), alice));
+        power_levels.users.insert(alice.to_owned(), ruma::int!(100));
+        assert!(can_invite(Some(power_levels), alice));
+    }
+
+    #[test]
+    fn rooms_in_unsupported_versions_are_incompatible() {
+        let supported = [RoomVersionId::V9, RoomVersionId::V10];
+
+        assert_eq!(
+            joinable_room_version(Some(RoomVersionId::V10), &supported).unwrap(),
+            RoomVersionId::V10
+        );
+        assert!(matches!(
+            joinable_room_version(Some(RoomVersionId::V1), &supported),
+            Err(Error::BadRequest(
+                ErrorKind::IncompatibleRoomVersion { room_version },
+                _
+            )) if room_version == RoomVersionId::V1
+        ));
+        // No version means version 1
+        assert!(matches!(
+            joinable_room_version(None, &supported),
+            Err(Error::BadRequest(
+                ErrorKind::IncompatibleRoomVersion { room_version },
+                _
+            )) if room_version == RoomVersionId::V1
+        ));
+
+        // The remote server can reject the join itself
+        let rejected = Error::FederationError(
+            ruma::server_name!("remote.example.org").to_owned(),
+            RumaError {
+                body: ErrorBody::Standard {
+                    kind: ErrorKind::IncompatibleRoomVersion {
+                        room_version: RoomVersionId::V1,
+                    },
+                    message:
+                        "Your homeserver does not support the features required to join this room"
+                            .to_owned(),
+                },
+                status_code: http::StatusCode::BAD_REQUEST,
+            },
+        );
+        assert_eq!(
+            incompatible_room_version(&rejected),
+            Some(RoomVersionId::V1)
+        );
+        assert_eq!(
+            incompatible_room_version(&Error::BadServerResponse(
+                "Server returned bad 200 response."
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn members_are_filtered_by_membership() {
+        let join = Some(&MembershipEventFilter::Join);
+        let leave = Some(&MembershipEventFilter::Leave);
+
+        assert!(membership_matches(&MembershipState::Join, None, None));
+        assert!(membership_matches(&MembershipState::Join, join, None));
+        assert!(!membership_matches(&MembershipState::Invite, join, None));
+
+        assert!(!membership_matches(&MembershipState::Leave, None, leave));
+        assert!(membership_matches(&MembershipState::Ban, None, leave));
+        // Contradicting filters match nothing
+        assert!(!membership_matches(&MembershipState::Join, join, join));
+    }
+
+    #[test]
+    fn third_party_invite_event_lists_the_identity_server_keys() {
+        let response: StoreInviteResponse = serde_json::from_value(serde_json::json!({
+            "token": "abc",
+            "display_name": "a...@e...",
+            "public_keys": [
+                {
+                    "public_key": "key1",
+                    "key_validity_url": "https://id.example.org/_matrix/identity/v2/pubkey/isvalid",
+                },
+                {
+                    "public_key": "key2",
+                    "key_validity_url": "https://id.example.org/_matrix/identity/v2/pubkey/ephemeral/isvalid",
+                },
+            ],
+        }))
+        .unwrap();
+
+        let content = third_party_invite_content(&response).unwrap();
+
+        assert_eq!(content["display_name"], "a...@e...");
+        assert_eq!(content["public_key"], "key1");
+        assert_eq!(
+            content["key_validity_url"],
+            "https://id.example.org/_matrix/identity/v2/pubkey/isvalid"
+        );
+        assert_eq!(content["public_keys"][1]["public_key"], "key2");
+        // The content is what clients and the auth rules expect
+        serde_json::from_value::<RoomThirdPartyInviteEventContent>(content).unwrap();
+
+        let no_keys: StoreInviteResponse = serde_json::from_value(serde_json::json!({
+            "token": "abc",
+            "display_name": "a...@e...",
+            "public_keys": [],
+        }))
+        .unwrap();
+        assert!(third_party_invite_content(&no_keys).is_err());
+    }
+
+    #[tokio::test]
+    async fn joined_members_follow_membership_and_profile_changes() {
+        let alice = testing::user("joined_members_alice").await;
+        let bob = testing::user("joined_members_bob").await;
+        let room_id = testing::room(&alice).await;
+
+        let joined = || async {
+            joined_members_route(testing::request(
+                joined_members::v3::Request::new(room_id.clone()),
+                &alice,
+            ))
+            .await
+            .unwrap()
+            .joined
+        };
+
+        assert_eq!(joined().await.keys().collect::<Vec<_>>(), vec![&alice]);
+
+        testing::invite(&alice, &bob, &room_id).await;
+        testing::join(&bob, &room_id).await;
+        assert!(joined().await.contains_key(&bob));
+
+        // The new display name is sent into the room in the background
+        set_displayname_route(testing::request(
+            set_display_name::v3::Request::new(bob.clone(), Some("Bobby".to_owned())),
+            &bob,
+        ))
+        .await
+        .unwrap();
+        let mut display_name = None;
+        for _ in 0..100 {
+            display_name = joined().await[&bob].display_name.clone();
+            if display_name.as_deref() == Some("Bobby") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(display_name.as_deref(), Some("Bobby"));
+
+        leave_room_route(testing::request(
+            leave_room::v3::Request::new(room_id.clone()),
+            &bob,
+        ))
+        .await
+        .unwrap();
+        assert!(!joined().await.contains_key(&bob));
+    }
+
+    #[tokio::test]
+    async fn members_are_filtered_and_read_at_a_token() {
+        let alice = testing::user("members_at_alice").await;
+        let bob = testing::user("members_at_bob").await;
+        let carol = testing::user("members_at_carol").await;
+        let dave = testing::user("members_at_dave").await;
+        let room_id = testing::room(&alice).await;
+        testing::invite(&alice, &bob, &room_id).await;
+        testing::invite(&alice, &carol, &room_id).await;
+        testing::join(&carol, &room_id).await;
+
+        // A sync token from before bob joined
+        let before_join = services().globals.current_count().unwrap().to_string();
+        testing::join(&bob, &room_id).await;
+
+        let members = |sender: &UserId,
+                       at: Option<&str>,
+                       membership: Option<MembershipEventFilter>,
+                       not_membership: Option<MembershipEventFilter>| {
+            let mut request = get_member_events::v3::Request::new(room_id.clone());
+            request.at = at.map(ToOwned::to_owned);
+            request.membership = membership;
+            request.not_membership = not_membership;
+
+            get_member_events_route(testing::request(request, sender))
+        };
+        let state_keys = |response: get_member_events::v3::Response| {
+            let mut state_keys: Vec<_> = response
+                .chunk
+                .iter()
+                .map(|event| {
+                    serde_json::from_str::<serde_json::Value>(event.json().get()).unwrap()
+                        ["state_key"]
+                        .as_str()
+                        .unwrap()
+                        .to_owned()
+                })
+                .collect();
+            state_keys.sort();
+            state_keys
+        };
+        let sorted = |users: &[&UserId]| {
+            let mut users: Vec<_> = users.iter().map(|user| user.to_string()).collect();
+            users.sort();
+            users
+        };
+
+        let joined = members(&alice, None, Some(MembershipEventFilter::Join), None)
+            .await
+            .unwrap();
+        assert_eq!(state_keys(joined), sorted(&[&alice, &bob, &carol]));
+
+        let joined_before = members(
+            &alice,
+            Some(&before_join),
+            Some(MembershipEventFilter::Join),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(state_keys(joined_before), sorted(&[&alice, &carol]));
+
+        let not_joined_before = members(
+            &bob,
+            Some(&before_join),
+            None,
+            Some(MembershipEventFilter::Join),
+        )
+        .await
+        .unwrap();
+        assert_eq!(state_keys(not_joined_before), sorted(&[&bob]));
+
+        assert!(matches!(
+            members(&dave, None, None, None).await,
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn joining_stops_at_the_joined_rooms_limit() {
+        use ruma::api::client::room::create_room;
+
+        let alice = testing::user("join_limit_alice").await;
+        let carol = testing::user("join_limit_carol").await;
+        let public_room = || {
+            testing::room_with(
+                &alice,
+                create_room::v3::Request {
+                    preset: Some(create_room::v3::RoomPreset::PublicChat),
+                    ..create_room::v3::Request::new()
+                },
+            )
+        };
+        let first = public_room().await;
+        let second = public_room().await;
+        let join = |room_id: &RoomId| {
+            join_room_by_id_route(testing::request(
+                join_room_by_id::v3::Request::new(room_id.to_owned()),
+                &carol,
+            ))
+        };
+
+        for _ in 0..5 {
+            testing::room(&carol).await;
+        }
+        join(&first).await.unwrap();
+        assert!(matches!(
+            join(&second).await,
+            Err(Error::BadRequest(ErrorKind::LimitExceeded { .. }, _))
+        ));
+
+        // Leaving frees the slot, unlike for rooms created
+        leave_room(&carol, &first, None).await.unwrap();
+        join(&second).await.unwrap();
+    }
+}
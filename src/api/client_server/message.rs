@@ -1,10 +1,10 @@
-use crate::{service::pdu::PduBuilder, services, utils, Error, Result, Ruma};
+use crate::{service::pdu::PduBuilder, services, utils, Error, PduEvent, Result, Ruma};
 use ruma::{
     api::client::{
         error::ErrorKind,
         message::{get_message_events, send_message_event},
     },
-    events::{RoomEventType, StateEventType},
+    events::StateEventType,
 };
 use std::{
     collections::{BTreeMap, HashSet},
@@ -18,6 +18,8 @@ use std::{
 /// - Is a NOOP if the txn id was already used before and returns the same event id again
 /// - The only requirement for the content is that it has to be valid json
 /// - Tries to send the event into the room, auth rules will determine if it is allowed
+/// - Messages are rate limited per user and room, except for admins, appservices and call
+/// signalling
 pub async fn send_message_event_route(
     body: Ruma<send_message_event::v3::Request>,
 ) -> Result<send_message_event::v3::Response> {
@@ -35,16 +37,6 @@ pub async fn send_message_event_route(
     );
     let state_lock = mutex_state.lock().await;
 
-    // Forbid m.room.encrypted if encryption is disabled
-    if RoomEventType::RoomEncrypted == body.event_type.to_string().into()
-        && !services().globals.allow_encryption()
-    {
-        return Err(Error::BadRequest(
-            ErrorKind::Forbidden,
-            "Encryption has been disabled",
-        ));
-    }
-
     // Check if this is a new transaction id
     if let Some(response) =
         services()
@@ -67,6 +59,13 @@ pub async fn send_message_event_route(
         return Ok(send_message_event::v3::Response { event_id });
     }
 
+    services().rooms.timeline.check_message_event(
+        sender_user,
+        &body.room_id,
+        &body.event_type.to_string(),
+        body.from_appservice,
+    )?;
+
     let mut unsigned = BTreeMap::new();
     unsigned.insert("transaction_id".to_owned(), body.txn_id.to_string().into());
 
@@ -102,18 +101,34 @@ pub async fn send_message_event_route(
 ///
 /// Allows paginating through room history.
 ///
-/// - Only works if the user is joined (TODO: always allow, but only show events where the user was
-/// joined, depending on history_visibility)
+/// - Only works if the user is or was joined, or if the room is world readable. Peeking into world
+/// readable rooms also works without an access token
+/// - Only returns events the user is allowed to see according to the history visibility
 pub async fn get_message_events_route(
     body: Ruma<get_message_events::v3::Request>,
 ) -> Result<get_message_events::v3::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
-    let sender_device = body.sender_device.as_ref().expect("user is authenticated");
+    let sender_user = body.sender_user.as_deref();
+    let sender_device = body.sender_device.as_deref();
+
+    let is_member = match sender_user {
+        Some(sender_user) => {
+            services()
+                .rooms
+                .state_cache
+                .is_joined(sender_user, &body.room_id)?
+                || services()
+                    .rooms
+                    .state_cache
+                    .once_joined(sender_user, &body.room_id)?
+        }
+        None => false,
+    };
 
-    if !services()
-        .rooms
-        .state_cache
-        .is_joined(sender_user, &body.room_id)?
+    if !is_member
+        && !services()
+            .rooms
+            .state_accessor
+            .is_world_readable(&body.room_id)?
     {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
@@ -121,6 +136,25 @@ pub async fn get_message_events_route(
         ));
     }
 
+    // Peeking users don't have their own events in the room, so any user works for reading
+    let conduit_user = services().globals.server_user();
+    let reading_user = sender_user.unwrap_or(&*conduit_user);
+
+    let can_see = |pdu: &PduEvent| {
+        match sender_user {
+            Some(sender_user) => services().rooms.state_accessor.user_can_see_event(
+                sender_user,
+                &body.room_id,
+                &pdu.event_id,
+            ),
+            None => services()
+                .rooms
+                .state_accessor
+                .world_can_see_event(&pdu.event_id),
+        }
+        .unwrap_or(false)
+    };
+
     let from = match body.from.clone() {
         Some(from) => from
             .parse()
@@ -134,12 +168,14 @@ pub async fn get_message_events_route(
 
     let to = body.to.as_ref().map(|t| t.parse());
 
-    services().rooms.lazy_loading.lazy_load_confirm_delivery(
-        sender_user,
-        sender_device,
-        &body.room_id,
-        from,
-    )?;
+    if let (Some(sender_user), Some(sender_device)) = (sender_user, sender_device) {
+        services().rooms.lazy_loading.lazy_load_confirm_delivery(
+            sender_user,
+            sender_device,
+            &body.room_id,
+            from,
+        )?;
+    }
 
     // Use limit or else 10
     let limit = body.limit.try_into().map_or(10_usize, |l: u32| l as usize);
@@ -155,7 +191,7 @@ pub async fn get_message_events_route(
             let events_after: Vec<_> = services()
                 .rooms
                 .timeline
-                .pdus_after(sender_user, &body.room_id, from)?
+                .pdus_after(reading_user, &body.room_id, from)?
                 .take(limit)
                 .filter_map(|r| r.ok()) // Filter out buggy events
                 .filter_map(|(pdu_id, pdu)| {
@@ -169,6 +205,14 @@ pub async fn get_message_events_route(
                 .take_while(|&(k, _)| Some(Ok(k)) != to) // Stop at `to`
                 .collect();
 
+            // Continue after the last event we looked at, even if it is hidden
+            next_token = events_after.last().map(|(count, _)| count).copied();
+
+            let events_after: Vec<_> = events_after
+                .into_iter()
+                .filter(|(_, pdu)| can_see(pdu))
+                .collect();
+
             for (_, event) in &events_after {
                 /* TODO: Remove this when these are resolved:
                  * https://github.com/vector-im/element-android/issues/3417
@@ -185,8 +229,6 @@ pub async fn get_message_events_route(
                 lazy_loaded.insert(event.sender.clone());
             }
 
-            next_token = events_after.last().map(|(count, _)| count).copied();
-
             let events_after: Vec<_> = events_after
                 .into_iter()
                 .map(|(_, pdu)| pdu.to_room_event())
@@ -200,7 +242,7 @@ pub async fn get_message_events_route(
             let events_before: Vec<_> = services()
                 .rooms
                 .timeline
-                .pdus_until(sender_user, &body.room_id, from)?
+                .pdus_until(reading_user, &body.room_id, from)?
                 .take(limit)
                 .filter_map(|r| r.ok()) // Filter out buggy events
                 .filter_map(|(pdu_id, pdu)| {
@@ -214,6 +256,14 @@ pub async fn get_message_events_route(
                 .take_while(|&(k, _)| Some(Ok(k)) != to) // Stop at `to`
                 .collect();
 
+            // Continue after the last event we looked at, even if it is hidden
+            next_token = events_before.last().map(|(count, _)| count).copied();
+
+            let events_before: Vec<_> = events_before
+                .into_iter()
+                .filter(|(_, pdu)| can_see(pdu))
+                .collect();
+
             for (_, event) in &events_before {
                 /* TODO: Remove this when these are resolved:
                  * https://github.com/vector-im/element-android/issues/3417
@@ -230,8 +280,6 @@ pub async fn get_message_events_route(
                 lazy_loaded.insert(event.sender.clone());
             }
 
-            next_token = events_before.last().map(|(count, _)| count).copied();
-
             let events_before: Vec<_> = events_before
                 .into_iter()
                 .map(|(_, pdu)| pdu.to_room_event())
@@ -4,6 +4,7 @@ mod backup;
 mod capabilities;
 mod config;
 mod context;
+mod delayed_event;
 mod device;
 mod directory;
 mod filter;
@@ -11,6 +12,7 @@ mod keys;
 mod media;
 mod membership;
 mod message;
+mod peek;
 mod presence;
 mod profile;
 mod push;
@@ -24,6 +26,7 @@ mod state;
 mod sync;
 mod tag;
 mod thirdparty;
+mod timestamp;
 mod to_device;
 mod typing;
 mod unversioned;
@@ -36,6 +39,7 @@ pub use backup::*;
 pub use capabilities::*;
 pub use config::*;
 pub use context::*;
+pub use delayed_event::*;
 pub use device::*;
 pub use directory::*;
 pub use filter::*;
@@ -43,6 +47,7 @@ pub use keys::*;
 pub use media::*;
 pub use membership::*;
 pub use message::*;
+pub use peek::*;
 pub use presence::*;
 pub use profile::*;
 pub use push::*;
@@ -56,6 +61,7 @@ pub use state::*;
 pub use sync::*;
 pub use tag::*;
 pub use thirdparty::*;
+pub use timestamp::*;
 pub use to_device::*;
 pub use typing::*;
 pub use unversioned::*;
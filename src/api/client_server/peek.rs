@@ -0,0 +1,259 @@
+use crate::{services, Error, PduEvent, Result};
+use axum::{extract::Query, Json};
+use ruma::{api::client::error::ErrorKind, OwnedRoomId, RoomId};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// How many of the latest events a peek returns
+const PEEK_LIMIT: usize = 20;
+/// Upper bound and default for the `timeout` of a peeking client
+const MAX_PEEK_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn check_world_readable(room_id: &RoomId) -> Result<()> {
+    if !services().rooms.state_accessor.is_world_readable(room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Only world readable rooms can be peeked into.",
+        ));
+    }
+
+    Ok(())
+}
+
+fn world_can_see(pdu: &PduEvent) -> bool {
+    services()
+        .rooms
+        .state_accessor
+        .world_can_see_event(&pdu.event_id)
+        .unwrap_or(false)
+}
+
+/// # `GET /_matrix/client/r0/rooms/{roomId}/initialSync`
+///
+/// Returns the current state and latest messages of a room, without joining it.
+///
+/// - Only works for world readable rooms, also without an access token
+pub async fn peek_room(room_id: &RoomId) -> Result<Value> {
+    check_world_readable(room_id)?;
+
+    // Peeking users don't have their own events in the room, so any user works for reading
+    let conduit_user = services().globals.server_user();
+
+    let latest: Vec<_> = services()
+        .rooms
+        .timeline
+        .pdus_until(&conduit_user, room_id, u64::MAX)?
+        .filter_map(|r| r.ok()) // Filter out buggy events
+        .take(PEEK_LIMIT)
+        .collect();
+
+    let start = latest
+        .last()
+        .and_then(|(pdu_id, _)| services().rooms.timeline.pdu_count(pdu_id).ok())
+        .map(|count| count.to_string());
+    let end = latest
+        .first()
+        .and_then(|(pdu_id, _)| services().rooms.timeline.pdu_count(pdu_id).ok())
+        .map(|count| count.to_string());
+
+    let chunk: Vec<_> = latest
+        .into_iter()
+        .rev()
+        .filter(|(_, pdu)| world_can_see(pdu))
+        .map(|(_, pdu)| pdu.to_room_event())
+        .collect();
+
+    let state: Vec<_> = services()
+        .rooms
+        .state_accessor
+        .room_state_full(room_id)
+        .await?
+        .values()
+        .map(|pdu| pdu.to_state_event())
+        .collect();
+
+    Ok(json!({
+        "room_id": room_id,
+        "messages": {
+            "chunk": chunk,
+            "start": start,
+            "end": end,
+        },
+        "state": state,
+        "presence": [],
+        "account_data": [],
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct PeekEventsParams {
+    room_id: OwnedRoomId,
+    from: Option<String>,
+    /// In milliseconds
+    timeout: Option<u64>,
+}
+
+/// # `GET /_matrix/client/r0/events?room_id={roomId}`
+///
+/// Returns the events sent to a room after `from`, waiting up to `timeout` for new ones. Peeking
+/// clients poll this instead of /sync.
+///
+/// - Only works for world readable rooms, also without an access token
+/// - Without `from`, only returns events sent after the request
+pub async fn peek_events_route(Query(params): Query<PeekEventsParams>) -> Result<Json<Value>> {
+    let from = params
+        .from
+        .map(|from| from.parse())
+        .transpose()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from` value."))?;
+    let timeout = params
+        .timeout
+        .map_or(MAX_PEEK_TIMEOUT, Duration::from_millis);
+
+    Ok(Json(peek_events(&params.room_id, from, timeout).await?))
+}
+
+/// Returns the events after `from`, see `peek_events_route`.
+pub async fn peek_events(room_id: &RoomId, from: Option<u64>, timeout: Duration) -> Result<Value> {
+    check_world_readable(room_id)?;
+
+    let conduit_user = services().globals.server_user();
+    let from = match from {
+        Some(from) => from,
+        None => services()
+            .rooms
+            .timeline
+            .last_timeline_count(&conduit_user, room_id)?,
+    };
+
+    let events_after = |from| -> Result<Vec<(u64, PduEvent)>> {
+        Ok(services()
+            .rooms
+            .timeline
+            .pdus_after(&conduit_user, room_id, from)?
+            .filter_map(|r| r.ok()) // Filter out buggy events
+            .take(PEEK_LIMIT)
+            .filter_map(|(pdu_id, pdu)| {
+                let count = services().rooms.timeline.pdu_count(&pdu_id).ok()?;
+                Some((count, pdu))
+            })
+            .collect())
+    };
+
+    // Started before the first read, so events sent in between wake us up
+    let new_pdus = services().rooms.timeline.watch_pdus(room_id)?;
+    let mut events = events_after(from)?;
+    if events.is_empty() {
+        let _ = tokio::time::timeout(timeout.min(MAX_PEEK_TIMEOUT), new_pdus).await;
+        events = events_after(from)?;
+    }
+
+    // Continue after the last event, even if it is hidden
+    let end = events.last().map_or(from, |(count, _)| *count);
+    let chunk: Vec<_> = events
+        .into_iter()
+        .filter(|(_, pdu)| world_can_see(pdu))
+        .map(|(_, pdu)| pdu.to_room_event())
+        .collect();
+
+    Ok(json!({
+        "start": from.to_string(),
+        "end": end.to_string(),
+        "chunk": chunk,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::{
+        api::client::{message::get_message_events, room::create_room, Direction},
+        serde::Raw,
+        UserId,
+    };
+    use serde_json::value::to_raw_value;
+
+    use super::*;
+    use crate::{api::client_server::get_message_events_route, utils::testing};
+
+    async fn room_with_visibility(creator: &UserId, history_visibility: &str) -> OwnedRoomId {
+        testing::room_with(
+            creator,
+            create_room::v3::Request {
+                initial_state: vec![Raw::from_json(
+                    to_raw_value(&json!({
+                        "type": "m.room.history_visibility",
+                        "state_key": "",
+                        "content": { "history_visibility": history_visibility },
+                    }))
+                    .unwrap(),
+                )],
+                ..create_room::v3::Request::new()
+            },
+        )
+        .await
+    }
+
+    fn is_forbidden<T>(result: Result<T>) -> bool {
+        matches!(result, Err(Error::BadRequest(ErrorKind::Forbidden, _)))
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_clients_only_read_world_readable_rooms() {
+        let alice = testing::user("peek_alice").await;
+        let world_readable = room_with_visibility(&alice, "world_readable").await;
+        let shared = room_with_visibility(&alice, "shared").await;
+        let message = testing::send_message(&alice, &world_readable, "hello world").await;
+        testing::send_message(&alice, &shared, "hello members").await;
+
+        let messages = |room_id: &RoomId| {
+            get_message_events_route(testing::unauthenticated_request(
+                get_message_events::v3::Request::new(room_id.to_owned(), Direction::Backward),
+            ))
+        };
+        let event_ids: Vec<_> = messages(&world_readable)
+            .await
+            .unwrap()
+            .chunk
+            .iter()
+            .filter_map(|event| event.get_field::<String>("event_id").unwrap())
+            .collect();
+        assert!(event_ids.contains(&message.to_string()));
+        assert!(is_forbidden(messages(&shared).await));
+
+        let peek = peek_room(&world_readable).await.unwrap();
+        assert!(peek["messages"]["chunk"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|event| event["event_id"] == message.as_str()));
+        assert!(is_forbidden(peek_room(&shared).await));
+        assert!(is_forbidden(
+            peek_events(&shared, None, Duration::ZERO).await
+        ));
+    }
+
+    #[tokio::test]
+    async fn peeking_clients_get_new_events() {
+        let bob = testing::user("peek_bob").await;
+        let room_id = room_with_visibility(&bob, "world_readable").await;
+
+        let peek = {
+            let room_id = room_id.clone();
+            tokio::spawn(async move { peek_events(&room_id, None, Duration::from_secs(10)).await })
+        };
+        // Let the peek start waiting
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let message = testing::send_message(&bob, &room_id, "hello peekers").await;
+
+        let response = peek.await.unwrap().unwrap();
+        assert_eq!(response["chunk"][0]["event_id"], message.as_str());
+
+        // The next poll continues after it
+        let from = response["end"].as_str().unwrap().parse().unwrap();
+        let response = peek_events(&room_id, Some(from), Duration::ZERO)
+            .await
+            .unwrap();
+        assert!(response["chunk"].as_array().unwrap().is_empty());
+    }
+}
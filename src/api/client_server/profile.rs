@@ -1,4 +1,7 @@
-use crate::{service::pdu::PduBuilder, services, utils, Error, Result, Ruma};
+use std::collections::BTreeMap;
+
+use crate::{api::ruma_wrapper::SenderUser, services, Error, Result, Ruma};
+use axum::{extract::Path, Json};
 use ruma::{
     api::{
         client::{
@@ -9,108 +12,32 @@ use ruma::{
         },
         federation::{self, query::get_profile_information::v1::ProfileField},
     },
-    events::{room::member::RoomMemberEventContent, RoomEventType, StateEventType},
+    OwnedMxcUri, OwnedUserId, UserId,
 };
-use serde_json::value::to_raw_value;
-use std::sync::Arc;
+use serde_json::{json, Map, Value};
 
 /// # `PUT /_matrix/client/r0/profile/{userId}/displayname`
 ///
 /// Updates the displayname.
 ///
-/// - Also makes sure other users receive the update using presence EDUs
+/// - Also sends new member events and presence EDUs into all joined rooms, in the background
 pub async fn set_displayname_route(
     body: Ruma<set_display_name::v3::Request>,
 ) -> Result<set_display_name::v3::Response> {
+    if !services().globals.allow_displayname_change() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Changing display names is disabled on this server.",
+        ));
+    }
+
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
     services()
         .users
         .set_displayname(sender_user, body.displayname.clone())?;
 
-    // Send a new membership event and presence update into all joined rooms
-    let all_rooms_joined: Vec<_> = services()
-        .rooms
-        .state_cache
-        .rooms_joined(sender_user)
-        .filter_map(|r| r.ok())
-        .map(|room_id| {
-            Ok::<_, Error>((
-                PduBuilder {
-                    event_type: RoomEventType::RoomMember,
-                    content: to_raw_value(&RoomMemberEventContent {
-                        displayname: body.displayname.clone(),
-                        ..serde_json::from_str(
-                            services()
-                                .rooms
-                                .state_accessor
-                                .room_state_get(
-                                    &room_id,
-                                    &StateEventType::RoomMember,
-                                    sender_user.as_str(),
-                                )?
-                                .ok_or_else(|| {
-                                    Error::bad_database(
-                                        "Tried to send displayname update for user not in the \
-                                     room.",
-                                    )
-                                })?
-                                .content
-                                .get(),
-                        )
-                        .map_err(|_| Error::bad_database("Database contains invalid PDU."))?
-                    })
-                    .expect("event is valid, we just created it"),
-                    unsigned: None,
-                    state_key: Some(sender_user.to_string()),
-                    redacts: None,
-                },
-                room_id,
-            ))
-        })
-        .filter_map(|r| r.ok())
-        .collect();
-
-    for (pdu_builder, room_id) in all_rooms_joined {
-        let mutex_state = Arc::clone(
-            services()
-                .globals
-                .roomid_mutex_state
-                .write()
-                .unwrap()
-                .entry(room_id.clone())
-                .or_default(),
-        );
-        let state_lock = mutex_state.lock().await;
-
-        let _ = services().rooms.timeline.build_and_append_pdu(
-            pdu_builder,
-            sender_user,
-            &room_id,
-            &state_lock,
-        );
-
-        // Presence update
-        services().rooms.edus.presence.update_presence(
-            sender_user,
-            &room_id,
-            ruma::events::presence::PresenceEvent {
-                content: ruma::events::presence::PresenceEventContent {
-                    avatar_url: services().users.avatar_url(sender_user)?,
-                    currently_active: None,
-                    displayname: services().users.displayname(sender_user)?,
-                    last_active_ago: Some(
-                        utils::millis_since_unix_epoch()
-                            .try_into()
-                            .expect("time is valid"),
-                    ),
-                    presence: ruma::presence::PresenceState::Online,
-                    status_msg: None,
-                },
-                sender: sender_user.clone(),
-            },
-        )?;
-    }
+    services().users.propagate_profile(sender_user);
 
     Ok(set_display_name::v3::Response {})
 }
@@ -123,7 +50,10 @@ pub async fn set_displayname_route(
 pub async fn get_displayname_route(
     body: Ruma<get_display_name::v3::Request>,
 ) -> Result<get_display_name::v3::Response> {
-    if body.user_id.server_name() != services().globals.server_name() {
+    if !services()
+        .globals
+        .server_is_ours(body.user_id.server_name())
+    {
         let response = services()
             .sending
             .send_federation_request(
@@ -149,10 +79,17 @@ pub async fn get_displayname_route(
 ///
 /// Updates the avatar_url and blurhash.
 ///
-/// - Also makes sure other users receive the update using presence EDUs
+/// - Also sends new member events and presence EDUs into all joined rooms, in the background
 pub async fn set_avatar_url_route(
     body: Ruma<set_avatar_url::v3::Request>,
 ) -> Result<set_avatar_url::v3::Response> {
+    if !services().globals.allow_avatar_change() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Changing avatars is disabled on this server.",
+        ));
+    }
+
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
     services()
@@ -163,89 +100,7 @@ pub async fn set_avatar_url_route(
         .users
         .set_blurhash(sender_user, body.blurhash.clone())?;
 
-    // Send a new membership event and presence update into all joined rooms
-    let all_joined_rooms: Vec<_> = services()
-        .rooms
-        .state_cache
-        .rooms_joined(sender_user)
-        .filter_map(|r| r.ok())
-        .map(|room_id| {
-            Ok::<_, Error>((
-                PduBuilder {
-                    event_type: RoomEventType::RoomMember,
-                    content: to_raw_value(&RoomMemberEventContent {
-                        avatar_url: body.avatar_url.clone(),
-                        ..serde_json::from_str(
-                            services()
-                                .rooms
-                                .state_accessor
-                                .room_state_get(
-                                    &room_id,
-                                    &StateEventType::RoomMember,
-                                    sender_user.as_str(),
-                                )?
-                                .ok_or_else(|| {
-                                    Error::bad_database(
-                                        "Tried to send displayname update for user not in the \
-                                     room.",
-                                    )
-                                })?
-                                .content
-                                .get(),
-                        )
-                        .map_err(|_| Error::bad_database("Database contains invalid PDU."))?
-                    })
-                    .expect("event is valid, we just created it"),
-                    unsigned: None,
-                    state_key: Some(sender_user.to_string()),
-                    redacts: None,
-                },
-                room_id,
-            ))
-        })
-        .filter_map(|r| r.ok())
-        .collect();
-
-    for (pdu_builder, room_id) in all_joined_rooms {
-        let mutex_state = Arc::clone(
-            services()
-                .globals
-                .roomid_mutex_state
-                .write()
-                .unwrap()
-                .entry(room_id.clone())
-                .or_default(),
-        );
-        let state_lock = mutex_state.lock().await;
-
-        let _ = services().rooms.timeline.build_and_append_pdu(
-            pdu_builder,
-            sender_user,
-            &room_id,
-            &state_lock,
-        );
-
-        // Presence update
-        services().rooms.edus.presence.update_presence(
-            sender_user,
-            &room_id,
-            ruma::events::presence::PresenceEvent {
-                content: ruma::events::presence::PresenceEventContent {
-                    avatar_url: services().users.avatar_url(sender_user)?,
-                    currently_active: None,
-                    displayname: services().users.displayname(sender_user)?,
-                    last_active_ago: Some(
-                        utils::millis_since_unix_epoch()
-                            .try_into()
-                            .expect("time is valid"),
-                    ),
-                    presence: ruma::presence::PresenceState::Online,
-                    status_msg: None,
-                },
-                sender: sender_user.clone(),
-            },
-        )?;
-    }
+    services().users.propagate_profile(sender_user);
 
     Ok(set_avatar_url::v3::Response {})
 }
@@ -258,7 +113,10 @@ pub async fn set_avatar_url_route(
 pub async fn get_avatar_url_route(
     body: Ruma<get_avatar_url::v3::Request>,
 ) -> Result<get_avatar_url::v3::Response> {
-    if body.user_id.server_name() != services().globals.server_name() {
+    if !services()
+        .globals
+        .server_is_ours(body.user_id.server_name())
+    {
         let response = services()
             .sending
             .send_federation_request(
@@ -284,13 +142,14 @@ pub async fn get_avatar_url_route(
 
 /// # `GET /_matrix/client/r0/profile/{userId}`
 ///
-/// Returns the displayname, avatar_url and blurhash of the user.
+/// Returns the displayname, avatar_url, blurhash and custom fields of the profile of the user.
 ///
-/// - If user is on another server: Fetches profile over federation
-pub async fn get_profile_route(
-    body: Ruma<get_profile::v3::Request>,
-) -> Result<get_profile::v3::Response> {
-    if body.user_id.server_name() != services().globals.server_name() {
+/// - If user is on another server: Fetches profile over federation, without custom fields
+pub async fn get_profile_route(body: Ruma<get_profile::v3::Request>) -> Result<Json<Value>> {
+    if !services()
+        .globals
+        .server_is_ours(body.user_id.server_name())
+    {
         let response = services()
             .sending
             .send_federation_request(
@@ -302,11 +161,12 @@ pub async fn get_profile_route(
             )
             .await?;
 
-        return Ok(get_profile::v3::Response {
-            displayname: response.displayname,
-            avatar_url: response.avatar_url,
-            blurhash: response.blurhash,
-        });
+        return Ok(Json(profile_json(
+            response.displayname,
+            response.avatar_url,
+            response.blurhash,
+            BTreeMap::new(),
+        )));
     }
 
     if !services().users.exists(&body.user_id)? {
@@ -317,9 +177,155 @@ pub async fn get_profile_route(
         ));
     }
 
-    Ok(get_profile::v3::Response {
-        avatar_url: services().users.avatar_url(&body.user_id)?,
-        blurhash: services().users.blurhash(&body.user_id)?,
-        displayname: services().users.displayname(&body.user_id)?,
-    })
+    Ok(Json(local_profile_json(&body.user_id)?))
+}
+
+/// # `GET /_matrix/client/unstable/uk.tcpip.msc4133/profile/{userId}/{keyName}`
+///
+/// Returns one field of the profile of the user, which can be a custom field (MSC4133).
+///
+/// - Only works for users on this server
+pub async fn get_profile_field_route(
+    Path((user_id, key)): Path<(String, String)>,
+) -> Result<Json<Value>> {
+    let user_id = parse_user_id(&user_id)?;
+    if !services().globals.server_is_ours(user_id.server_name()) {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Custom profile fields of remote users are not supported.",
+        ));
+    }
+
+    if !services().users.exists(&user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Profile was not found.",
+        ));
+    }
+
+    let value = local_profile_json(&user_id)?
+        .get(&key)
+        .cloned()
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Profile field was not found.",
+        ))?;
+
+    Ok(Json(json!({ key: value })))
+}
+
+/// # `PUT /_matrix/client/unstable/uk.tcpip.msc4133/profile/{userId}/{keyName}`
+///
+/// Sets a custom field of the profile of the sender user (MSC4133).
+///
+/// - The body has to contain the key
+/// - Fields are limited to 4 KiB and the whole profile to 64 KiB
+pub async fn set_profile_field_route(
+    SenderUser(sender_user): SenderUser,
+    Path((user_id, key)): Path<(String, String)>,
+    Json(body): Json<Map<String, Value>>,
+) -> Result<Json<Value>> {
+    if parse_user_id(&user_id)? != sender_user {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You can only change your own profile.",
+        ));
+    }
+
+    let value = body.get(&key).ok_or(Error::BadRequest(
+        ErrorKind::MissingParam,
+        "Body has to contain the profile key.",
+    ))?;
+
+    services()
+        .users
+        .set_profile_field(&sender_user, &key, Some(value))?;
+
+    Ok(Json(json!({})))
+}
+
+/// # `DELETE /_matrix/client/unstable/uk.tcpip.msc4133/profile/{userId}/{keyName}`
+///
+/// Removes a custom field of the profile of the sender user (MSC4133).
+pub async fn delete_profile_field_route(
+    SenderUser(sender_user): SenderUser,
+    Path((user_id, key)): Path<(String, String)>,
+) -> Result<Json<Value>> {
+    if parse_user_id(&user_id)? != sender_user {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You can only change your own profile.",
+        ));
+    }
+
+    services()
+        .users
+        .set_profile_field(&sender_user, &key, None)?;
+
+    Ok(Json(json!({})))
+}
+
+fn parse_user_id(user_id: &str) -> Result<OwnedUserId> {
+    UserId::parse(user_id)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid user id."))
+}
+
+/// The whole profile of a user on this server, including custom fields.
+pub(crate) fn local_profile_json(user_id: &UserId) -> Result<Value> {
+    Ok(profile_json(
+        services().users.displayname(user_id)?,
+        services().users.avatar_url(user_id)?,
+        services().users.blurhash(user_id)?,
+        services().users.profile_fields(user_id)?,
+    ))
+}
+
+/// The JSON of a profile, in the format of the profile endpoints.
+pub(crate) fn profile_json(
+    displayname: Option<String>,
+    avatar_url: Option<OwnedMxcUri>,
+    blurhash: Option<String>,
+    custom_fields: BTreeMap<String, Value>,
+) -> Value {
+    let mut profile: Map<String, Value> = custom_fields.into_iter().collect();
+    if let Some(displayname) = displayname {
+        profile.insert("displayname".to_owned(), displayname.into());
+    }
+    if let Some(avatar_url) = avatar_url {
+        profile.insert("avatar_url".to_owned(), avatar_url.to_string().into());
+    }
+    if let Some(blurhash) = blurhash {
+        profile.insert("xyz.amorgan.blurhash".to_owned(), blurhash.into());
+    }
+
+    Value::Object(profile)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn profiles_contain_custom_fields() {
+        let custom_fields = BTreeMap::from([
+            ("io.fsky.pronouns".to_owned(), json!("she/her")),
+            ("us.cloke.msc4175.tz".to_owned(), json!("Europe/London")),
+        ]);
+
+        assert_eq!(
+            profile_json(
+                Some("Alice".to_owned()),
+                Some("mxc://example.org/abc".into()),
+                None,
+                custom_fields,
+            ),
+            json!({
+                "displayname": "Alice",
+                "avatar_url": "mxc://example.org/abc",
+                "io.fsky.pronouns": "she/her",
+                "us.cloke.msc4175.tz": "Europe/London",
+            })
+        );
+        assert_eq!(profile_json(None, None, None, BTreeMap::new()), json!({}));
+    }
 }
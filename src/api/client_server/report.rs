@@ -1,14 +1,24 @@
-use crate::{services, utils::HtmlEscape, Error, Result, Ruma};
+use crate::{
+    api::ruma_wrapper::SenderUser,
+    service::reports::{check_reason, Report},
+    services,
+    utils::HtmlEscape,
+    Error, Result, Ruma,
+};
+use axum::{extract::Path, Json};
 use ruma::{
     api::client::{error::ErrorKind, room::report_content},
     events::room::message,
-    int,
+    int, MilliSecondsSinceUnixEpoch, RoomId,
 };
+use serde::Deserialize;
+use serde_json::{json, Value};
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/report/{eventId}`
 ///
 /// Reports an inappropriate event to homeserver admins
 ///
+/// - Event and room reports count against a limit per reporter
 pub async fn report_event_route(
     body: Ruma<report_content::v3::Request>,
 ) -> Result<report_content::v3::Response> {
@@ -31,12 +41,17 @@ pub async fn report_event_route(
         ));
     };
 
-    if let Some(true) = body.reason.clone().map(|s| s.chars().count() > 250) {
-        return Err(Error::BadRequest(
-            ErrorKind::InvalidParam,
-            "Reason too long, should be 250 characters or fewer",
-        ));
-    };
+    check_reason(body.reason.as_deref())?;
+    services().reports.check_limit(sender_user)?;
+
+    services().reports.add_report(&Report {
+        reporter: sender_user.clone(),
+        room_id: pdu.room_id.clone(),
+        event_id: Some(pdu.event_id.as_ref().to_owned()),
+        score: body.score.map(i64::from),
+        reason: body.reason.clone(),
+        received_ts: MilliSecondsSinceUnixEpoch::now(),
+    })?;
 
     services().admin
         .send_message(message::RoomMessageEventContent::text_html(
@@ -67,3 +82,144 @@ pub async fn report_event_route(
 
     Ok(report_content::v3::Response {})
 }
+
+#[derive(Deserialize)]
+pub struct ReportRoomBody {
+    reason: Option<String>,
+}
+
+/// # `POST /_matrix/client/v3/rooms/{roomId}/report`
+///
+/// Reports a whole room to homeserver admins (MSC4151).
+///
+/// - The reporter doesn't have to be a member, rooms can be reported from invites and previews
+/// - Only rooms this server knows or the reporter is invited to can be reported
+/// - Event and room reports count against a limit per reporter
+pub async fn report_room_route(
+    SenderUser(sender_user): SenderUser,
+    Path(room_id): Path<String>,
+    Json(body): Json<ReportRoomBody>,
+) -> Result<Json<Value>> {
+    let room_id = RoomId::parse(room_id)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid room id."))?;
+    // Invites to rooms on other servers are all we know of some rooms
+    if !services().rooms.metadata.exists(&room_id)?
+        && !services()
+            .rooms
+            .state_cache
+            .is_invited(&sender_user, &room_id)?
+    {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Room not found."));
+    }
+
+    check_reason(body.reason.as_deref())?;
+    services().reports.check_limit(&sender_user)?;
+
+    services().reports.add_report(&Report {
+        reporter: sender_user.clone(),
+        room_id: room_id.clone(),
+        event_id: None,
+        score: None,
+        reason: body.reason.clone(),
+        received_ts: MilliSecondsSinceUnixEpoch::now(),
+    })?;
+
+    services()
+        .admin
+        .send_message(message::RoomMessageEventContent::text_html(
+            format!(
+                "Room report received from: {}\n\n\
+                Room ID: {:?}\n\n\
+                Report Reason: {:?}",
+                sender_user, room_id, body.reason
+            ),
+            format!(
+                "<details><summary>Room report received from: <a href=\"https://matrix.to/#/{0:?}\">{0:?}\
+                </a></summary><ul><li>Room ID: <code>{1:?}</code></li><li>Report Reason: {2}</li>\
+                </ul></details>",
+                sender_user,
+                room_id,
+                HtmlEscape(body.reason.as_deref().unwrap_or(""))
+            ),
+        ));
+
+    Ok(Json(json!({})))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        api::admin_server::{reports_route, AdminUser},
+        utils::testing,
+    };
+    use ruma::UserId;
+
+    async fn report_room(sender_user: &UserId, room_id: &str) -> Result<Json<Value>> {
+        report_room_route(
+            SenderUser(sender_user.to_owned()),
+            Path(room_id.to_owned()),
+            Json(ReportRoomBody {
+                reason: Some("Spam".to_owned()),
+            }),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn room_reports_are_listed_for_admins() {
+        let alice = testing::user("report_room_alice").await;
+        let bob = testing::user("report_room_bob").await;
+        let admin = testing::admin("report_room_admin").await;
+        let room_id = testing::room(&alice).await;
+
+        // Bob isn't a member, but can still report the room
+        report_room(&bob, room_id.as_str()).await.unwrap();
+
+        let reports = reports_route(AdminUser(admin)).await.unwrap().0;
+        let reports: Vec<_> = reports["reports"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|report| report["reporter"] == bob.as_str())
+            .collect();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0]["room_id"], room_id.as_str());
+        assert_eq!(reports[0]["event_id"], Value::Null);
+        assert_eq!(reports[0]["reason"], "Spam");
+
+        assert!(matches!(
+            report_room(&bob, "!unknown:conduit.test").await,
+            Err(Error::BadRequest(ErrorKind::NotFound, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reports_are_limited_per_reporter() {
+        let alice = testing::user("report_limit_alice").await;
+        let bob = testing::user("report_limit_bob").await;
+        let room_id = testing::room(&alice).await;
+
+        for _ in 0..10 {
+            report_room(&alice, room_id.as_str()).await.unwrap();
+        }
+        assert!(matches!(
+            report_room(&alice, room_id.as_str()).await,
+            Err(Error::BadRequest(ErrorKind::LimitExceeded { .. }, _))
+        ));
+
+        // Event reports count against the same limit
+        let event_id = testing::send_message(&alice, &room_id, "Reported").await;
+        assert!(matches!(
+            report_event_route(testing::request(
+                report_content::v3::Request::new(room_id.clone(), event_id, None, None),
+                &alice,
+            ))
+            .await,
+            Err(Error::BadRequest(ErrorKind::LimitExceeded { .. }, _))
+        ));
+
+        // Other reporters have their own limit
+        report_room(&bob, room_id.as_str()).await.unwrap();
+    }
+}
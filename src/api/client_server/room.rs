@@ -1,5 +1,8 @@
 use crate::{
-    api::client_server::invite_helper, service::pdu::PduBuilder, services, Error, Result, Ruma,
+    api::client_server::{can_publish_rooms, check_room_limits, invite_3pid_helper, invite_helper},
+    config::EncryptionDefault,
+    service::pdu::PduBuilder,
+    services, Error, PduEvent, Result, Ruma,
 };
 use ruma::{
     api::client::{
@@ -10,6 +13,7 @@ use ruma::{
         room::{
             canonical_alias::RoomCanonicalAliasEventContent,
             create::RoomCreateEventContent,
+            encryption::RoomEncryptionEventContent,
             guest_access::{GuestAccess, RoomGuestAccessEventContent},
             history_visibility::{HistoryVisibility, RoomHistoryVisibilityEventContent},
             join_rules::{JoinRule, RoomJoinRulesEventContent},
@@ -23,10 +27,15 @@ use ruma::{
     },
     int,
     serde::JsonObject,
-    CanonicalJsonObject, OwnedRoomAliasId, RoomAliasId, RoomId,
+    CanonicalJsonObject, EventEncryptionAlgorithm, OwnedRoomAliasId, OwnedUserId, RoomAliasId,
+    RoomId, UserId,
 };
 use serde_json::{json, value::to_raw_value};
-use std::{cmp::max, collections::BTreeMap, sync::Arc};
+use std::{
+    cmp::max,
+    collections::{BTreeMap, HashSet},
+    sync::Arc,
+};
 use tracing::{info, warn};
 
 /// # `POST /_matrix/client/r0/createRoom`
@@ -34,14 +43,18 @@ use tracing::{info, warn};
 /// Creates a new room.
 ///
 /// - Room ID is randomly generated
+/// - Fails once the user hit `max_rooms_per_user_join` or `max_rooms_per_user_create`, or created
+///   `max_rooms_created_per_hour` rooms within the last hour
+/// - Contradictory requests are rejected before anything is created
 /// - Create alias if room_alias_name is set
 /// - Send create event
 /// - Join sender user
 /// - Send power levels event
 /// - Send canonical room alias
-/// - Send join rules
-/// - Send history visibility
-/// - Send guest access
+/// - Send join rules, history visibility and guest access of the preset, unless initial state
+///   contains them
+/// - Enable encryption if `encryption_enabled_by_default_for_room_type` covers the room, unless
+///   initial state contains an encryption event
 /// - Send events listed in initial state
 /// - Send events implied by `name` and `topic`
 /// - Send invite events
@@ -67,16 +80,56 @@ pub async fn create_room_route(
     );
     let state_lock = mutex_state.lock().await;
 
-    if !services().globals.allow_room_creation()
-        && !body.from_appservice
-        && !services().users.is_admin(sender_user)?
-    {
+    let exempt = body.from_appservice || services().users.is_admin(sender_user)?;
+
+    if !services().globals.allow_room_creation() && !exempt {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
             "Room creation has been disabled.",
         ));
     }
 
+    // Rooms created are a lifetime count, concurrent requests must not all pass the check before
+    // any of them is counted
+    let mutex_roomcreation = Arc::clone(
+        services()
+            .globals
+            .userid_mutex_roomcreation
+            .write()
+            .unwrap()
+            .entry(sender_user.clone())
+            .or_default(),
+    );
+    let roomcreation_lock = mutex_roomcreation.lock().await;
+
+    check_room_limits(sender_user, body.from_appservice, true)?;
+
+    if !exempt {
+        // Only counted once the room exists, so failed requests don't use up the limit
+        let rate_limited = services()
+            .globals
+            .room_creation_limiter
+            .lock()
+            .unwrap()
+            .allowed(sender_user);
+
+        if let Err(retry_after) = rate_limited {
+            return Err(Error::BadRequest(
+                ErrorKind::LimitExceeded {
+                    retry_after_ms: Some(retry_after),
+                },
+                "You have created too many rooms recently.",
+            ));
+        }
+    }
+
+    if body.visibility == room::Visibility::Public && !can_publish_rooms(sender_user)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Only server admins can publish rooms to the room directory.",
+        ));
+    }
+
     let alias: Option<OwnedRoomAliasId> =
         body.room_alias_name
             .as_ref()
@@ -122,11 +175,44 @@ pub async fn create_room_route(
         None => services().globals.default_room_version(),
     };
 
+    // Figure out preset. We need it for preset specific events
+    let preset = body.preset.clone().unwrap_or(match &body.visibility {
+        room::Visibility::Private => RoomPreset::PrivateChat,
+        room::Visibility::Public => RoomPreset::PublicChat,
+        _ => RoomPreset::PrivateChat, // Room visibility should not be custom
+    });
+    let (join_rule, history_visibility, guest_access) = preset_state(&preset).ok_or(
+        Error::BadRequest(ErrorKind::InvalidParam, "Unknown preset."),
+    )?;
+
+    let initial_state = body
+        .initial_state
+        .iter()
+        .map(|event| {
+            let mut pdu_builder = event.deserialize_as::<PduBuilder>().map_err(|e| {
+                warn!("Invalid initial state event: {:?}", e);
+                Error::BadRequest(ErrorKind::InvalidParam, "Invalid initial state event.")
+            })?;
+
+            // Implicit state key defaults to ""
+            pdu_builder.state_key.get_or_insert_with(|| "".to_owned());
+
+            Ok(pdu_builder)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    validate_create_room(
+        sender_user,
+        &initial_state,
+        body.power_level_content_override.is_some(),
+        &body.invite,
+    )?;
+
     let content = match &body.creation_content {
         Some(content) => {
             let mut content = content
                 .deserialize_as::<CanonicalJsonObject>()
-                .expect("Invalid creation content");
+                .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid creation content"))?;
             content.insert(
                 "creator".into(),
                 json!(&sender_user).try_into().map_err(|_| {
@@ -211,14 +297,6 @@ pub async fn create_room_route(
     )?;
 
     // 3. Power levels
-
-    // Figure out preset. We need it for preset specific events
-    let preset = body.preset.clone().unwrap_or(match &body.visibility {
-        room::Visibility::Private => RoomPreset::PrivateChat,
-        room::Visibility::Public => RoomPreset::PublicChat,
-        _ => RoomPreset::PrivateChat, // Room visibility should not be custom
-    });
-
     let mut users = BTreeMap::new();
     users.insert(sender_user.clone(), int!(100));
 
@@ -228,11 +306,7 @@ pub async fn create_room_route(
         }
     }
 
-    let mut power_levels_content = serde_json::to_value(RoomPowerLevelsEventContent {
-        users,
-        ..Default::default()
-    })
-    .expect("event is valid, we just created it");
+    let mut power_levels_content = services().globals.config.new_room_power_levels(users);
 
     if let Some(power_level_content_override) = &body.power_level_content_override {
         let json: JsonObject = serde_json::from_str(power_level_content_override.json().get())
@@ -279,72 +353,76 @@ pub async fn create_room_route(
         )?;
     }
 
-    // 5. Events set by preset
+    // 5. Events set by preset, unless initial_state replaces them anyway
+    let preset_events = [
+        (
+            RoomEventType::RoomJoinRules,
+            to_raw_value(&RoomJoinRulesEventContent::new(join_rule)),
+        ),
+        (
+            RoomEventType::RoomHistoryVisibility,
+            to_raw_value(&RoomHistoryVisibilityEventContent::new(history_visibility)),
+        ),
+        (
+            RoomEventType::RoomGuestAccess,
+            to_raw_value(&RoomGuestAccessEventContent::new(guest_access)),
+        ),
+    ];
 
-    // 5.1 Join Rules
-    services().rooms.timeline.build_and_append_pdu(
-        PduBuilder {
-            event_type: RoomEventType::RoomJoinRules,
-            content: to_raw_value(&RoomJoinRulesEventContent::new(match preset {
-                RoomPreset::PublicChat => JoinRule::Public,
-                // according to spec "invite" is the default
-                _ => JoinRule::Invite,
-            }))
-            .expect("event is valid, we just created it"),
-            unsigned: None,
-            state_key: Some("".to_owned()),
-            redacts: None,
-        },
-        sender_user,
-        &room_id,
-        &state_lock,
-    )?;
+    for (event_type, content) in preset_events {
+        if initial_state
+            .iter()
+            .any(|pdu| pdu.event_type == event_type && pdu.state_key.as_deref() == Some(""))
+        {
+            continue;
+        }
 
-    // 5.2 History Visibility
-    services().rooms.timeline.build_and_append_pdu(
-        PduBuilder {
-            event_type: RoomEventType::RoomHistoryVisibility,
-            content: to_raw_value(&RoomHistoryVisibilityEventContent::new(
-                HistoryVisibility::Shared,
-            ))
-            .expect("event is valid, we just created it"),
-            unsigned: None,
-            state_key: Some("".to_owned()),
-            redacts: None,
-        },
-        sender_user,
-        &room_id,
-        &state_lock,
-    )?;
+        services().rooms.timeline.build_and_append_pdu(
+            PduBuilder {
+                event_type,
+                content: content.expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some("".to_owned()),
+                redacts: None,
+            },
+            sender_user,
+            &room_id,
+            &state_lock,
+        )?;
+    }
 
-    // 5.3 Guest Access
-    services().rooms.timeline.build_and_append_pdu(
-        PduBuilder {
-            event_type: RoomEventType::RoomGuestAccess,
-            content: to_raw_value(&RoomGuestAccessEventContent::new(match preset {
-                RoomPreset::PublicChat => GuestAccess::Forbidden,
-                _ => GuestAccess::CanJoin,
-            }))
-            .expect("event is valid, we just created it"),
-            unsigned: None,
-            state_key: Some("".to_owned()),
-            redacts: None,
-        },
-        sender_user,
-        &room_id,
-        &state_lock,
-    )?;
+    // 5.1 Encryption, if the server enables it by default for this kind of room
+    if services().globals.allow_encryption()
+        && encrypt_by_default(
+            services()
+                .globals
+                .encryption_enabled_by_default_for_room_type(),
+            &preset,
+            body.is_direct,
+        )
+        && !initial_state
+            .iter()
+            .any(|pdu| pdu.event_type == RoomEventType::RoomEncryption)
+    {
+        services().rooms.timeline.build_and_append_pdu(
+            PduBuilder {
+                event_type: RoomEventType::RoomEncryption,
+                content: to_raw_value(&RoomEncryptionEventContent::new(
+                    EventEncryptionAlgorithm::MegolmV1AesSha2,
+                ))
+                .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some("".to_owned()),
+                redacts: None,
+            },
+            sender_user,
+            &room_id,
+            &state_lock,
+        )?;
+    }
 
     // 6. Events listed in initial_state
-    for event in &body.initial_state {
-        let mut pdu_builder = event.deserialize_as::<PduBuilder>().map_err(|e| {
-            warn!("Invalid initial state event: {:?}", e);
-            Error::BadRequest(ErrorKind::InvalidParam, "Invalid initial state event.")
-        })?;
-
-        // Implicit state key defaults to ""
-        pdu_builder.state_key.get_or_insert_with(|| "".to_owned());
-
+    for pdu_builder in initial_state {
         // Silently skip encryption events if they are not allowed
         if pdu_builder.event_type == RoomEventType::RoomEncryption
             && !services().globals.allow_encryption()
@@ -395,10 +473,15 @@ pub async fn create_room_route(
         )?;
     }
 
-    // 8. Events implied by invite (and TODO: invite_3pid)
+    // 8. Events implied by invite and invite_3pid
     drop(state_lock);
     for user_id in &body.invite {
-        let _ = invite_helper(sender_user, user_id, &room_id, None, body.is_direct).await;
+        let _ = invite_helper(sender_user, user_id, &room_id, None, body.is_direct, None).await;
+    }
+    for invite in &body.invite_3pid {
+        if let Err(e) = invite_3pid_helper(sender_user, &room_id, invite).await {
+            warn!("Failed to invite {} to new room: {}", invite.address, e);
+        }
     }
 
     // Homeserver specific stuff
@@ -406,51 +489,188 @@ pub async fn create_room_route(
         services().rooms.alias.set_alias(&alias, &room_id)?;
     }
 
-    if body.visibility == room::Visibility::Public {
+    if publish_new_room(
+        &body.visibility,
+        &preset,
+        services().globals.room_list_publication_default(),
+    ) && can_publish_rooms(sender_user)?
+    {
         services().rooms.directory.set_public(&room_id)?;
     }
 
+    services().users.increment_rooms_created(sender_user)?;
+    if !exempt {
+        services()
+            .globals
+            .room_creation_limiter
+            .lock()
+            .unwrap()
+            .record(sender_user);
+    }
+
+    drop(roomcreation_lock);
+    drop(mutex_roomcreation);
+    services()
+        .globals
+        .userid_mutex_roomcreation
+        .write()
+        .unwrap()
+        .retain(|_, mutex| Arc::strong_count(mutex) > 1);
+
     info!("{} created a room", sender_user);
 
     Ok(create_room::v3::Response::new(room_id))
 }
 
+/// Whether a new room is published to the room directory. Besides the rooms clients ask to
+/// publish, `room_list_publication_default` publishes all rooms created with the public_chat
+/// preset.
+fn publish_new_room(
+    visibility: &room::Visibility,
+    preset: &create_room::v3::RoomPreset,
+    publication_default: bool,
+) -> bool {
+    *visibility == room::Visibility::Public
+        || (publication_default && *preset == create_room::v3::RoomPreset::PublicChat)
+}
+
+/// Whether a new room with this preset is encrypted without the creator asking for it.
+fn encrypt_by_default(
+    mode: EncryptionDefault,
+    preset: &create_room::v3::RoomPreset,
+    is_direct: bool,
+) -> bool {
+    use create_room::v3::RoomPreset;
+
+    match mode {
+        EncryptionDefault::Off => false,
+        EncryptionDefault::Invite => matches!(
+            preset,
+            RoomPreset::PrivateChat | RoomPreset::TrustedPrivateChat
+        ),
+        EncryptionDefault::Direct => is_direct,
+        EncryptionDefault::All => true,
+    }
+}
+
+/// The join rule, history visibility and guest access of a new room with this preset, or `None`
+/// for presets the spec doesn't define.
+fn preset_state(
+    preset: &create_room::v3::RoomPreset,
+) -> Option<(JoinRule, HistoryVisibility, GuestAccess)> {
+    use create_room::v3::RoomPreset;
+
+    match preset {
+        RoomPreset::PrivateChat | RoomPreset::TrustedPrivateChat => Some((
+            JoinRule::Invite,
+            HistoryVisibility::Shared,
+            GuestAccess::CanJoin,
+        )),
+        RoomPreset::PublicChat => Some((
+            JoinRule::Public,
+            HistoryVisibility::Shared,
+            GuestAccess::Forbidden,
+        )),
+        _ => None,
+    }
+}
+
+/// Rejects createRoom requests that contradict themselves or the creation sequence, before
+/// anything is created.
+fn validate_create_room(
+    sender_user: &UserId,
+    initial_state: &[PduBuilder],
+    has_power_level_override: bool,
+    invite: &[OwnedUserId],
+) -> Result<()> {
+    let mut seen = HashSet::new();
+    for pdu in initial_state {
+        match pdu.event_type {
+            RoomEventType::RoomCreate => {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Use creation_content instead of an m.room.create event in initial_state.",
+                ))
+            }
+            RoomEventType::RoomMember => {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Use invite instead of m.room.member events in initial_state.",
+                ))
+            }
+            RoomEventType::RoomPowerLevels if has_power_level_override => {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "initial_state contains power levels and power_level_content_override is set.",
+                ))
+            }
+            _ => {}
+        }
+
+        if !seen.insert((pdu.event_type.to_string(), pdu.state_key.clone())) {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "initial_state contains the same state event more than once.",
+            ));
+        }
+    }
+
+    if invite.iter().any(|user_id| user_id == sender_user) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "The room creator can't be invited.",
+        ));
+    }
+
+    Ok(())
+}
+
 /// # `GET /_matrix/client/r0/rooms/{roomId}/event/{eventId}`
 ///
 /// Gets a single event.
 ///
-/// - You have to currently be joined to the room (TODO: Respect history visibility)
+/// - You have to be allowed to see the event according to the history visibility
+/// - Events the user can't see are reported as not found, so their existence isn't leaked
 pub async fn get_room_event_route(
     body: Ruma<get_room_event::v3::Request>,
 ) -> Result<get_room_event::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    if !services()
-        .rooms
-        .state_cache
-        .is_joined(sender_user, &body.room_id)?
-    {
-        return Err(Error::BadRequest(
-            ErrorKind::Forbidden,
-            "You don't have permission to view this room.",
-        ));
-    }
+    let event = visible_event(
+        services().rooms.timeline.get_pdu(&body.event_id)?,
+        &body.room_id,
+        |event| {
+            services().rooms.state_accessor.user_can_see_event(
+                sender_user,
+                &body.room_id,
+                &event.event_id,
+            )
+        },
+    )?;
 
     Ok(get_room_event::v3::Response {
-        event: services()
-            .rooms
-            .timeline
-            .get_pdu(&body.event_id)?
-            .ok_or(Error::BadRequest(ErrorKind::NotFound, "Event not found."))?
-            .to_room_event(),
+        event: event.to_room_event(),
     })
 }
 
+/// Returns the event if it is in the room and `can_see` allows it. Events of other rooms and
+/// events the user can't see are reported as not found, just like events that don't exist.
+fn visible_event(
+    event: Option<Arc<PduEvent>>,
+    room_id: &RoomId,
+    can_see: impl FnOnce(&PduEvent) -> Result<bool>,
+) -> Result<Arc<PduEvent>> {
+    match event {
+        Some(event) if event.room_id == room_id && can_see(&event)? => Ok(event),
+        _ => Err(Error::BadRequest(ErrorKind::NotFound, "Event not found.")),
+    }
+}
+
 /// # `GET /_matrix/client/r0/rooms/{roomId}/aliases`
 ///
 /// Lists all aliases of the room.
 ///
-/// - Only users joined to the room are allowed to call this TODO: Allow any user to call it if history_visibility is world readable
+/// - Only users joined to the room are allowed to call this, unless the room is world readable
 pub async fn get_room_aliases_route(
     body: Ruma<aliases::v3::Request>,
 ) -> Result<aliases::v3::Response> {
@@ -458,8 +678,8 @@ pub async fn get_room_aliases_route(
 
     if !services()
         .rooms
-        .state_cache
-        .is_joined(sender_user, &body.room_id)?
+        .state_accessor
+        .user_can_see_state(Some(sender_user), &body.room_id)?
     {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
@@ -732,3 +952,271 @@ pub async fn upgrade_room_route(
     // Return the replacement room id
     Ok(upgrade_room::v3::Response { replacement_room })
 }
+
+#[cfg(test)]
+mod test {
+    use ruma::{room_id, user_id};
+
+    use super::*;
+    use crate::utils::testing;
+
+    #[test]
+    fn invisible_events_are_not_found() {
+        let event: Arc<PduEvent> = Arc::new(
+            serde_json::from_value(json!({
+                "event_id": "$event:example.org",
+                "room_id": "!room:example.org",
+                "sender": "@alice:example.org",
+                "origin_server_ts": 1234,
+                "type": "m.room.message",
+                "content": { "msgtype": "m.text", "body": "Hello" },
+                "prev_events": [],
+                "depth": 1,
+                "auth_events": [],
+                "hashes": { "sha256": "" },
+            }))
+            .unwrap(),
+        );
+        let room = room_id!("!room:example.org");
+        let not_found = |result: Result<Arc<PduEvent>>| {
+            matches!(result, Err(Error::BadRequest(ErrorKind::NotFound, _)))
+        };
+
+        assert!(visible_event(Some(event.clone()), room, |_| Ok(true)).is_ok());
+        // A non-member asking for an event of a room with joined history visibility gets the
+        // same answer as for an event that doesn't exist
+        assert!(not_found(visible_event(Some(event.clone()), room, |_| Ok(
+            false
+        ))));
+        assert!(not_found(visible_event(None, room, |_| Ok(true))));
+        assert!(not_found(visible_event(
+            Some(event),
+            room_id!("!other:example.org"),
+            |_| Ok(true)
+        )));
+    }
+
+    #[test]
+    fn public_chats_are_published_by_default_if_configured() {
+        use create_room::v3::RoomPreset;
+
+        let private = room::Visibility::Private;
+        let public = room::Visibility::Public;
+
+        assert!(publish_new_room(&public, &RoomPreset::PrivateChat, false));
+        assert!(!publish_new_room(&private, &RoomPreset::PublicChat, false));
+        assert!(publish_new_room(&private, &RoomPreset::PublicChat, true));
+        assert!(!publish_new_room(&private, &RoomPreset::PrivateChat, true));
+    }
+
+    #[test]
+    fn encryption_by_default_follows_the_configured_room_type() {
+        use create_room::v3::RoomPreset;
+
+        let private = RoomPreset::PrivateChat;
+        let trusted = RoomPreset::TrustedPrivateChat;
+        let public = RoomPreset::PublicChat;
+
+        assert!(!encrypt_by_default(EncryptionDefault::Off, &private, true));
+
+        assert!(encrypt_by_default(
+            EncryptionDefault::Invite,
+            &private,
+            false
+        ));
+        assert!(encrypt_by_default(
+            EncryptionDefault::Invite,
+            &trusted,
+            false
+        ));
+        assert!(!encrypt_by_default(
+            EncryptionDefault::Invite,
+            &public,
+            true
+        ));
+
+        assert!(encrypt_by_default(EncryptionDefault::Direct, &public, true));
+        assert!(!encrypt_by_default(
+            EncryptionDefault::Direct,
+            &private,
+            false
+        ));
+
+        assert!(encrypt_by_default(EncryptionDefault::All, &public, false));
+    }
+
+    #[test]
+    fn presets_set_join_rules_history_visibility_and_guest_access() {
+        use create_room::v3::RoomPreset;
+
+        assert_eq!(
+            preset_state(&RoomPreset::PrivateChat),
+            Some((
+                JoinRule::Invite,
+                HistoryVisibility::Shared,
+                GuestAccess::CanJoin
+            ))
+        );
+        assert_eq!(
+            preset_state(&RoomPreset::TrustedPrivateChat),
+            Some((
+                JoinRule::Invite,
+                HistoryVisibility::Shared,
+                GuestAccess::CanJoin
+            ))
+        );
+        assert_eq!(
+            preset_state(&RoomPreset::PublicChat),
+            Some((
+                JoinRule::Public,
+                HistoryVisibility::Shared,
+                GuestAccess::Forbidden
+            ))
+        );
+        assert_eq!(preset_state(&RoomPreset::from("org.example.custom")), None);
+    }
+
+    #[test]
+    fn contradictory_create_room_requests_are_rejected() {
+        let alice = user_id!("@alice:example.org");
+        let state_event = |event_type: &str, state_key: &str| -> PduBuilder {
+            serde_json::from_value(json!({
+                "type": event_type,
+                "state_key": state_key,
+                "content": {},
+            }))
+            .unwrap()
+        };
+        let is_invalid_param = |result: Result<()>| {
+            matches!(result, Err(Error::BadRequest(ErrorKind::InvalidParam, _)))
+        };
+
+        let topic = [state_event("m.room.topic", "")];
+        assert!(validate_create_room(alice, &topic, true, &[]).is_ok());
+
+        for event_type in ["m.room.create", "m.room.member"] {
+            let initial_state = [state_event(event_type, alice.as_str())];
+            assert!(is_invalid_param(validate_create_room(
+                alice,
+                &initial_state,
+                false,
+                &[]
+            )));
+        }
+
+        let power_levels = [state_event("m.room.power_levels", "")];
+        assert!(validate_create_room(alice, &power_levels, false, &[]).is_ok());
+        assert!(is_invalid_param(validate_create_room(
+            alice,
+            &power_levels,
+            true,
+            &[]
+        )));
+
+        let duplicates = [
+            state_event("m.room.join_rules", ""),
+            state_event("m.room.join_rules", ""),
+        ];
+        assert!(is_invalid_param(validate_create_room(
+            alice,
+            &duplicates,
+            false,
+            &[]
+        )));
+
+        assert!(is_invalid_param(validate_create_room(
+            alice,
+            &[],
+            false,
+            &[alice.to_owned()]
+        )));
+    }
+
+    #[tokio::test]
+    async fn only_members_list_the_aliases_of_private_rooms() {
+        let alice = testing::user("aliases_alice").await;
+        let bob = testing::user("aliases_bob").await;
+        let room_id = testing::room_with(
+            &alice,
+            create_room::v3::Request {
+                room_alias_name: Some("aliases_private".to_owned()),
+                ..create_room::v3::Request::new()
+            },
+        )
+        .await;
+        let list = |user_id: &UserId| {
+            get_room_aliases_route(testing::request(
+                aliases::v3::Request::new(room_id.clone()),
+                user_id,
+            ))
+        };
+
+        let aliases = list(&alice).await.unwrap().aliases;
+        assert_eq!(aliases.len(), 1);
+        assert_eq!(aliases[0].alias(), "aliases_private");
+
+        assert!(matches!(
+            list(&bob).await,
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rooms_created_count_for_the_lifetime_of_the_account() {
+        let alice = testing::user("create_limit_alice").await;
+        let create = |user_id: &UserId| {
+            create_room_route(testing::request(create_room::v3::Request::new(), user_id))
+        };
+
+        let mut rooms = Vec::new();
+        for _ in 0..5 {
+            rooms.push(create(&alice).await.unwrap().room_id);
+        }
+        assert!(matches!(
+            create(&alice).await,
+            Err(Error::BadRequest(ErrorKind::LimitExceeded { .. }, _))
+        ));
+
+        // Leaving a room doesn't give back the room created
+        crate::api::client_server::leave_room(&alice, &rooms[0], None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            create(&alice).await,
+            Err(Error::BadRequest(ErrorKind::LimitExceeded { .. }, _))
+        ));
+
+        let admin = testing::admin("create_limit_admin").await;
+        for _ in 0..6 {
+            create(&admin).await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_creations_dont_exceed_the_limit() {
+        let bob = testing::user("create_limit_bob").await;
+        for _ in 0..4 {
+            testing::room(&bob).await;
+        }
+
+        let requests: Vec<_> = (0..4)
+            .map(|_| {
+                let bob = bob.clone();
+                tokio::spawn(async move {
+                    create_room_route(testing::request(create_room::v3::Request::new(), &bob))
+                        .await
+                        .is_ok()
+                })
+            })
+            .collect();
+        let mut created = 0;
+        for request in requests {
+            if request.await.unwrap() {
+                created += 1;
+            }
+        }
+
+        assert_eq!(created, 1);
+        assert_eq!(services().users.rooms_created(&bob).unwrap(), 5);
+    }
+}
@@ -1,14 +1,19 @@
-use super::{DEVICE_ID_LENGTH, TOKEN_LENGTH};
-use crate::{services, utils, Error, Result, Ruma};
+use super::{DEVICE_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH};
+use crate::{
+    api::ruma_wrapper::SenderDevice, service::users::LOGIN_TOKEN_LIFETIME, services, utils, Error,
+    Result, Ruma,
+};
+use axum::{body::Bytes, Json};
 use ruma::{
     api::client::{
         error::ErrorKind,
         session::{get_login_types, login, logout, logout_all},
-        uiaa::UserIdentifier,
+        uiaa::{AuthData, AuthFlow, AuthType, UiaaInfo, UserIdentifier},
     },
-    UserId,
+    CanonicalJsonValue, UserId,
 };
 use serde::Deserialize;
+use serde_json::{json, Value};
 use tracing::info;
 
 #[derive(Debug, Deserialize)]
@@ -26,6 +31,7 @@ pub async fn get_login_types_route(
 ) -> Result<get_login_types::v3::Response> {
     Ok(get_login_types::v3::Response::new(vec![
         get_login_types::v3::LoginType::Password(Default::default()),
+        get_login_types::v3::LoginType::Token(Default::default()),
     ]))
 }
 
@@ -33,7 +39,8 @@ pub async fn get_login_types_route(
 ///
 /// Authenticates the user and returns an access token it can use in subsequent requests.
 ///
-/// - The user needs to authenticate using their password (or if enabled using a json web token)
+/// - The user needs to authenticate using their password, a login token issued to one of their
+///   other devices or, if enabled, a json web token
 /// - If `device_id` is known: invalidates old access token of that device
 /// - If `device_id` is unknown: creates a new device
 /// - Returns access token that is associated with the user and device
@@ -82,10 +89,29 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
                 ));
             }
 
+            // Upgrade the stored hash if the configured parameters were raised since it was
+            // calculated
+            if utils::password_hash_needs_rehash(
+                &hash,
+                services().globals.argon2_memory(),
+                services().globals.argon2_iterations(),
+            ) {
+                info!("Rehashing password of {}", user_id);
+                services().users.set_password(&user_id, Some(password))?;
+            }
+
             user_id
         }
         login::v3::LoginInfo::Token(login::v3::Token { token }) => {
-            if let Some(jwt_decoding_key) = services().globals.jwt_decoding_key() {
+            if let Some(user_id) = services().users.redeem_login_token(token) {
+                if services().users.is_deactivated(&user_id)? {
+                    return Err(Error::BadRequest(
+                        ErrorKind::UserDeactivated,
+                        "The user has been deactivated",
+                    ));
+                }
+                user_id
+            } else if let Some(jwt_decoding_key) = services().globals.jwt_decoding_key() {
                 let token = jsonwebtoken::decode::<Claims>(
                     token,
                     jwt_decoding_key,
@@ -98,8 +124,8 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
                 )?
             } else {
                 return Err(Error::BadRequest(
-                    ErrorKind::Unknown,
-                    "Token login is not supported (server has no jwt decoding key).",
+                    ErrorKind::Forbidden,
+                    "Login token is invalid or expired.",
                 ));
             }
         }
@@ -152,6 +178,74 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
     })
 }
 
+/// # `POST /_matrix/client/v1/login/get_token`
+///
+/// Issues a token the sender user can log in with once on another device (MSC3882), e.g. after
+/// scanning a QR code.
+///
+/// - Requires UIAA to verify the user's password. Users without a password, e.g. users logging in
+/// with JWT, have nothing to verify and only need their access token
+/// - The token expires after two minutes
+pub async fn get_login_token_route(
+    SenderDevice(sender_user, sender_device): SenderDevice,
+    body: Bytes,
+) -> Result<Json<Value>> {
+    // Clients may leave out the body if they don't authenticate yet
+    let body: Value = if body.is_empty() {
+        json!({})
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|_| Error::BadRequest(ErrorKind::NotJson, "Body is not valid JSON."))?
+    };
+
+    let has_password = services()
+        .users
+        .password_hash(&sender_user)?
+        .map_or(false, |hash| !hash.is_empty());
+
+    if has_password {
+        let mut uiaainfo = UiaaInfo {
+            flows: vec![AuthFlow {
+                stages: vec![AuthType::Password],
+            }],
+            completed: Vec::new(),
+            params: Default::default(),
+            session: None,
+            auth_error: None,
+        };
+
+        if let Some(auth) = body.get("auth") {
+            let auth: AuthData = serde_json::from_value(auth.clone())
+                .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid auth."))?;
+            let (worked, uiaainfo) = services()
+                .uiaa
+                .try_auth(&sender_user, &sender_device, &auth, &uiaainfo)
+                .await?;
+            if !worked {
+                return Err(Error::Uiaa(uiaainfo));
+            }
+        // Success!
+        } else {
+            let json = CanonicalJsonValue::try_from(body)
+                .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid json."))?;
+            uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
+            services()
+                .uiaa
+                .create(&sender_user, &sender_device, &uiaainfo, &json)?;
+            return Err(Error::Uiaa(uiaainfo));
+        }
+    }
+
+    let login_token = services().users.create_login_token(&sender_user);
+
+    info!("{} requested a login token for another device", sender_user);
+
+    Ok(Json(json!({
+        "login_token": login_token,
+        "expires_in_ms": LOGIN_TOKEN_LIFETIME.as_millis() as u64,
+    })))
+}
+
 /// # `POST /_matrix/client/r0/logout`
 ///
 /// Log out the current device.
@@ -173,10 +267,11 @@ pub async fn logout_route(body: Ruma<logout::v3::Request>) -> Result<logout::v3:
 ///
 /// Log out all devices of this user.
 ///
-/// - Invalidates all access tokens
+/// - Invalidates the access tokens of all devices, including devices without metadata
 /// - Deletes all device metadata (device id, device display name, last seen ip, last seen ts)
 /// - Forgets all to-device events
 /// - Triggers device list updates
+/// - Keeps cross-signing keys
 ///
 /// Note: This is equivalent to calling [`GET /_matrix/client/r0/logout`](fn.logout_route.html)
 /// from each device of this user.
@@ -185,9 +280,53 @@ pub async fn logout_all_route(
 ) -> Result<logout_all::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    for device_id in services().users.all_device_ids(sender_user).flatten() {
-        services().users.remove_device(sender_user, &device_id)?;
-    }
+    services().users.remove_all_devices(sender_user)?;
 
     Ok(logout_all::v3::Response::new())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::testing;
+
+    #[tokio::test]
+    async fn logging_out_everywhere_revokes_every_token() {
+        let alice = testing::user("logout_all_alice").await;
+        services()
+            .users
+            .create_device(&alice, "PHONE".into(), "logout_all_phone", None)
+            .unwrap();
+        let whoami = |token: &str| {
+            testing::extract::<ruma::api::client::account::whoami::v3::Request>(
+                "/_matrix/client/v3/account/whoami",
+                testing::client_request(
+                    http::Method::GET,
+                    "/_matrix/client/v3/account/whoami",
+                    token,
+                    serde_json::json!({}),
+                ),
+                None,
+            )
+        };
+        let tokens = [
+            testing::access_token("logout_all_alice"),
+            "logout_all_phone".to_owned(),
+        ];
+        for token in &tokens {
+            assert!(whoami(token).await.is_ok());
+        }
+
+        logout_all_route(testing::request(logout_all::v3::Request::new(), &alice))
+            .await
+            .unwrap();
+
+        for token in &tokens {
+            assert_eq!(
+                whoami(token).await.unwrap_err()["errcode"],
+                "M_UNKNOWN_TOKEN"
+            );
+        }
+        assert_eq!(services().users.all_device_ids(&alice).count(), 0);
+    }
+}
@@ -1,6 +1,7 @@
-use crate::{services, Error, Result, Ruma, RumaResponse};
+use crate::{services, Error, PduEvent, Result, Ruma, RumaResponse};
 use ruma::{
     api::client::{
+        error::ErrorKind,
         filter::{FilterDefinition, LazyLoadOptions},
         sync::sync_events::{self, DeviceLists, UnreadNotificationsCount},
         uiaa::UiaaResponse,
@@ -10,15 +11,24 @@ use ruma::{
         RoomEventType, StateEventType,
     },
     serde::Raw,
-    OwnedDeviceId, OwnedUserId, RoomId, UserId,
+    EventId, OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, UserId,
 };
 use std::{
     collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+    future::Future,
     sync::Arc,
     time::Duration,
 };
-use tokio::sync::watch::Sender;
-use tracing::error;
+use tokio::{
+    runtime::Handle,
+    sync::watch::Sender,
+    task::{JoinError, JoinSet},
+};
+use tracing::{error, warn};
+
+/// How long to wait before the first retry of a sync that hit a transient database error, later
+/// retries wait longer.
+const SYNC_RETRY_BACKOFF: Duration = Duration::from_millis(100);
 
 /// # `GET /_matrix/client/r0/sync`
 ///
@@ -50,10 +60,12 @@ use tracing::error;
 /// - If the user was invited after `since`: A subset of the state of the room at the point of the invite
 ///
 /// For left rooms:
-/// - If the user left after `since`: prev_batch token, empty state (TODO: subset of the state at the point of the leave)
+/// - If the user left after `since`: The most recent events up to and including the leave event
+/// that the user is allowed to see, and the state that changed up to the start of that timeline
 ///
 /// - Sync is handled in an async task, multiple requests from the same device with the same
 /// `since` will be cached
+/// - Users can only have `max_concurrent_syncs` requests open at the same time
 pub async fn sync_events_route(
     body: Ruma<sync_events::v3::Request>,
 ) -> Result<sync_events::v3::Response, RumaResponse<UiaaResponse>> {
@@ -61,6 +73,18 @@ pub async fn sync_events_route(
     let sender_device = body.sender_device.expect("user is authenticated");
     let body = body.body;
 
+    // Held while this request waits for the sync result
+    let _permit = services()
+        .globals
+        .sync_limiter
+        .try_start(&sender_user)
+        .ok_or(Error::BadRequest(
+            ErrorKind::LimitExceeded {
+                retry_after_ms: None,
+            },
+            "Too many syncs open at the same time.",
+        ))?;
+
     let mut rx = match services()
         .globals
         .sync_receivers
@@ -129,7 +153,14 @@ async fn sync_helper_wrapper(
 ) {
     let since = body.since.clone();
 
-    let r = sync_helper(sender_user.clone(), sender_device.clone(), body).await;
+    // A failed attempt's response is thrown away, so next_batch never skips data that couldn't be
+    // read
+    let r = retry_transient(
+        services().globals.config.sync_db_retries,
+        SYNC_RETRY_BACKOFF,
+        || sync_helper(sender_user.clone(), sender_device.clone(), body.clone()),
+    )
+    .await;
 
     if let Ok((_, caching_allowed)) = r {
         if !caching_allowed {
@@ -154,6 +185,28 @@ async fn sync_helper_wrapper(
     let _ = tx.send(Some(r.map(|(r, _)| r)));
 }
 
+/// Runs `read` again if it fails with a transient database error, up to `retries` times.
+async fn retry_transient<T, F, Fut>(retries: u32, backoff: Duration, mut read: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match read().await {
+            Err(e) if e.is_transient() && attempt < retries => {
+                attempt += 1;
+                warn!(
+                    "Transient database error during sync, retrying ({}/{}): {}",
+                    attempt, retries, e
+                );
+                tokio::time::sleep(backoff * attempt).await;
+            }
+            result => return result,
+        }
+    }
+}
+
 async fn sync_helper(
     sender_user: OwnedUserId,
     sender_device: OwnedDeviceId,
@@ -161,12 +214,14 @@ async fn sync_helper(
     // bool = caching allowed
 ) -> Result<(sync_events::v3::Response, bool), Error> {
     use sync_events::v3::{
-        Ephemeral, Filter, GlobalAccountData, InviteState, InvitedRoom, JoinedRoom, LeftRoom,
-        Presence, RoomAccountData, RoomSummary, Rooms, State, Timeline, ToDevice,
+        Filter, GlobalAccountData, InviteState, InvitedRoom, LeftRoom, Presence, RoomAccountData,
+        Rooms, State, Timeline, ToDevice,
     };
 
     // TODO: match body.set_presence {
-    services().rooms.edus.presence.ping_presence(&sender_user)?;
+    if services().globals.allow_presence() {
+        services().rooms.edus.presence.ping_presence(&sender_user)?;
+    }
 
     // Setup watchers, so if there's no response, we can wait for them
     let watcher = services().globals.watch(&sender_user, &sender_device);
@@ -203,6 +258,14 @@ async fn sync_helper(
     let mut device_list_updates = HashSet::new();
     let mut device_list_left = HashSet::new();
 
+    // Users whose devices this connection tracks already don't need to be reported again when
+    // they newly share an encrypted room
+    let tracked_device_lists = Arc::new(services().rooms.lazy_loading.device_lists_tracked(
+        &sender_user,
+        &sender_device,
+        since,
+    ));
+
     // Look for device list updates of this account
     device_list_updates.extend(
         services()
@@ -216,12 +279,134 @@ async fn sync_helper(
         .state_cache
         .rooms_joined(&sender_user)
         .collect::<Vec<_>>();
-    for room_id in all_joined_rooms {
-        let room_id = room_id?;
+
+    let timeline_limit = timeline_limit(
+        filter.room.timeline.limit.map(u64::from),
+        services().globals.sync_timeline_limit_default(),
+        services().globals.sync_timeline_limit_max(),
+    );
+    let timeline_max_bytes = services()
+        .globals
+        .sync_response_max_bytes()
+        .map(|max_bytes| max_bytes / all_joined_rooms.len().max(1));
+
+    // Rooms are independent, so up to sync_room_concurrency of them are loaded in parallel. The
+    // database reads block, so each room is loaded on a blocking thread. The results are put back
+    // in the order of the rooms, so merging presence below stays deterministic. If the request is
+    // cancelled, dropping the JoinSet aborts the rooms that didn't start yet.
+    let concurrency = services().globals.sync_room_concurrency().max(1);
+    let mut loaded_rooms: Vec<_> = all_joined_rooms.iter().map(|_| None).collect();
+    let mut loading = JoinSet::new();
+
+    for (i, room_id) in all_joined_rooms.into_iter().enumerate() {
+        if loading.len() >= concurrency {
+            if let Some(result) = loading.join_next().await {
+                let (i, loaded) = finished_room(result);
+                loaded_rooms[i] = Some(loaded);
+            }
+        }
+
+        let runtime = Handle::current();
+        let (sender_user, sender_device, full_state, tracked_device_lists) = (
+            sender_user.clone(),
+            sender_device.clone(),
+            body.full_state,
+            Arc::clone(&tracked_device_lists),
+        );
+        loading.spawn(async move {
+            let loaded = tokio::task::spawn_blocking(move || {
+                runtime.block_on(load_joined_room(
+                    sender_user,
+                    sender_device,
+                    room_id,
+                    since,
+                    next_batch,
+                    lazy_load_enabled,
+                    lazy_load_send_redundant,
+                    full_state,
+                    timeline_limit,
+                    timeline_max_bytes,
+                    tracked_device_lists,
+                ))
+            })
+            .await;
+            (i, loaded)
+        });
+    }
+
+    while let Some(result) = loading.join_next().await {
+        let (i, loaded) = finished_room(result);
+        loaded_rooms[i] = Some(loaded);
+    }
+
+    for loaded in loaded_rooms.into_iter().flatten() {
+        let (room_id, joined_room, room_device_list_updates, room_left_encrypted_users) =
+            match loaded? {
+                Some(loaded) => loaded,
+                None => continue,
+            };
+
+        device_list_updates.extend(room_device_list_updates);
+        left_encrypted_users.extend(room_left_encrypted_users);
+
+        if !joined_room.is_empty() {
+            joined_rooms.insert(room_id.clone(), joined_room);
+        }
+
+        // Take presence updates from this room
+        let room_presence = if services().globals.allow_presence() {
+            services()
+                .rooms
+                .edus
+                .presence
+                .presence_since(&room_id, since)?
+        } else {
+            HashMap::new()
+        };
+
+        for (user_id, presence) in room_presence {
+            match presence_updates.entry(user_id) {
+                Entry::Vacant(v) => {
+                    v.insert(presence);
+                }
+                Entry::Occupied(mut o) => {
+                    let p = o.get_mut();
+
+                    // Update existing presence event with more info
+                    p.content.presence = presence.content.presence;
+                    if let Some(status_msg) = presence.content.status_msg {
+                        p.content.status_msg = Some(status_msg);
+                    }
+                    if let Some(last_active_ago) = presence.content.last_active_ago {
+                        p.content.last_active_ago = Some(last_active_ago);
+                    }
+                    if let Some(displayname) = presence.content.displayname {
+                        p.content.displayname = Some(displayname);
+                    }
+                    if let Some(avatar_url) = presence.content.avatar_url {
+                        p.content.avatar_url = Some(avatar_url);
+                    }
+                    if let Some(currently_active) = presence.content.currently_active {
+                        p.content.currently_active = Some(currently_active);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut left_rooms = BTreeMap::new();
+    let all_left_rooms: Vec<_> = services()
+        .rooms
+        .state_cache
+        .rooms_left(&sender_user)
+        .collect();
+    for result in all_left_rooms {
+        let (room_id, _) = result?;
+
+        let mut left_state_events = Vec::new();
 
         {
             // Get and drop the lock to wait for remaining operations to finish
-            // This will make sure the we have all events until next_batch
             let mutex_insert = Arc::clone(
                 services()
                     .globals
@@ -235,225 +420,144 @@ async fn sync_helper(
             drop(insert_lock);
         }
 
-        let timeline_pdus;
-        let limited;
-        if services()
+        let left_count = services()
             .rooms
-            .timeline
-            .last_timeline_count(&sender_user, &room_id)?
-            > since
-        {
-            let mut non_timeline_pdus = services()
-                .rooms
-                .timeline
-                .pdus_until(&sender_user, &room_id, u64::MAX)?
-                .filter_map(|r| {
-                    // Filter out buggy events
-                    if r.is_err() {
-                        error!("Bad pdu in pdus_since: {:?}", r);
-                    }
-                    r.ok()
-                })
-                .take_while(|(pduid, _)| {
-                    services()
-                        .rooms
-                        .timeline
-                        .pdu_count(pduid)
-                        .map_or(false, |count| count > since)
-                });
-
-            // Take the last 10 events for the timeline
-            timeline_pdus = non_timeline_pdus
-                .by_ref()
-                .take(10)
-                .collect::<Vec<_>>()
-                .into_iter()
-                .rev()
-                .collect::<Vec<_>>();
+            .state_cache
+            .get_left_count(&room_id, &sender_user)?;
 
-            // They /sync response doesn't always return all messages, so we say the output is
-            // limited unless there are events in non_timeline_pdus
-            limited = non_timeline_pdus.next().is_some();
-        } else {
-            timeline_pdus = Vec::new();
-            limited = false;
+        if !left_since(since, left_count) {
+            continue;
         }
 
-        let send_notification_counts = !timeline_pdus.is_empty()
-            || services()
-                .rooms
-                .user
-                .last_notification_read(&sender_user, &room_id)?
-                > since;
-
-        let mut timeline_users = HashSet::new();
-        for (_, event) in &timeline_pdus {
-            timeline_users.insert(event.sender.as_str().to_owned());
+        if !services().rooms.metadata.exists(&room_id)? {
+            // This is just a rejected invite, not a room we know
+            continue;
         }
 
-        services().rooms.lazy_loading.lazy_load_confirm_delivery(
-            &sender_user,
-            &sender_device,
-            &room_id,
-            since,
-        )?;
-
-        // Database queries:
-
-        let current_shortstatehash =
-            if let Some(s) = services().rooms.state.get_room_shortstatehash(&room_id)? {
-                s
-            } else {
-                error!("Room {} has no state", room_id);
-                continue;
-            };
-
         let since_shortstatehash = services()
             .rooms
             .user
             .get_token_shortstatehash(&room_id, since)?;
 
-        // Calculates joined_member_count, invited_member_count and heroes
-        let calculate_counts = || {
-            let joined_member_count = services()
-                .rooms
-                .state_cache
-                .room_joined_count(&room_id)?
-                .unwrap_or(0);
-            let invited_member_count = services()
-                .rooms
-                .state_cache
-                .room_invited_count(&room_id)?
-                .unwrap_or(0);
-
-            // Recalculate heroes (first 5 members)
-            let mut heroes = Vec::new();
+        let since_state_ids = match since_shortstatehash {
+            Some(s) => services().rooms.state_accessor.state_full_ids(s).await?,
+            None => HashMap::new(),
+        };
 
-            if joined_member_count + invited_member_count <= 5 {
-                // Go through all PDUs and for each member event, check if the user is still joined or
-                // invited until we have 5 or we reach the end
+        let left_event_id = match services().rooms.state_accessor.room_state_get_id(
+            &room_id,
+            &StateEventType::RoomMember,
+            sender_user.as_str(),
+        )? {
+            Some(e) => e,
+            None => {
+                error!("Left room but no left state event");
+                continue;
+            }
+        };
 
-                for hero in services()
-                    .rooms
-                    .timeline
-                    .all_pdus(&sender_user, &room_id)?
-                    .filter_map(|pdu| pdu.ok()) // Ignore all broken pdus
-                    .filter(|(_, pdu)| pdu.kind == RoomEventType::RoomMember)
-                    .map(|(_, pdu)| {
-                        let content: RoomMemberEventContent =
-                            serde_json::from_str(pdu.content.get()).map_err(|_| {
-                                Error::bad_database("Invalid member event in database.")
-                            })?;
-
-                        if let Some(state_key) = &pdu.state_key {
-                            let user_id = UserId::parse(state_key.clone()).map_err(|_| {
-                                Error::bad_database("Invalid UserId in member PDU.")
-                            })?;
-
-                            // The membership was and still is invite or join
-                            if matches!(
-                                content.membership,
-                                MembershipState::Join | MembershipState::Invite
-                            ) && (services().rooms.state_cache.is_joined(&user_id, &room_id)?
+        // The timeline ends with the leave event, the user must not see what happened after it
+        let (left_timeline_pdus, left_limited) =
+            match services().rooms.timeline.get_pdu_count(&left_event_id)? {
+                Some(left_count) => {
+                    let pdus = services()
+                        .rooms
+                        .timeline
+                        .pdus_until(&sender_user, &room_id, left_count.saturating_add(1))?
+                        .filter_map(|r| {
+                            // Filter out buggy events
+                            if r.is_err() {
+                                error!("Bad pdu in pdus_until: {:?}", r);
+                            }
+                            r.ok()
+                        })
+                        .filter_map(|(pduid, pdu)| {
+                            let count = services().rooms.timeline.pdu_count(&pduid).ok()?;
+                            Some((count, pdu))
+                        })
+                        // Before the window is cut, so hidden events don't take up its space
+                        .filter(|(count, pdu)| {
+                            *count > left_count
                                 || services()
                                     .rooms
-                                    .state_cache
-                                    .is_invited(&user_id, &room_id)?)
-                            {
-                                Ok::<_, Error>(Some(state_key.clone()))
-                            } else {
-                                Ok(None)
-                            }
-                        } else {
-                            Ok(None)
-                        }
-                    })
-                    // Filter out buggy users
-                    .filter_map(|u| u.ok())
-                    // Filter for possible heroes
-                    .flatten()
-                {
-                    if heroes.contains(&hero) || hero == sender_user.as_str() {
-                        continue;
-                    }
+                                    .state_accessor
+                                    .user_can_see_event(&sender_user, &room_id, &pdu.event_id)
+                                    .unwrap_or(false)
+                        });
 
-                    heroes.push(hero);
+                    timeline_window(pdus, since, left_count, timeline_limit)
                 }
-            }
+                None => (Vec::new(), false),
+            };
 
-            Ok::<_, Error>((
-                Some(joined_member_count),
-                Some(invited_member_count),
-                heroes,
-            ))
-        };
+        let left_state_ids = match left_timeline_pdus.first() {
+            // The state before the timeline, the leave event itself is part of the timeline
+            Some(first_pdu) => {
+                let shortstatehash = match services()
+                    .rooms
+                    .state_accessor
+                    .pdu_shortstatehash(&first_pdu.event_id)?
+                {
+                    Some(s) => s,
+                    None => {
+                        error!("Timeline event has no state");
+                        continue;
+                    }
+                };
 
-        let since_sender_member: Option<RoomMemberEventContent> = since_shortstatehash
-            .and_then(|shortstatehash| {
                 services()
                     .rooms
                     .state_accessor
-                    .state_get(
-                        shortstatehash,
-                        &StateEventType::RoomMember,
-                        sender_user.as_str(),
-                    )
-                    .transpose()
-            })
-            .transpose()?
-            .and_then(|pdu| {
-                serde_json::from_str(pdu.content.get())
-                    .map_err(|_| Error::bad_database("Invalid PDU in database."))
-                    .ok()
-            });
+                    .state_full_ids(shortstatehash)
+                    .await?
+            }
+            None => {
+                let left_shortstatehash = match services()
+                    .rooms
+                    .state_accessor
+                    .pdu_shortstatehash(&left_event_id)?
+                {
+                    Some(s) => s,
+                    None => {
+                        error!("Leave event has no state");
+                        continue;
+                    }
+                };
 
-        let joined_since_last_sync =
-            since_sender_member.map_or(true, |member| member.membership != MembershipState::Join);
+                let mut left_state_ids = services()
+                    .rooms
+                    .state_accessor
+                    .state_full_ids(left_shortstatehash)
+                    .await?;
 
-        let (
-            heroes,
-            joined_member_count,
-            invited_member_count,
-            joined_since_last_sync,
-            state_events,
-        ) = if since_shortstatehash.is_none() || joined_since_last_sync {
-            // Probably since = 0, we will do an initial sync
+                let leave_shortstatekey = services().rooms.short.get_or_create_shortstatekey(
+                    &StateEventType::RoomMember,
+                    sender_user.as_str(),
+                )?;
 
-            let (joined_member_count, invited_member_count, heroes) = calculate_counts()?;
+                left_state_ids.insert(leave_shortstatekey, left_event_id);
+                left_state_ids
+            }
+        };
 
-            let current_state_ids = services()
+        let left_prev_batch = match left_timeline_pdus.first() {
+            Some(first_pdu) => services()
                 .rooms
-                .state_accessor
-                .state_full_ids(current_shortstatehash)
-                .await?;
-
-            let mut state_events = Vec::new();
-            let mut lazy_loaded = HashSet::new();
-
-            let mut i = 0;
-            for (shortstatekey, id) in current_state_ids {
-                let (event_type, state_key) = services()
-                    .rooms
-                    .short
-                    .get_statekey_from_short(shortstatekey)?;
+                .timeline
+                .get_pdu_count(&first_pdu.event_id)?
+                .map(|count| count.to_string()),
+            None => Some(next_batch_string.clone()),
+        };
 
-                if event_type != StateEventType::RoomMember {
-                    let pdu = match services().rooms.timeline.get_pdu(&id)? {
-                        Some(pdu) => pdu,
-                        None => {
-                            error!("Pdu in state not found: {}", id);
-                            continue;
-                        }
-                    };
-                    state_events.push(pdu);
+        let mut i = 0;
+        for (key, id) in left_state_ids {
+            if body.full_state || since_state_ids.get(&key) != Some(&id) {
+                let (event_type, state_key) =
+                    services().rooms.short.get_statekey_from_short(key)?;
 
-                    i += 1;
-                    if i % 100 == 0 {
-                        tokio::task::yield_now().await;
-                    }
-                } else if !lazy_load_enabled
+                if !lazy_load_enabled
+                    || event_type != StateEventType::RoomMember
                     || body.full_state
-                    || timeline_users.contains(&state_key)
                     // TODO: Delete the following line when this is resolved: https://github.com/vector-im/element-web/issues/22565
                     || *sender_user == state_key
                 {
@@ -465,11 +569,7 @@ async fn sync_helper(
                         }
                     };
 
-                    // This check is in case a bad user ID made it into the database
-                    if let Ok(uid) = UserId::parse(&state_key) {
-                        lazy_loaded.insert(uid);
-                    }
-                    state_events.push(pdu);
+                    left_state_events.push(pdu.to_sync_state_event());
 
                     i += 1;
                     if i % 100 == 0 {
@@ -477,34 +577,511 @@ async fn sync_helper(
                     }
                 }
             }
+        }
 
-            // Reset lazy loading because this is an initial sync
-            services().rooms.lazy_loading.lazy_load_reset(
-                &sender_user,
-                &sender_device,
-                &room_id,
-            )?;
+        left_rooms.insert(
+            room_id.clone(),
+            LeftRoom {
+                account_data: RoomAccountData { events: Vec::new() },
+                timeline: Timeline {
+                    limited: left_limited,
+                    prev_batch: left_prev_batch,
+                    events: left_timeline_pdus
+                        .iter()
+                        .map(|pdu| pdu.to_sync_room_event())
+                        .collect(),
+                },
+                state: State {
+                    events: left_state_events,
+                },
+            },
+        );
+    }
 
-            // The state_events above should contain all timeline_users, let's mark them as lazy
-            // loaded.
-            services().rooms.lazy_loading.lazy_load_mark_sent(
-                &sender_user,
-                &sender_device,
-                &room_id,
-                lazy_loaded,
-                next_batch,
-            );
+    let mut invited_rooms = BTreeMap::new();
+    let all_invited_rooms: Vec<_> = services()
+        .rooms
+        .state_cache
+        .rooms_invited(&sender_user)
+        .collect();
+    for result in all_invited_rooms {
+        let (room_id, invite_state_events) = result?;
 
-            (
-                heroes,
-                joined_member_count,
-                invited_member_count,
-                true,
-                state_events,
-            )
-        } else if timeline_pdus.is_empty() && since_shortstatehash == Some(current_shortstatehash) {
-            // No state changes
-            (Vec::new(), None, None, false, Vec::new())
+        {
+            // Get and drop the lock to wait for remaining operations to finish
+            let mutex_insert = Arc::clone(
+                services()
+                    .globals
+                    .roomid_mutex_insert
+                    .write()
+                    .unwrap()
+                    .entry(room_id.clone())
+                    .or_default(),
+            );
+            let insert_lock = mutex_insert.lock().unwrap();
+            drop(insert_lock);
+        }
+
+        let invite_count = services()
+            .rooms
+            .state_cache
+            .get_invite_count(&room_id, &sender_user)?;
+
+        // Invited before last sync
+        if Some(since) >= invite_count {
+            continue;
+        }
+
+        invited_rooms.insert(
+            room_id.clone(),
+            InvitedRoom {
+                invite_state: InviteState {
+                    events: invite_state_events,
+                },
+            },
+        );
+    }
+
+    for user_id in left_encrypted_users {
+        let still_share_encrypted_room = services()
+            .rooms
+            .user
+            .get_shared_rooms(vec![sender_user.clone(), user_id.clone()])?
+            .filter_map(|r| r.ok())
+            .filter_map(|other_room_id| {
+                Some(
+                    services()
+                        .rooms
+                        .state_accessor
+                        .room_state_get(&other_room_id, &StateEventType::RoomEncryption, "")
+                        .ok()?
+                        .is_some(),
+                )
+            })
+            .all(|encrypted| !encrypted);
+        // If the user doesn't share an encrypted room with the target anymore, we need to tell
+        // them
+        if still_share_encrypted_room {
+            device_list_left.insert(user_id);
+        }
+    }
+
+    services().rooms.lazy_loading.device_lists_mark_sent(
+        &sender_user,
+        &sender_device,
+        next_batch,
+        device_list_updates.clone(),
+        device_list_left.clone(),
+    );
+
+    // Remove all to-device events the device received *last time*
+    services()
+        .users
+        .remove_to_device_events(&sender_user, &sender_device, since)?;
+
+    let response = sync_events::v3::Response {
+        next_batch: next_batch_string,
+        rooms: Rooms {
+            leave: left_rooms,
+            join: joined_rooms,
+            invite: invited_rooms,
+            knock: BTreeMap::new(), // TODO
+        },
+        presence: Presence {
+            events: presence_updates
+                .into_values()
+                .map(|v| Raw::new(&v).expect("PresenceEvent always serializes successfully"))
+                .collect(),
+        },
+        account_data: GlobalAccountData {
+            events: services()
+                .account_data
+                .changes_since(None, &sender_user, since)?
+                .into_iter()
+                .filter_map(|(_, v)| {
+                    serde_json::from_str(v.json().get())
+                        .map_err(|_| Error::bad_database("Invalid account event in database."))
+                        .ok()
+                })
+                .collect(),
+        },
+        device_lists: DeviceLists {
+            changed: device_list_updates.into_iter().collect(),
+            left: device_list_left.into_iter().collect(),
+        },
+        device_one_time_keys_count: services()
+            .users
+            .count_one_time_keys(&sender_user, &sender_device)?,
+        to_device: ToDevice {
+            events: services()
+                .users
+                .get_to_device_events(&sender_user, &sender_device)?,
+        },
+        // Fallback keys are not yet supported
+        device_unused_fallback_key_types: None,
+    };
+
+    // TODO: Retry the endpoint instead of returning (waiting for #118)
+    if !body.full_state
+        && response.rooms.is_empty()
+        && response.presence.is_empty()
+        && response.account_data.is_empty()
+        && response.device_lists.is_empty()
+        && response.to_device.is_empty()
+    {
+        // Hang a few seconds so requests are not spammed
+        // Stop hanging if new info arrives
+        let mut duration = body.timeout.unwrap_or_default();
+        if duration.as_secs() > 30 {
+            duration = Duration::from_secs(30);
+        }
+        let _ = tokio::time::timeout(duration, watcher).await;
+        Ok((response, false))
+    } else {
+        Ok((response, since != next_batch)) // Only cache if we made progress
+    }
+}
+
+/// Loads the sync response of one joined room. Also returns the device list updates and the users
+/// who left encrypted rooms that were found in the room. Returns None if the room has no state.
+/// Returns the index and result of a room loaded by `sync_helper`. Panics of the room's tasks
+/// are passed on.
+fn finished_room<T>(result: Result<(usize, Result<T, JoinError>), JoinError>) -> (usize, T) {
+    match result.and_then(|(i, loaded)| loaded.map(|loaded| (i, loaded))) {
+        Ok(finished) => finished,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn load_joined_room(
+    sender_user: OwnedUserId,
+    sender_device: OwnedDeviceId,
+    room_id: Result<OwnedRoomId>,
+    since: u64,
+    next_batch: u64,
+    lazy_load_enabled: bool,
+    lazy_load_send_redundant: bool,
+    full_state: bool,
+    timeline_limit: usize,
+    timeline_max_bytes: Option<usize>,
+    tracked_device_lists: Arc<HashSet<OwnedUserId>>,
+) -> Result<
+    Option<(
+        OwnedRoomId,
+        sync_events::v3::JoinedRoom,
+        HashSet<OwnedUserId>,
+        HashSet<OwnedUserId>,
+    )>,
+> {
+    use sync_events::v3::{Ephemeral, JoinedRoom, RoomAccountData, RoomSummary, State, Timeline};
+
+    let room_id = room_id?;
+    let mut device_list_updates = HashSet::new();
+    let mut left_encrypted_users = HashSet::new();
+
+    {
+        // Get and drop the lock to wait for remaining operations to finish
+        // This will make sure the we have all events until next_batch
+        let mutex_insert = Arc::clone(
+            services()
+                .globals
+                .roomid_mutex_insert
+                .write()
+                .unwrap()
+                .entry(room_id.clone())
+                .or_default(),
+        );
+        let insert_lock = mutex_insert.lock().unwrap();
+        drop(insert_lock);
+    }
+
+    let timeline_pdus;
+    let limited;
+    if services()
+        .rooms
+        .timeline
+        .last_timeline_count(&sender_user, &room_id)?
+        > since
+    {
+        let mut non_timeline_pdus = services()
+            .rooms
+            .timeline
+            .pdus_until(&sender_user, &room_id, u64::MAX)?
+            .filter_map(|r| {
+                // Filter out buggy events
+                if r.is_err() {
+                    error!("Bad pdu in pdus_since: {:?}", r);
+                }
+                r.ok()
+            })
+            .take_while(|(pduid, _)| {
+                services()
+                    .rooms
+                    .timeline
+                    .pdu_count(pduid)
+                    .map_or(false, |count| count > since)
+            })
+            // Events the user can't see don't count against the limit
+            .filter(|(_, pdu)| {
+                services()
+                    .rooms
+                    .state_accessor
+                    .user_can_see_event(&sender_user, &room_id, &pdu.event_id)
+                    .unwrap_or(false)
+            });
+
+        // Take the last events for the timeline
+        let mut pdus = non_timeline_pdus
+            .by_ref()
+            .take(timeline_limit)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .collect::<Vec<_>>();
+
+        let mut truncated = false;
+        if let Some(max_bytes) = timeline_max_bytes {
+            let sizes: Vec<_> = pdus
+                .iter()
+                .rev()
+                .map(|(_, pdu)| pdu.to_sync_room_event().json().get().len())
+                .collect();
+            let dropped = pdus.len() - events_within(&sizes, max_bytes);
+            // The oldest events are dropped, prev_batch then points before the oldest one left
+            pdus.drain(..dropped);
+            truncated = dropped > 0;
+        }
+
+        timeline_pdus = pdus;
+        // They /sync response doesn't always return all messages, so we say the output is
+        // limited unless there are events in non_timeline_pdus
+        limited = truncated || non_timeline_pdus.next().is_some();
+    } else {
+        timeline_pdus = Vec::new();
+        limited = false;
+    }
+
+    let send_notification_counts = !timeline_pdus.is_empty()
+        || services()
+            .rooms
+            .user
+            .last_notification_read(&sender_user, &room_id)?
+            > since;
+
+    let mut timeline_users = HashSet::new();
+    for (_, event) in &timeline_pdus {
+        timeline_users.insert(event.sender.as_str().to_owned());
+    }
+
+    services().rooms.lazy_loading.lazy_load_confirm_delivery(
+        &sender_user,
+        &sender_device,
+        &room_id,
+        since,
+    )?;
+
+    // Database queries:
+
+    let current_shortstatehash =
+        if let Some(s) = services().rooms.state.get_room_shortstatehash(&room_id)? {
+            s
+        } else {
+            error!("Room {} has no state", room_id);
+            return Ok(None);
+        };
+
+    let since_shortstatehash = services()
+        .rooms
+        .user
+        .get_token_shortstatehash(&room_id, since)?;
+
+    // Calculates joined_member_count, invited_member_count and heroes
+    let calculate_counts = || {
+        let joined_member_count = services()
+            .rooms
+            .state_cache
+            .room_joined_count(&room_id)?
+            .unwrap_or(0);
+        let invited_member_count = services()
+            .rooms
+            .state_cache
+            .room_invited_count(&room_id)?
+            .unwrap_or(0);
+
+        // Recalculate heroes (first 5 members)
+        let mut heroes = Vec::new();
+
+        if joined_member_count + invited_member_count <= 5 {
+            // Go through all PDUs and for each member event, check if the user is still joined or
+            // invited until we have 5 or we reach the end
+
+            for hero in services()
+                .rooms
+                .timeline
+                .all_pdus(&sender_user, &room_id)?
+                .filter_map(|pdu| pdu.ok()) // Ignore all broken pdus
+                .filter(|(_, pdu)| pdu.kind == RoomEventType::RoomMember)
+                .map(|(_, pdu)| {
+                    let content: RoomMemberEventContent = serde_json::from_str(pdu.content.get())
+                        .map_err(|_| {
+                        Error::bad_database("Invalid member event in database.")
+                    })?;
+
+                    if let Some(state_key) = &pdu.state_key {
+                        let user_id = UserId::parse(state_key.clone())
+                            .map_err(|_| Error::bad_database("Invalid UserId in member PDU."))?;
+
+                        // The membership was and still is invite or join
+                        if matches!(
+                            content.membership,
+                            MembershipState::Join | MembershipState::Invite
+                        ) && (services().rooms.state_cache.is_joined(&user_id, &room_id)?
+                            || services()
+                                .rooms
+                                .state_cache
+                                .is_invited(&user_id, &room_id)?)
+                        {
+                            Ok::<_, Error>(Some(state_key.clone()))
+                        } else {
+                            Ok(None)
+                        }
+                    } else {
+                        Ok(None)
+                    }
+                })
+                // Filter out buggy users
+                .filter_map(|u| u.ok())
+                // Filter for possible heroes
+                .flatten()
+            {
+                if heroes.contains(&hero) || hero == sender_user.as_str() {
+                    continue;
+                }
+
+                heroes.push(hero);
+            }
+        }
+
+        Ok::<_, Error>((
+            Some(joined_member_count),
+            Some(invited_member_count),
+            heroes,
+        ))
+    };
+
+    let since_sender_member: Option<RoomMemberEventContent> = since_shortstatehash
+        .and_then(|shortstatehash| {
+            services()
+                .rooms
+                .state_accessor
+                .state_get(
+                    shortstatehash,
+                    &StateEventType::RoomMember,
+                    sender_user.as_str(),
+                )
+                .transpose()
+        })
+        .transpose()?
+        .and_then(|pdu| {
+            serde_json::from_str(pdu.content.get())
+                .map_err(|_| Error::bad_database("Invalid PDU in database."))
+                .ok()
+        });
+
+    let joined_since_last_sync =
+        since_sender_member.map_or(true, |member| member.membership != MembershipState::Join);
+
+    let (heroes, joined_member_count, invited_member_count, joined_since_last_sync, state_events) =
+        if since_shortstatehash.is_none() || joined_since_last_sync {
+            // Probably since = 0, we will do an initial sync
+
+            let (joined_member_count, invited_member_count, heroes) = calculate_counts()?;
+
+            let current_state_ids = services()
+                .rooms
+                .state_accessor
+                .state_full_ids(current_shortstatehash)
+                .await?;
+
+            let mut state_events = Vec::new();
+            let mut lazy_loaded = HashSet::new();
+
+            let mut i = 0;
+            for (shortstatekey, id) in current_state_ids {
+                let (event_type, state_key) = services()
+                    .rooms
+                    .short
+                    .get_statekey_from_short(shortstatekey)?;
+
+                if event_type != StateEventType::RoomMember {
+                    let pdu = match services().rooms.timeline.get_pdu(&id)? {
+                        Some(pdu) => pdu,
+                        None => {
+                            error!("Pdu in state not found: {}", id);
+                            continue;
+                        }
+                    };
+                    state_events.push(pdu);
+
+                    i += 1;
+                    if i % 100 == 0 {
+                        tokio::task::yield_now().await;
+                    }
+                } else if !lazy_load_enabled
+                || full_state
+                || timeline_users.contains(&state_key)
+                // TODO: Delete the following line when this is resolved: https://github.com/vector-im/element-web/issues/22565
+                || *sender_user == state_key
+                {
+                    let pdu = match services().rooms.timeline.get_pdu(&id)? {
+                        Some(pdu) => pdu,
+                        None => {
+                            error!("Pdu in state not found: {}", id);
+                            continue;
+                        }
+                    };
+
+                    // This check is in case a bad user ID made it into the database
+                    if let Ok(uid) = UserId::parse(&state_key) {
+                        lazy_loaded.insert(uid);
+                    }
+                    state_events.push(pdu);
+
+                    i += 1;
+                    if i % 100 == 0 {
+                        tokio::task::yield_now().await;
+                    }
+                }
+            }
+
+            // Reset lazy loading because this is an initial sync
+            services().rooms.lazy_loading.lazy_load_reset(
+                &sender_user,
+                &sender_device,
+                &room_id,
+            )?;
+
+            // The state_events above should contain all timeline_users, let's mark them as lazy
+            // loaded.
+            services().rooms.lazy_loading.lazy_load_mark_sent(
+                &sender_user,
+                &sender_device,
+                &room_id,
+                lazy_loaded,
+                next_batch,
+            );
+
+            (
+                heroes,
+                joined_member_count,
+                invited_member_count,
+                true,
+                state_events,
+            )
+        } else if timeline_pdus.is_empty() && since_shortstatehash == Some(current_shortstatehash) {
+            // No state changes
+            (Vec::new(), None, None, false, Vec::new())
         } else {
             // Incremental /sync
             let since_shortstatehash = since_shortstatehash.unwrap();
@@ -512,11 +1089,22 @@ async fn sync_helper(
             let mut state_events = Vec::new();
             let mut lazy_loaded = HashSet::new();
 
-            if since_shortstatehash != current_shortstatehash {
-                let current_state_ids = services()
+            // The state block has the changes up to the start of the timeline, including those
+            // in the gap before a limited timeline. Changes in the timeline are only sent there.
+            let timeline_start_shortstatehash = match timeline_pdus.first() {
+                Some((_, pdu)) => services()
                     .rooms
                     .state_accessor
-                    .state_full_ids(current_shortstatehash)
+                    .pdu_shortstatehash(&pdu.event_id)?
+                    .unwrap_or(current_shortstatehash),
+                None => current_shortstatehash,
+            };
+
+            if full_state || since_shortstatehash != timeline_start_shortstatehash {
+                let timeline_start_state_ids = services()
+                    .rooms
+                    .state_accessor
+                    .state_full_ids(timeline_start_shortstatehash)
                     .await?;
                 let since_state_ids = services()
                     .rooms
@@ -524,33 +1112,31 @@ async fn sync_helper(
                     .state_full_ids(since_shortstatehash)
                     .await?;
 
-                for (key, id) in current_state_ids {
-                    if body.full_state || since_state_ids.get(&key) != Some(&id) {
-                        let pdu = match services().rooms.timeline.get_pdu(&id)? {
-                            Some(pdu) => pdu,
-                            None => {
-                                error!("Pdu in state not found: {}", id);
-                                continue;
-                            }
-                        };
-
-                        if pdu.kind == RoomEventType::RoomMember {
-                            match UserId::parse(
-                                pdu.state_key
-                                    .as_ref()
-                                    .expect("State event has state key")
-                                    .clone(),
-                            ) {
-                                Ok(state_key_userid) => {
-                                    lazy_loaded.insert(state_key_userid);
-                                }
-                                Err(e) => error!("Invalid state key for member event: {}", e),
-                            }
+                for id in changed_state(&since_state_ids, &timeline_start_state_ids, full_state) {
+                    let pdu = match services().rooms.timeline.get_pdu(id)? {
+                        Some(pdu) => pdu,
+                        None => {
+                            error!("Pdu in state not found: {}", id);
+                            continue;
                         }
+                    };
 
-                        state_events.push(pdu);
-                        tokio::task::yield_now().await;
+                    if pdu.kind == RoomEventType::RoomMember {
+                        match UserId::parse(
+                            pdu.state_key
+                                .as_ref()
+                                .expect("State event has state key")
+                                .clone(),
+                        ) {
+                            Ok(state_key_userid) => {
+                                lazy_loaded.insert(state_key_userid);
+                            }
+                            Err(e) => error!("Invalid state key for member event: {}", e),
+                        }
                     }
+
+                    state_events.push(pdu);
+                    tokio::task::yield_now().await;
                 }
             }
 
@@ -600,12 +1186,24 @@ async fn sync_helper(
             // Calculations:
             let new_encrypted_room = encrypted_room && since_encryption.is_none();
 
-            let send_member_count = state_events
+            // Membership changes in the timeline count as much as those in the state block
+            let changed_state_events: Vec<&PduEvent> = state_events
+                .iter()
+                .map(|pdu| &**pdu)
+                .chain(
+                    timeline_pdus
+                        .iter()
+                        .map(|(_, pdu)| pdu)
+                        .filter(|pdu| pdu.state_key.is_some()),
+                )
+                .collect();
+
+            let send_member_count = changed_state_events
                 .iter()
                 .any(|event| event.kind == RoomEventType::RoomMember);
 
             if encrypted_room {
-                for state_event in &state_events {
+                for state_event in &changed_state_events {
                     if state_event.kind != RoomEventType::RoomMember {
                         continue;
                     }
@@ -627,7 +1225,9 @@ async fn sync_helper(
                         match new_membership {
                             MembershipState::Join => {
                                 // A new user joined an encrypted room
-                                if !share_encrypted_room(&sender_user, &user_id, &room_id)? {
+                                if !tracked_device_lists.contains(&user_id)
+                                    && !share_encrypted_room(&sender_user, &user_id, &room_id)?
+                                {
                                     device_list_updates.insert(user_id);
                                 }
                             }
@@ -636,420 +1236,124 @@ async fn sync_helper(
                                 left_encrypted_users.insert(user_id);
                             }
                             _ => {}
-                        }
-                    }
-                }
-            }
-
-            if joined_since_last_sync && encrypted_room || new_encrypted_room {
-                // If the user is in a new encrypted room, give them all joined users
-                device_list_updates.extend(
-                    services()
-                        .rooms
-                        .state_cache
-                        .room_members(&room_id)
-                        .flatten()
-                        .filter(|user_id| {
-                            // Don't send key updates from the sender to the sender
-                            &sender_user != user_id
-                        })
-                        .filter(|user_id| {
-                            // Only send keys if the sender doesn't share an encrypted room with the target already
-                            !share_encrypted_room(&sender_user, user_id, &room_id).unwrap_or(false)
-                        }),
-                );
-            }
-
-            let (joined_member_count, invited_member_count, heroes) = if send_member_count {
-                calculate_counts()?
-            } else {
-                (None, None, Vec::new())
-            };
-
-            (
-                heroes,
-                joined_member_count,
-                invited_member_count,
-                joined_since_last_sync,
-                state_events,
-            )
-        };
-
-        // Look for device list updates in this room
-        device_list_updates.extend(
-            services()
-                .users
-                .keys_changed(room_id.as_ref(), since, None)
-                .filter_map(|r| r.ok()),
-        );
-
-        let notification_count = if send_notification_counts {
-            Some(
-                services()
-                    .rooms
-                    .user
-                    .notification_count(&sender_user, &room_id)?
-                    .try_into()
-                    .expect("notification count can't go that high"),
-            )
-        } else {
-            None
-        };
-
-        let highlight_count = if send_notification_counts {
-            Some(
-                services()
-                    .rooms
-                    .user
-                    .highlight_count(&sender_user, &room_id)?
-                    .try_into()
-                    .expect("highlight count can't go that high"),
-            )
-        } else {
-            None
-        };
-
-        let prev_batch = timeline_pdus
-            .first()
-            .map_or(Ok::<_, Error>(None), |(pdu_id, _)| {
-                Ok(Some(
-                    services().rooms.timeline.pdu_count(pdu_id)?.to_string(),
-                ))
-            })?;
-
-        let room_events: Vec<_> = timeline_pdus
-            .iter()
-            .map(|(_, pdu)| pdu.to_sync_room_event())
-            .collect();
-
-        let mut edus: Vec<_> = services()
-            .rooms
-            .edus
-            .read_receipt
-            .readreceipts_since(&room_id, since)
-            .filter_map(|r| r.ok()) // Filter out buggy events
-            .map(|(_, _, v)| v)
-            .collect();
-
-        if services().rooms.edus.typing.last_typing_update(&room_id)? > since {
-            edus.push(
-                serde_json::from_str(
-                    &serde_json::to_string(&services().rooms.edus.typing.typings_all(&room_id)?)
-                        .expect("event is valid, we just created it"),
-                )
-                .expect("event is valid, we just created it"),
-            );
-        }
-
-        // Save the state after this sync so we can send the correct state diff next sync
-        services().rooms.user.associate_token_shortstatehash(
-            &room_id,
-            next_batch,
-            current_shortstatehash,
-        )?;
-
-        let joined_room = JoinedRoom {
-            account_data: RoomAccountData {
-                events: services()
-                    .account_data
-                    .changes_since(Some(&room_id), &sender_user, since)?
-                    .into_iter()
-                    .filter_map(|(_, v)| {
-                        serde_json::from_str(v.json().get())
-                            .map_err(|_| Error::bad_database("Invalid account event in database."))
-                            .ok()
-                    })
-                    .collect(),
-            },
-            summary: RoomSummary {
-                heroes,
-                joined_member_count: joined_member_count.map(|n| (n as u32).into()),
-                invited_member_count: invited_member_count.map(|n| (n as u32).into()),
-            },
-            unread_notifications: UnreadNotificationsCount {
-                highlight_count,
-                notification_count,
-            },
-            timeline: Timeline {
-                limited: limited || joined_since_last_sync,
-                prev_batch,
-                events: room_events,
-            },
-            state: State {
-                events: state_events
-                    .iter()
-                    .map(|pdu| pdu.to_sync_state_event())
-                    .collect(),
-            },
-            ephemeral: Ephemeral { events: edus },
-            unread_thread_notifications: BTreeMap::new(),
-        };
-
-        if !joined_room.is_empty() {
-            joined_rooms.insert(room_id.clone(), joined_room);
-        }
-
-        // Take presence updates from this room
-        for (user_id, presence) in services()
-            .rooms
-            .edus
-            .presence
-            .presence_since(&room_id, since)?
-        {
-            match presence_updates.entry(user_id) {
-                Entry::Vacant(v) => {
-                    v.insert(presence);
-                }
-                Entry::Occupied(mut o) => {
-                    let p = o.get_mut();
-
-                    // Update existing presence event with more info
-                    p.content.presence = presence.content.presence;
-                    if let Some(status_msg) = presence.content.status_msg {
-                        p.content.status_msg = Some(status_msg);
-                    }
-                    if let Some(last_active_ago) = presence.content.last_active_ago {
-                        p.content.last_active_ago = Some(last_active_ago);
-                    }
-                    if let Some(displayname) = presence.content.displayname {
-                        p.content.displayname = Some(displayname);
-                    }
-                    if let Some(avatar_url) = presence.content.avatar_url {
-                        p.content.avatar_url = Some(avatar_url);
-                    }
-                    if let Some(currently_active) = presence.content.currently_active {
-                        p.content.currently_active = Some(currently_active);
-                    }
-                }
-            }
-        }
-    }
-
-    let mut left_rooms = BTreeMap::new();
-    let all_left_rooms: Vec<_> = services()
-        .rooms
-        .state_cache
-        .rooms_left(&sender_user)
-        .collect();
-    for result in all_left_rooms {
-        let (room_id, _) = result?;
-
-        let mut left_state_events = Vec::new();
-
-        {
-            // Get and drop the lock to wait for remaining operations to finish
-            let mutex_insert = Arc::clone(
-                services()
-                    .globals
-                    .roomid_mutex_insert
-                    .write()
-                    .unwrap()
-                    .entry(room_id.clone())
-                    .or_default(),
-            );
-            let insert_lock = mutex_insert.lock().unwrap();
-            drop(insert_lock);
-        }
-
-        let left_count = services()
-            .rooms
-            .state_cache
-            .get_left_count(&room_id, &sender_user)?;
-
-        // Left before last sync
-        if Some(since) >= left_count {
-            continue;
-        }
-
-        if !services().rooms.metadata.exists(&room_id)? {
-            // This is just a rejected invite, not a room we know
-            continue;
-        }
-
-        let since_shortstatehash = services()
-            .rooms
-            .user
-            .get_token_shortstatehash(&room_id, since)?;
-
-        let since_state_ids = match since_shortstatehash {
-            Some(s) => services().rooms.state_accessor.state_full_ids(s).await?,
-            None => HashMap::new(),
-        };
-
-        let left_event_id = match services().rooms.state_accessor.room_state_get_id(
-            &room_id,
-            &StateEventType::RoomMember,
-            sender_user.as_str(),
-        )? {
-            Some(e) => e,
-            None => {
-                error!("Left room but no left state event");
-                continue;
+                        }
+                    }
+                }
             }
-        };
 
-        let left_shortstatehash = match services()
-            .rooms
-            .state_accessor
-            .pdu_shortstatehash(&left_event_id)?
-        {
-            Some(s) => s,
-            None => {
-                error!("Leave event has no state");
-                continue;
+            if joined_since_last_sync && encrypted_room || new_encrypted_room {
+                // If the user is in a new encrypted room, give them all joined users
+                device_list_updates.extend(
+                    services()
+                        .rooms
+                        .state_cache
+                        .room_members(&room_id)
+                        .flatten()
+                        .filter(|user_id| {
+                            // Don't send key updates from the sender to the sender
+                            &sender_user != user_id
+                        })
+                        .filter(|user_id| !tracked_device_lists.contains(user_id))
+                        .filter(|user_id| {
+                            // Only send keys if the sender doesn't share an encrypted room with the target already
+                            !share_encrypted_room(&sender_user, user_id, &room_id).unwrap_or(false)
+                        }),
+                );
             }
-        };
-
-        let mut left_state_ids = services()
-            .rooms
-            .state_accessor
-            .state_full_ids(left_shortstatehash)
-            .await?;
 
-        let leave_shortstatekey = services()
-            .rooms
-            .short
-            .get_or_create_shortstatekey(&StateEventType::RoomMember, sender_user.as_str())?;
+            let (joined_member_count, invited_member_count, heroes) = if send_member_count {
+                calculate_counts()?
+            } else {
+                (None, None, Vec::new())
+            };
 
-        left_state_ids.insert(leave_shortstatekey, left_event_id);
+            (
+                heroes,
+                joined_member_count,
+                invited_member_count,
+                joined_since_last_sync,
+                state_events,
+            )
+        };
 
-        let mut i = 0;
-        for (key, id) in left_state_ids {
-            if body.full_state || since_state_ids.get(&key) != Some(&id) {
-                let (event_type, state_key) =
-                    services().rooms.short.get_statekey_from_short(key)?;
+    // Look for device list updates in this room
+    device_list_updates.extend(
+        services()
+            .users
+            .keys_changed(room_id.as_ref(), since, None)
+            .filter_map(|r| r.ok()),
+    );
 
-                if !lazy_load_enabled
-                    || event_type != StateEventType::RoomMember
-                    || body.full_state
-                    // TODO: Delete the following line when this is resolved: https://github.com/vector-im/element-web/issues/22565
-                    || *sender_user == state_key
-                {
-                    let pdu = match services().rooms.timeline.get_pdu(&id)? {
-                        Some(pdu) => pdu,
-                        None => {
-                            error!("Pdu in state not found: {}", id);
-                            continue;
-                        }
-                    };
+    let notification_count = if send_notification_counts {
+        Some(
+            services()
+                .rooms
+                .user
+                .notification_count(&sender_user, &room_id)?
+                .try_into()
+                .expect("notification count can't go that high"),
+        )
+    } else {
+        None
+    };
 
-                    left_state_events.push(pdu.to_sync_state_event());
+    let highlight_count = if send_notification_counts {
+        Some(
+            services()
+                .rooms
+                .user
+                .highlight_count(&sender_user, &room_id)?
+                .try_into()
+                .expect("highlight count can't go that high"),
+        )
+    } else {
+        None
+    };
 
-                    i += 1;
-                    if i % 100 == 0 {
-                        tokio::task::yield_now().await;
-                    }
-                }
-            }
-        }
+    let prev_batch = timeline_pdus
+        .first()
+        .map_or(Ok::<_, Error>(None), |(pdu_id, _)| {
+            Ok(Some(
+                services().rooms.timeline.pdu_count(pdu_id)?.to_string(),
+            ))
+        })?;
 
-        left_rooms.insert(
-            room_id.clone(),
-            LeftRoom {
-                account_data: RoomAccountData { events: Vec::new() },
-                timeline: Timeline {
-                    limited: false,
-                    prev_batch: Some(next_batch_string.clone()),
-                    events: Vec::new(),
-                },
-                state: State {
-                    events: left_state_events,
-                },
-            },
-        );
-    }
+    let room_events: Vec<_> = timeline_pdus
+        .iter()
+        .map(|(_, pdu)| pdu.to_sync_room_event())
+        .collect();
 
-    let mut invited_rooms = BTreeMap::new();
-    let all_invited_rooms: Vec<_> = services()
+    let mut edus: Vec<_> = services()
         .rooms
-        .state_cache
-        .rooms_invited(&sender_user)
+        .edus
+        .read_receipt
+        .readreceipts_since(&room_id, since)
+        .filter_map(|r| r.ok()) // Filter out buggy events
+        .map(|(_, _, v)| v)
         .collect();
-    for result in all_invited_rooms {
-        let (room_id, invite_state_events) = result?;
-
-        {
-            // Get and drop the lock to wait for remaining operations to finish
-            let mutex_insert = Arc::clone(
-                services()
-                    .globals
-                    .roomid_mutex_insert
-                    .write()
-                    .unwrap()
-                    .entry(room_id.clone())
-                    .or_default(),
-            );
-            let insert_lock = mutex_insert.lock().unwrap();
-            drop(insert_lock);
-        }
-
-        let invite_count = services()
-            .rooms
-            .state_cache
-            .get_invite_count(&room_id, &sender_user)?;
-
-        // Invited before last sync
-        if Some(since) >= invite_count {
-            continue;
-        }
 
-        invited_rooms.insert(
-            room_id.clone(),
-            InvitedRoom {
-                invite_state: InviteState {
-                    events: invite_state_events,
-                },
-            },
+    if services().rooms.edus.typing.last_typing_update(&room_id)? > since {
+        edus.push(
+            serde_json::from_str(
+                &serde_json::to_string(&services().rooms.edus.typing.typings_all(&room_id)?)
+                    .expect("event is valid, we just created it"),
+            )
+            .expect("event is valid, we just created it"),
         );
     }
 
-    for user_id in left_encrypted_users {
-        let still_share_encrypted_room = services()
-            .rooms
-            .user
-            .get_shared_rooms(vec![sender_user.clone(), user_id.clone()])?
-            .filter_map(|r| r.ok())
-            .filter_map(|other_room_id| {
-                Some(
-                    services()
-                        .rooms
-                        .state_accessor
-                        .room_state_get(&other_room_id, &StateEventType::RoomEncryption, "")
-                        .ok()?
-                        .is_some(),
-                )
-            })
-            .all(|encrypted| !encrypted);
-        // If the user doesn't share an encrypted room with the target anymore, we need to tell
-        // them
-        if still_share_encrypted_room {
-            device_list_left.insert(user_id);
-        }
-    }
-
-    // Remove all to-device events the device received *last time*
-    services()
-        .users
-        .remove_to_device_events(&sender_user, &sender_device, since)?;
+    // Save the state after this sync so we can send the correct state diff next sync
+    services().rooms.user.associate_token_shortstatehash(
+        &room_id,
+        next_batch,
+        current_shortstatehash,
+    )?;
 
-    let response = sync_events::v3::Response {
-        next_batch: next_batch_string,
-        rooms: Rooms {
-            leave: left_rooms,
-            join: joined_rooms,
-            invite: invited_rooms,
-            knock: BTreeMap::new(), // TODO
-        },
-        presence: Presence {
-            events: presence_updates
-                .into_values()
-                .map(|v| Raw::new(&v).expect("PresenceEvent always serializes successfully"))
-                .collect(),
-        },
-        account_data: GlobalAccountData {
+    let joined_room = JoinedRoom {
+        account_data: RoomAccountData {
             events: services()
                 .account_data
-                .changes_since(None, &sender_user, since)?
+                .changes_since(Some(&room_id), &sender_user, since)?
                 .into_iter()
                 .filter_map(|(_, v)| {
                     serde_json::from_str(v.json().get())
@@ -1058,41 +1362,102 @@ async fn sync_helper(
                 })
                 .collect(),
         },
-        device_lists: DeviceLists {
-            changed: device_list_updates.into_iter().collect(),
-            left: device_list_left.into_iter().collect(),
+        summary: RoomSummary {
+            heroes,
+            joined_member_count: joined_member_count.map(|n| (n as u32).into()),
+            invited_member_count: invited_member_count.map(|n| (n as u32).into()),
         },
-        device_one_time_keys_count: services()
-            .users
-            .count_one_time_keys(&sender_user, &sender_device)?,
-        to_device: ToDevice {
-            events: services()
-                .users
-                .get_to_device_events(&sender_user, &sender_device)?,
+        unread_notifications: UnreadNotificationsCount {
+            highlight_count,
+            notification_count,
         },
-        // Fallback keys are not yet supported
-        device_unused_fallback_key_types: None,
+        timeline: Timeline {
+            limited: limited || joined_since_last_sync,
+            prev_batch,
+            events: room_events,
+        },
+        state: State {
+            events: state_events
+                .iter()
+                .map(|pdu| pdu.to_sync_state_event())
+                .collect(),
+        },
+        ephemeral: Ephemeral { events: edus },
+        unread_thread_notifications: BTreeMap::new(),
     };
 
-    // TODO: Retry the endpoint instead of returning (waiting for #118)
-    if !body.full_state
-        && response.rooms.is_empty()
-        && response.presence.is_empty()
-        && response.account_data.is_empty()
-        && response.device_lists.is_empty()
-        && response.to_device.is_empty()
-    {
-        // Hang a few seconds so requests are not spammed
-        // Stop hanging if new info arrives
-        let mut duration = body.timeout.unwrap_or_default();
-        if duration.as_secs() > 30 {
-            duration = Duration::from_secs(30);
-        }
-        let _ = tokio::time::timeout(duration, watcher).await;
-        Ok((response, false))
-    } else {
-        Ok((response, since != next_batch)) // Only cache if we made progress
-    }
+    Ok(Some((
+        room_id,
+        joined_room,
+        device_list_updates,
+        left_encrypted_users,
+    )))
+}
+
+/// The ids of the state events that changed from `since` to `state`, or all of `state` if
+/// `full_state` is set.
+fn changed_state<'a>(
+    since: &HashMap<u64, Arc<EventId>>,
+    state: &'a HashMap<u64, Arc<EventId>>,
+    full_state: bool,
+) -> Vec<&'a Arc<EventId>> {
+    state
+        .iter()
+        .filter(|(key, id)| full_state || since.get(key) != Some(id))
+        .map(|(_, id)| id)
+        .collect()
+}
+
+/// How many events of each room's timeline are sent: what the filter asks for, capped at the
+/// server maximum, and at least one, so clients always get a prev_batch to paginate from.
+fn timeline_limit(filter_limit: Option<u64>, default: usize, max: usize) -> usize {
+    filter_limit
+        .map_or(default, |limit| limit.try_into().unwrap_or(usize::MAX))
+        .clamp(1, max.max(1))
+}
+
+/// How many of the newest events fit in `max_bytes`, given their sizes newest first. The newest
+/// event is always sent, however big it is.
+fn events_within(sizes: &[usize], max_bytes: usize) -> usize {
+    let mut total = 0;
+    let fitting = sizes
+        .iter()
+        .take_while(|size| {
+            total += *size;
+            total <= max_bytes
+        })
+        .count();
+
+    fitting.max(sizes.len().min(1))
+}
+
+/// The newest `limit` events after `since` and up to and including `until`, oldest first, and
+/// whether older events after `since` were left out. `pdus` are the counts and events of a room,
+/// newest first.
+fn timeline_window<T>(
+    pdus: impl Iterator<Item = (u64, T)>,
+    since: u64,
+    until: u64,
+    limit: usize,
+) -> (Vec<T>, bool) {
+    let mut pdus = pdus
+        .skip_while(|(count, _)| *count > until)
+        .take_while(|(count, _)| *count > since);
+
+    let mut window = pdus
+        .by_ref()
+        .take(limit)
+        .map(|(_, pdu)| pdu)
+        .collect::<Vec<_>>();
+    window.reverse();
+
+    (window, pdus.next().is_some())
+}
+
+/// Whether the user left the room after `since`. Forgotten rooms have no left count, so they don't
+/// show up again, not even in initial syncs.
+fn left_since(since: u64, left_count: Option<u64>) -> bool {
+    left_count.map_or(false, |left_count| left_count > since)
 }
 
 fn share_encrypted_room(
@@ -1118,3 +1483,118 @@ fn share_encrypted_room(
         })
         .any(|encrypted| encrypted))
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn state_changes_in_the_gap_are_synced() {
+        let id = |id: &str| Arc::<EventId>::from(<&EventId>::try_from(id).unwrap());
+        let create = id("$create");
+        let alice_join = id("$alice_join");
+        let bob_join = id("$bob_join");
+        let bob_leave = id("$bob_leave");
+
+        // Bob left in the gap between the last sync and the start of the limited timeline
+        let since = HashMap::from([(1, create.clone()), (2, alice_join), (3, bob_join)]);
+        let timeline_start =
+            HashMap::from([(1, create), (2, since[&2].clone()), (3, bob_leave.clone())]);
+
+        assert_eq!(changed_state(&since, &timeline_start, false), [&bob_leave]);
+        assert_eq!(changed_state(&since, &timeline_start, true).len(), 3);
+        assert!(changed_state(&since, &since, false).is_empty());
+    }
+
+    #[test]
+    fn timeline_limit_of_the_filter_is_capped() {
+        assert_eq!(timeline_limit(None, 10, 100), 10);
+        assert_eq!(timeline_limit(Some(50), 10, 100), 50);
+        assert_eq!(timeline_limit(Some(5000), 10, 100), 100);
+        assert_eq!(timeline_limit(Some(0), 10, 100), 1);
+    }
+
+    #[test]
+    fn full_timelines_are_truncated_to_the_size_limit() {
+        // A room full of events of 500 bytes each
+        let sizes = vec![500; 1000];
+        assert_eq!(events_within(&sizes, 10_000), 20);
+        assert_eq!(events_within(&sizes, 10_499), 20);
+        assert_eq!(events_within(&sizes, 1_000_000), 1000);
+
+        // The newest event is sent even if it's too big on its own
+        assert_eq!(events_within(&[20_000, 500], 10_000), 1);
+        assert_eq!(events_within(&[], 10_000), 0);
+    }
+
+    #[test]
+    fn forgotten_rooms_are_not_synced() {
+        // Left after the last sync
+        assert!(left_since(5, Some(7)));
+        // Left before the last sync
+        assert!(!left_since(7, Some(5)));
+        // Initial syncs show left rooms, until they are forgotten
+        assert!(left_since(0, Some(5)));
+        assert!(!left_since(0, None));
+        assert!(!left_since(5, None));
+    }
+
+    #[test]
+    fn left_timelines_end_with_the_leave_event() {
+        // Events 1 to 10 in the room, the user left with event 6
+        let room = || (1..=10).rev().map(|count| (count, count));
+
+        assert_eq!(
+            timeline_window(room(), 0, 6, 10),
+            (vec![1, 2, 3, 4, 5, 6], false)
+        );
+        assert_eq!(timeline_window(room(), 0, 6, 2), (vec![5, 6], true));
+        assert_eq!(timeline_window(room(), 4, 6, 10), (vec![5, 6], false));
+        assert_eq!(timeline_window(room(), 6, 6, 10), (vec![], false));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn transient_errors_are_retried() {
+        let busy = || Error::SqliteError {
+            source: rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+                None,
+            ),
+        };
+        let attempts = &AtomicU32::new(0);
+
+        let result = retry_transient(3, Duration::ZERO, || async move {
+            if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                Err(busy())
+            } else {
+                Ok("next_batch")
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), "next_batch");
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        // Gives up after the configured number of retries
+        attempts.store(0, Ordering::SeqCst);
+        let result: Result<()> = retry_transient(2, Duration::ZERO, || async move {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(busy())
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // Other errors aren't retried
+        attempts.store(0, Ordering::SeqCst);
+        let result: Result<()> = retry_transient(2, Duration::ZERO, || async move {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(Error::bad_database("Invalid data."))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
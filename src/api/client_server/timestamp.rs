@@ -0,0 +1,309 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use crate::{services, Error, Result, Ruma, RumaResponse};
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use ruma::{
+    api::{
+        client::{error::ErrorKind, room::get_event_by_timestamp, Direction},
+        federation,
+    },
+    events::StateEventType,
+    MilliSecondsSinceUnixEpoch, OwnedEventId, RoomId, ServerName, UserId,
+};
+use tracing::{debug, warn};
+
+/// Other servers are asked when our closest event is further away from the requested time, they
+/// might have history we are missing.
+const MAX_LOCAL_DISTANCE: Duration = Duration::from_secs(60 * 60);
+/// How many other servers are asked at once.
+const MAX_REMOTE_SERVERS: usize = 5;
+/// How long they have to answer, including fetching the event they name.
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// # `GET /_matrix/client/unstable/org.matrix.msc3030/rooms/{roomId}/timestamp_to_event`
+///
+/// Finds the event closest to a timestamp, to let clients jump to a date.
+///
+/// - Only works if the user can see the room, and only returns events they are allowed to see
+/// - Asks the other servers in the room if we don't have an event close to the timestamp. Events
+///   they name that we only have as outliers can't be checked and are not returned
+pub async fn get_event_by_timestamp_route(
+    body: Ruma<get_event_by_timestamp::v1::Request>,
+) -> Result<get_event_by_timestamp::v1::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    if !services()
+        .rooms
+        .state_accessor
+        .user_can_see_state(Some(sender_user), &body.room_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to view this room.",
+        ));
+    }
+
+    let mut closest = services()
+        .rooms
+        .timeline
+        .closest_event(&body.room_id, body.ts.0, body.dir.clone(), |event_id| {
+            services()
+                .rooms
+                .state_accessor
+                .user_can_see_event(sender_user, &body.room_id, event_id)
+        })?
+        .map(|pdu| {
+            (
+                (*pdu.event_id).to_owned(),
+                MilliSecondsSinceUnixEpoch(pdu.origin_server_ts),
+            )
+        });
+
+    let close_enough = closest.as_ref().map_or(false, |(_, origin_server_ts)| {
+        distance(*origin_server_ts, body.ts) <= MAX_LOCAL_DISTANCE.as_millis() as u64
+    });
+
+    if !close_enough {
+        if let Some(remote) =
+            remote_closest_event(sender_user, &body.room_id, body.ts, &body.dir).await
+        {
+            let closer = closest.as_ref().map_or(true, |(_, origin_server_ts)| {
+                distance(remote.1, body.ts) < distance(*origin_server_ts, body.ts)
+            });
+            if closer {
+                closest = Some(remote);
+            }
+        }
+    }
+
+    let (event_id, origin_server_ts) = closest.ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "No event found in that direction.",
+    ))?;
+
+    Ok(get_event_by_timestamp::v1::Response::new(
+        event_id,
+        origin_server_ts,
+    ))
+}
+
+/// # `GET /_matrix/client/v1/rooms/{roomId}/timestamp_to_event`
+///
+/// The stable path of `get_event_by_timestamp_route`, ruma only knows the unstable one.
+pub async fn get_event_by_timestamp_v1_route(
+    body: Ruma<get_event_by_timestamp::v1::Request>,
+) -> Result<RumaResponse<get_event_by_timestamp::v1::Response>> {
+    get_event_by_timestamp_route(body).await.map(RumaResponse)
+}
+
+/// Asks some of the other servers in the room at once and returns the first answer that holds up:
+/// the event is fetched from the server that named it, has to be an event of the room in the
+/// requested direction and `sender_user` has to be allowed to see it.
+async fn remote_closest_event(
+    sender_user: &UserId,
+    room_id: &RoomId,
+    ts: MilliSecondsSinceUnixEpoch,
+    dir: &Direction,
+) -> Option<(OwnedEventId, MilliSecondsSinceUnixEpoch)> {
+    if !services().globals.allow_federation() {
+        return None;
+    }
+
+    let mut requests: FuturesUnordered<_> = services()
+        .rooms
+        .state_cache
+        .room_servers(room_id)
+        .filter_map(|r| r.ok())
+        .filter(|server| !services().globals.server_is_ours(server))
+        .take(MAX_REMOTE_SERVERS)
+        .map(|server| async move {
+            let response = tokio::time::timeout(
+                REMOTE_TIMEOUT,
+                remote_event_by_timestamp(&server, room_id, ts, dir),
+            )
+            .await;
+            (server, response)
+        })
+        .collect();
+
+    while let Some((server, response)) = requests.next().await {
+        match response {
+            Ok(Ok(closest)) => match services().rooms.state_accessor.user_can_see_event(
+                sender_user,
+                room_id,
+                &closest.0,
+            ) {
+                Ok(true) => return Some(closest),
+                Ok(false) => debug!("{} named an event the user can't see", server),
+                Err(e) => warn!("Could not check the event {} named: {}", server, e),
+            },
+            Ok(Err(e)) => warn!("Could not ask {} for an event by timestamp: {}", server, e),
+            Err(_) => warn!("Timed out asking {} for an event by timestamp", server),
+        }
+    }
+
+    None
+}
+
+async fn remote_event_by_timestamp(
+    server: &ServerName,
+    room_id: &RoomId,
+    ts: MilliSecondsSinceUnixEpoch,
+    dir: &Direction,
+) -> Result<(OwnedEventId, MilliSecondsSinceUnixEpoch)> {
+    let response = services()
+        .sending
+        .send_federation_request(
+            server,
+            federation::event::get_event_by_timestamp::v1::Request::new(
+                room_id.to_owned(),
+                ts,
+                dir.clone(),
+            ),
+        )
+        .await?;
+
+    let create_event = services()
+        .rooms
+        .state_accessor
+        .room_state_get(room_id, &StateEventType::RoomCreate, "")?
+        .ok_or_else(|| Error::bad_database("Room has no create event."))?;
+    let room_version_id = services().rooms.state.get_room_version(room_id)?;
+    let pub_key_map = RwLock::new(BTreeMap::new());
+
+    // Fetches the event if we don't have it and checks its signatures
+    let pdu = services()
+        .rooms
+        .event_handler
+        .fetch_and_handle_outliers(
+            server,
+            &[Arc::from(&*response.event_id)],
+            &create_event,
+            room_id,
+            &room_version_id,
+            &pub_key_map,
+        )
+        .await
+        .pop()
+        .map(|(pdu, _)| pdu)
+        .ok_or(Error::BadServerResponse(
+            "Server returned an invalid event.",
+        ))?;
+
+    let in_direction = match dir {
+        Direction::Forward => pdu.origin_server_ts >= ts.0,
+        Direction::Backward => pdu.origin_server_ts <= ts.0,
+    };
+    if pdu.room_id != room_id || !in_direction {
+        return Err(Error::BadServerResponse(
+            "Server returned an event that doesn't match the request.",
+        ));
+    }
+
+    Ok((
+        (*pdu.event_id).to_owned(),
+        MilliSecondsSinceUnixEpoch(pdu.origin_server_ts),
+    ))
+}
+
+/// How many milliseconds lie between two timestamps.
+fn distance(a: MilliSecondsSinceUnixEpoch, b: MilliSecondsSinceUnixEpoch) -> u64 {
+    u64::from(a.0).abs_diff(u64::from(b.0))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use axum::{routing::get, Json, Router};
+    use ruma::{
+        api::client::room::create_room, events::room::member::MembershipState, serde::Raw, user_id,
+        UInt,
+    };
+    use serde_json::{json, value::to_raw_value};
+
+    use super::*;
+    use crate::utils::testing;
+
+    #[tokio::test]
+    async fn events_named_by_other_servers_are_checked_for_visibility() {
+        let alice = testing::user("timestamp_alice").await;
+        let bob = testing::user("timestamp_bob").await;
+        let room_id = testing::room_with(
+            &alice,
+            create_room::v3::Request {
+                initial_state: vec![Raw::from_json(
+                    to_raw_value(&json!({
+                        "type": "m.room.history_visibility",
+                        "state_key": "",
+                        "content": { "history_visibility": "joined" },
+                    }))
+                    .unwrap(),
+                )],
+                ..create_room::v3::Request::new()
+            },
+        )
+        .await;
+        let secret = testing::send_message(&alice, &room_id, "Before bob joined").await;
+        testing::invite(&alice, &bob, &room_id).await;
+        testing::join(&bob, &room_id).await;
+        testing::send_message(&alice, &room_id, "After bob joined").await;
+
+        // The other server names the event from before bob joined
+        static ASKED: AtomicBool = AtomicBool::new(false);
+        let answer = json!({ "event_id": secret, "origin_server_ts": 1 });
+        let timestamp_to_event = get(move || {
+            ASKED.store(true, Ordering::SeqCst);
+            let answer = answer.clone();
+            async move { Json(answer) }
+        });
+        testing::remote_server(
+            "timestamp.remote.test",
+            Router::new()
+                .route(
+                    "/_matrix/federation/v1/timestamp_to_event/:room_id",
+                    timestamp_to_event.clone(),
+                )
+                .route(
+                    "/_matrix/federation/unstable/org.matrix.msc3030/timestamp_to_event/:room_id",
+                    timestamp_to_event,
+                ),
+        )
+        .await;
+        let erin = user_id!("@erin:timestamp.remote.test");
+        services()
+            .rooms
+            .state_cache
+            .update_membership(&room_id, erin, MembershipState::Join, erin, None, true)
+            .unwrap();
+
+        // Our events are all far away from two hours ago, so the other server is asked
+        let two_hours_ago = MilliSecondsSinceUnixEpoch(
+            MilliSecondsSinceUnixEpoch::now()
+                .0
+                .saturating_sub(UInt::from(2 * 60 * 60 * 1000_u32)),
+        );
+        let response = get_event_by_timestamp_route(testing::request(
+            get_event_by_timestamp::v1::Request::new(
+                room_id.clone(),
+                two_hours_ago,
+                Direction::Forward,
+            ),
+            &bob,
+        ))
+        .await
+        .unwrap();
+
+        assert!(ASKED.load(Ordering::SeqCst));
+        assert_ne!(response.event_id, secret);
+        assert!(services()
+            .rooms
+            .state_accessor
+            .user_can_see_event(&bob, &room_id, &response.event_id)
+            .unwrap());
+    }
+}
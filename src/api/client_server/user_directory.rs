@@ -2,89 +2,84 @@ use crate::{services, Result, Ruma};
 use ruma::{
     api::client::user_directory::search_users,
     events::{
-        room::join_rules::{JoinRule, RoomJoinRulesEventContent},
+        room::{
+            join_rules::{JoinRule, RoomJoinRulesEventContent},
+            member::RoomMemberEventContent,
+        },
         StateEventType,
     },
+    OwnedRoomId, UserId,
 };
 
+/// Upper bound for the `limit` a client can request
+const MAX_SEARCH_LIMIT: usize = 100;
+
 /// # `POST /_matrix/client/r0/user_directory/search`
 ///
 /// Searches all known users for a match.
 ///
-/// - Hides any local users that aren't in any public rooms (i.e. those that have the join rule set to public)
-/// and don't share a room with the sender
+/// - Known users include remote users from rooms this server participates in
+/// - Hides deactivated local users
+/// - Unless `user_directory_search_all_users` is set, hides any users that aren't in any public
+/// rooms (i.e. those that have the join rule set to public) and don't share a room with the sender
 pub async fn search_users_route(
     body: Ruma<search_users::v3::Request>,
 ) -> Result<search_users::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
-    let limit = u64::from(body.limit) as usize;
+    let limit = (u64::from(body.limit) as usize).min(MAX_SEARCH_LIMIT);
+    let search_term = body.search_term.to_lowercase();
 
     let mut users = services().users.iter().filter_map(|user_id| {
         // Filter out buggy users (they should not exist, but you never know...)
         let user_id = user_id.ok()?;
 
-        let user = search_users::v3::User {
+        // Remote users are stored as deactivated, so only check this for our own users
+        if services().globals.server_is_ours(user_id.server_name())
+            && services().users.is_deactivated(&user_id).ok()?
+        {
+            return None;
+        }
+
+        let mut user = search_users::v3::User {
             user_id: user_id.clone(),
             display_name: services().users.displayname(&user_id).ok()?,
             avatar_url: services().users.avatar_url(&user_id).ok()?,
         };
 
-        let user_id_matches = user
-            .user_id
-            .to_string()
-            .to_lowercase()
-            .contains(&body.search_term.to_lowercase());
-
-        let user_displayname_matches = user
-            .display_name
-            .as_ref()
-            .filter(|name| {
-                name.to_lowercase()
-                    .contains(&body.search_term.to_lowercase())
-            })
-            .is_some();
-
-        if !user_id_matches && !user_displayname_matches {
+        // Cheap check before looking at the rooms of the user
+        let profile_known = user.display_name.is_some();
+        if profile_known && !user_matches(&user, &search_term) {
             return None;
         }
 
-        let user_is_in_public_rooms = services()
-            .rooms
-            .state_cache
-            .rooms_joined(&user_id)
-            .filter_map(|r| r.ok())
-            .any(|room| {
+        let visible_room = match user_visibility(sender_user, &user_id) {
+            Visibility::Hidden => return None,
+            Visibility::Visible(room) => room,
+        };
+
+        // We don't store profiles of remote users, use their member event instead
+        if !profile_known {
+            if let Some(member) = visible_room.and_then(|room_id| {
                 services()
                     .rooms
                     .state_accessor
-                    .room_state_get(&room, &StateEventType::RoomJoinRules, "")
-                    .map_or(false, |event| {
-                        event.map_or(false, |event| {
-                            serde_json::from_str(event.content.get())
-                                .map_or(false, |r: RoomJoinRulesEventContent| {
-                                    r.join_rule == JoinRule::Public
-                                })
-                        })
+                    .room_state_get(&room_id, &StateEventType::RoomMember, user_id.as_str())
+                    .ok()
+                    .flatten()
+                    .and_then(|pdu| {
+                        serde_json::from_str::<RoomMemberEventContent>(pdu.content.get()).ok()
                     })
-            });
-
-        if user_is_in_public_rooms {
-            return Some(user);
+            }) {
+                user.display_name = member.displayname;
+                user.avatar_url = user.avatar_url.or(member.avatar_url);
+            }
         }
 
-        let user_is_in_shared_rooms = services()
-            .rooms
-            .user
-            .get_shared_rooms(vec![sender_user.clone(), user_id])
-            .ok()?
-            .next()
-            .is_some();
-
-        if user_is_in_shared_rooms {
-            return Some(user);
+        if !user_matches(&user, &search_term) {
+            return None;
         }
 
-        None
+        Some(user)
     });
 
     let results = users.by_ref().take(limit).collect();
@@ -92,3 +87,144 @@ pub async fn search_users_route(
 
     Ok(search_users::v3::Response { results, limited })
 }
+
+enum Visibility {
+    Hidden,
+    /// The user is visible, optionally because of the given room
+    Visible(Option<OwnedRoomId>),
+}
+
+/// Checks if the sender is allowed to find the user in the directory.
+fn user_visibility(sender_user: &UserId, user_id: &UserId) -> Visibility {
+    let user_in_public_room = services()
+        .rooms
+        .state_cache
+        .rooms_joined(user_id)
+        .filter_map(|r| r.ok())
+        .find(|room| {
+            services()
+                .rooms
+                .state_accessor
+                .room_state_get(room, &StateEventType::RoomJoinRules, "")
+                .map_or(false, |event| {
+                    event.map_or(false, |event| {
+                        serde_json::from_str(event.content.get())
+                            .map_or(false, |r: RoomJoinRulesEventContent| {
+                                r.join_rule == JoinRule::Public
+                            })
+                    })
+                })
+        });
+
+    if user_in_public_room.is_some() {
+        return Visibility::Visible(user_in_public_room);
+    }
+
+    let shared_room = services()
+        .rooms
+        .user
+        .get_shared_rooms(vec![sender_user.to_owned(), user_id.to_owned()])
+        .ok()
+        .and_then(|mut rooms| rooms.find_map(|r| r.ok()));
+
+    if shared_room.is_some() {
+        return Visibility::Visible(shared_room);
+    }
+
+    if services().globals.user_directory_search_all_users() {
+        return Visibility::Visible(None);
+    }
+
+    Visibility::Hidden
+}
+
+/// Checks if the user id or the displayname contain the (lowercase) search term.
+fn user_matches(user: &search_users::v3::User, search_term: &str) -> bool {
+    let user_id_matches = user.user_id.as_str().to_lowercase().contains(search_term);
+
+    let user_displayname_matches = user
+        .display_name
+        .as_ref()
+        .filter(|name| name.to_lowercase().contains(search_term))
+        .is_some();
+
+    user_id_matches || user_displayname_matches
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::{api::client::room::create_room, room::RoomPreset, user_id, OwnedUserId};
+
+    use super::*;
+    use crate::utils::testing;
+
+    fn user(display_name: Option<&str>) -> search_users::v3::User {
+        search_users::v3::User {
+            user_id: user_id!("@alice:example.org").to_owned(),
+            display_name: display_name.map(ToOwned::to_owned),
+            avatar_url: None,
+        }
+    }
+
+    #[test]
+    fn matches_user_id_and_displayname() {
+        assert!(user_matches(&user(None), "alice"));
+        assert!(user_matches(&user(None), "example.org"));
+        assert!(user_matches(&user(Some("Wonderland")), "wonder"));
+        assert!(!user_matches(&user(Some("Wonderland")), "bob"));
+    }
+
+    #[test]
+    fn search_is_case_insensitive() {
+        assert!(user_matches(&user(Some("Alice Liddell")), "liddell"));
+    }
+
+    async fn search(searcher: &UserId, term: &str) -> Vec<OwnedUserId> {
+        search_users_route(testing::request(
+            search_users::v3::Request::new(term.to_owned()),
+            searcher,
+        ))
+        .await
+        .unwrap()
+        .results
+        .into_iter()
+        .map(|user| user.user_id)
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn finds_users_in_public_rooms() {
+        let searcher = testing::user("dir_searcher1").await;
+        let creator = testing::user("dir_public_creator").await;
+        testing::room_with(
+            &creator,
+            create_room::v3::Request {
+                preset: Some(RoomPreset::PublicChat),
+                ..create_room::v3::Request::new()
+            },
+        )
+        .await;
+
+        assert_eq!(search(&searcher, "dir_public").await, vec![creator]);
+    }
+
+    #[tokio::test]
+    async fn finds_users_in_shared_private_rooms() {
+        let searcher = testing::user("dir_searcher2").await;
+        let member = testing::user("dir_shared_member").await;
+        let room_id = testing::room(&member).await;
+        testing::invite(&member, &searcher, &room_id).await;
+        testing::join(&searcher, &room_id).await;
+
+        assert_eq!(search(&searcher, "dir_shared").await, vec![member]);
+    }
+
+    #[tokio::test]
+    async fn hides_users_without_public_or_shared_rooms() {
+        let searcher = testing::user("dir_searcher3").await;
+        let hidden = testing::user("dir_hidden").await;
+        testing::room(&hidden).await;
+
+        assert!(search(&searcher, "dir_hidden").await.is_empty());
+    }
+}
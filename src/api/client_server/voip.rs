@@ -1,6 +1,6 @@
 use crate::{services, Result, Ruma};
 use hmac::{Hmac, Mac};
-use ruma::{api::client::voip::get_turn_server_info, SecondsSinceUnixEpoch};
+use ruma::{api::client::voip::get_turn_server_info, SecondsSinceUnixEpoch, UserId};
 use sha1::Sha1;
 use std::time::{Duration, SystemTime};
 
@@ -8,40 +8,73 @@ type HmacSha1 = Hmac<Sha1>;
 
 /// # `GET /_matrix/client/r0/voip/turnServer`
 ///
-/// TODO: Returns information about the recommended turn server.
+/// Returns the TURN server and credentials for VoIP calls.
+///
+/// - With a shared secret, the credentials are generated for the user and expire after the TTL
+/// - Otherwise the static username and password are returned
 pub async fn turn_server_route(
     body: Ruma<get_turn_server_info::v3::Request>,
 ) -> Result<get_turn_server_info::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    let turn_secret = services().globals.turn_secret().clone();
+    let turn = services().globals.turn();
 
-    let (username, password) = if !turn_secret.is_empty() {
+    let (username, password) = if !turn.shared_secret.is_empty() {
         let expiry = SecondsSinceUnixEpoch::from_system_time(
-            SystemTime::now() + Duration::from_secs(services().globals.turn_ttl()),
+            SystemTime::now() + Duration::from_secs(turn.ttl),
         )
         .expect("time is valid");
 
-        let username: String = format!("{}:{}", expiry.get(), sender_user);
-
-        let mut mac = HmacSha1::new_from_slice(turn_secret.as_bytes())
-            .expect("HMAC can take key of any size");
-        mac.update(username.as_bytes());
-
-        let password: String = base64::encode_config(mac.finalize().into_bytes(), base64::STANDARD);
-
-        (username, password)
+        turn_credentials(&turn.shared_secret, sender_user, expiry.get().into())
     } else {
-        (
-            services().globals.turn_username().clone(),
-            services().globals.turn_password().clone(),
-        )
+        (turn.username, turn.password)
     };
 
     Ok(get_turn_server_info::v3::Response {
         username,
         password,
-        uris: services().globals.turn_uris().to_vec(),
-        ttl: Duration::from_secs(services().globals.turn_ttl()),
+        uris: turn.uris,
+        ttl: Duration::from_secs(turn.ttl),
     })
 }
+
+/// Time-limited credentials for coturn's `use-auth-secret` mode: the username is the expiry time
+/// and the user id, the password is the base64 encoded HMAC-SHA1 of the username.
+fn turn_credentials(shared_secret: &str, user_id: &UserId, expiry: u64) -> (String, String) {
+    let username = format!("{}:{}", expiry, user_id);
+
+    let mut mac =
+        HmacSha1::new_from_slice(shared_secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(username.as_bytes());
+
+    let password = base64::encode_config(mac.finalize().into_bytes(), base64::STANDARD);
+
+    (username, password)
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::user_id;
+
+    use super::*;
+
+    #[test]
+    fn generated_credentials_verify_against_the_secret() {
+        let (username, password) =
+            turn_credentials("s3cret", user_id!("@alice:example.org"), 1_700_000_000);
+
+        assert_eq!(username, "1700000000:@alice:example.org");
+
+        // What coturn does with the credentials
+        let mut mac = HmacSha1::new_from_slice(b"s3cret").unwrap();
+        mac.update(username.as_bytes());
+        mac.verify_slice(&base64::decode(&password).unwrap())
+            .unwrap();
+
+        let mut mac = HmacSha1::new_from_slice(b"other secret").unwrap();
+        mac.update(username.as_bytes());
+        assert!(mac
+            .verify_slice(&base64::decode(&password).unwrap())
+            .is_err());
+    }
+}
@@ -0,0 +1,701 @@
+use std::collections::BTreeMap;
+
+use reqwest::Method;
+use ring::{digest, signature};
+use ruma::{
+    api::client::error::ErrorKind, thirdparty::Medium, CanonicalJsonObject, CanonicalJsonValue,
+    ClientSecret, MilliSecondsSinceUnixEpoch, OwnedSessionId, OwnedUserId, SessionId, UserId,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{services, Error, Result};
+
+#[derive(Deserialize)]
+struct HashDetails {
+    algorithms: Vec<String>,
+    lookup_pepper: String,
+}
+
+#[derive(Deserialize)]
+struct LookupResponse {
+    #[serde(default)]
+    mappings: BTreeMap<String, OwnedUserId>,
+}
+
+/// What the identity server includes in the email or SMS to the invited person.
+#[derive(Serialize)]
+pub(crate) struct StoreInviteRequest<'a> {
+    pub medium: &'a str,
+    pub address: &'a str,
+    pub room_id: &'a str,
+    pub sender: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender_display_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct InvitePublicKey {
+    pub public_key: String,
+    pub key_validity_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ValidatedThreepid {
+    pub medium: Medium,
+    pub address: String,
+    pub validated_at: MilliSecondsSinceUnixEpoch,
+}
+
+#[derive(Deserialize)]
+struct PublicKeyResponse {
+    public_key: String,
+}
+
+#[derive(Deserialize)]
+struct MsisdnTokenResponse {
+    sid: OwnedSessionId,
+}
+
+#[derive(Deserialize)]
+struct SubmitTokenResponse {
+    success: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct StoreInviteResponse {
+    pub token: String,
+    pub public_keys: Vec<InvitePublicKey>,
+    pub display_name: String,
+}
+
+/// Returns the Matrix user the third party id is bound to, if any.
+#[tracing::instrument(skip(id_access_token))]
+pub(crate) async fn lookup(
+    id_server: &str,
+    id_access_token: &str,
+    medium: &Medium,
+    address: &str,
+) -> Result<Option<OwnedUserId>> {
+    let details: HashDetails = send_request(
+        Method::GET,
+        id_server,
+        "/_matrix/identity/v2/hash_details",
+        Some(bearer(id_access_token)),
+        None,
+    )
+    .await?;
+
+    if !details.algorithms.iter().any(|a| a == "sha256") {
+        return Err(Error::BadServerResponse(
+            "Identity server doesn't support sha256 lookups.",
+        ));
+    }
+
+    let hash = lookup_hash(address, medium.as_str(), &details.lookup_pepper);
+    let response: LookupResponse = send_request(
+        Method::POST,
+        id_server,
+        "/_matrix/identity/v2/lookup",
+        Some(bearer(id_access_token)),
+        Some(serde_json::json!({
+            "addresses": [&hash],
+            "algorithm": "sha256",
+            "pepper": details.lookup_pepper,
+        })),
+    )
+    .await?;
+
+    Ok(response.mappings.get(&hash).cloned())
+}
+
+/// Asks the identity server to invite the third party id and returns the token and keys for the
+/// m.room.third_party_invite event.
+#[tracing::instrument(skip(id_access_token, request))]
+pub(crate) async fn store_invite(
+    id_server: &str,
+    id_access_token: &str,
+    request: &StoreInviteRequest<'_>,
+) -> Result<StoreInviteResponse> {
+    send_request(
+        Method::POST,
+        id_server,
+        "/_matrix/identity/v2/store-invite",
+        Some(bearer(id_access_token)),
+        Some(serde_json::to_value(request).expect("request can be serialized")),
+    )
+    .await
+}
+
+/// Returns the third party id the client validated in the given session.
+#[tracing::instrument(skip(id_access_token, client_secret))]
+pub(crate) async fn validated_threepid(
+    id_server: &str,
+    id_access_token: &str,
+    sid: &SessionId,
+    client_secret: &ClientSecret,
+) -> Result<ValidatedThreepid> {
+    validated_threepid_at(
+        services().globals.default_client(),
+        &format!("https://{id_server}"),
+        id_access_token,
+        sid,
+        client_secret,
+    )
+    .await
+}
+
+async fn validated_threepid_at(
+    client: &reqwest::Client,
+    base_url: &str,
+    id_access_token: &str,
+    sid: &SessionId,
+    client_secret: &ClientSecret,
+) -> Result<ValidatedThreepid> {
+    send_request_to(
+        client,
+        Method::GET,
+        base_url,
+        &format!(
+            "/_matrix/identity/v2/3pid/getValidated3pid?sid={sid}&client_secret={client_secret}"
+        ),
+        Some(bearer(id_access_token)),
+        None,
+    )
+    .await
+}
+
+/// Asks the identity server to text a validation token to the phone number and returns the
+/// session id. `id_access_token` is the one of our account at the identity server.
+#[tracing::instrument(skip(id_access_token, client_secret))]
+pub(crate) async fn request_msisdn_token(
+    id_server: &str,
+    id_access_token: &str,
+    client_secret: &ClientSecret,
+    country: &str,
+    phone_number: &str,
+    send_attempt: u64,
+) -> Result<OwnedSessionId> {
+    let response: MsisdnTokenResponse = send_request(
+        Method::POST,
+        id_server,
+        "/_matrix/identity/v2/validate/msisdn/requestToken",
+        Some(bearer(id_access_token)),
+        Some(serde_json::json!({
+            "client_secret": client_secret,
+            "country": country,
+            "phone_number": phone_number,
+            "send_attempt": send_attempt,
+        })),
+    )
+    .await?;
+
+    Ok(response.sid)
+}
+
+/// Passes the token the user received by SMS on to the identity server. If it was the right one,
+/// returns the validated phone number.
+#[tracing::instrument(skip(id_access_token, client_secret, token))]
+pub(crate) async fn submit_msisdn_token(
+    id_server: &str,
+    id_access_token: &str,
+    sid: &SessionId,
+    client_secret: &ClientSecret,
+    token: &str,
+) -> Result<Option<ValidatedThreepid>> {
+    submit_msisdn_token_at(
+        services().globals.default_client(),
+        &format!("https://{id_server}"),
+        id_access_token,
+        sid,
+        client_secret,
+        token,
+    )
+    .await
+}
+
+async fn submit_msisdn_token_at(
+    client: &reqwest::Client,
+    base_url: &str,
+    id_access_token: &str,
+    sid: &SessionId,
+    client_secret: &ClientSecret,
+    token: &str,
+) -> Result<Option<ValidatedThreepid>> {
+    let response: SubmitTokenResponse = send_request_to(
+        client,
+        Method::POST,
+        base_url,
+        "/_matrix/identity/v2/validate/msisdn/submitToken",
+        Some(bearer(id_access_token)),
+        Some(serde_json::json!({
+            "sid": sid,
+            "client_secret": client_secret,
+            "token": token,
+        })),
+    )
+    .await?;
+
+    if !response.success {
+        return Ok(None);
+    }
+
+    let threepid =
+        validated_threepid_at(client, base_url, id_access_token, sid, client_secret).await?;
+    if threepid.medium != Medium::Msisdn {
+        return Err(Error::BadServerResponse(
+            "Identity server validated something else than a phone number.",
+        ));
+    }
+
+    Ok(Some(threepid))
+}
+
+/// Makes the identity server publish the validated third party id as belonging to `mxid`.
+#[tracing::instrument(skip(id_access_token, client_secret))]
+pub(crate) async fn bind(
+    id_server: &str,
+    id_access_token: &str,
+    sid: &SessionId,
+    client_secret: &ClientSecret,
+    mxid: &UserId,
+) -> Result<()> {
+    let _: serde_json::Value = send_request(
+        Method::POST,
+        id_server,
+        "/_matrix/identity/v2/3pid/bind",
+        Some(bearer(id_access_token)),
+        Some(serde_json::json!({
+            "sid": sid,
+            "client_secret": client_secret,
+            "mxid": mxid,
+        })),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Removes the binding of a third party id to `mxid`. The request is signed with the server key,
+/// because the identity server only accepts unbinds from the user's homeserver.
+#[tracing::instrument]
+pub(crate) async fn unbind(
+    id_server: &str,
+    mxid: &UserId,
+    medium: &Medium,
+    address: &str,
+) -> Result<()> {
+    let path = "/_matrix/identity/v2/3pid/unbind";
+    let body = serde_json::json!({
+        "mxid": mxid,
+        "threepid": { "medium": medium, "address": address },
+    });
+
+    let mut request_json: CanonicalJsonObject = serde_json::from_value(serde_json::json!({
+        "method": "POST",
+        "uri": path,
+        "origin": services().globals.server_name(),
+        "destination": id_server,
+        "content": &body,
+    }))
+    .expect("valid JSON is valid canonical JSON");
+
+    ruma::signatures::sign_json(
+        services().globals.server_name().as_str(),
+        &*services().globals.keypair(),
+        &mut request_json,
+    )
+    .expect("our request json is what ruma expects");
+
+    let authorization = match request_json
+        .get("signatures")
+        .and_then(|signatures| match signatures {
+            CanonicalJsonValue::Object(signatures) => {
+                signatures.get(services().globals.server_name().as_str())
+            }
+            _ => None,
+        }) {
+        Some(CanonicalJsonValue::Object(keys)) => keys.iter().find_map(|(key, sig)| match sig {
+            CanonicalJsonValue::String(sig) => Some(format!(
+                "X-Matrix origin={},key=\"{}\",sig=\"{}\"",
+                services().globals.server_name(),
+                key,
+                sig
+            )),
+            _ => None,
+        }),
+        _ => None,
+    }
+    .expect("we just signed the request");
+
+    let _: serde_json::Value = send_request(
+        Method::POST,
+        id_server,
+        path,
+        Some(authorization),
+        Some(body),
+    )
+    .await?;
+
+    Ok(())
+}
+
+fn bearer(id_access_token: &str) -> String {
+    format!("Bearer {id_access_token}")
+}
+
+async fn send_request<T: DeserializeOwned>(
+    method: Method,
+    id_server: &str,
+    path: &str,
+    authorization: Option<String>,
+    body: Option<serde_json::Value>,
+) -> Result<T> {
+    send_request_to(
+        services().globals.default_client(),
+        method,
+        &format!("https://{id_server}"),
+        path,
+        authorization,
+        body,
+    )
+    .await
+}
+
+async fn send_request_to<T: DeserializeOwned>(
+    client: &reqwest::Client,
+    method: Method,
+    base_url: &str,
+    path: &str,
+    authorization: Option<String>,
+    body: Option<serde_json::Value>,
+) -> Result<T> {
+    let mut request = client.request(method, format!("{base_url}{path}"));
+    if let Some(authorization) = authorization {
+        request = request.header(http::header::AUTHORIZATION, authorization);
+    }
+    if let Some(body) = body {
+        request = request
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&body).expect("JSON values can be serialized"));
+    }
+
+    let response = request.send().await.map_err(|e| {
+        warn!("Could not reach identity server {}: {}", base_url, e);
+        Error::BadServerResponse("Could not reach the identity server.")
+    })?;
+
+    if !response.status().is_success() {
+        warn!(
+            "Identity server {} returned {} for {}",
+            base_url,
+            response.status(),
+            path
+        );
+        return Err(Error::BadServerResponse(
+            "Identity server returned an error.",
+        ));
+    }
+
+    let body = response
+        .bytes()
+        .await
+        .map_err(|_| Error::BadServerResponse("Could not read identity server response."))?;
+
+    serde_json::from_slice(&body)
+        .map_err(|_| Error::BadServerResponse("Identity server returned an invalid response."))
+}
+
+/// The hashed form of a third party id that v2 lookups use.
+fn lookup_hash(address: &str, medium: &str, pepper: &str) -> String {
+    let hash = digest::digest(
+        &digest::SHA256,
+        format!("{address} {medium} {pepper}").as_bytes(),
+    );
+    base64::encode_config(hash, base64::URL_SAFE_NO_PAD)
+}
+
+/// Checks that the `signed` block of a third party invite was signed by a trusted identity server,
+/// with a key the server still publishes.
+#[tracing::instrument(skip(signed))]
+pub(crate) async fn verify_invite_signature(signed: &CanonicalJsonObject) -> Result<()> {
+    for (id_server, key_id) in signing_keys(signed, |id_server| {
+        services().globals.identity_server_trusted(id_server)
+    }) {
+        let response: Result<PublicKeyResponse> = send_request(
+            Method::GET,
+            &id_server,
+            &format!("/_matrix/identity/v2/pubkey/{key_id}"),
+            None,
+            None,
+        )
+        .await;
+
+        match response {
+            Ok(response) if signed_by_any(signed, &[response.public_key]) => return Ok(()),
+            Ok(_) => warn!(
+                "Invite signature of {} with {} is invalid",
+                id_server, key_id
+            ),
+            Err(e) => warn!("Could not get key {} of {}: {}", key_id, id_server, e),
+        }
+    }
+
+    Err(Error::BadRequest(
+        ErrorKind::Forbidden,
+        "The invite is not signed by a trusted identity server.",
+    ))
+}
+
+/// The identity servers and key ids of the signatures of a `signed` block, only of the identity
+/// servers that are trusted.
+fn signing_keys(
+    signed: &CanonicalJsonObject,
+    trusted: impl Fn(&str) -> bool,
+) -> Vec<(String, String)> {
+    match signed.get("signatures") {
+        Some(CanonicalJsonValue::Object(signatures)) => signatures
+            .iter()
+            .filter(|(id_server, _)| trusted(id_server))
+            .filter_map(|(id_server, keys)| match keys {
+                CanonicalJsonValue::Object(keys) => Some(
+                    keys.keys()
+                        .map(move |key_id| (id_server.clone(), key_id.clone())),
+                ),
+                _ => None,
+            })
+            .flatten()
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Whether the `signed` block of a third party invite carries a valid ed25519 signature by one of
+/// the public keys of the m.room.third_party_invite event.
+pub(crate) fn signed_by_any(signed: &CanonicalJsonObject, public_keys: &[String]) -> bool {
+    let signatures = match signed.get("signatures") {
+        Some(CanonicalJsonValue::Object(signatures)) => signatures,
+        _ => return false,
+    };
+
+    let mut unsigned = signed.clone();
+    unsigned.remove("signatures");
+    let message = serde_json::to_string(&unsigned).expect("canonical JSON can be serialized");
+
+    let decode = |s: &str| {
+        base64::decode_config(s, base64::STANDARD_NO_PAD)
+            .or_else(|_| base64::decode_config(s, base64::URL_SAFE_NO_PAD))
+            .ok()
+    };
+    let public_keys: Vec<_> = public_keys.iter().filter_map(|key| decode(key)).collect();
+
+    signatures
+        .values()
+        .filter_map(|keys| match keys {
+            CanonicalJsonValue::Object(keys) => Some(keys.values()),
+            _ => None,
+        })
+        .flatten()
+        .filter_map(|signature| match signature {
+            CanonicalJsonValue::String(signature) => decode(signature),
+            _ => None,
+        })
+        .any(|signature| {
+            public_keys.iter().any(|public_key| {
+                signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+                    .verify(message.as_bytes(), &signature)
+                    .is_ok()
+            })
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use ring::{rand::SystemRandom, signature::KeyPair};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    #[test]
+    fn lookup_hash_matches_the_spec_example() {
+        assert_eq!(
+            lookup_hash("alice@example.com", "email", "matrixrocks"),
+            "4kenr7N9drpCJ4AfalmlGQVsOn3o2RHjkADUpXJWZUc"
+        );
+    }
+
+    #[test]
+    fn invites_signed_by_the_identity_server_are_accepted() {
+        let pkcs8 = signature::Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public_key =
+            base64::encode_config(key_pair.public_key().as_ref(), base64::STANDARD_NO_PAD);
+
+        let message = r#"{"mxid":"@alice:example.org","token":"abc"}"#;
+        let signature = base64::encode_config(
+            key_pair.sign(message.as_bytes()).as_ref(),
+            base64::STANDARD_NO_PAD,
+        );
+        let signed = |mxid: &str| -> CanonicalJsonObject {
+            serde_json::from_value(serde_json::json!({
+                "mxid": mxid,
+                "token": "abc",
+                "signatures": { "id.example.org": { "ed25519:0": &signature } },
+            }))
+            .unwrap()
+        };
+
+        assert!(signed_by_any(
+            &signed("@alice:example.org"),
+            &[public_key.clone()]
+        ));
+        // Someone else's invite
+        assert!(!signed_by_any(&signed("@bob:example.org"), &[public_key]));
+        // Not signed by the keys of the m.room.third_party_invite event
+        assert!(!signed_by_any(
+            &signed("@alice:example.org"),
+            &["AAAA".to_owned()]
+        ));
+    }
+
+    #[test]
+    fn only_keys_of_trusted_identity_servers_are_used() {
+        let signed: CanonicalJsonObject = serde_json::from_value(serde_json::json!({
+            "mxid": "@alice:example.org",
+            "token": "abc",
+            "signatures": {
+                "id.example.org": { "ed25519:0": "sig", "ed25519:1": "sig" },
+                "evil.example.org": { "ed25519:0": "sig" },
+            },
+        }))
+        .unwrap();
+
+        assert_eq!(
+            signing_keys(&signed, |id_server| id_server == "id.example.org"),
+            [
+                ("id.example.org".to_owned(), "ed25519:0".to_owned()),
+                ("id.example.org".to_owned(), "ed25519:1".to_owned()),
+            ]
+        );
+        assert!(signing_keys(&signed, |_| false).is_empty());
+
+        let unsigned: CanonicalJsonObject =
+            serde_json::from_value(serde_json::json!({ "mxid": "@alice:example.org" })).unwrap();
+        assert!(signing_keys(&unsigned, |_| true).is_empty());
+    }
+
+    /// Answers msisdn submitToken and getValidated3pid requests like an identity server that sent
+    /// the token `123456`, and returns the paths it was asked for. Every request has to carry the
+    /// access token `delegate_token`.
+    async fn mock_identity_server(listener: TcpListener, requests: usize) -> Vec<String> {
+        let mut paths = Vec::new();
+        for _ in 0..requests {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(stream);
+
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).await.unwrap();
+            let path = request_line.split(' ').nth(1).unwrap().to_owned();
+
+            let mut content_length = 0;
+            let mut authorization = None;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).await.unwrap();
+                if header == "\r\n" {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                    if name.eq_ignore_ascii_case("authorization") {
+                        authorization = Some(value.trim().to_owned());
+                    }
+                }
+            }
+            assert_eq!(authorization.as_deref(), Some("Bearer delegate_token"));
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).await.unwrap();
+
+            let response =
+                if path.starts_with("/_matrix/identity/v2/validate/msisdn/submitToken") {
+                    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                    serde_json::json!({ "success": body["token"] == "123456" })
+                } else {
+                    serde_json::json!({
+                        "medium": "msisdn",
+                        "address": "447700900000",
+                        "validated_at": 1_600_000_000_000_u64,
+                    })
+                }
+                .to_string();
+
+            reader
+                .get_mut()
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        response.len(),
+                        response
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+            paths.push(path);
+        }
+        paths
+    }
+
+    #[tokio::test]
+    async fn msisdn_tokens_are_submitted_to_the_identity_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(mock_identity_server(listener, 3));
+
+        let client = reqwest::Client::new();
+        let sid: &SessionId = "sid".try_into().unwrap();
+        let client_secret: &ClientSecret = "secret".try_into().unwrap();
+
+        assert!(submit_msisdn_token_at(
+            &client,
+            &base_url,
+            "delegate_token",
+            sid,
+            client_secret,
+            "000000"
+        )
+        .await
+        .unwrap()
+        .is_none());
+
+        let threepid = submit_msisdn_token_at(
+            &client,
+            &base_url,
+            "delegate_token",
+            sid,
+            client_secret,
+            "123456",
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(threepid.medium, Medium::Msisdn);
+        assert_eq!(threepid.address, "447700900000");
+
+        assert_eq!(
+            server.await.unwrap(),
+            [
+                "/_matrix/identity/v2/validate/msisdn/submitToken",
+                "/_matrix/identity/v2/validate/msisdn/submitToken",
+                "/_matrix/identity/v2/3pid/getValidated3pid?sid=sid&client_secret=secret",
+            ]
+        );
+    }
+}
@@ -1,4 +1,8 @@
+#[cfg(feature = "conduit_bin")]
+pub mod admin_server;
 pub mod appservice_server;
 pub mod client_server;
+pub(crate) mod identity_server;
 pub mod ruma_wrapper;
 pub mod server_server;
+pub mod well_known;
@@ -14,15 +14,15 @@ use axum::{
     BoxError,
 };
 use bytes::{BufMut, Bytes, BytesMut};
-use http::StatusCode;
+use http::{Method, StatusCode};
 use ruma::{
     api::{client::error::ErrorKind, AuthScheme, IncomingRequest, OutgoingResponse},
-    CanonicalJsonValue, OwnedDeviceId, OwnedServerName, UserId,
+    CanonicalJsonValue, OwnedDeviceId, OwnedServerName, OwnedUserId, UserId,
 };
 use serde::Deserialize;
 use tracing::{debug, error, warn};
 
-use super::{Ruma, RumaResponse};
+use super::{ClientIp, Ruma, RumaResponse, SenderDevice, SenderUser};
 use crate::{services, Error, Result};
 
 #[async_trait]
@@ -36,28 +36,11 @@ where
     type Rejection = Error;
 
     async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
-        #[derive(Deserialize)]
-        struct QueryParams {
-            access_token: Option<String>,
-            user_id: Option<String>,
-        }
-
         let metadata = T::METADATA;
         let auth_header = Option::<TypedHeader<Authorization<Bearer>>>::from_request(req).await?;
         let path_params = Path::<Vec<String>>::from_request(req).await?;
 
-        let query = req.uri().query().unwrap_or_default();
-        let query_params: QueryParams = match ruma::serde::urlencoded::from_str(query) {
-            Ok(params) => params,
-            Err(e) => {
-                error!(%query, "Failed to deserialize query parameters: {}", e);
-                return Err(Error::BadRequest(
-                    ErrorKind::Unknown,
-                    "Failed to read query parameters",
-                ));
-            }
-        };
-
+        let query_params = query_params(req)?;
         let token = match &auth_header {
             Some(TypedHeader(Authorization(bearer))) => Some(bearer.token()),
             None => query_params.access_token.as_deref(),
@@ -69,74 +52,38 @@ where
 
         let mut json_body = serde_json::from_slice::<CanonicalJsonValue>(&body).ok();
 
-        let appservices = services().appservice.all().unwrap();
-        let appservice_registration = appservices.iter().find(|(_id, registration)| {
-            registration
-                .get("as_token")
-                .and_then(|as_token| as_token.as_str())
-                .map_or(false, |as_token| token == Some(as_token))
-        });
+        let appservice_registration = token.map(appservice_for_token).transpose()?.flatten();
 
         let (sender_user, sender_device, sender_servername, from_appservice) =
-            if let Some((_id, registration)) = appservice_registration {
+            if let Some(registration) = appservice_registration {
                 match metadata.authentication {
-                    AuthScheme::AccessToken => {
-                        let user_id = query_params.user_id.map_or_else(
-                            || {
-                                UserId::parse_with_server_name(
-                                    registration
-                                        .get("sender_localpart")
-                                        .unwrap()
-                                        .as_str()
-                                        .unwrap(),
-                                    services().globals.server_name(),
-                                )
-                                .unwrap()
-                            },
-                            |s| UserId::parse(s).unwrap(),
-                        );
-
-                        if !services().users.exists(&user_id).unwrap() {
-                            return Err(Error::BadRequest(
-                                ErrorKind::Forbidden,
-                                "User does not exist.",
-                            ));
-                        }
-
-                        // TODO: Check if appservice is allowed to be that user
-                        (Some(user_id), None, None, true)
-                    }
+                    AuthScheme::AccessToken => (
+                        Some(appservice_user(&registration, query_params.user_id)?),
+                        None,
+                        None,
+                        true,
+                    ),
                     AuthScheme::ServerSignatures => (None, None, None, true),
                     AuthScheme::None => (None, None, None, true),
                 }
             } else {
                 match metadata.authentication {
-                    AuthScheme::AccessToken => {
-                        let token = match token {
-                            Some(token) => token,
-                            _ => {
-                                return Err(Error::BadRequest(
-                                    ErrorKind::MissingToken,
-                                    "Missing access token.",
-                                ))
-                            }
-                        };
-
-                        match services().users.find_from_token(token).unwrap() {
-                            None => {
-                                return Err(Error::BadRequest(
-                                    ErrorKind::UnknownToken { soft_logout: false },
-                                    "Unknown access token.",
-                                ))
-                            }
-                            Some((user_id, device_id)) => (
-                                Some(user_id),
-                                Some(OwnedDeviceId::from(device_id)),
-                                None,
-                                false,
-                            ),
+                    AuthScheme::AccessToken => match token {
+                        Some(token) => {
+                            let (user_id, device_id) = user_for_token(token)?;
+                            (Some(user_id), Some(device_id), None, false)
                         }
-                    }
+                        // The handlers only allow this for world readable rooms
+                        None if allows_peeking(req.method(), req.uri().path()) => {
+                            (None, None, None, false)
+                        }
+                        None => {
+                            return Err(Error::BadRequest(
+                                ErrorKind::MissingToken,
+                                "Missing access token.",
+                            ))
+                        }
+                    },
                     AuthScheme::ServerSignatures => {
                         let TypedHeader(Authorization(x_matrix)) =
                             TypedHeader::<Authorization<XMatrix>>::from_request(req)
@@ -180,12 +127,6 @@ where
                                 "origin".to_owned(),
                                 CanonicalJsonValue::String(x_matrix.origin.as_str().to_owned()),
                             ),
-                            (
-                                "destination".to_owned(),
-                                CanonicalJsonValue::String(
-                                    services().globals.server_name().as_str().to_owned(),
-                                ),
-                            ),
                             (
                                 "signatures".to_owned(),
                                 CanonicalJsonValue::Object(signatures),
@@ -196,6 +137,23 @@ where
                             request_map.insert("content".to_owned(), json_body.clone());
                         };
 
+                        // Requests can be addressed to server_name or any of its aliases. Older
+                        // servers don't send the destination, so try all of them.
+                        let destinations: Vec<OwnedServerName> = match &x_matrix.destination {
+                            Some(destination) if services().globals.server_is_ours(destination) => {
+                                vec![destination.clone()]
+                            }
+                            Some(_) => {
+                                return Err(Error::BadRequest(
+                                    ErrorKind::Forbidden,
+                                    "Request is not addressed to this server.",
+                                ))
+                            }
+                            None => std::iter::once(services().globals.server_name().to_owned())
+                                .chain(services().globals.server_name_aliases().iter().cloned())
+                                .collect(),
+                        };
+
                         let keys_result = services()
                             .rooms
                             .event_handler
@@ -216,7 +174,21 @@ where
                         let pub_key_map =
                             BTreeMap::from_iter([(x_matrix.origin.as_str().to_owned(), keys)]);
 
-                        match ruma::signatures::verify_json(&pub_key_map, &request_map) {
+                        let mut verified = None;
+                        for destination in destinations {
+                            request_map.insert(
+                                "destination".to_owned(),
+                                CanonicalJsonValue::String(destination.as_str().to_owned()),
+                            );
+                            let result = ruma::signatures::verify_json(&pub_key_map, &request_map);
+                            let done = result.is_ok();
+                            verified = Some(result);
+                            if done {
+                                break;
+                            }
+                        }
+
+                        match verified.expect("server_name is always a destination") {
                             Ok(()) => (None, None, Some(x_matrix.origin), false),
                             Err(e) => {
                                 warn!(
@@ -239,10 +211,42 @@ where
                             }
                         }
                     }
-                    AuthScheme::None => (None, None, None, false),
+                    // Endpoints like the public room list still need to know if the user is logged in
+                    AuthScheme::None => match token
+                        .map(|token| services().users.find_from_token(token))
+                        .transpose()?
+                        .flatten()
+                        .filter(|(user_id, device_id)| {
+                            !services()
+                                .users
+                                .token_expired(user_id, device_id.as_str().into())
+                                .unwrap_or(true)
+                        }) {
+                        Some((user_id, device_id)) => (
+                            Some(user_id),
+                            Some(OwnedDeviceId::from(device_id)),
+                            None,
+                            false,
+                        ),
+                        None => (None, None, None, false),
+                    },
                 }
             };
 
+        let client_ip = req.extensions().get::<ClientIp>().map(|ClientIp(ip)| *ip);
+
+        if let (Some(user_id), Some(device_id)) = (&sender_user, &sender_device) {
+            if let Err(e) = services()
+                .users
+                .update_device_last_seen(user_id, device_id, client_ip)
+            {
+                warn!(
+                    "Failed to update last seen of {} {}: {}",
+                    user_id, device_id, e
+                );
+            }
+        }
+
         let mut http_request = http::Request::builder().uri(req.uri()).method(req.method());
         *http_request.headers_mut().unwrap() = req.headers().clone();
 
@@ -292,12 +296,162 @@ where
             sender_servername,
             from_appservice,
             json_body,
+            client_ip,
         })
     }
 }
 
+/// Endpoints that can be used without an access token to peek into world readable rooms.
+fn allows_peeking(method: &Method, path: &str) -> bool {
+    if method != Method::GET {
+        return false;
+    }
+
+    let mut segments = path.split('/').skip_while(|segment| *segment != "rooms");
+    matches!(
+        (segments.nth(1), segments.next()),
+        (Some(_), Some("state" | "messages" | "initialSync"))
+    )
+}
+
+#[derive(Deserialize)]
+struct QueryParams {
+    access_token: Option<String>,
+    user_id: Option<String>,
+}
+
+fn query_params<B>(req: &RequestParts<B>) -> Result<QueryParams> {
+    let query = req.uri().query().unwrap_or_default();
+    ruma::serde::urlencoded::from_str(query).map_err(|e| {
+        error!(%query, "Failed to deserialize query parameters: {}", e);
+        Error::BadRequest(ErrorKind::Unknown, "Failed to read query parameters")
+    })
+}
+
+/// The access token of a request to an endpoint that requires one, from the Authorization header
+/// or the `access_token` query parameter.
+async fn required_access_token<B: Send>(
+    req: &mut RequestParts<B>,
+) -> Result<(String, QueryParams)> {
+    let auth_header = Option::<TypedHeader<Authorization<Bearer>>>::from_request(req).await?;
+    let mut query_params = query_params(req)?;
+
+    let token = match auth_header {
+        Some(TypedHeader(Authorization(bearer))) => bearer.token().to_owned(),
+        None => query_params.access_token.take().ok_or(Error::BadRequest(
+            ErrorKind::MissingToken,
+            "Missing access token.",
+        ))?,
+    };
+
+    Ok((token, query_params))
+}
+
+/// The registration of the appservice the token belongs to, if it is an appservice token.
+fn appservice_for_token(token: &str) -> Result<Option<serde_yaml::Value>> {
+    Ok(services()
+        .appservice
+        .all()?
+        .into_iter()
+        .map(|(_id, registration)| registration)
+        .find(|registration| {
+            registration
+                .get("as_token")
+                .and_then(|as_token| as_token.as_str())
+                .map_or(false, |as_token| as_token == token)
+        }))
+}
+
+/// The user an appservice acts as: the one in the `user_id` query parameter, or its sender.
+fn appservice_user(
+    registration: &serde_yaml::Value,
+    user_id: Option<String>,
+) -> Result<OwnedUserId> {
+    let user_id = match user_id {
+        Some(user_id) => UserId::parse(user_id)
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid user_id."))?,
+        None => UserId::parse_with_server_name(
+            registration
+                .get("sender_localpart")
+                .and_then(|localpart| localpart.as_str())
+                .ok_or_else(|| Error::bad_database("Appservice has no sender_localpart."))?,
+            services().globals.server_name(),
+        )
+        .map_err(|_| Error::bad_database("Appservice has an invalid sender_localpart."))?,
+    };
+
+    if !services().users.exists(&user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "User does not exist.",
+        ));
+    }
+
+    // TODO: Check if appservice is allowed to be that user
+    Ok(user_id)
+}
+
+/// The user and device an access token belongs to.
+fn user_for_token(token: &str) -> Result<(OwnedUserId, OwnedDeviceId)> {
+    match services().users.find_from_token(token)? {
+        None => Err(Error::BadRequest(
+            ErrorKind::UnknownToken { soft_logout: false },
+            "Unknown access token.",
+        )),
+        Some((user_id, device_id)) => {
+            if services()
+                .users
+                .token_expired(&user_id, device_id.as_str().into())?
+            {
+                return Err(Error::BadRequest(
+                    ErrorKind::UnknownToken { soft_logout: true },
+                    "Access token has expired.",
+                ));
+            }
+
+            Ok((user_id, device_id.into()))
+        }
+    }
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for SenderUser {
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let (token, query_params) = required_access_token(req).await?;
+
+        match appservice_for_token(&token)? {
+            Some(registration) => Ok(SenderUser(appservice_user(
+                &registration,
+                query_params.user_id,
+            )?)),
+            None => user_for_token(&token).map(|(user_id, _)| SenderUser(user_id)),
+        }
+    }
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for SenderDevice {
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let (token, _) = required_access_token(req).await?;
+
+        if appservice_for_token(&token)?.is_some() {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Appservices have no device to use this endpoint with.",
+            ));
+        }
+
+        user_for_token(&token).map(|(user_id, device_id)| SenderDevice(user_id, device_id))
+    }
+}
+
 struct XMatrix {
     origin: OwnedServerName,
+    destination: Option<OwnedServerName>,
     key: String, // KeyName?
     sig: String,
 }
@@ -316,6 +470,7 @@ impl Credentials for XMatrix {
             .trim_start();
 
         let mut origin = None;
+        let mut destination = None;
         let mut key = None;
         let mut sig = None;
 
@@ -332,6 +487,7 @@ impl Credentials for XMatrix {
             // FIXME: Catch multiple fields of the same name
             match name {
                 "origin" => origin = Some(value.try_into().ok()?),
+                "destination" => destination = Some(value.try_into().ok()?),
                 "key" => key = Some(value.to_owned()),
                 "sig" => sig = Some(value.to_owned()),
                 _ => debug!(
@@ -343,6 +499,7 @@ impl Credentials for XMatrix {
 
         Some(Self {
             origin: origin?,
+            destination,
             key: key?,
             sig: sig?,
         })
@@ -361,3 +518,81 @@ impl<T: OutgoingResponse> IntoResponse for RumaResponse<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use ruma::{api::client::account::whoami, MilliSecondsSinceUnixEpoch, UInt};
+
+    use super::*;
+    use crate::utils::testing;
+
+    #[test]
+    fn only_room_reads_allow_peeking() {
+        assert!(allows_peeking(
+            &Method::GET,
+            "/_matrix/client/v3/rooms/!room:example.org/messages"
+        ));
+        assert!(allows_peeking(
+            &Method::GET,
+            "/_matrix/client/r0/rooms/!room:example.org/state/m.room.name/"
+        ));
+        assert!(!allows_peeking(
+            &Method::PUT,
+            "/_matrix/client/v3/rooms/!room:example.org/state/m.room.name/"
+        ));
+        assert!(!allows_peeking(
+            &Method::GET,
+            "/_matrix/client/v3/rooms/!room:example.org/members"
+        ));
+        assert!(!allows_peeking(&Method::GET, "/_matrix/client/v3/sync"));
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_soft_logged_out() {
+        let day = 24 * 60 * 60 * 1000;
+        let days_ago = |days: u64| {
+            MilliSecondsSinceUnixEpoch(UInt::new_saturating(
+                crate::utils::millis_since_unix_epoch() - days * day,
+            ))
+        };
+        let whoami = |localpart: &str| {
+            testing::extract::<whoami::v3::Request>(
+                "/_matrix/client/v3/account/whoami",
+                testing::client_request(
+                    http::Method::GET,
+                    "/_matrix/client/v3/account/whoami",
+                    &testing::access_token(localpart),
+                    serde_json::json!({}),
+                ),
+                None,
+            )
+        };
+        let soft_logged_out = |error: serde_json::Value| {
+            error["errcode"] == "M_UNKNOWN_TOKEN" && error["soft_logout"] == true
+        };
+
+        // Used every day, but issued longer ago than `access_token_lifetime`
+        let alice = testing::user("expiry_alice").await;
+        assert!(whoami("expiry_alice").await.is_ok());
+        services()
+            .users
+            .db
+            .set_token_created(&alice, &testing::device_id(), days_ago(31))
+            .unwrap();
+        assert!(soft_logged_out(whoami("expiry_alice").await.unwrap_err()));
+
+        // Issued recently, but unused for longer than `session_idle_expiry`
+        let bob = testing::user("expiry_bob").await;
+        services()
+            .users
+            .db
+            .set_token_created(&bob, &testing::device_id(), days_ago(10))
+            .unwrap();
+        services()
+            .users
+            .db
+            .update_device_last_seen(&bob, &testing::device_id(), None, days_ago(8))
+            .unwrap();
+        assert!(soft_logged_out(whoami("expiry_bob").await.unwrap_err()));
+    }
+}
@@ -3,7 +3,7 @@ use ruma::{
     api::client::uiaa::UiaaResponse, CanonicalJsonValue, OwnedDeviceId, OwnedServerName,
     OwnedUserId,
 };
-use std::ops::Deref;
+use std::{net::IpAddr, ops::Deref};
 
 #[cfg(feature = "conduit_bin")]
 mod axum;
@@ -17,6 +17,8 @@ pub struct Ruma<T> {
     // This is None when body is not a valid string
     pub json_body: Option<CanonicalJsonValue>,
     pub from_appservice: bool,
+    /// See `ClientIp`
+    pub client_ip: Option<IpAddr>,
 }
 
 impl<T> Deref for Ruma<T> {
@@ -27,9 +29,22 @@ impl<T> Deref for Ruma<T> {
     }
 }
 
+/// Extractor for the user sending a request to an endpoint Ruma has no types for yet. Accepts the
+/// same access tokens as `Ruma`, including appservice tokens.
+pub struct SenderUser(pub OwnedUserId);
+
+/// Like `SenderUser`, with the device the access token belongs to. Appservice tokens have no
+/// device, so they are rejected.
+pub struct SenderDevice(pub OwnedUserId, pub OwnedDeviceId);
+
 #[derive(Clone)]
 pub struct RumaResponse<T>(pub T);
 
+/// The IP of the client that sent the request, resolved through trusted proxies. Every request
+/// carries it as an extension.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
 impl<T> From<T> for RumaResponse<T> {
     fn from(t: T) -> Self {
         Self(t)
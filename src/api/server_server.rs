@@ -1,11 +1,23 @@
 use crate::{
-    api::client_server::{self, claim_keys_helper, get_keys_helper},
-    service::pdu::{gen_event_id_canonical_json, PduBuilder},
+    api::{
+        client_server::{self, claim_keys_helper, get_keys_helper, local_profile_json},
+        identity_server,
+    },
+    service::{
+        globals,
+        pdu::{check_pdu_limits, gen_event_id_canonical_json, PduBuilder},
+    },
     services, utils, Error, PduEvent, Result, Ruma,
 };
-use axum::{response::IntoResponse, Json};
+use axum::{
+    extract::{Query, TypedHeader},
+    headers::Host,
+    response::IntoResponse,
+    Json,
+};
+use bytes::Bytes;
 use get_profile_information::v1::ProfileField;
-use http::header::{HeaderValue, AUTHORIZATION};
+use http::header::{HeaderValue, AUTHORIZATION, CACHE_CONTROL, LOCATION};
 
 use ruma::{
     api::{
@@ -14,8 +26,11 @@ use ruma::{
             authorization::get_event_authorization,
             device::get_devices::{self, v1::UserDevice},
             directory::{get_public_rooms, get_public_rooms_filtered},
-            discovery::{get_server_keys, get_server_version, ServerSigningKeys, VerifyKey},
-            event::{get_event, get_missing_events, get_room_state, get_room_state_ids},
+            discovery::{get_server_keys, OldVerifyKey, ServerSigningKeys, VerifyKey},
+            event::{
+                get_event, get_event_by_timestamp, get_missing_events, get_room_state,
+                get_room_state_ids,
+            },
             keys::{claim_keys, get_keys},
             membership::{
                 create_invite,
@@ -23,6 +38,7 @@ use ruma::{
                 prepare_join_event,
             },
             query::{get_profile_information, get_room_information},
+            thirdparty::exchange_invite,
             transactions::{
                 edu::{DeviceListUpdateContent, DirectDeviceContent, Edu, SigningKeyUpdateContent},
                 send_transaction_message,
@@ -41,22 +57,69 @@ use ruma::{
         RoomEventType, StateEventType,
     },
     serde::{Base64, JsonObject, Raw},
+    signatures::Ed25519KeyPair,
     to_device::DeviceIdOrAllDevices,
     CanonicalJsonObject, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch, OwnedEventId,
-    OwnedRoomId, OwnedServerName, OwnedServerSigningKeyId, OwnedUserId, RoomId, ServerName,
+    OwnedRoomId, OwnedServerName, OwnedServerSigningKeyId, OwnedUserId, RoomId, ServerName, UInt,
+    UserId,
 };
+use serde::Deserialize;
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     fmt::Debug,
-    mem,
+    iter, mem,
     net::{IpAddr, SocketAddr},
     sync::{Arc, RwLock},
     time::{Duration, Instant, SystemTime},
 };
 
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit};
 use tracing::{debug, error, info, warn};
 
+/// Waits for a permit to send an outgoing request, but at most `max_wait`, so a storm of
+/// federation requests can't pile up without bounds.
+async fn acquire_bounded(semaphore: &Semaphore, max_wait: Duration) -> Result<SemaphorePermit<'_>> {
+    match tokio::time::timeout(max_wait, semaphore.acquire()).await {
+        Ok(permit) => Ok(permit.expect("semaphore is never closed")),
+        Err(_) => {
+            warn!("Timed out waiting for a free outgoing request slot");
+            Err(Error::BadServerResponse(
+                "Too many outgoing federation requests.",
+            ))
+        }
+    }
+}
+
+/// Spec limits for the contents of a single incoming transaction.
+const MAX_TRANSACTION_PDUS: usize = 50;
+const MAX_TRANSACTION_EDUS: usize = 100;
+
+/// Takes one of the `limit` slots for transactions from `origin`, or returns None if they are all
+/// in use. The slot is freed when the permit is dropped.
+///
+/// Only origins with transactions in flight are kept, so the map is bounded by the number of
+/// concurrent transactions instead of growing with every server that ever sent one.
+fn try_start_transaction(
+    transactions: &RwLock<HashMap<OwnedServerName, Arc<Semaphore>>>,
+    origin: &ServerName,
+    limit: u16,
+) -> Option<OwnedSemaphorePermit> {
+    let mut transactions = transactions.write().unwrap();
+
+    // Permits hold a reference to their semaphore, the map's is the only one of idle origins
+    transactions.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+
+    let semaphore = Arc::clone(
+        transactions
+            .entry(origin.to_owned())
+            .or_insert_with(|| Arc::new(Semaphore::new(limit.into()))),
+    );
+    drop(transactions);
+
+    semaphore.try_acquire_owned().ok()
+}
+
 /// Wraps either an literal IP address plus port, or a hostname plus complement
 /// (colon-plus-port if it was specified).
 ///
@@ -83,9 +146,11 @@ pub enum FedDest {
 
 impl FedDest {
     fn into_https_string(self) -> String {
+        // The mock servers of tests have no certificates
+        let scheme = if cfg!(test) { "http" } else { "https" };
         match self {
-            Self::Literal(addr) => format!("https://{addr}"),
-            Self::Named(host, port) => format!("https://{host}{port}"),
+            Self::Literal(addr) => format!("{scheme}://{addr}"),
+            Self::Named(host, port) => format!("{scheme}://{host}{port}"),
         }
     }
 
@@ -119,10 +184,70 @@ pub(crate) async fn send_request<T: OutgoingRequest>(
 where
     T: Debug,
 {
+    send_request_as(services().globals.server_name(), destination, request).await
+}
+
+/// Like `send_request`, but sent by `origin`, which is server_name or one of its aliases. Requests
+/// on behalf of users on an alias domain have to come from that domain.
+#[tracing::instrument(skip(request))]
+pub(crate) async fn send_request_as<T: OutgoingRequest>(
+    origin: &ServerName,
+    destination: &ServerName,
+    request: T,
+) -> Result<T::IncomingResponse>
+where
+    T: Debug,
+{
+    send_request_inner(origin, destination, request, None, |response| {
+        T::IncomingResponse::try_from_http_response(response).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+/// Like `send_request_as`, with `query` added to the query string, for parameters the request
+/// type doesn't know about. Returns the body of the response.
+#[tracing::instrument(skip(request))]
+pub(crate) async fn send_request_with_query_as<T: OutgoingRequest>(
+    origin: &ServerName,
+    destination: &ServerName,
+    request: T,
+    query: &str,
+) -> Result<Bytes>
+where
+    T: Debug,
+{
+    send_request_inner(origin, destination, request, Some(query), |response| {
+        Ok(response.into_body())
+    })
+    .await
+}
+
+async fn send_request_inner<T, R>(
+    origin: &ServerName,
+    destination: &ServerName,
+    request: T,
+    query: Option<&str>,
+    parse: impl FnOnce(http::Response<Bytes>) -> std::result::Result<R, String>,
+) -> Result<R>
+where
+    T: OutgoingRequest + Debug,
+{
+    if !services().globals.server_is_ours(origin) {
+        return Err(Error::BadServerResponse(
+            "Federation requests can only be sent as this server.",
+        ));
+    }
+
     if !services().globals.allow_federation() {
         return Err(Error::bad_config("Federation is disabled."));
     }
 
+    let _permit = acquire_bounded(
+        &services().sending.maximum_requests,
+        services().globals.federation_timeout(),
+    )
+    .await?;
+
     let mut write_destination_to_cache = false;
 
     let cached_result = services()
@@ -131,18 +256,24 @@ where
         .read()
         .unwrap()
         .get(destination)
-        .cloned();
+        .filter(|(_, _, valid_until)| Instant::now() < *valid_until)
+        .map(|(actual_destination, host, _)| (actual_destination.clone(), host.clone()));
+
+    let mut cache_ttl = Duration::ZERO;
 
     let (actual_destination, host) = if let Some(result) = cached_result {
         result
     } else {
         write_destination_to_cache = true;
 
-        let result = find_actual_destination(destination).await;
+        let (actual_destination, host, ttl) = find_actual_destination(destination).await;
+        cache_ttl = ttl;
 
-        (result.0, result.1.into_uri_string())
+        (actual_destination, host.into_uri_string())
     };
 
+    check_destination_ips(&actual_destination).await?;
+
     let actual_destination_str = actual_destination.clone().into_https_string();
 
     let mut http_request = request
@@ -159,70 +290,23 @@ where
             Error::BadServerResponse("Invalid destination")
         })?;
 
-    let mut request_map = serde_json::Map::new();
-
-    if !http_request.body().is_empty() {
-        request_map.insert(
-            "content".to_owned(),
-            serde_json::from_slice(http_request.body())
-                .expect("body is valid json, we just created it"),
-        );
-    };
+    if let Some(query) = query {
+        let separator = if http_request.uri().query().is_some() {
+            '&'
+        } else {
+            '?'
+        };
+        *http_request.uri_mut() = format!("{}{}{}", http_request.uri(), separator, query)
+            .parse()
+            .expect("query parameters are valid in a uri");
+    }
 
-    request_map.insert("method".to_owned(), T::METADATA.method.to_string().into());
-    request_map.insert(
-        "uri".to_owned(),
-        http_request
-            .uri()
-            .path_and_query()
-            .expect("all requests have a path")
-            .to_string()
-            .into(),
+    sign_request(
+        &mut http_request,
+        origin,
+        destination,
+        &services().globals.keypair(),
     );
-    request_map.insert(
-        "origin".to_owned(),
-        services().globals.server_name().as_str().into(),
-    );
-    request_map.insert("destination".to_owned(), destination.as_str().into());
-
-    let mut request_json =
-        serde_json::from_value(request_map.into()).expect("valid JSON is valid BTreeMap");
-
-    ruma::signatures::sign_json(
-        services().globals.server_name().as_str(),
-        services().globals.keypair(),
-        &mut request_json,
-    )
-    .expect("our request json is what ruma expects");
-
-    let request_json: serde_json::Map<String, serde_json::Value> =
-        serde_json::from_slice(&serde_json::to_vec(&request_json).unwrap()).unwrap();
-
-    let signatures = request_json["signatures"]
-        .as_object()
-        .unwrap()
-        .values()
-        .map(|v| {
-            v.as_object()
-                .unwrap()
-                .iter()
-                .map(|(k, v)| (k, v.as_str().unwrap()))
-        });
-
-    for signature_server in signatures {
-        for s in signature_server {
-            http_request.headers_mut().insert(
-                AUTHORIZATION,
-                HeaderValue::from_str(&format!(
-                    "X-Matrix origin={},key=\"{}\",sig=\"{}\"",
-                    services().globals.server_name(),
-                    s.0,
-                    s.1
-                ))
-                .unwrap(),
-            );
-        }
-    }
 
     let reqwest_request = reqwest::Request::try_from(http_request)
         .expect("all http requests are valid reqwest requests");
@@ -271,7 +355,7 @@ where
                 .expect("reqwest body is valid http body");
 
             if status == 200 {
-                let response = T::IncomingResponse::try_from_http_response(http_response);
+                let response = parse(http_response);
                 if response.is_ok() && write_destination_to_cache {
                     services()
                         .globals
@@ -280,7 +364,7 @@ where
                         .unwrap()
                         .insert(
                             OwnedServerName::from(destination),
-                            (actual_destination, host),
+                            (actual_destination, host, Instant::now() + cache_ttl),
                         );
                 }
 
@@ -308,6 +392,73 @@ where
     }
 }
 
+/// Signs the request of `origin` to `destination` and adds the X-Matrix Authorization header.
+fn sign_request(
+    http_request: &mut http::Request<Vec<u8>>,
+    origin: &ServerName,
+    destination: &ServerName,
+    keypair: &Ed25519KeyPair,
+) {
+    let mut request_map = serde_json::Map::new();
+
+    if !http_request.body().is_empty() {
+        request_map.insert(
+            "content".to_owned(),
+            serde_json::from_slice(http_request.body())
+                .expect("body is valid json, we just created it"),
+        );
+    };
+
+    request_map.insert(
+        "method".to_owned(),
+        http_request.method().to_string().into(),
+    );
+    request_map.insert(
+        "uri".to_owned(),
+        http_request
+            .uri()
+            .path_and_query()
+            .expect("all requests have a path")
+            .to_string()
+            .into(),
+    );
+    request_map.insert("origin".to_owned(), origin.as_str().into());
+    request_map.insert("destination".to_owned(), destination.as_str().into());
+
+    let mut request_json =
+        serde_json::from_value(request_map.into()).expect("valid JSON is valid BTreeMap");
+
+    ruma::signatures::sign_json(origin.as_str(), keypair, &mut request_json)
+        .expect("our request json is what ruma expects");
+
+    let request_json: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(&serde_json::to_vec(&request_json).unwrap()).unwrap();
+
+    let signatures = request_json["signatures"]
+        .as_object()
+        .unwrap()
+        .values()
+        .map(|v| {
+            v.as_object()
+                .unwrap()
+                .iter()
+                .map(|(k, v)| (k, v.as_str().unwrap()))
+        });
+
+    for signature_server in signatures {
+        for s in signature_server {
+            http_request.headers_mut().insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!(
+                    "X-Matrix origin={},key=\"{}\",sig=\"{}\"",
+                    origin, s.0, s.1
+                ))
+                .unwrap(),
+            );
+        }
+    }
+}
+
 fn get_ip_with_port(destination_str: &str) -> Option<FedDest> {
     if let Ok(destination) = destination_str.parse::<SocketAddr>() {
         Some(FedDest::Literal(destination))
@@ -326,12 +477,14 @@ fn add_port_to_hostname(destination_str: &str) -> FedDest {
     FedDest::Named(host.to_owned(), port.to_owned())
 }
 
-/// Returns: actual_destination, host header
+/// Returns the actual destination, the host name to use, and how long to cache the result.
 /// Implemented according to the specification at https://matrix.org/docs/spec/server_server/r0.1.4#resolving-server-names
 /// Numbers in comments below refer to bullet points in linked section of specification
-async fn find_actual_destination(destination: &'_ ServerName) -> (FedDest, FedDest) {
+async fn find_actual_destination(destination: &'_ ServerName) -> (FedDest, FedDest, Duration) {
     let destination_str = destination.as_str().to_owned();
     let mut hostname = destination_str.clone();
+    // Without a .well-known file, look again after an hour in case one was added
+    let mut cache_ttl = WELL_KNOWN_ERROR_TTL;
     let actual_destination = match get_ip_with_port(&destination_str) {
         Some(host_port) => {
             // 1: IP literal with provided or default port
@@ -345,7 +498,8 @@ async fn find_actual_destination(destination: &'_ ServerName) -> (FedDest, FedDe
             } else {
                 match request_well_known(destination.as_str()).await {
                     // 3: A .well-known file is available
-                    Some(delegated_hostname) => {
+                    Some((delegated_hostname, ttl)) => {
+                        cache_ttl = ttl;
                         hostname = add_port_to_hostname(&delegated_hostname).into_uri_string();
                         match get_ip_with_port(&delegated_hostname) {
                             Some(host_and_port) => host_and_port, // 3.1: IP literal in .well-known file
@@ -453,7 +607,53 @@ async fn find_actual_destination(destination: &'_ ServerName) -> (FedDest, FedDe
     } else {
         FedDest::Named(hostname, ":8448".to_owned())
     };
-    (actual_destination, hostname)
+    (actual_destination, hostname, cache_ttl)
+}
+
+/// Refuses destinations that resolve to blocked IP addresses, so a malicious server name,
+/// .well-known file or SRV record can't make us send requests into private networks.
+async fn check_destination_ips(destination: &FedDest) -> Result<()> {
+    let ips: Vec<IpAddr> = match destination {
+        FedDest::Literal(addr) => vec![addr.ip()],
+        FedDest::Named(host, _) => {
+            // Hosts from SRV records were already resolved, and reqwest connects to these addresses
+            let overridden = services()
+                .globals
+                .tls_name_override
+                .read()
+                .unwrap()
+                .get(host)
+                .map(|(ips, _)| ips.clone());
+
+            match overridden {
+                Some(ips) => ips,
+                None => services()
+                    .globals
+                    .dns_resolver()
+                    .lookup_ip(host.as_str())
+                    .await
+                    .map_err(|e| {
+                        warn!("Failed to resolve {}: {}", host, e);
+                        Error::BadServerResponse("Failed to resolve destination.")
+                    })?
+                    .iter()
+                    .collect(),
+            }
+        }
+    };
+
+    if let Some(ip) = ips.iter().find(|ip| !services().globals.ip_allowed(**ip)) {
+        warn!(
+            "Refusing to connect to {:?}, {} is in a blocked IP range",
+            destination, ip
+        );
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Destination is in a blocked IP range.",
+        ));
+    }
+
+    Ok(())
 }
 
 async fn query_srv_record(hostname: &'_ str) -> Option<FedDest> {
@@ -477,38 +677,183 @@ async fn query_srv_record(hostname: &'_ str) -> Option<FedDest> {
     }
 }
 
-async fn request_well_known(destination: &str) -> Option<String> {
-    let body: serde_json::Value = serde_json::from_str(
-        &services()
+/// The destination a request to `url` connects to.
+fn fed_dest_of_url(url: &reqwest::Url) -> Option<FedDest> {
+    let host = url.host_str()?;
+    let port = url.port_or_known_default()?;
+
+    Some(
+        match host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+        {
+            Ok(ip) => FedDest::Literal(SocketAddr::new(ip, port)),
+            Err(_) => FedDest::Named(host.to_owned(), format!(":{port}")),
+        },
+    )
+}
+
+/// How long the result of a .well-known lookup is cached if the response doesn't say, the
+/// longest it may be cached, and how long a failed lookup is cached.
+const WELL_KNOWN_DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const WELL_KNOWN_MAX_TTL: Duration = Duration::from_secs(48 * 60 * 60);
+const WELL_KNOWN_ERROR_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How many redirects of a .well-known request are followed.
+const WELL_KNOWN_MAX_REDIRECTS: usize = 5;
+
+/// Returns the delegated server name and how long it may be cached.
+async fn request_well_known(destination: &str) -> Option<(String, Duration)> {
+    let mut url =
+        reqwest::Url::parse(&format!("https://{destination}/.well-known/matrix/server")).ok()?;
+    let mut redirects = 0;
+
+    let response = loop {
+        // The server name and every redirect may point anywhere, so each hop is checked. The
+        // client doesn't follow redirects itself
+        check_destination_ips(&fed_dest_of_url(&url)?).await.ok()?;
+
+        let response = services()
             .globals
-            .default_client()
-            .get(&format!("https://{destination}/.well-known/matrix/server"))
+            .url_preview_client()
+            .get(url.clone())
             .send()
             .await
-            .ok()?
-            .text()
-            .await
-            .ok()?,
-    )
-    .ok()?;
-    Some(body.get("m.server")?.as_str()?.to_owned())
+            .ok()?;
+
+        if !response.status().is_redirection() {
+            break response;
+        }
+
+        redirects += 1;
+        if redirects > WELL_KNOWN_MAX_REDIRECTS {
+            warn!(
+                "Too many redirects for the .well-known file of {}",
+                destination
+            );
+            return None;
+        }
+
+        let location = response.headers().get(LOCATION)?.to_str().ok()?;
+        url = url.join(location).ok()?;
+    };
+
+    if !response.status().is_success() {
+        return None;
+    }
+
+    let ttl = well_known_ttl(
+        response
+            .headers()
+            .get(CACHE_CONTROL)
+            .and_then(|value| value.to_str().ok()),
+    );
+
+    let body = response.text().await.ok()?;
+    match parse_well_known(&body) {
+        Some(delegated_hostname) => Some((delegated_hostname, ttl)),
+        None => {
+            warn!("Ignoring invalid .well-known file of {}", destination);
+            None
+        }
+    }
+}
+
+/// Asks a server which unstable features it advertises in its version response.
+pub(crate) async fn fetch_unstable_features(
+    destination: &ServerName,
+) -> Result<BTreeMap<String, bool>> {
+    let cached = services()
+        .globals
+        .actual_destination_cache
+        .read()
+        .unwrap()
+        .get(destination)
+        .filter(|(_, _, valid_until)| Instant::now() < *valid_until)
+        .map(|(actual_destination, _, _)| actual_destination.clone());
+
+    let actual_destination = match cached {
+        Some(actual_destination) => actual_destination,
+        None => find_actual_destination(destination).await.0,
+    };
+
+    check_destination_ips(&actual_destination).await?;
+
+    let response = services()
+        .globals
+        .federation_client()
+        .get(&format!(
+            "{}/_matrix/federation/v1/version",
+            actual_destination.into_https_string()
+        ))
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(Error::BadServerResponse(
+            "Server returned an error for its version.",
+        ));
+    }
+
+    Ok(parse_unstable_features(&response.bytes().await?))
+}
+
+/// Returns the `unstable_features` of a version response. Servers that don't advertise any
+/// have none enabled.
+fn parse_unstable_features(body: &[u8]) -> BTreeMap<String, bool> {
+    serde_json::from_slice::<serde_json::Value>(body)
+        .ok()
+        .and_then(|body| body.get("unstable_features").cloned())
+        .and_then(|features| serde_json::from_value(features).ok())
+        .unwrap_or_default()
+}
+
+/// Returns the `m.server` of a .well-known file if it is a valid server name.
+fn parse_well_known(body: &str) -> Option<String> {
+    let body: serde_json::Value = serde_json::from_str(body).ok()?;
+    let delegated_hostname = body.get("m.server")?.as_str()?;
+
+    <&ServerName>::try_from(delegated_hostname).ok()?;
+
+    Some(delegated_hostname.to_owned())
+}
+
+/// Uses the `max-age` of a Cache-Control header, capped at a maximum.
+fn well_known_ttl(cache_control: Option<&str>) -> Duration {
+    cache_control
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .find_map(|directive| directive.trim().strip_prefix("max-age="))
+        .and_then(|max_age| max_age.parse().ok())
+        .map_or(WELL_KNOWN_DEFAULT_TTL, |secs| {
+            Duration::from_secs(secs).min(WELL_KNOWN_MAX_TTL)
+        })
 }
 
 /// # `GET /_matrix/federation/v1/version`
 ///
 /// Get version information on this server.
-pub async fn get_server_version_route(
-    _body: Ruma<get_server_version::v1::Request>,
-) -> Result<get_server_version::v1::Response> {
+///
+/// - Also advertises the unstable features enabled in the config, so other servers can adapt
+// Response type for this endpoint is Json because ruma's response has no unstable features
+pub async fn get_server_version_route() -> Result<impl IntoResponse> {
     if !services().globals.allow_federation() {
         return Err(Error::bad_config("Federation is disabled."));
     }
 
-    Ok(get_server_version::v1::Response {
-        server: Some(get_server_version::v1::Server {
-            name: Some("Conduit".to_owned()),
-            version: Some(env!("CARGO_PKG_VERSION").to_owned()),
-        }),
+    Ok(Json(server_version_json(
+        &services().globals.config.unstable_features,
+    )))
+}
+
+fn server_version_json(unstable_features: &BTreeMap<String, bool>) -> serde_json::Value {
+    serde_json::json!({
+        "server": {
+            "name": "Conduit",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "unstable_features": unstable_features,
     })
 }
 
@@ -516,34 +861,83 @@ pub async fn get_server_version_route(
 ///
 /// Gets the public signing keys of this server.
 ///
-/// - Matrix does not support invalidating public keys, so the key returned by this will be valid
-/// forever.
+/// - Keys replaced by a key rotation are listed in `old_verify_keys`, so events signed with them
+/// can still be verified
 // Response type for this endpoint is Json because we need to calculate a signature for the response
-pub async fn get_server_keys_route() -> Result<impl IntoResponse> {
+pub async fn get_server_keys_route(host: Option<TypedHeader<Host>>) -> Result<impl IntoResponse> {
     if !services().globals.allow_federation() {
         return Err(Error::bad_config("Federation is disabled."));
     }
 
-    let mut verify_keys: BTreeMap<OwnedServerSigningKeyId, VerifyKey> = BTreeMap::new();
-    verify_keys.insert(
-        format!("ed25519:{}", services().globals.keypair().version())
-            .try_into()
-            .expect("found invalid server signing keys in DB"),
+    // Servers asking for the keys of an alias expect the response to be about that name
+    let server_name = host
+        .and_then(|TypedHeader(host)| {
+            let with_port = host
+                .port()
+                .map(|port| format!("{}:{}", host.hostname(), port));
+            with_port
+                .iter()
+                .map(String::as_str)
+                .chain(iter::once(host.hostname()))
+                .filter_map(|name| <&ServerName>::try_from(name).ok())
+                .find(|name| services().globals.server_is_ours(name))
+                .map(ToOwned::to_owned)
+        })
+        .unwrap_or_else(|| services().globals.server_name().to_owned());
+
+    let response = server_keys_json(
+        server_name,
+        &services().globals.keypair(),
+        services().globals.old_verify_keys()?,
+        SystemTime::now(),
+        services().globals.key_validity_period(),
+        services().globals.old_key_retention(),
+    );
+
+    Ok(Json(response))
+}
+
+/// Builds the signed key server response with the current key and the keys used before.
+///
+/// - The response may be cached for `validity_period`
+/// - Old keys that expired more than `old_key_retention` ago are left out
+pub(crate) fn server_keys_json(
+    server_name: OwnedServerName,
+    keypair: &Ed25519KeyPair,
+    mut old_verify_keys: BTreeMap<OwnedServerSigningKeyId, OldVerifyKey>,
+    now: SystemTime,
+    validity_period: Duration,
+    old_key_retention: Option<Duration>,
+) -> CanonicalJsonObject {
+    if let Some(retention) = old_key_retention {
+        old_verify_keys.retain(|_, old_key| {
+            old_key
+                .expired_ts
+                .to_system_time()
+                .and_then(|expired| expired.checked_add(retention))
+                .map_or(true, |until| until > now)
+        });
+    }
+
+    let valid_until_ts = now
+        .checked_add(validity_period)
+        .and_then(MilliSecondsSinceUnixEpoch::from_system_time)
+        .expect("validity period is bounded when the config is loaded");
+    let verify_keys = BTreeMap::from([(
+        globals::key_id(keypair),
         VerifyKey {
-            key: Base64::new(services().globals.keypair().public_key().to_vec()),
+            key: Base64::new(keypair.public_key().to_vec()),
         },
-    );
+    )]);
+
     let mut response = serde_json::from_slice(
         get_server_keys::v2::Response {
             server_key: Raw::new(&ServerSigningKeys {
-                server_name: services().globals.server_name().to_owned(),
+                server_name: server_name.clone(),
                 verify_keys,
-                old_verify_keys: BTreeMap::new(),
+                old_verify_keys,
                 signatures: BTreeMap::new(),
-                valid_until_ts: MilliSecondsSinceUnixEpoch::from_system_time(
-                    SystemTime::now() + Duration::from_secs(86400 * 7),
-                )
-                .expect("time is valid"),
+                valid_until_ts,
             })
             .expect("static conversion, no errors"),
         }
@@ -553,14 +947,9 @@ pub async fn get_server_keys_route() -> Result<impl IntoResponse> {
     )
     .unwrap();
 
-    ruma::signatures::sign_json(
-        services().globals.server_name().as_str(),
-        services().globals.keypair(),
-        &mut response,
-    )
-    .unwrap();
+    ruma::signatures::sign_json(server_name.as_str(), keypair, &mut response).unwrap();
 
-    Ok(Json(response))
+    response
 }
 
 /// # `GET /_matrix/key/v2/server/{keyId}`
@@ -569,8 +958,10 @@ pub async fn get_server_keys_route() -> Result<impl IntoResponse> {
 ///
 /// - Matrix does not support invalidating public keys, so the key returned by this will be valid
 /// forever.
-pub async fn get_server_keys_deprecated_route() -> impl IntoResponse {
-    get_server_keys_route().await
+pub async fn get_server_keys_deprecated_route(
+    host: Option<TypedHeader<Host>>,
+) -> impl IntoResponse {
+    get_server_keys_route(host).await
 }
 
 /// # `POST /_matrix/federation/v1/publicRooms`
@@ -583,8 +974,18 @@ pub async fn get_public_rooms_filtered_route(
         return Err(Error::bad_config("Federation is disabled."));
     }
 
-    let response = client_server::get_public_rooms_filtered_helper(
-        None,
+    if !services()
+        .globals
+        .allow_public_room_directory_over_federation()
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The room directory is not available over federation.",
+        ));
+    }
+
+    let response = client_server::get_public_rooms_filtered_helper(
+        None,
         body.limit,
         body.since.as_deref(),
         &body.filter,
@@ -610,6 +1011,16 @@ pub async fn get_public_rooms_route(
         return Err(Error::bad_config("Federation is disabled."));
     }
 
+    if !services()
+        .globals
+        .allow_public_room_directory_over_federation()
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The room directory is not available over federation.",
+        ));
+    }
+
     let response = client_server::get_public_rooms_filtered_helper(
         None,
         body.limit,
@@ -642,6 +1053,46 @@ pub async fn send_transaction_message_route(
         .as_ref()
         .expect("server is authenticated");
 
+    // Retried transactions get the same answer again
+    if let Some(pdus) = services()
+        .globals
+        .transaction_cache
+        .lock()
+        .unwrap()
+        .get(sender_servername, &body.transaction_id)
+    {
+        debug!(
+            "Transaction {} from {} was already handled",
+            body.transaction_id, sender_servername
+        );
+        return Ok(send_transaction_message::v1::Response { pdus });
+    }
+
+    if body.pdus.len() > MAX_TRANSACTION_PDUS || body.edus.len() > MAX_TRANSACTION_EDUS {
+        return Err(Error::BadRequest(
+            ErrorKind::TooLarge,
+            "Transaction has too many PDUs or EDUs.",
+        ));
+    }
+
+    let _permit = try_start_transaction(
+        &services().globals.servername_transactions,
+        sender_servername,
+        services().globals.max_concurrent_transactions_per_origin(),
+    )
+    .ok_or_else(|| {
+        warn!(
+            "Rejecting transaction from {}, too many are in flight",
+            sender_servername
+        );
+        Error::BadRequest(
+            ErrorKind::LimitExceeded {
+                retry_after_ms: None,
+            },
+            "Too many concurrent transactions.",
+        )
+    })?;
+
     let mut resolved_map = BTreeMap::new();
 
     let pub_key_map = RwLock::new(BTreeMap::new());
@@ -687,6 +1138,16 @@ pub async fn send_transaction_message_route(
         };
         // We do not add the event_id field to the pdu here because of signature and hashes checks
 
+        if let Err(e) = check_pdu_limits(&value, pdu.get().len(), &services().globals.pdu_limits())
+        {
+            warn!(
+                "Dropping event {} from {}: {}",
+                event_id, sender_servername, e
+            );
+            resolved_map.insert(event_id, Err(e));
+            continue;
+        }
+
         services()
             .rooms
             .event_handler
@@ -893,19 +1354,28 @@ pub async fn send_transaction_message_route(
         }
     }
 
-    Ok(send_transaction_message::v1::Response {
-        pdus: resolved_map
-            .into_iter()
-            .map(|(e, r)| (e, r.map_err(|e| e.to_string())))
-            .collect(),
-    })
+    let pdus: BTreeMap<_, _> = resolved_map
+        .into_iter()
+        .map(|(e, r)| (e, r.map_err(|e| e.to_string())))
+        .collect();
+
+    services().globals.transaction_cache.lock().unwrap().insert(
+        sender_servername,
+        &body.transaction_id,
+        pdus.clone(),
+    );
+
+    Ok(send_transaction_message::v1::Response { pdus })
 }
 
 /// # `GET /_matrix/federation/v1/event/{eventId}`
 ///
 /// Retrieves a single event from the server.
 ///
-/// - Only works if a user of this server is currently invited or joined the room
+/// - Only works if a user of this server is currently invited or joined the room, or if the room
+///   is world readable
+/// - The history visibility at the event must allow the requesting server to see it
+/// - Events that are not visible are reported as not found
 pub async fn get_event_route(
     body: Ruma<get_event::v1::Request>,
 ) -> Result<get_event::v1::Response> {
@@ -930,29 +1400,133 @@ pub async fn get_event_route(
         .ok_or_else(|| Error::bad_database("Invalid event in database"))?;
 
     let room_id = <&RoomId>::try_from(room_id_str)
-        .map_err(|_| Error::bad_database("Invalid room id field in event in database"))?;
+        .map_err(|_| Error::bad_database("Invalid room id field in event in database"))?
+        .to_owned();
+
+    let visible = !services().rooms.metadata.is_local_only(&room_id)?
+        && (services()
+            .rooms
+            .state_cache
+            .server_in_room(sender_servername, &room_id)?
+            || services()
+                .rooms
+                .state_accessor
+                .is_world_readable(&room_id)?)
+        && services().rooms.state_accessor.server_can_see_event(
+            sender_servername,
+            &room_id,
+            &body.event_id,
+        )?;
+
+    if !visible {
+        // Don't tell the server whether the event exists
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Event not found."));
+    }
+
+    // Signing the event again would change it, the stored event is served as it is
+    if lacks_our_signature(&event, services().globals.server_name()) {
+        warn!(
+            "Event {} from our server has no signature, other servers can't verify it",
+            body.event_id
+        );
+    }
+
+    Ok(get_event::v1::Response {
+        origin: services().globals.server_name().to_owned(),
+        origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+        pdu: PduEvent::convert_to_outgoing_federation_event(event),
+    })
+}
+
+/// Whether an event was sent by one of our users but doesn't carry a signature of our server,
+/// so other servers couldn't verify it.
+fn lacks_our_signature(event: &CanonicalJsonObject, server_name: &ServerName) -> bool {
+    let from_us = event
+        .get("sender")
+        .and_then(|sender| UserId::parse(sender.as_str()?).ok())
+        .map_or(false, |sender| sender.server_name() == server_name);
+
+    let signed = match event.get("signatures") {
+        Some(CanonicalJsonValue::Object(signatures)) => matches!(
+            signatures.get(server_name.as_str()),
+            Some(CanonicalJsonValue::Object(keys)) if !keys.is_empty()
+        ),
+        _ => false,
+    };
+
+    from_us && !signed
+}
+
+/// # `GET /_matrix/federation/v1/timestamp_to_event/{roomId}`
+///
+/// Finds the event closest to a timestamp for another server.
+///
+/// - Only works if the requesting server is in the room
+/// - Only returns events the requesting server is allowed to see
+pub async fn get_event_by_timestamp_route(
+    body: Ruma<get_event_by_timestamp::v1::Request>,
+) -> Result<get_event_by_timestamp::v1::Response> {
+    if !services().globals.allow_federation() {
+        return Err(Error::bad_config("Federation is disabled."));
+    }
+
+    let sender_servername = body
+        .sender_servername
+        .as_ref()
+        .expect("server is authenticated");
 
     if !services()
         .rooms
         .state_cache
-        .server_in_room(sender_servername, room_id)?
+        .server_in_room(sender_servername, &body.room_id)?
     {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
-            "Server is not in room",
+            "Server is not in room.",
         ));
     }
 
-    Ok(get_event::v1::Response {
-        origin: services().globals.server_name().to_owned(),
-        origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
-        pdu: PduEvent::convert_to_outgoing_federation_event(event),
-    })
+    services()
+        .rooms
+        .event_handler
+        .acl_check(sender_servername, &body.room_id)?;
+
+    let closest = services().rooms.timeline.closest_event(
+        &body.room_id,
+        body.ts.0,
+        body.dir.clone(),
+        |event_id| {
+            services().rooms.state_accessor.server_can_see_event(
+                sender_servername,
+                &body.room_id,
+                event_id,
+            )
+        },
+    )?;
+
+    timestamp_to_event_response(closest)
+}
+
+fn timestamp_to_event_response(
+    closest: Option<PduEvent>,
+) -> Result<get_event_by_timestamp::v1::Response> {
+    let pdu = closest.ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "No event found in that direction.",
+    ))?;
+
+    Ok(get_event_by_timestamp::v1::Response::new(
+        (*pdu.event_id).to_owned(),
+        MilliSecondsSinceUnixEpoch(pdu.origin_server_ts),
+    ))
 }
 
 /// # `POST /_matrix/federation/v1/get_missing_events/{roomId}`
 ///
 /// Retrieves events that the sender is missing.
+///
+/// - Returns the events between `earliest_events` and `latest_events`, oldest first
+/// - Leaves out events below `min_depth` and events the sender isn't allowed to see
 pub async fn get_missing_events_route(
     body: Ruma<get_missing_events::v1::Request>,
 ) -> Result<get_missing_events::v1::Response> {
@@ -981,24 +1555,21 @@ pub async fn get_missing_events_route(
         .event_handler
         .acl_check(sender_servername, &body.room_id)?;
 
-    let mut queued_events = body.latest_events.clone();
-    let mut events = Vec::new();
-
-    let mut i = 0;
-    while i < queued_events.len() && events.len() < u64::from(body.limit) as usize {
-        if let Some(pdu) = services().rooms.timeline.get_pdu_json(&queued_events[i])? {
-            let room_id_str = pdu
-                .get("room_id")
-                .and_then(|val| val.as_str())
-                .ok_or_else(|| Error::bad_database("Invalid event in database"))?;
-
-            let event_room_id = <&RoomId>::try_from(room_id_str)
-                .map_err(|_| Error::bad_database("Invalid room id field in event in database"))?;
-
-            if event_room_id != body.room_id {
+    let event_ids = walk_missing_events(
+        &body.latest_events,
+        &body.earliest_events,
+        u64::from(body.limit) as usize,
+        body.min_depth,
+        |event_id| {
+            let pdu = match services().rooms.timeline.get_pdu(event_id)? {
+                Some(pdu) => pdu,
+                None => return Ok(None),
+            };
+
+            if pdu.room_id != body.room_id {
                 warn!(
                     "Evil event detected: Event {} found while searching in room {}",
-                    queued_events[i], body.room_id
+                    event_id, body.room_id
                 );
                 return Err(Error::BadRequest(
                     ErrorKind::InvalidParam,
@@ -1006,32 +1577,89 @@ pub async fn get_missing_events_route(
                 ));
             }
 
-            if body.earliest_events.contains(&queued_events[i]) {
-                i += 1;
-                continue;
-            }
-            queued_events.extend_from_slice(
-                &serde_json::from_value::<Vec<OwnedEventId>>(
-                    serde_json::to_value(pdu.get("prev_events").cloned().ok_or_else(|| {
-                        Error::bad_database("Event in db has no prev_events field.")
-                    })?)
-                    .expect("canonical json is valid json value"),
-                )
-                .map_err(|_| Error::bad_database("Invalid prev_events content in pdu in db."))?,
-            );
+            Ok(Some((
+                pdu.depth,
+                pdu.prev_events
+                    .iter()
+                    .map(|prev_event| (**prev_event).to_owned())
+                    .collect(),
+            )))
+        },
+    )?;
+
+    let mut events = Vec::new();
+    for event_id in event_ids {
+        if !services().rooms.state_accessor.server_can_see_event(
+            sender_servername,
+            &body.room_id,
+            &event_id,
+        )? {
+            continue;
+        }
+
+        if let Some(pdu) = services().rooms.timeline.get_pdu_json(&event_id)? {
             events.push(PduEvent::convert_to_outgoing_federation_event(pdu));
         }
-        i += 1;
     }
 
     Ok(get_missing_events::v1::Response { events })
 }
 
+/// Walks backwards through the room graph, starting at the prev events of `latest_events`, and
+/// returns up to `limit` events in topological order. The walk stops at `earliest_events` and at
+/// events below `min_depth`. `latest_events` themselves are never returned.
+fn walk_missing_events(
+    latest_events: &[OwnedEventId],
+    earliest_events: &[OwnedEventId],
+    limit: usize,
+    min_depth: UInt,
+    mut get_event: impl FnMut(&EventId) -> Result<Option<(UInt, Vec<OwnedEventId>)>>,
+) -> Result<Vec<OwnedEventId>> {
+    let mut seen: HashSet<_> = latest_events
+        .iter()
+        .chain(earliest_events)
+        .cloned()
+        .collect();
+    let mut queue = VecDeque::new();
+
+    for event_id in latest_events {
+        if let Some((_, prev_events)) = get_event(event_id)? {
+            queue.extend(prev_events);
+        }
+    }
+
+    let mut found = Vec::new();
+    while found.len() < limit {
+        let event_id = match queue.pop_front() {
+            Some(event_id) => event_id,
+            None => break,
+        };
+
+        if !seen.insert(event_id.clone()) {
+            continue;
+        }
+
+        if let Some((depth, prev_events)) = get_event(&event_id)? {
+            if depth < min_depth {
+                continue;
+            }
+
+            queue.extend(prev_events);
+            found.push((depth, event_id));
+        }
+    }
+
+    found.sort();
+
+    Ok(found.into_iter().map(|(_, event_id)| event_id).collect())
+}
+
 /// # `GET /_matrix/federation/v1/event_auth/{roomId}/{eventId}`
 ///
 /// Retrieves the auth chain for a given event.
 ///
 /// - This does not include the event itself
+/// - Every event comes after its auth events, so they can be checked in order
 pub async fn get_event_authorization_route(
     body: Ruma<get_event_authorization::v1::Request>,
 ) -> Result<get_event_authorization::v1::Response> {
@@ -1074,20 +1702,132 @@ pub async fn get_event_authorization_route(
     let room_id = <&RoomId>::try_from(room_id_str)
         .map_err(|_| Error::bad_database("Invalid room id field in event in database"))?;
 
+    // Being in one room doesn't allow to look at the events of another
+    if room_id != body.room_id {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Event not found."));
+    }
+
     let auth_chain_ids = services()
         .rooms
         .auth_chain
         .get_auth_chain(room_id, vec![Arc::from(&*body.event_id)])
         .await?;
 
+    let auth_chain: BTreeMap<_, _> = auth_chain_ids
+        .filter_map(|id| {
+            let pdu = services().rooms.timeline.get_pdu_json(&id).ok()??;
+            Some(((*id).to_owned(), pdu))
+        })
+        .collect();
+
+    let missing = missing_auth_events(&event, &auth_chain);
+    if !missing.is_empty() {
+        warn!(
+            "Auth chain of {} is incomplete, missing {:?}",
+            body.event_id, missing
+        );
+    }
+
     Ok(get_event_authorization::v1::Response {
-        auth_chain: auth_chain_ids
-            .filter_map(|id| services().rooms.timeline.get_pdu_json(&id).ok()?)
+        auth_chain: sort_auth_chain(auth_chain)
+            .into_iter()
             .map(PduEvent::convert_to_outgoing_federation_event)
             .collect(),
     })
 }
 
+/// The ids in the `auth_events` of a PDU, which are plain strings or, in room versions 1 and 2,
+/// pairs of event id and hashes.
+fn pdu_auth_events(pdu: &CanonicalJsonObject) -> Vec<OwnedEventId> {
+    match pdu.get("auth_events") {
+        Some(CanonicalJsonValue::Array(auth_events)) => auth_events
+            .iter()
+            .filter_map(|auth_event| match auth_event {
+                CanonicalJsonValue::String(id) => Some(id),
+                CanonicalJsonValue::Array(pair) => match pair.first() {
+                    Some(CanonicalJsonValue::String(id)) => Some(id),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .filter_map(|id| OwnedEventId::try_from(id.as_str()).ok())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Auth events that are referenced by `event` or an event of its auth chain, but are not part of
+/// the chain.
+fn missing_auth_events(
+    event: &CanonicalJsonObject,
+    auth_chain: &BTreeMap<OwnedEventId, CanonicalJsonObject>,
+) -> BTreeSet<OwnedEventId> {
+    iter::once(event)
+        .chain(auth_chain.values())
+        .flat_map(pdu_auth_events)
+        .filter(|id| !auth_chain.contains_key(id))
+        .collect()
+}
+
+/// Orders an auth chain so that every event comes after its auth events. Events that could come
+/// next are ordered by depth and event id, which makes the order deterministic.
+fn sort_auth_chain(
+    mut auth_chain: BTreeMap<OwnedEventId, CanonicalJsonObject>,
+) -> Vec<CanonicalJsonObject> {
+    let depth = |pdu: &CanonicalJsonObject| match pdu.get("depth") {
+        Some(CanonicalJsonValue::Integer(depth)) => i64::from(*depth),
+        _ => 0,
+    };
+
+    // How many auth events of each event are not sorted yet, and which events wait for them
+    let mut pending = HashMap::new();
+    let mut dependents: HashMap<OwnedEventId, Vec<OwnedEventId>> = HashMap::new();
+    let mut ready = BTreeSet::new();
+
+    for (event_id, pdu) in &auth_chain {
+        let auth_events: HashSet<_> = pdu_auth_events(pdu)
+            .into_iter()
+            .filter(|id| auth_chain.contains_key(id))
+            .collect();
+
+        if auth_events.is_empty() {
+            ready.insert((depth(pdu), event_id.clone()));
+        } else {
+            pending.insert(event_id.clone(), auth_events.len());
+            for auth_event in auth_events {
+                dependents
+                    .entry(auth_event)
+                    .or_default()
+                    .push(event_id.clone());
+            }
+        }
+    }
+
+    let mut sorted = Vec::with_capacity(auth_chain.len());
+
+    while let Some((d, event_id)) = ready.iter().next().cloned() {
+        ready.remove(&(d, event_id.clone()));
+
+        for dependent in dependents.remove(&event_id).unwrap_or_default() {
+            let count = pending
+                .get_mut(&dependent)
+                .expect("dependents have pending auth events");
+            *count -= 1;
+            if *count == 0 {
+                pending.remove(&dependent);
+                ready.insert((depth(&auth_chain[&dependent]), dependent));
+            }
+        }
+
+        sorted.push(auth_chain.remove(&event_id).expect("event is in the chain"));
+    }
+
+    // Only a broken chain can contain cycles, its events are sent anyway
+    sorted.extend(auth_chain.into_values());
+
+    sorted
+}
+
 /// # `GET /_matrix/federation/v1/state/{roomId}`
 ///
 /// Retrieves the current state of the room.
@@ -1229,6 +1969,31 @@ pub async fn get_room_state_ids_route(
     })
 }
 
+/// Fails if `federation_allow_joins` turned off joins of remote users, see also
+/// `check_federation_invites_allowed`.
+fn check_federation_joins_allowed() -> Result<()> {
+    if !services().globals.federation_allow_joins() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This server does not allow remote users to join its rooms.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Fails if `federation_allow_invites` turned off invites from other servers.
+fn check_federation_invites_allowed() -> Result<()> {
+    if !services().globals.federation_allow_invites() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This server does not accept invites over federation.",
+        ));
+    }
+
+    Ok(())
+}
+
 /// # `GET /_matrix/federation/v1/make_join/{roomId}/{userId}`
 ///
 /// Creates a join template.
@@ -1239,6 +2004,8 @@ pub async fn create_join_event_template_route(
         return Err(Error::bad_config("Federation is disabled."));
     }
 
+    check_federation_joins_allowed()?;
+
     if !services().rooms.metadata.exists(&body.room_id)? {
         return Err(Error::BadRequest(
             ErrorKind::NotFound,
@@ -1345,11 +2112,14 @@ async fn create_join_event(
     sender_servername: &ServerName,
     room_id: &RoomId,
     pdu: &RawJsonValue,
+    omit_members: bool,
 ) -> Result<RoomState> {
     if !services().globals.allow_federation() {
         return Err(Error::bad_config("Federation is disabled."));
     }
 
+    check_federation_joins_allowed()?;
+
     if !services().rooms.metadata.exists(room_id)? {
         return Err(Error::BadRequest(
             ErrorKind::NotFound,
@@ -1426,6 +2196,15 @@ async fn create_join_event(
     )
     .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Origin field is invalid."))?;
 
+    let joining_user = value
+        .get("state_key")
+        .and_then(|state_key| state_key.as_str())
+        .and_then(|state_key| UserId::parse(state_key).ok())
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Join event needs a user id as state key.",
+        ))?;
+
     let mutex = Arc::clone(
         services()
             .globals
@@ -1452,35 +2231,96 @@ async fn create_join_event(
         .state_accessor
         .state_full_ids(shortstatehash)
         .await?;
+    let state_ids = if omit_members {
+        partial_join_state(room_id, state_ids, &joining_user)?
+    } else {
+        state_ids.into_values().collect()
+    };
     let auth_chain_ids = services()
         .rooms
         .auth_chain
-        .get_auth_chain(room_id, state_ids.values().cloned().collect())
+        .get_auth_chain(room_id, state_ids.clone())
         .await?;
+    // A partial state response leaves out the auth events the state contains already
+    let returned_state: HashSet<Arc<EventId>> = if omit_members {
+        state_ids.iter().cloned().collect()
+    } else {
+        HashSet::new()
+    };
 
     let servers = services()
         .rooms
         .state_cache
         .room_servers(room_id)
         .filter_map(|r| r.ok())
-        .filter(|server| &**server != services().globals.server_name());
+        .filter(|server| !services().globals.server_is_ours(server));
 
     services().sending.send_pdu(servers, &pdu_id)?;
 
     Ok(RoomState {
         auth_chain: auth_chain_ids
+            .filter(|id| !returned_state.contains(id))
             .filter_map(|id| services().rooms.timeline.get_pdu_json(&id).ok().flatten())
             .map(PduEvent::convert_to_outgoing_federation_event)
             .collect(),
         state: state_ids
             .iter()
-            .filter_map(|(_, id)| services().rooms.timeline.get_pdu_json(id).ok().flatten())
+            .filter_map(|id| services().rooms.timeline.get_pdu_json(id).ok().flatten())
             .map(PduEvent::convert_to_outgoing_federation_event)
             .collect(),
         event: None, // TODO: handle restricted joins
     })
 }
 
+/// The state a send_join response with `omit_members` returns (MSC3706): everything but the
+/// memberships of other users. Rooms without a name or canonical alias keep the memberships of up
+/// to five members, which clients need to name the room.
+fn partial_join_state(
+    room_id: &RoomId,
+    state_ids: HashMap<u64, Arc<EventId>>,
+    joining_user: &UserId,
+) -> Result<Vec<Arc<EventId>>> {
+    let state = state_ids
+        .into_iter()
+        .map(|(shortstatekey, event_id)| {
+            let (event_type, state_key) = services()
+                .rooms
+                .short
+                .get_statekey_from_short(shortstatekey)?;
+            Ok((event_type, state_key, event_id))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let named = state.iter().any(|(event_type, _, _)| {
+        matches!(
+            event_type,
+            StateEventType::RoomName | StateEventType::RoomCanonicalAlias
+        )
+    });
+
+    let mut members = BTreeSet::from([joining_user.to_string()]);
+    if !named {
+        members.extend(
+            services()
+                .rooms
+                .state_cache
+                .room_members(room_id)
+                .filter_map(|r| r.ok())
+                .filter(|user_id| user_id != joining_user)
+                .take(5)
+                .map(|user_id| user_id.to_string()),
+        );
+    }
+
+    Ok(state
+        .into_iter()
+        .filter(|(event_type, state_key, _)| {
+            *event_type != StateEventType::RoomMember || members.contains(state_key)
+        })
+        .map(|(_, _, event_id)| event_id)
+        .collect())
+}
+
 /// # `PUT /_matrix/federation/v1/send_join/{roomId}/{eventId}`
 ///
 /// Submits a signed join event.
@@ -1492,25 +2332,62 @@ pub async fn create_join_event_v1_route(
         .as_ref()
         .expect("server is authenticated");
 
-    let room_state = create_join_event(sender_servername, &body.room_id, &body.pdu).await?;
+    let room_state = create_join_event(sender_servername, &body.room_id, &body.pdu, false).await?;
 
     Ok(create_join_event::v1::Response { room_state })
 }
 
+#[derive(Deserialize)]
+pub struct SendJoinParams {
+    #[serde(default)]
+    omit_members: bool,
+}
+
 /// # `PUT /_matrix/federation/v2/send_join/{roomId}/{eventId}`
 ///
 /// Submits a signed join event.
+///
+/// - With `omit_members`, the state leaves out most memberships (MSC3706) and the response lists
+///   the servers in the room, which the joining server can ask for the full state later
 pub async fn create_join_event_v2_route(
+    Query(params): Query<SendJoinParams>,
     body: Ruma<create_join_event::v2::Request>,
-) -> Result<create_join_event::v2::Response> {
+) -> Result<Json<serde_json::Value>> {
     let sender_servername = body
         .sender_servername
         .as_ref()
         .expect("server is authenticated");
 
-    let room_state = create_join_event(sender_servername, &body.room_id, &body.pdu).await?;
+    let room_state = create_join_event(
+        sender_servername,
+        &body.room_id,
+        &body.pdu,
+        params.omit_members,
+    )
+    .await?;
+
+    let mut response: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(
+        create_join_event::v2::Response { room_state }
+            .try_into_http_response::<Vec<u8>>()
+            .expect("send_join response can be serialized")
+            .body(),
+    )
+    .expect("send_join response is a JSON object");
+
+    if params.omit_members {
+        let servers_in_room = services()
+            .rooms
+            .state_cache
+            .room_servers(&body.room_id)
+            .filter_map(|r| r.ok())
+            .map(|server| server.to_string())
+            .collect::<Vec<_>>();
+
+        response.insert("members_omitted".to_owned(), true.into());
+        response.insert("servers_in_room".to_owned(), servers_in_room.into());
+    }
 
-    Ok(create_join_event::v2::Response { room_state })
+    Ok(Json(response.into()))
 }
 
 /// # `PUT /_matrix/federation/v2/invite/{roomId}/{eventId}`
@@ -1523,6 +2400,8 @@ pub async fn create_invite_route(
         return Err(Error::bad_config("Federation is disabled."));
     }
 
+    check_federation_invites_allowed()?;
+
     let sender_servername = body
         .sender_servername
         .as_ref()
@@ -1551,7 +2430,7 @@ pub async fn create_invite_route(
 
     ruma::signatures::hash_and_sign_event(
         services().globals.server_name().as_str(),
-        services().globals.keypair(),
+        &*services().globals.keypair(),
         &mut signed_event,
         &body.room_version,
     )
@@ -1595,6 +2474,8 @@ pub async fn create_invite_route(
     )
     .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "state_key is not a user id."))?;
 
+    client_server::check_invite_limits(&sender, &invited_user, &body.room_id)?;
+
     let mut invite_state = body.invite_room_state.clone();
 
     let mut event: JsonObject = serde_json::from_str(body.event.get())
@@ -1630,46 +2511,296 @@ pub async fn create_invite_route(
     })
 }
 
-/// # `GET /_matrix/federation/v1/user/devices/{userId}`
+/// # `PUT /_matrix/federation/v1/exchange_third_party_invite/{roomId}`
 ///
-/// Gets information on all devices of the user.
-pub async fn get_devices_route(
-    body: Ruma<get_devices::v1::Request>,
-) -> Result<get_devices::v1::Response> {
+/// Turns a third party invite sent by one of our users into an invite for the user the address
+/// was bound to.
+pub async fn exchange_third_party_invite_route(
+    body: Ruma<exchange_invite::v1::Request>,
+) -> Result<exchange_invite::v1::Response> {
     if !services().globals.allow_federation() {
         return Err(Error::bad_config("Federation is disabled."));
     }
 
+    check_federation_invites_allowed()?;
+
     let sender_servername = body
         .sender_servername
         .as_ref()
         .expect("server is authenticated");
 
-    Ok(get_devices::v1::Response {
-        user_id: body.user_id.clone(),
-        stream_id: services()
-            .users
-            .get_devicelist_version(&body.user_id)?
-            .unwrap_or(0)
-            .try_into()
-            .expect("version will not grow that large"),
-        devices: services()
-            .users
-            .all_devices_metadata(&body.user_id)
-            .filter_map(|r| r.ok())
-            .filter_map(|metadata| {
-                Some(UserDevice {
-                    keys: services()
-                        .users
-                        .get_device_keys(&body.user_id, &metadata.device_id)
-                        .ok()??,
-                    device_id: metadata.device_id,
-                    device_display_name: metadata.display_name,
-                })
-            })
-            .collect(),
-        master_key: services()
-            .users
+    services()
+        .rooms
+        .event_handler
+        .acl_check(sender_servername, &body.room_id)?;
+
+    if body.kind != StateEventType::RoomMember {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Only m.room.member events can be exchanged.",
+        ));
+    }
+
+    let signed = serde_json::to_value(&body.content.signed)
+        .ok()
+        .and_then(|signed| serde_json::from_value(signed).ok())
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Invalid signed block.",
+        ))?;
+
+    exchange_third_party_invite(&body.room_id, &body.sender, &body.state_key, signed).await?;
+
+    Ok(exchange_invite::v1::Response {})
+}
+
+#[derive(Deserialize)]
+pub struct ThirdPartyBindRequest {
+    mxid: OwnedUserId,
+    #[serde(default)]
+    invites: Vec<BoundThirdPartyInvite>,
+}
+
+#[derive(Deserialize)]
+struct BoundThirdPartyInvite {
+    room_id: OwnedRoomId,
+    sender: OwnedUserId,
+    signed: CanonicalJsonObject,
+}
+
+/// # `PUT /_matrix/federation/v1/3pid/onbind`
+///
+/// Called by identity servers when an address with pending third party invites was bound to one
+/// of our users. Each invite is exchanged with the inviter's server for a real invite.
+// Handled without ruma, the identity server doesn't sign the request like a homeserver would
+pub async fn third_party_bind_callback_route(
+    Json(body): Json<ThirdPartyBindRequest>,
+) -> Result<impl IntoResponse> {
+    if !services().globals.allow_federation() {
+        return Err(Error::bad_config("Federation is disabled."));
+    }
+
+    if !services().globals.server_is_ours(body.mxid.server_name()) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "User does not belong to this server.",
+        ));
+    }
+
+    for invite in body.invites {
+        // Anyone can call this endpoint, the identity server's signature shows the bind happened
+        let verified = identity_server::verify_invite_signature(&invite.signed).await;
+
+        let result = match verified {
+            Err(e) => Err(e),
+            Ok(())
+                if services()
+                    .globals
+                    .server_is_ours(invite.sender.server_name()) =>
+            {
+                exchange_third_party_invite(
+                    &invite.room_id,
+                    &invite.sender,
+                    &body.mxid,
+                    invite.signed,
+                )
+                .await
+            }
+            Ok(()) => {
+                exchange_remote_third_party_invite(
+                    &invite.room_id,
+                    &invite.sender,
+                    &body.mxid,
+                    invite.signed,
+                )
+                .await
+            }
+        };
+
+        if let Err(e) = result {
+            warn!(
+                "Failed to exchange third party invite to {} from {}: {}",
+                invite.room_id, invite.sender, e
+            );
+        }
+    }
+
+    Ok(Json(serde_json::json!({})))
+}
+
+async fn exchange_remote_third_party_invite(
+    room_id: &RoomId,
+    sender: &UserId,
+    invitee: &UserId,
+    signed: CanonicalJsonObject,
+) -> Result<()> {
+    if signed.get("mxid").and_then(|mxid| mxid.as_str()) != Some(invitee.as_str()) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "The invite was signed for another user.",
+        ));
+    }
+
+    // The inviter's server fills in the display name from its m.room.third_party_invite event
+    let content = serde_json::from_value(serde_json::json!({
+        "display_name": "",
+        "signed": signed,
+    }))
+    .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid signed block."))?;
+
+    services()
+        .sending
+        .send_federation_request(
+            sender.server_name(),
+            exchange_invite::v1::Request {
+                room_id: room_id.to_owned(),
+                kind: StateEventType::RoomMember,
+                sender: sender.to_owned(),
+                state_key: invitee.to_owned(),
+                content,
+            },
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Checks that the identity server signed the invite of `invitee` with one of the keys of the
+/// m.room.third_party_invite event our user `sender` sent, then invites `invitee`.
+async fn exchange_third_party_invite(
+    room_id: &RoomId,
+    sender: &UserId,
+    invitee: &UserId,
+    signed: CanonicalJsonObject,
+) -> Result<()> {
+    #[derive(Deserialize)]
+    struct ExtractPublicKey {
+        public_key: String,
+    }
+
+    #[derive(Deserialize)]
+    struct ExtractThirdPartyInvite {
+        display_name: String,
+        public_key: Option<String>,
+        #[serde(default)]
+        public_keys: Vec<ExtractPublicKey>,
+    }
+
+    if !services().globals.server_is_ours(sender.server_name()) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "The inviter does not belong to this server.",
+        ));
+    }
+
+    let (token, mxid) = match (signed.get("token"), signed.get("mxid")) {
+        (Some(CanonicalJsonValue::String(token)), Some(CanonicalJsonValue::String(mxid))) => {
+            (token, mxid)
+        }
+        _ => {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Invalid signed block.",
+            ))
+        }
+    };
+
+    if mxid != invitee.as_str() {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "The invite was signed for another user.",
+        ));
+    }
+
+    let invite_event = services()
+        .rooms
+        .state_accessor
+        .room_state_get(room_id, &StateEventType::RoomThirdPartyInvite, token)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Unknown third party invite.",
+        ))?;
+
+    if &*invite_event.sender != sender {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The third party invite was sent by someone else.",
+        ));
+    }
+
+    let content: ExtractThirdPartyInvite = serde_json::from_str(invite_event.content.get())
+        .map_err(|_| Error::bad_database("Invalid third party invite event in database."))?;
+
+    let public_keys: Vec<_> = content
+        .public_key
+        .into_iter()
+        .chain(content.public_keys.into_iter().map(|key| key.public_key))
+        .collect();
+
+    if !identity_server::signed_by_any(&signed, &public_keys) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The third party invite is not signed by the identity server.",
+        ));
+    }
+
+    let third_party_invite = serde_json::from_value(serde_json::json!({
+        "display_name": content.display_name,
+        "signed": signed,
+    }))
+    .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid signed block."))?;
+
+    client_server::invite_helper(
+        sender,
+        invitee,
+        room_id,
+        None,
+        false,
+        Some(third_party_invite),
+    )
+    .await
+}
+
+/// # `GET /_matrix/federation/v1/user/devices/{userId}`
+///
+/// Gets information on all devices of the user.
+pub async fn get_devices_route(
+    body: Ruma<get_devices::v1::Request>,
+) -> Result<get_devices::v1::Response> {
+    if !services().globals.allow_federation() {
+        return Err(Error::bad_config("Federation is disabled."));
+    }
+
+    let sender_servername = body
+        .sender_servername
+        .as_ref()
+        .expect("server is authenticated");
+
+    Ok(get_devices::v1::Response {
+        user_id: body.user_id.clone(),
+        stream_id: services()
+            .users
+            .get_devicelist_version(&body.user_id)?
+            .unwrap_or(0)
+            .try_into()
+            .expect("version will not grow that large"),
+        devices: services()
+            .users
+            .all_devices_metadata(&body.user_id)
+            .filter_map(|r| r.ok())
+            .filter_map(|metadata| {
+                Some(UserDevice {
+                    keys: services()
+                        .users
+                        .get_device_keys(&body.user_id, &metadata.device_id)
+                        .ok()??,
+                    device_id: metadata.device_id,
+                    device_display_name: metadata.display_name,
+                })
+            })
+            .collect(),
+        master_key: services()
+            .users
             .get_master_key(&body.user_id, &|u| u.server_name() == sender_servername)?,
         self_signing_key: services()
             .users
@@ -1705,39 +2836,59 @@ pub async fn get_room_information_route(
 /// # `GET /_matrix/federation/v1/query/profile`
 ///
 /// Gets information on a profile.
+///
+/// - Returns only the requested `field`, or all fields if it's omitted. Unset fields are left out
+/// - Custom profile fields (MSC4133) are included, and can be asked for as `field`
+/// - 404 if the user doesn't exist on this server
 pub async fn get_profile_information_route(
     body: Ruma<get_profile_information::v1::Request>,
-) -> Result<get_profile_information::v1::Response> {
+) -> Result<Json<serde_json::Value>> {
     if !services().globals.allow_federation() {
         return Err(Error::bad_config("Federation is disabled."));
     }
 
-    let mut displayname = None;
-    let mut avatar_url = None;
-    let mut blurhash = None;
+    if !services()
+        .globals
+        .server_is_ours(body.user_id.server_name())
+        || !services().users.exists(&body.user_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Profile was not found.",
+        ));
+    }
 
-    match &body.field {
-        Some(ProfileField::DisplayName) => {
-            displayname = services().users.displayname(&body.user_id)?
-        }
-        Some(ProfileField::AvatarUrl) => {
-            avatar_url = services().users.avatar_url(&body.user_id)?;
-            blurhash = services().users.blurhash(&body.user_id)?
-        }
-        // TODO: what to do with custom
-        Some(_) => {}
-        None => {
-            displayname = services().users.displayname(&body.user_id)?;
-            avatar_url = services().users.avatar_url(&body.user_id)?;
-            blurhash = services().users.blurhash(&body.user_id)?;
+    Ok(Json(requested_profile_fields(
+        local_profile_json(&body.user_id)?,
+        body.field.as_ref(),
+    )))
+}
+
+/// Keeps only the field asked for in a federation profile query, or all fields if none was.
+/// Unset fields are not in the profile at all.
+fn requested_profile_fields(
+    profile: serde_json::Value,
+    field: Option<&ProfileField>,
+) -> serde_json::Value {
+    let field = match field {
+        Some(field) => field,
+        None => return profile,
+    };
+
+    let keys: &[&str] = match field {
+        ProfileField::DisplayName => &["displayname"],
+        ProfileField::AvatarUrl => &["avatar_url", "xyz.amorgan.blurhash"],
+        // Custom profile fields (MSC4133)
+        _ => &[field.as_str()],
+    };
+
+    match profile {
+        serde_json::Value::Object(mut profile) => {
+            profile.retain(|key, _| keys.contains(&key.as_str()));
+            serde_json::Value::Object(profile)
         }
+        profile => profile,
     }
-
-    Ok(get_profile_information::v1::Response {
-        blurhash,
-        displayname,
-        avatar_url,
-    })
 }
 
 /// # `POST /_matrix/federation/v1/user/keys/query`
@@ -1779,7 +2930,221 @@ pub async fn claim_keys_route(
 
 #[cfg(test)]
 mod tests {
-    use super::{add_port_to_hostname, get_ip_with_port, FedDest};
+    use super::{
+        acquire_bounded, add_port_to_hostname, get_event_route, get_ip_with_port,
+        get_profile_information_route, lacks_our_signature, missing_auth_events,
+        parse_unstable_features, parse_well_known, partial_join_state, requested_profile_fields,
+        send_transaction_message_route, server_keys_json, server_version_json, sign_request,
+        sort_auth_chain, timestamp_to_event_response, try_start_transaction, walk_missing_events,
+        well_known_ttl, FedDest, ProfileField, WELL_KNOWN_DEFAULT_TTL, WELL_KNOWN_MAX_TTL,
+    };
+    use crate::{service::rooms::timeline::nearest_visible, services, utils::testing};
+    use ruma::server_name;
+    use ruma::{
+        api::{
+            client::Direction,
+            federation::discovery::{OldVerifyKey, VerifyKey},
+        },
+        event_id,
+        serde::Base64,
+        signatures::Ed25519KeyPair,
+        uint, CanonicalJsonObject, MilliSecondsSinceUnixEpoch, OwnedEventId, UInt,
+    };
+    use std::{
+        collections::{BTreeMap, BTreeSet, HashMap},
+        sync::RwLock,
+        time::{Duration, SystemTime},
+    };
+    use tokio::sync::Semaphore;
+
+    #[test]
+    fn nearest_event_the_server_can_see_is_returned() {
+        let pdu = |event_id: &str, origin_server_ts: u64| -> crate::PduEvent {
+            serde_json::from_value(serde_json::json!({
+                "event_id": event_id,
+                "room_id": "!room:example.org",
+                "sender": "@alice:example.org",
+                "origin_server_ts": origin_server_ts,
+                "type": "m.room.message",
+                "content": { "msgtype": "m.text", "body": "Hello" },
+                "prev_events": [],
+                "depth": 1,
+                "auth_events": [],
+                "hashes": { "sha256": "" },
+            }))
+            .unwrap()
+        };
+        let pdus = vec![
+            pdu("$before:example.org", 900),
+            pdu("$hidden:example.org", 1100),
+            pdu("$visible:example.org", 1234),
+        ];
+        // Like server_can_see_event for a server that left before the hidden event
+        let server_can_see =
+            |event_id: &ruma::EventId| Ok(event_id != event_id!("$hidden:example.org"));
+        let closest = |dir| {
+            timestamp_to_event_response(
+                nearest_visible(pdus.clone(), uint!(1000), dir, server_can_see).unwrap(),
+            )
+        };
+
+        let response = closest(Direction::Forward).unwrap();
+        assert_eq!(response.event_id, event_id!("$visible:example.org"));
+        assert_eq!(
+            response.origin_server_ts,
+            MilliSecondsSinceUnixEpoch(uint!(1234))
+        );
+        assert_eq!(
+            closest(Direction::Backward).unwrap().event_id,
+            event_id!("$before:example.org")
+        );
+
+        let only_hidden = vec![pdu("$hidden:example.org", 1100)];
+        assert!(matches!(
+            timestamp_to_event_response(
+                nearest_visible(only_hidden, uint!(1000), Direction::Forward, server_can_see)
+                    .unwrap()
+            ),
+            Err(crate::Error::BadRequest(
+                ruma::api::client::error::ErrorKind::NotFound,
+                _
+            ))
+        ));
+    }
+
+    #[test]
+    fn well_known_delegates_to_another_host() {
+        assert_eq!(
+            parse_well_known(r#"{ "m.server": "matrix.example.org:443" }"#),
+            Some("matrix.example.org:443".to_owned())
+        );
+        assert_eq!(
+            add_port_to_hostname("matrix.example.org:443"),
+            FedDest::Named("matrix.example.org".to_owned(), ":443".to_owned())
+        );
+    }
+
+    #[test]
+    fn malformed_well_known_files_are_ignored() {
+        assert_eq!(parse_well_known("<html>Not found</html>"), None);
+        assert_eq!(parse_well_known(r#"{ "m.server": 42 }"#), None);
+        assert_eq!(parse_well_known(r#"{ "m.server": "not a host/" }"#), None);
+    }
+
+    #[test]
+    fn well_known_ttl_follows_cache_control() {
+        assert_eq!(well_known_ttl(None), WELL_KNOWN_DEFAULT_TTL);
+        assert_eq!(
+            well_known_ttl(Some("public, max-age=3600")),
+            Duration::from_secs(3600)
+        );
+        assert_eq!(well_known_ttl(Some("max-age=99999999")), WELL_KNOWN_MAX_TTL);
+    }
+
+    #[test]
+    fn remote_unstable_features_are_read_from_the_version() {
+        let features = parse_unstable_features(
+            br#"{
+                "server": { "name": "Synapse", "version": "1.70.0" },
+                "unstable_features": { "org.matrix.msc3706": true, "org.matrix.msc2716": false }
+            }"#,
+        );
+        assert_eq!(features.get("org.matrix.msc3706"), Some(&true));
+        assert_eq!(features.get("org.matrix.msc2716"), Some(&false));
+
+        assert!(parse_unstable_features(br#"{ "server": { "name": "Conduit" } }"#).is_empty());
+        assert!(parse_unstable_features(b"<html>Not found</html>").is_empty());
+        assert!(parse_unstable_features(br#"{ "unstable_features": { "a": "yes" } }"#).is_empty());
+    }
+
+    #[test]
+    fn version_advertises_enabled_features() {
+        let features = BTreeMap::from([
+            ("org.matrix.msc1234".to_owned(), true),
+            ("org.matrix.msc5678".to_owned(), false),
+        ]);
+
+        let version = server_version_json(&features);
+        assert_eq!(version["server"]["name"], "Conduit");
+        assert_eq!(version["unstable_features"]["org.matrix.msc1234"], true);
+        assert_eq!(version["unstable_features"]["org.matrix.msc5678"], false);
+    }
+
+    #[test]
+    fn concurrent_transactions_from_one_origin_are_limited() {
+        let transactions = RwLock::new(HashMap::new());
+        let origin = server_name!("example.org");
+        let other = server_name!("example.com");
+
+        let first = try_start_transaction(&transactions, origin, 1);
+        assert!(first.is_some());
+        assert!(try_start_transaction(&transactions, origin, 1).is_none());
+        // Other servers have their own limit
+        assert!(try_start_transaction(&transactions, other, 1).is_some());
+
+        drop(first);
+        assert!(try_start_transaction(&transactions, origin, 1).is_some());
+
+        // Servers without transactions in flight are forgotten
+        let origins = || {
+            transactions
+                .read()
+                .unwrap()
+                .keys()
+                .map(|server| server.to_string())
+                .collect::<Vec<_>>()
+        };
+        let in_flight = try_start_transaction(&transactions, origin, 1);
+        assert_eq!(origins(), vec![origin.to_string()]);
+        drop(in_flight);
+        let _in_flight = try_start_transaction(&transactions, other, 1);
+        assert_eq!(origins(), vec![other.to_string()]);
+    }
+
+    #[test]
+    fn missing_events_are_walked_in_topological_order() {
+        // A <- B <- C <- D, and B <- E <- D
+        let (a, b, c, d, e) = (
+            event_id!("$a:example.org").to_owned(),
+            event_id!("$b:example.org").to_owned(),
+            event_id!("$c:example.org").to_owned(),
+            event_id!("$d:example.org").to_owned(),
+            event_id!("$e:example.org").to_owned(),
+        );
+        let graph: HashMap<OwnedEventId, (UInt, Vec<OwnedEventId>)> = HashMap::from([
+            (a.clone(), (uint!(1), vec![])),
+            (b.clone(), (uint!(2), vec![a.clone()])),
+            (c.clone(), (uint!(3), vec![b.clone()])),
+            (e.clone(), (uint!(3), vec![b.clone()])),
+            (d.clone(), (uint!(4), vec![c.clone(), e.clone()])),
+        ]);
+        let walk = |earliest: &[OwnedEventId], limit, min_depth| {
+            walk_missing_events(&[d.clone()], earliest, limit, min_depth, |id| {
+                Ok(graph.get(id).cloned())
+            })
+            .unwrap()
+        };
+
+        assert_eq!(
+            walk(&[], 10, uint!(0)),
+            vec![a.clone(), b.clone(), c.clone(), e.clone()]
+        );
+        assert_eq!(walk(&[b.clone()], 10, uint!(0)), vec![c.clone(), e.clone()]);
+        assert_eq!(walk(&[], 10, uint!(2)), vec![b, c.clone(), e]);
+        assert_eq!(walk(&[], 1, uint!(0)), vec![c]);
+    }
+
+    #[tokio::test]
+    async fn requests_beyond_the_limit_wait_for_a_permit() {
+        let semaphore = Semaphore::new(1);
+        let wait = Duration::from_millis(50);
+
+        let first = acquire_bounded(&semaphore, wait).await.unwrap();
+        assert!(acquire_bounded(&semaphore, wait).await.is_err());
+
+        drop(first);
+        assert!(acquire_bounded(&semaphore, wait).await.is_ok());
+    }
 
     #[test]
     fn ips_get_default_ports() {
@@ -1820,4 +3185,772 @@ mod tests {
             FedDest::Named(String::from("example.com"), String::from(":1337"))
         )
     }
+
+    #[test]
+    fn our_events_must_be_signed_by_us() {
+        let event = |sender: &str, signatures: serde_json::Value| -> CanonicalJsonObject {
+            serde_json::from_value(serde_json::json!({
+                "sender": sender,
+                "signatures": signatures,
+            }))
+            .unwrap()
+        };
+        let ours = server_name!("example.org");
+
+        assert!(lacks_our_signature(
+            &event("@alice:example.org", serde_json::json!({})),
+            ours
+        ));
+        assert!(lacks_our_signature(
+            &event(
+                "@alice:example.org",
+                serde_json::json!({ "example.org": {} })
+            ),
+            ours
+        ));
+        assert!(!lacks_our_signature(
+            &event(
+                "@alice:example.org",
+                serde_json::json!({ "example.org": { "ed25519:key": "signature" } })
+            ),
+            ours
+        ));
+        // Events of other servers are served as they were received
+        assert!(!lacks_our_signature(
+            &event("@bob:remote.example", serde_json::json!({})),
+            ours
+        ));
+    }
+
+    #[test]
+    fn auth_chains_are_sorted_and_complete() {
+        let pdu = |event_id: &str, depth: u64, auth_events: &[&str]| {
+            let pdu: CanonicalJsonObject = serde_json::from_value(serde_json::json!({
+                "event_id": event_id,
+                "depth": depth,
+                "auth_events": auth_events,
+            }))
+            .unwrap();
+            (OwnedEventId::try_from(event_id).unwrap(), pdu)
+        };
+
+        let create = "$create:example.org";
+        let member = "$member:example.org";
+        let power_levels = "$power_levels:example.org";
+        let join_rules = "$join_rules:example.org";
+        let guest = "$guest:example.org";
+
+        // The power levels have a higher depth than events that depend on them, only the auth
+        // events decide the order
+        let auth_chain: BTreeMap<_, _> = [
+            pdu(guest, 3, &[create, power_levels, member, join_rules]),
+            pdu(join_rules, 2, &[create, power_levels, member]),
+            pdu(power_levels, 9, &[create, member]),
+            pdu(member, 1, &[create]),
+            pdu(create, 0, &[]),
+        ]
+        .into_iter()
+        .collect();
+
+        let event = pdu("$message:example.org", 10, &[create, power_levels, member]).1;
+        assert!(missing_auth_events(&event, &auth_chain).is_empty());
+
+        let sorted: Vec<_> = sort_auth_chain(auth_chain.clone())
+            .into_iter()
+            .map(|pdu| pdu["event_id"].as_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(
+            sorted,
+            [create, member, power_levels, join_rules, guest].map(ToOwned::to_owned)
+        );
+
+        // Auth events referenced anywhere in the chain must be part of it
+        let mut incomplete = auth_chain;
+        incomplete.remove(&OwnedEventId::try_from(member).unwrap());
+        assert_eq!(
+            missing_auth_events(&event, &incomplete),
+            BTreeSet::from([OwnedEventId::try_from(member).unwrap()])
+        );
+    }
+
+    #[test]
+    fn room_v1_auth_events_are_pairs() {
+        let event: CanonicalJsonObject = serde_json::from_value(serde_json::json!({
+            "auth_events": [["$create:example.org", { "sha256": "hash" }]],
+        }))
+        .unwrap();
+
+        assert_eq!(
+            missing_auth_events(&event, &BTreeMap::new()),
+            BTreeSet::from([OwnedEventId::try_from("$create:example.org").unwrap()])
+        );
+    }
+
+    #[test]
+    fn profile_queries_return_only_the_requested_field() {
+        let profile = serde_json::json!({
+            "displayname": "Alice",
+            "avatar_url": "mxc://example.org/abc",
+            "xyz.amorgan.blurhash": "LEHV6nWB2yk8",
+            "io.fsky.pronouns": "she/her",
+        });
+
+        assert_eq!(
+            requested_profile_fields(profile.clone(), Some(&ProfileField::DisplayName)),
+            serde_json::json!({ "displayname": "Alice" })
+        );
+        assert_eq!(
+            requested_profile_fields(profile.clone(), Some(&ProfileField::AvatarUrl)),
+            serde_json::json!({
+                "avatar_url": "mxc://example.org/abc",
+                "xyz.amorgan.blurhash": "LEHV6nWB2yk8",
+            })
+        );
+        assert_eq!(
+            requested_profile_fields(profile.clone(), Some(&"io.fsky.pronouns".into())),
+            serde_json::json!({ "io.fsky.pronouns": "she/her" })
+        );
+        assert_eq!(requested_profile_fields(profile.clone(), None), profile);
+
+        // Unset fields are left out
+        assert_eq!(
+            requested_profile_fields(
+                serde_json::json!({ "avatar_url": "mxc://example.org/abc" }),
+                Some(&ProfileField::DisplayName)
+            ),
+            serde_json::json!({})
+        );
+    }
+
+    #[test]
+    fn requests_for_alias_users_are_signed_as_the_alias() {
+        let keypair =
+            Ed25519KeyPair::from_der(&Ed25519KeyPair::generate().unwrap(), "key".into()).unwrap();
+        let mut request = http::Request::builder()
+            .method("PUT")
+            .uri("https://remote.example.org/_matrix/federation/v2/send_leave/!room:example.org/$leave")
+            .body(br#"{"type":"m.room.member"}"#.to_vec())
+            .unwrap();
+
+        sign_request(
+            &mut request,
+            server_name!("old.example.org"),
+            server_name!("remote.example.org"),
+            &keypair,
+        );
+
+        let header = request.headers()[http::header::AUTHORIZATION]
+            .to_str()
+            .unwrap();
+        assert!(header.starts_with("X-Matrix origin=old.example.org,key=\"ed25519:key\","));
+        let sig = header.split("sig=\"").nth(1).unwrap().trim_end_matches('"');
+
+        // What the remote server checks the signature against
+        let request_map: CanonicalJsonObject = serde_json::from_value(serde_json::json!({
+            "method": "PUT",
+            "uri": "/_matrix/federation/v2/send_leave/!room:example.org/$leave",
+            "origin": "old.example.org",
+            "destination": "remote.example.org",
+            "content": { "type": "m.room.member" },
+            "signatures": { "old.example.org": { "ed25519:key": sig } },
+        }))
+        .unwrap();
+        let public_keys = BTreeMap::from([(
+            "old.example.org".to_owned(),
+            BTreeMap::from([(
+                "ed25519:key".to_owned(),
+                Base64::new(keypair.public_key().to_vec()),
+            )]),
+        )]);
+        assert!(ruma::signatures::verify_json(&public_keys, &request_map).is_ok());
+    }
+
+    #[test]
+    fn rotated_keys_stay_published() {
+        let old =
+            Ed25519KeyPair::from_der(&Ed25519KeyPair::generate().unwrap(), "old".into()).unwrap();
+        let new =
+            Ed25519KeyPair::from_der(&Ed25519KeyPair::generate().unwrap(), "new".into()).unwrap();
+        let old_verify_key = OldVerifyKey::new(
+            MilliSecondsSinceUnixEpoch(uint!(1000)),
+            Base64::new(old.public_key().to_vec()),
+        );
+
+        let response = serde_json::to_value(server_keys_json(
+            server_name!("example.org").to_owned(),
+            &new,
+            BTreeMap::from([("ed25519:old".try_into().unwrap(), old_verify_key.clone())]),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(2),
+            Duration::from_secs(60),
+            None,
+        ))
+        .unwrap();
+
+        assert_eq!(
+            response["verify_keys"]["ed25519:new"],
+            serde_json::to_value(VerifyKey::new(Base64::new(new.public_key().to_vec()))).unwrap()
+        );
+        assert_eq!(
+            response["old_verify_keys"]["ed25519:old"],
+            serde_json::to_value(old_verify_key).unwrap()
+        );
+
+        // Signed with the new key only
+        let signatures = response["signatures"]["example.org"].as_object().unwrap();
+        assert!(signatures.contains_key("ed25519:new"));
+        assert!(!signatures.contains_key("ed25519:old"));
+    }
+
+    #[test]
+    fn key_validity_and_retention_follow_the_config() {
+        let keypair =
+            Ed25519KeyPair::from_der(&Ed25519KeyPair::generate().unwrap(), "new".into()).unwrap();
+        let old_verify_key = |expired_secs: u32| {
+            OldVerifyKey::new(
+                MilliSecondsSinceUnixEpoch(UInt::from(expired_secs * 1000)),
+                Base64::new(keypair.public_key().to_vec()),
+            )
+        };
+        let old_verify_keys = BTreeMap::from([
+            ("ed25519:older".try_into().unwrap(), old_verify_key(100)),
+            ("ed25519:old".try_into().unwrap(), old_verify_key(900)),
+        ]);
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+
+        let response = serde_json::to_value(server_keys_json(
+            server_name!("example.org").to_owned(),
+            &keypair,
+            old_verify_keys.clone(),
+            now,
+            Duration::from_secs(3600),
+            Some(Duration::from_secs(500)),
+        ))
+        .unwrap();
+
+        assert_eq!(response["valid_until_ts"], 4_600_000);
+        let old_keys = response["old_verify_keys"].as_object().unwrap();
+        assert!(old_keys.contains_key("ed25519:old"));
+        assert!(!old_keys.contains_key("ed25519:older"));
+
+        // Without a retention period old keys are kept forever
+        let response = serde_json::to_value(server_keys_json(
+            server_name!("example.org").to_owned(),
+            &keypair,
+            old_verify_keys,
+            now,
+            Duration::from_secs(3600),
+            None,
+        ))
+        .unwrap();
+        assert_eq!(response["old_verify_keys"].as_object().unwrap().len(), 2);
+    }
+
+    /// The members in the state of a partial state send_join response, and whether it has the
+    /// create event.
+    async fn partial_join_members(
+        room_id: &ruma::RoomId,
+        joining_user: &ruma::UserId,
+    ) -> (Vec<String>, bool) {
+        let shortstatehash = services()
+            .rooms
+            .state
+            .get_room_shortstatehash(room_id)
+            .unwrap()
+            .unwrap();
+        let state_ids = services()
+            .rooms
+            .state_accessor
+            .state_full_ids(shortstatehash)
+            .await
+            .unwrap();
+
+        let pdus = partial_join_state(room_id, state_ids, joining_user)
+            .unwrap()
+            .iter()
+            .map(|event_id| {
+                services()
+                    .rooms
+                    .timeline
+                    .get_pdu(event_id)
+                    .unwrap()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let mut members = pdus
+            .iter()
+            .filter(|pdu| pdu.kind == ruma::events::RoomEventType::RoomMember)
+            .map(|pdu| pdu.state_key.clone().unwrap())
+            .collect::<Vec<_>>();
+        members.sort();
+
+        (
+            members,
+            pdus.iter()
+                .any(|pdu| pdu.kind == ruma::events::RoomEventType::RoomCreate),
+        )
+    }
+
+    #[tokio::test]
+    async fn partial_join_state_leaves_out_other_members() {
+        let alice = testing::user("partial_join_alice").await;
+        let bob = testing::user("partial_join_bob").await;
+        let remote_user = ruma::user_id!("@carol:remote.test");
+
+        let named_room = testing::room_with(
+            &alice,
+            ruma::api::client::room::create_room::v3::Request {
+                name: Some("Named".to_owned()),
+                ..ruma::api::client::room::create_room::v3::Request::new()
+            },
+        )
+        .await;
+        testing::invite(&alice, &bob, &named_room).await;
+        testing::join(&bob, &named_room).await;
+
+        assert_eq!(
+            partial_join_members(&named_room, remote_user).await,
+            (Vec::new(), true)
+        );
+        // The joining user keeps its own membership, e.g. an invite
+        assert_eq!(
+            partial_join_members(&named_room, &bob).await,
+            (vec![bob.to_string()], true)
+        );
+
+        // Without a name the members are needed to name the room
+        let unnamed_room = testing::room(&alice).await;
+        testing::invite(&alice, &bob, &unnamed_room).await;
+        testing::join(&bob, &unnamed_room).await;
+
+        let mut members = vec![alice.to_string(), bob.to_string()];
+        members.sort();
+        assert_eq!(
+            partial_join_members(&unnamed_room, remote_user).await,
+            (members, true)
+        );
+    }
+
+    #[tokio::test]
+    async fn transactions_backfill_unknown_parents_from_the_origin() {
+        use axum::{routing::post, Json, Router};
+        use ruma::{
+            api::{client::room::create_room, federation::transactions::send_transaction_message},
+            events::StateEventType,
+            room::RoomPreset,
+            TransactionId,
+        };
+        use serde_json::{json, value::RawValue as RawJsonValue};
+
+        let alice = testing::user("backfill_alice").await;
+        let room_id = testing::room_with(
+            &alice,
+            create_room::v3::Request {
+                preset: Some(RoomPreset::PublicChat),
+                ..create_room::v3::Request::new()
+            },
+        )
+        .await;
+        let room_version = services().rooms.state.get_room_version(&room_id).unwrap();
+
+        let remote = testing::RemoteHomeserver::new("backfill.remote.test").await;
+        let dave = remote.user_id("dave");
+
+        let state_event = |event_type, state_key: &str| {
+            services()
+                .rooms
+                .state_accessor
+                .room_state_get(&room_id, &event_type, state_key)
+                .unwrap()
+                .unwrap()
+                .event_id
+                .to_string()
+        };
+        let create = state_event(StateEventType::RoomCreate, "");
+        let power_levels = state_event(StateEventType::RoomPowerLevels, "");
+        let join_rules = state_event(StateEventType::RoomJoinRules, "");
+
+        let extremities = services()
+            .rooms
+            .state
+            .get_forward_extremities(&room_id)
+            .unwrap();
+        let depth = extremities
+            .iter()
+            .map(|event_id| {
+                services()
+                    .rooms
+                    .timeline
+                    .get_pdu(event_id)
+                    .unwrap()
+                    .unwrap()
+                    .depth
+            })
+            .max()
+            .map(u64::from)
+            .unwrap();
+
+        // Later than the events of the room, older events aren't backfilled
+        let origin_server_ts = crate::utils::millis_since_unix_epoch() + 1000;
+        let event = |event_type: &str,
+                     state_key: Option<&str>,
+                     content: serde_json::Value,
+                     prev_events: Vec<String>,
+                     auth_events: Vec<String>,
+                     depth: u64| {
+            let mut event = json!({
+                "type": event_type,
+                "room_id": room_id,
+                "sender": dave,
+                "origin": remote.server_name,
+                "origin_server_ts": origin_server_ts,
+                "content": content,
+                "depth": depth,
+                "prev_events": prev_events,
+                "auth_events": auth_events,
+            });
+            if let Some(state_key) = state_key {
+                event["state_key"] = state_key.into();
+            }
+
+            remote.sign(event, &room_version)
+        };
+
+        let (join_id, join) = event(
+            "m.room.member",
+            Some(dave.as_str()),
+            json!({ "membership": "join" }),
+            extremities
+                .iter()
+                .map(|event_id| event_id.to_string())
+                .collect(),
+            vec![create.clone(), power_levels.clone(), join_rules],
+            depth + 1,
+        );
+        let message_auth_events = vec![create, power_levels, join_id.to_string()];
+        let (missing_id, missing) = event(
+            "m.room.message",
+            None,
+            json!({ "msgtype": "m.text", "body": "Missing" }),
+            vec![join_id.to_string()],
+            message_auth_events.clone(),
+            depth + 2,
+        );
+        let (latest_id, latest) = event(
+            "m.room.message",
+            None,
+            json!({ "msgtype": "m.text", "body": "Latest" }),
+            vec![missing_id.to_string()],
+            message_auth_events,
+            depth + 3,
+        );
+
+        // The remote only answers get_missing_events, so the parent can't come from anywhere else
+        remote
+            .serve(Router::new().route(
+                "/_matrix/federation/v1/get_missing_events/:room_id",
+                post(move || {
+                    let missing = missing.clone();
+                    async move { Json(json!({ "events": [missing] })) }
+                }),
+            ))
+            .await;
+
+        let send = |pdus: Vec<Box<RawJsonValue>>| {
+            let mut request = send_transaction_message::v1::Request::new(
+                TransactionId::new(),
+                remote.server_name.clone(),
+                MilliSecondsSinceUnixEpoch::now(),
+            );
+            request.pdus = pdus;
+
+            send_transaction_message_route(testing::federation_request(
+                request,
+                &remote.server_name,
+            ))
+        };
+
+        let response = send(vec![join]).await.unwrap();
+        assert!(response.pdus[&join_id].is_ok());
+        assert!(services()
+            .rooms
+            .state_cache
+            .is_joined(&dave, &room_id)
+            .unwrap());
+
+        let response = send(vec![latest]).await.unwrap();
+        assert!(response.pdus[&latest_id].is_ok());
+        for event_id in [&missing_id, &latest_id] {
+            assert!(services()
+                .rooms
+                .timeline
+                .get_pdu_id(event_id)
+                .unwrap()
+                .is_some());
+        }
+    }
+
+    #[test]
+    fn redirect_targets_are_checked_where_they_connect() {
+        let dest = |url: &str| fed_dest_of_url(&reqwest::Url::parse(url).unwrap()).unwrap();
+
+        assert_eq!(
+            dest("https://example.org/.well-known/matrix/server"),
+            FedDest::Named("example.org".to_owned(), ":443".to_owned())
+        );
+        assert_eq!(
+            dest("http://example.org:8080/"),
+            FedDest::Named("example.org".to_owned(), ":8080".to_owned())
+        );
+        assert_eq!(
+            dest("https://10.0.0.1/"),
+            FedDest::Literal("10.0.0.1:443".parse().unwrap())
+        );
+        assert_eq!(
+            dest("https://[::1]:8448/"),
+            FedDest::Literal("[::1]:8448".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn destinations_in_blocked_ranges_are_refused() {
+        testing::services_for_tests().await;
+
+        let literal = |addr: &str| FedDest::Literal(addr.parse().unwrap());
+
+        // Only 127.0.0.1 is whitelisted for the mocks of the tests
+        assert!(check_destination_ips(&literal("127.0.0.1:8448"))
+            .await
+            .is_ok());
+        assert!(check_destination_ips(&literal("127.0.0.2:8448"))
+            .await
+            .is_err());
+        assert!(check_destination_ips(&literal("10.1.2.3:8448"))
+            .await
+            .is_err());
+        assert!(check_destination_ips(&literal("[::1]:8448")).await.is_err());
+        assert!(check_destination_ips(&literal("1.1.1.1:8448"))
+            .await
+            .is_ok());
+
+        // Names are checked by the addresses they resolve to, here the ones of an SRV record
+        services()
+            .globals
+            .tls_name_override
+            .write()
+            .unwrap()
+            .insert(
+                "internal.remote.test".to_owned(),
+                (
+                    vec!["127.0.0.1".parse().unwrap(), "10.0.0.1".parse().unwrap()],
+                    8448,
+                ),
+            );
+        assert!(check_destination_ips(&FedDest::Named(
+            "internal.remote.test".to_owned(),
+            ":8448".to_owned()
+        ))
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn users_on_alias_names_are_local() {
+        let alice = testing::user("alias_alice").await;
+        let room_id = testing::room(&alice).await;
+
+        let aliased =
+            ruma::UserId::parse(format!("@alias_bob:{}", testing::ALIAS_SERVER_NAME)).unwrap();
+        services().users.create(&aliased, Some("password")).unwrap();
+        services()
+            .users
+            .create_device(&aliased, &testing::device_id(), "alias_bob_token", None)
+            .unwrap();
+        services()
+            .users
+            .set_displayname(&aliased, Some("Bob".to_owned()))
+            .unwrap();
+
+        // Invites and joins are handled here, without asking another server
+        testing::invite(&alice, &aliased, &room_id).await;
+        testing::join(&aliased, &room_id).await;
+        assert!(services()
+            .rooms
+            .state_cache
+            .is_joined(&aliased, &room_id)
+            .unwrap());
+
+        // Other servers can ask for the profile under the alias name
+        let profile = get_profile_information_route(testing::federation_request(
+            ruma::api::federation::query::get_profile_information::v1::Request::new(
+                aliased.clone(),
+            ),
+            server_name!("profile.remote.test"),
+        ))
+        .await
+        .unwrap()
+        .0;
+        assert_eq!(profile["displayname"], "Bob");
+    }
+
+    #[tokio::test]
+    async fn federated_invites_stop_at_the_limits() {
+        let remote = testing::RemoteHomeserver::new("invites.remote.test").await;
+        let room_version = ruma::RoomVersionId::V9;
+        let invite = |room: &str, user_id: &ruma::UserId| {
+            let room_id = ruma::RoomId::parse(format!("!{room}:invites.remote.test")).unwrap();
+            let (event_id, event) = remote.sign(
+                serde_json::json!({
+                    "room_id": room_id,
+                    "sender": remote.user_id("inviter"),
+                    "origin": remote.server_name,
+                    "origin_server_ts": 1,
+                    "type": "m.room.member",
+                    "state_key": user_id,
+                    "content": { "membership": "invite" },
+                    "depth": 5,
+                    "prev_events": [],
+                    "auth_events": [],
+                }),
+                &room_version,
+            );
+            create_invite_route(testing::federation_request(
+                ruma::api::federation::membership::create_invite::v2::Request {
+                    room_id,
+                    event_id,
+                    room_version: room_version.clone(),
+                    event,
+                    invite_room_state: Vec::new(),
+                },
+                &remote.server_name,
+            ))
+        };
+
+        // The test config allows two pending invites per user
+        let carol = testing::user("federated_invites_carol").await;
+        invite("first", &carol).await.unwrap();
+        invite("second", &carol).await.unwrap();
+        assert!(matches!(
+            invite("third", &carol).await,
+            Err(crate::Error::BadRequest(
+                ruma::api::client::error::ErrorKind::LimitExceeded { .. },
+                _
+            ))
+        ));
+
+        // ... and three invited users per room
+        for localpart in ["federated_invites_dave", "federated_invites_erin"] {
+            let user_id = testing::user(localpart).await;
+            invite("first", &user_id).await.unwrap();
+        }
+        let frank = testing::user("federated_invites_frank").await;
+        assert!(matches!(
+            invite("first", &frank).await,
+            Err(crate::Error::BadRequest(
+                ruma::api::client::error::ErrorKind::Forbidden,
+                _
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn servers_in_the_room_find_events_by_timestamp() {
+        let alice = testing::user("federation_timestamp_alice").await;
+        let room_id = testing::room(&alice).await;
+        let before = testing::send_message(&alice, &room_id, "Before").await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let between = MilliSecondsSinceUnixEpoch::now();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let after = testing::send_message(&alice, &room_id, "After").await;
+
+        let member = server_name!("timestamp.remote.test");
+        let erin = ruma::user_id!("@erin:timestamp.remote.test");
+        services()
+            .rooms
+            .state_cache
+            .update_membership(
+                &room_id,
+                erin,
+                ruma::events::room::member::MembershipState::Join,
+                erin,
+                None,
+                true,
+            )
+            .unwrap();
+
+        let closest = |dir, origin| {
+            get_event_by_timestamp_route(testing::federation_request(
+                ruma::api::federation::event::get_event_by_timestamp::v1::Request::new(
+                    room_id.clone(),
+                    between,
+                    dir,
+                ),
+                origin,
+            ))
+        };
+
+        assert_eq!(
+            closest(Direction::Forward, member).await.unwrap().event_id,
+            after
+        );
+        assert_eq!(
+            closest(Direction::Backward, member).await.unwrap().event_id,
+            before
+        );
+
+        // Servers without members don't learn anything about the room
+        assert!(matches!(
+            closest(Direction::Forward, server_name!("stranger.remote.test")).await,
+            Err(crate::Error::BadRequest(
+                ruma::api::client::error::ErrorKind::Forbidden,
+                _
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn events_are_only_served_to_servers_in_the_room() {
+        let alice = testing::user("federation_event_alice").await;
+        let room_id = testing::room(&alice).await;
+        let event_id = testing::send_message(&alice, &room_id, "Members only").await;
+
+        let get_event = |origin| {
+            get_event_route(testing::federation_request(
+                ruma::api::federation::event::get_event::v1::Request::new(event_id.clone()),
+                origin,
+            ))
+        };
+
+        // A server without members gets the same answer as for an unknown event
+        let stranger = server_name!("stranger.remote.test");
+        assert!(matches!(
+            get_event(stranger).await,
+            Err(crate::Error::BadRequest(
+                ruma::api::client::error::ErrorKind::NotFound,
+                _
+            ))
+        ));
+
+        let member = server_name!("member.remote.test");
+        let erin = ruma::user_id!("@erin:member.remote.test");
+        services()
+            .rooms
+            .state_cache
+            .update_membership(
+                &room_id,
+                erin,
+                ruma::events::room::member::MembershipState::Join,
+                erin,
+                None,
+                true,
+            )
+            .unwrap();
+
+        let response = get_event(member).await.unwrap();
+        let pdu: serde_json::Value = serde_json::from_str(response.pdu.get()).unwrap();
+        assert_eq!(pdu["content"]["body"], "Members only");
+        assert!(pdu["signatures"][testing::SERVER_NAME].is_object());
+        assert!(pdu["hashes"]["sha256"].is_string());
+        assert!(get_event(stranger).await.is_err());
+    }
 }
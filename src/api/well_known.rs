@@ -0,0 +1,93 @@
+//! Delegation files below `/.well-known/matrix/`, which let other servers and clients reach this
+//! server on a different host or port than the one in its server name.
+
+use axum::{http::header::CACHE_CONTROL, response::IntoResponse, Json};
+use ruma::api::client::error::ErrorKind;
+use serde_json::{json, Value};
+
+use crate::{services, Error, Result};
+
+/// Clients and servers cache the files for a day.
+const CACHE_FOR_A_DAY: &str = "public, max-age=86400";
+
+/// # `GET /.well-known/matrix/server`
+///
+/// Tells other servers where to send federation requests.
+///
+/// - Returns 404 if `well_known_server` is not configured
+pub async fn well_known_server_route() -> Result<impl IntoResponse> {
+    let server = services()
+        .globals
+        .config
+        .well_known_server
+        .as_deref()
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Not found."))?;
+
+    Ok((
+        [(CACHE_CONTROL, CACHE_FOR_A_DAY)],
+        Json(server_json(server)),
+    ))
+}
+
+/// # `GET /.well-known/matrix/client`
+///
+/// Tells clients which base URL to use for this server.
+///
+/// - Returns 404 if `well_known_client` is not configured
+pub async fn well_known_client_route() -> Result<impl IntoResponse> {
+    let config = &services().globals.config;
+    let base_url = config
+        .well_known_client
+        .as_deref()
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Not found."))?;
+
+    Ok((
+        [(CACHE_CONTROL, CACHE_FOR_A_DAY)],
+        Json(client_json(
+            base_url,
+            config.well_known_identity_server.as_deref(),
+        )),
+    ))
+}
+
+fn server_json(server: &str) -> Value {
+    json!({ "m.server": server })
+}
+
+fn client_json(base_url: &str, identity_server: Option<&str>) -> Value {
+    let mut client = json!({
+        "m.homeserver": { "base_url": base_url },
+    });
+
+    if let Some(identity_server) = identity_server {
+        client["m.identity_server"] = json!({ "base_url": identity_server });
+    }
+
+    client
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn files_have_the_expected_shape() {
+        assert_eq!(
+            server_json("matrix.example.org:443"),
+            json!({ "m.server": "matrix.example.org:443" })
+        );
+
+        assert_eq!(
+            client_json("https://matrix.example.org", None),
+            json!({ "m.homeserver": { "base_url": "https://matrix.example.org" } })
+        );
+
+        assert_eq!(
+            client_json("https://matrix.example.org", Some("https://vector.im")),
+            json!({
+                "m.homeserver": { "base_url": "https://matrix.example.org" },
+                "m.identity_server": { "base_url": "https://vector.im" },
+            })
+        );
+    }
+}
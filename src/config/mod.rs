@@ -4,9 +4,15 @@ use std::{
     net::{IpAddr, Ipv4Addr},
 };
 
-use ruma::{OwnedServerName, RoomVersionId};
+use ruma::{
+    events::room::power_levels::RoomPowerLevelsEventContent, int, serde::JsonObject, Int,
+    OwnedMxcUri, OwnedServerName, OwnedUserId, RoomVersionId,
+};
 use serde::{de::IgnoredAny, Deserialize};
-use tracing::warn;
+use serde_json::Value as JsonValue;
+use tracing::{error, warn};
+
+use crate::{utils::ip_range::DEFAULT_IP_RANGE_BLACKLIST, Error, Result};
 
 mod proxy;
 
@@ -19,41 +25,165 @@ pub struct Config {
     #[serde(default = "default_port")]
     pub port: u16,
     pub tls: Option<TlsConfig>,
+    #[serde(default = "true_fn")]
+    pub http2_enabled: bool,
+    #[serde(default = "false_fn")]
+    pub allow_h2c: bool,
+    pub http2_max_concurrent_streams: Option<u32>,
+    pub tcp_keepalive_secs: Option<u64>,
+    pub http_idle_timeout_secs: Option<u64>,
+    #[serde(default = "true_fn")]
+    pub compression_enabled: bool,
+    pub compression_level: Option<u32>,
+    #[serde(default = "default_compression_excluded_content_types")]
+    pub compression_excluded_content_types: Vec<String>,
 
     pub server_name: OwnedServerName,
+    #[serde(default = "Vec::new")]
+    pub server_name_aliases: Vec<OwnedServerName>,
     #[serde(default = "default_database_backend")]
     pub database_backend: String,
     pub database_path: String,
+    #[serde(default)]
+    pub database_durability: DatabaseDurability,
+    #[serde(default = "default_database_flush_interval_secs")]
+    pub database_flush_interval_secs: u64,
+    pub backup_path: Option<String>,
+    pub backup_schedule: Option<String>,
     #[serde(default = "default_db_cache_capacity_mb")]
     pub db_cache_capacity_mb: f64,
     #[serde(default = "true_fn")]
     pub enable_lightning_bolt: bool,
+    pub default_displayname_template: Option<String>,
+    pub default_avatar_url: Option<OwnedMxcUri>,
     #[serde(default = "default_conduit_cache_capacity_modifier")]
     pub conduit_cache_capacity_modifier: f64,
     #[serde(default = "default_rocksdb_max_open_files")]
     pub rocksdb_max_open_files: i32,
-    #[serde(default = "default_pdu_cache_capacity")]
+    #[serde(default = "default_pdu_cache_capacity", alias = "event_cache_capacity")]
     pub pdu_cache_capacity: u32,
     #[serde(default = "default_cleanup_second_interval")]
     pub cleanup_second_interval: u32,
     #[serde(default = "default_max_request_size")]
     pub max_request_size: u32,
+    #[serde(default = "default_max_client_request_size")]
+    pub max_client_request_size: u32,
+    pub max_media_upload_size: Option<u32>,
+    pub max_federation_request_size: Option<u32>,
+    #[serde(default)]
+    pub message_rate_limit_per_second: f64,
+    #[serde(default = "default_message_rate_limit_burst")]
+    pub message_rate_limit_burst: u32,
+    #[serde(default = "default_max_concurrent_syncs")]
+    pub max_concurrent_syncs: usize,
+    #[serde(default = "default_sync_room_concurrency")]
+    pub sync_room_concurrency: usize,
+    #[serde(default = "default_sync_timeline_limit_default")]
+    pub sync_timeline_limit_default: usize,
+    #[serde(default = "default_sync_timeline_limit_max")]
+    pub sync_timeline_limit_max: usize,
+    pub sync_response_max_bytes: Option<usize>,
     #[serde(default = "default_max_concurrent_requests")]
     pub max_concurrent_requests: u16,
-    #[serde(default = "default_max_fetch_prev_events")]
+    #[serde(default = "default_max_concurrent_transactions_per_origin")]
+    pub max_concurrent_transactions_per_origin: u16,
+    #[serde(
+        default = "default_max_fetch_prev_events",
+        alias = "backfill_max_events"
+    )]
     pub max_fetch_prev_events: u16,
+    #[serde(default = "default_backfill_max_depth")]
+    pub backfill_max_depth: u64,
+    #[serde(default = "default_federation_keys_timeout_secs")]
+    pub federation_keys_timeout_secs: u64,
+    #[serde(default = "default_sync_db_retries")]
+    pub sync_db_retries: u32,
+    #[serde(default = "default_key_validity_period_secs")]
+    pub key_validity_period_secs: u64,
+    pub old_key_retention_secs: Option<u64>,
+    #[serde(default = "default_federation_timeout_ms")]
+    pub federation_timeout_ms: u64,
+    pub request_timeout_ms: Option<u64>,
+    #[serde(default = "default_request_timeout_excluded_paths")]
+    pub request_timeout_excluded_paths: Vec<String>,
+    #[serde(default = "default_max_pdu_size")]
+    pub max_pdu_size: usize,
+    #[serde(default = "default_max_pdu_prev_events")]
+    pub max_pdu_prev_events: usize,
+    #[serde(default = "default_max_pdu_auth_events")]
+    pub max_pdu_auth_events: usize,
     #[serde(default = "false_fn")]
     pub allow_registration: bool,
+    #[serde(default = "false_fn")]
+    pub registration_requires_email: bool,
+    #[serde(default = "false_fn")]
+    pub registration_requires_msisdn: bool,
+    #[serde(default = "Vec::new")]
+    pub forbidden_usernames: Vec<String>,
+    #[serde(default = "true_fn")]
+    pub allow_password_change: bool,
+    #[serde(default = "true_fn")]
+    pub allow_displayname_change: bool,
+    #[serde(default = "true_fn")]
+    pub allow_avatar_change: bool,
+    #[serde(default = "true_fn")]
+    pub allow_3pid_changes: bool,
     #[serde(default = "true_fn")]
     pub allow_encryption: bool,
+    #[serde(default)]
+    pub encryption_enabled_by_default_for_room_type: EncryptionDefault,
+    #[serde(default = "true_fn")]
+    pub forbid_disabling_encryption: bool,
     #[serde(default = "false_fn")]
     pub allow_federation: bool,
     #[serde(default = "true_fn")]
+    pub trust_own_signatures: bool,
+    #[serde(default = "true_fn")]
     pub allow_room_creation: bool,
     #[serde(default = "true_fn")]
+    pub allow_presence: bool,
+    #[serde(default = "default_presence_idle_timeout_secs")]
+    pub presence_idle_timeout_secs: u64,
+    #[serde(default = "default_presence_offline_timeout_secs")]
+    pub presence_offline_timeout_secs: u64,
+    #[serde(default = "true_fn")]
     pub allow_unstable_room_versions: bool,
+    #[serde(default = "false_fn")]
+    pub user_directory_search_all_users: bool,
+    #[serde(default = "false_fn")]
+    pub room_list_publication_default: bool,
+    #[serde(default = "false_fn")]
+    pub room_list_publication_requires_admin: bool,
+    #[serde(default = "true_fn")]
+    pub allow_public_room_directory_without_auth: bool,
+    #[serde(default = "true_fn", alias = "federation_allow_public_rooms")]
+    pub allow_public_room_directory_over_federation: bool,
+    #[serde(default = "true_fn")]
+    pub federation_allow_joins: bool,
+    #[serde(default = "true_fn")]
+    pub federation_allow_invites: bool,
+    #[serde(default = "false_fn")]
+    pub partial_state_joins: bool,
+    pub per_user_media_quota_bytes: Option<u64>,
+    pub max_rooms_per_user_create: Option<u64>,
+    pub max_rooms_per_user_join: Option<u64>,
+    pub max_rooms_created_per_hour: Option<u32>,
+    pub max_outstanding_invites_per_user: Option<u64>,
+    pub max_invites_per_room: Option<u64>,
+    #[serde(default = "false_fn")]
+    pub url_preview_enabled: bool,
+    #[serde(default = "Vec::new")]
+    pub url_preview_url_blacklist: Vec<String>,
+    #[serde(default = "Vec::new")]
+    pub url_preview_ip_blacklist: Vec<String>,
+    #[serde(default = "default_url_preview_max_spider_size")]
+    pub url_preview_max_spider_size: usize,
     #[serde(default = "default_default_room_version")]
     pub default_room_version: RoomVersionId,
+    #[serde(default)]
+    pub default_power_levels: JsonObject,
+    #[serde(default = "false_fn")]
+    pub maintenance_mode: bool,
     #[serde(default = "false_fn")]
     pub allow_jaeger: bool,
     #[serde(default = "false_fn")]
@@ -61,8 +191,23 @@ pub struct Config {
     #[serde(default)]
     pub proxy: ProxyConfig,
     pub jwt_secret: Option<String>,
+    pub access_token_lifetime: Option<u64>,
+    pub session_idle_expiry: Option<u64>,
     #[serde(default = "Vec::new")]
     pub trusted_servers: Vec<OwnedServerName>,
+    #[serde(default = "default_federation_ip_blacklist")]
+    pub federation_ip_blacklist: Vec<String>,
+    #[serde(default = "Vec::new")]
+    pub federation_ip_whitelist: Vec<String>,
+    #[serde(default = "default_trusted_proxies")]
+    pub trusted_proxies: Vec<String>,
+    #[serde(default)]
+    pub trusted_proxy_header: ForwardingHeader,
+    #[serde(default)]
+    pub unstable_features: BTreeMap<String, bool>,
+    pub well_known_server: Option<String>,
+    pub well_known_client: Option<String>,
+    pub well_known_identity_server: Option<String>,
     #[serde(default = "default_log")]
     pub log: String,
     #[serde(default)]
@@ -75,9 +220,28 @@ pub struct Config {
     pub turn_secret: String,
     #[serde(default = "default_turn_ttl")]
     pub turn_ttl: u64,
+    pub turn: Option<TurnConfig>,
+
+    #[serde(default = "default_argon2_memory")]
+    pub argon2_memory: u32,
+    #[serde(default = "default_argon2_iterations")]
+    pub argon2_iterations: u32,
 
     pub emergency_password: Option<String>,
 
+    #[serde(default = "default_server_user_localpart")]
+    pub server_user_localpart: String,
+    #[serde(default = "true_fn")]
+    pub admin_room_enabled: bool,
+    pub forget_abandoned_rooms_after_secs: Option<u64>,
+    pub max_event_delay_secs: Option<u64>,
+    #[serde(default = "default_max_delayed_events_per_user")]
+    pub max_delayed_events_per_user: usize,
+    #[serde(default)]
+    pub identity_server: IdentityServerConfig,
+    pub email: Option<EmailConfig>,
+    pub captcha: Option<CaptchaConfig>,
+
     #[serde(flatten)]
     pub catchall: BTreeMap<String, IgnoredAny>,
 }
@@ -88,6 +252,165 @@ pub struct TlsConfig {
     pub key: String,
 }
 
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct IdentityServerConfig {
+    /// Identity servers clients may ask us to use for third party invites, as hostname and
+    /// optional port. Third party invites are refused if it's empty.
+    #[serde(default = "Vec::new")]
+    pub trusted_servers: Vec<String>,
+    /// Identity server that texts validation tokens for phone numbers on our behalf. Phone
+    /// numbers are refused if it or `msisdn_delegate_access_token` is not set.
+    pub msisdn_delegate: Option<String>,
+    /// Access token of the account of this server at `msisdn_delegate`, for its v2 API.
+    pub msisdn_delegate_access_token: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct EmailConfig {
+    pub smtp_host: String,
+    /// Defaults to the usual port of the TLS mode.
+    pub smtp_port: Option<u16>,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    /// The sender of all emails, like `Conduit <noreply@example.org>`.
+    pub from: String,
+    /// Templates of the email sent to validate an address. `{server_name}`, `{link}` and `{token}`
+    /// are replaced.
+    #[serde(default = "default_verification_subject")]
+    pub verification_subject: String,
+    #[serde(default = "default_verification_text")]
+    pub verification_text: String,
+    #[serde(default = "default_verification_html")]
+    pub verification_html: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Plain connection, only for a relay on the same host.
+    None,
+    #[default]
+    Starttls,
+    /// TLS from the start, usually on port 465.
+    Tls,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CaptchaConfig {
+    pub provider: CaptchaProvider,
+    /// The public key clients show the captcha with.
+    pub site_key: String,
+    pub secret: String,
+    /// Defaults to the siteverify endpoint of the provider.
+    pub verify_url: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptchaProvider {
+    Recaptcha,
+    Hcaptcha,
+}
+
+impl CaptchaProvider {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CaptchaProvider::Recaptcha => "recaptcha",
+            CaptchaProvider::Hcaptcha => "hcaptcha",
+        }
+    }
+
+    pub fn verify_url(&self) -> &'static str {
+        match self {
+            CaptchaProvider::Recaptcha => "https://www.google.com/recaptcha/api/siteverify",
+            CaptchaProvider::Hcaptcha => "https://api.hcaptcha.com/siteverify",
+        }
+    }
+}
+
+/// When writes to the database are synced to disk.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseDurability {
+    /// Every write is synced before it returns.
+    Full,
+    /// Writes are synced about once a second, a crash of the machine can lose the writes since.
+    #[default]
+    Relaxed,
+}
+
+impl DatabaseDurability {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DatabaseDurability::Full => "full",
+            DatabaseDurability::Relaxed => "relaxed",
+        }
+    }
+}
+
+/// The header the `trusted_proxies` put the address of the client into.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardingHeader {
+    #[default]
+    XForwardedFor,
+    /// The standard header of RFC 7239.
+    Forwarded,
+}
+
+impl ForwardingHeader {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ForwardingHeader::XForwardedFor => "x-forwarded-for",
+            ForwardingHeader::Forwarded => "forwarded",
+        }
+    }
+}
+
+/// Which new rooms get an `m.room.encryption` event without the creator asking for one.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EncryptionDefault {
+    #[default]
+    Off,
+    /// Rooms created with the private_chat or trusted_private_chat preset.
+    #[serde(alias = "invited")]
+    Invite,
+    /// Rooms created with `is_direct`.
+    Direct,
+    All,
+}
+
+impl EncryptionDefault {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EncryptionDefault::Off => "off",
+            EncryptionDefault::Invite => "invite",
+            EncryptionDefault::Direct => "direct",
+            EncryptionDefault::All => "all",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TurnConfig {
+    #[serde(default = "Vec::new")]
+    pub uris: Vec<String>,
+    /// The static-auth-secret of coturn, used to generate time-limited credentials.
+    #[serde(default)]
+    pub shared_secret: String,
+    /// Static credentials, only used if there is no shared secret.
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+    /// How long generated credentials are valid, in seconds.
+    #[serde(default = "default_turn_ttl")]
+    pub ttl: u64,
+}
+
 const DEPRECATED_KEYS: &[&str] = &["cache_capacity"];
 
 impl Config {
@@ -106,15 +429,150 @@ impl Config {
             warn!("Read conduit documentation and check your configuration if any new configuration parameters should be adjusted");
         }
     }
+
+    pub fn max_media_upload_size(&self) -> u32 {
+        self.max_media_upload_size.unwrap_or(self.max_request_size)
+    }
+
+    pub fn max_federation_request_size(&self) -> u32 {
+        self.max_federation_request_size
+            .unwrap_or(self.max_request_size)
+    }
+
+    /// The TURN settings of the `[global.turn]` block, or of the `turn_*` keys if it's missing.
+    pub fn turn(&self) -> TurnConfig {
+        self.turn.clone().unwrap_or_else(|| TurnConfig {
+            uris: self.turn_uris.clone(),
+            shared_secret: self.turn_secret.clone(),
+            username: self.turn_username.clone(),
+            password: self.turn_password.clone(),
+            ttl: self.turn_ttl,
+        })
+    }
+
+    /// Returns the power levels of a new room before the client's `power_level_content_override`
+    /// is applied: the spec defaults with `default_power_levels` on top. Maps like `events` are
+    /// merged key by key, so configuring one event type keeps the defaults of the others.
+    pub fn new_room_power_levels(&self, users: BTreeMap<OwnedUserId, Int>) -> JsonValue {
+        let mut content = serde_json::to_value(RoomPowerLevelsEventContent {
+            users,
+            ..Default::default()
+        })
+        .expect("event is valid, we just created it");
+
+        for (key, value) in &self.default_power_levels {
+            match (&mut content[key], value) {
+                (JsonValue::Object(existing), JsonValue::Object(value)) => {
+                    existing.extend(value.clone());
+                }
+                (existing, value) => *existing = value.clone(),
+            }
+        }
+
+        content
+    }
+
+    /// Checks that `default_power_levels` are valid power levels that leave the room creator, who
+    /// has power level 100, able to do everything in the room.
+    pub fn check_default_power_levels(&self) -> Result<()> {
+        if self.default_power_levels.contains_key("users") {
+            return Err(Error::bad_config(
+                "default_power_levels can't set the power levels of users.",
+            ));
+        }
+
+        let content: RoomPowerLevelsEventContent =
+            serde_json::from_value(self.new_room_power_levels(BTreeMap::new())).map_err(|e| {
+                error!("Invalid default_power_levels: {}", e);
+                Error::bad_config("Invalid default_power_levels.")
+            })?;
+
+        let creator_level = int!(100);
+        if [
+            content.ban,
+            content.kick,
+            content.redact,
+            content.invite,
+            content.state_default,
+            content.events_default,
+            content.users_default,
+            content.notifications.room,
+        ]
+        .iter()
+        .chain(content.events.values())
+        .any(|level| *level > creator_level)
+        {
+            return Err(Error::bad_config(
+                "default_power_levels can't require more than the room creator's power level of 100.",
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 impl fmt::Display for Config {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let turn = self.turn();
+
         // Prepare a list of config values to show
         let lines = [
             ("Server name", self.server_name.host()),
+            ("HTTP/2 enabled", &self.http2_enabled.to_string()),
+            ("HTTP/2 without TLS (h2c)", &self.allow_h2c.to_string()),
+            (
+                "HTTP/2 max concurrent streams",
+                &self
+                    .http2_max_concurrent_streams
+                    .map_or_else(|| "default".to_owned(), |streams| streams.to_string()),
+            ),
+            (
+                "TCP keepalive (seconds)",
+                &self
+                    .tcp_keepalive_secs
+                    .map_or_else(|| "disabled".to_owned(), |secs| secs.to_string()),
+            ),
+            ("Compression enabled", &self.compression_enabled.to_string()),
+            (
+                "Compression level",
+                &self
+                    .compression_level
+                    .map_or_else(|| "default".to_owned(), |level| level.to_string()),
+            ),
+            (
+                "Content types that are never compressed",
+                &self.compression_excluded_content_types.join(", "),
+            ),
+            (
+                "HTTP idle timeout (seconds)",
+                &self
+                    .http_idle_timeout_secs
+                    .map_or_else(|| "disabled".to_owned(), |secs| secs.to_string()),
+            ),
+            (
+                "Server name aliases",
+                &self
+                    .server_name_aliases
+                    .iter()
+                    .map(|name| name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
             ("Database backend", &self.database_backend),
             ("Database path", &self.database_path),
+            ("Database durability", self.database_durability.as_str()),
+            (
+                "Database flush interval in seconds",
+                &self.database_flush_interval_secs.to_string(),
+            ),
+            (
+                "Backup path",
+                self.backup_path.as_deref().unwrap_or("not set"),
+            ),
+            (
+                "Backup schedule",
+                self.backup_schedule.as_deref().unwrap_or("not set"),
+            ),
             (
                 "Database cache capacity (MB)",
                 &self.db_cache_capacity_mb.to_string(),
@@ -134,18 +592,278 @@ impl fmt::Display for Config {
                 &self.cleanup_second_interval.to_string(),
             ),
             ("Maximum request size", &self.max_request_size.to_string()),
+            (
+                "Maximum client request size",
+                &self.max_client_request_size.to_string(),
+            ),
+            (
+                "Maximum media upload size",
+                &self.max_media_upload_size().to_string(),
+            ),
+            (
+                "Maximum federation request size",
+                &self.max_federation_request_size().to_string(),
+            ),
             (
                 "Maximum concurrent requests",
                 &self.max_concurrent_requests.to_string(),
             ),
+            (
+                "Rooms loaded concurrently per sync",
+                &self.sync_room_concurrency.to_string(),
+            ),
+            (
+                "Sync timeline limit",
+                &format!(
+                    "{} by default, at most {}",
+                    self.sync_timeline_limit_default, self.sync_timeline_limit_max
+                ),
+            ),
+            (
+                "Sync response max bytes",
+                &self
+                    .sync_response_max_bytes
+                    .map_or_else(|| "unlimited".to_owned(), |bytes| bytes.to_string()),
+            ),
+            (
+                "Message rate limit per room",
+                &format!(
+                    "{}/s, burst {}",
+                    self.message_rate_limit_per_second, self.message_rate_limit_burst
+                ),
+            ),
+            (
+                "Maximum concurrent syncs per user",
+                &self.max_concurrent_syncs.to_string(),
+            ),
+            (
+                "Maximum concurrent transactions per server",
+                &self.max_concurrent_transactions_per_origin.to_string(),
+            ),
+            (
+                "Backfill limit (events)",
+                &self.max_fetch_prev_events.to_string(),
+            ),
+            (
+                "Backfill limit (depth)",
+                &self.backfill_max_depth.to_string(),
+            ),
+            (
+                "Federation key query timeout (seconds)",
+                &self.federation_keys_timeout_secs.to_string(),
+            ),
+            (
+                "Sync retries on database errors",
+                &self.sync_db_retries.to_string(),
+            ),
+            (
+                "Signing key validity period (seconds)",
+                &self.key_validity_period_secs.to_string(),
+            ),
+            (
+                "Old signing key retention (seconds)",
+                &self
+                    .old_key_retention_secs
+                    .map_or_else(|| "forever".to_owned(), |secs| secs.to_string()),
+            ),
+            (
+                "Federation request timeout (ms)",
+                &self.federation_timeout_ms.to_string(),
+            ),
+            (
+                "Request timeout (ms)",
+                &self
+                    .request_timeout_ms
+                    .map_or_else(|| "none".to_owned(), |ms| ms.to_string()),
+            ),
+            (
+                "Paths without request timeout",
+                &self.request_timeout_excluded_paths.join(", "),
+            ),
+            ("Maximum PDU size", &self.max_pdu_size.to_string()),
+            (
+                "Maximum PDU prev_events",
+                &self.max_pdu_prev_events.to_string(),
+            ),
+            (
+                "Maximum PDU auth_events",
+                &self.max_pdu_auth_events.to_string(),
+            ),
             ("Allow registration", &self.allow_registration.to_string()),
+            (
+                "Registration requires email",
+                &self.registration_requires_email.to_string(),
+            ),
+            (
+                "Registration requires phone number",
+                &self.registration_requires_msisdn.to_string(),
+            ),
+            ("Forbidden usernames", &self.forbidden_usernames.join(", ")),
+            (
+                "Allow password change",
+                &self.allow_password_change.to_string(),
+            ),
+            (
+                "Allow display name change",
+                &self.allow_displayname_change.to_string(),
+            ),
+            ("Allow avatar change", &self.allow_avatar_change.to_string()),
+            ("Allow 3PID changes", &self.allow_3pid_changes.to_string()),
+            ("Server user localpart", &self.server_user_localpart),
+            ("Admin room enabled", &self.admin_room_enabled.to_string()),
+            (
+                "Forget abandoned rooms after (seconds)",
+                &self
+                    .forget_abandoned_rooms_after_secs
+                    .map_or_else(|| "never".to_owned(), |secs| secs.to_string()),
+            ),
+            (
+                "Max event delay (seconds)",
+                &self.max_event_delay_secs.map_or_else(
+                    || "delayed events disabled".to_owned(),
+                    |secs| secs.to_string(),
+                ),
+            ),
+            (
+                "Max delayed events per user",
+                &self.max_delayed_events_per_user.to_string(),
+            ),
+            ("Argon2 memory (KiB)", &self.argon2_memory.to_string()),
+            ("Argon2 iterations", &self.argon2_iterations.to_string()),
             (
                 "Enabled lightning bolt",
                 &self.enable_lightning_bolt.to_string(),
             ),
+            (
+                "Default display name",
+                self.default_displayname_template
+                    .as_deref()
+                    .unwrap_or("{localpart}"),
+            ),
+            (
+                "Default avatar",
+                self.default_avatar_url
+                    .as_ref()
+                    .map_or("not set", |url| url.as_str()),
+            ),
             ("Allow encryption", &self.allow_encryption.to_string()),
+            (
+                "Encryption enabled by default for",
+                self.encryption_enabled_by_default_for_room_type.as_str(),
+            ),
+            (
+                "Forbid disabling encryption",
+                &self.forbid_disabling_encryption.to_string(),
+            ),
             ("Allow federation", &self.allow_federation.to_string()),
+            (
+                "Trust own event signatures",
+                &self.trust_own_signatures.to_string(),
+            ),
             ("Allow room creation", &self.allow_room_creation.to_string()),
+            ("Allow presence", &self.allow_presence.to_string()),
+            (
+                "Presence idle timeout in seconds",
+                &self.presence_idle_timeout_secs.to_string(),
+            ),
+            (
+                "Presence offline timeout in seconds",
+                &self.presence_offline_timeout_secs.to_string(),
+            ),
+            ("Maintenance mode", &self.maintenance_mode.to_string()),
+            (
+                "User directory searches all users",
+                &self.user_directory_search_all_users.to_string(),
+            ),
+            (
+                "Media quota per user",
+                &self
+                    .per_user_media_quota_bytes
+                    .map_or_else(|| "unlimited".to_owned(), |quota| quota.to_string()),
+            ),
+            (
+                "Access token lifetime",
+                &self
+                    .access_token_lifetime
+                    .map_or_else(|| "unlimited".to_owned(), |secs| format!("{secs}s")),
+            ),
+            (
+                "Session idle expiry",
+                &self
+                    .session_idle_expiry
+                    .map_or_else(|| "never".to_owned(), |secs| format!("{secs}s")),
+            ),
+            (
+                "Created rooms per user",
+                &self
+                    .max_rooms_per_user_create
+                    .map_or_else(|| "unlimited".to_owned(), |max| max.to_string()),
+            ),
+            (
+                "Joined rooms per user",
+                &self
+                    .max_rooms_per_user_join
+                    .map_or_else(|| "unlimited".to_owned(), |max| max.to_string()),
+            ),
+            (
+                "Rooms created per user and hour",
+                &self
+                    .max_rooms_created_per_hour
+                    .map_or_else(|| "unlimited".to_owned(), |max| max.to_string()),
+            ),
+            (
+                "Pending invites per user",
+                &self
+                    .max_outstanding_invites_per_user
+                    .map_or_else(|| "unlimited".to_owned(), |max| max.to_string()),
+            ),
+            (
+                "Pending invites per room",
+                &self
+                    .max_invites_per_room
+                    .map_or_else(|| "unlimited".to_owned(), |max| max.to_string()),
+            ),
+            (
+                "Publish public rooms by default",
+                &self.room_list_publication_default.to_string(),
+            ),
+            (
+                "Only admins can publish rooms",
+                &self.room_list_publication_requires_admin.to_string(),
+            ),
+            (
+                "Room directory without login",
+                &self.allow_public_room_directory_without_auth.to_string(),
+            ),
+            (
+                "Room directory over federation",
+                &self.allow_public_room_directory_over_federation.to_string(),
+            ),
+            (
+                "Remote users joining local rooms",
+                &self.federation_allow_joins.to_string(),
+            ),
+            (
+                "Remote servers inviting local users",
+                &self.federation_allow_invites.to_string(),
+            ),
+            (
+                "Partial state joins of remote rooms",
+                &self.partial_state_joins.to_string(),
+            ),
+            ("URL previews", &self.url_preview_enabled.to_string()),
+            (
+                "Blocked URL preview URLs",
+                &self.url_preview_url_blacklist.join(", "),
+            ),
+            (
+                "Blocked URL preview IP ranges",
+                &self.url_preview_ip_blacklist.join(", "),
+            ),
+            (
+                "URL preview spider size",
+                &self.url_preview_max_spider_size.to_string(),
+            ),
             (
                 "JWT secret",
                 match self.jwt_secret {
@@ -153,6 +871,24 @@ impl fmt::Display for Config {
                     None => "not set",
                 },
             ),
+            (
+                "Delegated server name",
+                self.well_known_server.as_deref().unwrap_or("not set"),
+            ),
+            (
+                "Delegated client base URL",
+                self.well_known_client.as_deref().unwrap_or("not set"),
+            ),
+            (
+                "Blocked IP ranges",
+                &self.federation_ip_blacklist.join(", "),
+            ),
+            (
+                "Allowed IP ranges",
+                &self.federation_ip_whitelist.join(", "),
+            ),
+            ("Trusted proxies", &self.trusted_proxies.join(", ")),
+            ("Trusted proxy header", self.trusted_proxy_header.as_str()),
             ("Trusted servers", {
                 let mut lst = vec![];
                 for server in &self.trusted_servers {
@@ -160,37 +896,68 @@ impl fmt::Display for Config {
                 }
                 &lst.join(", ")
             }),
+            (
+                "Email server",
+                self.email
+                    .as_ref()
+                    .map_or("disabled", |email| email.smtp_host.as_str()),
+            ),
+            (
+                "Registration captcha",
+                self.captcha
+                    .as_ref()
+                    .map_or("disabled", |captcha| captcha.provider.as_str()),
+            ),
+            (
+                "Trusted identity servers",
+                &self.identity_server.trusted_servers.join(", "),
+            ),
+            (
+                "Phone number validation",
+                self.identity_server
+                    .msisdn_delegate
+                    .as_deref()
+                    .filter(|_| self.identity_server.msisdn_delegate_access_token.is_some())
+                    .unwrap_or("disabled"),
+            ),
+            (
+                "Default power levels",
+                &serde_json::to_string(&self.default_power_levels)
+                    .expect("JSON objects can be serialized"),
+            ),
+            ("Unstable features", {
+                let mut lst = vec![];
+                for (feature, enabled) in &self.unstable_features {
+                    if *enabled {
+                        lst.push(feature.as_str());
+                    }
+                }
+                &lst.join(", ")
+            }),
             (
                 "TURN username",
-                if self.turn_username.is_empty() {
+                if turn.username.is_empty() {
                     "not set"
                 } else {
-                    &self.turn_username
+                    &turn.username
                 },
             ),
             ("TURN password", {
-                if self.turn_password.is_empty() {
+                if turn.password.is_empty() {
                     "not set"
                 } else {
                     "set"
                 }
             }),
             ("TURN secret", {
-                if self.turn_secret.is_empty() {
+                if turn.shared_secret.is_empty() {
                     "not set"
                 } else {
                     "set"
                 }
             }),
-            ("Turn TTL", &self.turn_ttl.to_string()),
-            ("Turn URIs", {
-                let mut lst = vec![];
-                for item in self.turn_uris.iter().cloned().enumerate() {
-                    let (_, uri): (usize, String) = item;
-                    lst.push(uri);
-                }
-                &lst.join(", ")
-            }),
+            ("Turn TTL", &turn.ttl.to_string()),
+            ("Turn URIs", &turn.uris.join(", ")),
         ];
 
         let mut msg: String = "Active config values:\n\n".to_owned();
@@ -211,6 +978,10 @@ fn true_fn() -> bool {
     true
 }
 
+fn default_server_user_localpart() -> String {
+    "conduit".to_owned()
+}
+
 fn default_address() -> IpAddr {
     Ipv4Addr::LOCALHOST.into()
 }
@@ -239,6 +1010,10 @@ fn default_pdu_cache_capacity() -> u32 {
     150_000
 }
 
+fn default_database_flush_interval_secs() -> u64 {
+    1
+}
+
 fn default_cleanup_second_interval() -> u32 {
     60 // every minute
 }
@@ -247,23 +1022,237 @@ fn default_max_request_size() -> u32 {
     20 * 1024 * 1024 // Default to 20 MB
 }
 
+fn default_max_client_request_size() -> u32 {
+    1024 * 1024 // Default to 1 MB
+}
+
+fn default_url_preview_max_spider_size() -> usize {
+    1024 * 1024 // 1 MB
+}
+
+fn default_trusted_proxies() -> Vec<String> {
+    vec!["127.0.0.0/8".to_owned(), "::1/128".to_owned()]
+}
+
+fn default_federation_ip_blacklist() -> Vec<String> {
+    DEFAULT_IP_RANGE_BLACKLIST
+        .iter()
+        .map(|range| (*range).to_owned())
+        .collect()
+}
+
+fn default_max_concurrent_syncs() -> usize {
+    10
+}
+
+fn default_message_rate_limit_burst() -> u32 {
+    10
+}
+
+fn default_sync_room_concurrency() -> usize {
+    8
+}
+
+fn default_sync_timeline_limit_default() -> usize {
+    10
+}
+
+fn default_sync_timeline_limit_max() -> usize {
+    100
+}
+
 fn default_max_concurrent_requests() -> u16 {
     100
 }
 
+fn default_max_concurrent_transactions_per_origin() -> u16 {
+    1
+}
+
 fn default_max_fetch_prev_events() -> u16 {
     100_u16
 }
 
+fn default_max_delayed_events_per_user() -> usize {
+    100
+}
+
+fn default_backfill_max_depth() -> u64 {
+    500
+}
+
+fn default_federation_keys_timeout_secs() -> u64 {
+    10
+}
+
+fn default_sync_db_retries() -> u32 {
+    3
+}
+
+fn default_presence_idle_timeout_secs() -> u64 {
+    5 * 60
+}
+
+fn default_presence_offline_timeout_secs() -> u64 {
+    30 * 60
+}
+
+fn default_key_validity_period_secs() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_federation_timeout_ms() -> u64 {
+    3 * 60 * 1000
+}
+
+fn default_compression_excluded_content_types() -> Vec<String> {
+    vec![
+        "image/".to_owned(),
+        "video/".to_owned(),
+        "audio/".to_owned(),
+        "application/zip".to_owned(),
+        "application/gzip".to_owned(),
+        "application/octet-stream".to_owned(),
+    ]
+}
+
+fn default_request_timeout_excluded_paths() -> Vec<String> {
+    vec![
+        "/_matrix/client/r0/sync".to_owned(),
+        "/_matrix/client/v3/sync".to_owned(),
+        "/_matrix/media/".to_owned(),
+    ]
+}
+
+// The PDU limits from the spec, only meant to be changed for testing
+
+fn default_max_pdu_size() -> usize {
+    65_536
+}
+
+fn default_max_pdu_prev_events() -> usize {
+    20
+}
+
+fn default_max_pdu_auth_events() -> usize {
+    10
+}
+
 fn default_log() -> String {
     "warn,state_res=warn,_=off,sled=off".to_owned()
 }
 
+fn default_verification_subject() -> String {
+    "Validate your email address on {server_name}".to_owned()
+}
+
+fn default_verification_text() -> String {
+    "Someone asked to add this email address to an account on {server_name}. If that was you, \
+     open this link to confirm it:\n\n{link}\n\nOtherwise you can ignore this email."
+        .to_owned()
+}
+
+fn default_verification_html() -> String {
+    "<p>Someone asked to add this email address to an account on {server_name}. If that was \
+     you, <a href=\"{link}\">confirm it</a>.</p><p>Otherwise you can ignore this email.</p>"
+        .to_owned()
+}
+
 fn default_turn_ttl() -> u64 {
     60 * 60 * 24
 }
 
+fn default_argon2_memory() -> u32 {
+    4096 // in KiB, same as the argon2 crate default
+}
+
+fn default_argon2_iterations() -> u32 {
+    3
+}
+
 // I know, it's a great name
 pub fn default_default_room_version() -> RoomVersionId {
     RoomVersionId::V9
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn federation_joins_can_be_turned_off_alone() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "server_name": "example.org",
+            "database_path": "/var/lib/matrix-conduit/",
+            "allow_federation": true,
+            "federation_allow_joins": false,
+        }))
+        .unwrap();
+
+        assert!(config.allow_federation);
+        assert!(!config.federation_allow_joins);
+        assert!(config.federation_allow_invites);
+        assert!(config.allow_public_room_directory_over_federation);
+
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "server_name": "example.org",
+            "database_path": "/var/lib/matrix-conduit/",
+            "federation_allow_public_rooms": false,
+        }))
+        .unwrap();
+
+        assert!(!config.allow_public_room_directory_over_federation);
+        assert!(config.federation_allow_joins);
+    }
+
+    fn with_default_power_levels(default_power_levels: serde_json::Value) -> Config {
+        serde_json::from_value(serde_json::json!({
+            "server_name": "example.org",
+            "database_path": "/var/lib/matrix-conduit/",
+            "default_power_levels": default_power_levels,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn default_power_levels_are_applied() {
+        let config = with_default_power_levels(serde_json::json!({
+            "invite": 50,
+            "events": { "m.room.name": 100 },
+        }));
+        let users = BTreeMap::from([(ruma::user_id!("@alice:example.org").to_owned(), int!(100))]);
+
+        let content = config.new_room_power_levels(users);
+
+        assert_eq!(content["invite"], 50);
+        assert_eq!(content["events"]["m.room.name"], 100);
+        assert_eq!(content["users"]["@alice:example.org"], 100);
+
+        // Spec defaults that weren't configured are kept
+        let content: RoomPowerLevelsEventContent = serde_json::from_value(content).unwrap();
+        assert_eq!(content.invite, int!(50));
+        assert_eq!(content.ban, int!(50));
+    }
+
+    #[test]
+    fn default_power_levels_cant_lock_the_creator_out() {
+        assert!(with_default_power_levels(serde_json::json!({
+            "invite": 50,
+            "events": { "m.room.power_levels": 100 },
+        }))
+        .check_default_power_levels()
+        .is_ok());
+
+        for invalid in [
+            serde_json::json!({ "events": { "m.room.power_levels": 101 } }),
+            serde_json::json!({ "state_default": 150 }),
+            serde_json::json!({ "notifications": { "room": 200 } }),
+            serde_json::json!({ "users": { "@alice:example.org": 100 } }),
+            serde_json::json!({ "ban": "everyone" }),
+        ] {
+            assert!(with_default_power_levels(invalid)
+                .check_default_power_levels()
+                .is_err());
+        }
+    }
+}
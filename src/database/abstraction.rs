@@ -1,7 +1,7 @@
 use super::Config;
-use crate::Result;
+use crate::{Error, Result};
 
-use std::{future::Future, pin::Pin, sync::Arc};
+use std::{future::Future, path::Path, pin::Pin, sync::Arc};
 
 #[cfg(feature = "sled")]
 pub mod sled;
@@ -18,11 +18,15 @@ pub mod rocksdb;
 #[cfg(feature = "persy")]
 pub mod persy;
 
+#[cfg(test)]
+pub mod memory;
+
 #[cfg(any(
     feature = "sqlite",
     feature = "rocksdb",
     feature = "heed",
-    feature = "persy"
+    feature = "persy",
+    test
 ))]
 pub mod watchers;
 
@@ -32,12 +36,26 @@ pub trait KeyValueDatabaseEngine: Send + Sync {
         Self: Sized;
     fn open_tree(&self, name: &'static str) -> Result<Arc<dyn KvTree>>;
     fn flush(&self) -> Result<()>;
+    /// Whether the engine can defer syncing writes to `flush` in relaxed durability mode.
+    fn supports_relaxed_durability(&self) -> bool {
+        false
+    }
     fn cleanup(&self) -> Result<()> {
         Ok(())
     }
     fn memory_usage(&self) -> Result<String> {
         Ok("Current database engine does not support memory usage reporting.".to_owned())
     }
+    /// Writes a consistent copy of the database into the directory `path`, which must not exist
+    /// yet. The copy can be used as `database_path` of a new server.
+    ///
+    /// Engines that copy in steps call `progress` with the number of units (e.g. pages) copied so
+    /// far and the total after each step.
+    fn backup(&self, _path: &Path, _progress: &dyn Fn(u64, u64)) -> Result<()> {
+        Err(Error::BadConfig(
+            "Current database engine does not support online backups.",
+        ))
+    }
 }
 
 pub trait KvTree: Send + Sync {
@@ -0,0 +1,130 @@
+use super::{watchers::Watchers, KeyValueDatabaseEngine, KvTree};
+use crate::{database::Config, Result};
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    ops::Bound,
+    pin::Pin,
+    sync::{Arc, RwLock},
+};
+
+type TupleOfBytes = (Vec<u8>, Vec<u8>);
+
+/// Keeps every tree in memory. Only used by tests, which need services without a database on disk.
+#[derive(Default)]
+pub struct Engine;
+
+impl KeyValueDatabaseEngine for Arc<Engine> {
+    fn open(_config: &Config) -> Result<Self> {
+        Ok(Arc::new(Engine))
+    }
+
+    fn open_tree(&self, _name: &'static str) -> Result<Arc<dyn KvTree>> {
+        Ok(Arc::new(MemoryTree::default()))
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryTree {
+    map: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+    watchers: Watchers,
+}
+
+/// Iterators return a snapshot, so callers can write to the tree while they iterate, like they can
+/// with the other engines.
+fn snapshot<'a>(
+    entries: impl Iterator<Item = (&'a Vec<u8>, &'a Vec<u8>)>,
+) -> Box<dyn Iterator<Item = TupleOfBytes>> {
+    Box::new(
+        entries
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>()
+            .into_iter(),
+    )
+}
+
+impl KvTree for MemoryTree {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.map.read().unwrap().get(key).cloned())
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.map
+            .write()
+            .unwrap()
+            .insert(key.to_vec(), value.to_vec());
+        self.watchers.wake(key);
+        Ok(())
+    }
+
+    fn insert_batch(&self, iter: &mut dyn Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let mut map = self.map.write().unwrap();
+        for (key, value) in iter {
+            map.insert(key, value);
+        }
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        self.map.write().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = TupleOfBytes> + 'a> {
+        snapshot(self.map.read().unwrap().iter())
+    }
+
+    fn iter_from<'a>(
+        &'a self,
+        from: &[u8],
+        backwards: bool,
+    ) -> Box<dyn Iterator<Item = TupleOfBytes> + 'a> {
+        let map = self.map.read().unwrap();
+        if backwards {
+            snapshot(
+                map.range::<[u8], _>((Bound::Unbounded, Bound::Included(from)))
+                    .rev(),
+            )
+        } else {
+            snapshot(map.range::<[u8], _>((Bound::Included(from), Bound::Unbounded)))
+        }
+    }
+
+    fn increment(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let mut map = self.map.write().unwrap();
+        let new = crate::utils::increment(map.get(key).map(|old| &**old))
+            .expect("utils::increment always returns Some");
+        map.insert(key.to_vec(), new.clone());
+        Ok(new)
+    }
+
+    fn increment_batch(&self, iter: &mut dyn Iterator<Item = Vec<u8>>) -> Result<()> {
+        let mut map = self.map.write().unwrap();
+        for key in iter {
+            let new = crate::utils::increment(map.get(&key).map(|old| &**old))
+                .expect("utils::increment always returns Some");
+            map.insert(key, new);
+        }
+        Ok(())
+    }
+
+    fn scan_prefix<'a>(&'a self, prefix: Vec<u8>) -> Box<dyn Iterator<Item = TupleOfBytes> + 'a> {
+        Box::new(
+            self.iter_from(&prefix, false)
+                .take_while(move |(key, _)| key.starts_with(&prefix)),
+        )
+    }
+
+    fn watch_prefix<'a>(&'a self, prefix: &[u8]) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        self.watchers.watch(prefix)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.map.write().unwrap().clear();
+        Ok(())
+    }
+}
@@ -1,7 +1,8 @@
 use super::{super::Config, watchers::Watchers, KeyValueDatabaseEngine, KvTree};
-use crate::{utils, Result};
+use crate::{config::DatabaseDurability, utils, Result};
 use std::{
     future::Future,
+    path::Path,
     pin::Pin,
     sync::{Arc, RwLock},
 };
@@ -11,6 +12,16 @@ pub struct Engine {
     max_open_files: i32,
     cache: rocksdb::Cache,
     old_cfs: Vec<String>,
+    /// Whether every write waits until the WAL is synced to disk.
+    sync_writes: bool,
+}
+
+impl Engine {
+    fn write_options(&self) -> rocksdb::WriteOptions {
+        let mut write_options = rocksdb::WriteOptions::default();
+        write_options.set_sync(self.sync_writes);
+        write_options
+    }
 }
 
 pub struct RocksDbEngineTree<'a> {
@@ -80,6 +91,7 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
             max_open_files: config.rocksdb_max_open_files,
             cache: rocksdb_cache,
             old_cfs: cfs,
+            sync_writes: config.database_durability == DatabaseDurability::Full,
         }))
     }
 
@@ -100,7 +112,23 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
     }
 
     fn flush(&self) -> Result<()> {
-        // TODO?
+        if !self.sync_writes {
+            // A synced write also syncs all unsynced writes before it in the WAL
+            let mut write_options = rocksdb::WriteOptions::default();
+            write_options.set_sync(true);
+            self.rocks
+                .write_opt(rocksdb::WriteBatch::default(), &write_options)?;
+        }
+        Ok(())
+    }
+
+    fn supports_relaxed_durability(&self) -> bool {
+        true
+    }
+
+    fn backup(&self, path: &Path, _progress: &dyn Fn(u64, u64)) -> Result<()> {
+        // Checkpoints hardlink the immutable sst files, so this is cheap on the same filesystem
+        rocksdb::checkpoint::Checkpoint::new(&self.rocks)?.create_checkpoint(path)?;
         Ok(())
     }
 
@@ -136,7 +164,9 @@ impl KvTree for RocksDbEngineTree<'_> {
 
     fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
         let lock = self.write_lock.read().unwrap();
-        self.db.rocks.put_cf(&self.cf(), key, value)?;
+        self.db
+            .rocks
+            .put_cf_opt(&self.cf(), key, value, &self.db.write_options())?;
         drop(lock);
 
         self.watchers.wake(key);
@@ -145,15 +175,21 @@ impl KvTree for RocksDbEngineTree<'_> {
     }
 
     fn insert_batch<'a>(&self, iter: &mut dyn Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> {
+        // One write for the whole batch, so it is synced at most once
+        let mut batch = rocksdb::WriteBatch::default();
         for (key, value) in iter {
-            self.db.rocks.put_cf(&self.cf(), key, value)?;
+            batch.put_cf(&self.cf(), key, value);
         }
+        self.db.rocks.write_opt(batch, &self.db.write_options())?;
 
         Ok(())
     }
 
     fn remove(&self, key: &[u8]) -> Result<()> {
-        Ok(self.db.rocks.delete_cf(&self.cf(), key)?)
+        Ok(self
+            .db
+            .rocks
+            .delete_cf_opt(&self.cf(), key, &self.db.write_options())?)
     }
 
     fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
@@ -195,7 +231,9 @@ impl KvTree for RocksDbEngineTree<'_> {
 
         let old = self.db.rocks.get_cf(&self.cf(), key)?;
         let new = utils::increment(old.as_deref()).unwrap();
-        self.db.rocks.put_cf(&self.cf(), key, &new)?;
+        self.db
+            .rocks
+            .put_cf_opt(&self.cf(), key, &new, &self.db.write_options())?;
 
         drop(lock);
         Ok(new)
@@ -207,7 +245,9 @@ impl KvTree for RocksDbEngineTree<'_> {
         for key in iter {
             let old = self.db.rocks.get_cf(&self.cf(), &key)?;
             let new = utils::increment(old.as_deref()).unwrap();
-            self.db.rocks.put_cf(&self.cf(), key, new)?;
+            self.db
+                .rocks
+                .put_cf_opt(&self.cf(), key, new, &self.db.write_options())?;
         }
 
         drop(lock);
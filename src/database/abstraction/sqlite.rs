@@ -1,17 +1,27 @@
 use super::{watchers::Watchers, KeyValueDatabaseEngine, KvTree};
-use crate::{database::Config, Result};
+use crate::{config::DatabaseDurability, database::Config, Result};
 use parking_lot::{Mutex, MutexGuard};
-use rusqlite::{Connection, DatabaseName::Main, OptionalExtension};
+use rusqlite::{
+    backup::{Backup, Progress, StepResult},
+    Connection,
+    DatabaseName::Main,
+    OptionalExtension,
+};
 use std::{
     cell::RefCell,
     future::Future,
     path::{Path, PathBuf},
     pin::Pin,
     sync::Arc,
+    time::Duration,
 };
 use thread_local::ThreadLocal;
 use tracing::debug;
 
+/// Pages of 2 KiB the online backup copies before it reports progress
+const BACKUP_PAGES_PER_STEP: i32 = 4096;
+const BACKUP_BUSY_PAUSE: Duration = Duration::from_millis(50);
+
 thread_local! {
     static READ_CONNECTION: RefCell<Option<&'static Connection>> = RefCell::new(None);
     static READ_CONNECTION_ITERATOR: RefCell<Option<&'static Connection>> = RefCell::new(None);
@@ -44,15 +54,29 @@ pub struct Engine {
 
     path: PathBuf,
     cache_size_per_thread: u32,
+    durability: DatabaseDurability,
 }
 
 impl Engine {
-    fn prepare_conn(path: &Path, cache_size_kb: u32) -> Result<Connection> {
+    fn prepare_conn(
+        path: &Path,
+        cache_size_kb: u32,
+        durability: DatabaseDurability,
+    ) -> Result<Connection> {
         let conn = Connection::open(path)?;
 
         conn.pragma_update(Some(Main), "page_size", 2048)?;
         conn.pragma_update(Some(Main), "journal_mode", "WAL")?;
-        conn.pragma_update(Some(Main), "synchronous", "NORMAL")?;
+        // With FULL every commit syncs the WAL. With NORMAL the WAL is only synced when it is
+        // checkpointed, which flush does regularly.
+        conn.pragma_update(
+            Some(Main),
+            "synchronous",
+            match durability {
+                DatabaseDurability::Full => "FULL",
+                DatabaseDurability::Relaxed => "NORMAL",
+            },
+        )?;
         conn.pragma_update(Some(Main), "cache_size", -i64::from(cache_size_kb))?;
         conn.pragma_update(Some(Main), "wal_autocheckpoint", 0)?;
 
@@ -64,13 +88,15 @@ impl Engine {
     }
 
     fn read_lock(&self) -> &Connection {
-        self.read_conn_tls
-            .get_or(|| Self::prepare_conn(&self.path, self.cache_size_per_thread).unwrap())
+        self.read_conn_tls.get_or(|| {
+            Self::prepare_conn(&self.path, self.cache_size_per_thread, self.durability).unwrap()
+        })
     }
 
     fn read_lock_iterator(&self) -> &Connection {
-        self.read_iterator_conn_tls
-            .get_or(|| Self::prepare_conn(&self.path, self.cache_size_per_thread).unwrap())
+        self.read_iterator_conn_tls.get_or(|| {
+            Self::prepare_conn(&self.path, self.cache_size_per_thread, self.durability).unwrap()
+        })
     }
 
     pub fn flush_wal(self: &Arc<Self>) -> Result<()> {
@@ -92,7 +118,11 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
             / ((num_cpus::get().max(1) * 2) + 1) as f64)
             as u32;
 
-        let writer = Mutex::new(Engine::prepare_conn(&path, cache_size_per_thread)?);
+        let writer = Mutex::new(Engine::prepare_conn(
+            &path,
+            cache_size_per_thread,
+            config.database_durability,
+        )?);
 
         let arc = Arc::new(Engine {
             writer,
@@ -100,6 +130,7 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
             read_iterator_conn_tls: ThreadLocal::new(),
             path,
             cache_size_per_thread,
+            durability: config.database_durability,
         });
 
         Ok(arc)
@@ -116,13 +147,55 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
     }
 
     fn flush(&self) -> Result<()> {
-        // we enabled PRAGMA synchronous=normal, so this should not be necessary
+        if self.durability == DatabaseDurability::Relaxed {
+            // Syncs the WAL, a passive checkpoint doesn't wait for readers
+            self.write_lock()
+                .pragma_update(Some(Main), "wal_checkpoint", "PASSIVE")?;
+        }
         Ok(())
     }
 
+    fn supports_relaxed_durability(&self) -> bool {
+        true
+    }
+
     fn cleanup(&self) -> Result<()> {
         self.flush_wal()
     }
+
+    fn backup(&self, path: &Path, progress: &dyn Fn(u64, u64)) -> Result<()> {
+        std::fs::create_dir_all(path)?;
+
+        // The open read transaction pins a snapshot of the WAL, so the copy is consistent while
+        // writers keep going. The backup only restarts when its source connection sees new data,
+        // which the transaction prevents.
+        let source = Connection::open(&self.path)?;
+        source.execute_batch("BEGIN DEFERRED")?;
+        source.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))?;
+
+        let mut target = Connection::open(path.join("conduit.db"))?;
+        {
+            let backup = Backup::new(&source, &mut target)?;
+            loop {
+                let step = backup.step(BACKUP_PAGES_PER_STEP)?;
+                let Progress {
+                    remaining,
+                    pagecount,
+                } = backup.progress();
+                progress((pagecount - remaining) as u64, pagecount as u64);
+
+                match step {
+                    StepResult::Done => break,
+                    StepResult::More => {}
+                    // The target is ours alone, but a step can still hit a busy source page
+                    _ => std::thread::sleep(BACKUP_BUSY_PAUSE),
+                }
+            }
+        }
+        source.execute_batch("COMMIT")?;
+
+        Ok(())
+    }
 }
 
 pub struct SqliteTable {
@@ -336,3 +409,49 @@ impl KvTree for SqliteTable {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_engine(dir: &Path, durability: DatabaseDurability) -> Arc<Engine> {
+        std::fs::create_dir_all(dir).unwrap();
+        let path = dir.join("conduit.db");
+
+        Arc::new(Engine {
+            writer: Mutex::new(Engine::prepare_conn(&path, 1024, durability).unwrap()),
+            read_conn_tls: ThreadLocal::new(),
+            read_iterator_conn_tls: ThreadLocal::new(),
+            path,
+            cache_size_per_thread: 1024,
+            durability,
+        })
+    }
+
+    #[test]
+    fn backups_can_be_opened_as_a_database() {
+        let dir = std::env::temp_dir().join(format!("conduit-backup-{}", std::process::id()));
+
+        let engine = test_engine(&dir.join("database"), DatabaseDurability::Full);
+        let tree = engine.open_tree("backup").unwrap();
+        tree.insert(b"key", b"value").unwrap();
+        let reports = std::cell::Cell::new(0);
+        engine
+            .backup(&dir.join("backup"), &|copied, total| {
+                assert!(copied <= total);
+                reports.set(reports.get() + 1);
+            })
+            .unwrap();
+        assert!(reports.get() > 0);
+        tree.insert(b"key", b"changed after the backup").unwrap();
+
+        let backup = test_engine(&dir.join("backup"), DatabaseDurability::Full);
+        assert_eq!(
+            backup.open_tree("backup").unwrap().get(b"key").unwrap(),
+            Some(b"value".to_vec())
+        );
+
+        drop((tree, engine, backup));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,161 @@
+use std::fmt;
+
+use tracing::{info, warn};
+
+use super::{abstraction::KvTree, KeyValueDatabase};
+use crate::{PduEvent, Result};
+
+/// What a database integrity check found and, in repair mode, fixed.
+#[derive(Debug, Default)]
+pub struct IntegrityReport {
+    pub checked_pdus: u64,
+    /// PDUs that can't be parsed. These can't be repaired automatically.
+    pub invalid_pdus: u64,
+    /// Timeline PDUs that can't be found by their event id.
+    pub missing_eventid_pduid: u64,
+    /// Prev events of timeline PDUs that are not marked as referenced.
+    pub missing_referencedevents: u64,
+    /// Short ids without a mapping in the other direction.
+    pub missing_shorteventid_mappings: u64,
+    pub missing_shortstatekey_mappings: u64,
+    pub repaired: u64,
+}
+
+impl IntegrityReport {
+    pub fn problems(&self) -> u64 {
+        self.invalid_pdus
+            + self.missing_eventid_pduid
+            + self.missing_referencedevents
+            + self.missing_shorteventid_mappings
+            + self.missing_shortstatekey_mappings
+    }
+}
+
+impl fmt::Display for IntegrityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Checked PDUs: {}", self.checked_pdus)?;
+        writeln!(f, "Invalid PDUs: {}", self.invalid_pdus)?;
+        writeln!(
+            f,
+            "Missing event id -> PDU id entries: {}",
+            self.missing_eventid_pduid
+        )?;
+        writeln!(
+            f,
+            "Missing referenced event entries: {}",
+            self.missing_referencedevents
+        )?;
+        writeln!(
+            f,
+            "Missing short event id mappings: {}",
+            self.missing_shorteventid_mappings
+        )?;
+        writeln!(
+            f,
+            "Missing short state key mappings: {}",
+            self.missing_shortstatekey_mappings
+        )?;
+        write!(f, "Repaired: {}", self.repaired)
+    }
+}
+
+impl KeyValueDatabase {
+    /// Verifies that the derived indexes match the PDUs in the timeline, without changing
+    /// anything.
+    pub fn check_integrity(&self) -> Result<IntegrityReport> {
+        self.verify(false)
+    }
+
+    /// Like `check_integrity`, but rebuilds missing index entries from the timeline.
+    pub fn repair(&self) -> Result<IntegrityReport> {
+        let report = self.verify(true)?;
+        self.flush()?;
+        Ok(report)
+    }
+
+    fn verify(&self, repair: bool) -> Result<IntegrityReport> {
+        let mut report = IntegrityReport::default();
+
+        info!("Checking timeline PDUs");
+        for (pdu_id, pdu_json) in self.pduid_pdu.iter() {
+            report.checked_pdus += 1;
+
+            let pdu = match serde_json::from_slice::<PduEvent>(&pdu_json) {
+                Ok(pdu) => pdu,
+                Err(e) => {
+                    warn!(?pdu_id, "Invalid PDU in timeline: {}", e);
+                    report.invalid_pdus += 1;
+                    continue;
+                }
+            };
+
+            if self.eventid_pduid.get(pdu.event_id.as_bytes())?.as_deref()
+                != Some(pdu_id.as_slice())
+            {
+                warn!(event_id = %pdu.event_id, "Event id doesn't point to its PDU");
+                report.missing_eventid_pduid += 1;
+                if repair {
+                    self.eventid_pduid
+                        .insert(pdu.event_id.as_bytes(), &pdu_id)?;
+                    report.repaired += 1;
+                }
+            }
+
+            for prev_event in &pdu.prev_events {
+                let mut key = pdu.room_id.as_bytes().to_vec();
+                key.extend_from_slice(prev_event.as_bytes());
+
+                if self.referencedevents.get(&key)?.is_none() {
+                    report.missing_referencedevents += 1;
+                    if repair {
+                        self.referencedevents.insert(&key, &[])?;
+                        report.repaired += 1;
+                    }
+                }
+            }
+        }
+
+        info!("Checking short event ids");
+        let (missing, repaired) = verify_reverse_mapping(
+            &*self.eventid_shorteventid,
+            &*self.shorteventid_eventid,
+            repair,
+        )?;
+        report.missing_shorteventid_mappings += missing;
+        report.repaired += repaired;
+
+        info!("Checking short state keys");
+        let (missing, repaired) = verify_reverse_mapping(
+            &*self.statekey_shortstatekey,
+            &*self.shortstatekey_statekey,
+            repair,
+        )?;
+        report.missing_shortstatekey_mappings += missing;
+        report.repaired += repaired;
+
+        Ok(report)
+    }
+}
+
+/// Checks that every entry of `forward` has the reverse entry in `backward`. Returns the number
+/// of missing and repaired entries.
+fn verify_reverse_mapping(
+    forward: &dyn KvTree,
+    backward: &dyn KvTree,
+    repair: bool,
+) -> Result<(u64, u64)> {
+    let mut missing = 0;
+    let mut repaired = 0;
+
+    for (key, value) in forward.iter() {
+        if backward.get(&value)?.as_deref() != Some(key.as_slice()) {
+            missing += 1;
+            if repair {
+                backward.insert(&value, &key)?;
+                repaired += 1;
+            }
+        }
+    }
+
+    Ok((missing, repaired))
+}
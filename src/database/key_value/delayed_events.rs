@@ -0,0 +1,76 @@
+use ruma::{OwnedUserId, UserId};
+
+use crate::{database::KeyValueDatabase, service, utils, Error, Result};
+
+fn key(user_id: &UserId, delay_id: &str) -> Vec<u8> {
+    let mut key = user_id.as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(delay_id.as_bytes());
+    key
+}
+
+fn parse_event(value: &[u8]) -> Result<service::delayed_events::DelayedEvent> {
+    serde_json::from_slice(value)
+        .map_err(|_| Error::bad_database("Invalid delayed event in userdelayid_delayedevent."))
+}
+
+impl service::delayed_events::Data for KeyValueDatabase {
+    fn save_delayed_event(
+        &self,
+        user_id: &UserId,
+        event: &service::delayed_events::DelayedEvent,
+    ) -> Result<()> {
+        self.userdelayid_delayedevent.insert(
+            &key(user_id, &event.delay_id),
+            &serde_json::to_vec(event).expect("DelayedEvent::to_vec always works"),
+        )
+    }
+
+    fn get_delayed_event(
+        &self,
+        user_id: &UserId,
+        delay_id: &str,
+    ) -> Result<Option<service::delayed_events::DelayedEvent>> {
+        self.userdelayid_delayedevent
+            .get(&key(user_id, delay_id))?
+            .map(|value| parse_event(&value))
+            .transpose()
+    }
+
+    fn remove_delayed_event(&self, user_id: &UserId, delay_id: &str) -> Result<()> {
+        self.userdelayid_delayedevent
+            .remove(&key(user_id, delay_id))
+    }
+
+    fn delayed_events<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<service::delayed_events::DelayedEvent>> + 'a> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        Box::new(
+            self.userdelayid_delayedevent
+                .scan_prefix(prefix)
+                .map(|(_, value)| parse_event(&value)),
+        )
+    }
+
+    fn all_delayed_events<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedUserId, service::delayed_events::DelayedEvent)>> + 'a>
+    {
+        Box::new(self.userdelayid_delayedevent.iter().map(|(key, value)| {
+            let user_id = key
+                .split(|&b| b == 0xff)
+                .next()
+                .and_then(|bytes| utils::string_from_bytes(bytes).ok())
+                .and_then(|user_id| UserId::parse(user_id).ok())
+                .ok_or_else(|| {
+                    Error::bad_database("Invalid user id in userdelayid_delayedevent.")
+                })?;
+
+            Ok((user_id, parse_event(&value)?))
+        }))
+    }
+}
@@ -1,14 +1,17 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, path::Path};
 
 use async_trait::async_trait;
 use futures_util::{stream::FuturesUnordered, StreamExt};
 use ruma::{
-    api::federation::discovery::{ServerSigningKeys, VerifyKey},
+    api::federation::discovery::{OldVerifyKey, ServerSigningKeys, VerifyKey},
     signatures::Ed25519KeyPair,
     DeviceId, MilliSecondsSinceUnixEpoch, OwnedServerSigningKeyId, ServerName, UserId,
 };
 
-use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
+use crate::{
+    database::{abstraction::KvTree, KeyValueDatabase},
+    service, services, utils, Error, Result,
+};
 
 pub const COUNTER: &[u8] = b"c";
 
@@ -118,45 +121,37 @@ impl service::globals::Data for KeyValueDatabase {
         self._db.cleanup()
     }
 
+    fn flush(&self) -> Result<()> {
+        self._db.flush()
+    }
+
     fn memory_usage(&self) -> Result<String> {
         self._db.memory_usage()
     }
 
-    fn load_keypair(&self) -> Result<Ed25519KeyPair> {
-        let keypair_bytes = self.global.get(b"keypair")?.map_or_else(
-            || {
-                let keypair = utils::generate_keypair();
-                self.global.insert(b"keypair", &keypair)?;
-                Ok::<_, Error>(keypair)
-            },
-            |s| Ok(s.to_vec()),
-        )?;
+    fn backup(&self, path: &Path, progress: &dyn Fn(u64, u64)) -> Result<()> {
+        self._db.backup(path, progress)
+    }
 
-        let mut parts = keypair_bytes.splitn(2, |&b| b == 0xff);
-
-        utils::string_from_bytes(
-            // 1. version
-            parts
-                .next()
-                .expect("splitn always returns at least one element"),
-        )
-        .map_err(|_| Error::bad_database("Invalid version bytes in keypair."))
-        .and_then(|version| {
-            // 2. key
-            parts
-                .next()
-                .ok_or_else(|| Error::bad_database("Invalid keypair format in database."))
-                .map(|key| (version, key))
-        })
-        .and_then(|(version, key)| {
-            Ed25519KeyPair::from_der(key, version)
-                .map_err(|_| Error::bad_database("Private or public keys are invalid."))
-        })
+    fn load_keypair(&self) -> Result<Ed25519KeyPair> {
+        load_keypair(&*self.global)
     }
     fn remove_keypair(&self) -> Result<()> {
         self.global.remove(b"keypair")
     }
 
+    fn rotate_keypair(
+        &self,
+        old_key_id: OwnedServerSigningKeyId,
+        old_key: OldVerifyKey,
+    ) -> Result<Ed25519KeyPair> {
+        rotate_keypair(&*self.global, old_key_id, old_key)
+    }
+
+    fn old_verify_keys(&self) -> Result<BTreeMap<OwnedServerSigningKeyId, OldVerifyKey>> {
+        old_verify_keys(&*self.global)
+    }
+
     fn add_signing_key(
         &self,
         origin: &ServerName,
@@ -231,3 +226,132 @@ impl service::globals::Data for KeyValueDatabase {
         Ok(())
     }
 }
+
+fn load_keypair(global: &dyn KvTree) -> Result<Ed25519KeyPair> {
+    let keypair_bytes = global.get(b"keypair")?.map_or_else(
+        || {
+            let keypair = utils::generate_keypair();
+            global.insert(b"keypair", &keypair)?;
+            Ok::<_, Error>(keypair)
+        },
+        |s| Ok(s.to_vec()),
+    )?;
+
+    let mut parts = keypair_bytes.splitn(2, |&b| b == 0xff);
+
+    utils::string_from_bytes(
+        // 1. version
+        parts
+            .next()
+            .expect("splitn always returns at least one element"),
+    )
+    .map_err(|_| Error::bad_database("Invalid version bytes in keypair."))
+    .and_then(|version| {
+        // 2. key
+        parts
+            .next()
+            .ok_or_else(|| Error::bad_database("Invalid keypair format in database."))
+            .map(|key| (version, key))
+    })
+    .and_then(|(version, key)| {
+        Ed25519KeyPair::from_der(key, version)
+            .map_err(|_| Error::bad_database("Private or public keys are invalid."))
+    })
+}
+
+fn rotate_keypair(
+    global: &dyn KvTree,
+    old_key_id: OwnedServerSigningKeyId,
+    old_key: OldVerifyKey,
+) -> Result<Ed25519KeyPair> {
+    let mut old_verify_keys = old_verify_keys(global)?;
+    old_verify_keys.insert(old_key_id, old_key);
+
+    // The old key is stored first, so it's never lost if the server stops in between
+    global.insert(
+        b"old_verify_keys",
+        &serde_json::to_vec(&old_verify_keys).expect("old verify keys are valid json"),
+    )?;
+    global.insert(b"keypair", &utils::generate_keypair())?;
+
+    load_keypair(global)
+}
+
+fn old_verify_keys(global: &dyn KvTree) -> Result<BTreeMap<OwnedServerSigningKeyId, OldVerifyKey>> {
+    global
+        .get(b"old_verify_keys")?
+        .map_or(Ok(BTreeMap::new()), |bytes| {
+            serde_json::from_slice(&bytes)
+                .map_err(|_| Error::bad_database("Invalid old_verify_keys in database."))
+        })
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod test {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use ruma::{serde::Base64, server_name, MilliSecondsSinceUnixEpoch};
+
+    use super::*;
+    use crate::{
+        api::server_server::server_keys_json,
+        database::abstraction::{sqlite, KeyValueDatabaseEngine},
+        service::globals::key_id,
+        Config,
+    };
+
+    #[test]
+    fn rotated_keys_are_served_after_a_restart() {
+        let dir = std::env::temp_dir().join(format!("conduit-key-rotation-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "server_name": "example.org",
+            "database_path": dir,
+        }))
+        .unwrap();
+
+        let engine = <Arc<sqlite::Engine>>::open(&config).unwrap();
+        let global = engine.open_tree("global").unwrap();
+        let old = load_keypair(&*global).unwrap();
+        let old_key = OldVerifyKey::new(
+            MilliSecondsSinceUnixEpoch::now(),
+            Base64::new(old.public_key().to_vec()),
+        );
+        let new = rotate_keypair(&*global, key_id(&old), old_key.clone()).unwrap();
+        assert_ne!(key_id(&old), key_id(&new));
+        drop((global, engine));
+
+        // What the server loads when it starts again
+        let engine = <Arc<sqlite::Engine>>::open(&config).unwrap();
+        let global = engine.open_tree("global").unwrap();
+        let keypair = load_keypair(&*global).unwrap();
+        assert_eq!(keypair.public_key(), new.public_key());
+
+        let response = serde_json::to_value(server_keys_json(
+            server_name!("example.org").to_owned(),
+            &keypair,
+            old_verify_keys(&*global).unwrap(),
+            SystemTime::now(),
+            Duration::from_secs(60),
+            None,
+        ))
+        .unwrap();
+        assert_eq!(
+            response["verify_keys"][key_id(&new).as_str()]["key"],
+            serde_json::to_value(Base64::new(new.public_key().to_vec())).unwrap()
+        );
+        assert_eq!(
+            response["old_verify_keys"][key_id(&old).as_str()],
+            serde_json::to_value(old_key).unwrap()
+        );
+        let signatures = response["signatures"]["example.org"].as_object().unwrap();
+        assert!(signatures.contains_key(key_id(&new).as_str()));
+        assert!(!signatures.contains_key(key_id(&old).as_str()));
+
+        drop((global, engine));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use ruma::api::client::error::ErrorKind;
+use ruma::{api::client::error::ErrorKind, UserId};
 
 use crate::{database::KeyValueDatabase, service, utils, Error, Result};
 
@@ -79,4 +79,28 @@ impl service::media::Data for KeyValueDatabase {
         };
         Ok((content_disposition, content_type, key))
     }
+
+    fn quarantine(&self, mxc: &str) -> Result<()> {
+        self.mxc_quarantined.insert(mxc.as_bytes(), &[])
+    }
+
+    fn is_quarantined(&self, mxc: &str) -> Result<bool> {
+        Ok(self.mxc_quarantined.get(mxc.as_bytes())?.is_some())
+    }
+
+    fn media_usage(&self, user_id: &UserId) -> Result<u64> {
+        self.userid_mediausage
+            .get(user_id.as_bytes())?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Media usage in db is invalid."))
+            })
+            .transpose()
+            .map(|usage| usage.unwrap_or(0))
+    }
+
+    fn set_media_usage(&self, user_id: &UserId, bytes: u64) -> Result<()> {
+        self.userid_mediausage
+            .insert(user_id.as_bytes(), &bytes.to_be_bytes())
+    }
 }
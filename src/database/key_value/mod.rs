@@ -1,11 +1,13 @@
 mod account_data;
 //mod admin;
 mod appservice;
+mod delayed_events;
 mod globals;
 mod key_backups;
 mod media;
 //mod pdu;
 mod pusher;
+mod reports;
 mod rooms;
 mod sending;
 mod transaction_ids;
@@ -0,0 +1,27 @@
+use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
+
+impl service::reports::Data for KeyValueDatabase {
+    fn add_report(&self, report: &service::reports::Report) -> Result<u64> {
+        let id = services().globals.next_count()?;
+
+        self.reportid_report.insert(
+            &id.to_be_bytes(),
+            &serde_json::to_vec(report).expect("Report::to_vec always works"),
+        )?;
+
+        Ok(id)
+    }
+
+    fn reports<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(u64, service::reports::Report)>> + 'a> {
+        Box::new(self.reportid_report.iter().map(|(key, value)| {
+            let id = utils::u64_from_bytes(&key)
+                .map_err(|_| Error::bad_database("Invalid report id in reportid_report."))?;
+            let report = serde_json::from_slice(&value)
+                .map_err(|_| Error::bad_database("Invalid report in reportid_report."))?;
+
+            Ok((id, report))
+        }))
+    }
+}
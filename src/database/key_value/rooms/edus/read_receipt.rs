@@ -1,7 +1,8 @@
-use std::mem;
+use std::{collections::HashSet, mem};
 
 use ruma::{
-    events::receipt::ReceiptEvent, serde::Raw, CanonicalJsonObject, OwnedUserId, RoomId, UserId,
+    events::receipt::ReceiptEvent, serde::Raw, CanonicalJsonObject, EventId, OwnedUserId, RoomId,
+    UserId,
 };
 
 use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
@@ -105,6 +106,36 @@ impl service::rooms::edus::read_receipt::Data for KeyValueDatabase {
         )
     }
 
+    fn remove_readreceipts_for(
+        &self,
+        room_id: &RoomId,
+        event_ids: &HashSet<&EventId>,
+    ) -> Result<()> {
+        let mut prefix = room_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        // Collected first, because not all backends can delete while iterating
+        let receipts: Vec<_> = self
+            .readreceiptid_readreceipt
+            .scan_prefix(prefix)
+            .filter(|(_, value)| {
+                serde_json::from_slice::<ReceiptEvent>(value).map_or(false, |receipt| {
+                    receipt
+                        .content
+                        .keys()
+                        .all(|event_id| event_ids.contains(&**event_id))
+                })
+            })
+            .map(|(key, _)| key)
+            .collect();
+
+        for key in receipts {
+            self.readreceiptid_readreceipt.remove(&key)?;
+        }
+
+        Ok(())
+    }
+
     fn private_read_set(&self, room_id: &RoomId, user_id: &UserId, count: u64) -> Result<()> {
         let mut key = room_id.as_bytes().to_vec();
         key.push(0xff);
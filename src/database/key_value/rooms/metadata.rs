@@ -1,4 +1,4 @@
-use ruma::{OwnedRoomId, RoomId};
+use ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedServerName, RoomId, ServerName};
 
 use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
 
@@ -42,4 +42,79 @@ impl service::rooms::metadata::Data for KeyValueDatabase {
 
         Ok(())
     }
+
+    fn is_marked_local_only(&self, room_id: &RoomId) -> Result<bool> {
+        Ok(self.localonlyroomids.get(room_id.as_bytes())?.is_some())
+    }
+
+    fn mark_local_only(&self, room_id: &RoomId, local_only: bool) -> Result<()> {
+        if local_only {
+            self.localonlyroomids.insert(room_id.as_bytes(), &[])?;
+        } else {
+            self.localonlyroomids.remove(room_id.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    fn partial_state(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<(OwnedEventId, Vec<OwnedServerName>)>> {
+        self.roomid_partialstate
+            .get(room_id.as_bytes())?
+            .map(|bytes| {
+                let mut parts = bytes.split(|&b| b == 0xff);
+                let join_event_id = parts
+                    .next()
+                    .and_then(|bytes| utils::string_from_bytes(bytes).ok())
+                    .and_then(|event_id| EventId::parse(event_id).ok())
+                    .ok_or_else(|| {
+                        Error::bad_database("Join event ID in roomid_partialstate is invalid.")
+                    })?;
+                let servers = parts
+                    .map(|bytes| {
+                        utils::string_from_bytes(bytes)
+                            .ok()
+                            .and_then(|server| OwnedServerName::try_from(server).ok())
+                            .ok_or_else(|| {
+                                Error::bad_database(
+                                    "Server name in roomid_partialstate is invalid.",
+                                )
+                            })
+                    })
+                    .collect::<Result<_>>()?;
+
+                Ok((join_event_id, servers))
+            })
+            .transpose()
+    }
+
+    fn mark_partial_state(
+        &self,
+        room_id: &RoomId,
+        join_event_id: &EventId,
+        servers: &[&ServerName],
+    ) -> Result<()> {
+        let mut value = join_event_id.as_bytes().to_vec();
+        for server in servers {
+            value.push(0xff);
+            value.extend_from_slice(server.as_bytes());
+        }
+
+        self.roomid_partialstate.insert(room_id.as_bytes(), &value)
+    }
+
+    fn unmark_partial_state(&self, room_id: &RoomId) -> Result<()> {
+        self.roomid_partialstate.remove(room_id.as_bytes())
+    }
+
+    fn partial_state_rooms<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a> {
+        Box::new(self.roomid_partialstate.iter().map(|(bytes, _)| {
+            RoomId::parse(utils::string_from_bytes(&bytes).map_err(|_| {
+                Error::bad_database("Room ID in roomid_partialstate is invalid unicode.")
+            })?)
+            .map_err(|_| Error::bad_database("Room ID in roomid_partialstate is invalid."))
+        }))
+    }
 }
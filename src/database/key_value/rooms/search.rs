@@ -4,24 +4,41 @@ use ruma::RoomId;
 
 use crate::{database::KeyValueDatabase, service, services, utils, Result};
 
+/// The keys of the words of a message in `tokenids`.
+fn tokenids<'a>(
+    shortroomid: u64,
+    pdu_id: &'a [u8],
+    message_body: &'a str,
+) -> impl Iterator<Item = Vec<u8>> + 'a {
+    message_body
+        .split_terminator(|c: char| !c.is_alphanumeric())
+        .filter(|s| !s.is_empty())
+        .filter(|word| word.len() <= 50)
+        .map(str::to_lowercase)
+        .map(move |word| {
+            let mut key = shortroomid.to_be_bytes().to_vec();
+            key.extend_from_slice(word.as_bytes());
+            key.push(0xff);
+            key.extend_from_slice(pdu_id);
+            key
+        })
+}
+
 impl service::rooms::search::Data for KeyValueDatabase {
     fn index_pdu<'a>(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()> {
-        let mut batch = message_body
-            .split_terminator(|c: char| !c.is_alphanumeric())
-            .filter(|s| !s.is_empty())
-            .filter(|word| word.len() <= 50)
-            .map(str::to_lowercase)
-            .map(|word| {
-                let mut key = shortroomid.to_be_bytes().to_vec();
-                key.extend_from_slice(word.as_bytes());
-                key.push(0xff);
-                key.extend_from_slice(pdu_id);
-                (key, Vec::new())
-            });
+        let mut batch = tokenids(shortroomid, pdu_id, message_body).map(|key| (key, Vec::new()));
 
         self.tokenids.insert_batch(&mut batch)
     }
 
+    fn deindex_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()> {
+        for key in tokenids(shortroomid, pdu_id, message_body) {
+            self.tokenids.remove(&key)?;
+        }
+
+        Ok(())
+    }
+
     fn search_pdus<'a>(
         &'a self,
         room_id: &RoomId,
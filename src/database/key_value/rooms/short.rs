@@ -31,6 +31,20 @@ impl service::rooms::short::Data for KeyValueDatabase {
         Ok(short)
     }
 
+    fn remove_shorteventid(&self, event_id: &EventId) -> Result<()> {
+        self.eventidshort_cache.lock().unwrap().remove(event_id);
+
+        if let Some(shorteventid) = self.eventid_shorteventid.get(event_id.as_bytes())? {
+            let short = utils::u64_from_bytes(&shorteventid)
+                .map_err(|_| Error::bad_database("Invalid shorteventid in db."))?;
+            self.shorteventid_cache.lock().unwrap().remove(&short);
+            self.shorteventid_eventid.remove(&shorteventid)?;
+            self.eventid_shorteventid.remove(event_id.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
     fn get_shortstatekey(
         &self,
         event_type: &StateEventType,
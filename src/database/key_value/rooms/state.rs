@@ -28,12 +28,25 @@ impl service::rooms::state::Data for KeyValueDatabase {
         Ok(())
     }
 
+    fn remove_room_state(
+        &self,
+        room_id: &RoomId,
+        _mutex_lock: &MutexGuard<'_, ()>, // Take mutex guard to make sure users get the room state mutex
+    ) -> Result<()> {
+        self.roomid_shortstatehash.remove(room_id.as_bytes())
+    }
+
     fn set_event_state(&self, shorteventid: u64, shortstatehash: u64) -> Result<()> {
         self.shorteventid_shortstatehash
             .insert(&shorteventid.to_be_bytes(), &shortstatehash.to_be_bytes())?;
         Ok(())
     }
 
+    fn remove_event_state(&self, shorteventid: u64) -> Result<()> {
+        self.shorteventid_shortstatehash
+            .remove(&shorteventid.to_be_bytes())
+    }
+
     fn get_forward_extremities(&self, room_id: &RoomId) -> Result<HashSet<Arc<EventId>>> {
         let mut prefix = room_id.as_bytes().to_vec();
         prefix.push(0xff);
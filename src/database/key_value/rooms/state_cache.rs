@@ -33,6 +33,22 @@ impl service::rooms::state_cache::Data for KeyValueDatabase {
         self.userroomid_leftstate.remove(&userroom_id)?;
         self.roomuserid_leftcount.remove(&roomuser_id)?;
 
+        // Room account data, like tags and the fully read marker
+        let mut prefix = roomuser_id.clone();
+        prefix.push(0xff);
+        for (key, _) in self.roomusertype_roomuserdataid.scan_prefix(prefix.clone()) {
+            self.roomusertype_roomuserdataid.remove(&key)?;
+        }
+        for (key, _) in self.roomuserdataid_accountdata.scan_prefix(prefix) {
+            self.roomuserdataid_accountdata.remove(&key)?;
+        }
+
+        self.roomuserid_privateread.remove(&roomuser_id)?;
+        self.roomuserid_lastprivatereadupdate.remove(&roomuser_id)?;
+        self.userroomid_notificationcount.remove(&userroom_id)?;
+        self.userroomid_highlightcount.remove(&userroom_id)?;
+        self.roomuserid_lastnotificationread.remove(&roomuser_id)?;
+
         Ok(())
     }
 
@@ -100,7 +116,7 @@ impl service::rooms::state_cache::Data for KeyValueDatabase {
 
         for joined in self.room_members(room_id).filter_map(|r| r.ok()) {
             joined_servers.insert(joined.server_name().to_owned());
-            if joined.server_name() == services().globals.server_name()
+            if services().globals.server_is_ours(joined.server_name())
                 && !services().users.is_deactivated(&joined).unwrap_or(true)
             {
                 real_users.insert(joined);
@@ -1,4 +1,4 @@
-use std::{collections::hash_map, mem::size_of, sync::Arc};
+use std::{collections::hash_map, future::Future, mem::size_of, pin::Pin, sync::Arc};
 
 use ruma::{
     api::client::error::ErrorKind, CanonicalJsonObject, EventId, OwnedUserId, RoomId, UserId,
@@ -128,12 +128,7 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
     ///
     /// Checks the `eventid_outlierpdu` Tree if not found in the timeline.
     fn get_pdu(&self, event_id: &EventId) -> Result<Option<Arc<PduEvent>>> {
-        if let Some(p) = self.pdu_cache.lock().unwrap().get_mut(event_id) {
-            return Ok(Some(Arc::clone(p)));
-        }
-
-        if let Some(pdu) = self
-            .eventid_pduid
+        self.eventid_pduid
             .get(event_id.as_bytes())?
             .map_or_else(
                 || self.eventid_outlierpdu.get(event_id.as_bytes()),
@@ -148,16 +143,7 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
                     .map_err(|_| Error::bad_database("Invalid PDU in db."))
                     .map(Arc::new)
             })
-            .transpose()?
-        {
-            self.pdu_cache
-                .lock()
-                .unwrap()
-                .insert(event_id.to_owned(), Arc::clone(&pdu));
-            Ok(Some(pdu))
-        } else {
-            Ok(None)
-        }
+            .transpose()
     }
 
     /// Returns the pdu.
@@ -227,6 +213,11 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
         }
     }
 
+    fn remove_pdu(&self, pdu_id: &[u8], event_id: &EventId) -> Result<()> {
+        self.pduid_pdu.remove(pdu_id)?;
+        self.eventid_pduid.remove(event_id.as_bytes())
+    }
+
     /// Returns an iterator over all events in a room that happened after the event with id `since`
     /// in chronological order.
     fn pdus_since<'a>(
@@ -340,6 +331,20 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
         ))
     }
 
+    fn watch_pdus<'a>(
+        &'a self,
+        room_id: &RoomId,
+    ) -> Result<Pin<Box<dyn Future<Output = ()> + Send + 'a>>> {
+        let prefix = services()
+            .rooms
+            .short
+            .get_shortroomid(room_id)?
+            .expect("room exists")
+            .to_be_bytes();
+
+        Ok(self.pduid_pdu.watch_prefix(&prefix))
+    }
+
     fn increment_notification_counts(
         &self,
         room_id: &RoomId,
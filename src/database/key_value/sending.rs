@@ -1,4 +1,6 @@
-use ruma::{ServerName, UserId};
+use std::collections::BTreeMap;
+
+use ruma::{OwnedServerName, ServerName, UserId};
 
 use crate::{
     database::KeyValueDatabase,
@@ -96,6 +98,18 @@ impl service::sending::Data for KeyValueDatabase {
         );
     }
 
+    fn queue_depths(&self) -> Result<BTreeMap<OwnedServerName, u64>> {
+        let mut depths = BTreeMap::new();
+
+        for (key, value) in self.servernameevent_data.iter() {
+            if let (OutgoingKind::Normal(server), _) = parse_servercurrentevent(&key, value)? {
+                *depths.entry(server).or_default() += 1;
+            }
+        }
+
+        Ok(depths)
+    }
+
     fn mark_as_active(&self, events: &[(SendingEventType, Vec<u8>)]) -> Result<()> {
         for (e, key) in events {
             let value = if let SendingEventType::Edu(value) = &e {
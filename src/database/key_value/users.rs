@@ -1,10 +1,14 @@
-use std::{collections::BTreeMap, mem::size_of};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    mem::size_of,
+};
 
 use ruma::{
     api::client::{device::Device, error::ErrorKind, filter::FilterDefinition},
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
     events::{AnyToDeviceEvent, StateEventType},
     serde::Raw,
+    thirdparty::Medium,
     DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch, OwnedDeviceId,
     OwnedDeviceKeyId, OwnedMxcUri, OwnedUserId, UInt, UserId,
 };
@@ -12,7 +16,10 @@ use tracing::warn;
 
 use crate::{
     database::KeyValueDatabase,
-    service::{self, users::clean_signatures},
+    service::{
+        self,
+        users::{clean_signatures, Threepid},
+    },
     services, utils, Error, Result,
 };
 
@@ -102,7 +109,11 @@ impl service::users::Data for KeyValueDatabase {
     /// Hash and set the user's password to the Argon2 hash
     fn set_password(&self, user_id: &UserId, password: Option<&str>) -> Result<()> {
         if let Some(password) = password {
-            if let Ok(hash) = utils::calculate_password_hash(password) {
+            if let Ok(hash) = utils::calculate_password_hash(
+                password,
+                services().globals.argon2_memory(),
+                services().globals.argon2_iterations(),
+            ) {
                 self.userid_password
                     .insert(user_id.as_bytes(), hash.as_bytes())?;
                 Ok(())
@@ -191,6 +202,57 @@ impl service::users::Data for KeyValueDatabase {
         Ok(())
     }
 
+    fn profile_field(&self, user_id: &UserId, key: &str) -> Result<Option<serde_json::Value>> {
+        self.useridprofilekey_value
+            .get(&profile_key(user_id, key))?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Profile field in db is invalid."))
+            })
+            .transpose()
+    }
+
+    fn set_profile_field(
+        &self,
+        user_id: &UserId,
+        key: &str,
+        value: Option<&serde_json::Value>,
+    ) -> Result<()> {
+        let profile_key = profile_key(user_id, key);
+        if let Some(value) = value {
+            self.useridprofilekey_value.insert(
+                &profile_key,
+                &serde_json::to_vec(value).expect("JSON values can be serialized"),
+            )?;
+        } else {
+            self.useridprofilekey_value.remove(&profile_key)?;
+        }
+
+        Ok(())
+    }
+
+    fn profile_fields<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<(String, serde_json::Value)>> + 'a> {
+        let prefix = profile_key(user_id, "");
+        let prefix_len = prefix.len();
+
+        Box::new(
+            self.useridprofilekey_value
+                .scan_prefix(prefix)
+                .map(move |(key, bytes)| {
+                    let key = utils::string_from_bytes(&key[prefix_len..]).map_err(|_| {
+                        Error::bad_database("Profile key in useridprofilekey_value is invalid.")
+                    })?;
+                    let value = serde_json::from_slice(&bytes).map_err(|_| {
+                        Error::bad_database("Profile field in useridprofilekey_value is invalid.")
+                    })?;
+                    Ok((key, value))
+                }),
+        )
+    }
+
     /// Adds a new device to a user.
     fn create_device(
         &self,
@@ -219,6 +281,9 @@ impl service::users::Data for KeyValueDatabase {
             })
             .expect("Device::to_string never fails."),
         )?;
+        // A request of a deleted device with the same ID might have left these behind
+        self.userdeviceid_lastseents.remove(&userdeviceid)?;
+        self.userdeviceid_lastseenip.remove(&userdeviceid)?;
 
         self.set_token(user_id, device_id, token)?;
 
@@ -236,6 +301,7 @@ impl service::users::Data for KeyValueDatabase {
             self.userdeviceid_token.remove(&userdeviceid)?;
             self.token_userdeviceid.remove(&old_token)?;
         }
+        self.userdeviceid_tokencreated.remove(&userdeviceid)?;
 
         // Remove todevice events
         let mut prefix = userdeviceid.clone();
@@ -250,11 +316,50 @@ impl service::users::Data for KeyValueDatabase {
         self.userid_devicelistversion
             .increment(user_id.as_bytes())?;
 
+        self.userdeviceid_lastseents.remove(&userdeviceid)?;
+        self.userdeviceid_lastseenip.remove(&userdeviceid)?;
+
         self.userdeviceid_metadata.remove(&userdeviceid)?;
 
         Ok(())
     }
 
+    fn remove_devices(&self, user_id: &UserId, device_ids: &[OwnedDeviceId]) -> Result<()> {
+        // Revoke all tokens before cleaning up, so an error halfway through doesn't leave some
+        // of the devices logged in
+        for device_id in device_ids {
+            let mut userdeviceid = user_id.as_bytes().to_vec();
+            userdeviceid.push(0xff);
+            userdeviceid.extend_from_slice(device_id.as_bytes());
+
+            if let Some(old_token) = self.userdeviceid_token.get(&userdeviceid)? {
+                self.userdeviceid_token.remove(&userdeviceid)?;
+                self.token_userdeviceid.remove(&old_token)?;
+            }
+        }
+
+        for device_id in device_ids {
+            self.remove_device(user_id, device_id)?;
+        }
+
+        Ok(())
+    }
+
+    fn remove_all_devices(&self, user_id: &UserId) -> Result<()> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        // Devices with a token but no metadata are included, so their tokens are revoked too
+        let device_ids = self
+            .userdeviceid_metadata
+            .scan_prefix(prefix.clone())
+            .chain(self.userdeviceid_token.scan_prefix(prefix))
+            .map(|(bytes, _)| device_id_from_userdeviceid(&bytes))
+            .collect::<Result<BTreeSet<_>>>()?;
+
+        self.remove_devices(user_id, &device_ids.into_iter().collect::<Vec<_>>())
+    }
+
     /// Returns an iterator over all device ids of this user.
     fn all_device_ids<'a>(
         &'a self,
@@ -266,17 +371,7 @@ impl service::users::Data for KeyValueDatabase {
         Box::new(
             self.userdeviceid_metadata
                 .scan_prefix(prefix)
-                .map(|(bytes, _)| {
-                    Ok(utils::string_from_bytes(
-                        bytes.rsplit(|&b| b == 0xff).next().ok_or_else(|| {
-                            Error::bad_database("UserDevice ID in db is invalid.")
-                        })?,
-                    )
-                    .map_err(|_| {
-                        Error::bad_database("Device ID in userdeviceid_metadata is invalid.")
-                    })?
-                    .into())
-                }),
+                .map(|(bytes, _)| device_id_from_userdeviceid(&bytes)),
         )
     }
 
@@ -300,10 +395,51 @@ impl service::users::Data for KeyValueDatabase {
             .insert(&userdeviceid, token.as_bytes())?;
         self.token_userdeviceid
             .insert(token.as_bytes(), &userdeviceid)?;
+        self.userdeviceid_tokencreated.insert(
+            &userdeviceid,
+            &u64::from(MilliSecondsSinceUnixEpoch::now().get()).to_be_bytes(),
+        )?;
 
         Ok(())
     }
 
+    fn token_created(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<MilliSecondsSinceUnixEpoch>> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        self.userdeviceid_tokencreated
+            .get(&userdeviceid)?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .ok()
+                    .and_then(UInt::new)
+                    .map(MilliSecondsSinceUnixEpoch)
+                    .ok_or_else(|| {
+                        Error::bad_database("Invalid timestamp in userdeviceid_tokencreated.")
+                    })
+            })
+            .transpose()
+    }
+
+    fn set_token_created(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        created: MilliSecondsSinceUnixEpoch,
+    ) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        self.userdeviceid_tokencreated
+            .insert(&userdeviceid, &u64::from(created.get()).to_be_bytes())
+    }
+
     fn add_one_time_key(
         &self,
         user_id: &UserId,
@@ -851,6 +987,29 @@ impl service::users::Data for KeyValueDatabase {
         Ok(())
     }
 
+    fn update_device_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        ip: Option<String>,
+        ts: MilliSecondsSinceUnixEpoch,
+    ) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        // Only the last seen fields are written, so this can't undo a concurrent display name
+        // change
+        self.userdeviceid_lastseents
+            .insert(&userdeviceid, &u64::from(ts.get()).to_be_bytes())?;
+        if let Some(ip) = ip {
+            self.userdeviceid_lastseenip
+                .insert(&userdeviceid, ip.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
     /// Get device metadata.
     fn get_device_metadata(
         &self,
@@ -864,9 +1023,10 @@ impl service::users::Data for KeyValueDatabase {
         self.userdeviceid_metadata
             .get(&userdeviceid)?
             .map_or(Ok(None), |bytes| {
-                Ok(Some(serde_json::from_slice(&bytes).map_err(|_| {
+                let device = serde_json::from_slice(&bytes).map_err(|_| {
                     Error::bad_database("Metadata in userdeviceid_metadata is invalid.")
-                })?))
+                })?;
+                self.with_last_seen(&userdeviceid, device).map(Some)
             })
     }
 
@@ -890,14 +1050,77 @@ impl service::users::Data for KeyValueDatabase {
         Box::new(
             self.userdeviceid_metadata
                 .scan_prefix(key)
-                .map(|(_, bytes)| {
-                    serde_json::from_slice::<Device>(&bytes).map_err(|_| {
+                .map(|(userdeviceid, bytes)| {
+                    let device = serde_json::from_slice::<Device>(&bytes).map_err(|_| {
                         Error::bad_database("Device in userdeviceid_metadata is invalid.")
+                    })?;
+                    self.with_last_seen(&userdeviceid, device)
+                }),
+        )
+    }
+
+    fn add_threepid(&self, user_id: &UserId, threepid: &Threepid) -> Result<()> {
+        let threepid_key = threepid_key(&threepid.medium, &threepid.address);
+
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&threepid_key);
+
+        self.userthreepid_metadata.insert(
+            &key,
+            &serde_json::to_vec(threepid).expect("Threepid::to_vec always works"),
+        )?;
+        self.threepid_userid
+            .insert(&threepid_key, user_id.as_bytes())?;
+
+        Ok(())
+    }
+
+    fn remove_threepid(&self, user_id: &UserId, medium: &Medium, address: &str) -> Result<()> {
+        let threepid_key = threepid_key(medium, address);
+
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&threepid_key);
+
+        self.userthreepid_metadata.remove(&key)?;
+        if self.threepid_userid.get(&threepid_key)?.as_deref() == Some(user_id.as_bytes()) {
+            self.threepid_userid.remove(&threepid_key)?;
+        }
+
+        Ok(())
+    }
+
+    fn threepids<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<Threepid>> + 'a> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        Box::new(
+            self.userthreepid_metadata
+                .scan_prefix(prefix)
+                .map(|(_, bytes)| {
+                    serde_json::from_slice(&bytes).map_err(|_| {
+                        Error::bad_database("Threepid in userthreepid_metadata is invalid.")
                     })
                 }),
         )
     }
 
+    fn find_from_threepid(&self, medium: &Medium, address: &str) -> Result<Option<OwnedUserId>> {
+        self.threepid_userid
+            .get(&threepid_key(medium, address))?
+            .map(|bytes| {
+                UserId::parse(utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("User ID in threepid_userid is invalid unicode.")
+                })?)
+                .map_err(|_| Error::bad_database("User ID in threepid_userid is invalid."))
+            })
+            .transpose()
+    }
+
     /// Creates a new sync filter. Returns the filter id.
     fn create_filter(&self, user_id: &UserId, filter: &FilterDefinition) -> Result<String> {
         let filter_id = utils::random_string(4);
@@ -928,6 +1151,46 @@ impl service::users::Data for KeyValueDatabase {
             Ok(None)
         }
     }
+
+    fn rooms_created(&self, user_id: &UserId) -> Result<u64> {
+        self.userid_roomscreated
+            .get(user_id.as_bytes())?
+            .map_or(Ok(0), |bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid rooms created count in db."))
+            })
+    }
+
+    fn increment_rooms_created(&self, user_id: &UserId) -> Result<()> {
+        self.userid_roomscreated.increment(user_id.as_bytes())?;
+        Ok(())
+    }
+}
+
+impl KeyValueDatabase {
+    /// Fills in the last seen fields, which are stored apart from the rest of the device metadata.
+    /// Devices that weren't used since this was introduced keep the fields of their metadata.
+    fn with_last_seen(&self, userdeviceid: &[u8], mut device: Device) -> Result<Device> {
+        if let Some(bytes) = self.userdeviceid_lastseents.get(userdeviceid)? {
+            device.last_seen_ts = Some(
+                utils::u64_from_bytes(&bytes)
+                    .ok()
+                    .and_then(UInt::new)
+                    .map(MilliSecondsSinceUnixEpoch)
+                    .ok_or_else(|| {
+                        Error::bad_database("Invalid timestamp in userdeviceid_lastseents.")
+                    })?,
+            );
+        }
+
+        if let Some(bytes) = self.userdeviceid_lastseenip.get(userdeviceid)? {
+            device.last_seen_ip = Some(utils::string_from_bytes(&bytes).map_err(|_| {
+                Error::bad_database("Invalid IP address in userdeviceid_lastseenip.")
+            })?);
+        }
+
+        Ok(device)
+    }
 }
 
 /// Will only return with Some(username) if the password was not empty and the
@@ -951,3 +1214,28 @@ fn get_username_with_valid_password(username: &[u8], password: &[u8]) -> Option<
         }
     }
 }
+
+fn threepid_key(medium: &Medium, address: &str) -> Vec<u8> {
+    let mut key = medium.as_str().as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(address.as_bytes());
+    key
+}
+
+fn profile_key(user_id: &UserId, key: &str) -> Vec<u8> {
+    let mut profile_key = user_id.as_bytes().to_vec();
+    profile_key.push(0xff);
+    profile_key.extend_from_slice(key.as_bytes());
+    profile_key
+}
+
+fn device_id_from_userdeviceid(bytes: &[u8]) -> Result<OwnedDeviceId> {
+    Ok(utils::string_from_bytes(
+        bytes
+            .rsplit(|&b| b == 0xff)
+            .next()
+            .ok_or_else(|| Error::bad_database("UserDevice ID in db is invalid."))?,
+    )
+    .map_err(|_| Error::bad_database("Device ID in userdeviceid_metadata is invalid."))?
+    .into())
+}
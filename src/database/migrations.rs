@@ -0,0 +1,663 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::Write,
+    mem::size_of,
+};
+
+use ruma::{
+    events::{push_rules::PushRulesEvent, GlobalAccountDataEventType, StateEventType},
+    EventId, OwnedRoomId, RoomId, UserId,
+};
+use tracing::{debug, error, info, warn};
+
+use super::KeyValueDatabase;
+use crate::{services, utils, Error, Result};
+
+type Migration = (u64, fn(&KeyValueDatabase) -> Result<()>);
+
+/// All migrations in the order they need to be applied. A database with version `n` has all
+/// steps up to and including `n` applied.
+const MIGRATIONS: &[Migration] = &[
+    (1, migrate_1),
+    (2, migrate_2),
+    (3, migrate_3),
+    (4, migrate_4),
+    (5, migrate_5),
+    (6, migrate_6),
+    (7, migrate_7),
+    (8, migrate_8),
+    (9, migrate_9),
+    (10, migrate_10),
+    (11, migrate_11),
+    (12, migrate_12),
+    (13, migrate_13),
+];
+
+/// The database version of a database with all migrations applied.
+pub(super) fn latest_version() -> u64 {
+    MIGRATIONS.last().map_or(0, |(version, _)| *version)
+}
+
+/// Returns the steps that still need to be applied to a database with the given version.
+fn pending(stored_version: u64) -> Result<&'static [Migration]> {
+    if stored_version > latest_version() {
+        error!(
+            "The database has version {}, but this Conduit only supports up to version {}. \
+             Downgrading Conduit is not supported, please use a newer version.",
+            stored_version,
+            latest_version()
+        );
+        return Err(Error::bad_database(
+            "Database was created by a newer version of Conduit.",
+        ));
+    }
+
+    let applied = MIGRATIONS
+        .iter()
+        .take_while(|(version, _)| *version <= stored_version)
+        .count();
+
+    Ok(&MIGRATIONS[applied..])
+}
+
+/// Upgrades an existing database to the latest version.
+///
+/// The key value backends have no transactions spanning multiple trees, so each step is written
+/// to be safe to run again if Conduit stops in the middle of it. The stored version is bumped
+/// after every step.
+pub(super) fn run(db: &KeyValueDatabase) -> Result<()> {
+    let stored_version = services().globals.database_version()?;
+
+    apply(db, pending(stored_version)?, |version| {
+        services().globals.bump_database_version(version)
+    })
+}
+
+/// Applies `steps` in order and calls `bump_version` after each of them.
+fn apply(
+    db: &KeyValueDatabase,
+    steps: &[Migration],
+    bump_version: impl Fn(u64) -> Result<()>,
+) -> Result<()> {
+    for (version, migrate) in steps {
+        warn!("Migration: {} -> {} started", version - 1, version);
+
+        migrate(db)?;
+        db.flush()?;
+        bump_version(*version)?;
+
+        warn!("Migration: {} -> {} finished", version - 1, version);
+    }
+
+    Ok(())
+}
+
+/// Adds the server -> room direction of the room server index
+fn migrate_1(db: &KeyValueDatabase) -> Result<()> {
+    for (roomserverid, _) in db.roomserverids.iter() {
+        let mut parts = roomserverid.split(|&b| b == 0xff);
+        let room_id = parts.next().expect("split always returns one element");
+        let servername = match parts.next() {
+            Some(s) => s,
+            None => {
+                error!("Migration: Invalid roomserverid in db.");
+                continue;
+            }
+        };
+        let mut serverroomid = servername.to_vec();
+        serverroomid.push(0xff);
+        serverroomid.extend_from_slice(room_id);
+
+        db.serverroomids.insert(&serverroomid, &[])?;
+    }
+
+    Ok(())
+}
+
+/// Replaces hashed empty passwords with empty ones
+fn migrate_2(db: &KeyValueDatabase) -> Result<()> {
+    // We accidentally inserted hashed versions of "" into the db instead of just ""
+    for (userid, password) in db.userid_password.iter() {
+        let password = utils::string_from_bytes(&password);
+
+        let empty_hashed_password = password.map_or(false, |password| {
+            argon2::verify_encoded(&password, b"").unwrap_or(false)
+        });
+
+        if empty_hashed_password {
+            db.userid_password.insert(&userid, b"")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves media from the database to the filesystem
+fn migrate_3(db: &KeyValueDatabase) -> Result<()> {
+    // Move media to filesystem
+    for (key, content) in db.mediaid_file.iter() {
+        if content.is_empty() {
+            continue;
+        }
+
+        let path = services().globals.get_media_file(&key);
+        let mut file = fs::File::create(path)?;
+        file.write_all(&content)?;
+        db.mediaid_file.insert(&key, &[])?;
+    }
+
+    Ok(())
+}
+
+/// Adds federated users as deactivated users
+fn migrate_4(db: &KeyValueDatabase) -> Result<()> {
+    // Add federated users to services() as deactivated
+    for our_user in services().users.iter() {
+        let our_user = our_user?;
+        if services().users.is_deactivated(&our_user)? {
+            continue;
+        }
+        for room in services().rooms.state_cache.rooms_joined(&our_user) {
+            for user in services().rooms.state_cache.room_members(&room?) {
+                let user = user?;
+                if !services().globals.server_is_ours(user.server_name()) {
+                    info!(?user, "Migration: creating user");
+                    services().users.create(&user, None)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Upgrades the room account data store
+fn migrate_5(db: &KeyValueDatabase) -> Result<()> {
+    // Upgrade user data store
+    for (roomuserdataid, _) in db.roomuserdataid_accountdata.iter() {
+        let mut parts = roomuserdataid.split(|&b| b == 0xff);
+        let room_id = parts.next().unwrap();
+        let user_id = parts.next().unwrap();
+        let event_type = roomuserdataid.rsplit(|&b| b == 0xff).next().unwrap();
+
+        let mut key = room_id.to_vec();
+        key.push(0xff);
+        key.extend_from_slice(user_id);
+        key.push(0xff);
+        key.extend_from_slice(event_type);
+
+        db.roomusertype_roomuserdataid
+            .insert(&key, &roomuserdataid)?;
+    }
+
+    Ok(())
+}
+
+/// Sets the member counts of all rooms
+fn migrate_6(db: &KeyValueDatabase) -> Result<()> {
+    // Set room member count
+    for (roomid, _) in db.roomid_shortstatehash.iter() {
+        let string = utils::string_from_bytes(&roomid).unwrap();
+        let room_id = <&RoomId>::try_from(string.as_str()).unwrap();
+        services().rooms.state_cache.update_joined_count(room_id)?;
+    }
+
+    Ok(())
+}
+
+/// Upgrades the state store to compressed state diffs
+fn migrate_7(db: &KeyValueDatabase) -> Result<()> {
+    // Upgrade state store
+    let mut last_roomstates: HashMap<OwnedRoomId, u64> = HashMap::new();
+    let mut current_sstatehash: Option<u64> = None;
+    let mut current_room = None;
+    let mut current_state = HashSet::new();
+    let mut counter = 0;
+
+    let mut handle_state = |current_sstatehash: u64,
+                            current_room: &RoomId,
+                            current_state: HashSet<_>,
+                            last_roomstates: &mut HashMap<_, _>| {
+        counter += 1;
+        let last_roomsstatehash = last_roomstates.get(current_room);
+
+        let states_parents = last_roomsstatehash.map_or_else(
+            || Ok(Vec::new()),
+            |&last_roomsstatehash| {
+                services()
+                    .rooms
+                    .state_compressor
+                    .load_shortstatehash_info(last_roomsstatehash)
+            },
+        )?;
+
+        let (statediffnew, statediffremoved) = if let Some(parent_stateinfo) = states_parents.last()
+        {
+            let statediffnew = current_state
+                .difference(&parent_stateinfo.1)
+                .copied()
+                .collect::<HashSet<_>>();
+
+            let statediffremoved = parent_stateinfo
+                .1
+                .difference(&current_state)
+                .copied()
+                .collect::<HashSet<_>>();
+
+            (statediffnew, statediffremoved)
+        } else {
+            (current_state, HashSet::new())
+        };
+
+        services().rooms.state_compressor.save_state_from_diff(
+            current_sstatehash,
+            statediffnew,
+            statediffremoved,
+            2, // every state change is 2 event changes on average
+            states_parents,
+        )?;
+
+        /*
+        let mut tmp = services().rooms.load_shortstatehash_info(&current_sstatehash)?;
+        let state = tmp.pop().unwrap();
+        println!(
+            "{}\t{}{:?}: {:?} + {:?} - {:?}",
+            current_room,
+            "  ".repeat(tmp.len()),
+            utils::u64_from_bytes(&current_sstatehash).unwrap(),
+            tmp.last().map(|b| utils::u64_from_bytes(&b.0).unwrap()),
+            state
+                .2
+                .iter()
+                .map(|b| utils::u64_from_bytes(&b[size_of::<u64>()..]).unwrap())
+                .collect::<Vec<_>>(),
+            state
+                .3
+                .iter()
+                .map(|b| utils::u64_from_bytes(&b[size_of::<u64>()..]).unwrap())
+                .collect::<Vec<_>>()
+        );
+        */
+
+        Ok::<_, Error>(())
+    };
+
+    for (k, seventid) in db._db.open_tree("stateid_shorteventid")?.iter() {
+        let sstatehash =
+            utils::u64_from_bytes(&k[0..size_of::<u64>()]).expect("number of bytes is correct");
+        let sstatekey = k[size_of::<u64>()..].to_vec();
+        if Some(sstatehash) != current_sstatehash {
+            if let Some(current_sstatehash) = current_sstatehash {
+                handle_state(
+                    current_sstatehash,
+                    current_room.as_deref().unwrap(),
+                    current_state,
+                    &mut last_roomstates,
+                )?;
+                last_roomstates.insert(current_room.clone().unwrap(), current_sstatehash);
+            }
+            current_state = HashSet::new();
+            current_sstatehash = Some(sstatehash);
+
+            let event_id = db.shorteventid_eventid.get(&seventid).unwrap().unwrap();
+            let string = utils::string_from_bytes(&event_id).unwrap();
+            let event_id = <&EventId>::try_from(string.as_str()).unwrap();
+            let pdu = services()
+                .rooms
+                .timeline
+                .get_pdu(event_id)
+                .unwrap()
+                .unwrap();
+
+            if Some(&pdu.room_id) != current_room.as_ref() {
+                current_room = Some(pdu.room_id.clone());
+            }
+        }
+
+        let mut val = sstatekey;
+        val.extend_from_slice(&seventid);
+        current_state.insert(val.try_into().expect("size is correct"));
+    }
+
+    if let Some(current_sstatehash) = current_sstatehash {
+        handle_state(
+            current_sstatehash,
+            current_room.as_deref().unwrap(),
+            current_state,
+            &mut last_roomstates,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Generates short room ids and uses them in pdu ids
+fn migrate_8(db: &KeyValueDatabase) -> Result<()> {
+    // Generate short room ids for all rooms
+    for (room_id, _) in db.roomid_shortstatehash.iter() {
+        let shortroomid = services().globals.next_count()?.to_be_bytes();
+        db.roomid_shortroomid.insert(&room_id, &shortroomid)?;
+        info!("Migration: 8");
+    }
+    // Update pduids db layout
+    let mut batch = db.pduid_pdu.iter().filter_map(|(key, v)| {
+        if !key.starts_with(b"!") {
+            return None;
+        }
+        let mut parts = key.splitn(2, |&b| b == 0xff);
+        let room_id = parts.next().unwrap();
+        let count = parts.next().unwrap();
+
+        let short_room_id = db
+            .roomid_shortroomid
+            .get(room_id)
+            .unwrap()
+            .expect("shortroomid should exist");
+
+        let mut new_key = short_room_id;
+        new_key.extend_from_slice(count);
+
+        Some((new_key, v))
+    });
+
+    db.pduid_pdu.insert_batch(&mut batch)?;
+
+    let mut batch2 = db.eventid_pduid.iter().filter_map(|(k, value)| {
+        if !value.starts_with(b"!") {
+            return None;
+        }
+        let mut parts = value.splitn(2, |&b| b == 0xff);
+        let room_id = parts.next().unwrap();
+        let count = parts.next().unwrap();
+
+        let short_room_id = db
+            .roomid_shortroomid
+            .get(room_id)
+            .unwrap()
+            .expect("shortroomid should exist");
+
+        let mut new_value = short_room_id;
+        new_value.extend_from_slice(count);
+
+        Some((k, new_value))
+    });
+
+    db.eventid_pduid.insert_batch(&mut batch2)?;
+
+    Ok(())
+}
+
+/// Uses short room ids in the search token index
+fn migrate_9(db: &KeyValueDatabase) -> Result<()> {
+    // Update tokenids db layout
+    let mut iter = db
+        .tokenids
+        .iter()
+        .filter_map(|(key, _)| {
+            if !key.starts_with(b"!") {
+                return None;
+            }
+            let mut parts = key.splitn(4, |&b| b == 0xff);
+            let room_id = parts.next().unwrap();
+            let word = parts.next().unwrap();
+            let _pdu_id_room = parts.next().unwrap();
+            let pdu_id_count = parts.next().unwrap();
+
+            let short_room_id = db
+                .roomid_shortroomid
+                .get(room_id)
+                .unwrap()
+                .expect("shortroomid should exist");
+            let mut new_key = short_room_id;
+            new_key.extend_from_slice(word);
+            new_key.push(0xff);
+            new_key.extend_from_slice(pdu_id_count);
+            Some((new_key, Vec::new()))
+        })
+        .peekable();
+
+    while iter.peek().is_some() {
+        db.tokenids.insert_batch(&mut iter.by_ref().take(1000))?;
+        debug!("Inserted smaller batch");
+    }
+
+    info!("Deleting starts");
+
+    let batch2: Vec<_> = db
+        .tokenids
+        .iter()
+        .filter_map(|(key, _)| {
+            if key.starts_with(b"!") {
+                Some(key)
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    for key in batch2 {
+        db.tokenids.remove(&key)?;
+    }
+
+    Ok(())
+}
+
+/// Adds the other direction for short state keys and forces device list updates
+fn migrate_10(db: &KeyValueDatabase) -> Result<()> {
+    // Add other direction for shortstatekeys
+    for (statekey, shortstatekey) in db.statekey_shortstatekey.iter() {
+        db.shortstatekey_statekey
+            .insert(&shortstatekey, &statekey)?;
+    }
+
+    // Force E2EE device list updates so we can send them over federation
+    for user_id in services().users.iter().filter_map(|r| r.ok()) {
+        services().users.mark_device_key_update(&user_id)?;
+    }
+
+    Ok(())
+}
+
+/// Clears stored UIAA requests
+fn migrate_11(db: &KeyValueDatabase) -> Result<()> {
+    db._db
+        .open_tree("userdevicesessionid_uiaarequest")?
+        .clear()?;
+
+    Ok(())
+}
+
+/// Renames push rules to their spec compliant ids
+fn migrate_12(db: &KeyValueDatabase) -> Result<()> {
+    for username in services().users.list_local_users().unwrap() {
+        let user =
+            UserId::parse_with_server_name(username, services().globals.server_name()).unwrap();
+
+        let raw_rules_list = services()
+            .account_data
+            .get(
+                None,
+                &user,
+                GlobalAccountDataEventType::PushRules.to_string().into(),
+            )
+            .unwrap()
+            .expect("Username is invalid");
+
+        let mut account_data =
+            serde_json::from_str::<PushRulesEvent>(raw_rules_list.get()).unwrap();
+        let rules_list = &mut account_data.content.global;
+
+        //content rule
+        {
+            let content_rule_transformation =
+                [".m.rules.contains_user_name", ".m.rule.contains_user_name"];
+
+            let rule = rules_list.content.get(content_rule_transformation[0]);
+            if rule.is_some() {
+                let mut rule = rule.unwrap().clone();
+                rule.rule_id = content_rule_transformation[1].to_owned();
+                rules_list.content.remove(content_rule_transformation[0]);
+                rules_list.content.insert(rule);
+            }
+        }
+
+        //underride rules
+        {
+            let underride_rule_transformation = [
+                [".m.rules.call", ".m.rule.call"],
+                [".m.rules.room_one_to_one", ".m.rule.room_one_to_one"],
+                [
+                    ".m.rules.encrypted_room_one_to_one",
+                    ".m.rule.encrypted_room_one_to_one",
+                ],
+                [".m.rules.message", ".m.rule.message"],
+                [".m.rules.encrypted", ".m.rule.encrypted"],
+            ];
+
+            for transformation in underride_rule_transformation {
+                let rule = rules_list.underride.get(transformation[0]);
+                if let Some(rule) = rule {
+                    let mut rule = rule.clone();
+                    rule.rule_id = transformation[1].to_owned();
+                    rules_list.underride.remove(transformation[0]);
+                    rules_list.underride.insert(rule);
+                }
+            }
+        }
+
+        services().account_data.update(
+            None,
+            &user,
+            GlobalAccountDataEventType::PushRules.to_string().into(),
+            &serde_json::to_value(account_data).expect("to json value always works"),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Counts the rooms each local user created, for `max_rooms_per_user_create`
+fn migrate_13(db: &KeyValueDatabase) -> Result<()> {
+    // Counted from scratch, so running this again doesn't count rooms twice
+    db.userid_roomscreated.clear()?;
+
+    for room_id in services().rooms.metadata.iter_ids().filter_map(|r| r.ok()) {
+        let creator = match services().rooms.state_accessor.room_state_get(
+            &room_id,
+            &StateEventType::RoomCreate,
+            "",
+        )? {
+            Some(create) => create.sender.clone(),
+            None => continue,
+        };
+
+        if services().globals.server_is_ours(creator.server_name()) {
+            db.userid_roomscreated.increment(creator.as_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::testing;
+
+    #[test]
+    fn versions_are_consecutive() {
+        for (i, (version, _)) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(*version, i as u64 + 1);
+        }
+    }
+
+    #[test]
+    fn v1_database_runs_all_later_steps() {
+        let versions: Vec<_> = pending(1)
+            .unwrap()
+            .iter()
+            .map(|(version, _)| *version)
+            .collect();
+        assert_eq!(versions, (2..=latest_version()).collect::<Vec<_>>());
+
+        assert!(pending(latest_version()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn newer_database_is_refused() {
+        assert!(pending(latest_version() + 1).is_err());
+    }
+
+    /// The steps that only change the layout of the fixture. The others read through the
+    /// services, which tests share, so they would work on the shared database instead.
+    const LAYOUT_STEPS: &[u64] = &[5, 8, 9, 10, 11];
+
+    #[tokio::test]
+    async fn v1_fixture_is_upgraded() {
+        testing::services_for_tests().await;
+        let db = KeyValueDatabase::open(&testing::config()).unwrap();
+
+        let room_id = b"!fixture:example.org".as_slice();
+        let key = |parts: &[&[u8]]| parts.join(&0xff);
+        let count = 7_u64.to_be_bytes();
+        let pdu_id = key(&[room_id, &count]);
+
+        // Written like version 1 of the database did
+        let roomuserdataid = key(&[room_id, b"@alice:example.org", &count, b"m.tag"]);
+        db.roomuserdataid_accountdata
+            .insert(&roomuserdataid, b"{}")
+            .unwrap();
+        db.roomid_shortstatehash
+            .insert(room_id, &1_u64.to_be_bytes())
+            .unwrap();
+        db.pduid_pdu.insert(&pdu_id, b"pdu").unwrap();
+        db.eventid_pduid.insert(b"$event", &pdu_id).unwrap();
+        db.tokenids
+            .insert(&key(&[room_id, b"hello", room_id, &count]), &[])
+            .unwrap();
+        let statekey = key(&[b"m.room.member", b"@alice:example.org"]);
+        db.statekey_shortstatekey
+            .insert(&statekey, &2_u64.to_be_bytes())
+            .unwrap();
+
+        let versions = std::sync::Mutex::new(Vec::new());
+        let steps: Vec<_> = pending(1)
+            .unwrap()
+            .iter()
+            .filter(|(version, _)| LAYOUT_STEPS.contains(version))
+            .copied()
+            .collect();
+        apply(&db, &steps, |version| {
+            versions.lock().unwrap().push(version);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(*versions.lock().unwrap(), LAYOUT_STEPS);
+
+        assert_eq!(
+            db.roomusertype_roomuserdataid
+                .get(&key(&[room_id, b"@alice:example.org", b"m.tag"]))
+                .unwrap(),
+            Some(roomuserdataid)
+        );
+
+        let mut new_pdu_id = db.roomid_shortroomid.get(room_id).unwrap().unwrap();
+        let mut new_tokenid = new_pdu_id.clone();
+        new_pdu_id.extend_from_slice(&count);
+        assert_eq!(
+            db.pduid_pdu.get(&new_pdu_id).unwrap(),
+            Some(b"pdu".to_vec())
+        );
+        assert_eq!(db.eventid_pduid.get(b"$event").unwrap(), Some(new_pdu_id));
+
+        new_tokenid.extend_from_slice(&key(&[b"hello", &count]));
+        let tokenids: Vec<_> = db.tokenids.iter().map(|(key, _)| key).collect();
+        assert_eq!(tokenids, [new_tokenid]);
+
+        assert_eq!(
+            db.shortstatekey_statekey.get(&2_u64.to_be_bytes()).unwrap(),
+            Some(statekey)
+        );
+    }
+}
@@ -1,25 +1,25 @@
 pub mod abstraction;
+mod integrity;
 pub mod key_value;
+mod migrations;
 
-use crate::{services, utils, Config, Error, PduEvent, Result, Services, SERVICES};
+use crate::{
+    config::DatabaseDurability, services, utils, Config, Error, Result, Services, SERVICES,
+};
 use abstraction::{KeyValueDatabaseEngine, KvTree};
 use directories::ProjectDirs;
 use lru_cache::LruCache;
 use ruma::{
     events::{
-        push_rules::{PushRulesEvent, PushRulesEventContent},
-        room::message::RoomMessageEventContent,
+        push_rules::PushRulesEventContent, room::message::RoomMessageEventContent,
         GlobalAccountDataEvent, GlobalAccountDataEventType, StateEventType,
     },
     push::Ruleset,
-    CanonicalJsonValue, EventId, OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId,
-    UserId,
+    CanonicalJsonValue, EventId, OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedUserId,
 };
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
-    fs::{self, remove_dir_all},
-    io::Write,
-    mem::size_of,
+    fs::remove_dir_all,
     path::Path,
     sync::{Arc, Mutex, RwLock},
 };
@@ -38,10 +38,17 @@ pub struct KeyValueDatabase {
     pub(super) userid_displayname: Arc<dyn KvTree>,
     pub(super) userid_avatarurl: Arc<dyn KvTree>,
     pub(super) userid_blurhash: Arc<dyn KvTree>,
+    pub(super) useridprofilekey_value: Arc<dyn KvTree>, // UserIdProfileKey = UserId + ProfileKey
     pub(super) userdeviceid_token: Arc<dyn KvTree>,
     pub(super) userdeviceid_metadata: Arc<dyn KvTree>, // This is also used to check if a device exists
+    pub(super) userdeviceid_lastseents: Arc<dyn KvTree>, // LastSeenTs = MilliSecondsSinceUnixEpoch
+    pub(super) userdeviceid_lastseenip: Arc<dyn KvTree>,
     pub(super) userid_devicelistversion: Arc<dyn KvTree>, // DevicelistVersion = u64
     pub(super) token_userdeviceid: Arc<dyn KvTree>,
+    pub(super) userdeviceid_tokencreated: Arc<dyn KvTree>, // TokenCreated = MilliSecondsSinceUnixEpoch
+    pub(super) userid_roomscreated: Arc<dyn KvTree>, // RoomsCreated = Count of rooms the user created (u64)
+    pub(super) userthreepid_metadata: Arc<dyn KvTree>, // UserThreepid = UserId + Medium + Address
+    pub(super) threepid_userid: Arc<dyn KvTree>,     // Threepid = Medium + Address
 
     pub(super) onetimekeyid_onetimekeys: Arc<dyn KvTree>, // OneTimeKeyId = UserId + DeviceKeyId
     pub(super) userid_lastonetimekeyupdate: Arc<dyn KvTree>, // LastOneTimeKeyUpdate = Count
@@ -94,6 +101,8 @@ pub struct KeyValueDatabase {
     pub(super) roomuserid_leftcount: Arc<dyn KvTree>,
 
     pub(super) disabledroomids: Arc<dyn KvTree>, // Rooms where incoming federation handling is disabled
+    pub(super) localonlyroomids: Arc<dyn KvTree>, // Rooms an admin stopped federating
+    pub(super) roomid_partialstate: Arc<dyn KvTree>, // PartialState = JoinEventId + 0xff + ServerName + 0xff + ...
 
     pub(super) lazyloadedids: Arc<dyn KvTree>, // LazyLoadedIds = UserId + DeviceId + RoomId + LazyLoadedUserId
 
@@ -134,6 +143,8 @@ pub struct KeyValueDatabase {
 
     //pub media: media::Media,
     pub(super) mediaid_file: Arc<dyn KvTree>, // MediaId = MXC + WidthHeight + ContentDisposition + ContentType
+    pub(super) mxc_quarantined: Arc<dyn KvTree>,
+    pub(super) userid_mediausage: Arc<dyn KvTree>, // MediaUsage = Bytes uploaded (u64)
     //pub key_backups: key_backups::KeyBackups,
     pub(super) backupid_algorithm: Arc<dyn KvTree>, // BackupId = UserId + Version(Count)
     pub(super) backupid_etag: Arc<dyn KvTree>,      // BackupId = UserId + Version(Count)
@@ -146,6 +157,11 @@ pub struct KeyValueDatabase {
     pub(super) servernameevent_data: Arc<dyn KvTree>, // ServernameEvent = (+ / $)SenderKey / ServerName / UserId + PduId / Id (for edus), Data = EDU content
     pub(super) servercurrentevent_data: Arc<dyn KvTree>, // ServerCurrentEvents = (+ / $)ServerName / UserId + PduId / Id (for edus), Data = EDU content
 
+    //pub reports: reports::Reports,
+    pub(super) reportid_report: Arc<dyn KvTree>, // ReportId = Count
+
+    pub(super) userdelayid_delayedevent: Arc<dyn KvTree>, // UserDelayId = UserId + DelayId
+
     //pub appservice: appservice::Appservice,
     pub(super) id_appserviceregistrations: Arc<dyn KvTree>,
 
@@ -153,7 +169,6 @@ pub struct KeyValueDatabase {
     pub(super) senderkey_pusher: Arc<dyn KvTree>,
 
     pub(super) cached_registrations: Arc<RwLock<HashMap<String, serde_yaml::Value>>>,
-    pub(super) pdu_cache: Mutex<LruCache<OwnedEventId, Arc<PduEvent>>>,
     pub(super) shorteventid_cache: Mutex<LruCache<u64, Arc<EventId>>>,
     pub(super) auth_chain_cache: Mutex<LruCache<Vec<u64>, Arc<HashSet<u64>>>>,
     pub(super) eventidshort_cache: Mutex<LruCache<OwnedEventId, u64>>,
@@ -180,53 +195,91 @@ impl KeyValueDatabase {
     fn check_db_setup(config: &Config) -> Result<()> {
         let path = Path::new(&config.database_path);
 
-        let sled_exists = path.join("db").exists();
-        let sqlite_exists = path.join("conduit.db").exists();
-        let rocksdb_exists = path.join("IDENTITY").exists();
-
-        let mut count = 0;
-
-        if sled_exists {
-            count += 1;
-        }
-
-        if sqlite_exists {
-            count += 1;
+        if path.exists() && !path.is_dir() {
+            return Err(Error::bad_config(
+                "database_path points to a file, but needs to be a directory.",
+            ));
         }
 
-        if rocksdb_exists {
-            count += 1;
-        }
+        let found = Self::existing_backends(path);
 
-        if count > 1 {
+        if found.len() > 1 {
             warn!("Multiple databases at database_path detected");
             return Ok(());
         }
 
-        if sled_exists && config.database_backend != "sled" {
-            return Err(Error::bad_config(
-                "Found sled at database_path, but is not specified in config.",
-            ));
+        if let Some(backend) = found.first() {
+            if *backend != config.database_backend {
+                error!(
+                    "Found a {} database at database_path, but database_backend is set to {}. \
+                     Conduit can't convert databases between backends, so either set \
+                     database_backend = \"{}\" or use a different database_path.",
+                    backend, config.database_backend, backend
+                );
+                return Err(Error::bad_config(
+                    "Existing database at database_path doesn't match database_backend.",
+                ));
+            }
         }
 
-        if sqlite_exists && config.database_backend != "sqlite" {
-            return Err(Error::bad_config(
-                "Found sqlite at database_path, but is not specified in config.",
-            ));
-        }
+        Ok(())
+    }
+
+    /// The backends of the databases found at `path`.
+    fn existing_backends(path: &Path) -> Vec<&'static str> {
+        [
+            ("sled", path.join("db")),
+            ("sqlite", path.join("conduit.db")),
+            ("rocksdb", path.join("IDENTITY")),
+            ("persy", path.join("db.persy")),
+        ]
+        .into_iter()
+        .filter(|(_, file)| file.exists())
+        .map(|(backend, _)| backend)
+        .collect()
+    }
+
+    /// The database backends compiled into this binary.
+    pub fn available_backends() -> &'static [&'static str] {
+        &[
+            #[cfg(feature = "sqlite")]
+            "sqlite",
+            #[cfg(feature = "rocksdb")]
+            "rocksdb",
+            #[cfg(feature = "persy")]
+            "persy",
+        ]
+    }
 
-        if rocksdb_exists && config.database_backend != "rocksdb" {
+    fn unavailable_backend(backend: &str) -> Error {
+        error!(
+            "The database backend {:?} is not available in this binary. Available backends: {}. \
+             rocksdb (cargo feature backend_rocksdb) is the fastest choice for larger servers, \
+             but uses more memory. sqlite (backend_sqlite) is slower with many rooms, but is \
+             easy to inspect and back up, which suits small servers and testing.",
+            backend,
+            Self::available_backends().join(", ")
+        );
+        Error::BadConfig("Database backend not found.")
+    }
+
+    /// Like `open`, but fails instead of creating a new database if there is none at
+    /// `database_path`.
+    pub fn open_existing(config: &Config) -> Result<Self> {
+        if !Self::existing_backends(Path::new(&config.database_path))
+            .contains(&&*config.database_backend)
+        {
             return Err(Error::bad_config(
-                "Found rocksdb at database_path, but is not specified in config.",
+                "There is no database of the configured database_backend at database_path.",
             ));
         }
 
-        Ok(())
+        Self::open(config)
     }
 
-    /// Load an existing database or create a new one.
-    pub async fn load_or_create(config: Config) -> Result<()> {
-        Self::check_db_setup(&config)?;
+    /// Open the database trees without loading any services.
+    pub fn open(config: &Config) -> Result<Self> {
+        Self::check_db_setup(config)?;
 
         if !Path::new(&config.database_path).exists() {
             std::fs::create_dir_all(&config.database_path)
@@ -236,24 +289,26 @@ impl KeyValueDatabase {
         let builder: Arc<dyn KeyValueDatabaseEngine> = match &*config.database_backend {
             "sqlite" => {
                 #[cfg(not(feature = "sqlite"))]
-                return Err(Error::BadConfig("Database backend not found."));
+                return Err(Self::unavailable_backend("sqlite"));
                 #[cfg(feature = "sqlite")]
-                Arc::new(Arc::<abstraction::sqlite::Engine>::open(&config)?)
+                Arc::new(Arc::<abstraction::sqlite::Engine>::open(config)?)
             }
             "rocksdb" => {
                 #[cfg(not(feature = "rocksdb"))]
-                return Err(Error::BadConfig("Database backend not found."));
+                return Err(Self::unavailable_backend("rocksdb"));
                 #[cfg(feature = "rocksdb")]
-                Arc::new(Arc::<abstraction::rocksdb::Engine>::open(&config)?)
+                Arc::new(Arc::<abstraction::rocksdb::Engine>::open(config)?)
             }
             "persy" => {
                 #[cfg(not(feature = "persy"))]
-                return Err(Error::BadConfig("Database backend not found."));
+                return Err(Self::unavailable_backend("persy"));
                 #[cfg(feature = "persy")]
-                Arc::new(Arc::<abstraction::persy::Engine>::open(&config)?)
+                Arc::new(Arc::<abstraction::persy::Engine>::open(config)?)
             }
-            _ => {
-                return Err(Error::BadConfig("Database backend not found."));
+            #[cfg(test)]
+            "memory" => Arc::new(Arc::<abstraction::memory::Engine>::open(config)?),
+            backend => {
+                return Err(Self::unavailable_backend(backend));
             }
         };
 
@@ -261,16 +316,23 @@ impl KeyValueDatabase {
             error!(?config.max_request_size, "Max request size is less than 1KB. Please increase it.");
         }
 
-        let db_raw = Box::new(Self {
+        Ok(Self {
             _db: builder.clone(),
             userid_password: builder.open_tree("userid_password")?,
             userid_displayname: builder.open_tree("userid_displayname")?,
             userid_avatarurl: builder.open_tree("userid_avatarurl")?,
             userid_blurhash: builder.open_tree("userid_blurhash")?,
+            useridprofilekey_value: builder.open_tree("useridprofilekey_value")?,
             userdeviceid_token: builder.open_tree("userdeviceid_token")?,
             userdeviceid_metadata: builder.open_tree("userdeviceid_metadata")?,
+            userdeviceid_lastseents: builder.open_tree("userdeviceid_lastseents")?,
+            userdeviceid_lastseenip: builder.open_tree("userdeviceid_lastseenip")?,
             userid_devicelistversion: builder.open_tree("userid_devicelistversion")?,
             token_userdeviceid: builder.open_tree("token_userdeviceid")?,
+            userdeviceid_tokencreated: builder.open_tree("userdeviceid_tokencreated")?,
+            userid_roomscreated: builder.open_tree("userid_roomscreated")?,
+            userthreepid_metadata: builder.open_tree("userthreepid_metadata")?,
+            threepid_userid: builder.open_tree("threepid_userid")?,
             onetimekeyid_onetimekeys: builder.open_tree("onetimekeyid_onetimekeys")?,
             userid_lastonetimekeyupdate: builder.open_tree("userid_lastonetimekeyupdate")?,
             keychangeid_userid: builder.open_tree("keychangeid_userid")?,
@@ -314,6 +376,8 @@ impl KeyValueDatabase {
             roomuserid_leftcount: builder.open_tree("roomuserid_leftcount")?,
 
             disabledroomids: builder.open_tree("disabledroomids")?,
+            localonlyroomids: builder.open_tree("localonlyroomids")?,
+            roomid_partialstate: builder.open_tree("roomid_partialstate")?,
 
             lazyloadedids: builder.open_tree("lazyloadedids")?,
 
@@ -343,6 +407,8 @@ impl KeyValueDatabase {
             roomuserdataid_accountdata: builder.open_tree("roomuserdataid_accountdata")?,
             roomusertype_roomuserdataid: builder.open_tree("roomusertype_roomuserdataid")?,
             mediaid_file: builder.open_tree("mediaid_file")?,
+            mxc_quarantined: builder.open_tree("mxc_quarantined")?,
+            userid_mediausage: builder.open_tree("userid_mediausage")?,
             backupid_algorithm: builder.open_tree("backupid_algorithm")?,
             backupid_etag: builder.open_tree("backupid_etag")?,
             backupkeyid_backup: builder.open_tree("backupkeyid_backup")?,
@@ -350,18 +416,14 @@ impl KeyValueDatabase {
             servername_educount: builder.open_tree("servername_educount")?,
             servernameevent_data: builder.open_tree("servernameevent_data")?,
             servercurrentevent_data: builder.open_tree("servercurrentevent_data")?,
+            reportid_report: builder.open_tree("reportid_report")?,
+            userdelayid_delayedevent: builder.open_tree("userdelayid_delayedevent")?,
             id_appserviceregistrations: builder.open_tree("id_appserviceregistrations")?,
             senderkey_pusher: builder.open_tree("senderkey_pusher")?,
             global: builder.open_tree("global")?,
             server_signingkeys: builder.open_tree("server_signingkeys")?,
 
             cached_registrations: Arc::new(RwLock::new(HashMap::new())),
-            pdu_cache: Mutex::new(LruCache::new(
-                config
-                    .pdu_cache_capacity
-                    .try_into()
-                    .expect("pdu cache capacity fits into usize"),
-            )),
             auth_chain_cache: Mutex::new(LruCache::new(
                 (100_000.0 * config.conduit_cache_capacity_modifier) as usize,
             )),
@@ -380,7 +442,12 @@ impl KeyValueDatabase {
             our_real_users_cache: RwLock::new(HashMap::new()),
             appservice_in_room_cache: RwLock::new(HashMap::new()),
             lasttimelinecount_cache: Mutex::new(HashMap::new()),
-        });
+        })
+    }
+
+    /// Load an existing database or create a new one.
+    pub async fn load_or_create(config: Config) -> Result<()> {
+        let db_raw = Box::new(Self::open(&config)?);
 
         let db = Box::leak(db_raw);
 
@@ -392,9 +459,7 @@ impl KeyValueDatabase {
         // Matrix resource ownership is based on the server name; changing it
         // requires recreating the database from scratch.
         if services().users.count()? > 0 {
-            let conduit_user =
-                UserId::parse_with_server_name("conduit", services().globals.server_name())
-                    .expect("@conduit:server_name is valid");
+            let conduit_user = services().globals.server_user();
 
             if !services().users.exists(&conduit_user)? {
                 error!(
@@ -402,478 +467,16 @@ impl KeyValueDatabase {
                     conduit_user
                 );
                 return Err(Error::bad_database(
-                    "Cannot reuse an existing database after changing the server name, please delete the old one first."
+                    "Cannot reuse an existing database after changing the server name or server_user_localpart, please delete the old one first."
                 ));
             }
         }
 
         // If the database has any data, perform data migrations before starting
-        let latest_database_version = 12;
+        let latest_database_version = migrations::latest_version();
 
         if services().users.count()? > 0 {
-            // MIGRATIONS
-            if services().globals.database_version()? < 1 {
-                for (roomserverid, _) in db.roomserverids.iter() {
-                    let mut parts = roomserverid.split(|&b| b == 0xff);
-                    let room_id = parts.next().expect("split always returns one element");
-                    let servername = match parts.next() {
-                        Some(s) => s,
-                        None => {
-                            error!("Migration: Invalid roomserverid in db.");
-                            continue;
-                        }
-                    };
-                    let mut serverroomid = servername.to_vec();
-                    serverroomid.push(0xff);
-                    serverroomid.extend_from_slice(room_id);
-
-                    db.serverroomids.insert(&serverroomid, &[])?;
-                }
-
-                services().globals.bump_database_version(1)?;
-
-                warn!("Migration: 0 -> 1 finished");
-            }
-
-            if services().globals.database_version()? < 2 {
-                // We accidentally inserted hashed versions of "" into the db instead of just ""
-                for (userid, password) in db.userid_password.iter() {
-                    let password = utils::string_from_bytes(&password);
-
-                    let empty_hashed_password = password.map_or(false, |password| {
-                        argon2::verify_encoded(&password, b"").unwrap_or(false)
-                    });
-
-                    if empty_hashed_password {
-                        db.userid_password.insert(&userid, b"")?;
-                    }
-                }
-
-                services().globals.bump_database_version(2)?;
-
-                warn!("Migration: 1 -> 2 finished");
-            }
-
-            if services().globals.database_version()? < 3 {
-                // Move media to filesystem
-                for (key, content) in db.mediaid_file.iter() {
-                    if content.is_empty() {
-                        continue;
-                    }
-
-                    let path = services().globals.get_media_file(&key);
-                    let mut file = fs::File::create(path)?;
-                    file.write_all(&content)?;
-                    db.mediaid_file.insert(&key, &[])?;
-                }
-
-                services().globals.bump_database_version(3)?;
-
-                warn!("Migration: 2 -> 3 finished");
-            }
-
-            if services().globals.database_version()? < 4 {
-                // Add federated users to services() as deactivated
-                for our_user in services().users.iter() {
-                    let our_user = our_user?;
-                    if services().users.is_deactivated(&our_user)? {
-                        continue;
-                    }
-                    for room in services().rooms.state_cache.rooms_joined(&our_user) {
-                        for user in services().rooms.state_cache.room_members(&room?) {
-                            let user = user?;
-                            if user.server_name() != services().globals.server_name() {
-                                info!(?user, "Migration: creating user");
-                                services().users.create(&user, None)?;
-                            }
-                        }
-                    }
-                }
-
-                services().globals.bump_database_version(4)?;
-
-                warn!("Migration: 3 -> 4 finished");
-            }
-
-            if services().globals.database_version()? < 5 {
-                // Upgrade user data store
-                for (roomuserdataid, _) in db.roomuserdataid_accountdata.iter() {
-                    let mut parts = roomuserdataid.split(|&b| b == 0xff);
-                    let room_id = parts.next().unwrap();
-                    let user_id = parts.next().unwrap();
-                    let event_type = roomuserdataid.rsplit(|&b| b == 0xff).next().unwrap();
-
-                    let mut key = room_id.to_vec();
-                    key.push(0xff);
-                    key.extend_from_slice(user_id);
-                    key.push(0xff);
-                    key.extend_from_slice(event_type);
-
-                    db.roomusertype_roomuserdataid
-                        .insert(&key, &roomuserdataid)?;
-                }
-
-                services().globals.bump_database_version(5)?;
-
-                warn!("Migration: 4 -> 5 finished");
-            }
-
-            if services().globals.database_version()? < 6 {
-                // Set room member count
-                for (roomid, _) in db.roomid_shortstatehash.iter() {
-                    let string = utils::string_from_bytes(&roomid).unwrap();
-                    let room_id = <&RoomId>::try_from(string.as_str()).unwrap();
-                    services().rooms.state_cache.update_joined_count(room_id)?;
-                }
-
-                services().globals.bump_database_version(6)?;
-
-                warn!("Migration: 5 -> 6 finished");
-            }
-
-            if services().globals.database_version()? < 7 {
-                // Upgrade state store
-                let mut last_roomstates: HashMap<OwnedRoomId, u64> = HashMap::new();
-                let mut current_sstatehash: Option<u64> = None;
-                let mut current_room = None;
-                let mut current_state = HashSet::new();
-                let mut counter = 0;
-
-                let mut handle_state =
-                    |current_sstatehash: u64,
-                     current_room: &RoomId,
-                     current_state: HashSet<_>,
-                     last_roomstates: &mut HashMap<_, _>| {
-                        counter += 1;
-                        let last_roomsstatehash = last_roomstates.get(current_room);
-
-                        let states_parents = last_roomsstatehash.map_or_else(
-                            || Ok(Vec::new()),
-                            |&last_roomsstatehash| {
-                                services()
-                                    .rooms
-                                    .state_compressor
-                                    .load_shortstatehash_info(last_roomsstatehash)
-                            },
-                        )?;
-
-                        let (statediffnew, statediffremoved) =
-                            if let Some(parent_stateinfo) = states_parents.last() {
-                                let statediffnew = current_state
-                                    .difference(&parent_stateinfo.1)
-                                    .copied()
-                                    .collect::<HashSet<_>>();
-
-                                let statediffremoved = parent_stateinfo
-                                    .1
-                                    .difference(&current_state)
-                                    .copied()
-                                    .collect::<HashSet<_>>();
-
-                                (statediffnew, statediffremoved)
-                            } else {
-                                (current_state, HashSet::new())
-                            };
-
-                        services().rooms.state_compressor.save_state_from_diff(
-                            current_sstatehash,
-                            statediffnew,
-                            statediffremoved,
-                            2, // every state change is 2 event changes on average
-                            states_parents,
-                        )?;
-
-                        /*
-                        let mut tmp = services().rooms.load_shortstatehash_info(&current_sstatehash)?;
-                        let state = tmp.pop().unwrap();
-                        println!(
-                            "{}\t{}{:?}: {:?} + {:?} - {:?}",
-                            current_room,
-                            "  ".repeat(tmp.len()),
-                            utils::u64_from_bytes(&current_sstatehash).unwrap(),
-                            tmp.last().map(|b| utils::u64_from_bytes(&b.0).unwrap()),
-                            state
-                                .2
-                                .iter()
-                                .map(|b| utils::u64_from_bytes(&b[size_of::<u64>()..]).unwrap())
-                                .collect::<Vec<_>>(),
-                            state
-                                .3
-                                .iter()
-                                .map(|b| utils::u64_from_bytes(&b[size_of::<u64>()..]).unwrap())
-                                .collect::<Vec<_>>()
-                        );
-                        */
-
-                        Ok::<_, Error>(())
-                    };
-
-                for (k, seventid) in db._db.open_tree("stateid_shorteventid")?.iter() {
-                    let sstatehash = utils::u64_from_bytes(&k[0..size_of::<u64>()])
-                        .expect("number of bytes is correct");
-                    let sstatekey = k[size_of::<u64>()..].to_vec();
-                    if Some(sstatehash) != current_sstatehash {
-                        if let Some(current_sstatehash) = current_sstatehash {
-                            handle_state(
-                                current_sstatehash,
-                                current_room.as_deref().unwrap(),
-                                current_state,
-                                &mut last_roomstates,
-                            )?;
-                            last_roomstates
-                                .insert(current_room.clone().unwrap(), current_sstatehash);
-                        }
-                        current_state = HashSet::new();
-                        current_sstatehash = Some(sstatehash);
-
-                        let event_id = db.shorteventid_eventid.get(&seventid).unwrap().unwrap();
-                        let string = utils::string_from_bytes(&event_id).unwrap();
-                        let event_id = <&EventId>::try_from(string.as_str()).unwrap();
-                        let pdu = services()
-                            .rooms
-                            .timeline
-                            .get_pdu(event_id)
-                            .unwrap()
-                            .unwrap();
-
-                        if Some(&pdu.room_id) != current_room.as_ref() {
-                            current_room = Some(pdu.room_id.clone());
-                        }
-                    }
-
-                    let mut val = sstatekey;
-                    val.extend_from_slice(&seventid);
-                    current_state.insert(val.try_into().expect("size is correct"));
-                }
-
-                if let Some(current_sstatehash) = current_sstatehash {
-                    handle_state(
-                        current_sstatehash,
-                        current_room.as_deref().unwrap(),
-                        current_state,
-                        &mut last_roomstates,
-                    )?;
-                }
-
-                services().globals.bump_database_version(7)?;
-
-                warn!("Migration: 6 -> 7 finished");
-            }
-
-            if services().globals.database_version()? < 8 {
-                // Generate short room ids for all rooms
-                for (room_id, _) in db.roomid_shortstatehash.iter() {
-                    let shortroomid = services().globals.next_count()?.to_be_bytes();
-                    db.roomid_shortroomid.insert(&room_id, &shortroomid)?;
-                    info!("Migration: 8");
-                }
-                // Update pduids db layout
-                let mut batch = db.pduid_pdu.iter().filter_map(|(key, v)| {
-                    if !key.starts_with(b"!") {
-                        return None;
-                    }
-                    let mut parts = key.splitn(2, |&b| b == 0xff);
-                    let room_id = parts.next().unwrap();
-                    let count = parts.next().unwrap();
-
-                    let short_room_id = db
-                        .roomid_shortroomid
-                        .get(room_id)
-                        .unwrap()
-                        .expect("shortroomid should exist");
-
-                    let mut new_key = short_room_id;
-                    new_key.extend_from_slice(count);
-
-                    Some((new_key, v))
-                });
-
-                db.pduid_pdu.insert_batch(&mut batch)?;
-
-                let mut batch2 = db.eventid_pduid.iter().filter_map(|(k, value)| {
-                    if !value.starts_with(b"!") {
-                        return None;
-                    }
-                    let mut parts = value.splitn(2, |&b| b == 0xff);
-                    let room_id = parts.next().unwrap();
-                    let count = parts.next().unwrap();
-
-                    let short_room_id = db
-                        .roomid_shortroomid
-                        .get(room_id)
-                        .unwrap()
-                        .expect("shortroomid should exist");
-
-                    let mut new_value = short_room_id;
-                    new_value.extend_from_slice(count);
-
-                    Some((k, new_value))
-                });
-
-                db.eventid_pduid.insert_batch(&mut batch2)?;
-
-                services().globals.bump_database_version(8)?;
-
-                warn!("Migration: 7 -> 8 finished");
-            }
-
-            if services().globals.database_version()? < 9 {
-                // Update tokenids db layout
-                let mut iter = db
-                    .tokenids
-                    .iter()
-                    .filter_map(|(key, _)| {
-                        if !key.starts_with(b"!") {
-                            return None;
-                        }
-                        let mut parts = key.splitn(4, |&b| b == 0xff);
-                        let room_id = parts.next().unwrap();
-                        let word = parts.next().unwrap();
-                        let _pdu_id_room = parts.next().unwrap();
-                        let pdu_id_count = parts.next().unwrap();
-
-                        let short_room_id = db
-                            .roomid_shortroomid
-                            .get(room_id)
-                            .unwrap()
-                            .expect("shortroomid should exist");
-                        let mut new_key = short_room_id;
-                        new_key.extend_from_slice(word);
-                        new_key.push(0xff);
-                        new_key.extend_from_slice(pdu_id_count);
-                        Some((new_key, Vec::new()))
-                    })
-                    .peekable();
-
-                while iter.peek().is_some() {
-                    db.tokenids.insert_batch(&mut iter.by_ref().take(1000))?;
-                    debug!("Inserted smaller batch");
-                }
-
-                info!("Deleting starts");
-
-                let batch2: Vec<_> = db
-                    .tokenids
-                    .iter()
-                    .filter_map(|(key, _)| {
-                        if key.starts_with(b"!") {
-                            Some(key)
-                        } else {
-                            None
-                        }
-                    })
-                    .collect();
-
-                for key in batch2 {
-                    db.tokenids.remove(&key)?;
-                }
-
-                services().globals.bump_database_version(9)?;
-
-                warn!("Migration: 8 -> 9 finished");
-            }
-
-            if services().globals.database_version()? < 10 {
-                // Add other direction for shortstatekeys
-                for (statekey, shortstatekey) in db.statekey_shortstatekey.iter() {
-                    db.shortstatekey_statekey
-                        .insert(&shortstatekey, &statekey)?;
-                }
-
-                // Force E2EE device list updates so we can send them over federation
-                for user_id in services().users.iter().filter_map(|r| r.ok()) {
-                    services().users.mark_device_key_update(&user_id)?;
-                }
-
-                services().globals.bump_database_version(10)?;
-
-                warn!("Migration: 9 -> 10 finished");
-            }
-
-            if services().globals.database_version()? < 11 {
-                db._db
-                    .open_tree("userdevicesessionid_uiaarequest")?
-                    .clear()?;
-                services().globals.bump_database_version(11)?;
-
-                warn!("Migration: 10 -> 11 finished");
-            }
-
-            if services().globals.database_version()? < 12 {
-                for username in services().users.list_local_users().unwrap() {
-                    let user =
-                        UserId::parse_with_server_name(username, services().globals.server_name())
-                            .unwrap();
-
-                    let raw_rules_list = services()
-                        .account_data
-                        .get(
-                            None,
-                            &user,
-                            GlobalAccountDataEventType::PushRules.to_string().into(),
-                        )
-                        .unwrap()
-                        .expect("Username is invalid");
-
-                    let mut account_data =
-                        serde_json::from_str::<PushRulesEvent>(raw_rules_list.get()).unwrap();
-                    let rules_list = &mut account_data.content.global;
-
-                    //content rule
-                    {
-                        let content_rule_transformation =
-                            [".m.rules.contains_user_name", ".m.rule.contains_user_name"];
-
-                        let rule = rules_list.content.get(content_rule_transformation[0]);
-                        if rule.is_some() {
-                            let mut rule = rule.unwrap().clone();
-                            rule.rule_id = content_rule_transformation[1].to_owned();
-                            rules_list.content.remove(content_rule_transformation[0]);
-                            rules_list.content.insert(rule);
-                        }
-                    }
-
-                    //underride rules
-                    {
-                        let underride_rule_transformation = [
-                            [".m.rules.call", ".m.rule.call"],
-                            [".m.rules.room_one_to_one", ".m.rule.room_one_to_one"],
-                            [
-                                ".m.rules.encrypted_room_one_to_one",
-                                ".m.rule.encrypted_room_one_to_one",
-                            ],
-                            [".m.rules.message", ".m.rule.message"],
-                            [".m.rules.encrypted", ".m.rule.encrypted"],
-                        ];
-
-                        for transformation in underride_rule_transformation {
-                            let rule = rules_list.underride.get(transformation[0]);
-                            if let Some(rule) = rule {
-                                let mut rule = rule.clone();
-                                rule.rule_id = transformation[1].to_owned();
-                                rules_list.underride.remove(transformation[0]);
-                                rules_list.underride.insert(rule);
-                            }
-                        }
-                    }
-
-                    services().account_data.update(
-                        None,
-                        &user,
-                        GlobalAccountDataEventType::PushRules.to_string().into(),
-                        &serde_json::to_value(account_data).expect("to json value always works"),
-                    )?;
-                }
-
-                services().globals.bump_database_version(12)?;
-
-                warn!("Migration: 11 -> 12 finished");
-            }
-
-            assert_eq!(
-                services().globals.database_version().unwrap(),
-                latest_database_version
-            );
+            migrations::run(db)?;
 
             info!(
                 "Loaded {} database with version {}",
@@ -920,6 +523,26 @@ impl KeyValueDatabase {
 
         Self::start_cleanup_task().await;
 
+        if let Some(interval) = services().globals.backup_interval() {
+            Self::start_backup_task(interval).await;
+        }
+
+        if services().globals.database_durability() == DatabaseDurability::Relaxed
+            && db._db.supports_relaxed_durability()
+        {
+            Self::start_flush_task(services().globals.database_flush_interval()).await;
+        }
+
+        if services().globals.allow_presence() {
+            Self::start_presence_task().await;
+        }
+
+        if services().delayed_events.enabled() {
+            Self::start_delayed_events_task().await;
+        }
+
+        Self::start_partial_state_resync().await;
+
         Ok(())
     }
 
@@ -973,15 +596,136 @@ impl KeyValueDatabase {
                 } else {
                     debug!("cleanup: Finished in {:?}", start.elapsed());
                 }
+
+                if let Err(e) = services().rooms.timeline.forget_abandoned_rooms().await {
+                    error!("cleanup: Forgetting abandoned rooms failed: {}", e);
+                }
+            }
+        });
+    }
+
+    #[tracing::instrument]
+    pub async fn start_presence_task() {
+        use std::time::Duration;
+        use tokio::time::interval;
+
+        // Check often enough that users don't stay online much longer than the idle timeout
+        let timer_interval = (services().globals.presence_idle_timeout() / 10)
+            .clamp(Duration::from_secs(1), Duration::from_secs(60));
+
+        tokio::spawn(async move {
+            let mut i = interval(timer_interval);
+
+            loop {
+                i.tick().await;
+
+                if let Err(e) = services().rooms.edus.presence.apply_timeouts() {
+                    error!("presence: Applying timeouts failed: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Sends delayed events when their timer runs out. Timers survive restarts, events that
+    /// became due while the server was down are sent right away.
+    #[tracing::instrument]
+    pub async fn start_delayed_events_task() {
+        use std::time::Duration;
+
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = services().delayed_events.send_due().await {
+                    error!("delayed events: Sending failed: {}", e);
+                }
+
+                let wait = match services().delayed_events.next_send_at() {
+                    Ok(Some(send_at)) => Duration::from_millis(
+                        send_at.saturating_sub(utils::millis_since_unix_epoch()),
+                    ),
+                    Ok(None) => Duration::from_secs(60 * 60),
+                    Err(e) => {
+                        error!("delayed events: Reading timers failed: {}", e);
+                        Duration::from_secs(60)
+                    }
+                };
+
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    _ = services().delayed_events.timers_changed.notified() => {}
+                }
+            }
+        });
+    }
+
+    /// Resyncs the members of rooms that were still joined with a partial state when the server
+    /// stopped.
+    #[tracing::instrument]
+    pub async fn start_partial_state_resync() {
+        for room_id in services()
+            .rooms
+            .metadata
+            .partial_state_rooms()
+            .filter_map(|r| r.ok())
+        {
+            tokio::spawn(async move {
+                if let Err(e) = services()
+                    .rooms
+                    .event_handler
+                    .resync_partial_state(&room_id)
+                    .await
+                {
+                    warn!("Could not resync the members of {}: {}", room_id, e);
+                }
+            });
+        }
+    }
+
+    /// Syncs writes to disk regularly, in relaxed durability mode they aren't synced right away.
+    #[tracing::instrument]
+    pub async fn start_flush_task(timer_interval: std::time::Duration) {
+        use tokio::time::{interval, MissedTickBehavior};
+
+        tokio::spawn(async move {
+            let mut i = interval(timer_interval);
+            i.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                i.tick().await;
+
+                match tokio::task::spawn_blocking(|| services().globals.flush()).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => error!("flush: Errored: {}", e),
+                    Err(e) => error!("flush: Task failed: {}", e),
+                }
+            }
+        });
+    }
+
+    #[tracing::instrument]
+    pub async fn start_backup_task(timer_interval: std::time::Duration) {
+        use tokio::time::{interval_at, Instant};
+
+        tokio::spawn(async move {
+            // Don't take a backup right on startup
+            let mut i = interval_at(Instant::now() + timer_interval, timer_interval);
+
+            loop {
+                i.tick().await;
+                debug!("backup: Timer ticked");
+
+                match tokio::task::spawn_blocking(|| services().globals.backup()).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => error!("backup: Errored: {}", e),
+                    Err(e) => error!("backup: Task failed: {}", e),
+                }
             }
         });
     }
 }
 
-/// Sets the emergency password and push rules for the @conduit account in case emergency password is set
+/// Sets the emergency password and push rules for the server user in case emergency password is set
 fn set_emergency_access() -> Result<bool> {
-    let conduit_user = UserId::parse_with_server_name("conduit", services().globals.server_name())
-        .expect("@conduit:server_name is a valid UserId");
+    let conduit_user = services().globals.server_user();
 
     services().users.set_password(
         &conduit_user,
@@ -1005,3 +749,23 @@ fn set_emergency_access() -> Result<bool> {
 
     res
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn open_existing_doesnt_create_a_database() {
+        let database_path =
+            std::env::temp_dir().join(format!("conduit-missing-db-{}", std::process::id()));
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "server_name": "example.org",
+            "database_backend": "sqlite",
+            "database_path": database_path,
+        }))
+        .unwrap();
+
+        assert!(KeyValueDatabase::open_existing(&config).is_err());
+        assert!(!database_path.exists());
+    }
+}
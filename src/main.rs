@@ -7,17 +7,24 @@
 #![allow(clippy::suspicious_else_formatting)]
 #![deny(clippy::dbg_macro)]
 
-use std::{future::Future, io, net::SocketAddr, time::Duration};
+use std::{future::Future, io, net::SocketAddr, sync::Arc, time::Duration};
 
 use axum::{
-    extract::{DefaultBodyLimit, FromRequest, MatchedPath},
+    body::HttpBody,
+    extract::{ConnectInfo, DefaultBodyLimit, FromRequest, MatchedPath},
     handler::Handler,
     response::IntoResponse,
-    routing::{get, on, MethodFilter},
+    routing::{get, on, post, put, MethodFilter},
     Router,
 };
-use axum_server::{bind, bind_rustls, tls_rustls::RustlsConfig, Handle as ServerHandle};
-use conduit::api::{client_server, server_server};
+use axum_server::{
+    bind, bind_rustls, tls_rustls::RustlsConfig, AddrIncomingConfig, Handle as ServerHandle,
+    HttpConfig,
+};
+use clap::Parser;
+use conduit::api::{
+    admin_server, client_server, ruma_wrapper::ClientIp, server_server, well_known,
+};
 use figment::{
     providers::{Env, Format, Toml},
     Figment,
@@ -26,19 +33,27 @@ use http::{
     header::{self, HeaderName},
     Method, StatusCode, Uri,
 };
-use ruma::api::{
-    client::{
-        error::{Error as RumaError, ErrorBody, ErrorKind},
-        uiaa::UiaaResponse,
+use ruma::{
+    api::{
+        client::{
+            error::{Error as RumaError, ErrorBody, ErrorKind},
+            uiaa::UiaaResponse,
+        },
+        IncomingRequest,
     },
-    IncomingRequest,
+    RoomId,
 };
 
+use percent_encoding::percent_decode_str;
 use tower::ServiceBuilder;
 use tower_http::{
+    compression::{
+        predicate::{DefaultPredicate, Predicate, SizeAbove},
+        CompressionLayer,
+    },
     cors::{self, CorsLayer},
     trace::TraceLayer,
-    ServiceBuilderExt as _,
+    CompressionLevel, ServiceBuilderExt as _,
 };
 use tracing::{error, info, warn};
 use tracing_subscriber::{prelude::*, EnvFilter};
@@ -52,8 +67,23 @@ use tikv_jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
+/// A Matrix homeserver written in Rust
+#[derive(Parser)]
+#[command(name = "conduit", version = env!("CARGO_PKG_VERSION"))]
+struct Args {
+    /// Check the database for inconsistencies, then exit
+    #[arg(long)]
+    check: bool,
+
+    /// Check the database and rebuild inconsistent indexes from the stored events, then exit
+    #[arg(long, conflicts_with = "check")]
+    repair: bool,
+}
+
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
+
     // Initialize DB
     let raw_config =
         Figment::new()
@@ -123,6 +153,10 @@ async fn main() {
         tracing::subscriber::set_global_default(subscriber).unwrap();
     }
 
+    if args.check || args.repair {
+        check_database(&config, args.repair);
+    }
+
     info!("Loading database");
     if let Err(error) = KeyValueDatabase::load_or_create(config).await {
         error!(?error, "The database couldn't be loaded or created");
@@ -139,6 +173,41 @@ async fn main() {
     }
 }
 
+/// Runs the database integrity check (and optionally the repair) and exits the process.
+fn check_database(config: &Config, repair: bool) -> ! {
+    // Checking a database that doesn't exist must not create an empty one
+    let db = match KeyValueDatabase::open_existing(config) {
+        Ok(db) => db,
+        Err(error) => {
+            error!(?error, "The database couldn't be opened");
+            std::process::exit(1);
+        }
+    };
+
+    let result = if repair {
+        info!("Checking and repairing database");
+        db.repair()
+    } else {
+        info!("Checking database");
+        db.check_integrity()
+    };
+
+    match result {
+        Ok(report) => {
+            println!("{report}");
+
+            if report.problems() > report.repaired {
+                std::process::exit(2);
+            }
+            std::process::exit(0);
+        }
+        Err(error) => {
+            error!(?error, "The database check failed");
+            std::process::exit(1);
+        }
+    }
+}
+
 async fn run_server() -> io::Result<()> {
     let config = &services().globals.config;
     let addr = SocketAddr::from((config.address, config.port));
@@ -157,8 +226,11 @@ async fn run_server() -> io::Result<()> {
                 tracing::info_span!("http_request", %path)
             }),
         )
-        .compression()
+        .layer(compression_layer(config))
+        .layer(axum::middleware::from_fn(resolve_client_ip))
         .layer(axum::middleware::from_fn(unrecognized_method))
+        .layer(axum::middleware::from_fn(maintenance_mode))
+        .layer(axum::middleware::from_fn(request_timeout))
         .layer(
             CorsLayer::new()
                 .allow_origin(cors::Any)
@@ -178,22 +250,43 @@ async fn run_server() -> io::Result<()> {
                 ])
                 .max_age(Duration::from_secs(86400)),
         )
+        // Every route sets its own, smaller body limit (see `body_limit`). This outer limit
+        // only applies to routes without one, so it has to be the largest of them.
         .layer(DefaultBodyLimit::max(
-            config
-                .max_request_size
-                .try_into()
-                .expect("failed to convert max request size"),
+            [
+                services().globals.max_client_request_size(),
+                services().globals.max_media_upload_size(),
+                services().globals.max_federation_request_size(),
+            ]
+            .into_iter()
+            .max()
+            .expect("array is not empty")
+            .try_into()
+            .expect("failed to convert max request size"),
         ));
 
-    let app = routes().layer(middlewares).into_make_service();
+    let app = routes()
+        .layer(middlewares)
+        .into_make_service_with_connect_info::<SocketAddr>();
     let handle = ServerHandle::new();
 
+    services().globals.set_server_handle(handle.clone());
     tokio::spawn(shutdown_monitor::monitor(handle.clone()));
 
     match &config.tls {
         Some(tls) => {
             let conf = RustlsConfig::from_pem_file(&tls.certs, &tls.key).await?;
-            let server = bind_rustls(addr, conf).handle(handle).serve(app);
+            if !config.http2_enabled {
+                // Otherwise clients would still negotiate HTTP/2 during the TLS handshake
+                let mut inner = (*conf.get_inner()).clone();
+                inner.alpn_protocols = vec![b"http/1.1".to_vec()];
+                conf.reload_from_config(Arc::new(inner));
+            }
+            let server = bind_rustls(addr, conf)
+                .handle(handle)
+                .http_config(http_config(config, true))
+                .addr_incoming_config(addr_incoming_config(config))
+                .serve(app);
 
             #[cfg(feature = "systemd")]
             let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Ready]);
@@ -201,7 +294,11 @@ async fn run_server() -> io::Result<()> {
             server.await?
         }
         None => {
-            let server = bind(addr).handle(handle).serve(app);
+            let server = bind(addr)
+                .handle(handle)
+                .http_config(http_config(config, false))
+                .addr_incoming_config(addr_incoming_config(config))
+                .serve(app);
 
             #[cfg(feature = "systemd")]
             let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Ready]);
@@ -241,6 +338,214 @@ async fn unrecognized_method<B>(
     Ok(inner)
 }
 
+/// Responses smaller than this aren't worth compressing.
+const MIN_COMPRESSED_SIZE: u16 = 1024;
+
+/// Decides which responses are compressed, on top of tower-http's default rules.
+#[derive(Clone)]
+struct CompressionPolicy {
+    enabled: bool,
+    excluded_content_types: Arc<Vec<String>>,
+}
+
+impl Predicate for CompressionPolicy {
+    fn should_compress<B>(&self, response: &http::Response<B>) -> bool
+    where
+        B: HttpBody,
+    {
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .unwrap_or_default();
+
+        self.enabled
+            && !self
+                .excluded_content_types
+                .iter()
+                .any(|excluded| content_type.starts_with(excluded.as_str()))
+    }
+}
+
+fn compression_layer(config: &Config) -> CompressionLayer<impl Predicate> {
+    let policy = CompressionPolicy {
+        enabled: config.compression_enabled,
+        excluded_content_types: Arc::new(config.compression_excluded_content_types.clone()),
+    };
+
+    CompressionLayer::new()
+        .quality(
+            config
+                .compression_level
+                .map_or(CompressionLevel::Default, CompressionLevel::Precise),
+        )
+        .compress_when(
+            DefaultPredicate::new()
+                .and(SizeAbove::new(MIN_COMPRESSED_SIZE))
+                .and(policy),
+        )
+}
+
+/// Protocol settings for the connections, `tls` tells if HTTP/2 can be negotiated or would have to
+/// be spoken without TLS (h2c).
+fn http_config(config: &Config, tls: bool) -> HttpConfig {
+    let http2 = config.http2_enabled && (tls || config.allow_h2c);
+    let idle_timeout = config.http_idle_timeout_secs.map(Duration::from_secs);
+
+    let mut http_config = HttpConfig::new();
+    http_config
+        .http1_only(!http2)
+        .http2_max_concurrent_streams(config.http2_max_concurrent_streams)
+        .http2_keep_alive_interval(idle_timeout);
+    if let Some(idle_timeout) = idle_timeout {
+        http_config.http2_keep_alive_timeout(idle_timeout);
+    }
+
+    http_config.build()
+}
+
+fn addr_incoming_config(config: &Config) -> AddrIncomingConfig {
+    AddrIncomingConfig::new()
+        .tcp_keepalive(config.tcp_keepalive_secs.map(Duration::from_secs))
+        .build()
+}
+
+/// Adds the IP of the client to the request as `ClientIp`, see `trusted_proxies` in the config.
+async fn resolve_client_ip<B>(
+    mut req: axum::http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> std::result::Result<axum::response::Response, StatusCode> {
+    if let Some(ConnectInfo(peer)) = req.extensions().get::<ConnectInfo<SocketAddr>>().copied() {
+        let joined_header = |name| {
+            // Proxies may add their own header instead of appending to an existing one
+            let values = req
+                .headers()
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect::<Vec<_>>();
+            (!values.is_empty()).then(|| values.join(","))
+        };
+        // Only the header the proxies set, clients can send the other one themselves
+        let forwarded = joined_header(HeaderName::from_static(
+            services().globals.trusted_proxy_header().as_str(),
+        ));
+
+        let ip = services()
+            .globals
+            .client_ip(peer.ip(), forwarded.as_deref());
+        req.extensions_mut().insert(ClientIp(ip));
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Rejects all requests that would write to the database while the server is in maintenance mode.
+async fn maintenance_mode<B>(
+    req: axum::http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> std::result::Result<axum::response::Response, StatusCode> {
+    if services().globals.maintenance_mode()
+        && is_write_request(req.method(), req.uri().path())
+        && !is_admin_room_request(req.uri().path())
+    {
+        return Ok(Error::BadRequest(
+            ErrorKind::Unknown,
+            "The server is in maintenance mode, please try again later.",
+        )
+        .into_response());
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Cuts off requests that take longer than `request_timeout_ms`, except on excluded paths like
+/// the long-polling /sync, which has its own timeout.
+async fn request_timeout<B>(
+    req: axum::http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> std::result::Result<axum::response::Response, StatusCode> {
+    let timeout = match services().globals.request_timeout() {
+        Some(timeout)
+            if !is_timeout_excluded(
+                req.uri().path(),
+                &services().globals.config.request_timeout_excluded_paths,
+            ) =>
+        {
+            timeout
+        }
+        _ => return Ok(next.run(req).await),
+    };
+
+    let uri = req.uri().clone();
+    Ok(run_with_timeout(timeout, next.run(req))
+        .await
+        .unwrap_or_else(|| {
+            warn!("Request to {} timed out after {:?}", uri, timeout);
+            timed_out_response()
+        }))
+}
+
+fn is_timeout_excluded(path: &str, excluded_paths: &[String]) -> bool {
+    excluded_paths
+        .iter()
+        .any(|excluded| path.starts_with(excluded.as_str()))
+}
+
+/// Returns None if the response wasn't ready in time.
+async fn run_with_timeout(
+    timeout: Duration,
+    response: impl Future<Output = axum::response::Response>,
+) -> Option<axum::response::Response> {
+    tokio::time::timeout(timeout, response).await.ok()
+}
+
+fn timed_out_response() -> axum::response::Response {
+    RumaResponse(UiaaResponse::MatrixError(RumaError {
+        body: ErrorBody::Standard {
+            kind: ErrorKind::Unknown,
+            message: "The request took too long, please try again later.".to_owned(),
+        },
+        status_code: StatusCode::REQUEST_TIMEOUT,
+    }))
+    .into_response()
+}
+
+/// POST endpoints that only read data
+const READ_ONLY_POST_SUFFIXES: &[&str] = &["/keys/query", "/search", "/publicRooms"];
+
+fn is_write_request(method: &Method, path: &str) -> bool {
+    // Admin endpoints like backups must keep working during maintenance
+    if path.starts_with("/_conduit/admin/") {
+        return false;
+    }
+
+    match *method {
+        Method::GET | Method::HEAD | Method::OPTIONS => false,
+        Method::POST => !READ_ONLY_POST_SUFFIXES
+            .iter()
+            .any(|suffix| path.ends_with(suffix)),
+        _ => true,
+    }
+}
+
+/// Messages to the admin room are always allowed, so admins can leave maintenance mode again.
+fn is_admin_room_request(path: &str) -> bool {
+    let mut segments = path.split('/').skip_while(|segment| *segment != "rooms");
+
+    let room_id = match (segments.nth(1), segments.next()) {
+        (Some(room_id), Some("send")) => percent_decode_str(room_id).decode_utf8_lossy(),
+        _ => return false,
+    };
+
+    services()
+        .admin
+        .admin_room_id()
+        .ok()
+        .flatten()
+        .map_or(false, |admin_room| admin_room.as_str() == room_id)
+}
+
 fn routes() -> Router {
     Router::new()
         .ruma_route(client_server::get_supported_versions_route)
@@ -248,14 +553,24 @@ fn routes() -> Router {
         .ruma_route(client_server::register_route)
         .ruma_route(client_server::get_login_types_route)
         .ruma_route(client_server::login_route)
+        .route(
+            "/_matrix/client/v1/login/get_token",
+            post(client_server::get_login_token_route),
+        )
         .ruma_route(client_server::whoami_route)
         .ruma_route(client_server::logout_route)
         .ruma_route(client_server::logout_all_route)
         .ruma_route(client_server::change_password_route)
         .ruma_route(client_server::deactivate_route)
         .ruma_route(client_server::third_party_route)
+        .ruma_route(client_server::add_3pid_route)
+        .ruma_route(client_server::bind_3pid_route)
+        .ruma_route(client_server::delete_3pid_route)
+        .ruma_route(client_server::unbind_3pid_route)
+        .ruma_route(client_server::request_registration_token_via_email_route)
         .ruma_route(client_server::request_3pid_management_token_via_email_route)
         .ruma_route(client_server::request_3pid_management_token_via_msisdn_route)
+        .ruma_route(client_server::request_registration_token_via_msisdn_route)
         .ruma_route(client_server::get_capabilities_route)
         .ruma_route(client_server::get_pushrules_all_route)
         .ruma_route(client_server::set_pushrule_route)
@@ -277,7 +592,23 @@ fn routes() -> Router {
         .ruma_route(client_server::get_displayname_route)
         .ruma_route(client_server::set_avatar_url_route)
         .ruma_route(client_server::get_avatar_url_route)
-        .ruma_route(client_server::get_profile_route)
+        // get_profile_route and get_profile_information_route return JSON instead of the Ruma
+        // response types, so they can include custom profile fields
+        .route(
+            "/_matrix/client/r0/profile/:user_id",
+            get(client_server::get_profile_route),
+        )
+        .route(
+            "/_matrix/client/v3/profile/:user_id",
+            get(client_server::get_profile_route),
+        )
+        .route(
+            "/_matrix/client/unstable/uk.tcpip.msc4133/profile/:user_id/:key_name",
+            get(client_server::get_profile_field_route)
+                .put(client_server::set_profile_field_route)
+                .delete(client_server::delete_profile_field_route)
+                .layer(DefaultBodyLimit::max(body_limit("/_matrix/client/"))),
+        )
         .ruma_route(client_server::set_presence_route)
         .ruma_route(client_server::get_presence_route)
         .ruma_route(client_server::upload_keys_route)
@@ -303,6 +634,30 @@ fn routes() -> Router {
         .ruma_route(client_server::create_room_route)
         .ruma_route(client_server::redact_event_route)
         .ruma_route(client_server::report_event_route)
+        .route(
+            "/_matrix/client/v3/rooms/:room_id/report",
+            post(client_server::report_room_route),
+        )
+        .route(
+            "/_matrix/client/unstable/org.matrix.msc4140/rooms/:room_id/send/:event_type/:txn_id",
+            put(client_server::send_delayed_message_event_route),
+        )
+        .route(
+            "/_matrix/client/unstable/org.matrix.msc4140/rooms/:room_id/state/:event_type",
+            put(client_server::send_delayed_state_event_for_empty_key_route),
+        )
+        .route(
+            "/_matrix/client/unstable/org.matrix.msc4140/rooms/:room_id/state/:event_type/:state_key",
+            put(client_server::send_delayed_state_event_route),
+        )
+        .route(
+            "/_matrix/client/unstable/org.matrix.msc4140/delayed_events",
+            get(client_server::get_delayed_events_route),
+        )
+        .route(
+            "/_matrix/client/unstable/org.matrix.msc4140/delayed_events/:delay_id",
+            post(client_server::update_delayed_event_route),
+        )
         .ruma_route(client_server::create_alias_route)
         .ruma_route(client_server::delete_alias_route)
         .ruma_route(client_server::get_alias_route)
@@ -332,27 +687,36 @@ fn routes() -> Router {
         .route(
             "/_matrix/client/r0/rooms/:room_id/state/:event_type",
             get(client_server::get_state_events_for_empty_key_route)
-                .put(client_server::send_state_event_for_empty_key_route),
+                .put(client_server::send_state_event_for_empty_key_route)
+                .layer(DefaultBodyLimit::max(body_limit("/_matrix/client/"))),
         )
         .route(
             "/_matrix/client/v3/rooms/:room_id/state/:event_type",
             get(client_server::get_state_events_for_empty_key_route)
-                .put(client_server::send_state_event_for_empty_key_route),
+                .put(client_server::send_state_event_for_empty_key_route)
+                .layer(DefaultBodyLimit::max(body_limit("/_matrix/client/"))),
         )
         // These two endpoints allow trailing slashes
         .route(
             "/_matrix/client/r0/rooms/:room_id/state/:event_type/",
             get(client_server::get_state_events_for_empty_key_route)
-                .put(client_server::send_state_event_for_empty_key_route),
+                .put(client_server::send_state_event_for_empty_key_route)
+                .layer(DefaultBodyLimit::max(body_limit("/_matrix/client/"))),
         )
         .route(
             "/_matrix/client/v3/rooms/:room_id/state/:event_type/",
             get(client_server::get_state_events_for_empty_key_route)
-                .put(client_server::send_state_event_for_empty_key_route),
+                .put(client_server::send_state_event_for_empty_key_route)
+                .layer(DefaultBodyLimit::max(body_limit("/_matrix/client/"))),
         )
         .ruma_route(client_server::sync_events_route)
         .ruma_route(client_server::get_context_route)
         .ruma_route(client_server::get_message_events_route)
+        .ruma_route(client_server::get_event_by_timestamp_route)
+        .route(
+            "/_matrix/client/v1/rooms/:room_id/timestamp_to_event",
+            get(client_server::get_event_by_timestamp_v1_route),
+        )
         .ruma_route(client_server::search_events_route)
         .ruma_route(client_server::turn_server_route)
         .ruma_route(client_server::send_event_to_device_route)
@@ -361,6 +725,7 @@ fn routes() -> Router {
         .ruma_route(client_server::get_content_route)
         .ruma_route(client_server::get_content_as_filename_route)
         .ruma_route(client_server::get_content_thumbnail_route)
+        .ruma_route(client_server::get_media_preview_route)
         .ruma_route(client_server::get_devices_route)
         .ruma_route(client_server::get_device_route)
         .ruma_route(client_server::update_device_route)
@@ -376,7 +741,18 @@ fn routes() -> Router {
         .ruma_route(client_server::set_pushers_route)
         // .ruma_route(client_server::third_party_route)
         .ruma_route(client_server::upgrade_room_route)
-        .ruma_route(server_server::get_server_version_route)
+        .route(
+            "/_matrix/federation/v1/version",
+            get(server_server::get_server_version_route),
+        )
+        .route(
+            "/.well-known/matrix/server",
+            get(well_known::well_known_server_route),
+        )
+        .route(
+            "/.well-known/matrix/client",
+            get(well_known::well_known_client_route),
+        )
         .route(
             "/_matrix/key/v2/server",
             get(server_server::get_server_keys_route),
@@ -389,19 +765,40 @@ fn routes() -> Router {
         .ruma_route(server_server::get_public_rooms_filtered_route)
         .ruma_route(server_server::send_transaction_message_route)
         .ruma_route(server_server::get_event_route)
+        .ruma_route(server_server::get_event_by_timestamp_route)
         .ruma_route(server_server::get_missing_events_route)
         .ruma_route(server_server::get_event_authorization_route)
         .ruma_route(server_server::get_room_state_route)
         .ruma_route(server_server::get_room_state_ids_route)
         .ruma_route(server_server::create_join_event_template_route)
         .ruma_route(server_server::create_join_event_v1_route)
-        .ruma_route(server_server::create_join_event_v2_route)
+        .route(
+            "/_matrix/federation/v2/send_join/:room_id/:event_id",
+            put(server_server::create_join_event_v2_route)
+                .layer(DefaultBodyLimit::max(body_limit("/_matrix/federation/"))),
+        )
         .ruma_route(server_server::create_invite_route)
+        .ruma_route(server_server::exchange_third_party_invite_route)
+        .route(
+            "/_matrix/federation/v1/3pid/onbind",
+            put(server_server::third_party_bind_callback_route),
+        )
         .ruma_route(server_server::get_devices_route)
         .ruma_route(server_server::get_room_information_route)
-        .ruma_route(server_server::get_profile_information_route)
+        .route(
+            "/_matrix/federation/v1/query/profile",
+            get(server_server::get_profile_information_route),
+        )
         .ruma_route(server_server::get_keys_route)
         .ruma_route(server_server::claim_keys_route)
+        .route(
+            "/_matrix/client/unstable/add_threepid/email/submit_token",
+            get(client_server::submit_email_token_route),
+        )
+        .route(
+            "/_matrix/client/unstable/add_threepid/msisdn/submit_token",
+            post(client_server::submit_msisdn_token_route),
+        )
         .route(
             "/_matrix/client/r0/rooms/:room_id/initialSync",
             get(initial_sync),
@@ -410,6 +807,52 @@ fn routes() -> Router {
             "/_matrix/client/v3/rooms/:room_id/initialSync",
             get(initial_sync),
         )
+        .route(
+            "/_matrix/client/r0/events",
+            get(client_server::peek_events_route),
+        )
+        .route(
+            "/_matrix/client/v3/events",
+            get(client_server::peek_events_route),
+        )
+        .route(
+            "/_conduit/admin/backup",
+            get(admin_server::get_backup_status_route).post(admin_server::backup_route),
+        )
+        .route(
+            "/_conduit/admin/maintenance",
+            get(admin_server::get_maintenance_mode_route),
+        )
+        .route(
+            "/_conduit/admin/maintenance/enable",
+            post(admin_server::enable_maintenance_mode_route),
+        )
+        .route(
+            "/_conduit/admin/maintenance/disable",
+            post(admin_server::disable_maintenance_mode_route),
+        )
+        .route("/_conduit/admin/status", get(admin_server::status_route))
+        .route("/_conduit/admin/reports", get(admin_server::reports_route))
+        .route(
+            "/_conduit/admin/federation/destinations",
+            get(admin_server::federation_destinations_route),
+        )
+        .route(
+            "/_conduit/admin/federation/destinations/:server_name/retry",
+            post(admin_server::retry_federation_destination_route),
+        )
+        .route(
+            "/_conduit/admin/federation/destinations/:server_name/purge",
+            post(admin_server::purge_federation_destination_route),
+        )
+        .route(
+            "/_conduit/admin/signing_key/rotate",
+            post(admin_server::rotate_signing_key_route),
+        )
+        .route(
+            "/_conduit/admin/media/:server_name/:media_id/quarantine",
+            post(admin_server::quarantine_media_route),
+        )
         .fallback(not_found.into_service())
 }
 
@@ -418,11 +861,14 @@ async fn not_found(uri: Uri) -> impl IntoResponse {
     Error::BadRequest(ErrorKind::Unrecognized, "Unrecognized request")
 }
 
-async fn initial_sync(_uri: Uri) -> impl IntoResponse {
-    Error::BadRequest(
-        ErrorKind::GuestAccessForbidden,
-        "Guest access not implemented",
-    )
+/// Peeking into world readable rooms. Handled here because ruma doesn't have the endpoint.
+async fn initial_sync(
+    axum::extract::Path(room_id): axum::extract::Path<String>,
+) -> Result<axum::Json<serde_json::Value>> {
+    let room_id = RoomId::parse(room_id)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid room id."))?;
+
+    Ok(axum::Json(client_server::peek_room(&room_id).await?))
 }
 
 trait RouterExt {
@@ -471,7 +917,7 @@ macro_rules! impl_ruma_handler {
 
                     router = router.route(path, on(method_filter, |$( $ty: $ty, )* req| async move {
                         handler($($ty,)* req).await.map(RumaResponse)
-                    }))
+                    }).layer(DefaultBodyLimit::max(body_limit(path))))
                 }
 
                 router
@@ -490,6 +936,40 @@ impl_ruma_handler!(T1, T2, T3, T4, T5, T6);
 impl_ruma_handler!(T1, T2, T3, T4, T5, T6, T7);
 impl_ruma_handler!(T1, T2, T3, T4, T5, T6, T7, T8);
 
+#[derive(Debug, PartialEq, Eq)]
+enum RequestKind {
+    MediaUpload,
+    Federation,
+    Client,
+}
+
+fn request_kind(path: &str) -> RequestKind {
+    if path.starts_with("/_matrix/media/") && path.contains("/upload") {
+        RequestKind::MediaUpload
+    } else if path.starts_with("/_matrix/federation/") || path.starts_with("/_matrix/key/") {
+        RequestKind::Federation
+    } else {
+        RequestKind::Client
+    }
+}
+
+/// The maximum request body size for the route with the given path.
+fn body_limit(path: &str) -> usize {
+    body_limit_for(&services().globals.config, path)
+}
+
+fn body_limit_for(config: &Config, path: &str) -> usize {
+    let limit = match request_kind(path) {
+        RequestKind::MediaUpload => config.max_media_upload_size(),
+        RequestKind::Federation => config.max_federation_request_size(),
+        RequestKind::Client => config.max_client_request_size,
+    };
+
+    limit
+        .try_into()
+        .expect("failed to convert max request size")
+}
+
 fn method_to_filter(method: Method) -> MethodFilter {
     match method {
         Method::DELETE => MethodFilter::DELETE,
@@ -503,3 +983,271 @@ fn method_to_filter(method: Method) -> MethodFilter {
         m => panic!("Unsupported HTTP method: {m:?}"),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use ruma::user_id;
+
+    use super::*;
+
+    #[test]
+    fn reads_are_allowed_in_maintenance_mode() {
+        assert!(!is_write_request(
+            &Method::GET,
+            "/_matrix/client/v3/rooms/!room:example.org/messages"
+        ));
+        assert!(!is_write_request(&Method::GET, "/_matrix/client/v3/sync"));
+        assert!(!is_write_request(
+            &Method::POST,
+            "/_matrix/client/v3/keys/query"
+        ));
+    }
+
+    #[test]
+    fn writes_are_rejected_in_maintenance_mode() {
+        assert!(is_write_request(
+            &Method::PUT,
+            "/_matrix/client/v3/rooms/!room:example.org/send/m.room.message/1"
+        ));
+        assert!(is_write_request(
+            &Method::POST,
+            "/_matrix/client/v3/register"
+        ));
+        assert!(is_write_request(
+            &Method::PUT,
+            "/_matrix/federation/v1/send/1"
+        ));
+    }
+
+    #[test]
+    fn media_uploads_have_their_own_body_limit() {
+        assert_eq!(
+            request_kind("/_matrix/media/v3/upload"),
+            RequestKind::MediaUpload
+        );
+        assert_eq!(
+            request_kind("/_matrix/media/v3/download/example.org/abc"),
+            RequestKind::Client
+        );
+        assert_eq!(
+            request_kind("/_matrix/client/v3/rooms/:room_id/send/:event_type/:txn_id"),
+            RequestKind::Client
+        );
+        assert_eq!(
+            request_kind("/_matrix/federation/v1/send/:txn_id"),
+            RequestKind::Federation
+        );
+    }
+
+    #[tokio::test]
+    async fn large_bodies_are_only_accepted_for_media_uploads() {
+        use axum::body::{Body, Bytes};
+        use tower::ServiceExt;
+
+        let config = Figment::new()
+            .merge(Toml::string(
+                r#"
+                server_name = "example.org"
+                database_path = "/var/lib/matrix-conduit/"
+                max_client_request_size = 1024
+                max_media_upload_size = 1048576
+                "#,
+            ))
+            .extract::<Config>()
+            .unwrap();
+
+        let app = ["/_matrix/client/v3/profile", "/_matrix/media/v3/upload"]
+            .into_iter()
+            .fold(Router::new(), |app, path| {
+                app.route(
+                    path,
+                    post(|body: Bytes| async move { body.len().to_string() })
+                        .layer(DefaultBodyLimit::max(body_limit_for(&config, path))),
+                )
+            });
+
+        let status = |path: &'static str| {
+            let app = app.clone();
+            async move {
+                app.oneshot(
+                    http::Request::builder()
+                        .method(Method::POST)
+                        .uri(path)
+                        .body(Body::from(vec![b'a'; 64 * 1024]))
+                        .unwrap(),
+                )
+                .await
+                .unwrap()
+                .status()
+            }
+        };
+
+        assert_eq!(
+            status("/_matrix/client/v3/profile").await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(status("/_matrix/media/v3/upload").await, StatusCode::OK);
+    }
+
+    #[cfg(feature = "backend_sqlite")]
+    #[tokio::test]
+    async fn maintenance_mode_only_rejects_writes() {
+        use axum::body::Body;
+        use tower::ServiceExt;
+
+        let database_path =
+            std::env::temp_dir().join(format!("conduit-maintenance-test-{}", std::process::id()));
+        let config = Figment::new()
+            .merge(Toml::string(&format!(
+                r#"
+                server_name = "example.org"
+                database_backend = "sqlite"
+                database_path = "{}"
+                "#,
+                database_path.display()
+            )))
+            .extract::<Config>()
+            .unwrap();
+        KeyValueDatabase::load_or_create(config).await.unwrap();
+
+        let app = Router::new()
+            .route(
+                "/_matrix/client/v3/rooms/:room_id/send/:event_type/:txn_id",
+                put(|| async { StatusCode::OK }),
+            )
+            .route(
+                "/_matrix/client/v3/rooms/:room_id/messages",
+                get(|| async { StatusCode::OK }),
+            )
+            .route(
+                "/_matrix/client/v3/user/:user_id/filter",
+                post(|| async { StatusCode::OK }),
+            )
+            .layer(axum::middleware::from_fn(maintenance_mode));
+        let status = |method: Method, path: &'static str| {
+            let app = app.clone();
+            async move {
+                app.oneshot(
+                    http::Request::builder()
+                        .method(method)
+                        .uri(path)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap()
+                .status()
+            }
+        };
+        let send = "/_matrix/client/v3/rooms/%21room%3Aexample.org/send/m.room.message/1";
+        let read = "/_matrix/client/v3/rooms/%21room%3Aexample.org/messages";
+        let filter = "/_matrix/client/v3/user/%40alice%3Aexample.org/filter";
+
+        let admin = user_id!("@admin:example.org").to_owned();
+        admin_server::enable_maintenance_mode_route(admin_server::AdminUser(admin.clone()))
+            .await
+            .unwrap();
+        assert_eq!(status(Method::PUT, send).await, StatusCode::BAD_REQUEST);
+        assert_eq!(status(Method::POST, filter).await, StatusCode::BAD_REQUEST);
+        assert_eq!(status(Method::GET, read).await, StatusCode::OK);
+
+        admin_server::disable_maintenance_mode_route(admin_server::AdminUser(admin))
+            .await
+            .unwrap();
+        assert_eq!(status(Method::PUT, send).await, StatusCode::OK);
+        assert_eq!(status(Method::POST, filter).await, StatusCode::OK);
+
+        let _ = std::fs::remove_dir_all(database_path);
+    }
+
+    #[test]
+    fn sync_has_no_request_timeout() {
+        let excluded = [
+            "/_matrix/client/v3/sync".to_owned(),
+            "/_matrix/media/".to_owned(),
+        ];
+
+        assert!(is_timeout_excluded("/_matrix/client/v3/sync", &excluded));
+        assert!(is_timeout_excluded("/_matrix/media/v3/upload", &excluded));
+        assert!(!is_timeout_excluded(
+            "/_matrix/client/v3/rooms/!room:example.org/messages",
+            &excluded
+        ));
+    }
+
+    #[tokio::test]
+    async fn slow_requests_are_cut_off() {
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            StatusCode::OK.into_response()
+        };
+        let started = std::time::Instant::now();
+        assert!(run_with_timeout(Duration::from_millis(50), slow)
+            .await
+            .is_none());
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let fast = async { StatusCode::OK.into_response() };
+        assert_eq!(
+            run_with_timeout(Duration::from_millis(50), fast)
+                .await
+                .unwrap()
+                .status(),
+            StatusCode::OK
+        );
+
+        let response = timed_out_response();
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn media_is_not_compressed_again() {
+        use axum::body::Body;
+        use tower::ServiceExt;
+
+        let config = Figment::new()
+            .merge(Toml::string(
+                r#"
+                server_name = "example.org"
+                database_path = "/var/lib/matrix-conduit/"
+                "#,
+            ))
+            .extract::<Config>()
+            .unwrap();
+
+        let body = "a".repeat(4096);
+        let app = Router::new()
+            .route(
+                "/json",
+                get({
+                    let body = body.clone();
+                    || async move { ([(header::CONTENT_TYPE, "application/json")], body) }
+                }),
+            )
+            .route(
+                "/media",
+                get(|| async move { ([(header::CONTENT_TYPE, "video/mp4")], body) }),
+            )
+            .layer(compression_layer(&config));
+
+        let content_encoding = |path: &'static str| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(
+                        http::Request::builder()
+                            .uri(path)
+                            .header(header::ACCEPT_ENCODING, "gzip")
+                            .body(Body::empty())
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                response.headers().get(header::CONTENT_ENCODING).cloned()
+            }
+        };
+
+        assert_eq!(content_encoding("/json").await.unwrap(), "gzip");
+        assert!(content_encoding("/media").await.is_none());
+    }
+}
@@ -23,7 +23,7 @@ use ruma::{
         },
         RoomEventType,
     },
-    EventId, OwnedRoomAliasId, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
+    EventId, OwnedRoomAliasId, OwnedRoomId, RoomAliasId, RoomId, RoomVersionId, UInt, UserId,
 };
 use serde_json::value::to_raw_value;
 use tokio::sync::{mpsc, Mutex, MutexGuard};
@@ -153,10 +153,42 @@ enum AdminCommand {
         password: Option<String>,
     },
 
+    /// Delete the messages of a room that were sent before a timestamp
+    ///
+    /// State events and the latest events of the room are kept, so the room keeps working.
+    PurgeHistory {
+        room_id: Box<RoomId>,
+        /// Milliseconds since the unix epoch
+        before_ts: u64,
+    },
+
+    /// Forget a room of another server that no local user is in anymore
+    ///
+    /// All of its events are deleted and other servers no longer receive events for it. Joining
+    /// the room again loads its state over federation.
+    ForgetRoom { room_id: Box<RoomId> },
+
     /// Disables incoming federation handling for a room.
     DisableRoom { room_id: Box<RoomId> },
     /// Enables incoming federation handling for a room again.
     EnableRoom { room_id: Box<RoomId> },
+
+    /// Stops federating a room
+    ///
+    /// Its events no longer leave this server and other servers can no longer take part in it.
+    MakeRoomLocalOnly { room_id: Box<RoomId> },
+    /// Lets a room that was made local-only federate again
+    ///
+    /// Rooms created with `m.federate: false` always stay local-only.
+    FederateRoom { room_id: Box<RoomId> },
+
+    /// Puts the server into read-only maintenance mode
+    ///
+    /// Requests that write to the database are rejected, reads and /sync keep working.
+    /// Messages to the admin room are still accepted.
+    EnableMaintenanceMode,
+    /// Leaves maintenance mode
+    DisableMaintenanceMode,
 }
 
 #[derive(Debug)]
@@ -165,6 +197,13 @@ pub enum AdminRoomEvent {
     SendMessage(RoomMessageEventContent),
 }
 
+/// Whether a message in the admin room is a command, which starts with the id of the server
+/// user followed by a colon, like `@conduit:example.org: list-local-users`.
+pub fn is_command(body: &str, server_user: &UserId) -> bool {
+    body.strip_prefix(server_user.as_str())
+        .map_or(false, |rest| rest.starts_with(": "))
+}
+
 pub struct Service {
     pub sender: mpsc::UnboundedSender<AdminRoomEvent>,
     receiver: Mutex<mpsc::UnboundedReceiver<AdminRoomEvent>>,
@@ -191,8 +230,7 @@ impl Service {
         // TODO: Use futures when we have long admin commands
         //let mut futures = FuturesUnordered::new();
 
-        let conduit_user = UserId::parse(format!("@conduit:{}", services().globals.server_name()))
-            .expect("@conduit:server_name is valid");
+        let conduit_user = services().globals.server_user();
 
         let conduit_room = services()
             .rooms
@@ -260,6 +298,10 @@ impl Service {
     }
 
     pub fn send_message(&self, message_content: RoomMessageEventContent) {
+        if !services().globals.admin_room_enabled() {
+            return;
+        }
+
         self.sender
             .send(AdminRoomEvent::SendMessage(message_content))
             .unwrap();
@@ -274,9 +316,9 @@ impl Service {
         let admin_command = match self.parse_admin_command(command_line) {
             Ok(command) => command,
             Err(error) => {
-                let server_name = services().globals.server_name();
-                let message = error.replace("server.name", server_name.as_str());
-                let html_message = self.usage_to_html(&message, server_name);
+                let server_user = services().globals.server_user();
+                let message = error.replace("@conduit:server.name", server_user.as_str());
+                let html_message = self.usage_to_html(&message, &server_user);
 
                 return RoomMessageEventContent::text_html(message, html_message);
             }
@@ -557,12 +599,7 @@ impl Service {
                 // Check if the specified user is valid
                 if !services().users.exists(&user_id)?
                     || services().users.is_deactivated(&user_id)?
-                    || user_id
-                        == UserId::parse_with_server_name(
-                            "conduit",
-                            services().globals.server_name(),
-                        )
-                        .expect("conduit user exists")
+                    || user_id == services().globals.server_user()
                 {
                     return Ok(RoomMessageEventContent::text_plain(
                         "The specified user does not exist or is deactivated!",
@@ -645,6 +682,23 @@ impl Service {
                     "Created user with user_id: {user_id} and password: {password}"
                 ))
             }
+            AdminCommand::PurgeHistory { room_id, before_ts } => {
+                let count = services()
+                    .rooms
+                    .timeline
+                    .purge_history(&room_id, UInt::new_saturating(before_ts))?;
+                services().globals.cleanup()?;
+                RoomMessageEventContent::text_plain(format!(
+                    "Purged {count} events from {room_id}."
+                ))
+            }
+            AdminCommand::ForgetRoom { room_id } => {
+                let count = services().rooms.timeline.forget_room(&room_id).await?;
+                services().globals.cleanup()?;
+                RoomMessageEventContent::text_plain(format!(
+                    "Forgot {room_id} and purged {count} events."
+                ))
+            }
             AdminCommand::DisableRoom { room_id } => {
                 services().rooms.metadata.disable_room(&room_id, true)?;
                 RoomMessageEventContent::text_plain("Room disabled.")
@@ -653,6 +707,28 @@ impl Service {
                 services().rooms.metadata.disable_room(&room_id, false)?;
                 RoomMessageEventContent::text_plain("Room enabled.")
             }
+            AdminCommand::MakeRoomLocalOnly { room_id } => {
+                services().rooms.metadata.mark_local_only(&room_id, true)?;
+                RoomMessageEventContent::text_plain("Room is now local-only.")
+            }
+            AdminCommand::FederateRoom { room_id } => {
+                services().rooms.metadata.mark_local_only(&room_id, false)?;
+                if services().rooms.metadata.is_local_only(&room_id)? {
+                    RoomMessageEventContent::text_plain(
+                        "Room was created with m.federate: false and stays local-only.",
+                    )
+                } else {
+                    RoomMessageEventContent::text_plain("Room federates again.")
+                }
+            }
+            AdminCommand::EnableMaintenanceMode => {
+                services().globals.set_maintenance_mode(true);
+                RoomMessageEventContent::text_plain("Maintenance mode enabled.")
+            }
+            AdminCommand::DisableMaintenanceMode => {
+                services().globals.set_maintenance_mode(false);
+                RoomMessageEventContent::text_plain("Maintenance mode disabled.")
+            }
             AdminCommand::DeactivateUser {
                 leave_rooms,
                 user_id,
@@ -663,7 +739,7 @@ impl Service {
                         "Making {user_id} leave all rooms before deactivation..."
                     ));
 
-                    services().users.deactivate_account(&user_id)?;
+                    services().users.deactivate_account(&user_id, None).await?;
 
                     if leave_rooms {
                         leave_all_rooms(&user_id).await?;
@@ -713,7 +789,12 @@ impl Service {
                     }
 
                     for &user_id in &user_ids {
-                        if services().users.deactivate_account(user_id).is_ok() {
+                        if services()
+                            .users
+                            .deactivate_account(user_id, None)
+                            .await
+                            .is_ok()
+                        {
                             deactivation_count += 1
                         }
                     }
@@ -743,12 +824,9 @@ impl Service {
     }
 
     // Utility to turn clap's `--help` text to HTML.
-    fn usage_to_html(&self, text: &str, server_name: &ServerName) -> String {
+    fn usage_to_html(&self, text: &str, server_user: &UserId) -> String {
         // Replace `@conduit:servername:-subcmdname` with `@conduit:servername: subcmdname`
-        let text = text.replace(
-            &format!("@conduit:{server_name}:-"),
-            &format!("@conduit:{server_name}: "),
-        );
+        let text = text.replace(&format!("{server_user}:-"), &format!("{server_user}: "));
 
         // For the conduit admin room, subcommands become main commands
         let text = text.replace("SUBCOMMAND", "COMMAND");
@@ -801,8 +879,8 @@ impl Service {
         // Improve the usage section
         let text = if command_body.is_empty() {
             // Wrap the usage line in code tags
-            let re = Regex::new("(?m)^USAGE:\n    (@conduit:.*)$")
-                .expect("Regex compilation should not fail");
+            let re =
+                Regex::new("(?m)^USAGE:\n    (@.*)$").expect("Regex compilation should not fail");
             re.replace_all(&text, "USAGE:\n<code>$1</code>").to_string()
         } else {
             // Wrap the usage line in a code block, and add a yaml block example
@@ -820,6 +898,16 @@ impl Service {
             .replace("[nobr]<br>", "")
     }
 
+    /// Returns the id of the admin room, if it was created already.
+    pub fn admin_room_id(&self) -> Result<Option<OwnedRoomId>> {
+        services().rooms.alias.resolve_local_alias(
+            format!("#admins:{}", services().globals.server_name())
+                .as_str()
+                .try_into()
+                .expect("#admins:server_name is a valid room alias"),
+        )
+    }
+
     /// Create the admin room.
     ///
     /// Users in this room are considered admins by conduit, and the room can be
@@ -841,9 +929,7 @@ impl Service {
         let state_lock = mutex_state.lock().await;
 
         // Create a user for the server
-        let conduit_user =
-            UserId::parse_with_server_name("conduit", services().globals.server_name())
-                .expect("@conduit:server_name is valid");
+        let conduit_user = services().globals.server_user();
 
         services().users.create(&conduit_user, None)?;
 
@@ -1047,9 +1133,7 @@ impl Service {
         let state_lock = mutex_state.lock().await;
 
         // Use the server user to grant the new admin's power level
-        let conduit_user =
-            UserId::parse_with_server_name("conduit", services().globals.server_name())
-                .expect("@conduit:server_name is valid");
+        let conduit_user = services().globals.server_user();
 
         // Invite and join the real user
         services().rooms.timeline.build_and_append_pdu(
@@ -1124,8 +1208,8 @@ impl Service {
             PduBuilder {
                 event_type: RoomEventType::RoomMessage,
                 content: to_raw_value(&RoomMessageEventContent::text_html(
-                        format!("## Thank you for trying out Conduit!\n\nConduit is currently in Beta. This means you can join and participate in most Matrix rooms, but not all features are supported and you might run into bugs from time to time.\n\nHelpful links:\n> Website: https://conduit.rs\n> Git and Documentation: https://gitlab.com/famedly/conduit\n> Report issues: https://gitlab.com/famedly/conduit/-/issues\n\nFor a list of available commands, send the following message in this room: `@conduit:{}: --help`\n\nHere are some rooms you can join (by typing the command):\n\nConduit room (Ask questions and get notified on updates):\n`/join #conduit:fachschaften.org`\n\nConduit lounge (Off-topic, only Conduit users are allowed to join)\n`/join #conduit-lounge:conduit.rs`", services().globals.server_name()),
-                        format!("<h2>Thank you for trying out Conduit!</h2>\n<p>Conduit is currently in Beta. This means you can join and participate in most Matrix rooms, but not all features are supported and you might run into bugs from time to time.</p>\n<p>Helpful links:</p>\n<blockquote>\n<p>Website: https://conduit.rs<br>Git and Documentation: https://gitlab.com/famedly/conduit<br>Report issues: https://gitlab.com/famedly/conduit/-/issues</p>\n</blockquote>\n<p>For a list of available commands, send the following message in this room: <code>@conduit:{}: --help</code></p>\n<p>Here are some rooms you can join (by typing the command):</p>\n<p>Conduit room (Ask questions and get notified on updates):<br><code>/join #conduit:fachschaften.org</code></p>\n<p>Conduit lounge (Off-topic, only Conduit users are allowed to join)<br><code>/join #conduit-lounge:conduit.rs</code></p>\n", services().globals.server_name()),
+                        format!("## Thank you for trying out Conduit!\n\nConduit is currently in Beta. This means you can join and participate in most Matrix rooms, but not all features are supported and you might run into bugs from time to time.\n\nHelpful links:\n> Website: https://conduit.rs\n> Git and Documentation: https://gitlab.com/famedly/conduit\n> Report issues: https://gitlab.com/famedly/conduit/-/issues\n\nFor a list of available commands, send the following message in this room: `{}: --help`\n\nHere are some rooms you can join (by typing the command):\n\nConduit room (Ask questions and get notified on updates):\n`/join #conduit:fachschaften.org`\n\nConduit lounge (Off-topic, only Conduit users are allowed to join)\n`/join #conduit-lounge:conduit.rs`", conduit_user),
+                        format!("<h2>Thank you for trying out Conduit!</h2>\n<p>Conduit is currently in Beta. This means you can join and participate in most Matrix rooms, but not all features are supported and you might run into bugs from time to time.</p>\n<p>Helpful links:</p>\n<blockquote>\n<p>Website: https://conduit.rs<br>Git and Documentation: https://gitlab.com/famedly/conduit<br>Report issues: https://gitlab.com/famedly/conduit/-/issues</p>\n</blockquote>\n<p>For a list of available commands, send the following message in this room: <code>{}: --help</code></p>\n<p>Here are some rooms you can join (by typing the command):</p>\n<p>Conduit room (Ask questions and get notified on updates):<br><code>/join #conduit:fachschaften.org</code></p>\n<p>Conduit lounge (Off-topic, only Conduit users are allowed to join)<br><code>/join #conduit-lounge:conduit.rs</code></p>\n", conduit_user),
                 ))
                 .expect("event is valid, we just created it"),
                 unsigned: None,
@@ -1143,7 +1227,10 @@ impl Service {
 
 #[cfg(test)]
 mod test {
+    use std::time::Duration;
+
     use super::*;
+    use crate::utils::testing;
 
     #[test]
     fn get_help_short() {
@@ -1170,4 +1257,255 @@ mod test {
         assert!(error.contains("Commands:"));
         assert!(error.contains("Options:"));
     }
+
+    #[test]
+    fn commands_are_addressed_to_the_server_user() {
+        let server_user = ruma::user_id!("@admin-bot:example.org");
+
+        assert!(is_command(
+            "@admin-bot:example.org: list-local-users",
+            server_user
+        ));
+        assert!(!is_command(
+            "@conduit:example.org: list-local-users",
+            server_user
+        ));
+        assert!(!is_command("@admin-bot:example.org", server_user));
+        assert!(!is_command("hello @admin-bot:example.org: ", server_user));
+    }
+
+    #[test]
+    fn ops_commands_are_parsed() {
+        let parse =
+            |command: &str| AdminCommand::try_parse_from(command.split_whitespace()).unwrap();
+
+        assert!(matches!(
+            parse("@admin-bot:example.org: list-local-users"),
+            AdminCommand::ListLocalUsers
+        ));
+        assert!(matches!(
+            parse("@admin-bot:example.org: deactivate-user --leave-rooms @bob:example.org"),
+            AdminCommand::DeactivateUser { leave_rooms: true, user_id } if user_id.as_str() == "@bob:example.org"
+        ));
+        assert!(matches!(
+            parse("@admin-bot:example.org: get-auth-chain $event:example.org"),
+            AdminCommand::GetAuthChain { event_id } if event_id.as_str() == "$event:example.org"
+        ));
+    }
+
+    #[tokio::test]
+    async fn commands_in_the_admin_room_are_answered() {
+        let admin = testing::admin("admin_room_admin").await;
+        let admin_room = services().admin.admin_room_id().unwrap().unwrap();
+        let server_user = services().globals.server_user();
+
+        testing::send_message(
+            &admin,
+            &admin_room,
+            &format!("{}: list-local-users", server_user),
+        )
+        .await;
+
+        // The answer is sent in the background
+        let answer = |pdu: &PduEvent| {
+            let content: serde_json::Value = serde_json::from_str(pdu.content.get()).ok()?;
+            let body = content["body"].as_str()?;
+            (pdu.sender == server_user && body.starts_with("Found ")).then(|| body.to_owned())
+        };
+        let mut body = None;
+        for _ in 0..100 {
+            body = services()
+                .rooms
+                .timeline
+                .pdus_until(&admin, &admin_room, u64::MAX)
+                .unwrap()
+                .filter_map(|r| r.ok())
+                .find_map(|(_, pdu)| answer(&pdu));
+            if body.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let body = body.expect("the command is answered");
+        assert!(body.contains("local user account(s)"));
+        assert!(body.contains("admin_room_admin"));
+    }
+
+    #[tokio::test]
+    async fn forgotten_rooms_are_purged_after_local_users_left() {
+        use axum::{
+            routing::{get, put},
+            Json, Router,
+        };
+        use ruma::api::client::membership::{join_room_by_id, leave_room};
+        use serde_json::json;
+
+        use crate::api::client_server::{join_room_by_id_route, leave_room_route};
+
+        let alice = testing::user("forget_alice").await;
+        let remote = testing::RemoteHomeserver::new("forget.remote.test").await;
+        let dave = remote.user_id("dave");
+        let room_id = RoomId::parse("!abandoned:forget.remote.test").unwrap();
+        let room_version = RoomVersionId::V9;
+
+        let event = |event_type: &str,
+                     state_key: &str,
+                     content: serde_json::Value,
+                     prev_events: &[&EventId],
+                     auth_events: &[&EventId],
+                     depth: u64| {
+            remote.sign(
+                json!({
+                    "type": event_type,
+                    "room_id": room_id,
+                    "sender": dave,
+                    "state_key": state_key,
+                    "origin": remote.server_name,
+                    "origin_server_ts": utils::millis_since_unix_epoch(),
+                    "content": content,
+                    "depth": depth,
+                    "prev_events": prev_events,
+                    "auth_events": auth_events,
+                }),
+                &room_version,
+            )
+        };
+        let (create_id, create) = event(
+            "m.room.create",
+            "",
+            json!({ "creator": dave, "room_version": "9" }),
+            &[],
+            &[],
+            1,
+        );
+        let (join_id, join) = event(
+            "m.room.member",
+            dave.as_str(),
+            json!({ "membership": "join" }),
+            &[&create_id],
+            &[&create_id],
+            2,
+        );
+        let (power_levels_id, power_levels) = event(
+            "m.room.power_levels",
+            "",
+            json!({ "users": { dave.as_str(): 100 } }),
+            &[&join_id],
+            &[&create_id, &join_id],
+            3,
+        );
+        let (join_rules_id, join_rules) = event(
+            "m.room.join_rules",
+            "",
+            json!({ "join_rule": "public" }),
+            &[&power_levels_id],
+            &[&create_id, &join_id, &power_levels_id],
+            4,
+        );
+
+        let template = json!({
+            "type": "m.room.member",
+            "room_id": room_id,
+            "sender": alice,
+            "state_key": alice,
+            "content": { "membership": "join" },
+            "origin_server_ts": utils::millis_since_unix_epoch(),
+            "depth": 5,
+            "prev_events": [join_rules_id],
+            "auth_events": [create_id, power_levels_id, join_rules_id],
+        });
+        let state = json!([create, join, power_levels, join_rules]);
+        let origin = remote.server_name.to_string();
+        remote
+            .serve(
+                Router::new()
+                    .route(
+                        "/_matrix/federation/v1/make_join/:room_id/:user_id",
+                        get(move || {
+                            let template = template.clone();
+                            async move { Json(json!({ "room_version": "9", "event": template })) }
+                        }),
+                    )
+                    .route(
+                        "/_matrix/federation/v2/send_join/:room_id/:event_id",
+                        put(move || {
+                            let response = json!({
+                                "origin": origin,
+                                "state": state,
+                                "auth_chain": state,
+                            });
+                            async move { Json(response) }
+                        }),
+                    )
+                    // Our events are sent to the remote in the background
+                    .route(
+                        "/_matrix/federation/v1/send/:txn_id",
+                        put(|| async { Json(json!({ "pdus": {} })) }),
+                    ),
+            )
+            .await;
+
+        join_room_by_id_route(testing::request(
+            join_room_by_id::v3::Request::new(room_id.clone()),
+            &alice,
+        ))
+        .await
+        .unwrap();
+        let message = testing::send_message(&alice, &room_id, "Anyone here?").await;
+
+        // Rooms with local members are never forgotten
+        assert!(services()
+            .admin
+            .process_admin_command(
+                AdminCommand::ForgetRoom {
+                    room_id: room_id.clone().into()
+                },
+                Vec::new()
+            )
+            .await
+            .is_err());
+
+        leave_room_route(testing::request(
+            leave_room::v3::Request::new(room_id.clone()),
+            &alice,
+        ))
+        .await
+        .unwrap();
+
+        let reply = services()
+            .admin
+            .process_admin_command(
+                AdminCommand::ForgetRoom {
+                    room_id: room_id.clone().into(),
+                },
+                Vec::new(),
+            )
+            .await
+            .unwrap();
+        assert!(reply.body().starts_with(&format!("Forgot {room_id}")));
+
+        // Our join, the message and our leave were the room's timeline
+        assert!(services()
+            .rooms
+            .timeline
+            .get_pdu_id(&message)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            services()
+                .rooms
+                .timeline
+                .pdus_after(&alice, &room_id, 0)
+                .unwrap()
+                .count(),
+            0
+        );
+        assert!(services()
+            .rooms
+            .state
+            .get_room_shortstatehash(&room_id)
+            .unwrap()
+            .is_none());
+    }
 }
@@ -2,16 +2,25 @@ mod data;
 
 pub use data::Data;
 
-use crate::Result;
+use std::sync::{Arc, RwLock};
+
+use regex::{Regex, RegexSet};
+use ruma::UserId;
+
+use crate::{Error, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
+    /// The exclusive user namespaces of all appservices, compiled once after registrations change
+    pub exclusive_users: RwLock<Option<Arc<RegexSet>>>,
 }
 
 impl Service {
     /// Registers an appservice and returns the ID to the caller
     pub fn register_appservice(&self, yaml: serde_yaml::Value) -> Result<String> {
-        self.db.register_appservice(yaml)
+        let id = self.db.register_appservice(yaml)?;
+        *self.exclusive_users.write().unwrap() = None;
+        Ok(id)
     }
 
     /// Remove an appservice registration
@@ -20,7 +29,9 @@ impl Service {
     ///
     /// * `service_name` - the name you send to register the service previously
     pub fn unregister_appservice(&self, service_name: &str) -> Result<()> {
-        self.db.unregister_appservice(service_name)
+        self.db.unregister_appservice(service_name)?;
+        *self.exclusive_users.write().unwrap() = None;
+        Ok(())
     }
 
     pub fn get_registration(&self, id: &str) -> Result<Option<serde_yaml::Value>> {
@@ -34,4 +45,64 @@ impl Service {
     pub fn all(&self) -> Result<Vec<(String, serde_yaml::Value)>> {
         self.db.all()
     }
+
+    /// The exclusive user namespaces of all appservices. User ids matching one are reserved for
+    /// the appservice.
+    pub fn exclusive_users(&self) -> Result<Arc<RegexSet>> {
+        if let Some(exclusive_users) = &*self.exclusive_users.read().unwrap() {
+            return Ok(Arc::clone(exclusive_users));
+        }
+
+        let exclusive_users = Arc::new(exclusive_user_regexes(&self.all()?)?);
+        *self.exclusive_users.write().unwrap() = Some(Arc::clone(&exclusive_users));
+
+        Ok(exclusive_users)
+    }
+}
+
+/// Compiles the exclusive user namespaces of the registrations into one set. Invalid regexes are
+/// skipped.
+pub fn exclusive_user_regexes(registrations: &[(String, serde_yaml::Value)]) -> Result<RegexSet> {
+    let regexes = registrations
+        .iter()
+        .filter_map(|(_, registration)| registration.get("namespaces")?.get("users")?.as_sequence())
+        .flatten()
+        .filter(|users| {
+            users
+                .get("exclusive")
+                .and_then(|exclusive| exclusive.as_bool())
+                .unwrap_or(false)
+        })
+        .filter_map(|users| users.get("regex")?.as_str())
+        .filter(|regex| Regex::new(regex).is_ok());
+
+    RegexSet::new(regexes)
+        .map_err(|_| Error::bad_database("Appservice user namespaces are too large to compile."))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_exclusive_user_namespaces_are_compiled() {
+        let registration = serde_yaml::from_str(
+            r#"
+namespaces:
+  users:
+    - exclusive: true
+      regex: "@irc_.*:example.org"
+    - exclusive: false
+      regex: "@bridged_.*:example.org"
+    - exclusive: true
+      regex: "@broken_(:example.org"
+"#,
+        )
+        .unwrap();
+        let exclusive_users = exclusive_user_regexes(&[("irc".to_owned(), registration)]).unwrap();
+
+        assert!(exclusive_users.is_match("@irc_alice:example.org"));
+        assert!(!exclusive_users.is_match("@bridged_alice:example.org"));
+        assert!(!exclusive_users.is_match("@alice:example.org"));
+    }
 }
@@ -0,0 +1,173 @@
+use ruma::api::client::error::ErrorKind;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{services, Error, Result};
+
+#[derive(Deserialize)]
+struct SiteverifyResponse {
+    success: bool,
+    #[serde(default, rename = "error-codes")]
+    error_codes: Vec<String>,
+}
+
+pub struct Service;
+
+impl Service {
+    pub fn build() -> Self {
+        Self
+    }
+
+    /// Whether registration asks for a captcha.
+    pub fn enabled(&self) -> bool {
+        services().globals.captcha().is_some()
+    }
+
+    /// The public key clients show the captcha with.
+    pub fn site_key(&self) -> Option<&str> {
+        services()
+            .globals
+            .captcha()
+            .map(|captcha| captcha.site_key.as_str())
+    }
+
+    /// Asks the captcha provider whether the client solved the captcha.
+    pub async fn verify(&self, response: &str) -> Result<bool> {
+        let captcha = services().globals.captcha().ok_or(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Captchas are not enabled.",
+        ))?;
+
+        let url = captcha
+            .verify_url
+            .as_deref()
+            .unwrap_or_else(|| captcha.provider.verify_url());
+
+        verify_at(
+            &services().globals.default_client(),
+            url,
+            &captcha.secret,
+            response,
+        )
+        .await
+    }
+}
+
+/// reCAPTCHA and hCaptcha share the same siteverify API: a form with the secret and the response
+/// token, answered with `success`.
+async fn verify_at(
+    client: &reqwest::Client,
+    url: &str,
+    secret: &str,
+    response: &str,
+) -> Result<bool> {
+    let result = client
+        .post(url)
+        .form(&[("secret", secret), ("response", response)])
+        .send()
+        .await
+        .map_err(|e| {
+            warn!("Could not reach captcha provider {}: {}", url, e);
+            Error::BadServerResponse("Could not reach the captcha provider.")
+        })?;
+
+    if !result.status().is_success() {
+        warn!("Captcha provider {} returned {}", url, result.status());
+        return Err(Error::BadServerResponse(
+            "Captcha provider returned an error.",
+        ));
+    }
+
+    let body = result
+        .bytes()
+        .await
+        .map_err(|_| Error::BadServerResponse("Could not read captcha provider response."))?;
+    let verification: SiteverifyResponse = serde_json::from_slice(&body)
+        .map_err(|_| Error::BadServerResponse("Captcha provider returned an invalid response."))?;
+
+    if !verification.success && !verification.error_codes.is_empty() {
+        warn!(
+            "Captcha verification failed: {}",
+            verification.error_codes.join(", ")
+        );
+    }
+
+    Ok(verification.success)
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// Answers siteverify requests like a provider for which only the token `solved` is valid,
+    /// and returns the form bodies it got.
+    async fn mock_provider(listener: TcpListener, requests: usize) -> Vec<String> {
+        let mut bodies = Vec::new();
+        for _ in 0..requests {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(stream);
+
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).await.unwrap();
+                if header == "\r\n" {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).await.unwrap();
+            let body = String::from_utf8(body).unwrap();
+
+            let response = if body.ends_with("response=solved") {
+                r#"{"success":true}"#
+            } else {
+                r#"{"success":false,"error-codes":["invalid-input-response"]}"#
+            };
+
+            reader
+                .get_mut()
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        response.len(),
+                        response
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+            bodies.push(body);
+        }
+        bodies
+    }
+
+    #[tokio::test]
+    async fn captcha_tokens_are_checked_with_the_provider() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/siteverify", listener.local_addr().unwrap());
+        let server = tokio::spawn(mock_provider(listener, 2));
+
+        let client = reqwest::Client::new();
+        assert!(verify_at(&client, &url, "secret", "solved").await.unwrap());
+        assert!(!verify_at(&client, &url, "secret", "guessed").await.unwrap());
+
+        assert_eq!(
+            server.await.unwrap(),
+            [
+                "secret=secret&response=solved",
+                "secret=secret&response=guessed"
+            ]
+        );
+    }
+}
@@ -0,0 +1,25 @@
+use ruma::{OwnedUserId, UserId};
+
+use crate::Result;
+
+use super::DelayedEvent;
+
+pub trait Data: Send + Sync {
+    /// Stores a new delayed event or replaces one with the same delay id.
+    fn save_delayed_event(&self, user_id: &UserId, event: &DelayedEvent) -> Result<()>;
+
+    fn get_delayed_event(&self, user_id: &UserId, delay_id: &str) -> Result<Option<DelayedEvent>>;
+
+    fn remove_delayed_event(&self, user_id: &UserId, delay_id: &str) -> Result<()>;
+
+    /// Returns the delayed events of the user.
+    fn delayed_events<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<DelayedEvent>> + 'a>;
+
+    /// Returns the delayed events of all users.
+    fn all_delayed_events<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedUserId, DelayedEvent)>> + 'a>;
+}
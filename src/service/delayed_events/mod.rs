@@ -0,0 +1,476 @@
+mod data;
+
+pub use data::Data;
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use ruma::{
+    api::client::error::ErrorKind,
+    events::{AnyStateEventContent, StateEventType},
+    serde::Raw,
+    OwnedRoomId, OwnedUserId, RoomId, UserId,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue as RawJsonValue;
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use crate::{
+    service::{pdu::PduBuilder, rooms::state::validate_state_content},
+    services, utils, Error, Result,
+};
+
+const DELAY_ID_LENGTH: usize = 24;
+
+/// An event a user scheduled to be sent later (MSC4140). Events with a parent have no timer of
+/// their own, they are sent or cancelled together with their parent, but can also be sent or
+/// cancelled on their own.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DelayedEvent {
+    pub delay_id: String,
+    pub room_id: OwnedRoomId,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_key: Option<String>,
+    pub content: Box<RawJsonValue>,
+    /// In milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delay: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_delay_id: Option<String>,
+    /// When the timer runs out, in milliseconds since the unix epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub send_at: Option<u64>,
+    /// The transaction id of the request that scheduled the event, so retries don't schedule it
+    /// twice.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub txn_id: Option<String>,
+}
+
+/// When a delayed event was scheduled.
+pub enum Delay<'a> {
+    /// In milliseconds.
+    Timeout(u64),
+    Parent(&'a str),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DelayedEventAction {
+    Cancel,
+    Restart,
+    Send,
+}
+
+pub struct Service {
+    pub db: &'static dyn Data,
+    /// Wakes up the delayed events task when a timer changed.
+    pub timers_changed: Notify,
+    /// Held while delayed events are read and changed, so cancelling, restarting and sending
+    /// never act on an outdated copy of an event, and scheduling never goes over the limit.
+    pub change_lock: Mutex<()>,
+}
+
+impl Service {
+    pub fn enabled(&self) -> bool {
+        services().globals.max_event_delay().is_some()
+    }
+
+    /// Schedules an event and returns its delay id. A retry with the same transaction id returns
+    /// the delay id of the event it scheduled before.
+    #[allow(clippy::too_many_arguments)]
+    pub fn schedule(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        event_type: &str,
+        state_key: Option<&str>,
+        content: Box<RawJsonValue>,
+        delay: Delay<'_>,
+        txn_id: Option<&str>,
+    ) -> Result<String> {
+        let max_delay = services()
+            .globals
+            .max_event_delay()
+            .ok_or(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Delayed events are disabled on this server.",
+            ))?;
+
+        // Held until the event is saved, so retries running at the same time schedule it once
+        let _lock = self.change_lock.lock().unwrap();
+
+        if let Some(txn_id) = txn_id {
+            for event in self.db.delayed_events(user_id) {
+                let event = event?;
+                if event.txn_id.as_deref() == Some(txn_id) && event.room_id == room_id {
+                    return Ok(event.delay_id);
+                }
+            }
+        }
+
+        if self.db.delayed_events(user_id).count()
+            >= services().globals.max_delayed_events_per_user()
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::LimitExceeded {
+                    retry_after_ms: None,
+                },
+                "You have too many delayed events pending.",
+            ));
+        }
+
+        if !services().rooms.state_cache.is_joined(user_id, room_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "You are not joined to this room.",
+            ));
+        }
+
+        if state_key.is_some() {
+            validate_state_content(
+                &StateEventType::from(event_type),
+                &Raw::<AnyStateEventContent>::from_json(content.clone()),
+            )?;
+        }
+
+        let (delay, parent_delay_id, send_at) = match delay {
+            Delay::Timeout(delay) => {
+                if Duration::from_millis(delay) > max_delay {
+                    return Err(Error::BadRequest(
+                        ErrorKind::InvalidParam,
+                        "The delay is longer than this server allows.",
+                    ));
+                }
+                (
+                    Some(delay),
+                    None,
+                    Some(utils::millis_since_unix_epoch() + delay),
+                )
+            }
+            Delay::Parent(parent_delay_id) => {
+                match self.db.get_delayed_event(user_id, parent_delay_id)? {
+                    Some(parent) if parent.parent_delay_id.is_none() => {}
+                    _ => {
+                        return Err(Error::BadRequest(
+                            ErrorKind::NotFound,
+                            "The parent delayed event was not found.",
+                        ))
+                    }
+                }
+                (None, Some(parent_delay_id.to_owned()), None)
+            }
+        };
+
+        let event = DelayedEvent {
+            delay_id: utils::random_string(DELAY_ID_LENGTH),
+            room_id: room_id.to_owned(),
+            event_type: event_type.to_owned(),
+            state_key: state_key.map(ToOwned::to_owned),
+            content,
+            delay,
+            parent_delay_id,
+            send_at,
+            txn_id: txn_id.map(ToOwned::to_owned),
+        };
+        self.db.save_delayed_event(user_id, &event)?;
+        self.timers_changed.notify_one();
+
+        Ok(event.delay_id)
+    }
+
+    /// Returns the delayed events of the user.
+    pub fn delayed_events(&self, user_id: &UserId) -> Result<Vec<DelayedEvent>> {
+        self.db.delayed_events(user_id).collect()
+    }
+
+    /// Cancels, restarts or sends a delayed event of the user. Children can't be restarted, they
+    /// follow the timer of their parent.
+    pub async fn act(
+        &self,
+        user_id: &UserId,
+        delay_id: &str,
+        action: DelayedEventAction,
+    ) -> Result<()> {
+        let to_send = {
+            let _lock = self.change_lock.lock().unwrap();
+
+            let event = self
+                .db
+                .get_delayed_event(user_id, delay_id)?
+                .ok_or(Error::BadRequest(
+                    ErrorKind::NotFound,
+                    "Delayed event not found.",
+                ))?;
+
+            match action {
+                DelayedEventAction::Cancel => {
+                    for event in self.remove_group(user_id, &event)? {
+                        info!("{} cancelled delayed event {}", user_id, event.delay_id);
+                    }
+                    None
+                }
+                DelayedEventAction::Restart => {
+                    self.db.save_delayed_event(
+                        user_id,
+                        &restarted(event, utils::millis_since_unix_epoch())?,
+                    )?;
+                    self.timers_changed.notify_one();
+                    None
+                }
+                DelayedEventAction::Send => Some(self.remove_group(user_id, &event)?),
+            }
+        };
+
+        if let Some(group) = to_send {
+            self.send_group(user_id, group).await;
+        }
+
+        Ok(())
+    }
+
+    /// When the next timer runs out, in milliseconds since the unix epoch.
+    pub fn next_send_at(&self) -> Result<Option<u64>> {
+        let mut next = None;
+        for entry in self.db.all_delayed_events() {
+            let (_, event) = entry?;
+            next = match (next, event.send_at) {
+                (Some(next), Some(send_at)) => Some(std::cmp::min(next, send_at)),
+                (next, send_at) => next.or(send_at),
+            };
+        }
+        Ok(next)
+    }
+
+    /// Sends all delayed events whose timer ran out, with their children.
+    pub async fn send_due(&self) -> Result<()> {
+        let events = self.db.all_delayed_events().collect::<Result<Vec<_>>>()?;
+
+        for (user_id, event) in due(&events, utils::millis_since_unix_epoch()) {
+            if let Some(group) = self.take_group_if_unchanged(&user_id, event)? {
+                self.send_group(&user_id, group).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the event and its children like `remove_group`, unless the event was cancelled or
+    /// restarted since `snapshot` was read.
+    fn take_group_if_unchanged(
+        &self,
+        user_id: &UserId,
+        snapshot: &DelayedEvent,
+    ) -> Result<Option<Vec<DelayedEvent>>> {
+        let _lock = self.change_lock.lock().unwrap();
+
+        match self.db.get_delayed_event(user_id, &snapshot.delay_id)? {
+            Some(current) if unchanged(&current, snapshot) => {
+                Ok(Some(self.remove_group(user_id, &current)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Removes the event and its children from the database and returns them, parent first. The
+    /// caller has to hold `change_lock`.
+    fn remove_group(&self, user_id: &UserId, event: &DelayedEvent) -> Result<Vec<DelayedEvent>> {
+        let others = self
+            .db
+            .delayed_events(user_id)
+            .collect::<Result<Vec<_>>>()?;
+        let group = group(event, others);
+
+        for event in &group {
+            self.db.remove_delayed_event(user_id, &event.delay_id)?;
+        }
+        self.timers_changed.notify_one();
+
+        Ok(group)
+    }
+
+    /// Sends the events into their rooms. Events that can't be sent anymore, e.g. because the user
+    /// left the room, are dropped.
+    async fn send_group(&self, user_id: &UserId, group: Vec<DelayedEvent>) {
+        for event in group {
+            if let Err(e) = send_delayed_event(user_id, &event).await {
+                warn!(
+                    "Failed to send delayed event {} of {}: {}",
+                    event.delay_id, user_id, e
+                );
+            }
+        }
+    }
+}
+
+/// The event followed by its children among `others`. Children have no children themselves.
+fn group(event: &DelayedEvent, others: Vec<DelayedEvent>) -> Vec<DelayedEvent> {
+    let mut group = vec![event.clone()];
+    if event.parent_delay_id.is_none() {
+        let is_child =
+            |other: &DelayedEvent| other.parent_delay_id.as_deref() == Some(&*event.delay_id);
+        group.extend(others.into_iter().filter(is_child));
+    }
+    group
+}
+
+/// Whether the timer of an event is still the one it had when it was found to be due. Events
+/// restarted in the meantime aren't due anymore.
+fn unchanged(current: &DelayedEvent, snapshot: &DelayedEvent) -> bool {
+    current.send_at == snapshot.send_at
+}
+
+/// The event with its timer started again at `now`.
+fn restarted(mut event: DelayedEvent, now: u64) -> Result<DelayedEvent> {
+    let delay = event.delay.ok_or(Error::BadRequest(
+        ErrorKind::InvalidParam,
+        "Only delayed events with a delay can be restarted.",
+    ))?;
+    event.send_at = Some(now + delay);
+    Ok(event)
+}
+
+/// The delayed events whose timer ran out.
+fn due(events: &[(OwnedUserId, DelayedEvent)], now: u64) -> Vec<(OwnedUserId, &DelayedEvent)> {
+    events
+        .iter()
+        .filter(|(_, event)| event.send_at.map_or(false, |send_at| send_at <= now))
+        .map(|(user_id, event)| (user_id.clone(), event))
+        .collect()
+}
+
+async fn send_delayed_event(user_id: &UserId, event: &DelayedEvent) -> Result<()> {
+    if let Some(state_key) = &event.state_key {
+        services()
+            .rooms
+            .state
+            .send_state_event(
+                user_id,
+                &event.room_id,
+                &StateEventType::from(event.event_type.as_str()),
+                &Raw::from_json(event.content.clone()),
+                state_key.clone(),
+            )
+            .await?;
+        return Ok(());
+    }
+
+    // The same checks as if the event was sent right away
+    services().rooms.timeline.check_message_event(
+        user_id,
+        &event.room_id,
+        &event.event_type,
+        false,
+    )?;
+
+    let mutex_state = Arc::clone(
+        services()
+            .globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(event.room_id.clone())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    services().rooms.timeline.build_and_append_pdu(
+        PduBuilder {
+            event_type: event.event_type.as_str().into(),
+            content: event.content.clone(),
+            unsigned: None,
+            state_key: None,
+            redacts: None,
+        },
+        user_id,
+        &event.room_id,
+        &state_lock,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::{room_id, user_id};
+    use serde_json::value::to_raw_value;
+
+    use super::*;
+
+    fn event(delay_id: &str, send_at: Option<u64>, parent: Option<&str>) -> DelayedEvent {
+        DelayedEvent {
+            delay_id: delay_id.to_owned(),
+            room_id: room_id!("!room:example.org").to_owned(),
+            event_type: "m.room.message".to_owned(),
+            state_key: None,
+            content: to_raw_value(&serde_json::json!({ "body": "hi" })).unwrap(),
+            delay: send_at.map(|_| 1000),
+            parent_delay_id: parent.map(ToOwned::to_owned),
+            send_at,
+            txn_id: None,
+        }
+    }
+
+    #[test]
+    fn delayed_events_are_due_once_their_delay_passed() {
+        let alice = user_id!("@alice:example.org").to_owned();
+        let events = vec![
+            (alice.clone(), event("early", Some(5000), None)),
+            (alice.clone(), event("child", None, Some("early"))),
+            (alice.clone(), event("late", Some(9000), None)),
+        ];
+
+        assert!(due(&events, 4999).is_empty());
+
+        let ids = |now| {
+            due(&events, now)
+                .into_iter()
+                .map(|(_, event)| event.delay_id.clone())
+                .collect::<Vec<_>>()
+        };
+        // Children are sent with their parent, they are never due on their own
+        assert_eq!(ids(5000), ["early"]);
+        assert_eq!(ids(9000), ["early", "late"]);
+    }
+
+    #[test]
+    fn cancelling_takes_the_children_along() {
+        let parent = event("parent", Some(5000), None);
+        let others = vec![
+            parent.clone(),
+            event("child", None, Some("parent")),
+            event("other", Some(5000), None),
+            event("other child", None, Some("other")),
+        ];
+
+        let ids = |event: &DelayedEvent| {
+            group(event, others.clone())
+                .into_iter()
+                .map(|event| event.delay_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&parent), ["parent", "child"]);
+        // Cancelling a child leaves its parent and siblings alone
+        assert_eq!(ids(&others[1]), ["child"]);
+    }
+
+    #[test]
+    fn restarted_events_are_not_sent_by_an_older_timer() {
+        let snapshot = event("heartbeat", Some(5000), None);
+
+        let restarted_event = restarted(snapshot.clone(), 4500).unwrap();
+        assert_eq!(restarted_event.send_at, Some(5500));
+        assert!(!unchanged(&restarted_event, &snapshot));
+        assert!(unchanged(&snapshot, &snapshot));
+
+        // Children follow their parent's timer
+        assert!(matches!(
+            restarted(event("child", None, Some("heartbeat")), 4500),
+            Err(Error::BadRequest(ErrorKind::InvalidParam, _))
+        ));
+    }
+}
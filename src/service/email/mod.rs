@@ -0,0 +1,433 @@
+use std::{net::IpAddr, sync::Mutex, time::Duration};
+
+use lettre::{
+    message::{Mailbox, MultiPart},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use ruma::{api::client::error::ErrorKind, ClientSecret, MilliSecondsSinceUnixEpoch, SessionId};
+use tracing::{info, warn};
+
+use crate::{
+    config::{EmailConfig, SmtpTls},
+    service::{
+        globals::WindowLimiter,
+        threepid_sessions::{Session, SessionStart, ThreepidSessions},
+    },
+    services, utils, Error, Result,
+};
+
+const TOKEN_LENGTH: usize = 32;
+/// How many validation emails each address gets, and each client IP can ask for, per hour. The
+/// endpoints are unauthenticated, so this keeps them from being used to spam mailboxes.
+const EMAILS_PER_ADDRESS: u32 = 3;
+const EMAILS_PER_IP: u32 = 10;
+const EMAIL_LIMIT_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// Addresses and IPs the limits are kept for at most.
+const LIMITER_CAPACITY: usize = 10_000;
+
+/// What validating an email address needs besides the generic session.
+struct EmailToken {
+    token: String,
+    send_attempt: u64,
+}
+
+pub struct Service {
+    sessions: ThreepidSessions<EmailToken>,
+    address_limiter: Mutex<WindowLimiter<String>>,
+    ip_limiter: Mutex<WindowLimiter<IpAddr>>,
+}
+
+impl Service {
+    pub fn build() -> Self {
+        Self {
+            sessions: ThreepidSessions::new(),
+            address_limiter: Mutex::new(WindowLimiter::new(
+                LIMITER_CAPACITY,
+                Some(EMAILS_PER_ADDRESS),
+                EMAIL_LIMIT_WINDOW,
+            )),
+            ip_limiter: Mutex::new(WindowLimiter::new(
+                LIMITER_CAPACITY,
+                Some(EMAILS_PER_IP),
+                EMAIL_LIMIT_WINDOW,
+            )),
+        }
+    }
+
+    /// Whether emails can be sent at all.
+    pub fn enabled(&self) -> bool {
+        services().globals.email().is_some()
+    }
+
+    /// Sends an email with a validation link to the address and returns the session id. Retries
+    /// with the same `send_attempt` return the existing session without sending another email.
+    pub async fn request_validation(
+        &self,
+        email: &str,
+        client_secret: &ClientSecret,
+        send_attempt: u64,
+        client_ip: Option<IpAddr>,
+    ) -> Result<String> {
+        let config = services().globals.email().ok_or(Error::BadRequest(
+            ErrorKind::ThreepidDenied,
+            "This server can't send emails.",
+        ))?;
+
+        let token = utils::random_string(TOKEN_LENGTH);
+        let sid = match self.start_session(email, client_secret, send_attempt, token.clone())? {
+            SessionStart::New { sid } => sid,
+            SessionStart::Retry { sid } => return Ok(sid),
+        };
+
+        if let Err(e) = self.check_limits(email, client_ip) {
+            self.sessions.remove(&sid);
+            return Err(e);
+        }
+
+        let link = format!(
+            "{}/_matrix/client/unstable/add_threepid/email/submit_token?sid={}&client_secret={}&token={}",
+            services().globals.client_base_url(),
+            sid,
+            client_secret,
+            token
+        );
+        let server_name = services().globals.server_name().as_str();
+        let vars = [
+            ("server_name", server_name),
+            ("link", &link),
+            ("token", &token),
+        ];
+
+        if let Err(e) = send_email(
+            config,
+            email,
+            &render(&config.verification_subject, &vars, false),
+            &render(&config.verification_text, &vars, false),
+            &render(&config.verification_html, &vars, true),
+        )
+        .await
+        {
+            self.sessions.remove(&sid);
+            return Err(e);
+        }
+
+        info!("Sent validation email for session {}", sid);
+        Ok(sid)
+    }
+
+    /// Creates a session for the token, unless this is a retry of an existing one.
+    fn start_session(
+        &self,
+        email: &str,
+        client_secret: &ClientSecret,
+        send_attempt: u64,
+        token: String,
+    ) -> Result<SessionStart> {
+        self.sessions.start(
+            Session::new(
+                client_secret,
+                email.to_owned(),
+                None,
+                EmailToken {
+                    token,
+                    send_attempt,
+                },
+            ),
+            |session| {
+                session.client_secret == client_secret.as_str()
+                    && session.address == email
+                    && session.data.send_attempt >= send_attempt
+            },
+        )
+    }
+
+    /// Counts a new validation email against the limits of the address and the client IP.
+    fn check_limits(&self, email: &str, client_ip: Option<IpAddr>) -> Result<()> {
+        let limited = client_ip
+            .map_or(Ok(()), |ip| self.ip_limiter.lock().unwrap().check(&ip))
+            .and_then(|()| self.address_limiter.lock().unwrap().check(email));
+
+        limited.map_err(|retry_after| {
+            Error::BadRequest(
+                ErrorKind::LimitExceeded {
+                    retry_after_ms: Some(retry_after),
+                },
+                "Too many validation emails requested, try again later.",
+            )
+        })
+    }
+
+    /// Marks the session as validated if the token is the one we sent.
+    pub fn submit_token(&self, sid: &str, client_secret: &str, token: &str) -> Result<()> {
+        if self.sessions.validate(sid, |session| {
+            session.client_secret == client_secret && session.data.token == token
+        }) {
+            Ok(())
+        } else {
+            Err(Error::BadRequest(
+                ErrorKind::ThreepidAuthFailed,
+                "Unknown session or wrong token.",
+            ))
+        }
+    }
+
+    /// Returns the email address and when it was validated, if the session was validated.
+    pub fn validated(
+        &self,
+        sid: &SessionId,
+        client_secret: &ClientSecret,
+    ) -> Option<(String, MilliSecondsSinceUnixEpoch)> {
+        self.sessions.validated(sid, client_secret)
+    }
+
+    /// Remembers the address validated in the `m.login.email.identity` stage of a UIAA session.
+    pub fn set_uiaa_email(
+        &self,
+        uiaa_session: &str,
+        email: String,
+        validated_at: MilliSecondsSinceUnixEpoch,
+    ) {
+        self.sessions.set_uiaa(uiaa_session, email, validated_at);
+    }
+
+    /// Returns the address validated in the UIAA session, once.
+    pub fn take_uiaa_email(
+        &self,
+        uiaa_session: &str,
+    ) -> Option<(String, MilliSecondsSinceUnixEpoch)> {
+        self.sessions.take_uiaa(uiaa_session)
+    }
+
+    /// Sends an email to `to`, for notices that aren't part of a validation.
+    pub async fn send(&self, to: &str, subject: &str, text: &str, html: &str) -> Result<()> {
+        let config = services().globals.email().ok_or(Error::BadRequest(
+            ErrorKind::ThreepidDenied,
+            "This server can't send emails.",
+        ))?;
+
+        send_email(config, to, subject, text, html).await
+    }
+}
+
+async fn send_email(
+    config: &EmailConfig,
+    to: &str,
+    subject: &str,
+    text: &str,
+    html: &str,
+) -> Result<()> {
+    let from: Mailbox = config
+        .from
+        .parse()
+        .map_err(|_| Error::bad_config("Invalid email.from address."))?;
+    let to: Mailbox = to
+        .parse()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid email address."))?;
+
+    let message = Message::builder()
+        .from(from)
+        .to(to)
+        .subject(subject)
+        .multipart(MultiPart::alternative_plain_html(
+            text.to_owned(),
+            html.to_owned(),
+        ))
+        .map_err(|_| Error::bad_config("Could not build email from the templates."))?;
+
+    let builder = match config.tls {
+        SmtpTls::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+            &config.smtp_host,
+        )),
+        SmtpTls::Starttls => {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host)
+        }
+        SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.smtp_host),
+    }
+    .map_err(|_| Error::bad_config("Invalid email.smtp_host."))?;
+
+    let mut builder = match config.smtp_port {
+        Some(port) => builder.port(port),
+        None => builder,
+    };
+    if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+        builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+
+    builder.build().send(message).await.map_err(|e| {
+        warn!("Could not send email through {}: {}", config.smtp_host, e);
+        Error::BadServerResponse("Could not send email.")
+    })?;
+
+    Ok(())
+}
+
+/// Replaces `{name}` placeholders in the template. Values are escaped for HTML templates.
+fn render(template: &str, vars: &[(&str, &str)], html: bool) -> String {
+    vars.iter()
+        .fold(template.to_owned(), |rendered, (name, value)| {
+            let value = if html {
+                value
+                    .replace('&', "&amp;")
+                    .replace('<', "&lt;")
+                    .replace('>', "&gt;")
+                    .replace('"', "&quot;")
+            } else {
+                (*value).to_owned()
+            };
+            rendered.replace(&format!("{{{name}}}"), &value)
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    #[test]
+    fn placeholders_are_replaced() {
+        let vars = [
+            ("server_name", "example.org"),
+            ("link", "https://a/?b=1&c=2"),
+        ];
+
+        assert_eq!(
+            render("Validate on {server_name}: {link}", &vars, false),
+            "Validate on example.org: https://a/?b=1&c=2"
+        );
+        assert_eq!(
+            render("<a href=\"{link}\">{unknown}</a>", &vars, true),
+            "<a href=\"https://a/?b=1&amp;c=2\">{unknown}</a>"
+        );
+    }
+
+    #[test]
+    fn sessions_are_validated_with_the_emailed_token() {
+        let service = Service::build();
+        let client_secret: &ClientSecret = "secret".try_into().unwrap();
+
+        let start = |token: &str| {
+            service
+                .start_session("alice@example.com", client_secret, 1, token.to_owned())
+                .unwrap()
+        };
+
+        let sid = match start("token") {
+            SessionStart::New { sid } => sid,
+            SessionStart::Retry { .. } => panic!("no session was started yet"),
+        };
+        // Retrying doesn't send another email
+        assert_eq!(start("other"), SessionStart::Retry { sid: sid.clone() });
+
+        let sid_ref: &SessionId = sid.as_str().try_into().unwrap();
+        assert!(service.submit_token(&sid, "secret", "wrong").is_err());
+        assert!(service.validated(sid_ref, client_secret).is_none());
+
+        service.submit_token(&sid, "secret", "token").unwrap();
+        assert_eq!(
+            service
+                .validated(sid_ref, client_secret)
+                .map(|(email, _)| email),
+            Some("alice@example.com".to_owned())
+        );
+        assert!(service
+            .validated(sid_ref, "other".try_into().unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn validation_emails_are_limited_per_address_and_ip() {
+        let service = Service::build();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        for _ in 0..EMAILS_PER_ADDRESS {
+            assert!(service.check_limits("alice@example.com", None).is_ok());
+        }
+        assert!(service.check_limits("alice@example.com", None).is_err());
+
+        for i in 0..EMAILS_PER_IP {
+            assert!(service
+                .check_limits(&format!("user{i}@example.com"), Some(ip))
+                .is_ok());
+        }
+        assert!(service.check_limits("bob@example.com", Some(ip)).is_err());
+        assert!(service.check_limits("bob@example.com", None).is_ok());
+    }
+
+    /// Accepts one SMTP session and returns the message it received.
+    async fn mock_smtp_server(listener: TcpListener) -> String {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let mut message = String::new();
+        let mut in_data = false;
+
+        writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            if in_data {
+                if line == "." {
+                    in_data = false;
+                    writer.write_all(b"250 queued\r\n").await.unwrap();
+                } else {
+                    message.push_str(&line);
+                    message.push('\n');
+                }
+                continue;
+            }
+
+            let reply: &[u8] = match line.split(' ').next().unwrap().to_uppercase().as_str() {
+                "EHLO" | "HELO" => b"250 localhost\r\n",
+                "DATA" => {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                }
+                "QUIT" => {
+                    writer.write_all(b"221 bye\r\n").await.unwrap();
+                    break;
+                }
+                _ => b"250 ok\r\n",
+            };
+            writer.write_all(reply).await.unwrap();
+        }
+
+        message
+    }
+
+    #[tokio::test]
+    async fn verification_email_is_sent_over_smtp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_smtp_server(listener));
+
+        let config = EmailConfig {
+            smtp_host: "127.0.0.1".to_owned(),
+            smtp_port: Some(port),
+            tls: SmtpTls::None,
+            smtp_username: None,
+            smtp_password: None,
+            from: "Conduit <noreply@example.org>".to_owned(),
+            verification_subject: "Validate".to_owned(),
+            verification_text: "Open {link}".to_owned(),
+            verification_html: "<a href=\"{link}\">Open</a>".to_owned(),
+        };
+
+        send_email(
+            &config,
+            "alice@example.com",
+            "Validate",
+            "Open https://example.org/validate",
+            "<a href=\"https://example.org/validate\">Open</a>",
+        )
+        .await
+        .unwrap();
+
+        let message = server.await.unwrap();
+        assert!(message.contains("To: alice@example.com"));
+        assert!(message.contains("Subject: Validate"));
+        assert!(message.contains("Open https://example.org/validate"));
+    }
+}
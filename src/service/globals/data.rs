@@ -1,8 +1,8 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, path::Path};
 
 use async_trait::async_trait;
 use ruma::{
-    api::federation::discovery::{ServerSigningKeys, VerifyKey},
+    api::federation::discovery::{OldVerifyKey, ServerSigningKeys, VerifyKey},
     signatures::Ed25519KeyPair,
     DeviceId, OwnedServerSigningKeyId, ServerName, UserId,
 };
@@ -15,9 +15,21 @@ pub trait Data: Send + Sync {
     fn current_count(&self) -> Result<u64>;
     async fn watch(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()>;
     fn cleanup(&self) -> Result<()>;
+    /// Syncs writes to disk that were not synced yet.
+    fn flush(&self) -> Result<()>;
     fn memory_usage(&self) -> Result<String>;
+    fn backup(&self, path: &Path, progress: &dyn Fn(u64, u64)) -> Result<()>;
     fn load_keypair(&self) -> Result<Ed25519KeyPair>;
     fn remove_keypair(&self) -> Result<()>;
+    /// Stores a newly generated keypair and keeps the public key of the previous one as an old
+    /// key, so events signed with it can still be verified.
+    fn rotate_keypair(
+        &self,
+        old_key_id: OwnedServerSigningKeyId,
+        old_key: OldVerifyKey,
+    ) -> Result<Ed25519KeyPair>;
+    /// The public keys of keypairs this server used before, with when they were replaced.
+    fn old_verify_keys(&self) -> Result<BTreeMap<OwnedServerSigningKeyId, OldVerifyKey>>;
     fn add_signing_key(
         &self,
         origin: &ServerName,
@@ -1,33 +1,56 @@
 mod data;
+mod rate_limiter;
+mod sync_limiter;
+mod transaction_cache;
 pub use data::Data;
+pub use rate_limiter::{RateLimiter, RoomCreationLimiter, WindowLimiter};
 use ruma::{
     OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedServerName, OwnedServerSigningKeyId, OwnedUserId,
 };
+pub use sync_limiter::{SyncLimiter, SyncPermit};
+pub use transaction_cache::{TransactionCache, TransactionResult};
 
 use crate::api::server_server::FedDest;
 
-use crate::{Config, Error, Result};
+use crate::{
+    config::{
+        CaptchaConfig, DatabaseDurability, EmailConfig, EncryptionDefault, ForwardingHeader,
+        TurnConfig,
+    },
+    service::pdu::PduLimits,
+    utils::{self, ip_range::IpRange},
+    Config, Error, Result,
+};
+use lru_cache::LruCache;
+use regex::RegexSet;
 use ruma::{
     api::{
         client::sync::sync_events,
-        federation::discovery::{ServerSigningKeys, VerifyKey},
+        federation::discovery::{OldVerifyKey, ServerSigningKeys, VerifyKey},
     },
-    DeviceId, RoomVersionId, ServerName, UserId,
+    serde::Base64,
+    signatures::Ed25519KeyPair,
+    DeviceId, MilliSecondsSinceUnixEpoch, OwnedMxcUri, OwnedUserId, RoomVersionId, ServerName,
+    UserId,
 };
+use serde::Serialize;
 use std::{
     collections::{BTreeMap, HashMap},
     fs,
     future::Future,
     net::{IpAddr, SocketAddr},
-    path::PathBuf,
-    sync::{Arc, Mutex, RwLock},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
     time::{Duration, Instant},
 };
 use tokio::sync::{broadcast, watch::Receiver, Mutex as TokioMutex, Semaphore};
-use tracing::error;
+use tracing::{error, info};
 use trust_dns_resolver::TokioAsyncResolver;
 
-type WellKnownMap = HashMap<OwnedServerName, (FedDest, String)>;
+type WellKnownMap = HashMap<OwnedServerName, (FedDest, String, Instant)>; // valid until
 type TlsNameMap = HashMap<String, (Vec<IpAddr>, u16)>;
 type RateLimitState = (Instant, u32); // Time if last failed try, number of failed tries
 type SyncHandle = (
@@ -35,29 +58,73 @@ type SyncHandle = (
     Receiver<Option<Result<sync_events::v3::Response>>>, // rx
 );
 
+/// Remote servers don't cache keys longer than a week anyway, this only keeps the validity
+/// timestamps representable.
+const MAX_KEY_VALIDITY_PERIOD_SECS: u64 = 365 * 24 * 60 * 60;
+
 pub struct Service {
     pub db: &'static dyn Data,
 
-    pub actual_destination_cache: Arc<RwLock<WellKnownMap>>, // actual_destination, host
+    pub actual_destination_cache: Arc<RwLock<WellKnownMap>>, // actual_destination, host, valid until
     pub tls_name_override: Arc<RwLock<TlsNameMap>>,
     pub config: Config,
-    keypair: Arc<ruma::signatures::Ed25519KeyPair>,
+    keypair: RwLock<Arc<ruma::signatures::Ed25519KeyPair>>,
     dns_resolver: TokioAsyncResolver,
     jwt_decoding_key: Option<jsonwebtoken::DecodingKey>,
     federation_client: reqwest::Client,
     default_client: reqwest::Client,
+    url_preview_client: reqwest::Client,
     pub stable_room_versions: Vec<RoomVersionId>,
     pub unstable_room_versions: Vec<RoomVersionId>,
     pub bad_event_ratelimiter: Arc<RwLock<HashMap<OwnedEventId, RateLimitState>>>,
     pub bad_signature_ratelimiter: Arc<RwLock<HashMap<Vec<String>, RateLimitState>>>,
     pub servername_ratelimiter: Arc<RwLock<HashMap<OwnedServerName, Arc<Semaphore>>>>,
+    pub servername_transactions: RwLock<HashMap<OwnedServerName, Arc<Semaphore>>>, // in-flight incoming transactions
+    pub transaction_cache: Mutex<TransactionCache>,
+    pub message_rate_limiter: Mutex<RateLimiter>,
+    pub room_creation_limiter: Mutex<RoomCreationLimiter>,
+    pub sync_limiter: SyncLimiter,
     pub sync_receivers: RwLock<HashMap<(OwnedUserId, OwnedDeviceId), SyncHandle>>,
     pub roomid_mutex_insert: RwLock<HashMap<OwnedRoomId, Arc<Mutex<()>>>>,
     pub roomid_mutex_state: RwLock<HashMap<OwnedRoomId, Arc<TokioMutex<()>>>>,
     pub roomid_mutex_federation: RwLock<HashMap<OwnedRoomId, Arc<TokioMutex<()>>>>, // this lock will be held longer
     pub roomid_federationhandletime: RwLock<HashMap<OwnedRoomId, (OwnedEventId, Instant)>>,
+    pub userid_mutex_roomcreation: RwLock<HashMap<OwnedUserId, Arc<TokioMutex<()>>>>, // held from the room limits check until the new room is counted
     pub stateres_mutex: Arc<Mutex<()>>,
+    pub created_events: Mutex<LruCache<OwnedEventId, ()>>, // events we hashed and signed
     pub rotate: RotationHandler,
+    maintenance_mode: AtomicBool,
+    backup_status: RwLock<BackupStatus>,
+    server_handle: RwLock<Option<axum_server::Handle>>,
+    ip_blacklist: Vec<IpRange>,
+    ip_whitelist: Vec<IpRange>,
+    url_preview_ip_blacklist: Vec<IpRange>,
+    trusted_proxies: Vec<IpRange>,
+    forbidden_usernames: RegexSet,
+}
+
+/// What the database backup is doing or did last, for `GET /_conduit/admin/backup`.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum BackupStatus {
+    Idle,
+    /// `copied` and `total` count engine specific units, e.g. pages for sqlite
+    Running {
+        path: PathBuf,
+        started_at: MilliSecondsSinceUnixEpoch,
+        copied: u64,
+        total: u64,
+    },
+    Finished {
+        path: PathBuf,
+        size: u64,
+        finished_at: MilliSecondsSinceUnixEpoch,
+    },
+    Failed {
+        path: PathBuf,
+        error: String,
+        finished_at: MilliSecondsSinceUnixEpoch,
+    },
 }
 
 /// Handles "rotation" of long-polling requests. "Rotation" in this context is similar to "rotation" of log files and the like.
@@ -111,8 +178,12 @@ impl Service {
             .map(|secret| jsonwebtoken::DecodingKey::from_secret(secret.as_bytes()));
 
         let default_client = reqwest_client_builder(&config)?.build()?;
+        let url_preview_client = reqwest_client_builder(&config)?
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
         let name_override = Arc::clone(&tls_name_override);
         let federation_client = reqwest_client_builder(&config)?
+            .timeout(Duration::from_millis(config.federation_timeout_ms))
             .resolve_fn(move |domain| {
                 let read_guard = name_override.read().unwrap();
                 let (override_name, port) = read_guard.get(&domain)?;
@@ -132,10 +203,118 @@ impl Service {
         // Experimental, partially supported room versions
         let unstable_room_versions = vec![RoomVersionId::V3, RoomVersionId::V4, RoomVersionId::V5];
 
+        if let Some(schedule) = &config.backup_schedule {
+            if config.backup_path.is_none() {
+                return Err(Error::bad_config(
+                    "backup_schedule is set, but backup_path is not.",
+                ));
+            }
+            if utils::parse_schedule(schedule).is_none() {
+                return Err(Error::bad_config("Invalid backup_schedule."));
+            }
+        }
+
+        if UserId::parse_with_server_name(
+            config.server_user_localpart.as_str(),
+            &config.server_name,
+        )
+        .is_err()
+        {
+            return Err(Error::bad_config("Invalid server_user_localpart."));
+        }
+
+        if config.http2_max_concurrent_streams == Some(0) {
+            return Err(Error::bad_config(
+                "http2_max_concurrent_streams must be greater than 0.",
+            ));
+        }
+
+        if config.tcp_keepalive_secs == Some(0) || config.http_idle_timeout_secs == Some(0) {
+            return Err(Error::bad_config(
+                "tcp_keepalive_secs and http_idle_timeout_secs must be greater than 0.",
+            ));
+        }
+
+        if config.presence_idle_timeout_secs == 0
+            || config.presence_offline_timeout_secs < config.presence_idle_timeout_secs
+        {
+            return Err(Error::bad_config(
+                "presence_idle_timeout_secs must be greater than 0 and at most presence_offline_timeout_secs.",
+            ));
+        }
+
+        if config.sync_timeline_limit_default == 0
+            || config.sync_timeline_limit_max < config.sync_timeline_limit_default
+        {
+            return Err(Error::bad_config(
+                "sync_timeline_limit_default must be greater than 0 and at most sync_timeline_limit_max.",
+            ));
+        }
+
+        if config.database_flush_interval_secs == 0 {
+            return Err(Error::bad_config(
+                "database_flush_interval_secs must be greater than 0.",
+            ));
+        }
+
+        if config.key_validity_period_secs == 0
+            || config.key_validity_period_secs > MAX_KEY_VALIDITY_PERIOD_SECS
+        {
+            return Err(Error::bad_config(
+                "key_validity_period_secs must be greater than 0 and at most a year.",
+            ));
+        }
+
+        if config.server_name_aliases.contains(&config.server_name) {
+            return Err(Error::bad_config(
+                "server_name_aliases must not contain server_name.",
+            ));
+        }
+
+        if config
+            .default_avatar_url
+            .as_ref()
+            .map_or(false, |url| !url.is_valid())
+        {
+            return Err(Error::bad_config("Invalid default_avatar_url."));
+        }
+
+        config.check_default_power_levels()?;
+
+        let maintenance_mode = AtomicBool::new(config.maintenance_mode);
+
+        let sync_limiter = SyncLimiter::new(config.max_concurrent_syncs);
+        let message_rate_limiter = Mutex::new(RateLimiter::new(
+            10_000,
+            config.message_rate_limit_per_second,
+            config.message_rate_limit_burst,
+        ));
+
+        let parse_ip_ranges = |ranges: &[String]| {
+            ranges
+                .iter()
+                .map(|range| {
+                    range.parse().map_err(|e| {
+                        error!("Invalid IP range {}: {}", range, e);
+                        Error::bad_config("Invalid IP range in config.")
+                    })
+                })
+                .collect::<Result<Vec<IpRange>>>()
+        };
+        let ip_blacklist = parse_ip_ranges(&config.federation_ip_blacklist)?;
+        let ip_whitelist = parse_ip_ranges(&config.federation_ip_whitelist)?;
+        let url_preview_ip_blacklist = parse_ip_ranges(&config.url_preview_ip_blacklist)?;
+        let trusted_proxies = parse_ip_ranges(&config.trusted_proxies)?;
+
+        let forbidden_usernames = RegexSet::new(&config.forbidden_usernames).map_err(|e| {
+            error!("Invalid forbidden_usernames: {}", e);
+            Error::bad_config("Invalid regular expression in forbidden_usernames.")
+        })?;
+
         let mut s = Self {
             db,
             config,
-            keypair: Arc::new(keypair),
+            keypair: RwLock::new(Arc::new(keypair)),
             dns_resolver: TokioAsyncResolver::tokio_from_system_conf().map_err(|e| {
                 error!(
                     "Failed to set up trust dns resolver with system config: {}",
@@ -147,19 +326,42 @@ impl Service {
             tls_name_override,
             federation_client,
             default_client,
+            url_preview_client,
             jwt_decoding_key,
             stable_room_versions,
             unstable_room_versions,
             bad_event_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
             bad_signature_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
             servername_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
+            servername_transactions: RwLock::new(HashMap::new()),
+            transaction_cache: Mutex::new(TransactionCache::new(
+                1000,
+                Duration::from_secs(60 * 60),
+            )),
+            message_rate_limiter,
+            room_creation_limiter: Mutex::new(RoomCreationLimiter::new(
+                10_000,
+                config.max_rooms_created_per_hour,
+                Duration::from_secs(60 * 60),
+            )),
+            sync_limiter,
             roomid_mutex_state: RwLock::new(HashMap::new()),
             roomid_mutex_insert: RwLock::new(HashMap::new()),
             roomid_mutex_federation: RwLock::new(HashMap::new()),
+            userid_mutex_roomcreation: RwLock::new(HashMap::new()),
             roomid_federationhandletime: RwLock::new(HashMap::new()),
             stateres_mutex: Arc::new(Mutex::new(())),
+            created_events: Mutex::new(LruCache::new(1000)),
             sync_receivers: RwLock::new(HashMap::new()),
             rotate: RotationHandler::new(),
+            maintenance_mode,
+            backup_status: RwLock::new(BackupStatus::Idle),
+            ip_blacklist,
+            ip_whitelist,
+            url_preview_ip_blacklist,
+            trusted_proxies,
+            forbidden_usernames,
+            server_handle: RwLock::new(None),
         };
 
         fs::create_dir_all(s.get_media_folder())?;
@@ -175,9 +377,42 @@ impl Service {
         Ok(s)
     }
 
-    /// Returns this server's keypair.
-    pub fn keypair(&self) -> &ruma::signatures::Ed25519KeyPair {
-        &self.keypair
+    /// Returns this server's current keypair.
+    pub fn keypair(&self) -> Arc<ruma::signatures::Ed25519KeyPair> {
+        Arc::clone(&self.keypair.read().unwrap())
+    }
+
+    /// Replaces the keypair by a new one. The public key of the old keypair stays published
+    /// until it expires, so events signed with it can still be verified.
+    ///
+    /// Returns the id of the new key.
+    pub fn rotate_keypair(&self) -> Result<OwnedServerSigningKeyId> {
+        let mut keypair = self.keypair.write().unwrap();
+
+        let old_key = OldVerifyKey::new(
+            MilliSecondsSinceUnixEpoch::now(),
+            Base64::new(keypair.public_key().to_vec()),
+        );
+        let new_keypair = self.db.rotate_keypair(key_id(&keypair), old_key)?;
+        let new_key_id = key_id(&new_keypair);
+        *keypair = Arc::new(new_keypair);
+
+        Ok(new_key_id)
+    }
+
+    /// The public keys of the keypairs this server used before.
+    pub fn old_verify_keys(&self) -> Result<BTreeMap<OwnedServerSigningKeyId, OldVerifyKey>> {
+        self.db.old_verify_keys()
+    }
+
+    /// How long other servers may cache our signing keys.
+    pub fn key_validity_period(&self) -> Duration {
+        Duration::from_secs(self.config.key_validity_period_secs)
+    }
+
+    /// How long keys replaced by a rotation stay published, None keeps them forever.
+    pub fn old_key_retention(&self) -> Option<Duration> {
+        self.config.old_key_retention_secs.map(Duration::from_secs)
     }
 
     /// Returns a reqwest client which can be used to send requests
@@ -186,6 +421,12 @@ impl Service {
         self.default_client.clone()
     }
 
+    /// Returns a client which doesn't follow redirects, used to fetch URL previews and .well-known
+    /// files, which check every hop themselves
+    pub fn url_preview_client(&self) -> reqwest::Client {
+        self.url_preview_client.clone()
+    }
+
     /// Returns a client used for resolving .well-knowns
     pub fn federation_client(&self) -> reqwest::Client {
         // Client is cheap to clone (Arc wrapper) and avoids lifetime issues
@@ -210,50 +451,421 @@ impl Service {
         self.db.cleanup()
     }
 
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()
+    }
+
     pub fn memory_usage(&self) -> Result<String> {
         self.db.memory_usage()
     }
 
+    /// Writes a snapshot of the database into a new directory below `backup_path`. Returns the
+    /// path and size in bytes of the snapshot.
+    pub fn backup(&self) -> Result<(PathBuf, u64)> {
+        let backup_path = self
+            .config
+            .backup_path
+            .as_ref()
+            .ok_or(Error::BadConfig("backup_path is not set."))?;
+        fs::create_dir_all(backup_path)?;
+
+        let path =
+            Path::new(backup_path).join(format!("conduit-{}", utils::millis_since_unix_epoch()));
+
+        {
+            let mut status = self.backup_status.write().unwrap();
+            if matches!(*status, BackupStatus::Running { .. }) {
+                return Err(Error::Conflict("A backup is already running."));
+            }
+            *status = BackupStatus::Running {
+                path: path.clone(),
+                started_at: MilliSecondsSinceUnixEpoch::now(),
+                copied: 0,
+                total: 0,
+            };
+        }
+
+        info!("Writing database backup to {}", path.display());
+        let start = Instant::now();
+        let result = self
+            .db
+            .backup(&path, &|copied, total| {
+                if let BackupStatus::Running {
+                    copied: c,
+                    total: t,
+                    ..
+                } = &mut *self.backup_status.write().unwrap()
+                {
+                    *c = copied;
+                    *t = total;
+                }
+            })
+            .and_then(|()| utils::dir_size(&path));
+
+        let finished_at = MilliSecondsSinceUnixEpoch::now();
+        *self.backup_status.write().unwrap() = match &result {
+            Ok(size) => {
+                info!(
+                    "Database backup of {} bytes finished in {:?}",
+                    size,
+                    start.elapsed()
+                );
+                BackupStatus::Finished {
+                    path: path.clone(),
+                    size: *size,
+                    finished_at,
+                }
+            }
+            Err(e) => {
+                error!("Database backup to {} failed: {}", path.display(), e);
+                BackupStatus::Failed {
+                    path: path.clone(),
+                    error: e.to_string(),
+                    finished_at,
+                }
+            }
+        };
+
+        Ok((path, result?))
+    }
+
+    pub fn backup_status(&self) -> BackupStatus {
+        self.backup_status.read().unwrap().clone()
+    }
+
+    pub fn database_durability(&self) -> DatabaseDurability {
+        self.config.database_durability
+    }
+
+    pub fn database_flush_interval(&self) -> Duration {
+        Duration::from_secs(self.config.database_flush_interval_secs)
+    }
+
+    pub fn backup_interval(&self) -> Option<Duration> {
+        self.config
+            .backup_schedule
+            .as_deref()
+            .and_then(utils::parse_schedule)
+    }
+
     pub fn server_name(&self) -> &ServerName {
         self.config.server_name.as_ref()
     }
 
+    /// Other names this server answers to, see `server_name_aliases` in the config.
+    pub fn server_name_aliases(&self) -> &[OwnedServerName] {
+        &self.config.server_name_aliases
+    }
+
+    /// Whether the server name is our server_name or one of its aliases.
+    pub fn server_is_ours(&self, server_name: &ServerName) -> bool {
+        is_our_server(server_name, self.server_name(), self.server_name_aliases())
+    }
+
+    /// The user that sends the messages of the server, e.g. in the admin room.
+    pub fn server_user(&self) -> OwnedUserId {
+        UserId::parse_with_server_name(
+            self.config.server_user_localpart.as_str(),
+            self.server_name(),
+        )
+        .expect("server_user_localpart was checked on startup")
+    }
+
+    pub fn admin_room_enabled(&self) -> bool {
+        self.config.admin_room_enabled
+    }
+
+    pub fn forget_abandoned_rooms_after_secs(&self) -> Option<u64> {
+        self.config.forget_abandoned_rooms_after_secs
+    }
+
+    pub fn identity_server_trusted(&self, id_server: &str) -> bool {
+        self.config
+            .identity_server
+            .trusted_servers
+            .iter()
+            .any(|trusted| trusted.eq_ignore_ascii_case(id_server))
+    }
+
     pub fn max_request_size(&self) -> u32 {
         self.config.max_request_size
     }
 
+    pub fn max_client_request_size(&self) -> u32 {
+        self.config.max_client_request_size
+    }
+
+    pub fn max_media_upload_size(&self) -> u32 {
+        self.config.max_media_upload_size()
+    }
+
+    pub fn max_federation_request_size(&self) -> u32 {
+        self.config.max_federation_request_size()
+    }
+
+    pub fn federation_timeout(&self) -> Duration {
+        Duration::from_millis(self.config.federation_timeout_ms)
+    }
+
+    /// How long a request may take before it's cut off, see `request_timeout_excluded_paths`.
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.config.request_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn pdu_limits(&self) -> PduLimits {
+        PduLimits {
+            max_size: self.config.max_pdu_size,
+            max_prev_events: self.config.max_pdu_prev_events,
+            max_auth_events: self.config.max_pdu_auth_events,
+        }
+    }
+
+    pub fn max_concurrent_transactions_per_origin(&self) -> u16 {
+        self.config.max_concurrent_transactions_per_origin
+    }
+
+    /// How many rooms of one sync request are loaded at the same time, at least one.
+    pub fn sync_room_concurrency(&self) -> usize {
+        self.config.sync_room_concurrency.max(1)
+    }
+
+    pub fn sync_timeline_limit_default(&self) -> usize {
+        self.config.sync_timeline_limit_default
+    }
+
+    pub fn sync_timeline_limit_max(&self) -> usize {
+        self.config.sync_timeline_limit_max
+    }
+
+    pub fn sync_response_max_bytes(&self) -> Option<usize> {
+        self.config.sync_response_max_bytes
+    }
+
     pub fn max_fetch_prev_events(&self) -> u16 {
         self.config.max_fetch_prev_events
     }
 
+    pub fn backfill_max_depth(&self) -> u64 {
+        self.config.backfill_max_depth
+    }
+
     pub fn allow_registration(&self) -> bool {
         self.config.allow_registration
     }
 
+    pub fn registration_requires_email(&self) -> bool {
+        self.config.registration_requires_email
+    }
+
+    pub fn registration_requires_msisdn(&self) -> bool {
+        self.config.registration_requires_msisdn
+    }
+
+    pub fn forbidden_usernames(&self) -> &RegexSet {
+        &self.forbidden_usernames
+    }
+
     pub fn allow_encryption(&self) -> bool {
         self.config.allow_encryption
     }
 
+    pub fn encryption_enabled_by_default_for_room_type(&self) -> EncryptionDefault {
+        self.config.encryption_enabled_by_default_for_room_type
+    }
+
+    pub fn forbid_disabling_encryption(&self) -> bool {
+        self.config.forbid_disabling_encryption
+    }
+
     pub fn allow_federation(&self) -> bool {
         self.config.allow_federation
     }
 
+    pub fn trust_own_signatures(&self) -> bool {
+        self.config.trust_own_signatures
+    }
+
+    pub fn allow_presence(&self) -> bool {
+        self.config.allow_presence
+    }
+
+    pub fn presence_idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.presence_idle_timeout_secs)
+    }
+
+    pub fn presence_offline_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.presence_offline_timeout_secs)
+    }
+
     pub fn allow_room_creation(&self) -> bool {
         self.config.allow_room_creation
     }
 
+    pub fn allow_password_change(&self) -> bool {
+        self.config.allow_password_change
+    }
+
+    pub fn allow_displayname_change(&self) -> bool {
+        self.config.allow_displayname_change
+    }
+
+    pub fn allow_avatar_change(&self) -> bool {
+        self.config.allow_avatar_change
+    }
+
+    pub fn allow_3pid_changes(&self) -> bool {
+        self.config.allow_3pid_changes
+    }
+
     pub fn allow_unstable_room_versions(&self) -> bool {
         self.config.allow_unstable_room_versions
     }
 
+    pub fn room_list_publication_default(&self) -> bool {
+        self.config.room_list_publication_default
+    }
+
+    pub fn room_list_publication_requires_admin(&self) -> bool {
+        self.config.room_list_publication_requires_admin
+    }
+
+    pub fn allow_public_room_directory_without_auth(&self) -> bool {
+        self.config.allow_public_room_directory_without_auth
+    }
+
+    pub fn allow_public_room_directory_over_federation(&self) -> bool {
+        self.config.allow_public_room_directory_over_federation
+    }
+
+    pub fn federation_allow_joins(&self) -> bool {
+        self.config.federation_allow_joins
+    }
+
+    pub fn federation_allow_invites(&self) -> bool {
+        self.config.federation_allow_invites
+    }
+
+    pub fn partial_state_joins(&self) -> bool {
+        self.config.partial_state_joins
+    }
+
+    pub fn per_user_media_quota_bytes(&self) -> Option<u64> {
+        self.config.per_user_media_quota_bytes
+    }
+
+    pub fn access_token_lifetime(&self) -> Option<u64> {
+        self.config.access_token_lifetime
+    }
+
+    pub fn session_idle_expiry(&self) -> Option<u64> {
+        self.config.session_idle_expiry
+    }
+
+    pub fn max_rooms_per_user_create(&self) -> Option<u64> {
+        self.config.max_rooms_per_user_create
+    }
+
+    pub fn max_rooms_per_user_join(&self) -> Option<u64> {
+        self.config.max_rooms_per_user_join
+    }
+
+    pub fn max_outstanding_invites_per_user(&self) -> Option<u64> {
+        self.config.max_outstanding_invites_per_user
+    }
+
+    pub fn max_invites_per_room(&self) -> Option<u64> {
+        self.config.max_invites_per_room
+    }
+
+    pub fn url_preview_enabled(&self) -> bool {
+        self.config.url_preview_enabled
+    }
+
+    pub fn url_preview_url_blacklist(&self) -> &[String] {
+        &self.config.url_preview_url_blacklist
+    }
+
+    pub fn url_preview_max_spider_size(&self) -> usize {
+        self.config.url_preview_max_spider_size
+    }
+
+    /// Whether the URL preview spider may connect to this address. Previews are subject to both
+    /// the federation IP ranges and url_preview_ip_blacklist.
+    pub fn url_preview_ip_allowed(&self, ip: IpAddr) -> bool {
+        self.ip_allowed(ip)
+            && !self
+                .url_preview_ip_blacklist
+                .iter()
+                .any(|range| range.contains(ip))
+    }
+
+    pub fn user_directory_search_all_users(&self) -> bool {
+        self.config.user_directory_search_all_users
+    }
+
     pub fn default_room_version(&self) -> RoomVersionId {
         self.config.default_room_version.clone()
     }
 
+    /// In maintenance mode, all requests that would write to the database are rejected.
+    pub fn maintenance_mode(&self) -> bool {
+        self.maintenance_mode.load(Ordering::Relaxed)
+    }
+
+    pub fn set_maintenance_mode(&self, enabled: bool) {
+        self.maintenance_mode.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Makes the handle of the running HTTP server available to the rest of Conduit.
+    pub fn set_server_handle(&self, handle: axum_server::Handle) {
+        *self.server_handle.write().unwrap() = Some(handle);
+    }
+
+    /// Number of open client and federation connections, if the HTTP server is running.
+    pub fn connection_count(&self) -> Option<usize> {
+        self.server_handle
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|handle| handle.connection_count())
+    }
+
     pub fn enable_lightning_bolt(&self) -> bool {
         self.config.enable_lightning_bolt
     }
 
+    pub fn default_displayname_template(&self) -> &str {
+        self.config
+            .default_displayname_template
+            .as_deref()
+            .unwrap_or("{localpart}")
+    }
+
+    pub fn default_avatar_url(&self) -> Option<&OwnedMxcUri> {
+        self.config.default_avatar_url.as_ref()
+    }
+
+    /// The header the `trusted_proxies` put the address of the client into.
+    pub fn trusted_proxy_header(&self) -> ForwardingHeader {
+        self.config.trusted_proxy_header
+    }
+
+    /// The IP of the client that sent a request over a connection from `peer`, given the value of
+    /// the `trusted_proxy_header`. It is only used if `peer` is one of the `trusted_proxies`.
+    pub fn client_ip(&self, peer: IpAddr, forwarded: Option<&str>) -> IpAddr {
+        utils::ip_range::client_ip(
+            peer,
+            self.trusted_proxy_header(),
+            forwarded,
+            &self.trusted_proxies,
+        )
+    }
+
+    /// Whether outgoing requests may connect to this address.
+    pub fn ip_allowed(&self, ip: IpAddr) -> bool {
+        utils::ip_range::ip_allowed(ip, &self.ip_blacklist, &self.ip_whitelist)
+    }
+
     pub fn trusted_servers(&self) -> &[OwnedServerName] {
         &self.config.trusted_servers
     }
@@ -266,24 +878,49 @@ impl Service {
         self.jwt_decoding_key.as_ref()
     }
 
-    pub fn turn_password(&self) -> &String {
-        &self.config.turn_password
+    pub fn email(&self) -> Option<&EmailConfig> {
+        self.config.email.as_ref()
+    }
+
+    /// Where clients reach us, for links we send to users.
+    pub fn client_base_url(&self) -> String {
+        self.config.well_known_client.as_deref().map_or_else(
+            || format!("https://{}", self.server_name()),
+            |client| client.trim_end_matches('/').to_owned(),
+        )
+    }
+
+    /// The identity server that texts validation tokens and our access token there.
+    pub fn msisdn_delegate(&self) -> Option<(&str, &str)> {
+        let identity_server = &self.config.identity_server;
+        identity_server
+            .msisdn_delegate
+            .as_deref()
+            .zip(identity_server.msisdn_delegate_access_token.as_deref())
     }
 
-    pub fn turn_ttl(&self) -> u64 {
-        self.config.turn_ttl
+    pub fn max_event_delay(&self) -> Option<Duration> {
+        self.config.max_event_delay_secs.map(Duration::from_secs)
     }
 
-    pub fn turn_uris(&self) -> &[String] {
-        &self.config.turn_uris
+    pub fn max_delayed_events_per_user(&self) -> usize {
+        self.config.max_delayed_events_per_user
     }
 
-    pub fn turn_username(&self) -> &String {
-        &self.config.turn_username
+    pub fn captcha(&self) -> Option<&CaptchaConfig> {
+        self.config.captcha.as_ref()
     }
 
-    pub fn turn_secret(&self) -> &String {
-        &self.config.turn_secret
+    pub fn turn(&self) -> TurnConfig {
+        self.config.turn()
+    }
+
+    pub fn argon2_memory(&self) -> u32 {
+        self.config.argon2_memory
+    }
+
+    pub fn argon2_iterations(&self) -> u32 {
+        self.config.argon2_iterations
     }
 
     pub fn emergency_password(&self) -> &Option<String> {
@@ -354,3 +991,39 @@ fn reqwest_client_builder(config: &Config) -> Result<reqwest::ClientBuilder> {
 
     Ok(reqwest_client_builder)
 }
+
+/// The id under which the keypair is published, e.g. `ed25519:abcdefgh`.
+pub fn key_id(keypair: &Ed25519KeyPair) -> OwnedServerSigningKeyId {
+    format!("ed25519:{}", keypair.version())
+        .try_into()
+        .expect("found invalid server signing keys in DB")
+}
+
+fn is_our_server(
+    server_name: &ServerName,
+    canonical: &ServerName,
+    aliases: &[OwnedServerName],
+) -> bool {
+    server_name == canonical || aliases.iter().any(|alias| &**alias == server_name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn aliases_are_local() {
+        let canonical = <&ServerName>::try_from("example.com").unwrap();
+        let aliases = vec![ServerName::parse("old.example.com").unwrap()];
+
+        let user = UserId::parse("@alice:old.example.com").unwrap();
+        assert!(is_our_server(user.server_name(), canonical, &aliases));
+        assert!(is_our_server(canonical, canonical, &aliases));
+        assert!(!is_our_server(
+            <&ServerName>::try_from("example.org").unwrap(),
+            canonical,
+            &aliases
+        ));
+        assert!(!is_our_server(user.server_name(), canonical, &[]));
+    }
+}
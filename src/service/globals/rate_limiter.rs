@@ -0,0 +1,291 @@
+use std::{
+    borrow::Borrow,
+    collections::VecDeque,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+use lru_cache::LruCache;
+use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+
+/// Upper bound of the retry delay, so tiny rates can't overflow the `Duration`.
+const MAX_RETRY_AFTER_SECS: f64 = 60.0 * 60.0 * 24.0;
+
+/// Token buckets limiting how fast each user can send messages into each room. Every message takes
+/// a token, tokens refill at a sustained rate up to the burst size.
+pub struct RateLimiter {
+    /// Tokens left and when they were counted. Evicted buckets start over full.
+    buckets: LruCache<(OwnedUserId, OwnedRoomId), (f64, Instant)>,
+    per_second: f64,
+    burst: f64,
+}
+
+impl RateLimiter {
+    /// A rate of 0 disables the limit.
+    pub fn new(capacity: usize, per_second: f64, burst: u32) -> Self {
+        Self {
+            buckets: LruCache::new(capacity),
+            per_second,
+            burst: f64::from(burst.max(1)),
+        }
+    }
+
+    /// Takes a token for the user in the room. Returns how long to wait for the next token if
+    /// there is none left.
+    pub fn check(&mut self, user_id: &UserId, room_id: &RoomId) -> Result<(), Duration> {
+        self.check_at(user_id, room_id, Instant::now())
+    }
+
+    fn check_at(
+        &mut self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        if self.per_second <= 0.0 {
+            return Ok(());
+        }
+
+        let key = (user_id.to_owned(), room_id.to_owned());
+        let (tokens, counted_at) = self
+            .buckets
+            .get_mut(&key)
+            .map_or((self.burst, now), |bucket| *bucket);
+
+        let elapsed = now.saturating_duration_since(counted_at).as_secs_f64();
+        let tokens = (tokens + elapsed * self.per_second).min(self.burst);
+
+        if tokens < 1.0 {
+            self.buckets.insert(key, (tokens, now));
+            return Err(Duration::from_secs_f64(
+                ((1.0 - tokens) / self.per_second).min(MAX_RETRY_AFTER_SECS),
+            ));
+        }
+
+        self.buckets.insert(key, (tokens - 1.0, now));
+        Ok(())
+    }
+}
+
+/// Limits how many times each key may do something within a rolling window, like how many rooms
+/// each user can create within an hour.
+pub struct WindowLimiter<K: Hash + Eq> {
+    /// When each key did it within the window, oldest first. Evicted keys start over.
+    done: LruCache<K, VecDeque<Instant>>,
+    limit: Option<u32>,
+    window: Duration,
+}
+
+pub type RoomCreationLimiter = WindowLimiter<OwnedUserId>;
+
+impl<K: Hash + Eq> WindowLimiter<K> {
+    /// No limit if `limit` is None.
+    pub fn new(capacity: usize, limit: Option<u32>, window: Duration) -> Self {
+        Self {
+            done: LruCache::new(capacity),
+            limit,
+            window,
+        }
+    }
+
+    /// Counts another time for the key. Returns how long until the key may do it again if it hit
+    /// the limit.
+    pub fn check<Q>(&mut self, key: &Q) -> Result<(), Duration>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        self.check_at(key, Instant::now())
+    }
+
+    /// Like `check`, but doesn't count it yet, for things that can still fail. Call `record` once
+    /// they succeeded.
+    pub fn allowed<Q>(&mut self, key: &Q) -> Result<(), Duration>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        self.allowed_at(key, Instant::now())
+    }
+
+    /// Counts another time for the key, even if it is over the limit.
+    pub fn record<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        self.record_at(key, Instant::now());
+    }
+
+    fn allowed_at<Q>(&mut self, key: &Q, now: Instant) -> Result<(), Duration>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        let limit = match self.limit {
+            Some(limit) => limit as usize,
+            None => return Ok(()),
+        };
+        let window = self.window;
+
+        let done = match self.done.get_mut(key) {
+            Some(done) => done,
+            None => return Ok(()),
+        };
+        while done.front().map_or(false, |oldest| {
+            now.saturating_duration_since(*oldest) >= window
+        }) {
+            done.pop_front();
+        }
+
+        if done.len() >= limit {
+            return Err(done.front().map_or(window, |oldest| {
+                window - now.saturating_duration_since(*oldest)
+            }));
+        }
+
+        Ok(())
+    }
+
+    fn record_at<Q>(&mut self, key: &Q, now: Instant)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        if self.limit.is_none() {
+            return;
+        }
+
+        match self.done.get_mut(key) {
+            Some(done) => done.push_back(now),
+            None => {
+                self.done.insert(key.to_owned(), VecDeque::from([now]));
+            }
+        }
+    }
+
+    fn check_at<Q>(&mut self, key: &Q, now: Instant) -> Result<(), Duration>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        self.allowed_at(key, now)?;
+        self.record_at(key, now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::{room_id, user_id};
+
+    use super::*;
+
+    #[test]
+    fn messages_are_throttled_after_the_burst() {
+        let mut limiter = RateLimiter::new(10, 2.0, 5);
+        let (alice, room) = (
+            user_id!("@alice:example.org"),
+            room_id!("!room:example.org"),
+        );
+        let start = Instant::now();
+
+        for _ in 0..5 {
+            assert_eq!(limiter.check_at(alice, room, start), Ok(()));
+        }
+        assert_eq!(
+            limiter.check_at(alice, room, start),
+            Err(Duration::from_millis(500))
+        );
+
+        // Other rooms and users have their own buckets
+        assert!(limiter
+            .check_at(alice, room_id!("!other:example.org"), start)
+            .is_ok());
+        assert!(limiter
+            .check_at(user_id!("@bob:example.org"), room, start)
+            .is_ok());
+
+        // Tokens refill at the sustained rate
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.check_at(alice, room, later), Ok(()));
+        assert!(limiter.check_at(alice, room, later).is_err());
+    }
+
+    #[test]
+    fn tiny_rates_have_a_bounded_retry_delay() {
+        let mut limiter = RateLimiter::new(10, f64::MIN_POSITIVE, 1);
+        let (alice, room) = (
+            user_id!("@alice:example.org"),
+            room_id!("!room:example.org"),
+        );
+        let start = Instant::now();
+
+        assert!(limiter.check_at(alice, room, start).is_ok());
+        assert_eq!(
+            limiter.check_at(alice, room, start),
+            Err(Duration::from_secs(60 * 60 * 24))
+        );
+    }
+
+    #[test]
+    fn zero_rate_disables_the_limit() {
+        let mut limiter = RateLimiter::new(10, 0.0, 1);
+        let (alice, room) = (
+            user_id!("@alice:example.org"),
+            room_id!("!room:example.org"),
+        );
+
+        for _ in 0..100 {
+            assert!(limiter.check(alice, room).is_ok());
+        }
+    }
+
+    #[test]
+    fn room_creation_is_limited_per_rolling_hour() {
+        let mut limiter = RoomCreationLimiter::new(10, Some(3), Duration::from_secs(60 * 60));
+        let alice = user_id!("@alice:example.org");
+        let start = Instant::now();
+        let minute = Duration::from_secs(60);
+
+        for i in 0..3 {
+            assert_eq!(limiter.check_at(alice, start + minute * i), Ok(()));
+        }
+        // The first room leaves the window after an hour
+        assert_eq!(
+            limiter.check_at(alice, start + minute * 10),
+            Err(minute * 50)
+        );
+        assert!(limiter
+            .check_at(user_id!("@bob:example.org"), start)
+            .is_ok());
+
+        assert_eq!(limiter.check_at(alice, start + minute * 60), Ok(()));
+        assert!(limiter.check_at(alice, start + minute * 60).is_err());
+        assert_eq!(limiter.check_at(alice, start + minute * 61), Ok(()));
+    }
+
+    #[test]
+    fn only_recorded_creations_count() {
+        let mut limiter = RoomCreationLimiter::new(10, Some(1), Duration::from_secs(60 * 60));
+        let alice = user_id!("@alice:example.org");
+        let start = Instant::now();
+
+        // Requests that fail after the check don't use up the limit
+        for _ in 0..3 {
+            assert_eq!(limiter.allowed_at(alice, start), Ok(()));
+        }
+        limiter.record_at(alice, start);
+        assert!(limiter.allowed_at(alice, start).is_err());
+    }
+
+    #[test]
+    fn room_creation_is_unlimited_without_a_limit() {
+        let mut limiter = RoomCreationLimiter::new(10, None, Duration::from_secs(60 * 60));
+        let alice = user_id!("@alice:example.org");
+
+        for _ in 0..1000 {
+            assert!(limiter.check(alice).is_ok());
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use ruma::{OwnedUserId, UserId};
+
+/// Counts the /sync requests each user has open, so a misbehaving client can't hold an unbounded
+/// number of long-polls.
+pub struct SyncLimiter {
+    active: Arc<Mutex<HashMap<OwnedUserId, usize>>>,
+    max_per_user: usize,
+}
+
+/// Counts as an open sync until it's dropped.
+pub struct SyncPermit {
+    active: Arc<Mutex<HashMap<OwnedUserId, usize>>>,
+    user_id: OwnedUserId,
+}
+
+impl SyncLimiter {
+    /// A maximum of 0 disables the limit.
+    pub fn new(max_per_user: usize) -> Self {
+        Self {
+            active: Arc::new(Mutex::new(HashMap::new())),
+            max_per_user,
+        }
+    }
+
+    /// Returns None if the user already has the maximum number of syncs open.
+    pub fn try_start(&self, user_id: &UserId) -> Option<SyncPermit> {
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(user_id.to_owned()).or_default();
+
+        if self.max_per_user != 0 && *count >= self.max_per_user {
+            return None;
+        }
+        *count += 1;
+
+        Some(SyncPermit {
+            active: Arc::clone(&self.active),
+            user_id: user_id.to_owned(),
+        })
+    }
+
+    /// The number of /sync requests that are open right now, of all users.
+    pub fn open_syncs(&self) -> usize {
+        self.active.lock().unwrap().values().sum()
+    }
+}
+
+impl Drop for SyncPermit {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.user_id) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.user_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::user_id;
+
+    use super::*;
+
+    #[test]
+    fn syncs_over_the_limit_are_rejected() {
+        let limiter = SyncLimiter::new(2);
+        let alice = user_id!("@alice:example.org");
+
+        let first = limiter.try_start(alice).unwrap();
+        let _second = limiter.try_start(alice).unwrap();
+        assert!(limiter.try_start(alice).is_none());
+
+        // Other users have their own limit
+        assert!(limiter.try_start(user_id!("@bob:example.org")).is_some());
+
+        // Finished syncs make room for new ones
+        drop(first);
+        assert!(limiter.try_start(alice).is_some());
+    }
+
+    #[test]
+    fn zero_disables_the_limit() {
+        let limiter = SyncLimiter::new(0);
+        let alice = user_id!("@alice:example.org");
+
+        let permits = (0..100)
+            .map(|_| limiter.try_start(alice))
+            .collect::<Vec<_>>();
+        assert!(permits.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn open_syncs_are_counted_until_they_finish() {
+        let limiter = SyncLimiter::new(0);
+
+        let first = limiter.try_start(user_id!("@alice:example.org")).unwrap();
+        let _second = limiter.try_start(user_id!("@alice:example.org")).unwrap();
+        let _third = limiter.try_start(user_id!("@bob:example.org")).unwrap();
+        assert_eq!(limiter.open_syncs(), 3);
+
+        drop(first);
+        assert_eq!(limiter.open_syncs(), 2);
+    }
+}
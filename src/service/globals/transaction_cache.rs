@@ -0,0 +1,99 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use lru_cache::LruCache;
+use ruma::{OwnedEventId, OwnedServerName, OwnedTransactionId, ServerName, TransactionId};
+
+/// The result of each PDU in an incoming transaction, as sent back to the origin.
+pub type TransactionResult = BTreeMap<OwnedEventId, Result<(), String>>;
+
+/// Remembers the results of recently handled incoming transactions, so a retried transaction gets
+/// the same answer without being handled again.
+pub struct TransactionCache {
+    cache: LruCache<(OwnedServerName, OwnedTransactionId), (Instant, TransactionResult)>,
+    ttl: Duration,
+}
+
+impl TransactionCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            cache: LruCache::new(capacity),
+            ttl,
+        }
+    }
+
+    pub fn get(
+        &mut self,
+        origin: &ServerName,
+        txn_id: &TransactionId,
+    ) -> Option<TransactionResult> {
+        let key = (origin.to_owned(), txn_id.to_owned());
+
+        let (handled_at, result) = self.cache.get_mut(&key)?;
+        if handled_at.elapsed() < self.ttl {
+            return Some(result.clone());
+        }
+
+        self.cache.remove(&key);
+        None
+    }
+
+    pub fn insert(
+        &mut self,
+        origin: &ServerName,
+        txn_id: &TransactionId,
+        result: TransactionResult,
+    ) {
+        self.cache.insert(
+            (origin.to_owned(), txn_id.to_owned()),
+            (Instant::now(), result),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::{event_id, server_name};
+
+    use super::*;
+
+    #[test]
+    fn repeated_transactions_are_cache_hits() {
+        let mut cache = TransactionCache::new(10, Duration::from_secs(60));
+        let origin = server_name!("example.org");
+        let txn_id = &TransactionId::new();
+
+        let result = BTreeMap::from([
+            (event_id!("$a:example.org").to_owned(), Ok(())),
+            (
+                event_id!("$b:example.org").to_owned(),
+                Err("Signature verification failed".to_owned()),
+            ),
+        ]);
+
+        assert_eq!(cache.get(origin, txn_id), None);
+        cache.insert(origin, txn_id, result.clone());
+        assert_eq!(cache.get(origin, txn_id), Some(result));
+
+        // Transaction ids are only unique per origin
+        assert_eq!(cache.get(server_name!("example.com"), txn_id), None);
+    }
+
+    #[test]
+    fn old_transactions_are_evicted() {
+        let origin = server_name!("example.org");
+        let (first, second) = (&TransactionId::new(), &TransactionId::new());
+
+        let mut cache = TransactionCache::new(1, Duration::from_secs(60));
+        cache.insert(origin, first, BTreeMap::new());
+        cache.insert(origin, second, BTreeMap::new());
+        assert_eq!(cache.get(origin, first), None);
+        assert!(cache.get(origin, second).is_some());
+
+        let mut cache = TransactionCache::new(10, Duration::ZERO);
+        cache.insert(origin, first, BTreeMap::new());
+        assert_eq!(cache.get(origin, first), None);
+    }
+}
@@ -1,3 +1,5 @@
+use ruma::UserId;
+
 use crate::Result;
 
 pub trait Data: Send + Sync {
@@ -17,4 +19,13 @@ pub trait Data: Send + Sync {
         width: u32,
         height: u32,
     ) -> Result<(Option<String>, Option<String>, Vec<u8>)>;
+
+    fn quarantine(&self, mxc: &str) -> Result<()>;
+
+    fn is_quarantined(&self, mxc: &str) -> Result<bool>;
+
+    /// Returns the number of bytes the user has uploaded.
+    fn media_usage(&self, user_id: &UserId) -> Result<u64>;
+
+    fn set_media_usage(&self, user_id: &UserId, bytes: u64) -> Result<()>;
 }
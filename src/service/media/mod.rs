@@ -1,16 +1,35 @@
 mod data;
-use std::io::Cursor;
+mod preview;
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
 
 pub use data::Data;
+pub use preview::UrlPreviewData;
 
-use crate::{services, Result};
+use crate::{services, Error, Result};
 use image::imageops::FilterType;
+use lru_cache::LruCache;
+use reqwest::{
+    header::{CONTENT_TYPE, LOCATION},
+    Url,
+};
+use ruma::{api::client::error::ErrorKind, OwnedUserId, UserId};
+use tracing::warn;
 
 use tokio::{
     fs::File,
     io::{AsyncReadExt, AsyncWriteExt},
+    sync::Mutex as TokioMutex,
 };
 
+const URL_PREVIEW_CACHE_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+const URL_PREVIEW_MAX_REDIRECTS: usize = 5;
+
 pub struct FileMeta {
     pub content_disposition: Option<String>,
     pub content_type: Option<String>,
@@ -19,6 +38,9 @@ pub struct FileMeta {
 
 pub struct Service {
     pub db: &'static dyn Data,
+    pub url_preview_cache: Mutex<LruCache<String, (Instant, UrlPreviewData)>>,
+    pub url_mutex_preview: RwLock<HashMap<String, Arc<TokioMutex<()>>>>,
+    pub userid_mutex_usage: RwLock<HashMap<OwnedUserId, Arc<Mutex<()>>>>,
 }
 
 impl Service {
@@ -41,6 +63,56 @@ impl Service {
         Ok(())
     }
 
+    /// Hides the media and its thumbnails from the download endpoints, for abuse handling.
+    pub fn quarantine(&self, mxc: &str) -> Result<()> {
+        self.db.quarantine(mxc)
+    }
+
+    pub fn is_quarantined(&self, mxc: &str) -> Result<bool> {
+        self.db.is_quarantined(mxc)
+    }
+
+    fn usage_mutex(&self, user_id: &UserId) -> Arc<Mutex<()>> {
+        Arc::clone(
+            self.userid_mutex_usage
+                .write()
+                .unwrap()
+                .entry(user_id.to_owned())
+                .or_default(),
+        )
+    }
+
+    /// Counts `size` bytes against the user's quota before an upload. Fails if that would take
+    /// the user over `per_user_media_quota_bytes`. Admins have no quota.
+    pub fn reserve_quota(&self, user_id: &UserId, size: u64) -> Result<()> {
+        let mutex_usage = self.usage_mutex(user_id);
+        let _usage_lock = mutex_usage.lock().unwrap();
+
+        let usage = self.db.media_usage(user_id)?;
+        let quota = services().globals.per_user_media_quota_bytes();
+
+        if quota.is_some()
+            && quota_exceeded(usage, size, quota)
+            && !services().users.is_admin(user_id)?
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Media upload quota exceeded.",
+            ));
+        }
+
+        self.db.set_media_usage(user_id, usage.saturating_add(size))
+    }
+
+    /// Gives back the quota reserved for an upload that failed.
+    pub fn release_quota(&self, user_id: &UserId, size: u64) -> Result<()> {
+        let mutex_usage = self.usage_mutex(user_id);
+        let _usage_lock = mutex_usage.lock().unwrap();
+
+        let usage = self.db.media_usage(user_id)?;
+        self.db.set_media_usage(user_id, usage.saturating_sub(size))
+    }
+
     /// Uploads or replaces a file thumbnail.
     #[allow(clippy::too_many_arguments)]
     pub async fn upload_thumbnail(
@@ -223,4 +295,386 @@ impl Service {
             Ok(None)
         }
     }
+
+    /// Returns a preview of the page at `url`. Previews are cached for a day, the image of the
+    /// page is stored as media so clients can download and thumbnail it.
+    pub async fn get_url_preview(&self, url: &Url) -> Result<UrlPreviewData> {
+        if let Some(preview) = self.cached_url_preview(url) {
+            return Ok(preview);
+        }
+
+        // Concurrent requests for the same URL wait for the first one instead of fetching again
+        let mutex_preview = Arc::clone(
+            self.url_mutex_preview
+                .write()
+                .unwrap()
+                .entry(url.to_string())
+                .or_default(),
+        );
+        let preview_lock = mutex_preview.lock().await;
+
+        let preview = match self.cached_url_preview(url) {
+            Some(preview) => Ok(preview),
+            None => self.request_url_preview(url).await.map(|preview| {
+                self.url_preview_cache
+                    .lock()
+                    .unwrap()
+                    .insert(url.to_string(), (Instant::now(), preview.clone()));
+                preview
+            }),
+        };
+
+        drop(preview_lock);
+        drop(mutex_preview);
+        self.url_mutex_preview
+            .write()
+            .unwrap()
+            .retain(|_, mutex| Arc::strong_count(mutex) > 1);
+
+        preview
+    }
+
+    fn cached_url_preview(&self, url: &Url) -> Option<UrlPreviewData> {
+        self.url_preview_cache
+            .lock()
+            .unwrap()
+            .get_mut(url.as_str())
+            .filter(|(fetched_at, _)| fetched_at.elapsed() < URL_PREVIEW_CACHE_TTL)
+            .map(|(_, preview)| preview.clone())
+    }
+
+    async fn request_url_preview(&self, url: &Url) -> Result<UrlPreviewData> {
+        let (response, url) = fetch_url(url.clone()).await?;
+
+        let content_type = media_type(&response);
+
+        if content_type.starts_with("image/") {
+            // Links to images are previewed as the image itself
+            let mut preview = UrlPreviewData::default();
+            self.store_preview_image(&mut preview, &url, Some(response))
+                .await?;
+            return Ok(preview);
+        }
+
+        if content_type != "text/html" && content_type != "application/xhtml+xml" {
+            return Err(Error::BadRequest(
+                ErrorKind::Unknown,
+                "URL does not point to an HTML page or image.",
+            ));
+        }
+
+        // Only the start of a page is parsed, OpenGraph tags are in the head
+        let (html, _) =
+            read_body(response, services().globals.url_preview_max_spider_size()).await?;
+        let mut preview = preview::parse_html(&String::from_utf8_lossy(&html), &url);
+
+        if let Some(image_url) = preview.image.take() {
+            // A page is still worth previewing if its image is broken
+            let image_url: Url = image_url.parse().expect("image URLs are valid");
+            if let Err(e) = self
+                .store_preview_image(&mut preview, &image_url, None)
+                .await
+            {
+                warn!("Failed to store preview image {}: {}", image_url, e);
+            }
+        }
+
+        Ok(preview)
+    }
+
+    /// Stores the image at `image_url` as media and adds it to the preview. The media ID is
+    /// derived from the URL, so an image that is already stored is reused instead of being
+    /// fetched and stored again. `response` is the image if it was fetched already.
+    async fn store_preview_image(
+        &self,
+        preview: &mut UrlPreviewData,
+        image_url: &Url,
+        response: Option<reqwest::Response>,
+    ) -> Result<()> {
+        let mxc = format!(
+            "mxc://{}/{}",
+            services().globals.server_name(),
+            preview_media_id(image_url)
+        );
+
+        let bytes = match self.get(mxc.clone()).await? {
+            Some(FileMeta { file, .. }) => file,
+            None => {
+                let response = match response {
+                    Some(response) => response,
+                    None => fetch_url(image_url.clone()).await?.0,
+                };
+
+                let content_type = media_type(&response);
+                if !content_type.starts_with("image/") {
+                    return Err(Error::BadServerResponse("Preview image is not an image."));
+                }
+
+                let (bytes, truncated) = read_body(
+                    response,
+                    services().globals.max_media_upload_size() as usize,
+                )
+                .await?;
+                if truncated {
+                    return Err(Error::BadRequest(
+                        ErrorKind::TooLarge,
+                        "Preview image is too large.",
+                    ));
+                }
+
+                self.create(mxc.clone(), None, Some(&content_type), &bytes)
+                    .await?;
+                bytes
+            }
+        };
+
+        if let Ok((width, height)) = image::io::Reader::new(Cursor::new(&bytes))
+            .with_guessed_format()
+            .map_err(image::ImageError::from)
+            .and_then(|reader| reader.into_dimensions())
+        {
+            preview.image_width = Some(width);
+            preview.image_height = Some(height);
+        }
+        preview.image_size = Some(bytes.len());
+        preview.image = Some(mxc);
+
+        Ok(())
+    }
+}
+
+/// The media ID of the preview image at `url`, the same for every preview that uses it.
+fn preview_media_id(url: &Url) -> String {
+    base64::encode_config(
+        ring::digest::digest(&ring::digest::SHA256, url.as_str().as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+fn quota_exceeded(usage: u64, size: u64, quota: Option<u64>) -> bool {
+    quota.map_or(false, |quota| usage.saturating_add(size) > quota)
+}
+
+/// Fetches `url`, following redirects manually so the target of every redirect is checked
+/// against the blacklists. Returns the response and the final URL.
+async fn fetch_url(mut url: Url) -> Result<(reqwest::Response, Url)> {
+    for _ in 0..=URL_PREVIEW_MAX_REDIRECTS {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Only http and https URLs can be previewed.",
+            ));
+        }
+
+        if preview::url_blacklisted(&url, services().globals.url_preview_url_blacklist()) {
+            warn!("Refusing to preview {}, it is blacklisted", url);
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "URL is blacklisted for previews.",
+            ));
+        }
+
+        check_url_ips(&url).await?;
+
+        let response = services()
+            .globals
+            .url_preview_client()
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|e| {
+                warn!("Failed to fetch {} for URL preview: {}", url, e);
+                Error::BadServerResponse("Failed to fetch URL.")
+            })?;
+
+        if !response.status().is_redirection() {
+            if !response.status().is_success() {
+                return Err(Error::BadServerResponse("URL returned an error status."));
+            }
+
+            return Ok((response, url));
+        }
+
+        url = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| url.join(location).ok())
+            .ok_or(Error::BadServerResponse("Invalid redirect location."))?;
+    }
+
+    Err(Error::BadServerResponse("URL redirected too often."))
+}
+
+async fn check_url_ips(url: &Url) -> Result<()> {
+    let host = url.host_str().ok_or(Error::BadRequest(
+        ErrorKind::InvalidParam,
+        "URL has no host.",
+    ))?;
+
+    let ips: Vec<IpAddr> = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => vec![ip],
+        Err(_) => services()
+            .globals
+            .dns_resolver()
+            .lookup_ip(host)
+            .await
+            .map_err(|e| {
+                warn!("Failed to resolve {}: {}", host, e);
+                Error::BadServerResponse("Failed to resolve URL host.")
+            })?
+            .iter()
+            .collect(),
+    };
+
+    if let Some(ip) = ips
+        .iter()
+        .find(|ip| !services().globals.url_preview_ip_allowed(**ip))
+    {
+        warn!(
+            "Refusing to preview {}, {} is in a blocked IP range",
+            url, ip
+        );
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "URL is in a blocked IP range.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// The media type of the response, without parameters like the charset.
+fn media_type(response: &reqwest::Response) -> String {
+    response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Reads at most `limit` bytes of the body. Also returns whether the body was cut off.
+async fn read_body(mut response: reqwest::Response, limit: usize) -> Result<(Vec<u8>, bool)> {
+    let mut body = Vec::new();
+
+    while let Some(chunk) = response.chunk().await.map_err(|e| {
+        warn!("Failed to read response body for URL preview: {}", e);
+        Error::BadServerResponse("Failed to fetch URL.")
+    })? {
+        if body.len() + chunk.len() > limit {
+            body.extend_from_slice(&chunk[..limit - body.len()]);
+            return Ok((body, true));
+        }
+
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok((body, false))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::{response::Html, routing::get, Router};
+
+    use super::*;
+    use crate::utils::testing;
+
+    #[test]
+    fn uploads_over_the_quota_are_refused() {
+        assert!(!quota_exceeded(500, 1000, None));
+        assert!(!quota_exceeded(500, 500, Some(1000)));
+        assert!(quota_exceeded(500, 501, Some(1000)));
+        assert!(quota_exceeded(u64::MAX, 1, Some(u64::MAX)));
+    }
+
+    #[tokio::test]
+    async fn bodies_are_cut_off_at_the_limit() {
+        let response = |body: &str| reqwest::Response::from(http::Response::new(body.to_owned()));
+
+        assert_eq!(
+            read_body(response("<html></html>"), 1024).await.unwrap(),
+            (b"<html></html>".to_vec(), false)
+        );
+        assert_eq!(
+            read_body(response("<html></html>"), 6).await.unwrap(),
+            (b"<html>".to_vec(), true)
+        );
+    }
+
+    #[tokio::test]
+    async fn preview_images_are_stored_once_per_url() {
+        testing::services_for_tests().await;
+
+        let mut png = Vec::new();
+        image::RgbImage::new(3, 2)
+            .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .unwrap();
+
+        let image_fetches = Arc::new(AtomicUsize::new(0));
+        let addr = testing::mock_server(
+            Router::new()
+                .route(
+                    "/page",
+                    get(|| async {
+                        Html(
+                            r#"<html><head>
+                            <meta property="og:title" content="Mock page">
+                            <meta property="og:image" content="/logo.png">
+                            </head></html>"#,
+                        )
+                    }),
+                )
+                .route(
+                    "/logo.png",
+                    get({
+                        let image_fetches = Arc::clone(&image_fetches);
+                        move || {
+                            let (image_fetches, png) = (Arc::clone(&image_fetches), png.clone());
+                            async move {
+                                image_fetches.fetch_add(1, Ordering::SeqCst);
+                                ([(CONTENT_TYPE, "image/png")], png)
+                            }
+                        }
+                    }),
+                ),
+        );
+        let url: Url = format!("http://{}/page", addr).parse().unwrap();
+
+        let preview = services().media.get_url_preview(&url).await.unwrap();
+        assert_eq!(preview.title.as_deref(), Some("Mock page"));
+        assert_eq!(
+            (preview.image_width, preview.image_height),
+            (Some(3), Some(2))
+        );
+        let mxc = preview.image.clone().unwrap();
+        assert_eq!(
+            services()
+                .media
+                .get(mxc.clone())
+                .await
+                .unwrap()
+                .unwrap()
+                .file
+                .len(),
+            preview.image_size.unwrap()
+        );
+
+        // Once the preview is evicted from the cache, the stored image is reused
+        services()
+            .media
+            .url_preview_cache
+            .lock()
+            .unwrap()
+            .remove(url.as_str());
+        assert_eq!(
+            services().media.get_url_preview(&url).await.unwrap(),
+            preview
+        );
+        assert_eq!(image_fetches.load(Ordering::SeqCst), 1);
+    }
 }
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::Url;
+use serde::Serialize;
+
+static META_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?is)<meta\s[^>]*>").expect("Regex compilation should not fail"));
+static ATTRIBUTE_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?is)([a-z:_-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#)
+        .expect("Regex compilation should not fail")
+});
+static TITLE_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?is)<title[^>]*>(.*?)</title>").expect("Regex compilation should not fail")
+});
+
+/// OpenGraph data of a web page, in the format of the preview_url endpoint.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct UrlPreviewData {
+    #[serde(rename = "og:title", skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(rename = "og:description", skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(rename = "og:image", skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    #[serde(rename = "matrix:image:size", skip_serializing_if = "Option::is_none")]
+    pub image_size: Option<usize>,
+    #[serde(rename = "og:image:width", skip_serializing_if = "Option::is_none")]
+    pub image_width: Option<u32>,
+    #[serde(rename = "og:image:height", skip_serializing_if = "Option::is_none")]
+    pub image_height: Option<u32>,
+}
+
+/// Extracts title, description and image URL from an HTML page. OpenGraph tags are preferred,
+/// the `<title>` tag and the description meta tag are used as fallbacks. The image URL is
+/// resolved against the URL of the page.
+pub fn parse_html(html: &str, url: &Url) -> UrlPreviewData {
+    let mut meta = HashMap::new();
+    for tag in META_RE.find_iter(html) {
+        let attributes: HashMap<_, _> = ATTRIBUTE_RE
+            .captures_iter(tag.as_str())
+            .filter_map(|c| {
+                Some((
+                    c[1].to_ascii_lowercase(),
+                    c.get(2).or_else(|| c.get(3))?.as_str(),
+                ))
+            })
+            .collect();
+
+        let key = attributes
+            .get("property")
+            .or_else(|| attributes.get("name"));
+
+        if let (Some(key), Some(content)) = (key, attributes.get("content")) {
+            // The first tag wins, like in most other implementations
+            meta.entry(key.to_ascii_lowercase())
+                .or_insert_with(|| decode_entities(content.trim()));
+        }
+    }
+
+    let title = meta
+        .remove("og:title")
+        .or_else(|| {
+            TITLE_RE
+                .captures(html)
+                .map(|c| decode_entities(c[1].trim()))
+        })
+        .filter(|title| !title.is_empty());
+
+    let description = meta
+        .remove("og:description")
+        .or_else(|| meta.remove("description"))
+        .filter(|description| !description.is_empty());
+
+    let image = meta
+        .remove("og:image")
+        .and_then(|image| url.join(&image).ok())
+        .filter(|image| matches!(image.scheme(), "http" | "https"))
+        .map(|image| image.to_string());
+
+    UrlPreviewData {
+        title,
+        description,
+        image,
+        ..Default::default()
+    }
+}
+
+/// Whether the URL matches one of the glob patterns, where `*` matches any sequence of
+/// characters. Patterns without a `/` are matched against the host, others against the host
+/// followed by the path, like `example.org/private/*`.
+pub fn url_blacklisted(url: &Url, patterns: &[String]) -> bool {
+    let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+    let host_and_path = format!("{}{}", host, url.path());
+
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.to_ascii_lowercase();
+        if pattern.contains('/') {
+            glob_matches(&pattern, &host_and_path)
+        } else {
+            glob_matches(&pattern, &host)
+        }
+    })
+}
+
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.as_bytes(), text.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern and the text position it was tried at
+    let mut backtrack = None;
+
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star, star_t)) = backtrack {
+            // Let the last `*` match one more character
+            p = star + 1;
+            t = star_t + 1;
+            backtrack = Some((star, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+fn decode_entities(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn opengraph_tags_are_parsed() {
+        let html = r#"<!DOCTYPE html>
+            <html>
+            <head>
+                <title>Fallback title</title>
+                <meta property="og:title" content="Conduit &amp; Matrix">
+                <meta name="description" content="Fallback description">
+                <META PROPERTY="og:description" CONTENT='A &quot;simple&quot; homeserver' />
+                <meta property="og:image" content="/images/logo.png">
+            </head>
+            <body><p>Hello</p></body>
+            </html>"#;
+
+        let preview = parse_html(html, &"https://example.org/blog/post".parse().unwrap());
+
+        assert_eq!(preview.title.as_deref(), Some("Conduit & Matrix"));
+        assert_eq!(
+            preview.description.as_deref(),
+            Some("A \"simple\" homeserver")
+        );
+        assert_eq!(
+            preview.image.as_deref(),
+            Some("https://example.org/images/logo.png")
+        );
+    }
+
+    #[test]
+    fn blacklisted_urls_are_matched() {
+        let patterns = [
+            "*.internal.example.org".to_owned(),
+            "localhost".to_owned(),
+            "example.org/private/*".to_owned(),
+        ];
+        let blacklisted = |url: &str| url_blacklisted(&url.parse().unwrap(), &patterns);
+
+        assert!(blacklisted("https://wiki.internal.example.org/page"));
+        assert!(blacklisted("http://LOCALHOST:8080/"));
+        assert!(blacklisted("https://example.org/private/keys.html"));
+
+        assert!(!blacklisted("https://internal.example.org/"));
+        assert!(!blacklisted("https://example.org/public/private/"));
+        assert!(!blacklisted("https://localhost.example.com/"));
+    }
+
+    #[test]
+    fn title_and_description_tags_are_fallbacks() {
+        let html = r#"<html><head>
+            <title>
+                Plain page
+            </title>
+            <meta name="description" content="Without OpenGraph">
+            <meta property="og:image" content="javascript:alert(1)">
+            </head></html>"#;
+
+        let preview = parse_html(html, &"https://example.org/".parse().unwrap());
+
+        assert_eq!(
+            preview,
+            UrlPreviewData {
+                title: Some("Plain page".to_owned()),
+                description: Some("Without OpenGraph".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(&preview).unwrap(),
+            serde_json::json!({
+                "og:title": "Plain page",
+                "og:description": "Without OpenGraph",
+            })
+        );
+    }
+}
@@ -1,22 +1,29 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    sync::{atomic::AtomicU64, Arc, Mutex, RwLock},
 };
 
 use lru_cache::LruCache;
+use tokio::sync::Notify;
 
 use crate::{Config, Result};
 
 pub mod account_data;
 pub mod admin;
 pub mod appservice;
+pub mod captcha;
+pub mod delayed_events;
+pub mod email;
 pub mod globals;
 pub mod key_backups;
 pub mod media;
+pub mod msisdn;
 pub mod pdu;
 pub mod pusher;
+pub mod reports;
 pub mod rooms;
 pub mod sending;
+pub mod threepid_sessions;
 pub mod transaction_ids;
 pub mod uiaa;
 pub mod users;
@@ -24,15 +31,20 @@ pub mod users;
 pub struct Services {
     pub appservice: appservice::Service,
     pub pusher: pusher::Service,
+    pub reports: reports::Service,
     pub rooms: rooms::Service,
     pub transaction_ids: transaction_ids::Service,
     pub uiaa: uiaa::Service,
     pub users: users::Service,
     pub account_data: account_data::Service,
+    pub delayed_events: delayed_events::Service,
     pub admin: Arc<admin::Service>,
+    pub email: email::Service,
     pub globals: globals::Service,
     pub key_backups: key_backups::Service,
     pub media: media::Service,
+    pub msisdn: msisdn::Service,
+    pub captcha: captcha::Service,
     pub sending: Arc<sending::Service>,
 }
 
@@ -40,11 +52,13 @@ impl Services {
     pub fn build<
         D: appservice::Data
             + pusher::Data
+            + reports::Data
             + rooms::Data
             + transaction_ids::Data
             + uiaa::Data
             + users::Data
             + account_data::Data
+            + delayed_events::Data
             + globals::Data
             + key_backups::Data
             + media::Data
@@ -55,21 +69,33 @@ impl Services {
         config: Config,
     ) -> Result<Self> {
         Ok(Self {
-            appservice: appservice::Service { db },
+            appservice: appservice::Service {
+                db,
+                exclusive_users: RwLock::new(None),
+            },
             pusher: pusher::Service { db },
+            reports: reports::Service::build(db),
             rooms: rooms::Service {
                 alias: rooms::alias::Service { db },
                 auth_chain: rooms::auth_chain::Service { db },
                 directory: rooms::directory::Service { db },
                 edus: rooms::edus::Service {
-                    presence: rooms::edus::presence::Service { db },
+                    presence: rooms::edus::presence::Service {
+                        db,
+                        activity: Mutex::new(HashMap::new()),
+                    },
                     read_receipt: rooms::edus::read_receipt::Service { db },
                     typing: rooms::edus::typing::Service { db },
                 },
                 event_handler: rooms::event_handler::Service,
                 lazy_loading: rooms::lazy_loading::Service {
                     db,
-                    lazy_load_waiting: Mutex::new(HashMap::new()),
+                    sent_members: Mutex::new(rooms::lazy_loading::SentMembers::new(
+                        (1000.0 * config.conduit_cache_capacity_modifier) as usize,
+                    )),
+                    sent_device_lists: Mutex::new(rooms::lazy_loading::SentDeviceLists::new(
+                        (1000.0 * config.conduit_cache_capacity_modifier) as usize,
+                    )),
                 },
                 metadata: rooms::metadata::Service { db },
                 outlier: rooms::outlier::Service { db },
@@ -77,8 +103,28 @@ impl Services {
                 search: rooms::search::Service { db },
                 short: rooms::short::Service { db },
                 state: rooms::state::Service { db },
-                state_accessor: rooms::state_accessor::Service { db },
-                state_cache: rooms::state_cache::Service { db },
+                state_accessor: rooms::state_accessor::Service {
+                    db,
+                    current_state_cache: Mutex::new(rooms::state_accessor::CurrentStateCache::new(
+                        (100.0 * config.conduit_cache_capacity_modifier) as usize,
+                    )),
+                    history_visibility_cache: Mutex::new(LruCache::new(
+                        (1000.0 * config.conduit_cache_capacity_modifier) as usize,
+                    )),
+                    membership_cache: Mutex::new(LruCache::new(
+                        (10_000.0 * config.conduit_cache_capacity_modifier) as usize,
+                    )),
+                },
+                state_cache: rooms::state_cache::Service {
+                    db,
+                    joined_members_cache: Mutex::new(LruCache::new(
+                        (100.0 * config.conduit_cache_capacity_modifier) as usize,
+                    )),
+                    joined_members_generation: AtomicU64::new(0),
+                    joined_counts: Mutex::new(rooms::state_cache::JoinedCounts::new(
+                        (100.0 * config.conduit_cache_capacity_modifier) as usize,
+                    )),
+                },
                 state_compressor: rooms::state_compressor::Service {
                     db,
                     stateinfo_cache: Mutex::new(LruCache::new(
@@ -88,16 +134,44 @@ impl Services {
                 timeline: rooms::timeline::Service {
                     db,
                     lasttimelinecount_cache: Mutex::new(HashMap::new()),
+                    pdu_cache: rooms::timeline::PduCache::new(
+                        config
+                            .pdu_cache_capacity
+                            .try_into()
+                            .expect("pdu cache capacity fits into usize"),
+                    ),
                 },
                 user: rooms::user::Service { db },
             },
             transaction_ids: transaction_ids::Service { db },
             uiaa: uiaa::Service { db },
-            users: users::Service { db },
+            users: users::Service {
+                db,
+                last_seen_cache: Mutex::new(LruCache::new(
+                    (1000.0 * config.conduit_cache_capacity_modifier) as usize,
+                )),
+                profile_updates: Mutex::new(HashMap::new()),
+                login_tokens: Mutex::new(users::LoginTokens::default()),
+            },
             account_data: account_data::Service { db },
+            delayed_events: delayed_events::Service {
+                db,
+                timers_changed: Notify::new(),
+                change_lock: Mutex::new(()),
+            },
             admin: admin::Service::build(),
+            email: email::Service::build(),
             key_backups: key_backups::Service { db },
-            media: media::Service { db },
+            media: media::Service {
+                db,
+                url_preview_cache: Mutex::new(LruCache::new(
+                    (100.0 * config.conduit_cache_capacity_modifier) as usize,
+                )),
+                url_mutex_preview: RwLock::new(HashMap::new()),
+                userid_mutex_usage: RwLock::new(HashMap::new()),
+            },
+            msisdn: msisdn::Service::build(),
+            captcha: captcha::Service::build(),
             sending: sending::Service::build(db, &config),
 
             globals: globals::Service::load(db, config)?,
@@ -0,0 +1,221 @@
+use std::{net::IpAddr, sync::Mutex, time::Duration};
+
+use ruma::{
+    api::client::error::ErrorKind, thirdparty::Medium, ClientSecret, MilliSecondsSinceUnixEpoch,
+    OwnedSessionId, SessionId,
+};
+use tracing::info;
+
+use crate::{
+    api::identity_server::{self, ValidatedThreepid},
+    service::{
+        globals::WindowLimiter,
+        threepid_sessions::{Session, ThreepidSessions},
+    },
+    services, Error, Result,
+};
+
+const TEXTS_PER_NUMBER: u32 = 3;
+const TEXTS_PER_IP: u32 = 10;
+const TEXT_LIMIT_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// Phone numbers and IPs the limits are kept for at most.
+const LIMITER_CAPACITY: usize = 10_000;
+
+/// Phone numbers the identity server validated through our submit_token endpoint.
+pub struct Service {
+    sessions: ThreepidSessions<()>,
+    number_limiter: Mutex<WindowLimiter<String>>,
+    ip_limiter: Mutex<WindowLimiter<IpAddr>>,
+}
+
+impl Service {
+    pub fn build() -> Self {
+        Self {
+            sessions: ThreepidSessions::new(),
+            number_limiter: Mutex::new(WindowLimiter::new(
+                LIMITER_CAPACITY,
+                Some(TEXTS_PER_NUMBER),
+                TEXT_LIMIT_WINDOW,
+            )),
+            ip_limiter: Mutex::new(WindowLimiter::new(
+                LIMITER_CAPACITY,
+                Some(TEXTS_PER_IP),
+                TEXT_LIMIT_WINDOW,
+            )),
+        }
+    }
+
+    /// Whether phone numbers can be validated at all.
+    pub fn enabled(&self) -> bool {
+        services().globals.msisdn_delegate().is_some()
+    }
+
+    /// Makes the identity server text a validation token to the phone number and returns the
+    /// session id. Every request counts against the limits of the number and the client IP, the
+    /// identity server decides whether it's a retry.
+    pub async fn request_token(
+        &self,
+        client_secret: &ClientSecret,
+        country: &str,
+        phone_number: &str,
+        send_attempt: u64,
+        client_ip: Option<IpAddr>,
+    ) -> Result<OwnedSessionId> {
+        let (id_server, id_access_token) =
+            services()
+                .globals
+                .msisdn_delegate()
+                .ok_or(Error::BadRequest(
+                    ErrorKind::ThreepidDenied,
+                    "Third party identifier is not allowed",
+                ))?;
+
+        self.check_limits(&format!("{country} {phone_number}"), client_ip)?;
+
+        let sid = identity_server::request_msisdn_token(
+            id_server,
+            id_access_token,
+            client_secret,
+            country,
+            phone_number,
+            send_attempt,
+        )
+        .await?;
+
+        info!(
+            "Identity server {} texted a token for session {}",
+            id_server, sid
+        );
+        Ok(sid)
+    }
+
+    /// Counts a new text against the limits of the phone number and the client IP.
+    fn check_limits(&self, number: &str, client_ip: Option<IpAddr>) -> Result<()> {
+        let limited = client_ip
+            .map_or(Ok(()), |ip| self.ip_limiter.lock().unwrap().check(&ip))
+            .and_then(|()| self.number_limiter.lock().unwrap().check(number));
+
+        limited.map_err(|retry_after| {
+            Error::BadRequest(
+                ErrorKind::LimitExceeded {
+                    retry_after_ms: Some(retry_after),
+                },
+                "Too many validation texts requested, try again later.",
+            )
+        })
+    }
+
+    /// Passes the token on to the identity server and remembers the phone number if it was the
+    /// right one.
+    pub async fn submit_token(
+        &self,
+        sid: &SessionId,
+        client_secret: &ClientSecret,
+        token: &str,
+    ) -> Result<bool> {
+        let (id_server, id_access_token) =
+            services()
+                .globals
+                .msisdn_delegate()
+                .ok_or(Error::BadRequest(
+                    ErrorKind::ThreepidDenied,
+                    "Third party identifier is not allowed",
+                ))?;
+
+        match identity_server::submit_msisdn_token(
+            id_server,
+            id_access_token,
+            sid,
+            client_secret,
+            token,
+        )
+        .await?
+        {
+            Some(threepid) => {
+                self.finish_session(sid, client_secret, threepid)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Remembers the validated phone number, unless another account already has it.
+    fn finish_session(
+        &self,
+        sid: &SessionId,
+        client_secret: &ClientSecret,
+        threepid: ValidatedThreepid,
+    ) -> Result<()> {
+        if services()
+            .users
+            .find_from_threepid(&Medium::Msisdn, &threepid.address)?
+            .is_some()
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidInUse,
+                "The phone number is already in use.",
+            ));
+        }
+
+        self.sessions.insert(
+            sid,
+            Session::new(
+                client_secret,
+                threepid.address,
+                Some(threepid.validated_at),
+                (),
+            ),
+        )
+    }
+
+    /// Returns the phone number and when it was validated, if the session was validated.
+    pub fn validated(
+        &self,
+        sid: &SessionId,
+        client_secret: &ClientSecret,
+    ) -> Option<(String, MilliSecondsSinceUnixEpoch)> {
+        self.sessions.validated(sid, client_secret)
+    }
+
+    /// Remembers the phone number validated in the `m.login.msisdn` stage of a UIAA session.
+    pub fn set_uiaa_msisdn(
+        &self,
+        uiaa_session: &str,
+        msisdn: String,
+        validated_at: MilliSecondsSinceUnixEpoch,
+    ) {
+        self.sessions.set_uiaa(uiaa_session, msisdn, validated_at);
+    }
+
+    /// Returns the phone number validated in the UIAA session, once.
+    pub fn take_uiaa_msisdn(
+        &self,
+        uiaa_session: &str,
+    ) -> Option<(String, MilliSecondsSinceUnixEpoch)> {
+        self.sessions.take_uiaa(uiaa_session)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validation_texts_are_limited_per_number_and_ip() {
+        let service = Service::build();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        for _ in 0..TEXTS_PER_NUMBER {
+            assert!(service.check_limits("GB 7700900000", None).is_ok());
+        }
+        assert!(service.check_limits("GB 7700900000", None).is_err());
+
+        for i in 0..TEXTS_PER_IP {
+            assert!(service
+                .check_limits(&format!("GB 77009001{i:02}"), Some(ip))
+                .is_ok());
+        }
+        assert!(service.check_limits("GB 7700900200", Some(ip)).is_err());
+        assert!(service.check_limits("GB 7700900200", None).is_ok());
+    }
+}
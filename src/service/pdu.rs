@@ -1,12 +1,13 @@
 use crate::Error;
 use ruma::{
+    api::client::error::ErrorKind,
     events::{
         room::member::RoomMemberEventContent, AnyEphemeralRoomEvent, AnyStateEvent,
         AnyStrippedStateEvent, AnySyncStateEvent, AnySyncTimelineEvent, AnyTimelineEvent,
         RoomEventType, StateEvent,
     },
     serde::Raw,
-    state_res, CanonicalJsonObject, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch,
+    state_res, CanonicalJsonObject, CanonicalJsonValue, EventId, Int, MilliSecondsSinceUnixEpoch,
     OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, RoomVersionId, UInt, UserId,
 };
 use serde::{Deserialize, Serialize};
@@ -329,6 +330,83 @@ impl Ord for PduEvent {
     }
 }
 
+/// Longest signature we accept on an incoming PDU, in bytes.
+const MAX_SIGNATURE_SIZE: usize = 65_536;
+
+/// Limits for PDUs received over federation. The defaults are the limits from the spec.
+#[derive(Clone, Copy, Debug)]
+pub struct PduLimits {
+    /// Size of the whole PDU as JSON, in bytes
+    pub max_size: usize,
+    pub max_prev_events: usize,
+    pub max_auth_events: usize,
+}
+
+/// Checks an incoming PDU against `limits`. `size` is the length of the JSON it was received as.
+pub(crate) fn check_pdu_limits(
+    value: &CanonicalJsonObject,
+    size: usize,
+    limits: &PduLimits,
+) -> crate::Result<()> {
+    if size > limits.max_size {
+        return Err(Error::BadRequest(ErrorKind::TooLarge, "PDU is too large."));
+    }
+
+    let list_len = |key| match value.get(key) {
+        Some(CanonicalJsonValue::Array(list)) => Ok(list.len()),
+        _ => Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "PDU has invalid prev_events or auth_events.",
+        )),
+    };
+
+    if list_len("prev_events")? > limits.max_prev_events {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "PDU has too many prev_events.",
+        ));
+    }
+
+    if list_len("auth_events")? > limits.max_auth_events {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "PDU has too many auth_events.",
+        ));
+    }
+
+    match value.get("depth") {
+        Some(CanonicalJsonValue::Integer(depth)) if *depth >= Int::from(0) => {}
+        _ => {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "PDU has an invalid depth.",
+            ))
+        }
+    }
+
+    if let Some(CanonicalJsonValue::Object(signatures)) = value.get("signatures") {
+        let too_long = signatures
+            .values()
+            .filter_map(|keys| match keys {
+                CanonicalJsonValue::Object(keys) => Some(keys.values()),
+                _ => None,
+            })
+            .flatten()
+            .any(|signature| {
+                matches!(signature, CanonicalJsonValue::String(s) if s.len() > MAX_SIGNATURE_SIZE)
+            });
+
+        if too_long {
+            return Err(Error::BadRequest(
+                ErrorKind::TooLarge,
+                "PDU has a signature that is too large.",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
 /// Generates a correct eventId for the incoming pdu.
 ///
 /// Returns a tuple of the new `EventId` and the PDU as a `BTreeMap<String, CanonicalJsonValue>`.
@@ -363,3 +441,76 @@ pub struct PduBuilder {
     pub state_key: Option<String>,
     pub redacts: Option<Arc<EventId>>,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits() -> PduLimits {
+        PduLimits {
+            max_size: 1000,
+            max_prev_events: 2,
+            max_auth_events: 2,
+        }
+    }
+
+    fn pdu(prev_events: usize) -> CanonicalJsonObject {
+        let prev_events: Vec<_> = (0..prev_events).map(|i| format!("$event{}", i)).collect();
+        serde_json::from_value(json!({
+            "prev_events": prev_events,
+            "auth_events": [],
+            "depth": 5,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn pdus_within_the_limits_are_accepted() {
+        assert!(check_pdu_limits(&pdu(2), 500, &limits()).is_ok());
+    }
+
+    #[test]
+    fn oversized_pdus_are_rejected() {
+        assert!(check_pdu_limits(&pdu(1), 1001, &limits()).is_err());
+    }
+
+    #[test]
+    fn pdus_with_too_many_prev_events_are_rejected() {
+        assert!(check_pdu_limits(&pdu(3), 500, &limits()).is_err());
+    }
+
+    #[test]
+    fn call_invites_are_sent_to_other_servers_unchanged() {
+        let content = json!({
+            "call_id": "1414213562373095",
+            "party_id": "ABCDEF",
+            "version": "1",
+            "lifetime": 60000,
+            "offer": { "type": "offer", "sdp": "v=0" },
+            "org.example.unknown": true,
+        });
+        let pdu_json: CanonicalJsonObject = serde_json::from_value(json!({
+            "event_id": "$call:example.org",
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 1,
+            "type": "m.call.invite",
+            "content": content,
+            "prev_events": [],
+            "depth": 5,
+            "auth_events": [],
+            "hashes": { "sha256": "" },
+            "unsigned": { "transaction_id": "m1" },
+        }))
+        .unwrap();
+
+        let outgoing: serde_json::Value =
+            serde_json::from_str(PduEvent::convert_to_outgoing_federation_event(pdu_json).get())
+                .unwrap();
+
+        assert_eq!(outgoing["type"], "m.call.invite");
+        assert_eq!(outgoing["content"], content);
+        assert!(outgoing.get("event_id").is_none());
+        assert!(outgoing["unsigned"].get("transaction_id").is_none());
+    }
+}
@@ -0,0 +1,11 @@
+use crate::Result;
+
+use super::Report;
+
+pub trait Data: Send + Sync {
+    /// Stores a report and returns its id.
+    fn add_report(&self, report: &Report) -> Result<u64>;
+
+    /// Returns all reports, oldest first.
+    fn reports<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(u64, Report)>> + 'a>;
+}
@@ -0,0 +1,84 @@
+mod data;
+
+use std::{sync::Mutex, time::Duration};
+
+pub use data::Data;
+
+use ruma::{
+    api::client::error::ErrorKind, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId,
+    OwnedUserId, UserId,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{service::globals::WindowLimiter, Error, Result};
+
+const REPORTS_PER_USER: u32 = 10;
+const REPORT_LIMIT_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// Reporters the limit is kept for at most.
+const LIMITER_CAPACITY: usize = 10_000;
+
+/// A report of an event or a whole room, sent by a user to the server admins.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Report {
+    pub reporter: OwnedUserId,
+    pub room_id: OwnedRoomId,
+    /// None for reports of the whole room.
+    pub event_id: Option<OwnedEventId>,
+    pub score: Option<i64>,
+    pub reason: Option<String>,
+    pub received_ts: MilliSecondsSinceUnixEpoch,
+}
+
+pub struct Service {
+    pub db: &'static dyn Data,
+    reporter_limiter: Mutex<WindowLimiter<OwnedUserId>>,
+}
+
+impl Service {
+    pub fn build(db: &'static dyn Data) -> Self {
+        Self {
+            db,
+            reporter_limiter: Mutex::new(WindowLimiter::new(
+                LIMITER_CAPACITY,
+                Some(REPORTS_PER_USER),
+                REPORT_LIMIT_WINDOW,
+            )),
+        }
+    }
+
+    /// Counts a new report against the limit of the reporter.
+    pub fn check_limit(&self, reporter: &UserId) -> Result<()> {
+        self.reporter_limiter
+            .lock()
+            .unwrap()
+            .check(reporter)
+            .map_err(|retry_after| {
+                Error::BadRequest(
+                    ErrorKind::LimitExceeded {
+                        retry_after_ms: Some(retry_after),
+                    },
+                    "Too many reports sent, try again later.",
+                )
+            })
+    }
+
+    pub fn add_report(&self, report: &Report) -> Result<u64> {
+        self.db.add_report(report)
+    }
+
+    pub fn reports(&self) -> impl Iterator<Item = Result<(u64, Report)>> + '_ {
+        self.db.reports()
+    }
+}
+
+/// Checks the reason given for a report.
+pub fn check_reason(reason: Option<&str>) -> Result<()> {
+    if reason.map_or(false, |reason| reason.chars().count() > 250) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Reason too long, should be 250 characters or fewer",
+        ));
+    }
+
+    Ok(())
+}
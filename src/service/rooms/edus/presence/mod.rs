@@ -1,16 +1,91 @@
 mod data;
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
 pub use data::Data;
-use ruma::{events::presence::PresenceEvent, OwnedUserId, RoomId, UserId};
+use ruma::{
+    events::presence::{PresenceEvent, PresenceEventContent},
+    presence::PresenceState,
+    OwnedUserId, RoomId, UserId,
+};
+use tracing::debug;
 
-use crate::Result;
+use crate::{services, utils, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
+    /// When local users were last active, for the presence timers. Only kept in memory.
+    pub activity: Mutex<HashMap<OwnedUserId, Activity>>,
+}
+
+pub struct Activity {
+    last_active: Instant,
+    presence: PresenceState,
+    status_msg: Option<String>,
+    /// Whether the presence was set by the timers instead of the user
+    timed_out: bool,
 }
 
 impl Service {
+    /// Sets the presence of a local user in all rooms they are joined to, so the other members
+    /// see it on their next sync.
+    pub fn set_presence(
+        &self,
+        user_id: &UserId,
+        presence: PresenceState,
+        status_msg: Option<String>,
+    ) -> Result<()> {
+        self.publish(user_id, presence.clone(), status_msg.clone())?;
+
+        self.activity.lock().unwrap().insert(
+            user_id.to_owned(),
+            Activity {
+                last_active: Instant::now(),
+                presence,
+                status_msg,
+                timed_out: false,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn publish(
+        &self,
+        user_id: &UserId,
+        presence: PresenceState,
+        status_msg: Option<String>,
+    ) -> Result<()> {
+        for room_id in services().rooms.state_cache.rooms_joined(user_id) {
+            let room_id = room_id?;
+
+            self.update_presence(
+                user_id,
+                &room_id,
+                PresenceEvent {
+                    content: PresenceEventContent {
+                        avatar_url: services().users.avatar_url(user_id)?,
+                        currently_active: None,
+                        displayname: services().users.displayname(user_id)?,
+                        last_active_ago: Some(
+                            utils::millis_since_unix_epoch()
+                                .try_into()
+                                .expect("time is valid"),
+                        ),
+                        presence: presence.clone(),
+                        status_msg: status_msg.clone(),
+                    },
+                    sender: user_id.to_owned(),
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
     /// Adds a presence event which will be saved until a new event replaces it.
     ///
     /// Note: This method takes a RoomId because presence updates are always bound to rooms to
@@ -24,11 +99,70 @@ impl Service {
         self.db.update_presence(user_id, room_id, presence)
     }
 
-    /// Resets the presence timeout, so the user will stay in their current presence state.
+    /// Resets the presence timeout, so the user will stay in their current presence state. Users
+    /// the timers set to unavailable or offline are online again.
     pub fn ping_presence(&self, user_id: &UserId) -> Result<()> {
+        let status_msg = {
+            let mut activity = self.activity.lock().unwrap();
+            let activity = activity
+                .entry(user_id.to_owned())
+                .or_insert_with(|| Activity {
+                    last_active: Instant::now(),
+                    presence: PresenceState::Online,
+                    status_msg: None,
+                    timed_out: false,
+                });
+            activity.last_active = Instant::now();
+            if activity.timed_out {
+                activity.presence = PresenceState::Online;
+                activity.timed_out = false;
+                Some(activity.status_msg.clone())
+            } else {
+                None
+            }
+        };
+
+        if let Some(status_msg) = status_msg {
+            self.publish(user_id, PresenceState::Online, status_msg)?;
+        }
+
         self.db.ping_presence(user_id)
     }
 
+    /// Sets users who have been inactive for too long to unavailable or offline.
+    pub fn apply_timeouts(&self) -> Result<()> {
+        let idle_timeout = services().globals.presence_idle_timeout();
+        let offline_timeout = services().globals.presence_offline_timeout();
+
+        let timed_out: Vec<_> = self
+            .activity
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(user_id, activity)| {
+                timed_out_presence(
+                    &activity.presence,
+                    activity.last_active.elapsed(),
+                    idle_timeout,
+                    offline_timeout,
+                )
+                .map(|presence| (user_id.clone(), presence, activity.status_msg.clone()))
+            })
+            .collect();
+
+        for (user_id, presence, status_msg) in timed_out {
+            debug!("Presence of {} timed out to {:?}", user_id, presence);
+            if let Some(activity) = self.activity.lock().unwrap().get_mut(&user_id) {
+                activity.presence = presence.clone();
+                activity.timed_out = true;
+            }
+
+            self.publish(&user_id, presence, status_msg)?;
+        }
+
+        Ok(())
+    }
+
     pub fn get_last_presence_event(
         &self,
         user_id: &UserId,
@@ -120,3 +254,46 @@ impl Service {
         self.db.presence_since(room_id, since)
     }
 }
+
+/// The presence a user should be moved to after being inactive for `inactive_for`, if any.
+/// Only online users become unavailable, any user becomes offline.
+fn timed_out_presence(
+    presence: &PresenceState,
+    inactive_for: Duration,
+    idle_timeout: Duration,
+    offline_timeout: Duration,
+) -> Option<PresenceState> {
+    if inactive_for >= offline_timeout && *presence != PresenceState::Offline {
+        Some(PresenceState::Offline)
+    } else if inactive_for >= idle_timeout && *presence == PresenceState::Online {
+        Some(PresenceState::Unavailable)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn inactive_users_go_idle_then_offline() {
+        let idle = Duration::from_secs(5 * 60);
+        let offline = Duration::from_secs(30 * 60);
+        let after = |minutes, presence: &PresenceState| {
+            timed_out_presence(presence, Duration::from_secs(minutes * 60), idle, offline)
+        };
+
+        assert_eq!(after(1, &PresenceState::Online), None);
+        assert_eq!(
+            after(5, &PresenceState::Online),
+            Some(PresenceState::Unavailable)
+        );
+        assert_eq!(after(10, &PresenceState::Unavailable), None);
+        assert_eq!(
+            after(30, &PresenceState::Unavailable),
+            Some(PresenceState::Offline)
+        );
+        assert_eq!(after(60, &PresenceState::Offline), None);
+    }
+}
@@ -1,5 +1,7 @@
+use std::collections::HashSet;
+
 use crate::Result;
-use ruma::{events::receipt::ReceiptEvent, serde::Raw, OwnedUserId, RoomId, UserId};
+use ruma::{events::receipt::ReceiptEvent, serde::Raw, EventId, OwnedUserId, RoomId, UserId};
 
 pub trait Data: Send + Sync {
     /// Replaces the previous read receipt.
@@ -25,6 +27,13 @@ pub trait Data: Send + Sync {
             > + 'a,
     >;
 
+    /// Removes the read receipts of the room that only point to the given events.
+    fn remove_readreceipts_for(
+        &self,
+        room_id: &RoomId,
+        event_ids: &HashSet<&EventId>,
+    ) -> Result<()>;
+
     /// Sets a private read marker at `count`.
     fn private_read_set(&self, room_id: &RoomId, user_id: &UserId, count: u64) -> Result<()>;
 
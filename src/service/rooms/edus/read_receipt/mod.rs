@@ -2,8 +2,10 @@ mod data;
 
 pub use data::Data;
 
+use std::collections::HashSet;
+
 use crate::Result;
-use ruma::{events::receipt::ReceiptEvent, serde::Raw, OwnedUserId, RoomId, UserId};
+use ruma::{events::receipt::ReceiptEvent, serde::Raw, EventId, OwnedUserId, RoomId, UserId};
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -36,6 +38,16 @@ impl Service {
         self.db.readreceipts_since(room_id, since)
     }
 
+    /// Removes the read receipts of the room that only point to the given events, e.g. after
+    /// they were purged.
+    pub fn remove_readreceipts_for(
+        &self,
+        room_id: &RoomId,
+        event_ids: &HashSet<&EventId>,
+    ) -> Result<()> {
+        self.db.remove_readreceipts_for(room_id, event_ids)
+    }
+
     /// Sets a private read marker at `count`.
     #[tracing::instrument(skip(self))]
     pub fn private_read_set(&self, room_id: &RoomId, user_id: &UserId, count: u64) -> Result<()> {
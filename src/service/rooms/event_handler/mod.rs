@@ -3,8 +3,8 @@ type AsyncRecursiveType<'a, T> = Pin<Box<dyn Future<Output = T> + 'a + Send>>;
 
 use ruma::{
     api::federation::discovery::{get_remote_server_keys, get_server_keys},
-    CanonicalJsonObject, CanonicalJsonValue, OwnedServerName, OwnedServerSigningKeyId,
-    RoomVersionId,
+    CanonicalJsonObject, CanonicalJsonValue, OwnedEventId, OwnedServerName,
+    OwnedServerSigningKeyId, RoomVersionId,
 };
 use std::{
     collections::{hash_map, BTreeMap, HashMap, HashSet},
@@ -19,8 +19,11 @@ use ruma::{
     api::{
         client::error::ErrorKind,
         federation::{
-            discovery::get_remote_server_keys_batch::{self, v2::QueryCriteria},
-            event::{get_event, get_room_state_ids},
+            discovery::{
+                get_remote_server_keys_batch::{self, v2::QueryCriteria},
+                ServerSigningKeys,
+            },
+            event::{get_event, get_missing_events, get_room_state, get_room_state_ids},
             membership::create_join_event,
         },
     },
@@ -29,9 +32,9 @@ use ruma::{
         StateEventType,
     },
     int,
-    serde::Base64,
+    serde::{Base64, Raw},
     state_res::{self, RoomVersion, StateMap},
-    uint, EventId, MilliSecondsSinceUnixEpoch, RoomId, ServerName,
+    uint, EventId, MilliSecondsSinceUnixEpoch, RoomId, ServerName, UInt,
 };
 use serde_json::value::RawValue as RawJsonValue;
 use tracing::{debug, error, info, trace, warn};
@@ -138,6 +141,7 @@ impl Service {
                 room_version_id,
                 pub_key_map,
                 incoming_pdu.prev_events.clone(),
+                incoming_pdu.depth,
             )
             .await?;
 
@@ -280,13 +284,6 @@ impl Service {
 
             // TODO: For RoomVersion6 we must check that Raw<..> is canonical do we anywhere?: https://matrix.org/docs/spec/rooms/v6#canonical-json
 
-            // We go through all the signatures we see on the value and fetch the corresponding signing
-            // keys
-            self.fetch_required_signing_keys(&value, pub_key_map)
-                .await?;
-
-            // 2. Check signatures, otherwise drop
-            // 3. check content hash, redact if doesn't match
             let create_event_content: RoomCreateEventContent =
                 serde_json::from_str(create_event.content.get()).map_err(|e| {
                     error!("Invalid create event: {}", e);
@@ -297,20 +294,13 @@ impl Service {
             let room_version =
                 RoomVersion::new(room_version_id).expect("room version is supported");
 
-            let mut val = match ruma::signatures::verify_event(
-                &pub_key_map.read().expect("RwLock is poisoned."),
-                &value,
-                room_version_id,
-            ) {
-                Err(e) => {
-                    // Drop
-                    warn!("Dropping bad event {}: {}", event_id, e);
-                    return Err(Error::BadRequest(
-                        ErrorKind::InvalidParam,
-                        "Signature verification failed",
-                    ));
-                }
-                Ok(ruma::signatures::Verified::Signatures) => {
+            // 2. Check signatures, otherwise drop
+            // 3. check content hash, redact if doesn't match
+            let mut val = match self
+                .verify_event(event_id, &value, room_version_id, pub_key_map)
+                .await?
+            {
+                ruma::signatures::Verified::Signatures => {
                     // Redact
                     warn!("Calculated hash does not match: {}", event_id);
                     match ruma::canonical_json::redact(value, room_version_id, None) {
@@ -323,7 +313,7 @@ impl Service {
                         }
                     }
                 }
-                Ok(ruma::signatures::Verified::All) => value,
+                ruma::signatures::Verified::All => value,
             };
 
             // Now that we have checked the signature and hashes we can add the eventID and convert
@@ -572,14 +562,12 @@ impl Service {
                     let mut starting_events = Vec::with_capacity(leaf_state.len());
 
                     for (k, id) in leaf_state {
-                        if let Ok((ty, st_key)) = services().rooms.short.get_statekey_from_short(k)
-                        {
-                            // FIXME: Undo .to_string().into() when StateMap
-                            //        is updated to use StateEventType
-                            state.insert((ty.to_string().into(), st_key), id.clone());
-                        } else {
-                            warn!("Failed to get_statekey_from_short.");
-                        }
+                        // A fork state with missing entries would resolve differently than on
+                        // other servers, so don't resolve at all in that case
+                        let (ty, st_key) = services().rooms.short.get_statekey_from_short(k)?;
+                        // FIXME: Undo .to_string().into() when StateMap
+                        //        is updated to use StateEventType
+                        state.insert((ty.to_string().into(), st_key), id.clone());
                         starting_events.push(id);
                     }
 
@@ -926,21 +914,20 @@ impl Service {
 
                 info!("Loading fork states");
 
-                let fork_states: Vec<_> = fork_states
+                let fork_states = fork_states
                     .into_iter()
                     .map(|map| {
                         map.into_iter()
-                            .filter_map(|(k, id)| {
+                            .map(|(k, id)| {
                                 services()
                                     .rooms
                                     .short
                                     .get_statekey_from_short(k)
                                     .map(|(ty, st_key)| ((ty.to_string().into(), st_key), id))
-                                    .ok()
                             })
-                            .collect::<StateMap<_>>()
+                            .collect::<Result<StateMap<_>>>()
                     })
-                    .collect();
+                    .collect::<Result<Vec<_>>>()?;
 
                 info!("Resolving state");
 
@@ -1197,13 +1184,13 @@ impl Service {
         room_version_id: &RoomVersionId,
         pub_key_map: &RwLock<BTreeMap<String, BTreeMap<String, Base64>>>,
         initial_set: Vec<Arc<EventId>>,
+        depth: UInt,
     ) -> Result<(
         Vec<Arc<EventId>>,
         HashMap<Arc<EventId>, (Arc<PduEvent>, BTreeMap<String, CanonicalJsonValue>)>,
     )> {
         let mut graph: HashMap<Arc<EventId>, _> = HashMap::new();
         let mut eventid_info = HashMap::new();
-        let mut todo_outlier_stack: Vec<Arc<EventId>> = initial_set;
 
         let first_pdu_in_room = services()
             .rooms
@@ -1211,6 +1198,21 @@ impl Service {
             .first_pdu_in_room(room_id)?
             .ok_or_else(|| Error::bad_database("Failed to find first pdu in db."))?;
 
+        let min_depth = min_backfill_depth(depth, services().globals.backfill_max_depth());
+
+        self.prefetch_missing_events(
+            origin,
+            create_event,
+            room_id,
+            room_version_id,
+            pub_key_map,
+            &initial_set,
+            min_depth,
+        )
+        .await?;
+
+        let mut todo_outlier_stack: Vec<Arc<EventId>> = initial_set;
+
         let mut amount = 0;
 
         while let Some(prev_event_id) = todo_outlier_stack.pop() {
@@ -1241,7 +1243,9 @@ impl Service {
                         .ok()
                         .flatten()
                 }) {
-                    if pdu.origin_server_ts > first_pdu_in_room.origin_server_ts {
+                    if pdu.origin_server_ts > first_pdu_in_room.origin_server_ts
+                        && pdu.depth >= min_depth
+                    {
                         amount += 1;
                         for prev_prev in &pdu.prev_events {
                             if !graph.contains_key(prev_prev) {
@@ -1254,7 +1258,7 @@ impl Service {
                             pdu.prev_events.iter().cloned().collect(),
                         );
                     } else {
-                        // Time based check failed
+                        // Time or depth based check failed
                         graph.insert(prev_event_id.clone(), HashSet::new());
                     }
 
@@ -1287,6 +1291,127 @@ impl Service {
         Ok((sorted, eventid_info))
     }
 
+    /// Asks `origin` for the events between our forward extremities and `latest_events` in a
+    /// single request and stores them as outliers, so fetch_unknown_prev_events finds them
+    /// locally instead of requesting them one by one.
+    ///
+    /// Events whose auth chain can't be completed are rejected here and never become part of the
+    /// graph.
+    async fn prefetch_missing_events(
+        &self,
+        origin: &ServerName,
+        create_event: &PduEvent,
+        room_id: &RoomId,
+        room_version_id: &RoomVersionId,
+        pub_key_map: &RwLock<BTreeMap<String, BTreeMap<String, Base64>>>,
+        latest_events: &[Arc<EventId>],
+        min_depth: UInt,
+    ) -> Result<()> {
+        let mut unknown_events = Vec::new();
+        for event_id in latest_events {
+            if services().rooms.timeline.get_pdu_json(event_id)?.is_none() {
+                unknown_events.push((**event_id).to_owned());
+            }
+        }
+
+        if unknown_events.is_empty() {
+            return Ok(());
+        }
+
+        let earliest_events = services()
+            .rooms
+            .state
+            .get_forward_extremities(room_id)?
+            .into_iter()
+            .map(|event_id| (*event_id).to_owned())
+            .collect();
+
+        let response = match services()
+            .sending
+            .send_federation_request(
+                origin,
+                get_missing_events::v1::Request {
+                    room_id: room_id.to_owned(),
+                    limit: services().globals.max_fetch_prev_events().into(),
+                    min_depth,
+                    earliest_events,
+                    latest_events: unknown_events,
+                },
+            )
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                // The events are fetched one by one instead
+                warn!("Failed to fetch missing events from {}: {}", origin, e);
+                return Ok(());
+            }
+        };
+
+        let mut events = Vec::new();
+        for pdu in &response.events {
+            match pdu::gen_event_id_canonical_json(pdu, room_version_id) {
+                Ok(event) => events.push(event),
+                Err(_) => warn!("Invalid missing event from {}", origin),
+            }
+        }
+
+        // Handle the oldest events first so their auth events are already known. Servers don't
+        // agree on the order of the response, so sort it ourselves
+        sort_by_depth(&mut events);
+
+        for (event_id, value) in events
+            .into_iter()
+            .take(services().globals.max_fetch_prev_events().into())
+        {
+            if services().rooms.timeline.get_pdu_json(&event_id)?.is_some() {
+                continue;
+            }
+
+            if let Err(e) = self
+                .handle_outlier_pdu(origin, create_event, &event_id, room_id, value, pub_key_map)
+                .await
+            {
+                warn!("Soft failing missing event {}: {}", event_id, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the signatures and the content hash of an event, after fetching the signing keys it
+    /// needs. Events this server created only get their content hash checked, see
+    /// `trust_own_signatures` in the config. Fails if a signature is wrong.
+    pub async fn verify_event(
+        &self,
+        event_id: &EventId,
+        value: &CanonicalJsonObject,
+        room_version_id: &RoomVersionId,
+        pub_key_map: &RwLock<BTreeMap<String, BTreeMap<String, Base64>>>,
+    ) -> Result<ruma::signatures::Verified> {
+        if services().globals.trust_own_signatures() && created_by_us(value, room_version_id) {
+            return Ok(if content_hash_matches(value) {
+                ruma::signatures::Verified::All
+            } else {
+                ruma::signatures::Verified::Signatures
+            });
+        }
+
+        // We go through all the signatures we see on the value and fetch the corresponding signing
+        // keys
+        self.fetch_required_signing_keys(value, pub_key_map).await?;
+
+        ruma::signatures::verify_event(
+            &pub_key_map.read().expect("RwLock is poisoned."),
+            value,
+            room_version_id,
+        )
+        .map_err(|e| {
+            warn!("Dropping bad event {}: {}", event_id, e);
+            Error::BadRequest(ErrorKind::InvalidParam, "Signature verification failed")
+        })
+    }
+
     #[tracing::instrument(skip_all)]
     pub(crate) async fn fetch_required_signing_keys(
         &self,
@@ -1464,6 +1589,14 @@ impl Service {
         }
 
         for server in services().globals.trusted_servers() {
+            let trusted_server_keys = match self.trusted_server_keys(server).await {
+                Ok(keys) => keys,
+                Err(e) => {
+                    warn!("Failed to get the keys of trusted server {}: {}", server, e);
+                    continue;
+                }
+            };
+
             trace!("Asking batch signing keys from trusted server {}", server);
             if let Ok(keys) = services()
                 .sending
@@ -1480,19 +1613,26 @@ impl Service {
                     .write()
                     .map_err(|_| Error::bad_database("RwLock is poisoned."))?;
                 for k in keys.server_keys {
-                    let k = match k.deserialize() {
-                        Ok(key) => key,
-                        Err(e) => {
+                    let k = match verify_relayed_keys(&k, server, &trusted_server_keys) {
+                        Some(key) => key,
+                        None => {
                             warn!(
-                                "Received error {} while fetching keys from trusted server {}",
-                                e, server
+                                "Received invalid or badly signed keys from trusted server {}",
+                                server
                             );
                             warn!("{}", k.into_json());
                             continue;
                         }
                     };
 
-                    // TODO: Check signature from trusted server?
+                    if !servers.contains_key(&k.server_name) {
+                        warn!(
+                            "Trusted server {} sent keys for {} that we didn't ask for",
+                            server, k.server_name
+                        );
+                        continue;
+                    }
+
                     servers.remove(&k.server_name);
 
                     let result = services()
@@ -1543,8 +1683,190 @@ impl Service {
         Ok(())
     }
 
-    /// Returns Ok if the acl allows the server
+    /// Completes the state of a room joined with a partial state (MSC3706). The servers that were
+    /// in the room are asked for the state at our join in turn, until one of them sends it.
+    #[tracing::instrument(skip(self))]
+    pub async fn resync_partial_state(&self, room_id: &RoomId) -> Result<()> {
+        let (join_event_id, servers) = match services().rooms.metadata.partial_state(room_id)? {
+            Some(partial_state) => partial_state,
+            None => return Ok(()),
+        };
+        let room_version_id = services().rooms.state.get_room_version(room_id)?;
+
+        for server in servers
+            .iter()
+            .filter(|server| !services().globals.server_is_ours(server))
+        {
+            let response = match services()
+                .sending
+                .send_federation_request(
+                    server,
+                    get_room_state::v1::Request {
+                        room_id: room_id.to_owned(),
+                        event_id: join_event_id.clone(),
+                    },
+                )
+                .await
+            {
+                Ok(response) => response,
+                Err(e) => {
+                    warn!(
+                        "Could not fetch the state of {} from {}: {}",
+                        room_id, server, e
+                    );
+                    continue;
+                }
+            };
+
+            let pub_key_map = RwLock::new(BTreeMap::new());
+
+            for pdu in &response.auth_chain {
+                match self
+                    .verify_state_event(pdu, &room_version_id, &pub_key_map)
+                    .await
+                {
+                    Ok((event_id, value)) => services()
+                        .rooms
+                        .outlier
+                        .add_pdu_outlier(&event_id, &value)?,
+                    Err(e) => warn!("Invalid auth event from {}: {}", server, e),
+                }
+            }
+
+            let mut members = Vec::new();
+            for pdu in &response.pdus {
+                let (event_id, value) = match self
+                    .verify_state_event(pdu, &room_version_id, &pub_key_map)
+                    .await
+                {
+                    Ok(t) => t,
+                    Err(e) => {
+                        warn!("Invalid state event from {}: {}", server, e);
+                        continue;
+                    }
+                };
+
+                services()
+                    .rooms
+                    .outlier
+                    .add_pdu_outlier(&event_id, &value)?;
+
+                if value.get("type").and_then(|t| t.as_str()) == Some("m.room.member") {
+                    if let Some(state_key) = value.get("state_key").and_then(|k| k.as_str()) {
+                        members.push((state_key.to_owned(), event_id));
+                    }
+                }
+            }
+
+            return self.complete_partial_state(room_id, members).await;
+        }
+
+        Err(Error::BadServerResponse(
+            "No server in the room sent the state at our join.",
+        ))
+    }
+
+    /// Adds the member events to the current state of a partial-state room, for users the state
+    /// has no membership of yet, and marks the state of the room complete. Memberships that
+    /// arrived since the join are newer and stay.
+    pub(crate) async fn complete_partial_state(
+        &self,
+        room_id: &RoomId,
+        members: Vec<(String, OwnedEventId)>,
+    ) -> Result<()> {
+        let mutex_state = Arc::clone(
+            services()
+                .globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(room_id.to_owned())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        let mut state = match services().rooms.state.get_room_shortstatehash(room_id)? {
+            Some(shortstatehash) => {
+                services()
+                    .rooms
+                    .state_accessor
+                    .state_full_ids(shortstatehash)
+                    .await?
+            }
+            None => HashMap::new(),
+        };
+
+        for (state_key, event_id) in members {
+            let shortstatekey = services()
+                .rooms
+                .short
+                .get_or_create_shortstatekey(&StateEventType::RoomMember, &state_key)?;
+            state
+                .entry(shortstatekey)
+                .or_insert_with(|| Arc::from(event_id));
+        }
+
+        let (shortstatehash, new, removed) = services().rooms.state_compressor.save_state(
+            room_id,
+            state
+                .into_iter()
+                .map(|(shortstatekey, event_id)| {
+                    services()
+                        .rooms
+                        .state_compressor
+                        .compress_state_event(shortstatekey, &event_id)
+                })
+                .collect::<Result<_>>()?,
+        )?;
+
+        services()
+            .rooms
+            .state
+            .force_state(room_id, shortstatehash, new, removed, &state_lock)
+            .await?;
+
+        services().rooms.metadata.unmark_partial_state(room_id)
+    }
+
+    /// Checks the signatures of an event from a state response and adds its event id.
+    async fn verify_state_event(
+        &self,
+        pdu: &RawJsonValue,
+        room_version_id: &RoomVersionId,
+        pub_key_map: &RwLock<BTreeMap<String, BTreeMap<String, Base64>>>,
+    ) -> Result<(OwnedEventId, CanonicalJsonObject)> {
+        let (event_id, mut value) = pdu::gen_event_id_canonical_json(pdu, room_version_id)
+            .map_err(|_| Error::BadServerResponse("Invalid PDU in state response."))?;
+
+        self.fetch_required_signing_keys(&value, pub_key_map)
+            .await?;
+
+        ruma::signatures::verify_event(
+            &*pub_key_map
+                .read()
+                .map_err(|_| Error::bad_database("RwLock is poisoned."))?,
+            &value,
+            room_version_id,
+        )
+        .map_err(|_| Error::BadServerResponse("Event failed verification."))?;
+
+        value.insert(
+            "event_id".to_owned(),
+            CanonicalJsonValue::String(event_id.as_str().to_owned()),
+        );
+
+        Ok((event_id, value))
+    }
+
+    /// Returns Ok if the acl allows the server. Local-only rooms deny all other servers.
     pub fn acl_check(&self, server_name: &ServerName, room_id: &RoomId) -> Result<()> {
+        if services().rooms.metadata.is_local_only(room_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "This room does not federate.",
+            ));
+        }
+
         let acl_event = match services().rooms.state_accessor.room_state_get(
             room_id,
             &StateEventType::RoomServerAcl,
@@ -1684,6 +2006,14 @@ impl Service {
         }
 
         for server in services().globals.trusted_servers() {
+            let trusted_server_keys = match self.trusted_server_keys(server).await {
+                Ok(keys) => keys,
+                Err(e) => {
+                    warn!("Failed to get the keys of trusted server {}: {}", server, e);
+                    continue;
+                }
+            };
+
             debug!("Asking {} for {}'s signing key", server, origin);
             if let Some(server_keys) = services()
                 .sending
@@ -1703,8 +2033,9 @@ impl Service {
                 .ok()
                 .map(|resp| {
                     resp.server_keys
-                        .into_iter()
-                        .filter_map(|e| e.deserialize().ok())
+                        .iter()
+                        .filter_map(|k| verify_relayed_keys(k, server, &trusted_server_keys))
+                        .filter(|k| *k.server_name == *origin)
                         .collect::<Vec<_>>()
                 })
             {
@@ -1738,4 +2069,760 @@ impl Service {
             "Failed to find public key for server",
         ))
     }
+
+    /// Returns the keys of a trusted server, fetching them from the server itself if we don't
+    /// know them yet. They are needed to check the keys it relays for other servers.
+    async fn trusted_server_keys(&self, server: &ServerName) -> Result<BTreeMap<String, Base64>> {
+        let mut keys = services().globals.signing_keys_for(server)?;
+
+        if keys.is_empty() {
+            let server_key = services()
+                .sending
+                .send_federation_request(server, get_server_keys::v2::Request::new())
+                .await?
+                .server_key
+                .deserialize()
+                .map_err(|_| Error::BadServerResponse("Invalid server keys."))?;
+
+            keys = services().globals.add_signing_key(server, server_key)?;
+        }
+
+        Ok(keys
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.key))
+            .collect())
+    }
+}
+
+/// Checks keys a trusted server relayed for another server. They have to be signed both by the
+/// server they belong to and by the trusted server, whose keys are `trusted_server_keys`.
+fn verify_relayed_keys(
+    raw: &Raw<ServerSigningKeys>,
+    trusted_server: &ServerName,
+    trusted_server_keys: &BTreeMap<String, Base64>,
+) -> Option<ServerSigningKeys> {
+    let keys = raw.deserialize().ok()?;
+    let mut object: CanonicalJsonObject = serde_json::from_str(raw.json().get()).ok()?;
+
+    let mut public_key_map = BTreeMap::new();
+    public_key_map.insert(
+        keys.server_name.to_string(),
+        keys.verify_keys
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.key.clone()))
+            .collect(),
+    );
+    public_key_map.insert(trusted_server.to_string(), trusted_server_keys.clone());
+
+    // Other servers might have signed the keys as well, but we can only check these two
+    match object.get_mut("signatures") {
+        Some(CanonicalJsonValue::Object(signatures)) => {
+            signatures.retain(|server, _| public_key_map.contains_key(server));
+            if signatures.len() != public_key_map.len() {
+                return None;
+            }
+        }
+        _ => return None,
+    }
+
+    ruma::signatures::verify_json(&public_key_map, &object)
+        .ok()
+        .map(|_| keys)
+}
+
+/// Whether this server created the event and only servers of ours signed it. Only rooms where
+/// the event id is the reference hash of the event qualify: if the hash is one of an event we
+/// created, the event is exactly what we signed, apart from the content the content hash covers.
+fn created_by_us(value: &CanonicalJsonObject, room_version_id: &RoomVersionId) -> bool {
+    if matches!(room_version_id, RoomVersionId::V1 | RoomVersionId::V2) {
+        return false;
+    }
+
+    let only_our_signatures = match value.get("signatures") {
+        Some(CanonicalJsonValue::Object(signatures)) => signatures.keys().all(|server| {
+            <&ServerName>::try_from(server.as_str())
+                .map_or(false, |server| services().globals.server_is_ours(server))
+        }),
+        _ => false,
+    };
+    if !only_our_signatures {
+        return false;
+    }
+
+    ruma::signatures::reference_hash(value, room_version_id)
+        .ok()
+        .and_then(|hash| EventId::parse(format!("${hash}")).ok())
+        .map_or(false, |event_id| {
+            services()
+                .globals
+                .created_events
+                .lock()
+                .unwrap()
+                .contains_key(&event_id)
+        })
+}
+
+/// Checks the sha256 content hash of an event, like `verify_event` does after the signatures.
+fn content_hash_matches(value: &CanonicalJsonObject) -> bool {
+    let mut object = value.clone();
+    object.remove("unsigned");
+    object.remove("signatures");
+
+    let expected = match object.remove("hashes") {
+        Some(CanonicalJsonValue::Object(hashes)) => match hashes.get("sha256") {
+            Some(CanonicalJsonValue::String(hash)) => hash.clone(),
+            _ => return false,
+        },
+        _ => return false,
+    };
+
+    let json = serde_json::to_string(&object).expect("canonical json is valid json");
+    let hash = ring::digest::digest(&ring::digest::SHA256, json.as_bytes());
+
+    base64::encode_config(hash, base64::STANDARD_NO_PAD) == expected
+}
+
+/// The lowest depth that is still fetched when backfilling behind an event at `depth`.
+fn min_backfill_depth(depth: UInt, max_depth: u64) -> UInt {
+    depth.saturating_sub(UInt::try_from(max_depth).unwrap_or(UInt::MAX))
+}
+
+/// Sorts events by their depth, oldest first. Events without a valid depth go last.
+fn sort_by_depth(events: &mut [(OwnedEventId, CanonicalJsonObject)]) {
+    events.sort_by_key(|(_, value)| match value.get("depth") {
+        Some(CanonicalJsonValue::Integer(depth)) => i64::from(*depth),
+        _ => i64::MAX,
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::testing;
+    use ruma::UserId;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn missing_events_are_handled_oldest_first() {
+        let event = |id: &str, depth: Option<i64>| {
+            let mut value = CanonicalJsonObject::new();
+            if let Some(depth) = depth {
+                value.insert(
+                    "depth".to_owned(),
+                    CanonicalJsonValue::Integer(depth.try_into().unwrap()),
+                );
+            }
+            (EventId::parse(id).unwrap(), value)
+        };
+
+        // In the order of a get_missing_events response that starts at the latest events
+        let mut events = vec![
+            event("$c:example.org", Some(12)),
+            event("$broken:example.org", None),
+            event("$a:example.org", Some(10)),
+            event("$b:example.org", Some(11)),
+        ];
+        sort_by_depth(&mut events);
+
+        let ids: Vec<_> = events.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(
+            ids,
+            [
+                "$a:example.org",
+                "$b:example.org",
+                "$c:example.org",
+                "$broken:example.org"
+            ]
+        );
+    }
+
+    #[test]
+    fn backfill_depth_is_bounded() {
+        assert_eq!(min_backfill_depth(uint!(1000), 100), uint!(900));
+        assert_eq!(min_backfill_depth(uint!(50), 100), uint!(0));
+        assert_eq!(min_backfill_depth(uint!(50), u64::MAX), uint!(0));
+    }
+
+    fn key_pair(version: &str) -> ruma::signatures::Ed25519KeyPair {
+        let document = ruma::signatures::Ed25519KeyPair::generate().unwrap();
+        ruma::signatures::Ed25519KeyPair::from_der(&document, version.to_owned()).unwrap()
+    }
+
+    /// Keys of example.org, signed by the given servers.
+    fn relayed_keys(
+        origin_key: &ruma::signatures::Ed25519KeyPair,
+        signers: &[(&str, &ruma::signatures::Ed25519KeyPair)],
+    ) -> Raw<ServerSigningKeys> {
+        let mut object: CanonicalJsonObject = serde_json::from_value(serde_json::json!({
+            "server_name": "example.org",
+            "verify_keys": {
+                "ed25519:origin": { "key": Base64::new(origin_key.public_key().to_vec()).encode() },
+            },
+            "old_verify_keys": {},
+            "valid_until_ts": 1_000_000,
+        }))
+        .unwrap();
+
+        for (server, key) in signers {
+            ruma::signatures::sign_json(server, *key, &mut object).unwrap();
+        }
+
+        Raw::from_json(serde_json::value::to_raw_value(&object).unwrap())
+    }
+
+    #[test]
+    fn relayed_keys_need_both_signatures() {
+        let origin_key = key_pair("origin");
+        let notary_key = key_pair("notary");
+        let notary = ServerName::parse("notary.example.com").unwrap();
+        let notary_keys = BTreeMap::from([(
+            "ed25519:notary".to_owned(),
+            Base64::new(notary_key.public_key().to_vec()),
+        )]);
+
+        let signed_by_both = relayed_keys(
+            &origin_key,
+            &[("example.org", &origin_key), (notary.as_str(), &notary_key)],
+        );
+        let keys = verify_relayed_keys(&signed_by_both, &notary, &notary_keys).unwrap();
+        assert_eq!(keys.server_name.as_str(), "example.org");
+
+        // The notary has to vouch for the keys
+        let only_self_signed = relayed_keys(&origin_key, &[("example.org", &origin_key)]);
+        assert!(verify_relayed_keys(&only_self_signed, &notary, &notary_keys).is_none());
+
+        // With a key that isn't the notary's
+        let forged = relayed_keys(
+            &origin_key,
+            &[
+                ("example.org", &origin_key),
+                (notary.as_str(), &key_pair("notary")),
+            ],
+        );
+        assert!(verify_relayed_keys(&forged, &notary, &notary_keys).is_none());
+    }
+
+    async fn verify(
+        value: &CanonicalJsonObject,
+        room_version_id: &RoomVersionId,
+        pub_key_map: &RwLock<BTreeMap<String, BTreeMap<String, Base64>>>,
+    ) -> Result<ruma::signatures::Verified> {
+        services()
+            .rooms
+            .event_handler
+            .verify_event(
+                &EventId::parse("$verified:conduit.test").unwrap(),
+                value,
+                room_version_id,
+                pub_key_map,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn only_events_we_created_skip_signature_checks() {
+        let alice = testing::user("own_events_alice").await;
+        let room_id = testing::room(&alice).await;
+        let event_id = testing::send_message(&alice, &room_id, "Hello").await;
+        let room_version_id = services().rooms.state.get_room_version(&room_id).unwrap();
+        let content = |body: &str| -> CanonicalJsonValue {
+            serde_json::from_value(json!({ "msgtype": "m.text", "body": body })).unwrap()
+        };
+
+        // The event as it comes back over federation
+        let mut ours = services()
+            .rooms
+            .timeline
+            .get_pdu_json(&event_id)
+            .unwrap()
+            .unwrap();
+        ours.remove("event_id");
+        ours.remove("unsigned");
+
+        // No signing keys are needed for it, but the content hash is still checked
+        let pub_key_map = RwLock::new(BTreeMap::new());
+        assert!(matches!(
+            verify(&ours, &room_version_id, &pub_key_map).await,
+            Ok(ruma::signatures::Verified::All)
+        ));
+        assert!(pub_key_map.read().unwrap().is_empty());
+        let mut tampered = ours.clone();
+        tampered.insert("content".to_owned(), content("Bye"));
+        assert!(matches!(
+            verify(&tampered, &room_version_id, &pub_key_map).await,
+            Ok(ruma::signatures::Verified::Signatures)
+        ));
+
+        // Any other change makes it an event we didn't create
+        let mut forged = ours.clone();
+        forged.insert(
+            "origin_server_ts".to_owned(),
+            CanonicalJsonValue::Integer(1_u32.into()),
+        );
+        assert!(!created_by_us(&forged, &room_version_id));
+
+        // Event ids of version 1 and 2 rooms aren't hashes of the event
+        assert!(!created_by_us(&ours, &RoomVersionId::V2));
+
+        // Federated events are always verified
+        let remote = testing::RemoteHomeserver::new("verify.remote.test").await;
+        let (_, raw) = remote.sign(
+            json!({
+                "room_id": room_id,
+                "sender": remote.user_id("bob"),
+                "origin": remote.server_name,
+                "origin_server_ts": 1,
+                "type": "m.room.message",
+                "content": { "msgtype": "m.text", "body": "Hi" },
+                "depth": 10,
+                "prev_events": [],
+                "auth_events": [],
+            }),
+            &room_version_id,
+        );
+        let theirs: CanonicalJsonObject = serde_json::from_str(raw.get()).unwrap();
+        assert!(!created_by_us(&theirs, &room_version_id));
+        assert!(matches!(
+            verify(&theirs, &room_version_id, &pub_key_map).await,
+            Ok(ruma::signatures::Verified::All)
+        ));
+        assert!(pub_key_map
+            .read()
+            .unwrap()
+            .contains_key(remote.server_name.as_str()));
+
+        let mut forged = theirs.clone();
+        forged.insert(
+            "depth".to_owned(),
+            CanonicalJsonValue::Integer(11_u32.into()),
+        );
+        assert!(verify(&forged, &room_version_id, &pub_key_map)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn completing_a_partial_state_adds_the_missing_members() {
+        let alice = testing::user("partial_state_resync_alice").await;
+        let bob = testing::user("partial_state_resync_bob").await;
+        let room_id = testing::room(&alice).await;
+        testing::invite(&alice, &bob, &room_id).await;
+        testing::join(&bob, &room_id).await;
+
+        let member_event = |user_id: &UserId| {
+            services()
+                .rooms
+                .state_accessor
+                .room_state_get(&room_id, &StateEventType::RoomMember, user_id.as_str())
+                .unwrap()
+                .map(|pdu| pdu.event_id.clone())
+        };
+        let alice_join = member_event(&alice).unwrap();
+        let bob_join = member_event(&bob).unwrap();
+
+        // The state of a partial state join, without bob's membership
+        {
+            let mutex_state = Arc::clone(
+                services()
+                    .globals
+                    .roomid_mutex_state
+                    .write()
+                    .unwrap()
+                    .entry(room_id.clone())
+                    .or_default(),
+            );
+            let state_lock = mutex_state.lock().await;
+
+            let shortstatehash = services()
+                .rooms
+                .state
+                .get_room_shortstatehash(&room_id)
+                .unwrap()
+                .unwrap();
+            let bob_key = services()
+                .rooms
+                .short
+                .get_shortstatekey(&StateEventType::RoomMember, bob.as_str())
+                .unwrap()
+                .unwrap();
+            let partial_state = services()
+                .rooms
+                .state_accessor
+                .state_full_ids(shortstatehash)
+                .await
+                .unwrap()
+                .into_iter()
+                .filter(|(shortstatekey, _)| *shortstatekey != bob_key)
+                .map(|(shortstatekey, event_id)| {
+                    services()
+                        .rooms
+                        .state_compressor
+                        .compress_state_event(shortstatekey, &event_id)
+                })
+                .collect::<Result<_>>()
+                .unwrap();
+            let (shortstatehash, new, removed) = services()
+                .rooms
+                .state_compressor
+                .save_state(&room_id, partial_state)
+                .unwrap();
+            services()
+                .rooms
+                .state
+                .force_state(&room_id, shortstatehash, new, removed, &state_lock)
+                .await
+                .unwrap();
+        }
+        assert_eq!(member_event(&bob), None);
+
+        let remote = ruma::server_name!("remote.test");
+        services()
+            .rooms
+            .metadata
+            .mark_partial_state(&room_id, &bob_join, &[remote])
+            .unwrap();
+        assert_eq!(
+            services().rooms.metadata.partial_state(&room_id).unwrap(),
+            Some(((*bob_join).to_owned(), vec![remote.to_owned()]))
+        );
+
+        // Alice's membership is in the state already and stays, even if the resynced state has
+        // another one
+        Service
+            .complete_partial_state(
+                &room_id,
+                vec![
+                    (bob.to_string(), (*bob_join).to_owned()),
+                    (alice.to_string(), (*bob_join).to_owned()),
+                ],
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(member_event(&bob), Some(bob_join));
+        assert_eq!(member_event(&alice), Some(alice_join));
+        assert!(!services()
+            .rooms
+            .metadata
+            .is_partial_state(&room_id)
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn keys_of_unreachable_servers_are_relayed_by_trusted_servers() {
+        use axum::{routing::get, Json, Router};
+
+        let origin = testing::remote_server("unreachable.remote.test", Router::new()).await;
+        let origin_key = key_pair("relayed");
+        let notary = ServerName::parse(testing::TRUSTED_SERVER).unwrap();
+        let notary_key = key_pair("notary");
+
+        let notary_keys = serde_json::to_value(crate::api::server_server::server_keys_json(
+            notary.clone(),
+            &notary_key,
+            BTreeMap::new(),
+            SystemTime::now(),
+            Duration::from_secs(3600),
+            None,
+        ))
+        .unwrap();
+
+        let mut relayed: CanonicalJsonObject = serde_json::from_value(serde_json::json!({
+            "server_name": origin,
+            "verify_keys": {
+                "ed25519:relayed": { "key": Base64::new(origin_key.public_key().to_vec()).encode() },
+            },
+            "old_verify_keys": {},
+            "valid_until_ts": MilliSecondsSinceUnixEpoch::now().get() + uint!(3_600_000),
+        }))
+        .unwrap();
+        ruma::signatures::sign_json(origin.as_str(), &origin_key, &mut relayed).unwrap();
+        ruma::signatures::sign_json(notary.as_str(), &notary_key, &mut relayed).unwrap();
+        let query_response = serde_json::json!({ "server_keys": [relayed] });
+
+        // The origin itself has no keys to offer, it only answers with 404
+        let query = get(move || {
+            let query_response = query_response.clone();
+            async move { Json(query_response) }
+        });
+        testing::remote_server(
+            notary.as_str(),
+            Router::new()
+                .route(
+                    "/_matrix/key/v2/server",
+                    get(move || {
+                        let notary_keys = notary_keys.clone();
+                        async move { Json(notary_keys) }
+                    }),
+                )
+                .route("/_matrix/key/v2/query/:server_name", query.clone())
+                .route("/_matrix/key/v2/query/:server_name/:valid_until", query),
+        )
+        .await;
+
+        let keys = Service
+            .fetch_signing_keys(&origin, vec!["ed25519:relayed".to_owned()])
+            .await
+            .unwrap();
+        assert_eq!(
+            keys["ed25519:relayed"].encode(),
+            Base64::new(origin_key.public_key().to_vec()).encode()
+        );
+    }
+
+    // Known state resolution vectors. The algorithm itself lives in ruma, these make sure the fork
+    // states and auth chains we build resolve to the same state as on other servers.
+
+    const ROOM: &str = "!room:example.org";
+    const ALICE: &str = "@alice:example.org";
+    const BOB: &str = "@bob:example.org";
+    const CHARLIE: &str = "@charlie:example.org";
+
+    fn state_pdu(
+        event_id: &str,
+        sender: &str,
+        kind: &str,
+        state_key: &str,
+        content: Value,
+        auth_events: &[&str],
+        origin_server_ts: u64,
+    ) -> Arc<PduEvent> {
+        let pdu = json!({
+            "event_id": event_id,
+            "room_id": ROOM,
+            "sender": sender,
+            "origin_server_ts": origin_server_ts,
+            "type": kind,
+            "content": content,
+            "state_key": state_key,
+            "prev_events": [],
+            "depth": 0,
+            "auth_events": auth_events,
+            "hashes": { "sha256": "" },
+        });
+
+        Arc::new(serde_json::from_str(&pdu.to_string()).expect("test event is valid"))
+    }
+
+    /// A public room created by Alice that Bob joined.
+    fn base_events(bob_power: i64) -> Vec<Arc<PduEvent>> {
+        vec![
+            state_pdu(
+                "$create",
+                ALICE,
+                "m.room.create",
+                "",
+                json!({ "creator": ALICE }),
+                &[],
+                1,
+            ),
+            state_pdu(
+                "$alice-join",
+                ALICE,
+                "m.room.member",
+                ALICE,
+                json!({ "membership": "join" }),
+                &["$create"],
+                2,
+            ),
+            state_pdu(
+                "$power",
+                ALICE,
+                "m.room.power_levels",
+                "",
+                json!({ "users": { ALICE: 100, BOB: bob_power } }),
+                &["$create", "$alice-join"],
+                3,
+            ),
+            state_pdu(
+                "$join-rules",
+                ALICE,
+                "m.room.join_rules",
+                "",
+                json!({ "join_rule": "public" }),
+                &["$create", "$alice-join", "$power"],
+                4,
+            ),
+            state_pdu(
+                "$bob-join",
+                BOB,
+                "m.room.member",
+                BOB,
+                json!({ "membership": "join" }),
+                &["$create", "$power", "$join-rules"],
+                5,
+            ),
+        ]
+    }
+
+    fn auth_chain<'a>(
+        events: &HashMap<Arc<EventId>, Arc<PduEvent>>,
+        starting_events: impl Iterator<Item = &'a Arc<EventId>>,
+    ) -> HashSet<Arc<EventId>> {
+        let mut chain = HashSet::new();
+        let mut todo: Vec<_> = starting_events.cloned().collect();
+
+        while let Some(event_id) = todo.pop() {
+            for auth_event in &events[&event_id].auth_events {
+                if chain.insert(auth_event.clone()) {
+                    todo.push(auth_event.clone());
+                }
+            }
+        }
+
+        chain
+    }
+
+    /// Resolves the states of the given forks the same way the event handler does and returns the
+    /// event ids of the resolved state by type and state key.
+    fn resolve(events: &[Arc<PduEvent>], forks: &[&[&str]]) -> HashMap<(String, String), String> {
+        let events: HashMap<_, _> = events
+            .iter()
+            .map(|pdu| (pdu.event_id.clone(), pdu.clone()))
+            .collect();
+
+        let fork_states: Vec<StateMap<Arc<EventId>>> = forks
+            .iter()
+            .map(|fork| {
+                fork.iter()
+                    .map(|event_id| {
+                        let pdu = &events[<&EventId>::try_from(*event_id).unwrap()];
+                        (
+                            (
+                                pdu.kind.to_string().into(),
+                                pdu.state_key.clone().expect("state event"),
+                            ),
+                            pdu.event_id.clone(),
+                        )
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let auth_chain_sets = fork_states
+            .iter()
+            .map(|state| auth_chain(&events, state.values()))
+            .collect();
+
+        state_res::resolve(&RoomVersionId::V6, &fork_states, auth_chain_sets, |id| {
+            events.get(id).cloned()
+        })
+        .expect("state resolution succeeds")
+        .into_iter()
+        .map(|((event_type, state_key), event_id)| {
+            ((event_type.to_string(), state_key), event_id.to_string())
+        })
+        .collect()
+    }
+
+    fn key(event_type: &str, state_key: &str) -> (String, String) {
+        (event_type.to_owned(), state_key.to_owned())
+    }
+
+    #[test]
+    fn ban_beats_concurrent_events_of_the_banned_user() {
+        let mut events = base_events(50);
+        events.push(state_pdu(
+            "$ban",
+            ALICE,
+            "m.room.member",
+            BOB,
+            json!({ "membership": "ban" }),
+            &["$create", "$alice-join", "$power", "$bob-join"],
+            10,
+        ));
+        events.push(state_pdu(
+            "$topic",
+            BOB,
+            "m.room.topic",
+            "",
+            json!({ "topic": "Bob's topic" }),
+            &["$create", "$power", "$bob-join"],
+            11,
+        ));
+
+        let resolved = resolve(
+            &events,
+            &[
+                &["$create", "$alice-join", "$power", "$join-rules", "$ban"],
+                &[
+                    "$create",
+                    "$alice-join",
+                    "$power",
+                    "$join-rules",
+                    "$bob-join",
+                    "$topic",
+                ],
+            ],
+        );
+
+        assert_eq!(resolved[&key("m.room.member", BOB)], "$ban");
+        assert!(!resolved.contains_key(&key("m.room.topic", "")));
+    }
+
+    #[test]
+    fn power_levels_of_equally_powerful_senders_are_ordered_by_timestamp() {
+        let mut events = base_events(100);
+        events.push(state_pdu(
+            "$power-alice",
+            ALICE,
+            "m.room.power_levels",
+            "",
+            json!({ "users": { ALICE: 100, BOB: 100, CHARLIE: 10 } }),
+            &["$create", "$alice-join", "$power"],
+            10,
+        ));
+        events.push(state_pdu(
+            "$power-bob",
+            BOB,
+            "m.room.power_levels",
+            "",
+            json!({ "users": { ALICE: 100, BOB: 100, CHARLIE: 20 } }),
+            &["$create", "$bob-join", "$power"],
+            20,
+        ));
+
+        let base = ["$create", "$alice-join", "$join-rules", "$bob-join"];
+        let fork_alice: Vec<_> = base.iter().copied().chain(["$power-alice"]).collect();
+        let fork_bob: Vec<_> = base.iter().copied().chain(["$power-bob"]).collect();
+
+        // The later event is applied last
+        let resolved = resolve(&events, &[&fork_alice[..], &fork_bob[..]]);
+        assert_eq!(resolved[&key("m.room.power_levels", "")], "$power-bob");
+
+        // The order of the forks doesn't matter
+        let resolved = resolve(&events, &[&fork_bob[..], &fork_alice[..]]);
+        assert_eq!(resolved[&key("m.room.power_levels", "")], "$power-bob");
+    }
+
+    #[test]
+    fn power_levels_with_equal_timestamps_are_ordered_by_event_id() {
+        let mut events = base_events(100);
+        events.push(state_pdu(
+            "$power-b",
+            ALICE,
+            "m.room.power_levels",
+            "",
+            json!({ "users": { ALICE: 100, BOB: 100, CHARLIE: 10 } }),
+            &["$create", "$alice-join", "$power"],
+            10,
+        ));
+        events.push(state_pdu(
+            "$power-a",
+            BOB,
+            "m.room.power_levels",
+            "",
+            json!({ "users": { ALICE: 100, BOB: 100, CHARLIE: 20 } }),
+            &["$create", "$bob-join", "$power"],
+            10,
+        ));
+
+        let base = ["$create", "$alice-join", "$join-rules", "$bob-join"];
+        let fork_a: Vec<_> = base.iter().copied().chain(["$power-a"]).collect();
+        let fork_b: Vec<_> = base.iter().copied().chain(["$power-b"]).collect();
+
+        // The greater event id is applied last
+        let resolved = resolve(&events, &[&fork_a[..], &fork_b[..]]);
+        assert_eq!(resolved[&key("m.room.power_levels", "")], "$power-b");
+    }
 }
@@ -0,0 +1,240 @@
+use std::collections::HashSet;
+
+use lru_cache::LruCache;
+use ruma::{OwnedDeviceId, OwnedRoomId, OwnedUserId, UserId};
+
+type ConnectionKey = (OwnedUserId, OwnedDeviceId);
+type RoomConnectionKey = (OwnedUserId, OwnedDeviceId, OwnedRoomId);
+type WaitingKey = (OwnedUserId, OwnedDeviceId, OwnedRoomId, u64); // ..., next_batch
+
+/// Member events sent to each sync connection, per room. Bounded, so connections that never come
+/// back are evicted. An evicted entry only means a member event may be sent again.
+pub struct SentMembers {
+    /// Members sent in a sync response, until the client confirms delivery by syncing with its
+    /// next_batch.
+    waiting: LruCache<WaitingKey, HashSet<OwnedUserId>>,
+    /// Members whose delivery was confirmed. Entries are a subset of what the database knows.
+    confirmed: LruCache<RoomConnectionKey, HashSet<OwnedUserId>>,
+}
+
+impl SentMembers {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            waiting: LruCache::new(capacity),
+            confirmed: LruCache::new(capacity),
+        }
+    }
+
+    pub fn was_sent(&mut self, key: &RoomConnectionKey, ll_user: &UserId) -> bool {
+        self.confirmed
+            .get_mut(key)
+            .map_or(false, |sent| sent.contains(ll_user))
+    }
+
+    /// Remembers a member the database knows was sent.
+    pub fn insert_confirmed(&mut self, key: RoomConnectionKey, ll_user: &UserId) {
+        self.extend_confirmed(key, HashSet::from([ll_user.to_owned()]));
+    }
+
+    pub fn mark_sent(&mut self, key: WaitingKey, ll_users: HashSet<OwnedUserId>) {
+        self.waiting.insert(key, ll_users);
+    }
+
+    /// The client synced with `since`, so the members sent in that response arrived. Returns them
+    /// so they can be written to the database.
+    pub fn confirm(&mut self, key: RoomConnectionKey, since: u64) -> Option<HashSet<OwnedUserId>> {
+        let (user_id, device_id, room_id) = key;
+        let ll_users =
+            self.waiting
+                .remove(&(user_id.clone(), device_id.clone(), room_id.clone(), since))?;
+        self.extend_confirmed((user_id, device_id, room_id), ll_users.clone());
+        Some(ll_users)
+    }
+
+    pub fn reset(&mut self, key: &RoomConnectionKey) {
+        self.confirmed.remove(key);
+    }
+
+    fn extend_confirmed(&mut self, key: RoomConnectionKey, ll_users: HashSet<OwnedUserId>) {
+        match self.confirmed.get_mut(&key) {
+            Some(confirmed) => confirmed.extend(ll_users),
+            None => {
+                self.confirmed.insert(key, ll_users);
+            }
+        }
+    }
+}
+
+/// Users whose device lists each sync connection was told about in `device_lists`.
+#[derive(Default)]
+struct ConnectionDeviceLists {
+    /// Users reported as changed and not as left since, confirmed by the client syncing with the
+    /// next_batch they were sent in. The client tracks their devices already.
+    tracked: HashSet<OwnedUserId>,
+    /// The next_batch of the last response, and the users reported as changed and left in it.
+    waiting: Option<(u64, HashSet<OwnedUserId>, HashSet<OwnedUserId>)>,
+}
+
+/// The device list delta of each sync connection. A user who newly shares an encrypted room with
+/// the sender only needs to be reported if the connection doesn't track them yet. Bounded, an
+/// evicted connection only means users may be reported again.
+pub struct SentDeviceLists {
+    connections: LruCache<ConnectionKey, ConnectionDeviceLists>,
+}
+
+impl SentDeviceLists {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            connections: LruCache::new(capacity),
+        }
+    }
+
+    /// Starts a sync of the connection with `since`, 0 for an initial sync. Returns the users the
+    /// connection already tracks.
+    pub fn tracked(&mut self, key: ConnectionKey, since: u64) -> HashSet<OwnedUserId> {
+        if since == 0 {
+            self.connections.remove(&key);
+            return HashSet::new();
+        }
+
+        let connection = match self.connections.get_mut(&key) {
+            Some(connection) => connection,
+            None => return HashSet::new(),
+        };
+
+        if let Some((next_batch, changed, left)) = connection.waiting.take() {
+            if next_batch == since {
+                connection.tracked.extend(changed);
+                connection.tracked.retain(|user_id| !left.contains(user_id));
+            } else {
+                // The client didn't get that response, it may sync with the same since again
+                connection.waiting = Some((next_batch, changed, left));
+            }
+        }
+
+        connection.tracked.clone()
+    }
+
+    /// Remembers what a sync response with `next_batch` reported in `device_lists`.
+    pub fn mark_sent(
+        &mut self,
+        key: ConnectionKey,
+        next_batch: u64,
+        changed: HashSet<OwnedUserId>,
+        left: HashSet<OwnedUserId>,
+    ) {
+        if self.connections.get_mut(&key).is_none() {
+            self.connections
+                .insert(key.clone(), ConnectionDeviceLists::default());
+        }
+        let connection = self.connections.get_mut(&key).expect("inserted above");
+        connection.waiting = Some((next_batch, changed, left));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::{device_id, room_id, user_id};
+
+    use super::*;
+
+    fn connection() -> ConnectionKey {
+        (
+            user_id!("@alice:example.org").to_owned(),
+            device_id!("DEVICE").to_owned(),
+        )
+    }
+
+    fn room_connection() -> RoomConnectionKey {
+        let (user_id, device_id) = connection();
+        (user_id, device_id, room_id!("!room:example.org").to_owned())
+    }
+
+    #[test]
+    fn members_are_not_resent_across_incremental_syncs() {
+        let mut sent = SentMembers::new(10);
+        let key = room_connection();
+        let bob = user_id!("@bob:example.org");
+
+        // The first sync sends Bob's member event and returns next_batch 10
+        assert!(!sent.was_sent(&key, bob));
+        let (user_id, device_id, room_id) = key.clone();
+        sent.mark_sent(
+            (user_id, device_id, room_id, 10),
+            HashSet::from([bob.to_owned()]),
+        );
+        // Until the client confirms it got the response, Bob would be sent again
+        assert!(!sent.was_sent(&key, bob));
+
+        // Syncing with since=10 confirms the delivery, the next syncs don't send Bob again
+        assert_eq!(
+            sent.confirm(key.clone(), 10),
+            Some(HashSet::from([bob.to_owned()]))
+        );
+        assert_eq!(sent.confirm(key.clone(), 10), None);
+        for _ in 0..2 {
+            assert!(sent.was_sent(&key, bob));
+        }
+
+        // An initial sync starts over
+        sent.reset(&key);
+        assert!(!sent.was_sent(&key, bob));
+    }
+
+    #[test]
+    fn unconfirmed_syncs_are_evicted() {
+        let mut sent = SentMembers::new(2);
+        let (user_id, device_id, room_id) = room_connection();
+
+        for next_batch in 0..5 {
+            sent.mark_sent(
+                (
+                    user_id.clone(),
+                    device_id.clone(),
+                    room_id.clone(),
+                    next_batch,
+                ),
+                HashSet::new(),
+            );
+        }
+
+        assert_eq!(sent.waiting.len(), 2);
+    }
+
+    #[test]
+    fn tracked_device_lists_are_not_resent() {
+        let mut sent = SentDeviceLists::new(10);
+        let (bob, carol) = (user_id!("@bob:example.org"), user_id!("@carol:example.org"));
+
+        assert!(sent.tracked(connection(), 0).is_empty());
+        sent.mark_sent(
+            connection(),
+            10,
+            HashSet::from([bob.to_owned(), carol.to_owned()]),
+            HashSet::new(),
+        );
+
+        // A sync that didn't confirm the response leaves it waiting
+        assert!(sent.tracked(connection(), 5).is_empty());
+        assert_eq!(
+            sent.tracked(connection(), 10),
+            HashSet::from([bob.to_owned(), carol.to_owned()])
+        );
+
+        // Users reported as left are no longer tracked
+        sent.mark_sent(
+            connection(),
+            20,
+            HashSet::new(),
+            HashSet::from([carol.to_owned()]),
+        );
+        assert_eq!(
+            sent.tracked(connection(), 20),
+            HashSet::from([bob.to_owned()])
+        );
+
+        // An initial sync starts over
+        assert!(sent.tracked(connection(), 0).is_empty());
+        assert!(sent.tracked(connection(), 20).is_empty());
+    }
+}
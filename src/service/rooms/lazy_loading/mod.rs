@@ -1,19 +1,18 @@
+mod connections;
 mod data;
-use std::{
-    collections::{HashMap, HashSet},
-    sync::Mutex,
-};
+use std::{collections::HashSet, sync::Mutex};
 
+pub use connections::{SentDeviceLists, SentMembers};
 pub use data::Data;
-use ruma::{DeviceId, OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, UserId};
+use ruma::{DeviceId, OwnedUserId, RoomId, UserId};
 
 use crate::Result;
 
 pub struct Service {
     pub db: &'static dyn Data,
 
-    pub lazy_load_waiting:
-        Mutex<HashMap<(OwnedUserId, OwnedDeviceId, OwnedRoomId, u64), HashSet<OwnedUserId>>>,
+    pub sent_members: Mutex<SentMembers>,
+    pub sent_device_lists: Mutex<SentDeviceLists>,
 }
 
 impl Service {
@@ -25,8 +24,25 @@ impl Service {
         room_id: &RoomId,
         ll_user: &UserId,
     ) -> Result<bool> {
-        self.db
-            .lazy_load_was_sent_before(user_id, device_id, room_id, ll_user)
+        let key = (user_id.to_owned(), device_id.to_owned(), room_id.to_owned());
+
+        // Active connections don't need to ask the database
+        if self.sent_members.lock().unwrap().was_sent(&key, ll_user) {
+            return Ok(true);
+        }
+
+        let sent = self
+            .db
+            .lazy_load_was_sent_before(user_id, device_id, room_id, ll_user)?;
+
+        if sent {
+            self.sent_members
+                .lock()
+                .unwrap()
+                .insert_confirmed(key, ll_user);
+        }
+
+        Ok(sent)
     }
 
     #[tracing::instrument(skip(self))]
@@ -38,7 +54,7 @@ impl Service {
         lazy_load: HashSet<OwnedUserId>,
         count: u64,
     ) {
-        self.lazy_load_waiting.lock().unwrap().insert(
+        self.sent_members.lock().unwrap().mark_sent(
             (
                 user_id.to_owned(),
                 device_id.to_owned(),
@@ -57,20 +73,18 @@ impl Service {
         room_id: &RoomId,
         since: u64,
     ) -> Result<()> {
-        if let Some(user_ids) = self.lazy_load_waiting.lock().unwrap().remove(&(
-            user_id.to_owned(),
-            device_id.to_owned(),
-            room_id.to_owned(),
+        let confirmed = self.sent_members.lock().unwrap().confirm(
+            (user_id.to_owned(), device_id.to_owned(), room_id.to_owned()),
             since,
-        )) {
+        );
+
+        if let Some(user_ids) = confirmed {
             self.db.lazy_load_confirm_delivery(
                 user_id,
                 device_id,
                 room_id,
                 &mut user_ids.iter().map(|u| &**u),
             )?;
-        } else {
-            // Ignore
         }
 
         Ok(())
@@ -83,6 +97,42 @@ impl Service {
         device_id: &DeviceId,
         room_id: &RoomId,
     ) -> Result<()> {
+        self.sent_members.lock().unwrap().reset(&(
+            user_id.to_owned(),
+            device_id.to_owned(),
+            room_id.to_owned(),
+        ));
         self.db.lazy_load_reset(user_id, device_id, room_id)
     }
+
+    /// Starts a sync of the device with `since`, 0 for an initial sync. Returns the users whose
+    /// device lists the device already tracks, so they don't need to be reported again.
+    pub fn device_lists_tracked(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        since: u64,
+    ) -> HashSet<OwnedUserId> {
+        self.sent_device_lists
+            .lock()
+            .unwrap()
+            .tracked((user_id.to_owned(), device_id.to_owned()), since)
+    }
+
+    /// Remembers what the sync response with `next_batch` reported in `device_lists`.
+    pub fn device_lists_mark_sent(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        next_batch: u64,
+        changed: HashSet<OwnedUserId>,
+        left: HashSet<OwnedUserId>,
+    ) {
+        self.sent_device_lists.lock().unwrap().mark_sent(
+            (user_id.to_owned(), device_id.to_owned()),
+            next_batch,
+            changed,
+            left,
+        );
+    }
 }
@@ -1,9 +1,25 @@
 use crate::Result;
-use ruma::{OwnedRoomId, RoomId};
+use ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedServerName, RoomId, ServerName};
 
 pub trait Data: Send + Sync {
     fn exists(&self, room_id: &RoomId) -> Result<bool>;
     fn iter_ids<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a>;
     fn is_disabled(&self, room_id: &RoomId) -> Result<bool>;
     fn disable_room(&self, room_id: &RoomId, disabled: bool) -> Result<()>;
+    /// Whether an admin made the room local-only.
+    fn is_marked_local_only(&self, room_id: &RoomId) -> Result<bool>;
+    fn mark_local_only(&self, room_id: &RoomId, local_only: bool) -> Result<()>;
+    /// The join event and the servers in the room of a room joined with a partial state.
+    fn partial_state(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<(OwnedEventId, Vec<OwnedServerName>)>>;
+    fn mark_partial_state(
+        &self,
+        room_id: &RoomId,
+        join_event_id: &EventId,
+        servers: &[&ServerName],
+    ) -> Result<()>;
+    fn unmark_partial_state(&self, room_id: &RoomId) -> Result<()>;
+    fn partial_state_rooms<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a>;
 }
@@ -1,9 +1,13 @@
 mod data;
 
 pub use data::Data;
-use ruma::{OwnedRoomId, RoomId};
+use ruma::{
+    events::StateEventType, EventId, OwnedEventId, OwnedRoomId, OwnedServerName, RoomId, ServerName,
+};
+use serde::Deserialize;
+use serde_json::value::RawValue as RawJsonValue;
 
-use crate::Result;
+use crate::{services, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -27,4 +31,97 @@ impl Service {
     pub fn disable_room(&self, room_id: &RoomId, disabled: bool) -> Result<()> {
         self.db.disable_room(room_id, disabled)
     }
+
+    /// Whether the events of a room must never leave this server, because it was created with
+    /// `m.federate: false` or an admin made it local-only.
+    pub fn is_local_only(&self, room_id: &RoomId) -> Result<bool> {
+        if self.db.is_marked_local_only(room_id)? {
+            return Ok(true);
+        }
+
+        Ok(services()
+            .rooms
+            .state_accessor
+            .room_state_get(room_id, &StateEventType::RoomCreate, "")?
+            .map_or(false, |create| !federates(&create.content)))
+    }
+
+    /// Makes a room local-only or lets it federate again. Rooms created with `m.federate: false`
+    /// stay local-only.
+    pub fn mark_local_only(&self, room_id: &RoomId, local_only: bool) -> Result<()> {
+        self.db.mark_local_only(room_id, local_only)
+    }
+
+    /// Whether the room was joined with a partial state (MSC3706), so its state has no members
+    /// yet apart from the heroes and this server's joined users.
+    pub fn is_partial_state(&self, room_id: &RoomId) -> Result<bool> {
+        Ok(self.db.partial_state(room_id)?.is_some())
+    }
+
+    /// Our join event of a partial-state room and the servers that were in the room when we
+    /// joined, which can send the full state.
+    pub fn partial_state(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<(OwnedEventId, Vec<OwnedServerName>)>> {
+        self.db.partial_state(room_id)
+    }
+
+    pub fn mark_partial_state(
+        &self,
+        room_id: &RoomId,
+        join_event_id: &EventId,
+        servers: &[&ServerName],
+    ) -> Result<()> {
+        self.db.mark_partial_state(room_id, join_event_id, servers)
+    }
+
+    /// Marks the state of the room complete after the members were resynced.
+    pub fn unmark_partial_state(&self, room_id: &RoomId) -> Result<()> {
+        self.db.unmark_partial_state(room_id)
+    }
+
+    pub fn partial_state_rooms<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a> {
+        self.db.partial_state_rooms()
+    }
+}
+
+/// Whether a room with this create event content federates. `m.federate` defaults to true.
+fn federates(create_content: &RawJsonValue) -> bool {
+    #[derive(Deserialize)]
+    struct ExtractFederate {
+        #[serde(rename = "m.federate", default = "default_federate")]
+        federate: bool,
+    }
+
+    fn default_federate() -> bool {
+        true
+    }
+
+    serde_json::from_str::<ExtractFederate>(create_content.get())
+        .map_or(true, |content| content.federate)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{json, value::to_raw_value};
+
+    use super::*;
+
+    #[test]
+    fn rooms_created_with_federate_false_are_local_only() {
+        let create = |content| to_raw_value(&content).unwrap();
+
+        assert!(!federates(&create(json!({
+            "creator": "@alice:example.org",
+            "m.federate": false,
+        }))));
+        assert!(federates(&create(json!({
+            "creator": "@alice:example.org",
+            "m.federate": true,
+        }))));
+        assert!(federates(&create(
+            json!({ "creator": "@alice:example.org" })
+        )));
+    }
 }
@@ -6,6 +6,9 @@ use ruma::{events::StateEventType, EventId, RoomId};
 pub trait Data: Send + Sync {
     fn get_or_create_shorteventid(&self, event_id: &EventId) -> Result<u64>;
 
+    /// Forgets the short id of an event that was deleted.
+    fn remove_shorteventid(&self, event_id: &EventId) -> Result<()>;
+
     fn get_shortstatekey(
         &self,
         event_type: &StateEventType,
@@ -15,6 +15,12 @@ impl Service {
         self.db.get_or_create_shorteventid(event_id)
     }
 
+    /// Forgets the short id of an event that was deleted. State events keep theirs, because the
+    /// state snapshots refer to them by it.
+    pub fn remove_shorteventid(&self, event_id: &EventId) -> Result<()> {
+        self.db.remove_shorteventid(event_id)
+    }
+
     pub fn get_shortstatekey(
         &self,
         event_type: &StateEventType,
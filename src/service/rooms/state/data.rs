@@ -15,9 +15,19 @@ pub trait Data: Send + Sync {
         _mutex_lock: &MutexGuard<'_, ()>, // Take mutex guard to make sure users get the room state mutex
     ) -> Result<()>;
 
+    /// Forgets the current state of a room, so it is loaded over federation on the next join.
+    fn remove_room_state(
+        &self,
+        room_id: &RoomId,
+        _mutex_lock: &MutexGuard<'_, ()>, // Take mutex guard to make sure users get the room state mutex
+    ) -> Result<()>;
+
     /// Associates a state with an event.
     fn set_event_state(&self, shorteventid: u64, shortstatehash: u64) -> Result<()>;
 
+    /// Forgets the state at an event.
+    fn remove_event_state(&self, shorteventid: u64) -> Result<()>;
+
     /// Returns all events we would send as the prev_events of the next event.
     fn get_forward_extremities(&self, room_id: &RoomId) -> Result<HashSet<Arc<EventId>>>;
 
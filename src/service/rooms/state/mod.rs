@@ -1,24 +1,44 @@
 mod data;
 use std::{
     collections::{HashMap, HashSet},
+    mem::size_of,
     sync::Arc,
 };
 
 pub use data::Data;
 use ruma::{
+    api::client::error::ErrorKind,
     events::{
-        room::{create::RoomCreateEventContent, member::MembershipState},
-        AnyStrippedStateEvent, RoomEventType, StateEventType,
+        room::{
+            canonical_alias::RoomCanonicalAliasEventContent,
+            create::RoomCreateEventContent,
+            encryption::RoomEncryptionEventContent,
+            guest_access::RoomGuestAccessEventContent,
+            history_visibility::RoomHistoryVisibilityEventContent,
+            join_rules::RoomJoinRulesEventContent,
+            member::{MembershipState, RoomMemberEventContent},
+            name::RoomNameEventContent,
+            pinned_events::RoomPinnedEventsEventContent,
+            power_levels::RoomPowerLevelsEventContent,
+            topic::RoomTopicEventContent,
+        },
+        AnyStateEventContent, AnyStrippedStateEvent, RoomEventType, StateEventType,
     },
     serde::Raw,
     state_res::{self, StateMap},
     EventId, OwnedEventId, RoomId, RoomVersionId, UserId,
 };
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
 use tokio::sync::MutexGuard;
 use tracing::warn;
 
-use crate::{services, utils::calculate_hash, Error, PduEvent, Result};
+use crate::{
+    service::pdu::PduBuilder,
+    services,
+    utils::{self, calculate_hash},
+    Error, PduEvent, Result,
+};
 
 use super::state_compressor::CompressedStateEvent;
 
@@ -92,8 +112,7 @@ impl Service {
 
         services().rooms.state_cache.update_joined_count(room_id)?;
 
-        self.db
-            .set_room_state(room_id, shortstatehash, state_lock)?;
+        self.set_room_state(room_id, shortstatehash, state_lock)?;
 
         Ok(())
     }
@@ -308,7 +327,80 @@ impl Service {
         shortstatehash: u64,
         mutex_lock: &MutexGuard<'_, ()>, // Take mutex guard to make sure users get the room state mutex
     ) -> Result<()> {
-        self.db.set_room_state(room_id, shortstatehash, mutex_lock)
+        let previous = self.get_room_shortstatehash(room_id)?;
+
+        self.db
+            .set_room_state(room_id, shortstatehash, mutex_lock)?;
+
+        let changed = self.changed_state_keys(previous, shortstatehash)?;
+        services()
+            .rooms
+            .state_accessor
+            .invalidate_current_state(room_id, changed.as_deref());
+
+        Ok(())
+    }
+
+    /// Forgets the current state of a room, e.g. after all of its events were purged.
+    #[tracing::instrument(skip(self))]
+    pub fn remove_room_state(
+        &self,
+        room_id: &RoomId,
+        mutex_lock: &MutexGuard<'_, ()>, // Take mutex guard to make sure users get the room state mutex
+    ) -> Result<()> {
+        self.db.remove_room_state(room_id, mutex_lock)?;
+        services()
+            .rooms
+            .state_accessor
+            .invalidate_current_state(room_id, None);
+
+        Ok(())
+    }
+
+    /// The state keys that differ between two state hashes, if the new state was saved as a diff
+    /// on top of the previous state. That's the case when a single event is appended to the
+    /// state, after state resolution the whole state may have changed.
+    fn changed_state_keys(
+        &self,
+        previous: Option<u64>,
+        shortstatehash: u64,
+    ) -> Result<Option<Vec<(StateEventType, String)>>> {
+        if previous == Some(shortstatehash) {
+            return Ok(Some(Vec::new()));
+        }
+
+        let layers = services()
+            .rooms
+            .state_compressor
+            .load_shortstatehash_info(shortstatehash)?;
+
+        let (added, removed) = match layers.as_slice() {
+            [.., (parent, ..), (_, _, added, removed)] if Some(*parent) == previous => {
+                (added, removed)
+            }
+            _ => return Ok(None),
+        };
+
+        // A replaced event is both removed and added
+        let shortstatekeys: HashSet<_> = added
+            .iter()
+            .chain(removed)
+            .map(|compressed| {
+                utils::u64_from_bytes(&compressed[0..size_of::<u64>()])
+                    .expect("bytes have right length")
+            })
+            .collect();
+
+        shortstatekeys
+            .into_iter()
+            .map(|shortstatekey| {
+                services()
+                    .rooms
+                    .short
+                    .get_statekey_from_short(shortstatekey)
+            })
+            .collect::<Result<_>>()
+            .map(Some)
     }
 
     /// Returns the room's version.
@@ -339,6 +431,15 @@ impl Service {
         self.db.get_room_shortstatehash(room_id)
     }
 
+    /// Forgets the state at an event, e.g. after the event was purged.
+    pub fn remove_event_state(&self, event_id: &EventId) -> Result<()> {
+        let shorteventid = services()
+            .rooms
+            .short
+            .get_or_create_shorteventid(event_id)?;
+        self.db.remove_event_state(shorteventid)
+    }
+
     pub fn get_forward_extremities(&self, room_id: &RoomId) -> Result<HashSet<Arc<EventId>>> {
         self.db.get_forward_extremities(room_id)
     }
@@ -418,4 +519,399 @@ impl Service {
             })
             .collect())
     }
+
+    /// Sends a state event of a local user into the room, after the checks besides the auth
+    /// rules: well-known content has to be valid, canonical aliases have to exist, pinned events
+    /// have to be in the room, member events follow the profile settings and encryption can't be
+    /// disabled.
+    pub async fn send_state_event(
+        &self,
+        sender_user: &UserId,
+        room_id: &RoomId,
+        event_type: &StateEventType,
+        json: &Raw<AnyStateEventContent>,
+        state_key: String,
+    ) -> Result<Arc<EventId>> {
+        validate_state_content(event_type, json)?;
+
+        // TODO: Review this check, error if event is unparsable, use event type, allow alias if it
+        // previously existed
+        if let Ok(canonical_alias) =
+            serde_json::from_str::<RoomCanonicalAliasEventContent>(json.json().get())
+        {
+            let mut aliases = canonical_alias.alt_aliases.clone();
+
+            if let Some(alias) = canonical_alias.alias {
+                aliases.push(alias);
+            }
+
+            for alias in aliases {
+                if !services().globals.server_is_ours(alias.server_name())
+                    || services()
+                        .rooms
+                        .alias
+                        .resolve_local_alias(&alias)?
+                        .filter(|room| room == room_id) // Make sure it's the right room
+                        .is_none()
+                {
+                    return Err(Error::BadRequest(
+                        ErrorKind::Forbidden,
+                        "You are only allowed to send canonical_alias \
+                        events when it's aliases already exists",
+                    ));
+                }
+            }
+        }
+
+        if *event_type == StateEventType::RoomPinnedEvents {
+            let pinned_events = serde_json::from_str::<RoomPinnedEventsEventContent>(
+                json.json().get(),
+            )
+            .map_err(|_| {
+                Error::BadRequest(ErrorKind::BadJson, "Invalid m.room.pinned_events content.")
+            })?;
+
+            let current = services()
+                .rooms
+                .state_accessor
+                .room_state_get(room_id, &StateEventType::RoomPinnedEvents, "")?
+                .and_then(|pdu| {
+                    serde_json::from_str::<RoomPinnedEventsEventContent>(pdu.content.get()).ok()
+                });
+
+            check_pinned_events(&pinned_events, current.as_ref(), |event_id| {
+                Ok(services()
+                    .rooms
+                    .timeline
+                    .get_pdu(event_id)?
+                    .map_or(false, |pdu| &pdu.room_id == room_id))
+            })?;
+        }
+
+        if *event_type == StateEventType::RoomMember && state_key == sender_user.as_str() {
+            let member = serde_json::from_str::<RoomMemberEventContent>(json.json().get())
+                .map_err(|_| {
+                    Error::BadRequest(ErrorKind::BadJson, "Invalid m.room.member content.")
+                })?;
+            let current = services()
+                .rooms
+                .state_accessor
+                .room_state_get(room_id, &StateEventType::RoomMember, sender_user.as_str())?
+                .and_then(|pdu| {
+                    serde_json::from_str::<RoomMemberEventContent>(pdu.content.get()).ok()
+                });
+
+            check_profile_changes(
+                &member,
+                current.as_ref(),
+                services().globals.allow_displayname_change(),
+                services().globals.allow_avatar_change(),
+            )?;
+        }
+
+        if *event_type == StateEventType::RoomEncryption
+            && services().globals.forbid_disabling_encryption()
+            && services()
+                .rooms
+                .state_accessor
+                .room_state_get(room_id, &StateEventType::RoomEncryption, "")?
+                .is_some()
+            && serde_json::from_str::<RoomEncryptionEventContent>(json.json().get()).is_err()
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Encryption can't be disabled once it is enabled.",
+            ));
+        }
+
+        let mutex_state = Arc::clone(
+            services()
+                .globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(room_id.to_owned())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        let event_id = services().rooms.timeline.build_and_append_pdu(
+            PduBuilder {
+                event_type: event_type.to_string().into(),
+                content: serde_json::from_str(json.json().get()).expect("content is valid json"),
+                unsigned: None,
+                state_key: Some(state_key),
+                redacts: None,
+            },
+            sender_user,
+            room_id,
+            &state_lock,
+        )?;
+
+        Ok(event_id)
+    }
+}
+
+/// Makes sure the content of well-known state events can be understood, so clients can't brick
+/// rooms with bad state. Other event types are passed through.
+pub fn validate_state_content(
+    event_type: &StateEventType,
+    json: &Raw<AnyStateEventContent>,
+) -> Result<()> {
+    match event_type {
+        StateEventType::RoomPowerLevels => {
+            parse_content::<RoomPowerLevelsEventContent>(json)?;
+            check_power_levels(&parse_content::<Value>(json)?)?;
+        }
+        StateEventType::RoomJoinRules => {
+            parse_content::<RoomJoinRulesEventContent>(json)?;
+            check_known_value(
+                json,
+                "join_rule",
+                &[
+                    "public",
+                    "invite",
+                    "knock",
+                    "private",
+                    "restricted",
+                    "knock_restricted",
+                ],
+                "Unknown join rule.",
+            )?;
+        }
+        StateEventType::RoomHistoryVisibility => {
+            parse_content::<RoomHistoryVisibilityEventContent>(json)?;
+            check_known_value(
+                json,
+                "history_visibility",
+                &["invited", "joined", "shared", "world_readable"],
+                "Unknown history visibility.",
+            )?;
+        }
+        StateEventType::RoomGuestAccess => {
+            parse_content::<RoomGuestAccessEventContent>(json)?;
+            check_known_value(
+                json,
+                "guest_access",
+                &["can_join", "forbidden"],
+                "Unknown guest access.",
+            )?;
+        }
+        StateEventType::RoomName => {
+            parse_content::<RoomNameEventContent>(json)?;
+        }
+        StateEventType::RoomTopic => {
+            parse_content::<RoomTopicEventContent>(json)?;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn parse_content<T: DeserializeOwned>(json: &Raw<AnyStateEventContent>) -> Result<T> {
+    serde_json::from_str(json.json().get()).map_err(|_| {
+        Error::BadRequest(
+            ErrorKind::BadJson,
+            "Invalid content for this state event type.",
+        )
+    })
+}
+
+/// Rejects values of `field` that aren't in `known`, even if ruma would accept them as custom.
+fn check_known_value(
+    json: &Raw<AnyStateEventContent>,
+    field: &str,
+    known: &[&str],
+    error: &'static str,
+) -> Result<()> {
+    let content = parse_content::<Value>(json)?;
+    match content.get(field).and_then(Value::as_str) {
+        Some(value) if known.contains(&value) => Ok(()),
+        _ => Err(Error::BadRequest(ErrorKind::InvalidParam, error)),
+    }
+}
+
+/// Power levels have to be integers, strings and floats are only accepted from old rooms.
+fn check_power_levels(content: &Value) -> Result<()> {
+    let invalid = || Error::BadRequest(ErrorKind::BadJson, "Power levels must be integers.");
+    let content = content.as_object().ok_or_else(invalid)?;
+
+    for (key, value) in content {
+        let levels = match key.as_str() {
+            "ban" | "events_default" | "invite" | "kick" | "redact" | "state_default"
+            | "users_default" => vec![value],
+            "events" | "users" | "notifications" => {
+                value.as_object().ok_or_else(invalid)?.values().collect()
+            }
+            _ => continue,
+        };
+
+        if !levels.into_iter().all(|level| level.is_i64()) {
+            return Err(invalid());
+        }
+    }
+
+    Ok(())
+}
+
+/// Makes sure newly pinned events are events of the room, so clients don't pin dangling
+/// references. Events that are already pinned are not checked again, they may be gone from the
+/// database and the pins would otherwise not be editable anymore.
+fn check_pinned_events(
+    content: &RoomPinnedEventsEventContent,
+    current: Option<&RoomPinnedEventsEventContent>,
+    in_room: impl Fn(&EventId) -> Result<bool>,
+) -> Result<()> {
+    for event_id in &content.pinned {
+        if current.map_or(false, |current| current.pinned.contains(event_id)) {
+            continue;
+        }
+
+        if !in_room(event_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Pinned event does not exist in this room.",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Member events of the sender can't change their display name or avatar when the server doesn't
+/// allow changing them through the profile endpoints either.
+fn check_profile_changes(
+    member: &RoomMemberEventContent,
+    current: Option<&RoomMemberEventContent>,
+    allow_displayname_change: bool,
+    allow_avatar_change: bool,
+) -> Result<()> {
+    if !allow_displayname_change
+        && member.displayname != current.and_then(|current| current.displayname.clone())
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Changing display names is disabled on this server.",
+        ));
+    }
+
+    if !allow_avatar_change
+        && member.avatar_url != current.and_then(|current| current.avatar_url.clone())
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Changing avatars is disabled on this server.",
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::{event_id, mxc_uri};
+    use serde_json::json;
+
+    use super::*;
+
+    fn raw(content: Value) -> Raw<AnyStateEventContent> {
+        Raw::from_json(serde_json::value::to_raw_value(&content).unwrap())
+    }
+
+    #[test]
+    fn malformed_power_levels_are_rejected() {
+        let valid = raw(json!({ "ban": 50, "users": { "@alice:example.org": 100 } }));
+        assert!(validate_state_content(&StateEventType::RoomPowerLevels, &valid).is_ok());
+
+        for content in [
+            json!({ "ban": 50.5 }),
+            json!({ "kick": "fifty" }),
+            json!({ "users": { "@alice:example.org": "100" } }),
+            json!({ "events": [] }),
+        ] {
+            assert!(matches!(
+                validate_state_content(&StateEventType::RoomPowerLevels, &raw(content)),
+                Err(Error::BadRequest(ErrorKind::BadJson, _))
+            ));
+        }
+    }
+
+    #[test]
+    fn unknown_join_rules_are_rejected() {
+        let valid = raw(json!({ "join_rule": "invite" }));
+        assert!(validate_state_content(&StateEventType::RoomJoinRules, &valid).is_ok());
+
+        let invalid = raw(json!({ "join_rule": "everyone" }));
+        assert!(matches!(
+            validate_state_content(&StateEventType::RoomJoinRules, &invalid),
+            Err(Error::BadRequest(ErrorKind::InvalidParam, _))
+        ));
+
+        let custom = raw(json!({ "join_rule": 1 }));
+        assert!(validate_state_content(&"org.example.custom".into(), &custom).is_ok());
+    }
+
+    #[test]
+    fn only_events_in_the_room_can_be_pinned() {
+        let known = event_id!("$known:example.org");
+        let in_room = |event_id: &EventId| Ok(event_id == known);
+
+        let valid = RoomPinnedEventsEventContent::new(vec![known.to_owned()]);
+        assert!(check_pinned_events(&valid, None, in_room).is_ok());
+
+        let invalid = RoomPinnedEventsEventContent::new(vec![
+            known.to_owned(),
+            event_id!("$unknown:example.org").to_owned(),
+        ]);
+        assert!(matches!(
+            check_pinned_events(&invalid, None, in_room),
+            Err(Error::BadRequest(ErrorKind::InvalidParam, _))
+        ));
+    }
+
+    #[test]
+    fn member_events_follow_the_profile_settings() {
+        let mut current = RoomMemberEventContent::new(MembershipState::Join);
+        current.displayname = Some("Alice".to_owned());
+        current.avatar_url = Some(mxc_uri!("mxc://example.org/alice").to_owned());
+
+        let mut renamed = current.clone();
+        renamed.displayname = Some("Mallory".to_owned());
+        let mut new_avatar = current.clone();
+        new_avatar.avatar_url = Some(mxc_uri!("mxc://example.org/mallory").to_owned());
+
+        assert!(check_profile_changes(&current, Some(&current), false, false).is_ok());
+        assert!(check_profile_changes(&renamed, Some(&current), true, false).is_ok());
+        assert!(matches!(
+            check_profile_changes(&renamed, Some(&current), false, true),
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+        assert!(matches!(
+            check_profile_changes(&new_avatar, Some(&current), true, false),
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+        // A user without a member event can't pick a name either
+        assert!(matches!(
+            check_profile_changes(&renamed, None, false, true),
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+    }
+
+    #[test]
+    fn pins_of_unknown_events_stay_editable() {
+        let known = event_id!("$known:example.org");
+        let unknown = event_id!("$unknown:example.org");
+        let in_room = |event_id: &EventId| Ok(event_id == known);
+
+        let current = RoomPinnedEventsEventContent::new(vec![unknown.to_owned()]);
+        let updated = RoomPinnedEventsEventContent::new(vec![unknown.to_owned(), known.to_owned()]);
+        assert!(check_pinned_events(&updated, Some(&current), in_room).is_ok());
+
+        let current = RoomPinnedEventsEventContent::new(vec![known.to_owned()]);
+        assert!(matches!(
+            check_pinned_events(&updated, Some(&current), in_room),
+            Err(Error::BadRequest(ErrorKind::InvalidParam, _))
+        ));
+    }
 }
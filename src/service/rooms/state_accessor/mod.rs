@@ -1,13 +1,106 @@
 mod data;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 pub use data::Data;
-use ruma::{events::StateEventType, EventId, RoomId};
+use lru_cache::LruCache;
+use ruma::{
+    events::{
+        room::{
+            history_visibility::{HistoryVisibility, RoomHistoryVisibilityEventContent},
+            member::{MembershipState, RoomMemberEventContent},
+        },
+        StateEventType,
+    },
+    EventId, OwnedRoomId, OwnedUserId, RoomId, ServerName, UserId,
+};
 
-use crate::{PduEvent, Result};
+use crate::{services, Error, PduEvent, Result};
+
+type StateKey = (StateEventType, String);
+
+/// Current power levels and member events of rooms, which are read by every event that is sent.
+/// Entries are removed when the current state of their room changes.
+pub struct CurrentStateCache {
+    rooms: LruCache<OwnedRoomId, HashMap<StateKey, Option<Arc<PduEvent>>>>,
+    /// Increased after every change of the current state, so an event read before the change
+    /// isn't cached.
+    generation: u64,
+}
+
+impl CurrentStateCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            rooms: LruCache::new(capacity),
+            generation: 0,
+        }
+    }
+
+    /// Only power levels and member events are cached.
+    fn caches(event_type: &StateEventType) -> bool {
+        matches!(
+            event_type,
+            StateEventType::RoomPowerLevels | StateEventType::RoomMember
+        )
+    }
+
+    /// `None` if the event is not cached, `Some(None)` if the room has no such state.
+    fn get(&mut self, room_id: &RoomId, key: &StateKey) -> Option<Option<Arc<PduEvent>>> {
+        self.rooms
+            .get_mut(room_id)
+            .and_then(|state| state.get(key).cloned())
+    }
+
+    /// Caches an event read from the database, unless the current state changed since
+    /// `generation`.
+    fn insert(
+        &mut self,
+        room_id: &RoomId,
+        key: StateKey,
+        pdu: Option<Arc<PduEvent>>,
+        generation: u64,
+    ) {
+        if self.generation != generation {
+            return;
+        }
+
+        match self.rooms.get_mut(room_id) {
+            Some(state) => {
+                state.insert(key, pdu);
+            }
+            None => {
+                self.rooms
+                    .insert(room_id.to_owned(), HashMap::from([(key, pdu)]));
+            }
+        }
+    }
+
+    fn invalidate(&mut self, room_id: &RoomId, changed: Option<&[StateKey]>) {
+        self.generation += 1;
+
+        match changed {
+            Some(changed) => {
+                if let Some(state) = self.rooms.get_mut(room_id) {
+                    for key in changed {
+                        state.remove(key);
+                    }
+                }
+            }
+            None => {
+                self.rooms.remove(room_id);
+            }
+        }
+    }
+}
 
 pub struct Service {
     pub db: &'static dyn Data,
+    pub current_state_cache: Mutex<CurrentStateCache>,
+    /// The state at a state hash never changes, so these are never invalidated
+    pub history_visibility_cache: Mutex<LruCache<u64, HistoryVisibility>>,
+    pub membership_cache: Mutex<LruCache<(u64, OwnedUserId), Option<MembershipState>>>,
 }
 
 impl Service {
@@ -51,6 +144,223 @@ impl Service {
         self.db.pdu_shortstatehash(event_id)
     }
 
+    /// Whether a user is allowed to see an event, based on the history visibility of the room and
+    /// the membership of the user at the time of the event.
+    #[tracing::instrument(skip(self))]
+    pub fn user_can_see_event(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<bool> {
+        // Without the state at the event we can't know who was allowed to see it
+        let (shortstatehash, count) = match (
+            self.pdu_shortstatehash(event_id)?,
+            services().rooms.timeline.get_pdu_count(event_id)?,
+        ) {
+            (Some(shortstatehash), Some(count)) => (shortstatehash, count),
+            _ => return Ok(false),
+        };
+
+        self.user_can_see_state_at(user_id, room_id, shortstatehash, count)
+    }
+
+    /// Whether a user is allowed to see the state of a room at a point in its history, based on
+    /// the history visibility and the membership of the user at that point. `count` is the pdu
+    /// count of that point, for `shared` rooms the user can also see it if they joined later.
+    #[tracing::instrument(skip(self))]
+    pub fn user_can_see_state_at(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        shortstatehash: u64,
+        count: u64,
+    ) -> Result<bool> {
+        let history_visibility = self.history_visibility(shortstatehash)?;
+        let membership = self.membership(shortstatehash, user_id)?;
+
+        let joined_later = history_visibility == HistoryVisibility::Shared
+            && membership != Some(MembershipState::Join)
+            && self.joined_after(user_id, room_id, count)?;
+
+        Ok(visibility_allows(
+            &history_visibility,
+            membership.as_ref(),
+            joined_later,
+        ))
+    }
+
+    /// Whether the user joined the room at any point after the pdu with the given count. Walks
+    /// back through the member events of the user, newest first.
+    fn joined_after(&self, user_id: &UserId, room_id: &RoomId, count: u64) -> Result<bool> {
+        let mut member_event =
+            self.room_state_get(room_id, &StateEventType::RoomMember, user_id.as_str())?;
+
+        while let Some(pdu) = member_event {
+            match services().rooms.timeline.get_pdu_count(&pdu.event_id)? {
+                Some(pdu_count) if pdu_count > count => {}
+                _ => return Ok(false),
+            }
+
+            let membership = serde_json::from_str::<RoomMemberEventContent>(pdu.content.get())
+                .map_err(|_| Error::bad_database("Invalid member event in database."))?
+                .membership;
+            if membership == MembershipState::Join {
+                return Ok(true);
+            }
+
+            // The state at an event is the state before it, so this is the previous membership
+            member_event = match self.pdu_shortstatehash(&pdu.event_id)? {
+                Some(shortstatehash) => self.state_get(
+                    shortstatehash,
+                    &StateEventType::RoomMember,
+                    user_id.as_str(),
+                )?,
+                None => None,
+            };
+        }
+
+        Ok(false)
+    }
+
+    /// Whether a server is allowed to see an event. This is the case if any of its users could see
+    /// it when it was sent, current memberships don't count.
+    #[tracing::instrument(skip(self))]
+    pub fn server_can_see_event(
+        &self,
+        server_name: &ServerName,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<bool> {
+        let shortstatehash = match self.pdu_shortstatehash(event_id)? {
+            Some(shortstatehash) => shortstatehash,
+            None => return Ok(false),
+        };
+
+        let history_visibility = self.history_visibility(shortstatehash)?;
+        match history_visibility {
+            HistoryVisibility::WorldReadable => return Ok(true),
+            // Shared history is visible to servers that had a user in the room at some point
+            HistoryVisibility::Shared => {
+                return Ok(services()
+                    .rooms
+                    .state_cache
+                    .room_useroncejoined(room_id)
+                    .filter_map(|r| r.ok())
+                    .any(|user_id| user_id.server_name() == server_name))
+            }
+            _ => {}
+        }
+
+        let users = services()
+            .rooms
+            .state_cache
+            .room_useroncejoined(room_id)
+            .chain(services().rooms.state_cache.room_members_invited(room_id))
+            .filter_map(|r| r.ok())
+            .filter(|user_id| user_id.server_name() == server_name);
+
+        for user_id in users {
+            let membership_at_event = self.membership(shortstatehash, &user_id)?;
+            if visibility_allows(&history_visibility, membership_at_event.as_ref(), false) {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Whether anyone, even users that are not logged in, can see an event.
+    #[tracing::instrument(skip(self))]
+    pub fn world_can_see_event(&self, event_id: &EventId) -> Result<bool> {
+        Ok(match self.pdu_shortstatehash(event_id)? {
+            Some(shortstatehash) => {
+                self.history_visibility(shortstatehash)? == HistoryVisibility::WorldReadable
+            }
+            None => false,
+        })
+    }
+
+    /// Whether the current history visibility of the room allows anyone to peek into it.
+    #[tracing::instrument(skip(self))]
+    pub fn is_world_readable(&self, room_id: &RoomId) -> Result<bool> {
+        Ok(
+            match services().rooms.state.get_room_shortstatehash(room_id)? {
+                Some(shortstatehash) => {
+                    self.history_visibility(shortstatehash)? == HistoryVisibility::WorldReadable
+                }
+                None => false,
+            },
+        )
+    }
+
+    /// Members can see the current state of a room, everyone else only if it is world readable.
+    #[tracing::instrument(skip(self))]
+    pub fn user_can_see_state(&self, user_id: Option<&UserId>, room_id: &RoomId) -> Result<bool> {
+        if let Some(user_id) = user_id {
+            if services().rooms.state_cache.is_joined(user_id, room_id)? {
+                return Ok(true);
+            }
+        }
+
+        self.is_world_readable(room_id)
+    }
+
+    fn membership(&self, shortstatehash: u64, user_id: &UserId) -> Result<Option<MembershipState>> {
+        let key = (shortstatehash, user_id.to_owned());
+        if let Some(membership) = self.membership_cache.lock().unwrap().get_mut(&key) {
+            return Ok(membership.clone());
+        }
+
+        let membership = self
+            .state_get(
+                shortstatehash,
+                &StateEventType::RoomMember,
+                user_id.as_str(),
+            )?
+            .map(|event| {
+                serde_json::from_str(event.content.get())
+                    .map(|content: RoomMemberEventContent| content.membership)
+                    .map_err(|_| Error::bad_database("Invalid member event in database."))
+            })
+            .transpose()?;
+
+        self.membership_cache
+            .lock()
+            .unwrap()
+            .insert(key, membership.clone());
+
+        Ok(membership)
+    }
+
+    fn history_visibility(&self, shortstatehash: u64) -> Result<HistoryVisibility> {
+        if let Some(history_visibility) = self
+            .history_visibility_cache
+            .lock()
+            .unwrap()
+            .get_mut(&shortstatehash)
+        {
+            return Ok(history_visibility.clone());
+        }
+
+        let history_visibility = self
+            .state_get(shortstatehash, &StateEventType::RoomHistoryVisibility, "")?
+            .map_or(Ok(HistoryVisibility::Shared), |event| {
+                serde_json::from_str(event.content.get())
+                    .map(|content: RoomHistoryVisibilityEventContent| content.history_visibility)
+                    .map_err(|_| {
+                        Error::bad_database("Invalid history visibility event in database.")
+                    })
+            })?;
+
+        self.history_visibility_cache
+            .lock()
+            .unwrap()
+            .insert(shortstatehash, history_visibility.clone());
+
+        Ok(history_visibility)
+    }
+
     /// Returns the full room state.
     #[tracing::instrument(skip(self))]
     pub async fn room_state_full(
@@ -79,6 +389,198 @@ impl Service {
         event_type: &StateEventType,
         state_key: &str,
     ) -> Result<Option<Arc<PduEvent>>> {
-        self.db.room_state_get(room_id, event_type, state_key)
+        if !CurrentStateCache::caches(event_type) {
+            return self.db.room_state_get(room_id, event_type, state_key);
+        }
+
+        let key = (event_type.clone(), state_key.to_owned());
+
+        let generation = {
+            let mut cache = self.current_state_cache.lock().unwrap();
+            if let Some(pdu) = cache.get(room_id, &key) {
+                return Ok(pdu);
+            }
+            cache.generation
+        };
+
+        let pdu = self.db.room_state_get(room_id, event_type, state_key)?;
+
+        self.current_state_cache
+            .lock()
+            .unwrap()
+            .insert(room_id, key, pdu.clone(), generation);
+
+        Ok(pdu)
+    }
+
+    /// Removes cached events after the current state of a room changed. If `changed` is `None`,
+    /// it is unknown which state keys changed and the whole room is removed.
+    pub fn invalidate_current_state(
+        &self,
+        room_id: &RoomId,
+        changed: Option<&[(StateEventType, String)]>,
+    ) {
+        self.current_state_cache
+            .lock()
+            .unwrap()
+            .invalidate(room_id, changed);
+    }
+}
+
+/// Applies the history visibility rules of the spec to the membership of a user at the time of
+/// an event. `joined_later` is whether the user joined the room at any point after the event.
+fn visibility_allows(
+    history_visibility: &HistoryVisibility,
+    membership_at_event: Option<&MembershipState>,
+    joined_later: bool,
+) -> bool {
+    match history_visibility {
+        HistoryVisibility::WorldReadable => true,
+        // Everything sent while the user was joined, and everything before any of their joins.
+        // Leaving again doesn't hide it
+        HistoryVisibility::Shared => {
+            joined_later || membership_at_event == Some(&MembershipState::Join)
+        }
+        HistoryVisibility::Invited => matches!(
+            membership_at_event,
+            Some(MembershipState::Join | MembershipState::Invite)
+        ),
+        HistoryVisibility::Joined => membership_at_event == Some(&MembershipState::Join),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::{api::client::room::create_room, room_id, serde::Raw, server_name, user_id};
+    use serde_json::{json, value::to_raw_value};
+
+    use super::*;
+    use crate::utils::testing;
+
+    #[test]
+    fn cached_state_is_invalidated_by_state_changes() {
+        let mut cache = CurrentStateCache::new(10);
+        let room = room_id!("!room:example.org");
+        let power_levels = (StateEventType::RoomPowerLevels, String::new());
+        let member = (StateEventType::RoomMember, "@alice:example.org".to_owned());
+
+        // A burst of sends reads the power levels and the sender's membership once
+        assert_eq!(cache.get(room, &power_levels), None);
+        let generation = cache.generation;
+        cache.insert(room, power_levels.clone(), None, generation);
+        cache.insert(room, member.clone(), None, generation);
+        assert_eq!(cache.get(room, &power_levels), Some(None));
+        assert_eq!(cache.get(room, &member), Some(None));
+
+        // A new member event only invalidates that member
+        cache.invalidate(room, Some(&[member.clone()]));
+        assert_eq!(cache.get(room, &power_levels), Some(None));
+        assert_eq!(cache.get(room, &member), None);
+
+        // After state resolution or a redaction everything is read again
+        cache.invalidate(room, None);
+        assert_eq!(cache.get(room, &power_levels), None);
+
+        // Other state isn't cached
+        assert!(!CurrentStateCache::caches(&StateEventType::RoomTopic));
+    }
+
+    #[test]
+    fn events_read_before_a_state_change_are_not_cached() {
+        let mut cache = CurrentStateCache::new(10);
+        let room = room_id!("!room:example.org");
+        let power_levels = (StateEventType::RoomPowerLevels, String::new());
+
+        let generation = cache.generation;
+        // The state changes while the old power levels are read from the database
+        cache.invalidate(room, Some(&[power_levels.clone()]));
+        cache.insert(room, power_levels.clone(), None, generation);
+
+        assert_eq!(cache.get(room, &power_levels), None);
+    }
+
+    #[test]
+    fn history_visibility_follows_the_spec() {
+        use HistoryVisibility::*;
+        use MembershipState::*;
+
+        // Visibility, membership at the event, joined after the event, visible
+        let table = [
+            (WorldReadable, None, false, true),
+            (WorldReadable, Some(Leave), false, true),
+            // Sent while the user was joined, also after they left
+            (Shared, Some(Join), false, true),
+            // Sent before the user joined, also if they left again
+            (Shared, None, true, true),
+            (Shared, Some(Leave), true, true),
+            (Shared, Some(Invite), true, true),
+            // Sent after the user left for good, or while they were only invited
+            (Shared, Some(Leave), false, false),
+            (Shared, Some(Invite), false, false),
+            (Shared, None, false, false),
+            (Invited, Some(Invite), false, true),
+            (Invited, Some(Join), false, true),
+            (Invited, None, true, false),
+            (Invited, Some(Leave), true, false),
+            (Joined, Some(Join), false, true),
+            (Joined, Some(Invite), true, false),
+            (Joined, None, true, false),
+            (Joined, Some(Leave), false, false),
+        ];
+
+        for (visibility, membership, joined_later, visible) in table {
+            assert_eq!(
+                visibility_allows(&visibility, membership.as_ref(), joined_later),
+                visible,
+                "{:?}, {:?}, joined later: {}",
+                visibility,
+                membership,
+                joined_later
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn servers_see_shared_history_once_one_of_their_users_joined() {
+        let alice = testing::user("shared_history_alice").await;
+        let room_id = testing::room_with(
+            &alice,
+            create_room::v3::Request {
+                initial_state: vec![Raw::from_json(
+                    to_raw_value(&json!({
+                        "type": "m.room.history_visibility",
+                        "state_key": "",
+                        "content": { "history_visibility": "shared" },
+                    }))
+                    .unwrap(),
+                )],
+                ..create_room::v3::Request::new()
+            },
+        )
+        .await;
+        let event_id = testing::send_message(&alice, &room_id, "Shared").await;
+
+        let can_see = |server_name| {
+            services()
+                .rooms
+                .state_accessor
+                .server_can_see_event(server_name, &room_id, &event_id)
+                .unwrap()
+        };
+        let remote = server_name!("shared-history.remote.test");
+        assert!(!can_see(remote));
+
+        // Joining later is enough for shared history, even after leaving again
+        let dave = user_id!("@dave:shared-history.remote.test");
+        for membership in [MembershipState::Join, MembershipState::Leave] {
+            services()
+                .rooms
+                .state_cache
+                .update_membership(&room_id, dave, membership, dave, None, true)
+                .unwrap();
+        }
+        assert!(can_see(remote));
+        assert!(!can_see(server_name!("other.remote.test")));
     }
 }
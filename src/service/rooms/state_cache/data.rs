@@ -28,7 +28,8 @@ pub trait Data: Send + Sync {
         appservice: &(String, serde_yaml::Value),
     ) -> Result<bool>;
 
-    /// Makes a user forget a room.
+    /// Makes a user forget a room, together with their account data, read markers and
+    /// notification counts in it.
     fn forget(&self, room_id: &RoomId, user_id: &UserId) -> Result<()>;
 
     /// Returns an iterator of all servers participating in this room.
@@ -1,9 +1,17 @@
 mod data;
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
 
 pub use data::Data;
 
+use lru_cache::LruCache;
 use ruma::{
+    api::client::membership::joined_members::v3::RoomMember,
     events::{
         direct::DirectEvent,
         ignored_user_list::IgnoredUserListEvent,
@@ -15,10 +23,127 @@ use ruma::{
     OwnedRoomId, OwnedServerName, OwnedUserId, RoomId, ServerName, UserId,
 };
 
+use serde::Deserialize;
+use tracing::{debug, warn};
+
 use crate::{services, Error, Result};
 
+/// Global account data type in which users configure who may invite them, with the unstable prefix
+/// of MSC4155 until it is stable.
+const INVITE_PERMISSION_CONFIG: &str = "org.matrix.msc4155.invite_permission_config";
+
 pub struct Service {
     pub db: &'static dyn Data,
+    pub joined_members_cache: Mutex<LruCache<OwnedRoomId, Arc<BTreeMap<OwnedUserId, RoomMember>>>>,
+    /// Increased after every membership change, so a result computed from the old membership
+    /// isn't cached.
+    pub joined_members_generation: AtomicU64,
+    pub joined_counts: Mutex<JoinedCounts>,
+}
+
+/// How many rooms local users are joined to, kept up to date on membership changes once loaded.
+pub struct JoinedCounts {
+    /// The count and the generation it was loaded at
+    counts: LruCache<OwnedUserId, (u64, u64)>,
+    /// Increased when a membership change starts and when it is written, so a count loaded while
+    /// a change was being written isn't trusted.
+    generation: u64,
+}
+
+impl JoinedCounts {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            counts: LruCache::new(capacity),
+            generation: 0,
+        }
+    }
+
+    /// Returns the cached count, or the generation to load it at.
+    pub fn get(&mut self, user_id: &UserId) -> Result<u64, u64> {
+        self.counts
+            .get_mut(user_id)
+            .map(|(count, _)| *count)
+            .ok_or(self.generation)
+    }
+
+    /// Caches a count loaded at `generation`, unless a membership change started since then.
+    pub fn insert_loaded(&mut self, user_id: &UserId, count: u64, generation: u64) {
+        if self.generation == generation {
+            self.counts.insert(user_id.to_owned(), (count, generation));
+        }
+    }
+
+    /// Called before a membership change is written. Returns the generation to pass to
+    /// `finish_change`.
+    pub fn start_change(&mut self) -> u64 {
+        self.generation += 1;
+        self.generation
+    }
+
+    /// Called after a membership change is written. `joined` is the new membership if the user
+    /// joined or left the room.
+    pub fn finish_change(&mut self, user_id: &UserId, started: u64, joined: Option<bool>) {
+        self.generation += 1;
+
+        match self.counts.get_mut(user_id) {
+            Some((count, loaded)) if *loaded < started => {
+                *count = match joined {
+                    Some(true) => count.saturating_add(1),
+                    Some(false) => count.saturating_sub(1),
+                    None => *count,
+                };
+            }
+            // Loaded while the change was written, it may or may not include it
+            Some(_) => {
+                self.counts.remove(user_id);
+            }
+            None => {}
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct InvitePermissionConfigEvent {
+    content: InvitePermissionConfig,
+}
+
+/// Who may invite a user, from the `INVITE_PERMISSION_CONFIG` account data. Invites that aren't
+/// allowed are dropped, like invites from ignored users.
+#[derive(Debug, Default, Deserialize)]
+struct InvitePermissionConfig {
+    /// Only users sharing a room with the user may invite them
+    #[serde(default)]
+    block_strangers: bool,
+    /// Only the allowed users may invite the user
+    #[serde(default)]
+    only_allowed_users: bool,
+    /// Users who may always invite the user
+    #[serde(default)]
+    allowed_users: Vec<OwnedUserId>,
+}
+
+impl InvitePermissionConfig {
+    /// Whether an invite from `sender` gets through. `shares_room` is only called when needed.
+    fn allows(&self, sender: &UserId, shares_room: impl FnOnce() -> Result<bool>) -> Result<bool> {
+        if self.allowed_users.iter().any(|user| user == sender) {
+            return Ok(true);
+        }
+
+        if self.only_allowed_users {
+            return Ok(false);
+        }
+
+        if self.block_strangers {
+            return shares_room();
+        }
+
+        Ok(true)
+    }
+}
+
+/// Whether a user with `count` rooms may get another one.
+pub fn below_limit(count: u64, limit: Option<u64>) -> bool {
+    limit.map_or(true, |limit| count < limit)
 }
 
 impl Service {
@@ -34,11 +159,26 @@ impl Service {
         update_joined_count: bool,
     ) -> Result<()> {
         // Keep track what remote users exist by adding them as "deactivated" users
-        if user_id.server_name() != services().globals.server_name() {
+        if !services().globals.server_is_ours(user_id.server_name()) {
             services().users.create(user_id, None)?;
             // TODO: displayname, avatar url
         }
 
+        let change = if services().globals.server_is_ours(user_id.server_name()) {
+            let (started, cached) = {
+                let mut counts = self.joined_counts.lock().unwrap();
+                (counts.start_change(), counts.get(user_id).is_ok())
+            };
+            let was_joined = if cached {
+                Some(self.is_joined(user_id, room_id)?)
+            } else {
+                None
+            };
+            Some((started, was_joined))
+        } else {
+            None
+        };
+
         match &membership {
             MembershipState::Join => {
                 // Check if the user never joined this room
@@ -167,7 +307,7 @@ impl Service {
                             .any(|(user, _details)| user == sender)
                     });
 
-                if is_ignored {
+                if is_ignored || !self.accepts_invite_from(user_id, sender)? {
                     return Ok(());
                 }
 
@@ -179,6 +319,23 @@ impl Service {
             _ => {}
         }
 
+        if let Some((started, was_joined)) = change {
+            let joined = match was_joined {
+                Some(was_joined) => {
+                    let is_joined = self.is_joined(user_id, room_id)?;
+                    (was_joined != is_joined).then_some(is_joined)
+                }
+                None => None,
+            };
+            self.joined_counts
+                .lock()
+                .unwrap()
+                .finish_change(user_id, started, joined);
+        }
+
+        // Profile changes are membership events too
+        self.invalidate_joined_members(room_id);
+
         if update_joined_count {
             self.update_joined_count(room_id)?;
         }
@@ -188,7 +345,103 @@ impl Service {
 
     #[tracing::instrument(skip(self, room_id))]
     pub fn update_joined_count(&self, room_id: &RoomId) -> Result<()> {
-        self.db.update_joined_count(room_id)
+        self.db.update_joined_count(room_id)?;
+        self.invalidate_joined_members(room_id);
+        Ok(())
+    }
+
+    /// Returns the joined members of the room with their display names and avatar URLs. The
+    /// result is cached until the membership of the room changes.
+    #[tracing::instrument(skip(self))]
+    pub fn joined_members(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Arc<BTreeMap<OwnedUserId, RoomMember>>> {
+        if let Some(joined) = self.joined_members_cache.lock().unwrap().get_mut(room_id) {
+            return Ok(Arc::clone(joined));
+        }
+
+        let generation = self.joined_members_generation.load(Ordering::SeqCst);
+
+        let mut joined = BTreeMap::new();
+        for user_id in self.room_members(room_id).filter_map(|r| r.ok()) {
+            let display_name = services().users.displayname(&user_id)?;
+            let avatar_url = services().users.avatar_url(&user_id)?;
+
+            joined.insert(
+                user_id,
+                RoomMember {
+                    display_name,
+                    avatar_url,
+                },
+            );
+        }
+        let joined = Arc::new(joined);
+
+        let mut cache = self.joined_members_cache.lock().unwrap();
+        if self.joined_members_generation.load(Ordering::SeqCst) == generation {
+            cache.insert(room_id.to_owned(), Arc::clone(&joined));
+        }
+
+        Ok(joined)
+    }
+
+    /// Whether the user's invite permission config lets `sender` invite them.
+    fn accepts_invite_from(&self, user_id: &UserId, sender: &UserId) -> Result<bool> {
+        let config = match services().account_data.get(
+            None,
+            user_id,
+            RoomAccountDataEventType::from(INVITE_PERMISSION_CONFIG),
+        )? {
+            Some(event) => match serde_json::from_str::<InvitePermissionConfigEvent>(event.get()) {
+                Ok(event) => event.content,
+                Err(e) => {
+                    warn!("Invalid invite permission config of {}: {}", user_id, e);
+                    return Ok(true);
+                }
+            },
+            None => return Ok(true),
+        };
+
+        let allowed = config.allows(sender, || {
+            Ok(services()
+                .rooms
+                .user
+                .get_shared_rooms(vec![user_id.to_owned(), sender.to_owned()])?
+                .next()
+                .is_some())
+        })?;
+
+        if !allowed {
+            debug!("Dropping invite of {} from {}", user_id, sender);
+        }
+
+        Ok(allowed)
+    }
+
+    /// Returns how many rooms a local user is joined to. The count is kept up to date on
+    /// membership changes once loaded.
+    #[tracing::instrument(skip(self))]
+    pub fn joined_count(&self, user_id: &UserId) -> Result<u64> {
+        let generation = match self.joined_counts.lock().unwrap().get(user_id) {
+            Ok(count) => return Ok(count),
+            Err(generation) => generation,
+        };
+
+        let count = self.rooms_joined(user_id).filter_map(|r| r.ok()).count() as u64;
+        self.joined_counts
+            .lock()
+            .unwrap()
+            .insert_loaded(user_id, count, generation);
+
+        Ok(count)
+    }
+
+    fn invalidate_joined_members(&self, room_id: &RoomId) {
+        let mut cache = self.joined_members_cache.lock().unwrap();
+        self.joined_members_generation
+            .fetch_add(1, Ordering::SeqCst);
+        cache.remove(room_id);
     }
 
     #[tracing::instrument(skip(self, room_id))]
@@ -205,12 +458,32 @@ impl Service {
         self.db.appservice_in_room(room_id, appservice)
     }
 
-    /// Makes a user forget a room.
+    /// Makes a user forget a room, together with their account data, read markers and
+    /// notification counts in it.
     #[tracing::instrument(skip(self))]
     pub fn forget(&self, room_id: &RoomId, user_id: &UserId) -> Result<()> {
         self.db.forget(room_id, user_id)
     }
 
+    /// Drops the memberships of other servers' users, so we stop sending them the room's events
+    /// and the room is no longer counted as shared with them.
+    #[tracing::instrument(skip(self))]
+    pub fn forget_remote_members(&self, room_id: &RoomId) -> Result<()> {
+        let members: Vec<_> = self
+            .room_members(room_id)
+            .chain(self.room_members_invited(room_id))
+            .filter_map(|r| r.ok())
+            .filter(|user_id| !services().globals.server_is_ours(user_id.server_name()))
+            .collect();
+
+        for user_id in members {
+            self.db.mark_as_left(&user_id, room_id)?;
+            self.db.forget(room_id, &user_id)?;
+        }
+
+        self.update_joined_count(room_id)
+    }
+
     /// Returns an iterator of all servers participating in this room.
     #[tracing::instrument(skip(self))]
     pub fn room_servers<'a>(
@@ -346,3 +619,78 @@ impl Service {
         self.db.is_left(user_id, room_id)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn joined_counts_follow_membership_changes() {
+        let alice = ruma::user_id!("@alice:example.org");
+        let mut counts = JoinedCounts::new(10);
+
+        let generation = counts.get(alice).unwrap_err();
+        counts.insert_loaded(alice, 2, generation);
+        assert_eq!(counts.get(alice), Ok(2));
+
+        let started = counts.start_change();
+        counts.finish_change(alice, started, Some(true));
+        assert_eq!(counts.get(alice), Ok(3));
+
+        let started = counts.start_change();
+        counts.finish_change(alice, started, Some(false));
+        assert_eq!(counts.get(alice), Ok(2));
+    }
+
+    #[test]
+    fn joined_counts_loaded_during_a_change_are_not_trusted() {
+        let alice = ruma::user_id!("@alice:example.org");
+        let mut counts = JoinedCounts::new(10);
+
+        // Loading started before the change and finished after it started
+        let generation = counts.get(alice).unwrap_err();
+        let started = counts.start_change();
+        counts.insert_loaded(alice, 2, generation);
+        counts.finish_change(alice, started, None);
+        assert!(counts.get(alice).is_err());
+
+        // Loading started while the change was written
+        let started = counts.start_change();
+        let generation = counts.get(alice).unwrap_err();
+        counts.insert_loaded(alice, 3, generation);
+        counts.finish_change(alice, started, None);
+        assert!(counts.get(alice).is_err());
+    }
+
+    #[test]
+    fn stranger_invites_can_be_blocked() {
+        let friend = ruma::user_id!("@friend:example.org");
+        let stranger = ruma::user_id!("@stranger:example.org");
+        let config: InvitePermissionConfig = serde_json::from_value(serde_json::json!({
+            "block_strangers": true,
+            "allowed_users": [friend],
+        }))
+        .unwrap();
+
+        assert!(!config.allows(stranger, || Ok(false)).unwrap());
+        assert!(config.allows(stranger, || Ok(true)).unwrap());
+        assert!(config.allows(friend, || Ok(false)).unwrap());
+
+        let only_allowed = InvitePermissionConfig {
+            only_allowed_users: true,
+            ..config
+        };
+        assert!(!only_allowed.allows(stranger, || Ok(true)).unwrap());
+
+        let default = InvitePermissionConfig::default();
+        assert!(default.allows(stranger, || Ok(false)).unwrap());
+    }
+
+    #[test]
+    fn limits_are_exclusive_and_default_to_unlimited() {
+        assert!(below_limit(u64::MAX - 1, None));
+        assert!(below_limit(2, Some(3)));
+        assert!(!below_limit(3, Some(3)));
+        assert!(!below_limit(0, Some(0)));
+    }
+}
@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{future::Future, pin::Pin, sync::Arc};
 
 use ruma::{CanonicalJsonObject, EventId, OwnedUserId, RoomId, UserId};
 
@@ -53,6 +53,9 @@ pub trait Data: Send + Sync {
     /// Removes a pdu and creates a new one with the same id.
     fn replace_pdu(&self, pdu_id: &[u8], pdu: &PduEvent) -> Result<()>;
 
+    /// Deletes a pdu from the timeline.
+    fn remove_pdu(&self, pdu_id: &[u8], event_id: &EventId) -> Result<()>;
+
     /// Returns an iterator over all events in a room that happened after the event with id `since`
     /// in chronological order.
     fn pdus_since<'a>(
@@ -78,6 +81,12 @@ pub trait Data: Send + Sync {
         from: u64,
     ) -> Result<Box<dyn Iterator<Item = Result<(Vec<u8>, PduEvent)>> + 'a>>;
 
+    /// Resolves when a pdu is added to the timeline of the room after this was called.
+    fn watch_pdus<'a>(
+        &'a self,
+        room_id: &RoomId,
+    ) -> Result<Pin<Box<dyn Future<Output = ()> + Send + 'a>>>;
+
     fn increment_notification_counts(
         &self,
         room_id: &RoomId,
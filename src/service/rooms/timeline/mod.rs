@@ -1,16 +1,19 @@
 mod data;
+mod pdu_cache;
 
 use std::collections::HashMap;
 
 use std::{
     collections::HashSet,
+    future::Future,
     sync::{Arc, Mutex},
 };
 
 pub use data::Data;
+pub use pdu_cache::{PduCache, PduCacheStats};
 use regex::Regex;
 use ruma::{
-    api::client::error::ErrorKind,
+    api::client::{error::ErrorKind, Direction},
     canonical_json::to_canonical_value,
     events::{
         push_rules::PushRulesEvent,
@@ -24,24 +27,34 @@ use ruma::{
     state_res,
     state_res::RoomVersion,
     uint, CanonicalJsonObject, CanonicalJsonValue, EventId, OwnedEventId, OwnedRoomId,
-    OwnedServerName, RoomAliasId, RoomId, UserId,
+    OwnedServerName, RoomAliasId, RoomId, UInt, UserId,
 };
 use serde::Deserialize;
 use serde_json::value::to_raw_value;
 use tokio::sync::MutexGuard;
-use tracing::{error, warn};
+use tracing::{error, info, warn};
 
 use crate::{
-    service::pdu::{EventHash, PduBuilder},
+    service::{
+        admin,
+        pdu::{EventHash, PduBuilder},
+    },
     services, utils, Error, PduEvent, Result,
 };
 
 use super::state_compressor::CompressedStateEvent;
 
+/// Events on the other side of the position of a timestamp that are looked at, because timestamps
+/// don't strictly follow the order in which events arrived.
+const CLOSEST_EVENT_MARGIN: usize = 100;
+/// Events looked at in the requested direction, when the closest ones can't be seen.
+const CLOSEST_EVENT_SCAN_LIMIT: usize = 1000;
+
 pub struct Service {
     pub db: &'static dyn Data,
 
     pub lasttimelinecount_cache: Mutex<HashMap<OwnedRoomId, u64>>,
+    pub pdu_cache: PduCache,
 }
 
 impl Service {
@@ -112,7 +125,8 @@ impl Service {
     ///
     /// Checks the `eventid_outlierpdu` Tree if not found in the timeline.
     pub fn get_pdu(&self, event_id: &EventId) -> Result<Option<Arc<PduEvent>>> {
-        self.db.get_pdu(event_id)
+        self.pdu_cache
+            .get_or_load(event_id, || self.db.get_pdu(event_id))
     }
 
     /// Returns the pdu.
@@ -376,9 +390,10 @@ impl Service {
                         )
                         .expect("#admins:server_name is a valid room alias"),
                     )?;
-                    let server_user = format!("@conduit:{}", services().globals.server_name());
+                    let server_user = services().globals.server_user();
 
-                    let to_conduit = body.starts_with(&format!("{server_user}: "));
+                    let to_conduit = services().globals.admin_room_enabled()
+                        && admin::is_command(&body, &server_user);
 
                     // This will evaluate to false if the emergency password is set up so that
                     // the administrator can execute commands as conduit
@@ -620,16 +635,23 @@ impl Service {
 
         pdu_json.remove("event_id");
 
+        // Events of users on an alias domain have to be signed by that name, remote servers
+        // check the signature of the sender's server
+        let origin = if services().globals.server_is_ours(sender.server_name()) {
+            sender.server_name()
+        } else {
+            services().globals.server_name()
+        };
+
         // Add origin because synapse likes that (and it's required in the spec)
         pdu_json.insert(
             "origin".to_owned(),
-            to_canonical_value(services().globals.server_name())
-                .expect("server name is a valid CanonicalJsonValue"),
+            to_canonical_value(origin).expect("server name is a valid CanonicalJsonValue"),
         );
 
         match ruma::signatures::hash_and_sign_event(
-            services().globals.server_name().as_str(),
-            services().globals.keypair(),
+            origin.as_str(),
+            &*services().globals.keypair(),
             &mut pdu_json,
             &room_version_id,
         ) {
@@ -661,6 +683,14 @@ impl Service {
             CanonicalJsonValue::String(pdu.event_id.as_str().to_owned()),
         );
 
+        // Remember the event, so it isn't verified again when it comes back over federation
+        services()
+            .globals
+            .created_events
+            .lock()
+            .unwrap()
+            .insert((*pdu.event_id).to_owned(), ());
+
         // Generate short event id
         let _shorteventid = services()
             .rooms
@@ -722,9 +752,11 @@ impl Service {
         }
 
         // Remove our server from the server list since it will be added to it by room_servers() and/or the if statement above
-        servers.remove(services().globals.server_name());
+        servers.retain(|server| !services().globals.server_is_ours(server));
 
-        services().sending.send_pdu(servers.into_iter(), &pdu_id)?;
+        if !services().rooms.metadata.is_local_only(room_id)? {
+            services().sending.send_pdu(servers.into_iter(), &pdu_id)?;
+        }
 
         Ok(pdu.event_id)
     }
@@ -815,6 +847,238 @@ impl Service {
         self.db.pdus_after(user_id, room_id, from)
     }
 
+    /// Waits until a pdu is added to the timeline of the room. The watch starts when this is
+    /// called, not when the future is first polled.
+    pub fn watch_pdus(&self, room_id: &RoomId) -> Result<impl Future<Output = ()> + Send + '_> {
+        self.db.watch_pdus(room_id)
+    }
+
+    /// Returns the event closest to `ts` that `visible` allows: the first one sent at or after `ts`
+    /// going forward, the last one sent at or before it going backward.
+    ///
+    /// Events are stored in the order they arrived, which mostly follows their timestamps. The
+    /// position of `ts` is found by seeking through the pdu counts, and only the events around it
+    /// are looked at.
+    #[tracing::instrument(skip(self, visible))]
+    pub fn closest_event(
+        &self,
+        room_id: &RoomId,
+        ts: UInt,
+        dir: Direction,
+        visible: impl Fn(&EventId) -> Result<bool>,
+    ) -> Result<Option<PduEvent>> {
+        // Events of other users are read, so any user works
+        let conduit_user = services().globals.server_user();
+
+        let last_count = self.last_timeline_count(&conduit_user, room_id)?;
+        let position = seek_timestamp(last_count, ts, |count| {
+            self.pdus_after(&conduit_user, room_id, count)?
+                .filter_map(|r| r.ok()) // Filter out buggy events
+                .next()
+                .map(|(pdu_id, pdu)| Ok((self.pdu_count(&pdu_id)?, pdu.origin_server_ts)))
+                .transpose()
+        })?;
+
+        let (before_limit, after_limit) = match dir {
+            Direction::Forward => (CLOSEST_EVENT_MARGIN, CLOSEST_EVENT_SCAN_LIMIT),
+            Direction::Backward => (CLOSEST_EVENT_SCAN_LIMIT, CLOSEST_EVENT_MARGIN),
+        };
+        let pdus = self
+            .pdus_until(&conduit_user, room_id, position.saturating_add(1))?
+            .take(before_limit)
+            .chain(
+                self.pdus_after(&conduit_user, room_id, position)?
+                    .take(after_limit),
+            )
+            .filter_map(|r| r.ok())
+            .map(|(_, pdu)| pdu)
+            .collect();
+
+        nearest_visible(pdus, ts, dir, visible)
+    }
+
+    /// Deletes the messages of a room that were sent before `before` to free space and returns
+    /// how many were deleted. State events are kept, because the current state and the auth chains
+    /// of new events may need them, and so are the forward extremities, which new events reference.
+    #[tracing::instrument(skip(self))]
+    pub fn purge_history(&self, room_id: &RoomId, before: UInt) -> Result<u64> {
+        let extremities = services().rooms.state.get_forward_extremities(room_id)?;
+
+        self.purge_pdus(room_id, |pdu| is_purgeable(pdu, before, &extremities))
+    }
+
+    /// Forgets a room of another server that no local user is joined or invited to anymore: all
+    /// of its events and its current state are deleted and other servers no longer receive our
+    /// events for it. Joining the room again loads its state over federation, like for a room we
+    /// never knew. Returns how many events were deleted.
+    #[tracing::instrument(skip(self))]
+    pub async fn forget_room(&self, room_id: &RoomId) -> Result<u64> {
+        if services().globals.server_is_ours(room_id.server_name()) {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Rooms created on this server can't be rejoined over federation.",
+            ));
+        }
+
+        let mutex_state = Arc::clone(
+            services()
+                .globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(room_id.to_owned())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        if has_local_members(room_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Local users are still joined or invited to the room.",
+            ));
+        }
+
+        let count = self.purge_pdus(room_id, |_| true)?;
+
+        services()
+            .rooms
+            .state
+            .set_forward_extremities(room_id, Vec::new(), &state_lock)?;
+        services()
+            .rooms
+            .state
+            .remove_room_state(room_id, &state_lock)?;
+        services()
+            .rooms
+            .state_cache
+            .forget_remote_members(room_id)?;
+        self.lasttimelinecount_cache.lock().unwrap().remove(room_id);
+
+        Ok(count)
+    }
+
+    /// Forgets the rooms that were abandoned longer than the configured grace period, see
+    /// `forget_room`.
+    #[tracing::instrument(skip(self))]
+    pub async fn forget_abandoned_rooms(&self) -> Result<()> {
+        let grace_period = match services().globals.forget_abandoned_rooms_after_secs() {
+            Some(secs) => secs.saturating_mul(1000),
+            None => return Ok(()),
+        };
+        let now = utils::millis_since_unix_epoch();
+        let server_user = services().globals.server_user();
+
+        let abandoned: Vec<_> = services()
+            .rooms
+            .metadata
+            .iter_ids()
+            .filter_map(|r| r.ok())
+            .filter(|room_id| !services().globals.server_is_ours(room_id.server_name()))
+            .filter(|room_id| !has_local_members(room_id).unwrap_or(true))
+            .filter(|room_id| {
+                // Rooms without events were already forgotten
+                let newest_event = self
+                    .pdus_until(&server_user, room_id, u64::MAX)
+                    .ok()
+                    .and_then(|mut pdus| pdus.next())
+                    .and_then(|r| r.ok());
+                newest_event.map_or(false, |(_, pdu)| {
+                    abandoned_long_enough(pdu.origin_server_ts.into(), now, grace_period)
+                })
+            })
+            .collect();
+
+        for room_id in abandoned {
+            match self.forget_room(&room_id).await {
+                Ok(count) => info!("Forgot abandoned room {room_id}, purged {count} events"),
+                Err(e) => warn!("Failed to forget abandoned room {room_id}: {e}"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the events of a room for which `filter` returns true and returns how many were
+    /// deleted. Everything that refers to the events goes with them: search index entries, state
+    /// references, short ids of non-state events and read receipts that only point to purged
+    /// events. Notification counts can't be split by event, so local users who hadn't read up to
+    /// the purged events get theirs reset.
+    fn purge_pdus(&self, room_id: &RoomId, filter: impl Fn(&PduEvent) -> bool) -> Result<u64> {
+        #[derive(Deserialize)]
+        struct ExtractBody {
+            body: Option<String>,
+        }
+
+        let shortroomid = services()
+            .rooms
+            .short
+            .get_shortroomid(room_id)?
+            .ok_or(Error::BadRequest(ErrorKind::NotFound, "Room not found."))?;
+
+        // Collected first, because not all backends can delete while iterating
+        let purgeable: Vec<_> = self
+            .pdus_after(&services().globals.server_user(), room_id, 0)?
+            .filter_map(|r| r.ok())
+            .filter(|(_, pdu)| filter(pdu))
+            .map(|(pdu_id, pdu)| {
+                let body = serde_json::from_str::<ExtractBody>(pdu.content.get())
+                    .ok()
+                    .and_then(|content| content.body);
+                (pdu_id, pdu.event_id, pdu.state_key.is_some(), body)
+            })
+            .collect();
+
+        let mut newest_purged = 0;
+        for (pdu_id, event_id, is_state, body) in &purgeable {
+            if let Some(body) = body {
+                services()
+                    .rooms
+                    .search
+                    .deindex_pdu(shortroomid, pdu_id, body)?;
+            }
+
+            newest_purged = newest_purged.max(self.db.pdu_count(pdu_id)?);
+            self.db.remove_pdu(pdu_id, event_id)?;
+            services().rooms.state.remove_event_state(event_id)?;
+            if !is_state {
+                services().rooms.short.remove_shorteventid(event_id)?;
+            }
+            self.pdu_cache.remove(event_id);
+        }
+
+        if purgeable.is_empty() {
+            return Ok(0);
+        }
+
+        let purged_ids: HashSet<&EventId> = purgeable
+            .iter()
+            .map(|(_, event_id, _, _)| &**event_id)
+            .collect();
+        services()
+            .rooms
+            .edus
+            .read_receipt
+            .remove_readreceipts_for(room_id, &purged_ids)?;
+
+        for user_id in services()
+            .rooms
+            .state_cache
+            .room_members(room_id)
+            .filter_map(|r| r.ok())
+            .filter(|user_id| services().globals.server_is_ours(user_id.server_name()))
+        {
+            let user = &services().rooms.user;
+            if user.last_notification_read(&user_id, room_id)? < newest_purged
+                && (user.notification_count(&user_id, room_id)? > 0
+                    || user.highlight_count(&user_id, room_id)? > 0)
+            {
+                user.reset_notification_counts(&user_id, room_id)?;
+            }
+        }
+
+        Ok(purgeable.len() as u64)
+    }
+
     /// Replace a PDU with the redacted form.
     #[tracing::instrument(skip(self, reason))]
     pub fn redact_pdu(&self, event_id: &EventId, reason: &PduEvent) -> Result<()> {
@@ -824,8 +1088,411 @@ impl Service {
                 .ok_or_else(|| Error::bad_database("PDU ID points to invalid PDU."))?;
             pdu.redact(reason)?;
             self.replace_pdu(&pdu_id, &pdu)?;
+            self.pdu_cache.remove(event_id);
+            // The redacted event may be cached as current state
+            services()
+                .rooms
+                .state_accessor
+                .invalidate_current_state(&pdu.room_id, None);
         }
         // If event does not exist, just noop
         Ok(())
     }
+
+    /// The checks for sending a message event besides the auth rules: encrypted events need
+    /// encryption to be allowed, and the message rate limit of the room applies to everyone but
+    /// appservices and admins.
+    pub fn check_message_event(
+        &self,
+        sender_user: &UserId,
+        room_id: &RoomId,
+        event_type: &str,
+        from_appservice: bool,
+    ) -> Result<()> {
+        // Forbid m.room.encrypted if encryption is disabled
+        if RoomEventType::RoomEncrypted == event_type.into()
+            && !services().globals.allow_encryption()
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Encryption has been disabled",
+            ));
+        }
+
+        // Setting up a call takes many events in a short time, e.g. the ICE candidates
+        if !is_call_event(event_type) {
+            let rate_limited = services()
+                .globals
+                .message_rate_limiter
+                .lock()
+                .unwrap()
+                .check(sender_user, room_id);
+
+            if let Err(retry_after) = rate_limited {
+                if !from_appservice && !services().users.is_admin(sender_user)? {
+                    return Err(Error::BadRequest(
+                        ErrorKind::LimitExceeded {
+                            retry_after_ms: Some(retry_after),
+                        },
+                        "Too many messages sent into this room.",
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The VoIP call signalling events of the spec.
+const CALL_EVENT_TYPES: &[&str] = &[
+    "m.call.invite",
+    "m.call.candidates",
+    "m.call.answer",
+    "m.call.select_answer",
+    "m.call.reject",
+    "m.call.negotiate",
+    "m.call.sdp_stream_metadata_changed",
+    "m.call.hangup",
+];
+
+/// Whether the event belongs to the signalling of a VoIP call, like m.call.invite or
+/// m.call.candidates. Other events in the m.call namespace are limited like any message.
+fn is_call_event(event_type: &str) -> bool {
+    CALL_EVENT_TYPES.contains(&event_type)
+}
+
+/// Whether a local user is joined or invited to the room.
+fn has_local_members(room_id: &RoomId) -> Result<bool> {
+    let state_cache = &services().rooms.state_cache;
+    for user_id in state_cache
+        .room_members(room_id)
+        .chain(state_cache.room_members_invited(room_id))
+    {
+        if services().globals.server_is_ours(user_id?.server_name()) {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Whether the newest event of a room without local members is older than the grace period.
+fn abandoned_long_enough(newest_event_ts: u64, now: u64, grace_period: u64) -> bool {
+    now.saturating_sub(newest_event_ts) >= grace_period
+}
+
+/// Finds the pdu count after which events were sent at or after `ts`, by bisecting the counts up
+/// to `last_count`. `first_after` returns the count and timestamp of the first event after a
+/// count.
+fn seek_timestamp(
+    last_count: u64,
+    ts: UInt,
+    mut first_after: impl FnMut(u64) -> Result<Option<(u64, UInt)>>,
+) -> Result<u64> {
+    // Events up to `low` were sent before `ts`, events after `high` at or after it
+    let (mut low, mut high) = (0, last_count);
+
+    while low < high {
+        let middle = low + (high - low) / 2;
+        match first_after(middle)? {
+            Some((count, origin_server_ts)) if count <= high && origin_server_ts < ts => {
+                low = count;
+            }
+            _ => high = middle,
+        }
+    }
+
+    Ok(low)
+}
+
+/// Keeps the events sent at or after `ts` going forward, at or before it going backward, and sorts
+/// them by how close they are to `ts`.
+fn nearest_first(mut pdus: Vec<PduEvent>, ts: UInt, dir: Direction) -> Vec<PduEvent> {
+    match dir {
+        Direction::Forward => {
+            pdus.retain(|pdu| pdu.origin_server_ts >= ts);
+            pdus.sort_by_key(|pdu| pdu.origin_server_ts);
+        }
+        Direction::Backward => {
+            pdus.retain(|pdu| pdu.origin_server_ts <= ts);
+            pdus.sort_by_key(|pdu| std::cmp::Reverse(pdu.origin_server_ts));
+        }
+    }
+
+    pdus
+}
+
+/// Returns the event nearest to `ts` in the direction that `visible` allows.
+pub(crate) fn nearest_visible(
+    pdus: Vec<PduEvent>,
+    ts: UInt,
+    dir: Direction,
+    visible: impl Fn(&EventId) -> Result<bool>,
+) -> Result<Option<PduEvent>> {
+    for pdu in nearest_first(pdus, ts, dir) {
+        if visible(&pdu.event_id)? {
+            return Ok(Some(pdu));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Whether `purge_history` may delete an event.
+fn is_purgeable(pdu: &PduEvent, before: UInt, extremities: &HashSet<Arc<EventId>>) -> bool {
+    pdu.state_key.is_none() && pdu.origin_server_ts < before && !extremities.contains(&pdu.event_id)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use ruma::{
+        events::receipt::{Receipt, ReceiptEvent, ReceiptEventContent, ReceiptThread, ReceiptType},
+        MilliSecondsSinceUnixEpoch,
+    };
+
+    use super::*;
+    use crate::utils::testing;
+
+    fn pdu(event_id: &str, origin_server_ts: u64, state_key: Option<&str>) -> PduEvent {
+        let mut pdu = serde_json::json!({
+            "event_id": event_id,
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": origin_server_ts,
+            "type": "m.room.message",
+            "content": { "msgtype": "m.text", "body": "Hello" },
+            "prev_events": [],
+            "depth": 1,
+            "auth_events": [],
+            "hashes": { "sha256": "" },
+        });
+        if let Some(state_key) = state_key {
+            pdu["type"] = "m.room.topic".into();
+            pdu["state_key"] = state_key.into();
+        }
+        serde_json::from_str(&pdu.to_string()).unwrap()
+    }
+
+    #[test]
+    fn timestamps_are_found_by_seeking_the_counts() {
+        // Counts aren't contiguous, other rooms use the ones in between
+        let events = [(3, 100_u32), (7, 200), (8, 300), (20, 400), (31, 500)];
+        let seek = |ts: u32| {
+            seek_timestamp(31, UInt::from(ts), |count| {
+                Ok(events
+                    .iter()
+                    .find(|(c, _)| *c > count)
+                    .map(|(c, ts)| (*c, UInt::from(*ts))))
+            })
+            .unwrap()
+        };
+
+        assert_eq!(seek(50), 0);
+        assert_eq!(seek(100), 0);
+        assert_eq!(seek(250), 7);
+        assert_eq!(seek(300), 7);
+        assert_eq!(seek(450), 20);
+        assert_eq!(seek(600), 31);
+    }
+
+    #[test]
+    fn nearest_event_in_each_direction() {
+        let pdus = vec![
+            pdu("$first:example.org", 100, None),
+            pdu("$third:example.org", 300, None),
+            pdu("$second:example.org", 200, None),
+        ];
+        let nearest = |ts: u32, dir| {
+            nearest_first(pdus.clone(), UInt::from(ts), dir)
+                .first()
+                .map(|pdu| pdu.event_id.to_string())
+        };
+
+        assert_eq!(
+            nearest(150, Direction::Forward).as_deref(),
+            Some("$second:example.org")
+        );
+        assert_eq!(
+            nearest(250, Direction::Backward).as_deref(),
+            Some("$second:example.org")
+        );
+        assert_eq!(
+            nearest(300, Direction::Forward).as_deref(),
+            Some("$third:example.org")
+        );
+        assert_eq!(nearest(50, Direction::Backward), None);
+        assert_eq!(nearest(301, Direction::Forward), None);
+    }
+
+    #[test]
+    fn only_old_messages_are_purged() {
+        let latest = pdu("$latest:example.org", 100, None);
+        let extremities = HashSet::from([Arc::clone(&latest.event_id)]);
+        let before = uint!(50);
+
+        assert!(is_purgeable(
+            &pdu("$old:example.org", 10, None),
+            before,
+            &extremities
+        ));
+
+        // Recent messages, state events and the events new events will reference survive
+        assert!(!is_purgeable(
+            &pdu("$recent:example.org", 60, None),
+            before,
+            &extremities
+        ));
+        assert!(!is_purgeable(
+            &pdu("$topic:example.org", 10, Some("")),
+            before,
+            &extremities
+        ));
+        let old_extremity = pdu("$old_extremity:example.org", 10, None);
+        assert!(!is_purgeable(
+            &old_extremity,
+            before,
+            &HashSet::from([Arc::clone(&old_extremity.event_id)])
+        ));
+        assert!(!is_purgeable(&latest, before, &extremities));
+    }
+
+    #[test]
+    fn rooms_are_forgotten_after_the_grace_period() {
+        let day = 24 * 60 * 60 * 1000;
+        let newest_event_ts = 1_000 * day;
+
+        assert!(!abandoned_long_enough(
+            newest_event_ts,
+            newest_event_ts + day,
+            30 * day
+        ));
+        assert!(abandoned_long_enough(
+            newest_event_ts,
+            newest_event_ts + 30 * day,
+            30 * day
+        ));
+        // Events from the future don't make the room abandoned
+        assert!(!abandoned_long_enough(
+            newest_event_ts,
+            newest_event_ts - day,
+            30 * day
+        ));
+    }
+
+    #[tokio::test]
+    async fn purged_events_leave_nothing_behind() {
+        let alice = testing::user("purge_alice").await;
+        let bob = testing::user("purge_bob").await;
+        let room_id = testing::room(&alice).await;
+        testing::join(&bob, &room_id).await;
+
+        let old = testing::send_message(&alice, &room_id, "old").await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let before = UInt::new_saturating(utils::millis_since_unix_epoch());
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let recent = testing::send_message(&alice, &room_id, "recent").await;
+        let latest = testing::send_message(&alice, &room_id, "latest").await;
+
+        let short_old = services()
+            .rooms
+            .short
+            .get_or_create_shorteventid(&old)
+            .unwrap();
+        let receipt = |event_id: &EventId| ReceiptEvent {
+            content: ReceiptEventContent(BTreeMap::from([(
+                event_id.to_owned(),
+                BTreeMap::from([(
+                    ReceiptType::Read,
+                    BTreeMap::from([(
+                        bob.clone(),
+                        Receipt {
+                            ts: Some(MilliSecondsSinceUnixEpoch::now()),
+                            thread: ReceiptThread::Unthreaded,
+                        },
+                    )]),
+                )]),
+            )])),
+            room_id: room_id.clone(),
+        };
+        services()
+            .rooms
+            .edus
+            .read_receipt
+            .readreceipt_update(&bob, &room_id, receipt(&old))
+            .unwrap();
+        assert!(
+            services()
+                .rooms
+                .user
+                .notification_count(&bob, &room_id)
+                .unwrap()
+                > 0
+        );
+
+        let purged = services()
+            .rooms
+            .timeline
+            .purge_history(&room_id, before)
+            .unwrap();
+
+        // Only the message sent before `before`, the room's creation events are state
+        assert_eq!(purged, 1);
+        assert!(services()
+            .rooms
+            .timeline
+            .get_pdu_id(&old)
+            .unwrap()
+            .is_none());
+        assert!(services()
+            .rooms
+            .short
+            .get_eventid_from_short(short_old)
+            .is_err());
+        assert_eq!(
+            services()
+                .rooms
+                .edus
+                .read_receipt
+                .readreceipts_since(&room_id, 0)
+                .count(),
+            0
+        );
+        assert_eq!(
+            services()
+                .rooms
+                .user
+                .notification_count(&bob, &room_id)
+                .unwrap(),
+            0
+        );
+
+        for event_id in [&recent, &latest] {
+            assert!(services()
+                .rooms
+                .timeline
+                .get_pdu_id(event_id)
+                .unwrap()
+                .is_some());
+        }
+        assert!(services()
+            .rooms
+            .state_accessor
+            .room_state_get(&room_id, &StateEventType::RoomMember, bob.as_str())
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn only_call_signalling_skips_the_rate_limit() {
+        assert!(is_call_event("m.call.invite"));
+        assert!(is_call_event("m.call.candidates"));
+        assert!(is_call_event("m.call.hangup"));
+
+        assert!(!is_call_event("m.call.spam"));
+        assert!(!is_call_event("m.call.invite.extra"));
+        assert!(!is_call_event("m.room.message"));
+    }
 }
@@ -0,0 +1,148 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use lru_cache::LruCache;
+use ruma::{EventId, OwnedEventId};
+use serde::Serialize;
+
+use crate::{PduEvent, Result};
+
+/// Deserialized PDUs by event id, with counters of how often it was used.
+pub struct PduCache {
+    cache: Mutex<LruCache<OwnedEventId, Arc<PduEvent>>>,
+    /// Increased whenever an entry is removed, so a PDU read before it was changed isn't cached.
+    generation: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct PduCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+impl PduCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            cache: Mutex::new(LruCache::new(capacity)),
+            generation: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the cached PDU or loads and caches it.
+    pub fn get_or_load(
+        &self,
+        event_id: &EventId,
+        load: impl FnOnce() -> Result<Option<Arc<PduEvent>>>,
+    ) -> Result<Option<Arc<PduEvent>>> {
+        let cached = self.cache.lock().unwrap().get_mut(event_id).cloned();
+        if let Some(pdu) = cached {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(pdu));
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let generation = self.generation.load(Ordering::SeqCst);
+
+        let pdu = load()?;
+
+        if let Some(pdu) = &pdu {
+            let mut cache = self.cache.lock().unwrap();
+            if self.generation.load(Ordering::SeqCst) == generation {
+                cache.insert(event_id.to_owned(), Arc::clone(pdu));
+            }
+        }
+
+        Ok(pdu)
+    }
+
+    /// Must be called after a PDU was changed in the database, e.g. by a redaction.
+    pub fn remove(&self, event_id: &EventId) {
+        let mut cache = self.cache.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        cache.remove(event_id);
+    }
+
+    pub fn stats(&self) -> PduCacheStats {
+        PduCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.cache.lock().unwrap().len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pdu(event_id: &str, body: &str) -> Arc<PduEvent> {
+        let json = format!(
+            r#"{{
+                "event_id": "{event_id}",
+                "room_id": "!room:example.org",
+                "sender": "@alice:example.org",
+                "origin_server_ts": 0,
+                "type": "m.room.message",
+                "content": {{"msgtype":"m.text","body":"{body}"}},
+                "prev_events": [],
+                "depth": 1,
+                "auth_events": [],
+                "hashes": {{ "sha256": "" }}
+            }}"#
+        );
+        Arc::new(serde_json::from_str(&json).unwrap())
+    }
+
+    #[test]
+    fn second_fetch_is_a_hit() {
+        let cache = PduCache::new(10);
+        let event_id = <&EventId>::try_from("$event:example.org").unwrap();
+
+        for _ in 0..2 {
+            let loaded = cache
+                .get_or_load(event_id, || Ok(Some(pdu(event_id.as_str(), "Hello"))))
+                .unwrap();
+            assert_eq!(loaded.unwrap().event_id.as_ref(), event_id);
+        }
+
+        assert_eq!(
+            cache.stats(),
+            PduCacheStats {
+                hits: 1,
+                misses: 1,
+                entries: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn removed_pdus_are_loaded_again() {
+        let cache = PduCache::new(10);
+        let event_id = <&EventId>::try_from("$event:example.org").unwrap();
+
+        cache
+            .get_or_load(event_id, || Ok(Some(pdu(event_id.as_str(), "Hello"))))
+            .unwrap();
+        // The event was redacted
+        cache.remove(event_id);
+        let loaded = cache
+            .get_or_load(event_id, || Ok(Some(pdu(event_id.as_str(), ""))))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(loaded.content.get(), r#"{"msgtype":"m.text","body":""}"#);
+        assert_eq!(cache.stats().misses, 2);
+
+        // Unknown events aren't cached
+        let unknown = <&EventId>::try_from("$unknown:example.org").unwrap();
+        assert!(cache.get_or_load(unknown, || Ok(None)).unwrap().is_none());
+        assert_eq!(cache.stats().entries, 1);
+    }
+}
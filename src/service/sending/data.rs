@@ -1,4 +1,6 @@
-use ruma::ServerName;
+use std::collections::BTreeMap;
+
+use ruma::{OwnedServerName, ServerName};
 
 use crate::Result;
 
@@ -23,6 +25,8 @@ pub trait Data: Send + Sync {
         &'a self,
         outgoing_kind: &OutgoingKind,
     ) -> Box<dyn Iterator<Item = Result<(SendingEventType, Vec<u8>)>> + 'a>;
+    /// Number of queued, not yet active events for each destination server.
+    fn queue_depths(&self) -> Result<BTreeMap<OwnedServerName, u64>>;
     fn mark_as_active(&self, events: &[(SendingEventType, Vec<u8>)]) -> Result<()>;
     fn set_latest_educount(&self, server_name: &ServerName, educount: u64) -> Result<()>;
     fn get_latest_educount(&self, server_name: &ServerName) -> Result<u64>;
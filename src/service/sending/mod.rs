@@ -5,8 +5,8 @@ pub use data::Data;
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
     fmt::Debug,
-    sync::Arc,
-    time::{Duration, Instant},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime},
 };
 
 use crate::{
@@ -34,7 +34,8 @@ use ruma::{
         push_rules::PushRulesEvent, receipt::ReceiptType, AnySyncEphemeralRoomEvent,
         GlobalAccountDataEventType,
     },
-    push, uint, MilliSecondsSinceUnixEpoch, OwnedServerName, OwnedUserId, ServerName, UInt, UserId,
+    push, uint, CanonicalJsonObject, CanonicalJsonValue, MilliSecondsSinceUnixEpoch,
+    OwnedServerName, OwnedUserId, RoomId, ServerName, UInt, UserId,
 };
 use tokio::{
     select,
@@ -87,9 +88,18 @@ pub struct Service {
     db: &'static dyn Data,
 
     /// The state for a given state hash.
-    pub(super) maximum_requests: Arc<Semaphore>,
+    pub(crate) maximum_requests: Arc<Semaphore>,
     pub sender: mpsc::UnboundedSender<(OutgoingKind, SendingEventType, Vec<u8>)>,
     receiver: Mutex<mpsc::UnboundedReceiver<(OutgoingKind, SendingEventType, Vec<u8>)>>,
+    destinations: RwLock<HashMap<OwnedServerName, DestinationStatus>>,
+    command_sender: mpsc::UnboundedSender<DestinationCommand>,
+    command_receiver: Mutex<mpsc::UnboundedReceiver<DestinationCommand>>,
+}
+
+/// Admin actions on a federation destination, carried out by the sending handler.
+enum DestinationCommand {
+    ResetBackoff(OwnedServerName),
+    Purge(OwnedServerName),
 }
 
 enum TransactionStatus {
@@ -98,14 +108,111 @@ enum TransactionStatus {
     Retrying(u32),        // number of times failed
 }
 
+/// How transactions to a federation destination went since the server started.
+#[derive(Clone, Debug, Default)]
+pub struct DestinationStatus {
+    pub last_success: Option<SystemTime>,
+    pub last_failure: Option<SystemTime>,
+    /// Failed transactions since the last successful one.
+    pub failures: u32,
+    pub last_error: Option<String>,
+    /// The unstable features the destination advertised in its version response.
+    pub unstable_features: Option<BTreeMap<String, bool>>,
+    /// When we last asked the destination for its features.
+    pub features_requested: Option<SystemTime>,
+}
+
+impl DestinationStatus {
+    /// When sending to the destination is tried again, None if it's not backed off.
+    pub fn retry_at(&self) -> Option<SystemTime> {
+        if self.failures == 0 {
+            return None;
+        }
+
+        self.last_failure
+            .map(|failure| failure + backoff_duration(self.failures))
+    }
+}
+
+/// How long the unstable features a destination advertised are trusted before we ask again.
+const UNSTABLE_FEATURES_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// How long to wait after a destination failed `tries` times in a row (exponential backoff).
+fn backoff_duration(tries: u32) -> Duration {
+    (Duration::from_secs(30) * tries * tries).min(Duration::from_secs(60 * 60 * 24))
+}
+
+/// Marks a transaction to the destination as running. Returns whether the previous, failed
+/// transaction has to be retried, or None if one is running already or the destination is backed
+/// off.
+fn start_transaction(
+    outgoing_kind: &OutgoingKind,
+    current_transaction_status: &mut HashMap<OutgoingKind, TransactionStatus>,
+) -> Option<bool> {
+    let mut retry = false;
+    let mut allow = true;
+
+    let entry = current_transaction_status.entry(outgoing_kind.clone());
+
+    entry
+        .and_modify(|e| match e {
+            TransactionStatus::Running | TransactionStatus::Retrying(_) => {
+                allow = false; // already running
+            }
+            TransactionStatus::Failed(tries, time) => {
+                // Fail if a request has failed recently (exponential backoff)
+                if time.elapsed() < backoff_duration(*tries) {
+                    allow = false;
+                } else {
+                    retry = true;
+                    *e = TransactionStatus::Retrying(*tries);
+                }
+            }
+        })
+        .or_insert(TransactionStatus::Running);
+
+    allow.then_some(retry)
+}
+
+/// Lets a failed transaction to the destination be retried right away. Returns false if there is
+/// no failed transaction.
+fn clear_backoff(
+    outgoing_kind: &OutgoingKind,
+    current_transaction_status: &mut HashMap<OutgoingKind, TransactionStatus>,
+) -> bool {
+    match current_transaction_status.get_mut(outgoing_kind) {
+        Some(TransactionStatus::Failed(tries, _)) => {
+            *tries = 0;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Whether the event belongs to a room whose events must not leave this server. Events whose room
+/// can't be checked are treated as local-only, dropping an event is better than leaking it.
+fn is_local_only(pdu_json: &CanonicalJsonObject) -> bool {
+    match pdu_json.get("room_id") {
+        Some(CanonicalJsonValue::String(room_id)) => <&RoomId>::try_from(room_id.as_str())
+            .ok()
+            .and_then(|room_id| services().rooms.metadata.is_local_only(room_id).ok())
+            .unwrap_or(true),
+        _ => true,
+    }
+}
+
 impl Service {
     pub fn build(db: &'static dyn Data, config: &Config) -> Arc<Self> {
         let (sender, receiver) = mpsc::unbounded_channel();
+        let (command_sender, command_receiver) = mpsc::unbounded_channel();
         Arc::new(Self {
             db,
             sender,
             receiver: Mutex::new(receiver),
             maximum_requests: Arc::new(Semaphore::new(config.max_concurrent_requests as usize)),
+            destinations: RwLock::new(HashMap::new()),
+            command_sender,
+            command_receiver: Mutex::new(command_receiver),
         })
     }
 
@@ -118,6 +225,7 @@ impl Service {
 
     async fn handler(&self) -> Result<()> {
         let mut receiver = self.receiver.lock().await;
+        let mut command_receiver = self.command_receiver.lock().await;
 
         let mut futures = FuturesUnordered::new();
 
@@ -153,6 +261,7 @@ impl Service {
                 Some(response) = futures.next() => {
                     match response {
                         Ok(outgoing_kind) => {
+                            self.record_result(&outgoing_kind, None);
                             self.db.delete_all_active_requests_for(&outgoing_kind)?;
 
                             // Find events that have been added since starting the last request
@@ -172,7 +281,8 @@ impl Service {
                                 current_transaction_status.remove(&outgoing_kind);
                             }
                         }
-                        Err((outgoing_kind, _)) => {
+                        Err((outgoing_kind, error)) => {
+                            self.record_result(&outgoing_kind, Some(&error));
                             current_transaction_status.entry(outgoing_kind).and_modify(|e| *e = match e {
                                 TransactionStatus::Running => TransactionStatus::Failed(1, Instant::now()),
                                 TransactionStatus::Retrying(n) => TransactionStatus::Failed(*n+1, Instant::now()),
@@ -193,10 +303,48 @@ impl Service {
                         futures.push(Self::handle_events(outgoing_kind, events));
                     }
                 }
+                Some(command) = command_receiver.recv() => {
+                    match command {
+                        DestinationCommand::ResetBackoff(server) => {
+                            let outgoing_kind = OutgoingKind::Normal(server);
+                            if clear_backoff(&outgoing_kind, &mut current_transaction_status) {
+                                if let Ok(Some(events)) = self.select_events(
+                                    &outgoing_kind,
+                                    Vec::new(),
+                                    &mut current_transaction_status,
+                                ) {
+                                    futures.push(Self::handle_events(outgoing_kind, events));
+                                }
+                            }
+                        }
+                        DestinationCommand::Purge(server) => {
+                            let outgoing_kind = OutgoingKind::Normal(server);
+                            self.db.delete_all_requests_for(&outgoing_kind)?;
+                            // A running transaction removes itself when it finishes
+                            if let Some(TransactionStatus::Failed(_, _)) = current_transaction_status.get(&outgoing_kind) {
+                                current_transaction_status.remove(&outgoing_kind);
+                            }
+                        }
+                    }
+                }
             }
         }
     }
 
+    /// Forgets the backoff of a federation destination and retries its failed transaction right
+    /// away. The events queued in the meantime are sent once it succeeds.
+    pub fn reset_backoff(&self, server: OwnedServerName) {
+        let _ = self
+            .command_sender
+            .send(DestinationCommand::ResetBackoff(server));
+    }
+
+    /// Drops all events waiting to be sent to a federation destination, including the ones of
+    /// a failed transaction.
+    pub fn purge_queue(&self, server: OwnedServerName) {
+        let _ = self.command_sender.send(DestinationCommand::Purge(server));
+    }
+
     #[tracing::instrument(skip(self, outgoing_kind, new_events, current_transaction_status))]
     fn select_events(
         &self,
@@ -204,36 +352,10 @@ impl Service {
         new_events: Vec<(SendingEventType, Vec<u8>)>, // Events we want to send: event and full key
         current_transaction_status: &mut HashMap<OutgoingKind, TransactionStatus>,
     ) -> Result<Option<Vec<SendingEventType>>> {
-        let mut retry = false;
-        let mut allow = true;
-
-        let entry = current_transaction_status.entry(outgoing_kind.clone());
-
-        entry
-            .and_modify(|e| match e {
-                TransactionStatus::Running | TransactionStatus::Retrying(_) => {
-                    allow = false; // already running
-                }
-                TransactionStatus::Failed(tries, time) => {
-                    // Fail if a request has failed recently (exponential backoff)
-                    let mut min_elapsed_duration = Duration::from_secs(30) * (*tries) * (*tries);
-                    if min_elapsed_duration > Duration::from_secs(60 * 60 * 24) {
-                        min_elapsed_duration = Duration::from_secs(60 * 60 * 24);
-                    }
-
-                    if time.elapsed() < min_elapsed_duration {
-                        allow = false;
-                    } else {
-                        retry = true;
-                        *e = TransactionStatus::Retrying(*tries);
-                    }
-                }
-            })
-            .or_insert(TransactionStatus::Running);
-
-        if !allow {
-            return Ok(None);
-        }
+        let retry = match start_transaction(outgoing_kind, current_transaction_status) {
+            Some(retry) => retry,
+            None => return Ok(None),
+        };
 
         let mut events = Vec::new();
 
@@ -274,13 +396,17 @@ impl Service {
 
         'outer: for room_id in services().rooms.state_cache.server_rooms(server_name) {
             let room_id = room_id?;
+            if services().rooms.metadata.is_local_only(&room_id)? {
+                continue;
+            }
+
             // Look for device list updates in this room
             device_list_changes.extend(
                 services()
                     .users
                     .keys_changed(room_id.as_ref(), since, None)
                     .filter_map(|r| r.ok())
-                    .filter(|user_id| user_id.server_name() == services().globals.server_name()),
+                    .filter(|user_id| services().globals.server_is_ours(user_id.server_name())),
             );
 
             // Look for read receipts in this room
@@ -296,7 +422,7 @@ impl Service {
                     max_edu_count = count;
                 }
 
-                if user_id.server_name() != services().globals.server_name() {
+                if !services().globals.server_is_ours(user_id.server_name()) {
                     continue;
                 }
 
@@ -438,6 +564,74 @@ impl Service {
         Ok(())
     }
 
+    /// Remembers how the last transaction to a federation destination went.
+    fn record_result(&self, outgoing_kind: &OutgoingKind, error: Option<&Error>) {
+        let server = match outgoing_kind {
+            OutgoingKind::Normal(server) => server,
+            _ => return,
+        };
+
+        let mut destinations = self.destinations.write().unwrap();
+        let status = destinations.entry(server.clone()).or_default();
+        match error {
+            None => {
+                status.last_success = Some(SystemTime::now());
+                status.failures = 0;
+
+                // The destination is reachable, find out what it supports
+                let features_outdated = status.features_requested.map_or(true, |requested| {
+                    requested
+                        .elapsed()
+                        .map_or(true, |age| age > UNSTABLE_FEATURES_TTL)
+                });
+                if features_outdated {
+                    status.features_requested = Some(SystemTime::now());
+                    let server = server.clone();
+                    tokio::spawn(async move {
+                        match server_server::fetch_unstable_features(&server).await {
+                            Ok(features) => services().sending.record_features(&server, features),
+                            Err(e) => warn!("Failed to ask {} for its features: {}", server, e),
+                        }
+                    });
+                }
+            }
+            Some(error) => {
+                status.last_failure = Some(SystemTime::now());
+                status.failures += 1;
+                status.last_error = Some(error.to_string());
+            }
+        }
+    }
+
+    fn record_features(&self, server: &ServerName, features: BTreeMap<String, bool>) {
+        if let Some(status) = self.destinations.write().unwrap().get_mut(server) {
+            status.unstable_features = Some(features);
+        }
+    }
+
+    /// Whether the server advertised the unstable feature, e.g. to decide whether to use an
+    /// optional federation behaviour with it. False if it didn't tell us yet.
+    pub fn remote_feature_enabled(&self, server: &ServerName, feature: &str) -> bool {
+        self.destinations
+            .read()
+            .unwrap()
+            .get(server)
+            .and_then(|status| status.unstable_features.as_ref())
+            .and_then(|features| features.get(feature))
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// How transactions to each federation destination went since the server started.
+    pub fn destination_statuses(&self) -> HashMap<OwnedServerName, DestinationStatus> {
+        self.destinations.read().unwrap().clone()
+    }
+
+    /// Number of events waiting to be sent to each server.
+    pub fn queue_depths(&self) -> Result<BTreeMap<OwnedServerName, u64>> {
+        self.db.queue_depths()
+    }
+
     /// Cleanup event data
     /// Used for instance after we remove an appservice registration
     ///
@@ -461,18 +655,16 @@ impl Service {
                 for event in &events {
                     match event {
                         SendingEventType::Pdu(pdu_id) => {
-                            pdu_jsons.push(services().rooms.timeline
+                            match services()
+                                .rooms
+                                .timeline
                                 .get_pdu_from_id(pdu_id)
                                 .map_err(|e| (kind.clone(), e))?
-                                .ok_or_else(|| {
-                                    (
-                                        kind.clone(),
-                                        Error::bad_database(
-                                            "[Appservice] Event in servernameevent_data not found in db.",
-                                        ),
-                                    )
-                                })?
-                                .to_room_event())
+                            {
+                                Some(pdu) => pdu_jsons.push(pdu.to_room_event()),
+                                // The room was purged or forgotten after the event was queued
+                                None => warn!("[Appservice] Skipping purged event {pdu_id:?}"),
+                            }
                         }
                         SendingEventType::Edu(_) => {
                             // Appservices don't need EDUs (?)
@@ -525,20 +717,15 @@ impl Service {
                 for event in &events {
                     match event {
                         SendingEventType::Pdu(pdu_id) => {
-                            pdus.push(
-                                services().rooms
-                                    .timeline
-                                    .get_pdu_from_id(pdu_id)
-                                    .map_err(|e| (kind.clone(), e))?
-                                    .ok_or_else(|| {
-                                        (
-                                            kind.clone(),
-                                            Error::bad_database(
-                                                "[Push] Event in servernamevent_datas not found in db.",
-                                            ),
-                                        )
-                                    })?,
-                            );
+                            match services()
+                                .rooms
+                                .timeline
+                                .get_pdu_from_id(pdu_id)
+                                .map_err(|e| (kind.clone(), e))?
+                            {
+                                Some(pdu) => pdus.push(pdu),
+                                None => warn!("[Push] Skipping purged event {pdu_id:?}"),
+                            }
                         }
                         SendingEventType::Edu(_) => {
                             // Push gateways don't need EDUs (?)
@@ -608,22 +795,25 @@ impl Service {
                     match event {
                         SendingEventType::Pdu(pdu_id) => {
                             // TODO: check room version and remove event_id if needed
-                            let raw = PduEvent::convert_to_outgoing_federation_event(
-                                services().rooms
-                                    .timeline
-                                    .get_pdu_json_from_id(pdu_id)
-                                    .map_err(|e| (OutgoingKind::Normal(server.clone()), e))?
-                                    .ok_or_else(|| {
-                                        error!("event not found: {server} {pdu_id:?}");
-                                        (
-                                            OutgoingKind::Normal(server.clone()),
-                                            Error::bad_database(
-                                                "[Normal] Event in servernamevent_datas not found in db.",
-                                            ),
-                                        )
-                                    })?,
-                            );
-                            pdu_jsons.push(raw);
+                            match services()
+                                .rooms
+                                .timeline
+                                .get_pdu_json_from_id(pdu_id)
+                                .map_err(|e| (OutgoingKind::Normal(server.clone()), e))?
+                            {
+                                // Events queued before the room was made local-only are
+                                // dropped too
+                                Some(pdu_json) if is_local_only(&pdu_json) => {
+                                    warn!("[Normal] Skipping local-only event: {server} {pdu_id:?}")
+                                }
+                                Some(pdu_json) => pdu_jsons
+                                    .push(PduEvent::convert_to_outgoing_federation_event(pdu_json)),
+                                // The room was forgotten after the event was queued, so it isn't
+                                // sent anymore
+                                None => {
+                                    warn!("[Normal] Skipping purged event: {server} {pdu_id:?}")
+                                }
+                            }
                         }
                         SendingEventType::Edu(edu) => {
                             if let Ok(raw) = serde_json::from_slice(edu) {
@@ -633,9 +823,7 @@ impl Service {
                     }
                 }
 
-                let permit = services().sending.maximum_requests.acquire().await;
-
-                let response = server_server::send_request(
+                server_server::send_request(
                     server,
                     send_transaction_message::v1::Request {
                         origin: services().globals.server_name().to_owned(),
@@ -665,11 +853,7 @@ impl Service {
                     }
                     kind.clone()
                 })
-                .map_err(|e| (kind, e));
-
-                drop(permit);
-
-                response
+                .map_err(|e| (kind, e))
             }
         }
     }
@@ -683,11 +867,39 @@ impl Service {
     where
         T: Debug,
     {
-        let permit = self.maximum_requests.acquire().await;
-        let response = server_server::send_request(destination, request).await;
-        drop(permit);
+        // Outgoing federation requests are limited by `server_server::send_request` itself
+        server_server::send_request(destination, request).await
+    }
 
-        response
+    /// Like `send_federation_request`, sent by the server name of a local user, which can be an
+    /// alias of server_name.
+    #[tracing::instrument(skip(self, request))]
+    pub async fn send_federation_request_as<T: OutgoingRequest>(
+        &self,
+        origin: &ServerName,
+        destination: &ServerName,
+        request: T,
+    ) -> Result<T::IncomingResponse>
+    where
+        T: Debug,
+    {
+        server_server::send_request_as(origin, destination, request).await
+    }
+
+    /// Like `send_federation_request_as`, with `query` added to the query string, for parameters
+    /// ruma doesn't know about. Returns the body of the response.
+    #[tracing::instrument(skip(self, request))]
+    pub async fn send_federation_request_with_query_as<T: OutgoingRequest>(
+        &self,
+        origin: &ServerName,
+        destination: &ServerName,
+        request: T,
+        query: &str,
+    ) -> Result<bytes::Bytes>
+    where
+        T: Debug,
+    {
+        server_server::send_request_with_query_as(origin, destination, request, query).await
     }
 
     #[tracing::instrument(skip(self, registration, request))]
@@ -706,3 +918,102 @@ impl Service {
         response
     }
 }
+
+#[cfg(test)]
+mod test {
+    use ruma::{
+        api::client::room::create_room, events::room::member::MembershipState, serde::Raw,
+        server_name, user_id, OwnedEventId,
+    };
+    use serde_json::{json, value::to_raw_value};
+
+    use super::*;
+    use crate::utils::testing;
+
+    /// The events queued for `server`, the sending handler doesn't run in tests.
+    fn queued_events(server: &ServerName) -> Vec<OwnedEventId> {
+        services()
+            .sending
+            .db
+            .queued_requests(&OutgoingKind::Normal(server.to_owned()))
+            .filter_map(|r| match r.unwrap() {
+                (SendingEventType::Pdu(pdu_id), _) => Some(pdu_id),
+                (SendingEventType::Edu(_), _) => None,
+            })
+            .map(|pdu_id| {
+                services()
+                    .rooms
+                    .timeline
+                    .get_pdu_from_id(&pdu_id)
+                    .unwrap()
+                    .unwrap()
+                    .event_id
+                    .as_ref()
+                    .to_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reset_backoff_allows_sending_to_dead_destination() {
+        let dead = OutgoingKind::Normal(server_name!("dead.example").to_owned());
+        let mut statuses =
+            HashMap::from([(dead.clone(), TransactionStatus::Failed(5, Instant::now()))]);
+
+        assert_eq!(start_transaction(&dead, &mut statuses), None);
+
+        assert!(clear_backoff(&dead, &mut statuses));
+        assert_eq!(start_transaction(&dead, &mut statuses), Some(true));
+        assert!(matches!(statuses[&dead], TransactionStatus::Retrying(0)));
+
+        // A transaction that is already being retried is left alone
+        assert!(!clear_backoff(&dead, &mut statuses));
+        assert_eq!(start_transaction(&dead, &mut statuses), None);
+    }
+
+    #[tokio::test]
+    async fn events_of_local_only_rooms_are_not_queued() {
+        let alice = testing::user("local_only_alice").await;
+        let remote = server_name!("localonly.remote.test");
+        let erin = user_id!("@erin:localonly.remote.test");
+
+        let mut body = create_room::v3::Request::new();
+        body.creation_content = Some(Raw::from_json(
+            to_raw_value(&json!({ "m.federate": false })).unwrap(),
+        ));
+        let local_only = testing::room_with(&alice, body).await;
+        let federated = testing::room(&alice).await;
+
+        // Remote users can't join local-only rooms, a member from before is the worst case
+        for room_id in [&local_only, &federated] {
+            services()
+                .rooms
+                .state_cache
+                .update_membership(room_id, erin, MembershipState::Join, erin, None, true)
+                .unwrap();
+        }
+
+        let kept = testing::send_message(&alice, &local_only, "Stays here").await;
+        let sent = testing::send_message(&alice, &federated, "Goes out").await;
+        let queued = queued_events(remote);
+        assert!(queued.contains(&sent));
+        assert!(!queued.contains(&kept));
+
+        // Rooms an admin made local-only stop federating, events queued before are dropped
+        services()
+            .rooms
+            .metadata
+            .mark_local_only(&federated, true)
+            .unwrap();
+        let later = testing::send_message(&alice, &federated, "Stays here too").await;
+        assert!(!queued_events(remote).contains(&later));
+
+        let pdu_json = services()
+            .rooms
+            .timeline
+            .get_pdu_json(&sent)
+            .unwrap()
+            .unwrap();
+        assert!(is_local_only(&pdu_json));
+    }
+}
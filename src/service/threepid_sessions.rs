@@ -0,0 +1,263 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use ruma::{api::client::error::ErrorKind, ClientSecret, MilliSecondsSinceUnixEpoch, SessionId};
+
+use crate::{utils, Error, Result};
+
+/// How long a validation session, and a third party identifier validated in a UIAA session, can
+/// be used.
+const SESSION_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+const SESSION_ID_LENGTH: usize = 32;
+/// Sessions kept at most. Starting more fails until some expire.
+const MAX_SESSIONS: usize = 10_000;
+
+/// A pending or finished validation of a third party identifier. Sessions are only kept in memory,
+/// a restart means asking for a new email or SMS.
+pub struct Session<T> {
+    pub client_secret: String,
+    pub address: String,
+    pub validated_at: Option<MilliSecondsSinceUnixEpoch>,
+    /// What the kind of session needs besides this, like the token of an email
+    pub data: T,
+    created: Instant,
+}
+
+impl<T> Session<T> {
+    pub fn new(
+        client_secret: &ClientSecret,
+        address: String,
+        validated_at: Option<MilliSecondsSinceUnixEpoch>,
+        data: T,
+    ) -> Self {
+        Self {
+            client_secret: client_secret.as_str().to_owned(),
+            address,
+            validated_at,
+            data,
+            created: Instant::now(),
+        }
+    }
+
+    fn expired(&self) -> bool {
+        self.created.elapsed() >= SESSION_LIFETIME
+    }
+}
+
+/// What `ThreepidSessions::start` did.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SessionStart {
+    /// The session was created, so the token has to be sent
+    New { sid: String },
+    /// The request is a retry of an existing session, nothing has to be sent
+    Retry { sid: String },
+}
+
+/// The validation sessions of one kind of third party identifier, and the identifiers validated
+/// in user-interactive auth sessions.
+pub struct ThreepidSessions<T> {
+    sessions: Mutex<HashMap<String, Session<T>>>,
+    /// Validated addresses and when they were put here, by UIAA session id
+    uiaa: Mutex<HashMap<String, (String, MilliSecondsSinceUnixEpoch, Instant)>>,
+}
+
+impl<T> ThreepidSessions<T> {
+    pub fn new() -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            uiaa: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Creates a session with a new id, unless `is_retry` matches an existing one.
+    pub fn start(
+        &self,
+        session: Session<T>,
+        is_retry: impl Fn(&Session<T>) -> bool,
+    ) -> Result<SessionStart> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| !session.expired());
+
+        if let Some((sid, _)) = sessions.iter().find(|(_, session)| is_retry(session)) {
+            return Ok(SessionStart::Retry { sid: sid.clone() });
+        }
+
+        let sid = utils::random_string(SESSION_ID_LENGTH);
+        insert_capped(&mut sessions, sid.clone(), session)?;
+
+        Ok(SessionStart::New { sid })
+    }
+
+    /// Stores a session whose id someone else chose, like an identity server.
+    pub fn insert(&self, sid: &SessionId, session: Session<T>) -> Result<()> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| !session.expired());
+
+        insert_capped(&mut sessions, sid.as_str().to_owned(), session)
+    }
+
+    pub fn remove(&self, sid: &str) {
+        self.sessions.lock().unwrap().remove(sid);
+    }
+
+    /// Marks the session as validated if `check` accepts it. Returns whether it did.
+    pub fn validate(&self, sid: &str, check: impl FnOnce(&Session<T>) -> bool) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions
+            .get_mut(sid)
+            .filter(|session| !session.expired() && check(session))
+        {
+            Some(session) => {
+                session
+                    .validated_at
+                    .get_or_insert_with(MilliSecondsSinceUnixEpoch::now);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the address and when it was validated, if the session was validated.
+    pub fn validated(
+        &self,
+        sid: &SessionId,
+        client_secret: &ClientSecret,
+    ) -> Option<(String, MilliSecondsSinceUnixEpoch)> {
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(sid.as_str()).filter(|session| {
+            session.client_secret == client_secret.as_str() && !session.expired()
+        })?;
+
+        session
+            .validated_at
+            .map(|validated_at| (session.address.clone(), validated_at))
+    }
+
+    /// Remembers the address validated in a stage of a UIAA session.
+    pub fn set_uiaa(
+        &self,
+        uiaa_session: &str,
+        address: String,
+        validated_at: MilliSecondsSinceUnixEpoch,
+    ) {
+        let mut uiaa = self.uiaa.lock().unwrap();
+        // UIAA sessions that never finish would otherwise stay here forever
+        uiaa.retain(|_, (_, _, added)| added.elapsed() < SESSION_LIFETIME);
+        uiaa.insert(
+            uiaa_session.to_owned(),
+            (address, validated_at, Instant::now()),
+        );
+    }
+
+    /// Returns the address validated in the UIAA session, once.
+    pub fn take_uiaa(&self, uiaa_session: &str) -> Option<(String, MilliSecondsSinceUnixEpoch)> {
+        self.uiaa
+            .lock()
+            .unwrap()
+            .remove(uiaa_session)
+            .filter(|(_, _, added)| added.elapsed() < SESSION_LIFETIME)
+            .map(|(address, validated_at, _)| (address, validated_at))
+    }
+}
+
+impl<T> Default for ThreepidSessions<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn insert_capped<T>(
+    sessions: &mut HashMap<String, Session<T>>,
+    sid: String,
+    session: Session<T>,
+) -> Result<()> {
+    if sessions.len() >= MAX_SESSIONS {
+        return Err(Error::BadRequest(
+            ErrorKind::LimitExceeded {
+                retry_after_ms: None,
+            },
+            "Too many pending validations, try again later.",
+        ));
+    }
+
+    sessions.insert(sid, session);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retries_return_the_existing_session() {
+        let sessions = ThreepidSessions::new();
+        let client_secret: &ClientSecret = "secret".try_into().unwrap();
+        let start = |send_attempt: u64| {
+            sessions
+                .start(
+                    Session::new(
+                        client_secret,
+                        "alice@example.com".to_owned(),
+                        None,
+                        send_attempt,
+                    ),
+                    |session| {
+                        session.client_secret == client_secret.as_str()
+                            && session.address == "alice@example.com"
+                            && session.data >= send_attempt
+                    },
+                )
+                .unwrap()
+        };
+
+        let sid = match start(1) {
+            SessionStart::New { sid } => sid,
+            SessionStart::Retry { .. } => panic!("no session was started yet"),
+        };
+        assert_eq!(start(1), SessionStart::Retry { sid: sid.clone() });
+        assert!(matches!(start(2), SessionStart::New { sid: new } if new != sid));
+    }
+
+    #[test]
+    fn only_validated_sessions_return_their_address() {
+        let sessions = ThreepidSessions::new();
+        let client_secret: &ClientSecret = "secret".try_into().unwrap();
+        let sid: &SessionId = "sid".try_into().unwrap();
+
+        sessions
+            .insert(
+                sid,
+                Session::new(client_secret, "alice@example.com".to_owned(), None, ()),
+            )
+            .unwrap();
+        assert!(sessions.validated(sid, client_secret).is_none());
+
+        assert!(!sessions.validate("sid", |_| false));
+        assert!(sessions.validate("sid", |_| true));
+        assert_eq!(
+            sessions
+                .validated(sid, client_secret)
+                .map(|(address, _)| address),
+            Some("alice@example.com".to_owned())
+        );
+        assert!(sessions
+            .validated(sid, "other".try_into().unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn uiaa_addresses_are_taken_once() {
+        let sessions = ThreepidSessions::<()>::new();
+        let now = MilliSecondsSinceUnixEpoch::now();
+
+        sessions.set_uiaa("uiaa", "alice@example.com".to_owned(), now);
+        assert_eq!(
+            sessions.take_uiaa("uiaa"),
+            Some(("alice@example.com".to_owned(), now))
+        );
+        assert!(sessions.take_uiaa("uiaa").is_none());
+    }
+}
@@ -5,7 +5,10 @@ pub use data::Data;
 use ruma::{
     api::client::{
         error::ErrorKind,
-        uiaa::{AuthData, AuthType, Password, UiaaInfo, UserIdentifier},
+        uiaa::{
+            AuthData, AuthType, EmailIdentity, Msisdn, Password, ReCaptcha, UiaaInfo,
+            UserIdentifier,
+        },
     },
     CanonicalJsonValue, DeviceId, UserId,
 };
@@ -40,7 +43,7 @@ impl Service {
         )
     }
 
-    pub fn try_auth(
+    pub async fn try_auth(
         &self,
         user_id: &UserId,
         device_id: &DeviceId,
@@ -99,6 +102,67 @@ impl Service {
             AuthData::Dummy(_) => {
                 uiaainfo.completed.push(AuthType::Dummy);
             }
+            AuthData::EmailIdentity(EmailIdentity {
+                thirdparty_id_creds,
+                ..
+            }) => {
+                match services()
+                    .email
+                    .validated(&thirdparty_id_creds.sid, &thirdparty_id_creds.client_secret)
+                {
+                    Some((email, validated_at)) => {
+                        services().email.set_uiaa_email(
+                            uiaainfo.session.as_ref().expect("session is always set"),
+                            email,
+                            validated_at,
+                        );
+                        uiaainfo.completed.push(AuthType::EmailIdentity);
+                    }
+                    None => {
+                        uiaainfo.auth_error = Some(ruma::api::client::error::StandardErrorBody {
+                            kind: ErrorKind::ThreepidAuthFailed,
+                            message: "The email address has not been validated.".to_owned(),
+                        });
+                        return Ok((false, uiaainfo));
+                    }
+                }
+            }
+            AuthData::Msisdn(Msisdn {
+                thirdparty_id_creds,
+                ..
+            }) => {
+                match services()
+                    .msisdn
+                    .validated(&thirdparty_id_creds.sid, &thirdparty_id_creds.client_secret)
+                {
+                    Some((msisdn, validated_at)) => {
+                        services().msisdn.set_uiaa_msisdn(
+                            uiaainfo.session.as_ref().expect("session is always set"),
+                            msisdn,
+                            validated_at,
+                        );
+                        uiaainfo.completed.push(AuthType::Msisdn);
+                    }
+                    None => {
+                        uiaainfo.auth_error = Some(ruma::api::client::error::StandardErrorBody {
+                            kind: ErrorKind::ThreepidAuthFailed,
+                            message: "The phone number has not been validated.".to_owned(),
+                        });
+                        return Ok((false, uiaainfo));
+                    }
+                }
+            }
+            AuthData::ReCaptcha(ReCaptcha { response, .. }) => {
+                if services().captcha.verify(response).await? {
+                    uiaainfo.completed.push(AuthType::ReCaptcha);
+                } else {
+                    uiaainfo.auth_error = Some(ruma::api::client::error::StandardErrorBody {
+                        kind: ErrorKind::Forbidden,
+                        message: "Captcha verification failed.".to_owned(),
+                    });
+                    return Ok((false, uiaainfo));
+                }
+            }
             k => error!("type not supported: {:?}", k),
         }
 
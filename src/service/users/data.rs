@@ -1,11 +1,13 @@
+use super::Threepid;
 use crate::Result;
 use ruma::{
     api::client::{device::Device, filter::FilterDefinition},
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
     events::AnyToDeviceEvent,
     serde::Raw,
-    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri,
-    OwnedUserId, UInt, UserId,
+    thirdparty::Medium,
+    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch, OwnedDeviceId,
+    OwnedDeviceKeyId, OwnedMxcUri, OwnedUserId, UInt, UserId,
 };
 use std::collections::BTreeMap;
 
@@ -54,6 +56,23 @@ pub trait Data: Send + Sync {
     /// Sets a new avatar_url or removes it if avatar_url is None.
     fn set_blurhash(&self, user_id: &UserId, blurhash: Option<String>) -> Result<()>;
 
+    /// Returns a custom profile field of the user.
+    fn profile_field(&self, user_id: &UserId, key: &str) -> Result<Option<serde_json::Value>>;
+
+    /// Sets a custom profile field or removes it if value is None.
+    fn set_profile_field(
+        &self,
+        user_id: &UserId,
+        key: &str,
+        value: Option<&serde_json::Value>,
+    ) -> Result<()>;
+
+    /// Returns all custom profile fields of the user.
+    fn profile_fields<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<(String, serde_json::Value)>> + 'a>;
+
     /// Adds a new device to a user.
     fn create_device(
         &self,
@@ -66,6 +85,13 @@ pub trait Data: Send + Sync {
     /// Removes a device from a user.
     fn remove_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()>;
 
+    /// Removes several devices of a user, revoking all their access tokens first.
+    fn remove_devices(&self, user_id: &UserId, device_ids: &[OwnedDeviceId]) -> Result<()>;
+
+    /// Removes all devices of a user and revokes all their access tokens, also those whose
+    /// device is gone. Cross-signing keys are kept.
+    fn remove_all_devices(&self, user_id: &UserId) -> Result<()>;
+
     /// Returns an iterator over all device ids of this user.
     fn all_device_ids<'a>(
         &'a self,
@@ -75,6 +101,21 @@ pub trait Data: Send + Sync {
     /// Replaces the access token of one device.
     fn set_token(&self, user_id: &UserId, device_id: &DeviceId, token: &str) -> Result<()>;
 
+    /// When the current token of the device was issued. Missing for tokens issued before this
+    /// was tracked.
+    fn token_created(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<MilliSecondsSinceUnixEpoch>>;
+
+    fn set_token_created(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        created: MilliSecondsSinceUnixEpoch,
+    ) -> Result<()>;
+
     fn add_one_time_key(
         &self,
         user_id: &UserId,
@@ -179,6 +220,16 @@ pub trait Data: Send + Sync {
         device: &Device,
     ) -> Result<()>;
 
+    /// Sets when and from where the device was last used. Unlike other metadata, this is not a
+    /// device list change.
+    fn update_device_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        ip: Option<String>,
+        ts: MilliSecondsSinceUnixEpoch,
+    ) -> Result<()>;
+
     /// Get device metadata.
     fn get_device_metadata(&self, user_id: &UserId, device_id: &DeviceId)
         -> Result<Option<Device>>;
@@ -190,8 +241,24 @@ pub trait Data: Send + Sync {
         user_id: &UserId,
     ) -> Box<dyn Iterator<Item = Result<Device>> + 'a>;
 
+    /// Adds a third party id to the account of the user, or updates it.
+    fn add_threepid(&self, user_id: &UserId, threepid: &Threepid) -> Result<()>;
+
+    fn remove_threepid(&self, user_id: &UserId, medium: &Medium, address: &str) -> Result<()>;
+
+    fn threepids<'a>(&'a self, user_id: &UserId)
+        -> Box<dyn Iterator<Item = Result<Threepid>> + 'a>;
+
+    /// Returns the user who has the third party id on their account.
+    fn find_from_threepid(&self, medium: &Medium, address: &str) -> Result<Option<OwnedUserId>>;
+
     /// Creates a new sync filter. Returns the filter id.
     fn create_filter(&self, user_id: &UserId, filter: &FilterDefinition) -> Result<String>;
 
     fn get_filter(&self, user_id: &UserId, filter_id: &str) -> Result<Option<FilterDefinition>>;
+
+    /// Returns how many rooms the user created.
+    fn rooms_created(&self, user_id: &UserId) -> Result<u64>;
+
+    fn increment_rooms_created(&self, user_id: &UserId) -> Result<()>;
 }
@@ -1,20 +1,118 @@
 mod data;
-use std::{collections::BTreeMap, mem};
+use std::{
+    collections::{BTreeMap, HashMap},
+    mem,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 pub use data::Data;
+use lru_cache::LruCache;
 use ruma::{
-    api::client::{device::Device, error::ErrorKind, filter::FilterDefinition},
+    api::client::{
+        account::ThirdPartyIdRemovalStatus, device::Device, error::ErrorKind,
+        filter::FilterDefinition,
+    },
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
-    events::AnyToDeviceEvent,
+    events::{
+        presence::{PresenceEvent, PresenceEventContent},
+        room::member::{MembershipState, RoomMemberEventContent},
+        AnyToDeviceEvent, RoomEventType, StateEventType,
+    },
+    presence::PresenceState,
     serde::Raw,
-    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri,
-    OwnedUserId, RoomAliasId, UInt, UserId,
+    thirdparty::{Medium, ThirdPartyIdentifier},
+    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch, OwnedDeviceId,
+    OwnedDeviceKeyId, OwnedMxcUri, OwnedUserId, RoomAliasId, UInt, UserId,
 };
+use serde::{Deserialize, Serialize};
+use serde_json::value::to_raw_value;
+use tracing::warn;
+
+use crate::{api::identity_server, service::pdu::PduBuilder, services, utils, Error, Result};
+
+/// How often the last seen timestamp of a device is written while its IP stays the same.
+const LAST_SEEN_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How many rooms get a new member event right away after a profile change. The others are
+/// updated one at a time, so users in many rooms don't cause a storm of events.
+const PROFILE_UPDATE_BURST: usize = 10;
+const PROFILE_UPDATE_INTERVAL: Duration = Duration::from_millis(200);
 
-use crate::{services, Error, Result};
+/// Limits of custom profile fields (MSC4133), in bytes of JSON.
+const MAX_PROFILE_KEY_LENGTH: usize = 128;
+const MAX_PROFILE_FIELD_SIZE: usize = 4 * 1024;
+const MAX_PROFILE_SIZE: usize = 64 * 1024;
+
+/// How long a login token for another device (MSC3882) can be used.
+pub const LOGIN_TOKEN_LIFETIME: Duration = Duration::from_secs(2 * 60);
+const LOGIN_TOKEN_LENGTH: usize = 32;
+
+/// Short-lived tokens a user can log in with once on another device, e.g. by scanning a QR code.
+/// They are only kept in memory, a restart invalidates them.
+#[derive(Default)]
+pub struct LoginTokens {
+    tokens: HashMap<String, (OwnedUserId, Instant)>,
+}
+
+impl LoginTokens {
+    fn issue(&mut self, user_id: &UserId, now: Instant) -> String {
+        self.tokens.retain(|_, (_, expires_at)| *expires_at > now);
+
+        let token = utils::random_string(LOGIN_TOKEN_LENGTH);
+        self.tokens.insert(
+            token.clone(),
+            (user_id.to_owned(), now + LOGIN_TOKEN_LIFETIME),
+        );
+        token
+    }
+
+    /// Tokens are removed when they are used, whether they expired or not.
+    fn redeem(&mut self, token: &str, now: Instant) -> Option<OwnedUserId> {
+        let (user_id, expires_at) = self.tokens.remove(token)?;
+        (expires_at > now).then_some(user_id)
+    }
+}
 
 pub struct Service {
     pub db: &'static dyn Data,
+    /// When and from where each device's last seen metadata was last written.
+    pub last_seen_cache: Mutex<LruCache<(OwnedUserId, OwnedDeviceId), (Instant, Option<IpAddr>)>>,
+    /// The latest profile update of each user that is still being sent into their rooms.
+    pub profile_updates: Mutex<HashMap<OwnedUserId, u64>>,
+    pub login_tokens: Mutex<LoginTokens>,
+}
+
+/// A third party id on the account of a user.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Threepid {
+    pub medium: Medium,
+    pub address: String,
+    pub validated_at: MilliSecondsSinceUnixEpoch,
+    pub added_at: MilliSecondsSinceUnixEpoch,
+    /// The identity server it was bound at, so it can be unbound there later.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bound_id_server: Option<String>,
+}
+
+impl From<Threepid> for ThirdPartyIdentifier {
+    fn from(threepid: Threepid) -> Self {
+        ThirdPartyIdentifier {
+            address: threepid.address,
+            medium: threepid.medium,
+            validated_at: threepid.validated_at,
+            added_at: threepid.added_at,
+        }
+    }
+}
+
+/// Email addresses are case insensitive, so they are stored lowercased.
+pub fn canonical_threepid_address(medium: &Medium, address: &str) -> String {
+    match medium {
+        Medium::Email => address.trim().to_lowercase(),
+        _ => address.trim().to_owned(),
+    }
 }
 
 impl Service {
@@ -61,6 +159,23 @@ impl Service {
         self.db.find_from_token(token)
     }
 
+    /// Issues a token the user can log in with once on another device, for
+    /// `LOGIN_TOKEN_LIFETIME`.
+    pub fn create_login_token(&self, user_id: &UserId) -> String {
+        self.login_tokens
+            .lock()
+            .unwrap()
+            .issue(user_id, Instant::now())
+    }
+
+    /// Returns the user a login token was issued for. The token can't be used again.
+    pub fn redeem_login_token(&self, token: &str) -> Option<OwnedUserId> {
+        self.login_tokens
+            .lock()
+            .unwrap()
+            .redeem(token, Instant::now())
+    }
+
     /// Returns an iterator over all users on this homeserver.
     pub fn iter(&self) -> impl Iterator<Item = Result<OwnedUserId>> + '_ {
         self.db.iter()
@@ -88,7 +203,8 @@ impl Service {
         self.db.displayname(user_id)
     }
 
-    /// Sets a new displayname or removes it if displayname is None. You still need to nofify all rooms of this change.
+    /// Sets a new displayname or removes it if displayname is None. You still need to nofify all rooms of this change
+    /// with [`propagate_profile`](Self::propagate_profile).
     pub fn set_displayname(&self, user_id: &UserId, displayname: Option<String>) -> Result<()> {
         self.db.set_displayname(user_id, displayname)
     }
@@ -98,11 +214,155 @@ impl Service {
         self.db.avatar_url(user_id)
     }
 
-    /// Sets a new avatar_url or removes it if avatar_url is None.
+    /// Sets a new avatar_url or removes it if avatar_url is None. You still need to nofify all rooms of this change
+    /// with [`propagate_profile`](Self::propagate_profile).
     pub fn set_avatar_url(&self, user_id: &UserId, avatar_url: Option<OwnedMxcUri>) -> Result<()> {
         self.db.set_avatar_url(user_id, avatar_url)
     }
 
+    /// Returns a custom profile field of the user.
+    pub fn profile_field(&self, user_id: &UserId, key: &str) -> Result<Option<serde_json::Value>> {
+        self.db.profile_field(user_id, key)
+    }
+
+    /// Returns all custom profile fields of the user.
+    pub fn profile_fields(&self, user_id: &UserId) -> Result<BTreeMap<String, serde_json::Value>> {
+        self.db.profile_fields(user_id).collect()
+    }
+
+    /// Sets a custom profile field or removes it if value is None. Fails if the field or the
+    /// whole profile would get too large.
+    pub fn set_profile_field(
+        &self,
+        user_id: &UserId,
+        key: &str,
+        value: Option<&serde_json::Value>,
+    ) -> Result<()> {
+        if let Some(value) = value {
+            check_profile_field(key, value, &self.profile_fields(user_id)?)?;
+        }
+
+        self.db.set_profile_field(user_id, key, value)
+    }
+
+    /// Sends member events with the current displayname and avatar_url into all rooms the user
+    /// joined, and presence updates. This happens in the background and rate limited, a newer
+    /// profile change takes over from an update that is still running.
+    pub fn propagate_profile(&self, user_id: &UserId) {
+        let update = {
+            let mut profile_updates = self.profile_updates.lock().unwrap();
+            let update = profile_updates.entry(user_id.to_owned()).or_default();
+            *update += 1;
+            *update
+        };
+
+        let user_id = user_id.to_owned();
+        tokio::spawn(async move {
+            let users = &services().users;
+            if let Err(e) = users.send_profile_update(&user_id, update).await {
+                warn!("Failed to send profile update of {}: {}", user_id, e);
+            }
+
+            let mut profile_updates = users.profile_updates.lock().unwrap();
+            if profile_updates.get(&user_id) == Some(&update) {
+                profile_updates.remove(&user_id);
+            }
+        });
+    }
+
+    async fn send_profile_update(&self, user_id: &UserId, update: u64) -> Result<()> {
+        let rooms: Vec<_> = services()
+            .rooms
+            .state_cache
+            .rooms_joined(user_id)
+            .filter_map(|r| r.ok())
+            .collect();
+
+        for (i, room_id) in rooms.into_iter().enumerate() {
+            if i >= PROFILE_UPDATE_BURST {
+                tokio::time::sleep(PROFILE_UPDATE_INTERVAL).await;
+            }
+            if self.profile_updates.lock().unwrap().get(user_id) != Some(&update) {
+                // A newer update sends the current profile into all rooms again
+                return Ok(());
+            }
+
+            let displayname = self.displayname(user_id)?;
+            let avatar_url = self.avatar_url(user_id)?;
+
+            let mutex_state = Arc::clone(
+                services()
+                    .globals
+                    .roomid_mutex_state
+                    .write()
+                    .unwrap()
+                    .entry(room_id.clone())
+                    .or_default(),
+            );
+            let state_lock = mutex_state.lock().await;
+
+            let content: RoomMemberEventContent = match services()
+                .rooms
+                .state_accessor
+                .room_state_get(&room_id, &StateEventType::RoomMember, user_id.as_str())?
+            {
+                Some(pdu) => serde_json::from_str(pdu.content.get())
+                    .map_err(|_| Error::bad_database("Database contains invalid PDU."))?,
+                None => continue,
+            };
+            if content.membership != MembershipState::Join {
+                // The user left while the update was waiting, a new event would join them again
+                continue;
+            }
+
+            if let Some(content) =
+                updated_member_content(content, displayname.clone(), avatar_url.clone())
+            {
+                if let Err(e) = services().rooms.timeline.build_and_append_pdu(
+                    PduBuilder {
+                        event_type: RoomEventType::RoomMember,
+                        content: to_raw_value(&content)
+                            .expect("event is valid, we just created it"),
+                        unsigned: None,
+                        state_key: Some(user_id.to_string()),
+                        redacts: None,
+                    },
+                    user_id,
+                    &room_id,
+                    &state_lock,
+                ) {
+                    warn!(
+                        "Failed to send member event of {} into {}: {}",
+                        user_id, room_id, e
+                    );
+                }
+            }
+            drop(state_lock);
+
+            services().rooms.edus.presence.update_presence(
+                user_id,
+                &room_id,
+                PresenceEvent {
+                    content: PresenceEventContent {
+                        avatar_url,
+                        currently_active: None,
+                        displayname,
+                        last_active_ago: Some(
+                            utils::millis_since_unix_epoch()
+                                .try_into()
+                                .expect("time is valid"),
+                        ),
+                        presence: PresenceState::Online,
+                        status_msg: None,
+                    },
+                    sender: user_id.to_owned(),
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
     /// Get the blurhash of a user.
     pub fn blurhash(&self, user_id: &UserId) -> Result<Option<String>> {
         self.db.blurhash(user_id)
@@ -130,6 +390,21 @@ impl Service {
         self.db.remove_device(user_id, device_id)
     }
 
+    /// Removes several devices of a user. All their access tokens are revoked before anything
+    /// else is cleaned up. The device list change wakes up the user's syncs, including those of
+    /// the removed devices, and is sent to the users they share encrypted rooms with.
+    pub fn remove_devices(&self, user_id: &UserId, device_ids: &[OwnedDeviceId]) -> Result<()> {
+        self.db.remove_devices(user_id, device_ids)?;
+        self.db.mark_device_key_update(user_id)
+    }
+
+    /// Logs out every device of a user, revoking all their access tokens first. Cross-signing
+    /// keys are kept.
+    pub fn remove_all_devices(&self, user_id: &UserId) -> Result<()> {
+        self.db.remove_all_devices(user_id)?;
+        self.db.mark_device_key_update(user_id)
+    }
+
     /// Returns an iterator over all device ids of this user.
     pub fn all_device_ids<'a>(
         &'a self,
@@ -143,6 +418,39 @@ impl Service {
         self.db.set_token(user_id, device_id, token)
     }
 
+    /// Whether the token of the device is past `access_token_lifetime` or has been unused for
+    /// `session_idle_expiry`.
+    pub fn token_expired(&self, user_id: &UserId, device_id: &DeviceId) -> Result<bool> {
+        let lifetime = services().globals.access_token_lifetime();
+        let idle_expiry = services().globals.session_idle_expiry();
+        if lifetime.is_none() && idle_expiry.is_none() {
+            return Ok(false);
+        }
+
+        let now = MilliSecondsSinceUnixEpoch::now();
+        let created = match self.db.token_created(user_id, device_id)? {
+            Some(created) => created,
+            None => {
+                // Tokens issued before this was tracked count from their first use
+                self.db.set_token_created(user_id, device_id, now)?;
+                now
+            }
+        };
+
+        if lifetime.map_or(false, |lifetime| older_than(created, now, lifetime)) {
+            return Ok(true);
+        }
+
+        // Logging in again with the same device doesn't touch last seen
+        let last_used = self
+            .db
+            .get_device_metadata(user_id, device_id)?
+            .and_then(|device| device.last_seen_ts)
+            .map_or(created, |last_seen| last_seen.max(created));
+
+        Ok(idle_expiry.map_or(false, |idle_expiry| older_than(last_used, now, idle_expiry)))
+    }
+
     pub fn add_one_time_key(
         &self,
         user_id: &UserId,
@@ -289,6 +597,42 @@ impl Service {
         self.db.update_device_metadata(user_id, device_id, device)
     }
 
+    /// Records that a device made a request. Only written when the IP changed or the last write
+    /// is older than a few minutes, so not every request causes a database write.
+    pub fn update_device_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        ip: Option<IpAddr>,
+    ) -> Result<()> {
+        let now = Instant::now();
+        let key = (user_id.to_owned(), device_id.to_owned());
+
+        // Idle expiry is checked against the written timestamp, so it has to be written more often
+        // than the expiry window
+        let interval = services()
+            .globals
+            .session_idle_expiry()
+            .map_or(LAST_SEEN_INTERVAL, |idle_expiry| {
+                LAST_SEEN_INTERVAL.min(Duration::from_secs(idle_expiry) / 4)
+            });
+
+        {
+            let mut cache = self.last_seen_cache.lock().unwrap();
+            if !last_seen_outdated(cache.get_mut(&key).copied(), now, ip, interval) {
+                return Ok(());
+            }
+            cache.insert(key, (now, ip));
+        }
+
+        self.db.update_device_last_seen(
+            user_id,
+            device_id,
+            ip.map(|ip| ip.to_string()),
+            MilliSecondsSinceUnixEpoch::now(),
+        )
+    }
+
     /// Get device metadata.
     pub fn get_device_metadata(
         &self,
@@ -309,20 +653,111 @@ impl Service {
         self.db.all_devices_metadata(user_id)
     }
 
-    /// Deactivate account
-    pub fn deactivate_account(&self, user_id: &UserId) -> Result<()> {
+    /// Deactivate account. Its third party ids are removed and unbound at `id_server`, or the
+    /// identity server each was bound at.
+    pub async fn deactivate_account(
+        &self,
+        user_id: &UserId,
+        id_server: Option<&str>,
+    ) -> Result<ThirdPartyIdRemovalStatus> {
         // Remove all associated devices
-        for device_id in self.all_device_ids(user_id) {
-            self.remove_device(user_id, &device_id?)?;
-        }
+        self.remove_all_devices(user_id)?;
 
         // Set the password to "" to indicate a deactivated account. Hashes will never result in an
         // empty string, so the user will not be able to log in again. Systems like changing the
         // password without logging in should check if the account is deactivated.
         self.db.set_password(user_id, None)?;
 
-        // TODO: Unhook 3PID
-        Ok(())
+        // Removed first, the address must not stay with a deactivated account if unbinding fails
+        let threepids = self
+            .threepids(user_id)
+            .filter_map(|r| r.ok())
+            .collect::<Vec<_>>();
+        for threepid in &threepids {
+            self.remove_threepid(user_id, &threepid.medium, &threepid.address)?;
+        }
+
+        let mut status = ThirdPartyIdRemovalStatus::Success;
+        for threepid in threepids {
+            let id_server = id_server.or(threepid.bound_id_server.as_deref());
+            if id_server.is_none() {
+                continue;
+            }
+
+            if let ThirdPartyIdRemovalStatus::NoSupport = self
+                .unbind_threepid(user_id, id_server, &threepid.medium, &threepid.address)
+                .await
+            {
+                status = ThirdPartyIdRemovalStatus::NoSupport;
+            }
+        }
+
+        Ok(status)
+    }
+
+    /// Removes the binding of a third party id to the user at the identity server. Identity
+    /// servers that aren't trusted anymore aren't asked.
+    pub async fn unbind_threepid(
+        &self,
+        user_id: &UserId,
+        id_server: Option<&str>,
+        medium: &Medium,
+        address: &str,
+    ) -> ThirdPartyIdRemovalStatus {
+        let id_server = match id_server {
+            Some(id_server) if services().globals.identity_server_trusted(id_server) => id_server,
+            _ => return ThirdPartyIdRemovalStatus::NoSupport,
+        };
+
+        match identity_server::unbind(id_server, user_id, medium, address).await {
+            Ok(()) => ThirdPartyIdRemovalStatus::Success,
+            Err(e) => {
+                warn!("Failed to unbind {} at {}: {}", address, id_server, e);
+                ThirdPartyIdRemovalStatus::NoSupport
+            }
+        }
+    }
+
+    /// Adds a third party id to the account of the user, or updates it.
+    pub fn add_threepid(&self, user_id: &UserId, threepid: &Threepid) -> Result<()> {
+        self.db.add_threepid(user_id, threepid)
+    }
+
+    pub fn remove_threepid(&self, user_id: &UserId, medium: &Medium, address: &str) -> Result<()> {
+        self.db.remove_threepid(user_id, medium, address)
+    }
+
+    /// Returns the third party ids on the account of the user.
+    pub fn threepids<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> impl Iterator<Item = Result<Threepid>> + 'a {
+        self.db.threepids(user_id)
+    }
+
+    pub fn threepid(
+        &self,
+        user_id: &UserId,
+        medium: &Medium,
+        address: &str,
+    ) -> Result<Option<Threepid>> {
+        for threepid in self.threepids(user_id) {
+            let threepid = threepid?;
+            if &threepid.medium == medium && threepid.address == address {
+                return Ok(Some(threepid));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the user who has the third party id on their account.
+    pub fn find_from_threepid(
+        &self,
+        medium: &Medium,
+        address: &str,
+    ) -> Result<Option<OwnedUserId>> {
+        self.db.find_from_threepid(medium, address)
     }
 
     /// Creates a new sync filter. Returns the filter id.
@@ -337,6 +772,15 @@ impl Service {
     ) -> Result<Option<FilterDefinition>> {
         self.db.get_filter(user_id, filter_id)
     }
+
+    /// Returns how many rooms the user created, including rooms they left since.
+    pub fn rooms_created(&self, user_id: &UserId) -> Result<u64> {
+        self.db.rooms_created(user_id)
+    }
+
+    pub fn increment_rooms_created(&self, user_id: &UserId) -> Result<()> {
+        self.db.increment_rooms_created(user_id)
+    }
 }
 
 /// Ensure that a user only sees signatures from themselves and the target user
@@ -365,3 +809,339 @@ pub fn clean_signatures<F: Fn(&UserId) -> bool>(
 
     Ok(())
 }
+
+/// Checks that a custom profile field can be set to the value. The displayname and avatar_url
+/// have their own endpoints.
+fn check_profile_field(
+    key: &str,
+    value: &serde_json::Value,
+    existing: &BTreeMap<String, serde_json::Value>,
+) -> Result<()> {
+    if key.is_empty() || key.len() > MAX_PROFILE_KEY_LENGTH {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Profile key is empty or too long.",
+        ));
+    }
+
+    if matches!(key, "displayname" | "avatar_url") {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Use the displayname and avatar_url endpoints.",
+        ));
+    }
+
+    let size = |key: &str, value: &serde_json::Value| {
+        key.len()
+            + serde_json::to_vec(value)
+                .expect("JSON values can be serialized")
+                .len()
+    };
+
+    if size(key, value) > MAX_PROFILE_FIELD_SIZE {
+        return Err(Error::BadRequest(
+            ErrorKind::TooLarge,
+            "Profile field is too large.",
+        ));
+    }
+
+    let total: usize = existing
+        .iter()
+        .filter(|(existing_key, _)| existing_key.as_str() != key)
+        .map(|(key, value)| size(key, value))
+        .sum::<usize>()
+        + size(key, value);
+    if total > MAX_PROFILE_SIZE {
+        return Err(Error::BadRequest(
+            ErrorKind::TooLarge,
+            "Profile is too large.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// The member event content with the new profile, or None if nothing changed.
+fn updated_member_content(
+    mut content: RoomMemberEventContent,
+    displayname: Option<String>,
+    avatar_url: Option<OwnedMxcUri>,
+) -> Option<RoomMemberEventContent> {
+    if content.displayname == displayname && content.avatar_url == avatar_url {
+        return None;
+    }
+
+    content.displayname = displayname;
+    content.avatar_url = avatar_url;
+    Some(content)
+}
+
+/// Whether the last seen metadata written at `recorded` has to be written again.
+fn last_seen_outdated(
+    recorded: Option<(Instant, Option<IpAddr>)>,
+    now: Instant,
+    ip: Option<IpAddr>,
+    interval: Duration,
+) -> bool {
+    recorded.map_or(true, |(written, written_ip)| {
+        (ip.is_some() && ip != written_ip) || now.saturating_duration_since(written) >= interval
+    })
+}
+
+/// Whether `ts` is at least `secs` seconds before `now`.
+fn older_than(ts: MilliSecondsSinceUnixEpoch, now: MilliSecondsSinceUnixEpoch, secs: u64) -> bool {
+    u64::from(now.get()).saturating_sub(ts.get().into()) >= secs.saturating_mul(1000)
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::RoomId;
+
+    use super::*;
+    use crate::utils::testing;
+
+    #[test]
+    fn login_tokens_can_be_used_once_before_they_expire() {
+        let alice = ruma::user_id!("@alice:example.org");
+        let now = Instant::now();
+        let mut tokens = LoginTokens::default();
+
+        let token = tokens.issue(alice, now);
+        assert_eq!(
+            tokens
+                .redeem(&token, now + Duration::from_secs(10))
+                .as_deref(),
+            Some(alice)
+        );
+        // Single use
+        assert_eq!(tokens.redeem(&token, now + Duration::from_secs(20)), None);
+
+        let expired = tokens.issue(alice, now);
+        assert_eq!(tokens.redeem(&expired, now + LOGIN_TOKEN_LIFETIME), None);
+
+        assert_eq!(tokens.redeem("guessed", now), None);
+    }
+
+    #[test]
+    fn custom_profile_fields_are_limited() {
+        let mut existing = BTreeMap::new();
+        let pronouns = serde_json::json!("she/her");
+
+        assert!(check_profile_field("io.fsky.pronouns", &pronouns, &existing).is_ok());
+        assert!(check_profile_field("", &pronouns, &existing).is_err());
+        assert!(check_profile_field(&"k".repeat(129), &pronouns, &existing).is_err());
+        assert!(check_profile_field("displayname", &pronouns, &existing).is_err());
+
+        let big = serde_json::json!("x".repeat(4000));
+        assert!(check_profile_field("field", &big, &existing).is_ok());
+        let too_big = serde_json::json!("x".repeat(5000));
+        assert!(matches!(
+            check_profile_field("field", &too_big, &existing),
+            Err(Error::BadRequest(ErrorKind::TooLarge, _))
+        ));
+
+        for i in 0..16 {
+            existing.insert(format!("field{i}"), big.clone());
+        }
+        // Replacing a field only counts the new value
+        assert!(check_profile_field("field0", &pronouns, &existing).is_ok());
+        assert!(matches!(
+            check_profile_field("field16", &big, &existing),
+            Err(Error::BadRequest(ErrorKind::TooLarge, _))
+        ));
+    }
+
+    #[test]
+    fn member_events_get_the_new_profile() {
+        let mut content = RoomMemberEventContent::new(MembershipState::Join);
+        content.displayname = Some("alice".to_owned());
+        content.reason = Some("hi".to_owned());
+
+        assert!(updated_member_content(content.clone(), Some("alice".to_owned()), None).is_none());
+
+        let updated =
+            updated_member_content(content, Some("Alice Liddell".to_owned()), None).unwrap();
+        assert_eq!(updated.displayname.as_deref(), Some("Alice Liddell"));
+        assert_eq!(updated.membership, MembershipState::Join);
+        assert_eq!(updated.reason.as_deref(), Some("hi"));
+    }
+
+    #[test]
+    fn last_seen_is_written_on_ip_change_or_after_interval() {
+        let start = Instant::now();
+        let ip = Some(IpAddr::from([192, 0, 2, 1]));
+        let other_ip = Some(IpAddr::from([192, 0, 2, 2]));
+        let interval = LAST_SEEN_INTERVAL;
+
+        assert!(last_seen_outdated(None, start, ip, interval));
+        assert!(!last_seen_outdated(
+            Some((start, ip)),
+            start + Duration::from_secs(10),
+            ip,
+            interval
+        ));
+        assert!(!last_seen_outdated(
+            Some((start, ip)),
+            start + Duration::from_secs(10),
+            None,
+            interval
+        ));
+        assert!(last_seen_outdated(
+            Some((start, ip)),
+            start + Duration::from_secs(10),
+            other_ip,
+            interval
+        ));
+        assert!(last_seen_outdated(
+            Some((start, ip)),
+            start + LAST_SEEN_INTERVAL,
+            ip,
+            interval
+        ));
+    }
+
+    #[test]
+    fn tokens_expire_after_the_configured_time() {
+        let ts = |ms: u64| MilliSecondsSinceUnixEpoch(UInt::new(ms).unwrap());
+
+        assert!(!older_than(ts(1_000), ts(60_999), 60));
+        assert!(older_than(ts(1_000), ts(61_000), 60));
+        assert!(older_than(ts(1_000), ts(1_000), 0));
+        assert!(!older_than(ts(1_000), ts(500), 60));
+        assert!(!older_than(ts(0), ts(u64::MAX >> 12), u64::MAX));
+    }
+
+    #[test]
+    fn emails_are_stored_lowercased() {
+        assert_eq!(
+            canonical_threepid_address(&Medium::Email, " Alice@Example.ORG"),
+            "alice@example.org"
+        );
+        assert_eq!(
+            canonical_threepid_address(&Medium::Msisdn, "447700900000"),
+            "447700900000"
+        );
+    }
+
+    #[test]
+    fn stored_threepids_are_listed_as_added() {
+        let threepid = Threepid {
+            medium: Medium::Email,
+            address: canonical_threepid_address(&Medium::Email, "Alice@example.org"),
+            validated_at: MilliSecondsSinceUnixEpoch(UInt::new(1_000).unwrap()),
+            added_at: MilliSecondsSinceUnixEpoch(UInt::new(2_000).unwrap()),
+            bound_id_server: None,
+        };
+
+        let stored = serde_json::to_vec(&threepid).unwrap();
+        let listed: ThirdPartyIdentifier =
+            serde_json::from_slice::<Threepid>(&stored).unwrap().into();
+
+        assert_eq!(listed.medium, Medium::Email);
+        assert_eq!(listed.address, "alice@example.org");
+        assert_eq!(listed.validated_at, threepid.validated_at);
+        assert_eq!(listed.added_at, threepid.added_at);
+    }
+
+    #[tokio::test]
+    async fn deactivation_removes_third_party_ids() {
+        let threepid = |medium, address: &str, bound_id_server: Option<&str>| Threepid {
+            medium,
+            address: address.to_owned(),
+            validated_at: MilliSecondsSinceUnixEpoch::now(),
+            added_at: MilliSecondsSinceUnixEpoch::now(),
+            bound_id_server: bound_id_server.map(ToOwned::to_owned),
+        };
+        let alice = testing::user("deactivate_3pid_alice").await;
+        let users = &services().users;
+        users
+            .add_threepid(
+                &alice,
+                &threepid(Medium::Email, "deactivate_alice@example.org", None),
+            )
+            .unwrap();
+        users
+            .add_threepid(
+                &alice,
+                &threepid(Medium::Msisdn, "447700900001", Some("id.untrusted.test")),
+            )
+            .unwrap();
+
+        // The identity server it was bound at isn't trusted, so it can't be asked
+        assert!(matches!(
+            users.deactivate_account(&alice, None).await.unwrap(),
+            ThirdPartyIdRemovalStatus::NoSupport
+        ));
+        assert_eq!(users.threepids(&alice).count(), 0);
+        assert!(users
+            .find_from_threepid(&Medium::Email, "deactivate_alice@example.org")
+            .unwrap()
+            .is_none());
+        assert!(users
+            .find_from_threepid(&Medium::Msisdn, "447700900001")
+            .unwrap()
+            .is_none());
+
+        // Nothing was bound, so nothing is left at identity servers
+        let bob = testing::user("deactivate_3pid_bob").await;
+        users
+            .add_threepid(
+                &bob,
+                &threepid(Medium::Email, "deactivate_bob@example.org", None),
+            )
+            .unwrap();
+        assert!(matches!(
+            users.deactivate_account(&bob, None).await.unwrap(),
+            ThirdPartyIdRemovalStatus::Success
+        ));
+        assert_eq!(users.threepids(&bob).count(), 0);
+    }
+
+    #[tokio::test]
+    async fn profile_changes_reach_joined_rooms_only() {
+        let alice = testing::user("propagate_alice").await;
+        let joined = testing::room(&alice).await;
+        let left = testing::room(&alice).await;
+        crate::api::client_server::leave_room(&alice, &left, None)
+            .await
+            .unwrap();
+
+        let member = |room_id: &RoomId| {
+            let pdu = services()
+                .rooms
+                .state_accessor
+                .room_state_get(room_id, &StateEventType::RoomMember, alice.as_str())
+                .unwrap()
+                .unwrap();
+            serde_json::from_str::<RoomMemberEventContent>(pdu.content.get()).unwrap()
+        };
+
+        let users = &services().users;
+        users
+            .set_displayname(&alice, Some("Alice".to_owned()))
+            .unwrap();
+        users.propagate_profile(&alice);
+
+        let mut content = member(&joined);
+        for _ in 0..100 {
+            if content.displayname.as_deref() == Some("Alice") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            content = member(&joined);
+        }
+        assert_eq!(content.displayname.as_deref(), Some("Alice"));
+        assert_eq!(content.membership, MembershipState::Join);
+
+        // The update is done once nothing is running for the user anymore
+        for _ in 0..100 {
+            if !users.profile_updates.lock().unwrap().contains_key(&alice) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let content = member(&left);
+        assert_eq!(content.membership, MembershipState::Leave);
+        assert_ne!(content.displayname.as_deref(), Some("Alice"));
+    }
+}
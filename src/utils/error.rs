@@ -92,6 +92,33 @@ impl Error {
         error!("BadConfig: {}", message);
         Self::BadConfig(message)
     }
+
+    /// Whether the database was only briefly unavailable, e.g. locked by another writer, so
+    /// trying again shortly after may work.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            #[cfg(feature = "sqlite")]
+            Self::SqliteError {
+                source: rusqlite::Error::SqliteFailure(error, _),
+            } => matches!(
+                error.code,
+                rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked
+            ),
+            #[cfg(feature = "rocksdb")]
+            Self::RocksDbError { source } => {
+                let message = source.to_string();
+                [
+                    "Resource busy",
+                    "Operation timed out",
+                    "Operation aborted",
+                    "Try again",
+                ]
+                .iter()
+                .any(|status| message.starts_with(status))
+            }
+            _ => false,
+        }
+    }
 }
 
 impl Error {
@@ -0,0 +1,299 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+};
+
+use crate::config::ForwardingHeader;
+
+/// A range of IP addresses in CIDR notation, like `10.0.0.0/8` or `fe80::/10`. A single address
+/// is a range with the full prefix length.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+/// Private, loopback, link-local and other non-public ranges. Outgoing requests to them are
+/// blocked by default.
+pub const DEFAULT_IP_RANGE_BLACKLIST: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 addresses can also be written as IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, prefix_len) = match s.split_once('/') {
+            Some((network, prefix_len)) => (
+                network.parse().map_err(|_| "Invalid IP address.")?,
+                Some(prefix_len.parse().map_err(|_| "Invalid prefix length.")?),
+            ),
+            None => (s.parse().map_err(|_| "Invalid IP address.")?, None),
+        };
+
+        let max_len = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+
+        if prefix_len > max_len {
+            return Err("Invalid prefix length.");
+        }
+
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Whether an outgoing request may connect to `ip`. The whitelist takes precedence over the
+/// blacklist.
+pub fn ip_allowed(ip: IpAddr, blacklist: &[IpRange], whitelist: &[IpRange]) -> bool {
+    whitelist.iter().any(|range| range.contains(ip))
+        || !blacklist.iter().any(|range| range.contains(ip))
+}
+
+/// The IP of the client behind `peer`. Each trusted proxy, starting with the peer, added the
+/// address it got the request from to the end of `header`, so the list is walked backwards until
+/// an address isn't a trusted proxy. Anyone else could make the header up.
+pub fn client_ip(
+    peer: IpAddr,
+    header: ForwardingHeader,
+    value: Option<&str>,
+    trusted_proxies: &[IpRange],
+) -> IpAddr {
+    let hops: Vec<Option<IpAddr>> = match (header, value) {
+        (ForwardingHeader::Forwarded, Some(forwarded)) => forwarded
+            .split(',')
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node))
+            })
+            .collect(),
+        (ForwardingHeader::XForwardedFor, Some(forwarded_for)) => {
+            forwarded_for.split(',').map(parse_node).collect()
+        }
+        (_, None) => Vec::new(),
+    };
+
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        if !trusted_proxies.iter().any(|range| range.contains(client)) {
+            break;
+        }
+        match hop {
+            Some(ip) => client = ip,
+            // Obfuscated or garbage, the proxy is the best we know
+            None => break,
+        }
+    }
+
+    client
+}
+
+/// Parses `1.2.3.4`, `1.2.3.4:80`, `::1` or `[::1]:80`, quoted or not.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+
+    node.parse().ok().or_else(|| {
+        let (ip, _port) = node.split_once(':')?;
+        ip.parse::<Ipv4Addr>().ok().map(IpAddr::V4)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+    use crate::config::ForwardingHeader::{Forwarded, XForwardedFor};
+
+    fn default_blacklist() -> Vec<IpRange> {
+        DEFAULT_IP_RANGE_BLACKLIST
+            .iter()
+            .map(|range| range.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn private_addresses_are_blocked_by_default() {
+        let blacklist = default_blacklist();
+
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.0.1",
+            "::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!ip_allowed(ip.parse().unwrap(), &blacklist, &[]), "{ip}");
+        }
+
+        for ip in ["1.1.1.1", "2606:4700:4700::1111"] {
+            assert!(ip_allowed(ip.parse().unwrap(), &blacklist, &[]), "{ip}");
+        }
+    }
+
+    #[test]
+    fn whitelist_overrides_blacklist() {
+        let blacklist = default_blacklist();
+        let whitelist = ["127.0.0.1".parse().unwrap()];
+
+        assert!(ip_allowed(
+            Ipv4Addr::LOCALHOST.into(),
+            &blacklist,
+            &whitelist
+        ));
+        assert!(!ip_allowed(
+            Ipv4Addr::new(127, 0, 0, 2).into(),
+            &blacklist,
+            &whitelist
+        ));
+        assert!(!ip_allowed(
+            Ipv6Addr::LOCALHOST.into(),
+            &blacklist,
+            &whitelist
+        ));
+    }
+
+    #[test]
+    fn invalid_ranges_are_rejected() {
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("localhost".parse::<IpRange>().is_err());
+        assert!("fe80::/129".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn forwarded_ip_is_only_trusted_from_proxies() {
+        let proxies = [
+            "127.0.0.0/8".parse().unwrap(),
+            "10.0.0.0/8".parse().unwrap(),
+        ];
+        let local = IpAddr::from([127, 0, 0, 1]);
+        let remote = IpAddr::from([192, 0, 2, 1]);
+        let client = IpAddr::from([198, 51, 100, 7]);
+
+        // Untrusted peers can't choose their address
+        assert_eq!(
+            client_ip(remote, XForwardedFor, Some("198.51.100.7"), &proxies),
+            remote
+        );
+        assert_eq!(
+            client_ip(remote, Forwarded, Some("for=198.51.100.7"), &proxies),
+            remote
+        );
+
+        // Trusted proxies are skipped, but not what the client made up in front of them
+        assert_eq!(
+            client_ip(
+                local,
+                XForwardedFor,
+                Some("203.0.113.9, 198.51.100.7, 10.0.0.2"),
+                &proxies
+            ),
+            client
+        );
+        assert_eq!(
+            client_ip(
+                local,
+                Forwarded,
+                Some(r#"for=203.0.113.9, for="198.51.100.7:4711";proto=https, for=10.0.0.2"#),
+                &proxies
+            ),
+            client
+        );
+        assert_eq!(
+            client_ip(
+                local,
+                Forwarded,
+                Some(r#"For="[2001:db8::1]:4711""#),
+                &proxies
+            ),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+
+        // Garbage or obfuscated addresses leave the proxy as the client
+        assert_eq!(
+            client_ip(local, XForwardedFor, Some("garbage"), &proxies),
+            local
+        );
+        assert_eq!(
+            client_ip(local, Forwarded, Some("for=_hidden"), &proxies),
+            local
+        );
+        assert_eq!(client_ip(local, XForwardedFor, None, &proxies), local);
+    }
+
+    #[test]
+    fn only_the_configured_header_is_read() {
+        let proxies = ["127.0.0.0/8".parse().unwrap()];
+        let local = IpAddr::from([127, 0, 0, 1]);
+
+        // The other header comes from the client, the proxy passes it on untouched
+        assert_eq!(
+            client_ip(local, Forwarded, Some("198.51.100.7"), &proxies),
+            local
+        );
+        assert_eq!(
+            client_ip(local, XForwardedFor, Some("for=198.51.100.7"), &proxies),
+            local
+        );
+    }
+}
@@ -1,5 +1,8 @@
 pub mod error;
+pub mod ip_range;
 pub mod shutdown_monitor;
+#[cfg(test)]
+pub(crate) mod testing;
 
 use argon2::{Config, Variant};
 use cmp::Ordering;
@@ -7,9 +10,10 @@ use rand::prelude::*;
 use ring::digest;
 use ruma::{canonical_json::try_from_json_map, CanonicalJsonError, CanonicalJsonObject};
 use std::{
-    cmp, fmt,
+    cmp, fmt, fs, io,
+    path::Path,
     str::FromStr,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 pub fn millis_since_unix_epoch() -> u64 {
@@ -61,9 +65,18 @@ pub fn random_string(length: usize) -> String {
 }
 
 /// Calculate a new hash for the given password
-pub fn calculate_password_hash(password: &str) -> Result<String, argon2::Error> {
+///
+/// `mem_cost` is given in KiB. The parameters are stored in the encoded hash, so verifying
+/// works regardless of what is currently configured.
+pub fn calculate_password_hash(
+    password: &str,
+    mem_cost: u32,
+    time_cost: u32,
+) -> Result<String, argon2::Error> {
     let hashing_config = Config {
         variant: Variant::Argon2id,
+        mem_cost,
+        time_cost,
         ..Default::default()
     };
 
@@ -71,6 +84,83 @@ pub fn calculate_password_hash(password: &str) -> Result<String, argon2::Error>
     argon2::hash_encoded(password.as_bytes(), salt.as_bytes(), &hashing_config)
 }
 
+/// Checks if an encoded password hash was created with weaker parameters than the given ones
+/// (or with a different argon2 variant) and should be recalculated.
+pub fn password_hash_needs_rehash(hash: &str, mem_cost: u32, time_cost: u32) -> bool {
+    // Encoded hashes look like $argon2id$v=19$m=4096,t=3,p=1$salt$hash
+    let mut parts = hash.split('$').skip(1);
+
+    if parts.next() != Some("argon2id") {
+        return true;
+    }
+
+    let params = match parts.nth(1) {
+        Some(params) => params,
+        None => return true,
+    };
+
+    let mut hash_mem_cost = None;
+    let mut hash_time_cost = None;
+
+    for param in params.split(',') {
+        match param.split_once('=') {
+            Some(("m", value)) => hash_mem_cost = value.parse::<u32>().ok(),
+            Some(("t", value)) => hash_time_cost = value.parse::<u32>().ok(),
+            _ => {}
+        }
+    }
+
+    match (hash_mem_cost, hash_time_cost) {
+        (Some(m), Some(t)) => m < mem_cost || t < time_cost,
+        _ => true,
+    }
+}
+
+/// Parses a schedule like `@daily` or `6h` into the interval between two runs.
+///
+/// Supported are `@hourly`, `@daily`, `@weekly` and a number followed by one of the units `s`,
+/// `m`, `h` or `d`.
+pub fn parse_schedule(schedule: &str) -> Option<Duration> {
+    const MINUTE: u64 = 60;
+    const HOUR: u64 = 60 * MINUTE;
+    const DAY: u64 = 24 * HOUR;
+
+    let secs = match schedule.trim() {
+        "@hourly" => HOUR,
+        "@daily" => DAY,
+        "@weekly" => 7 * DAY,
+        interval => {
+            let unit_start = interval.find(|c: char| !c.is_ascii_digit())?;
+            let (count, unit) = interval.split_at(unit_start);
+            let unit = match unit {
+                "s" => 1,
+                "m" => MINUTE,
+                "h" => HOUR,
+                "d" => DAY,
+                _ => return None,
+            };
+            count.parse::<u64>().ok()?.checked_mul(unit)?
+        }
+    };
+
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Returns the combined size of all files below `path`.
+pub fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
 #[tracing::instrument(skip(keys))]
 pub fn calculate_hash(keys: &[&[u8]]) -> Vec<u8> {
     // We only hash the pdu's event ids, not the whole pdu
@@ -181,3 +271,52 @@ impl<'a> fmt::Display for HtmlEscape<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn old_password_hash_needs_rehash() {
+        let old_hash = calculate_password_hash("password", 4096, 3).unwrap();
+        assert!(password_hash_needs_rehash(&old_hash, 8192, 3));
+        assert!(password_hash_needs_rehash(&old_hash, 4096, 4));
+
+        let new_hash = calculate_password_hash("password", 8192, 4).unwrap();
+        assert!(!password_hash_needs_rehash(&new_hash, 8192, 4));
+        assert!(argon2::verify_encoded(&new_hash, b"password").unwrap());
+    }
+
+    #[test]
+    fn unknown_password_hash_needs_rehash() {
+        assert!(password_hash_needs_rehash("", 4096, 3));
+        assert!(password_hash_needs_rehash(
+            "$argon2i$v=19$m=4096,t=3,p=1$c2FsdA$aGFzaA",
+            4096,
+            3
+        ));
+    }
+
+    #[test]
+    fn parse_backup_schedules() {
+        assert_eq!(parse_schedule("@daily"), Some(Duration::from_secs(86400)));
+        assert_eq!(parse_schedule("30m"), Some(Duration::from_secs(1800)));
+        assert_eq!(parse_schedule("6h"), Some(Duration::from_secs(21600)));
+        assert_eq!(parse_schedule("0h"), None);
+        assert_eq!(parse_schedule("h"), None);
+        assert_eq!(parse_schedule("12"), None);
+        assert_eq!(parse_schedule("0 3 * * *"), None);
+    }
+
+    #[test]
+    fn dir_size_counts_nested_files() {
+        let dir = std::env::temp_dir().join(format!("conduit-dir-size-{}", random_string(8)));
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::write(dir.join("a"), [0; 10]).unwrap();
+        fs::write(dir.join("nested").join("b"), [0; 5]).unwrap();
+
+        assert_eq!(dir_size(&dir).unwrap(), 15);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,404 @@
+//! Services on an in-memory database, for tests of routes and services that need more than a
+//! pure function. All tests of the binary share them, so each test uses its own users and rooms.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::body::HttpBody;
+
+use ruma::{
+    api::{
+        client::{
+            membership::{
+                invite_user::{self, v3::InvitationRecipient},
+                join_room_by_id,
+            },
+            message::send_message_event,
+            room::create_room,
+        },
+        federation::discovery::{ServerSigningKeys, VerifyKey},
+        IncomingRequest,
+    },
+    events::room::message::RoomMessageEventContent,
+    serde::Base64,
+    signatures::Ed25519KeyPair,
+    CanonicalJsonObject, EventId, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedEventId,
+    OwnedRoomId, OwnedServerName, OwnedUserId, RoomId, RoomVersionId, ServerName, TransactionId,
+    UserId,
+};
+use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
+use tokio::sync::OnceCell;
+use tower::ServiceExt;
+
+use crate::{
+    api::{client_server, ruma_wrapper::ClientIp, server_server::FedDest},
+    service::globals,
+    services, Config, KeyValueDatabase, Ruma, Services, SERVICES,
+};
+
+pub(crate) const SERVER_NAME: &str = "conduit.test";
+/// The other name in `server_name_aliases`.
+pub(crate) const ALIAS_SERVER_NAME: &str = "alias.conduit.test";
+pub(crate) const DEVICE_ID: &str = "TESTDEVICE";
+/// The key server in `trusted_servers`. Tests that need it serve it with `remote_server`.
+pub(crate) const TRUSTED_SERVER: &str = "notary.test";
+
+lazy_static::lazy_static! {
+    static ref INIT: OnceCell<()> = OnceCell::new();
+}
+
+/// The config of the shared services. Limits are per user or room, so they only affect tests that
+/// go looking for them.
+pub(crate) fn config() -> Config {
+    serde_json::from_value(serde_json::json!({
+        "server_name": SERVER_NAME,
+        "server_name_aliases": [ALIAS_SERVER_NAME],
+        "database_backend": "memory",
+        "database_path": std::env::temp_dir()
+            .join(format!("conduit-test-{}", std::process::id())),
+        "allow_federation": true,
+        "allow_registration": true,
+        // Mock servers of other homeservers listen here
+        "federation_ip_whitelist": ["127.0.0.1/32"],
+        "trusted_servers": [TRUSTED_SERVER],
+        "per_user_media_quota_bytes": 1024,
+        "max_rooms_per_user_create": 5,
+        "max_rooms_per_user_join": 6,
+        "max_outstanding_invites_per_user": 2,
+        "max_invites_per_room": 3,
+        "access_token_lifetime": 30 * 24 * 60 * 60,
+        "session_idle_expiry": 7 * 24 * 60 * 60,
+        "room_list_publication_requires_admin": true,
+        "enable_lightning_bolt": false,
+        "default_displayname_template": "{localpart} (Test)",
+        "default_avatar_url": "mxc://conduit.test/default",
+    }))
+    .expect("test config is valid")
+}
+
+/// Sets the shared services up on first use, with the server user and admin room of a new server.
+/// The admin room answers commands.
+pub(crate) async fn services_for_tests() -> &'static Services {
+    INIT.get_or_init(|| async {
+        let config = config();
+        let db = Box::leak(Box::new(
+            KeyValueDatabase::open(&config).expect("in-memory database opens"),
+        ));
+        let services = Box::leak(Box::new(
+            Services::build(db, config).expect("services build on an empty database"),
+        ));
+        *SERVICES.write().unwrap() = Some(services);
+
+        services
+            .admin
+            .create_admin_room()
+            .await
+            .expect("admin room can be created");
+        // Commands in the admin room are answered like on a running server
+        services.admin.start_handler();
+    })
+    .await;
+
+    services()
+}
+
+/// Creates a local user with the password `password` and the device `DEVICE_ID`. Its access token
+/// is `access_token(localpart)`.
+pub(crate) async fn user(localpart: &str) -> OwnedUserId {
+    services_for_tests().await;
+
+    let user_id = UserId::parse_with_server_name(localpart, services().globals.server_name())
+        .expect("localpart is valid");
+    services()
+        .users
+        .create(&user_id, Some("password"))
+        .expect("user can be created");
+    services()
+        .users
+        .create_device(&user_id, &device_id(), &access_token(localpart), None)
+        .expect("device can be created");
+
+    user_id
+}
+
+/// Like `user`, but the user is a server admin.
+pub(crate) async fn admin(localpart: &str) -> OwnedUserId {
+    let user_id = user(localpart).await;
+    services()
+        .admin
+        .make_user_admin(&user_id, localpart.to_owned())
+        .await
+        .expect("user can be made an admin");
+
+    user_id
+}
+
+pub(crate) fn device_id() -> OwnedDeviceId {
+    DEVICE_ID.into()
+}
+
+pub(crate) fn access_token(localpart: &str) -> String {
+    format!("{localpart}_token")
+}
+
+/// A request of `sender` with the body a route would get after authentication.
+pub(crate) fn request<T>(body: T, sender: &UserId) -> Ruma<T> {
+    Ruma {
+        body,
+        sender_user: Some(sender.to_owned()),
+        sender_device: Some(device_id()),
+        sender_servername: None,
+        json_body: None,
+        from_appservice: false,
+        client_ip: None,
+    }
+}
+
+/// A request without an access token.
+pub(crate) fn unauthenticated_request<T>(body: T) -> Ruma<T> {
+    Ruma {
+        body,
+        sender_user: None,
+        sender_device: None,
+        sender_servername: None,
+        json_body: None,
+        from_appservice: false,
+        client_ip: None,
+    }
+}
+
+/// A federation request signed by `origin`.
+pub(crate) fn federation_request<T>(body: T, origin: &ServerName) -> Ruma<T> {
+    Ruma {
+        body,
+        sender_user: None,
+        sender_device: None,
+        sender_servername: Some(origin.to_owned()),
+        json_body: None,
+        from_appservice: false,
+        client_ip: None,
+    }
+}
+
+/// Runs `request` from `client_ip` through the authentication of the client API, matched against
+/// the route `path`. Returns what the route would get, or the error body the client would get.
+pub(crate) async fn extract<T>(
+    path: &str,
+    mut request: http::Request<axum::body::Body>,
+    client_ip: Option<IpAddr>,
+) -> Result<Ruma<T>, serde_json::Value>
+where
+    T: IncomingRequest + Send + 'static,
+{
+    if let Some(ip) = client_ip {
+        request.extensions_mut().insert(ClientIp(ip));
+    }
+
+    let extracted = Arc::new(Mutex::new(None));
+    let sink = Arc::clone(&extracted);
+    let router = axum::Router::new().route(
+        path,
+        axum::routing::any(move |ruma: Ruma<T>| async move {
+            *sink.lock().unwrap() = Some(ruma);
+        }),
+    );
+
+    let mut body = router
+        .oneshot(request)
+        .await
+        .expect("routers don't fail")
+        .into_body();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk.expect("response body can be read"));
+    }
+
+    let extracted = extracted.lock().unwrap().take();
+    extracted.ok_or_else(|| serde_json::from_slice(&bytes).expect("errors are JSON"))
+}
+
+/// A request of the client API, authenticated with `access_token`.
+pub(crate) fn client_request(
+    method: http::Method,
+    uri: &str,
+    access_token: &str,
+    body: serde_json::Value,
+) -> http::Request<axum::body::Body> {
+    http::Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(
+            http::header::AUTHORIZATION,
+            format!("Bearer {access_token}"),
+        )
+        .body(axum::body::Body::from(body.to_string()))
+        .expect("request is valid")
+}
+
+/// Creates a room with the defaults of createRoom.
+pub(crate) async fn room(creator: &UserId) -> OwnedRoomId {
+    room_with(creator, create_room::v3::Request::new()).await
+}
+
+pub(crate) async fn room_with(creator: &UserId, body: create_room::v3::Request) -> OwnedRoomId {
+    client_server::create_room_route(request(body, creator))
+        .await
+        .expect("room can be created")
+        .room_id
+}
+
+/// Invites `user_id` into a room of this server.
+pub(crate) async fn invite(sender: &UserId, user_id: &UserId, room_id: &RoomId) {
+    client_server::invite_user_route(request(
+        invite_user::v3::Request::new(
+            room_id.to_owned(),
+            InvitationRecipient::UserId {
+                user_id: user_id.to_owned(),
+            },
+        ),
+        sender,
+    ))
+    .await
+    .expect("user can be invited");
+}
+
+/// Joins `user_id` into a room of this server.
+pub(crate) async fn join(user_id: &UserId, room_id: &RoomId) {
+    client_server::join_room_by_id_route(request(
+        join_room_by_id::v3::Request::new(room_id.to_owned()),
+        user_id,
+    ))
+    .await
+    .expect("user can join the room");
+}
+
+/// Sends a text message and returns its event id.
+pub(crate) async fn send_message(sender: &UserId, room_id: &RoomId, body: &str) -> OwnedEventId {
+    client_server::send_message_event_route(request(
+        send_message_event::v3::Request::new(
+            room_id.to_owned(),
+            TransactionId::new(),
+            &RoomMessageEventContent::text_plain(body),
+        )
+        .expect("message serializes"),
+        sender,
+    ))
+    .await
+    .expect("message can be sent")
+    .event_id
+}
+
+/// Serves `router` as the homeserver `server_name`: federation requests to it go to the mock.
+pub(crate) async fn remote_server(server_name: &str, router: axum::Router) -> OwnedServerName {
+    services_for_tests().await;
+
+    let addr = mock_server(router);
+    let server_name = OwnedServerName::try_from(server_name).expect("server name is valid");
+    services()
+        .globals
+        .actual_destination_cache
+        .write()
+        .unwrap()
+        .insert(
+            server_name.clone(),
+            (
+                FedDest::Literal(addr),
+                addr.to_string(),
+                Instant::now() + Duration::from_secs(60 * 60),
+            ),
+        );
+
+    server_name
+}
+
+/// Serves `router` on a local port and returns its address, for mocks of other kinds of servers.
+pub(crate) fn mock_server(router: axum::Router) -> SocketAddr {
+    let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+        .serve(router.into_make_service());
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    addr
+}
+
+/// A homeserver the tests play, which signs events with its own key. The server under test knows
+/// the key already, so it doesn't have to fetch it.
+pub(crate) struct RemoteHomeserver {
+    pub(crate) server_name: OwnedServerName,
+    keypair: Ed25519KeyPair,
+}
+
+impl RemoteHomeserver {
+    pub(crate) async fn new(server_name: &str) -> Self {
+        services_for_tests().await;
+
+        let server_name = OwnedServerName::try_from(server_name).expect("server name is valid");
+        let keypair = Ed25519KeyPair::from_der(
+            &Ed25519KeyPair::generate().expect("key pair can be generated"),
+            "test".to_owned(),
+        )
+        .expect("generated key pair is valid");
+
+        let mut keys = ServerSigningKeys::new(
+            server_name.clone(),
+            MilliSecondsSinceUnixEpoch::from_system_time(
+                std::time::SystemTime::now() + Duration::from_secs(60 * 60),
+            )
+            .expect("time is valid"),
+        );
+        keys.verify_keys.insert(
+            globals::key_id(&keypair),
+            VerifyKey {
+                key: Base64::new(keypair.public_key().to_vec()),
+            },
+        );
+        services()
+            .globals
+            .add_signing_key(&server_name, keys)
+            .expect("signing key can be stored");
+
+        Self {
+            server_name,
+            keypair,
+        }
+    }
+
+    /// Serves `router` as this homeserver, see `remote_server`.
+    pub(crate) async fn serve(&self, router: axum::Router) {
+        remote_server(self.server_name.as_str(), router).await;
+    }
+
+    pub(crate) fn user_id(&self, localpart: &str) -> OwnedUserId {
+        UserId::parse_with_server_name(localpart, &self.server_name).expect("localpart is valid")
+    }
+
+    /// Adds hashes and the signature of this server to `event`, which has every other field of a
+    /// PDU but the event id. Returns the event id and the event as it is sent over federation.
+    pub(crate) fn sign(
+        &self,
+        event: serde_json::Value,
+        room_version: &RoomVersionId,
+    ) -> (OwnedEventId, Box<RawJsonValue>) {
+        let mut event: CanonicalJsonObject =
+            serde_json::from_value(event).expect("event is a canonical JSON object");
+        ruma::signatures::hash_and_sign_event(
+            self.server_name.as_str(),
+            &self.keypair,
+            &mut event,
+            room_version,
+        )
+        .expect("event can be signed");
+
+        let event_id = EventId::parse(format!(
+            "${}",
+            ruma::signatures::reference_hash(&event, room_version)
+                .expect("reference hash can be calculated")
+        ))
+        .expect("reference hashes are valid event ids");
+
+        (event_id, to_raw_value(&event).expect("event serializes"))
+    }
+}